@@ -21,7 +21,7 @@ fn main() -> Result<()> {
         .debug_mode(true)
         .capture_cursor(false)
         .output_path("output.mp4")
-        .build();
+        .build()?;
 
     // Create the recorder with your target window name
     let recorder = Recorder::new(config)?.with_process_name("Chrome");
@@ -38,7 +38,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .capture_microphone(true)
         .enable_replay_buffer(true)
         .replay_buffer_seconds(30) // Keep last 10 seconds in buffer
-        .build();
+        .build()?;
         
     let recorder = Recorder::new(recorder)?.with_process_name(process_name);
     
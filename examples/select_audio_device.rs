@@ -49,7 +49,7 @@ fn main() -> Result<()> {
         .capture_microphone(true)
         .microphone_device(selected_device.map(|d| d.name.clone()))
         .output_path("device.mp4")
-        .build();
+        .build()?;
     
     // Create and start the recorder
     let recorder = Recorder::new(config)?
@@ -55,7 +55,7 @@ fn main() -> Result<()> {
         .video_encoder(selected_encoder.encoder_type) // Use the encoder type from selected encoder
         .video_encoder_name(&selected_encoder.name) // Add this line to specify encoder by name
         .output_path("encoder_test.mp4")
-        .build();
+        .build()?;
 
     // Create and start the recorder
     let recorder = Recorder::new(config)?.with_process_name("Chrome");
@@ -0,0 +1,52 @@
+/* C interface of windows-record, built with `cargo build --release --features ffi` */
+#ifndef WINDOWS_RECORD_H
+#define WINDOWS_RECORD_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WR_OK 0
+/* Also returned, with the message in wr_last_error, if the call panicked */
+#define WR_ERROR (-1)
+#define WR_INVALID_ARGUMENT (-2)
+#define WR_NOT_RECORDING (-3)
+
+typedef struct WrConfig WrConfig;
+typedef struct WrRecorder WrRecorder;
+
+/* Message of the last failed call on this thread; returns the size needed including the NUL */
+size_t wr_last_error(char *buffer, size_t capacity);
+
+WrConfig *wr_config_new(void);
+void wr_config_free(WrConfig *config);
+int32_t wr_config_set_output_path(WrConfig *config, const char *path);
+int32_t wr_config_set_fps(WrConfig *config, uint32_t num, uint32_t den);
+int32_t wr_config_set_output_dimensions(WrConfig *config, uint32_t width, uint32_t height);
+int32_t wr_config_set_video_bitrate(WrConfig *config, uint32_t bitrate);
+int32_t wr_config_set_capture_audio(WrConfig *config, bool enabled);
+int32_t wr_config_set_capture_microphone(WrConfig *config, bool enabled);
+/* 0 disables the replay buffer */
+int32_t wr_config_set_replay_buffer(WrConfig *config, uint32_t seconds);
+
+/* Returns NULL on failure; the config is copied and can be freed afterwards */
+WrRecorder *wr_recorder_new(const WrConfig *config, const char *process_name);
+/* Stops the recording if it is running */
+void wr_recorder_free(WrRecorder *recorder);
+int32_t wr_recorder_start(WrRecorder *recorder);
+int32_t wr_recorder_stop(WrRecorder *recorder);
+int32_t wr_recorder_save_replay(WrRecorder *recorder, const char *path);
+/* The oldest event as one line of JSON, such as {"time_ms":...,"event":"Stopped",...}; returns
+   0 if no event is queued, otherwise the size needed including the NUL. The event is only
+   removed once it fits in the buffer */
+size_t wr_recorder_poll_event(WrRecorder *recorder, char *buffer, size_t capacity);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WINDOWS_RECORD_H */
@@ -0,0 +1,7 @@
+//! C ABI of windows-record as a DLL
+//!
+//! The `wr_*` functions live in the library's `ffi` module; this package only links them into a
+//! `cdylib`, so applications using the library from Rust do not build a DLL they never load.
+//! `include/windows_record.h` declares them.
+
+pub use windows_record::ffi::*;
@@ -1,12 +1,12 @@
-use log::{debug, info, trace};
+use crate::logging::{debug, info, trace, warn};
 use std::ptr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::Barrier;
+use std::time::Instant;
 use windows::core::{implement, IUnknown};
 use windows::core::{ComInterface, Result};
-use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
 use windows::Win32::Foundation::*;
 use windows::Win32::Media::Audio::*;
 use windows::Win32::Media::MediaFoundation::*;
@@ -15,8 +15,15 @@ use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerforma
 use windows::Win32::System::Threading::*;
 use StructuredStorage::PROPVARIANT;
 
+use super::endpoint::{
+    device_friendly_name, elapsed_hns, is_device_lost, DefaultDeviceWatcher, EndpointSwitcher,
+    DEVICE_RETRY_INTERVAL,
+};
+use super::window::{find_processes_by_executable, is_process_running};
+use crate::error::ThreadSource;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
 use crate::types::SendableSample;
-use crate::AudioSource;
+use crate::{AudioProcess, AudioSource, LoopbackMode};
 
 #[derive(Clone)]
 #[implement(IActivateAudioInterfaceCompletionHandler)]
@@ -71,14 +78,125 @@ impl IActivateAudioInterfaceCompletionHandler_Impl
     }
 }
 
+/// How often `AudioSource::Processes` looks for processes that started or exited
+const PROCESS_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Audio of a process captured by `AudioSource::Processes`, each mixed as its own source
+pub enum AudioPacket {
+    /// A packet of the named source
+    Sample {
+        source: Arc<str>,
+        sample: SendableSample,
+    },
+    /// The named source stopped delivering because its process exited
+    Ended { source: Arc<str> },
+}
+
+/// One loopback capture feeding the audio track
+struct LoopbackStream {
+    /// Process whose tree is captured, unused for desktop audio
+    process_id: u32,
+    /// Mixer source the packets of a captured process are sent as
+    name: Arc<str>,
+    stream: Option<(IAudioClient, IAudioCaptureClient)>,
+    last_attempt: Instant,
+    last_packet_qpc: u64,
+}
+
+impl LoopbackStream {
+    unsafe fn open_process(
+        process_id: u32,
+        name: Arc<str>,
+        wave_format: &WAVEFORMATEX,
+        audio_source: &AudioSource,
+    ) -> Self {
+        let stream = match open_audio(process_id, wave_format, audio_source) {
+            Ok((client, capture, _)) => Some((client, capture)),
+            Err(e) => {
+                warn!(
+                    "Failed to capture the audio of process {}, retrying: {:?}",
+                    process_id, e
+                );
+                None
+            }
+        };
+        Self {
+            process_id,
+            name,
+            stream,
+            last_attempt: Instant::now(),
+            last_packet_qpc: 0,
+        }
+    }
+}
+
+/// Top-level processes of the targets of `AudioSource::Processes` that are running now, with
+/// the name they are mixed under
+fn resolve_audio_processes(processes: &[AudioProcess]) -> Vec<(u32, String)> {
+    let mut resolved: Vec<(u32, String)> = Vec::new();
+    for process in processes {
+        let found = match process {
+            AudioProcess::Id(process_id) => is_process_running(*process_id)
+                .then_some(*process_id)
+                .into_iter()
+                .collect(),
+            AudioProcess::Executable(executable) => find_processes_by_executable(executable),
+        };
+        for process_id in found {
+            if !resolved.iter().any(|(id, _)| *id == process_id) {
+                resolved.push((process_id, process.source_name()));
+            }
+        }
+    }
+    resolved
+}
+
+/// Open the capture of a process, named `name` unless another captured process already is, e.g.
+/// a second instance of the same executable, which gets its id appended
+unsafe fn open_named_process(
+    streams: &[LoopbackStream],
+    process_id: u32,
+    name: &str,
+    wave_format: &WAVEFORMATEX,
+    audio_source: &AudioSource,
+) -> LoopbackStream {
+    let name = if streams.iter().any(|loopback| &*loopback.name == name) {
+        format!("{} ({})", name, process_id)
+    } else {
+        name.to_string()
+    };
+    info!(
+        "Capturing the audio of process {} as '{}'",
+        process_id, name
+    );
+    LoopbackStream::open_process(process_id, name.into(), wave_format, audio_source)
+}
+
+/// The recording an audio capture thread (system audio or microphone) delivers to
+pub struct AudioCaptureContext {
+    /// Cleared when the recording stops
+    pub recording: Arc<AtomicBool>,
+    /// Passed once every capture thread is ready
+    pub started: Arc<Barrier>,
+    /// Performance counter value the packet timestamps count from, or the thread's own start
+    pub start_qpc: Option<u64>,
+    pub events: EventEmitter,
+}
+
 pub unsafe fn collect_audio(
     send: Sender<SendableSample>,
-    recording: Arc<AtomicBool>,
+    sources: Sender<AudioPacket>,
+    capture: AudioCaptureContext,
     proc_id: u32,
-    started: Arc<Barrier>,
-    shared_start_qpc: Option<u64>,
     audio_source: &AudioSource,
+    loopback_mode: LoopbackMode,
 ) -> Result<()> {
+    let AudioCaptureContext {
+        recording,
+        started,
+        start_qpc: shared_start_qpc,
+        events,
+    } = capture;
     // Validate thread priority setting
     let priority_result = SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL);
     if priority_result.as_bool() == false {
@@ -100,31 +218,65 @@ pub unsafe fn collect_audio(
         qpc_freq, ticks_to_hns
     );
 
-    let wave_format = WAVEFORMATEX {
-        wFormatTag: WAVE_FORMAT_PCM.try_into().unwrap(),
-        nChannels: 2,
-        nSamplesPerSec: 44100,
-        nAvgBytesPerSec: 176400,
-        nBlockAlign: 4,
-        wBitsPerSample: 16,
-        cbSize: 0,
-    };
+    let wave_format = loopback_format();
 
-    let audio_client = match setup_audio_client(proc_id, &wave_format, audio_source) {
-        Ok(client) => client,
-        Err(e) => {
-            info!("Failed to setup audio client: {:?}", e);
-            return Err(e);
-        }
+    // Process loopback is missing from older Windows 10 builds, where the whole desktop audio is
+    // captured instead unless `loopback_mode` forces one or the other
+    let desktop_source = AudioSource::Desktop;
+    let (audio_source, fallback_reason) = match (audio_source, loopback_mode) {
+        (AudioSource::Desktop, _) | (_, LoopbackMode::Process) => (audio_source, None),
+        (_, LoopbackMode::Desktop) => (&desktop_source, None),
+        (_, LoopbackMode::Auto) => match probe_process_loopback(&wave_format) {
+            Ok(()) => (audio_source, None),
+            Err(e) => {
+                warn!(
+                    "Process loopback is not available, capturing the whole desktop audio: {:?}",
+                    e
+                );
+                (&desktop_source, Some(e.to_string()))
+            }
+        },
     };
-
-    let capture_client: IAudioCaptureClient = match audio_client.GetService() {
-        Ok(client) => client,
-        Err(e) => {
-            info!("Failed to get capture client: {:?}", e);
-            return Err(e);
+    let mode = match audio_source {
+        AudioSource::Desktop => LoopbackMode::Desktop,
+        _ => LoopbackMode::Process,
+    };
+    info!("Capturing system audio with {:?} loopback", mode);
+    events.emit(RecorderEvent::AudioLoopbackSelected {
+        mode,
+        fallback_reason,
+    });
+
+    // Several processes are each captured on their own and sent to the mixer as separate
+    // sources; a missing process is waited for instead of failing the recording
+    let per_process = matches!(audio_source, AudioSource::Processes(_));
+    let mut streams = match audio_source {
+        AudioSource::Processes(processes) => {
+            let resolved = resolve_audio_processes(processes);
+            if resolved.is_empty() {
+                info!("None of {:?} is running yet, waiting for them", processes);
+            }
+            let mut streams = Vec::new();
+            for (process_id, name) in resolved {
+                let loopback =
+                    open_named_process(&streams, process_id, &name, &wave_format, audio_source);
+                streams.push(loopback);
+            }
+            streams
+        }
+        _ => {
+            let (audio_client, capture_client, _) =
+                open_audio(proc_id, &wave_format, audio_source)?;
+            vec![LoopbackStream {
+                process_id: proc_id,
+                name: crate::SYSTEM_AUDIO_SOURCE.into(),
+                stream: Some((audio_client, capture_client)),
+                last_attempt: Instant::now(),
+                last_packet_qpc: 0,
+            }]
         }
     };
+    let mut last_scan = Instant::now();
 
     let packet_duration =
         std::time::Duration::from_nanos((1000000000.0 / wave_format.nSamplesPerSec as f64) as u64);
@@ -154,32 +306,120 @@ pub unsafe fn collect_audio(
     info!("Initial QPC value: {}", start_qpc);
 
     // Track timing statistics
-    let mut last_packet_time = start_qpc;
+    for loopback in streams.iter_mut() {
+        loopback.last_packet_qpc = start_qpc;
+    }
     let mut zero_packet_count = 0;
     let mut total_packets = 0;
 
-    match audio_client.Start() {
-        Ok(_) => info!("Audio client started successfully"),
-        Err(e) => {
-            info!("Failed to start audio client: {:?}", e);
-            return Err(e);
+    // Process loopback follows the default render device by itself; desktop capture has to
+    // reopen on the new default
+    let follow_default = matches!(audio_source, AudioSource::Desktop);
+    let watcher = if follow_default {
+        match DefaultDeviceWatcher::new(eRender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                info!("Failed to watch for default output device changes: {:?}", e);
+                None
+            }
         }
-    }
+    } else {
+        None
+    };
+    let mut switcher = EndpointSwitcher::new(ThreadSource::Audio, events, &wave_format);
 
     started.wait();
 
     while recording.load(Ordering::Relaxed) {
-        let next_packet_size = match capture_client.GetNextPacketSize() {
-            Ok(size) => size,
-            Err(e) => {
-                info!("Failed to get next packet size: {:?}", e);
-                return Err(e);
+        // Pick up processes started since the last scan and forget those that exited
+        if let AudioSource::Processes(processes) = audio_source {
+            if last_scan.elapsed() >= PROCESS_SCAN_INTERVAL {
+                last_scan = Instant::now();
+                streams.retain(|loopback| {
+                    let running = is_process_running(loopback.process_id);
+                    if !running {
+                        info!(
+                            "Process {} exited, no longer capturing its audio",
+                            loopback.process_id
+                        );
+                        // The mixer stops waiting for it
+                        let _ = sources.send(AudioPacket::Ended {
+                            source: loopback.name.clone(),
+                        });
+                    }
+                    running
+                });
+                for (process_id, name) in resolve_audio_processes(processes) {
+                    if !streams
+                        .iter()
+                        .any(|loopback| loopback.process_id == process_id)
+                    {
+                        let mut loopback = open_named_process(
+                            &streams,
+                            process_id,
+                            &name,
+                            &wave_format,
+                            audio_source,
+                        );
+                        loopback.last_packet_qpc = start_qpc;
+                        streams.push(loopback);
+                    }
+                }
             }
-        };
+        }
+
+        let default_changed = watcher.as_ref().is_some_and(|w| w.take_changed());
+        let mut received = false;
+        for loopback in streams.iter_mut() {
+            if loopback.stream.is_none() || default_changed {
+                if loopback.stream.is_some() {
+                    info!("Default output device changed, switching");
+                } else if loopback.last_attempt.elapsed() < DEVICE_RETRY_INTERVAL {
+                    continue;
+                }
+                if let Some((client, _)) = loopback.stream.take() {
+                    let _ = client.Stop();
+                }
+                loopback.last_attempt = Instant::now();
+                match open_audio(loopback.process_id, &wave_format, audio_source) {
+                    Ok((client, capture, device_name)) => {
+                        // The mixer covers the gaps of each captured process itself
+                        if !per_process {
+                            let now_hns = elapsed_hns(start_qpc, ticks_to_hns);
+                            switcher.switched(now_hns, device_name, &send)?;
+                        }
+                        loopback.stream = Some((client, capture));
+                    }
+                    Err(e) => {
+                        debug!("No output device to capture yet: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            let Some((_, capture_client)) = loopback.stream.as_ref() else {
+                continue;
+            };
 
-        if next_packet_size > 0 {
+            let next_packet_size = match capture_client.GetNextPacketSize() {
+                Ok(size) => size,
+                Err(e) if is_device_lost(&e) => {
+                    warn!(
+                        "Audio output device was removed, waiting for another one: {:?}",
+                        e
+                    );
+                    loopback.stream = None;
+                    continue;
+                }
+                Err(e) => {
+                    info!("Failed to get next packet size: {:?}", e);
+                    return Err(e);
+                }
+            };
+            if next_packet_size == 0 {
+                continue;
+            }
+            received = true;
             total_packets += 1;
-            zero_packet_count = 0; // Reset counter when we get data
 
             let mut buffer = ptr::null_mut();
             let mut num_frames_available = 0;
@@ -196,13 +436,14 @@ pub unsafe fn collect_audio(
             ) {
                 Ok(_) => {
                     // Validate QPC timing
-                    if qpc_position <= last_packet_time {
-                        info!(
-                            "QPC time went backwards: current={}, last={}",
-                            qpc_position, last_packet_time
+                    if qpc_position <= loopback.last_packet_qpc {
+                        debug!(
+                            qpc = qpc_position,
+                            last_qpc = loopback.last_packet_qpc,
+                            "QPC time went backwards"
                         );
                     }
-                    last_packet_time = qpc_position;
+                    loopback.last_packet_qpc = qpc_position;
 
                     if (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) == 0 {
                         let relative_qpc = qpc_position - start_qpc;
@@ -216,10 +457,22 @@ pub unsafe fn collect_audio(
                             packet_duration_hns,
                         ) {
                             Ok(sample) => {
-                                if let Err(e) = send.send(SendableSample::new(sample)) {
-                                    info!("Failed to send audio sample, receiver likely dropped: {:?}", e);
+                                let sample = SendableSample::new(sample);
+                                let sent = if per_process {
+                                    sources
+                                        .send(AudioPacket::Sample {
+                                            source: loopback.name.clone(),
+                                            sample,
+                                        })
+                                        .is_ok()
+                                } else {
+                                    send.send(sample).is_ok()
+                                };
+                                if !sent {
+                                    info!("Failed to send audio sample, receiver likely dropped");
                                     return Err(E_FAIL.into());
                                 }
+                                switcher.delivered(time_hns, num_frames_available);
                             }
                             Err(e) => {
                                 info!("Failed to create audio sample: {:?}", e);
@@ -230,24 +483,42 @@ pub unsafe fn collect_audio(
 
                     match capture_client.ReleaseBuffer(num_frames_available) {
                         Ok(_) => {}
+                        Err(e) if is_device_lost(&e) => {
+                            warn!(
+                                "Audio output device was removed, waiting for another one: {:?}",
+                                e
+                            );
+                            loopback.stream = None;
+                        }
                         Err(e) => {
                             info!("Failed to release buffer: {:?}", e);
                             return Err(e);
                         }
                     }
                 }
+                Err(e) if is_device_lost(&e) => {
+                    warn!(
+                        "Audio output device was removed, waiting for another one: {:?}",
+                        e
+                    );
+                    loopback.stream = None;
+                }
                 Err(e) => {
                     info!("Failed to get buffer: {:?}", e);
                     return Err(e);
                 }
             }
+        }
+
+        if received {
+            zero_packet_count = 0; // Reset counter when we get data
         } else {
             zero_packet_count += 1;
             if zero_packet_count >= 1000 {
                 // Log every ~1 second of no data
-                info!(
-                    "No audio data received for {} consecutive checks",
-                    zero_packet_count
+                debug!(
+                    checks = zero_packet_count,
+                    "No audio data received for {} consecutive checks", zero_packet_count
                 );
                 zero_packet_count = 0; // Reset to avoid spam
             }
@@ -259,23 +530,95 @@ pub unsafe fn collect_audio(
         "Recording stopped. Total packets processed: {}",
         total_packets
     );
-    match audio_client.Stop() {
-        Ok(_) => info!("Audio client stopped successfully"),
-        Err(e) => info!("Error stopping audio client: {:?}", e),
+    for (audio_client, _) in streams.into_iter().filter_map(|loopback| loopback.stream) {
+        match audio_client.Stop() {
+            Ok(_) => info!("Audio client stopped successfully"),
+            Err(e) => info!("Error stopping audio client: {:?}", e),
+        }
     }
 
     Ok(())
 }
 
+/// Format every loopback capture is converted to
+pub(super) fn loopback_format() -> WAVEFORMATEX {
+    WAVEFORMATEX {
+        wFormatTag: WAVE_FORMAT_PCM.try_into().unwrap(),
+        nChannels: 2,
+        nSamplesPerSec: 44100,
+        nAvgBytesPerSec: 176400,
+        nBlockAlign: 4,
+        wBitsPerSample: 16,
+        cbSize: 0,
+    }
+}
+
+/// Whether Windows can capture the audio of single processes, which older Windows 10 builds
+/// cannot; COM must be initialized on the calling thread
+pub(crate) fn process_loopback_supported() -> bool {
+    match unsafe { probe_process_loopback(&loopback_format()) } {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Process loopback is not available: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Activate a process loopback capture of this process, which fails where Windows lacks the API
+unsafe fn probe_process_loopback(wave_format: &WAVEFORMATEX) -> Result<()> {
+    setup_audio_client(
+        GetCurrentProcessId(),
+        wave_format,
+        &AudioSource::ActiveWindow,
+    )
+    .map(|_| ())
+}
+
+/// Set up and start an audio capture, returning the captured device's name when known
+unsafe fn open_audio(
+    proc_id: u32,
+    wave_format: &WAVEFORMATEX,
+    audio_source: &AudioSource,
+) -> Result<(IAudioClient, IAudioCaptureClient, Option<String>)> {
+    let (audio_client, device_name) = match setup_audio_client(proc_id, wave_format, audio_source) {
+        Ok(client) => client,
+        Err(e) => {
+            info!("Failed to setup audio client: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    let capture_client: IAudioCaptureClient = match audio_client.GetService() {
+        Ok(client) => client,
+        Err(e) => {
+            info!("Failed to get capture client: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    match audio_client.Start() {
+        Ok(_) => info!("Audio client started successfully"),
+        Err(e) => {
+            info!("Failed to start audio client: {:?}", e);
+            return Err(e);
+        }
+    }
+    Ok((audio_client, capture_client, device_name))
+}
+
 unsafe fn setup_audio_client(
     proc_id: u32,
     wave_format: &WAVEFORMATEX,
     audio_source: &AudioSource,
-) -> Result<IAudioClient> {
+) -> Result<(IAudioClient, Option<String>)> {
     info!("Setting up audio client with source: {:?}", audio_source);
 
     match audio_source {
-        AudioSource::ActiveWindow => {
+        // Generated by `synthetic::generate_audio` instead of captured
+        #[cfg(feature = "synthetic")]
+        AudioSource::Synthetic(_) => Err(E_INVALIDARG.into()),
+        AudioSource::ActiveWindow | AudioSource::Processes(_) => {
             // Process-specific audio capture (existing implementation)
             let activation_params = AUDIOCLIENT_ACTIVATION_PARAMS {
                 ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
@@ -319,7 +662,7 @@ unsafe fn setup_audio_client(
                 None,
             )?;
 
-            Ok(audio_client)
+            Ok((audio_client, None))
         }
         AudioSource::Desktop => {
             // System-wide audio capture
@@ -347,17 +690,9 @@ unsafe fn setup_audio_client(
                 info!("Selected audio output device ID: {}", id);
             }
 
-            if let Ok(props) = device.OpenPropertyStore(STGM_READ) {
-                if let Ok(prop_value) = props.GetValue(&PKEY_Device_FriendlyName) {
-                    let name = prop_value
-                        .Anonymous
-                        .Anonymous
-                        .Anonymous
-                        .pwszVal
-                        .to_string()
-                        .unwrap_or_default();
-                    info!("Selected audio output device name: {}", name);
-                }
+            let device_name = device_friendly_name(&device);
+            if let Some(name) = &device_name {
+                info!("Selected audio output device name: {}", name);
             }
 
             // Activate the audio client
@@ -380,12 +715,12 @@ unsafe fn setup_audio_client(
             let event = CreateEventW(None, false, false, None)?;
             audio_client.SetEventHandle(event)?;
 
-            Ok(audio_client)
+            Ok((audio_client, device_name))
         }
     }
 }
 
-unsafe fn create_audio_sample(
+pub(super) unsafe fn create_audio_sample(
     buffer: *mut u8,
     num_frames: u32,
     wave_format: &WAVEFORMATEX,
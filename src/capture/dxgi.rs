@@ -1,18 +1,152 @@
-use log::{debug, error, info, trace, warn};
+use crate::logging::{debug, error, info, trace, warn};
 use windows::core::{ComInterface, Result};
-use windows::Win32::Foundation::{HWND, POINT, RECT};
+use windows::Win32::Foundation::{HWND, LUID, POINT, RECT};
 use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D};
 use windows::Win32::Graphics::Dxgi::*;
+use windows::Win32::Graphics::Gdi::{MonitorFromWindow, MONITOR_DEFAULTTONEAREST};
 
-pub unsafe fn setup_dxgi_duplication(device: &ID3D11Device) -> Result<IDXGIOutputDuplication> {
+/// The output of the device's adapter that shows the monitor `hwnd` is on, which is duplicated;
+/// the adapter's first output if none of them shows it
+pub unsafe fn capture_output(device: &ID3D11Device, hwnd: HWND) -> Result<IDXGIOutput> {
     // Get DXGI device
     let dxgi_device: IDXGIDevice = device.cast()?;
 
     // Get adapter
     let dxgi_adapter: IDXGIAdapter = dxgi_device.GetAdapter()?;
 
-    // Get output
-    let output = dxgi_adapter.EnumOutputs(0)?;
+    // Find the output showing the window's monitor
+    let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST);
+    let mut output_index = 0;
+    while let Ok(output) = dxgi_adapter.EnumOutputs(output_index) {
+        let mut desc = DXGI_OUTPUT_DESC::default();
+        if output.GetDesc(&mut desc).is_ok() && desc.Monitor == monitor {
+            trace!("Window's monitor is adapter output {}", output_index);
+            return Ok(output);
+        }
+        output_index += 1;
+    }
+    debug!("No output of the device's adapter shows the window's monitor, using the first");
+    dxgi_adapter.EnumOutputs(0)
+}
+
+/// Top-left corner of the output duplicated for `hwnd` on the virtual desktop, which maps
+/// desktop coordinates into the captured frame; (0, 0) if the output cannot be queried
+pub unsafe fn capture_origin(device: &ID3D11Device, hwnd: HWND) -> (i32, i32) {
+    let mut desc = DXGI_OUTPUT_DESC::default();
+    match capture_output(device, hwnd).and_then(|output| output.GetDesc(&mut desc)) {
+        Ok(()) => (desc.DesktopCoordinates.left, desc.DesktopCoordinates.top),
+        Err(e) => {
+            warn!("Failed to get the captured output's position: {:?}", e);
+            (0, 0)
+        }
+    }
+}
+
+/// Description of the adapter the device was created on, for logs and events
+pub unsafe fn adapter_name(device: &ID3D11Device) -> String {
+    let adapter = device
+        .cast::<IDXGIDevice>()
+        .and_then(|dxgi_device| dxgi_device.GetAdapter());
+    let mut desc = DXGI_ADAPTER_DESC::default();
+    match adapter.and_then(|adapter| adapter.GetDesc(&mut desc)) {
+        Ok(()) => String::from_utf16_lossy(&desc.Description)
+            .trim_end_matches('\0')
+            .to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Locally unique identifier of an adapter packed into one number, as used by Media Foundation's
+/// `MFT_ENUM_ADAPTER_LUID`
+pub fn luid_to_u64(luid: LUID) -> u64 {
+    ((luid.HighPart as u32 as u64) << 32) | luid.LowPart as u64
+}
+
+/// LUID of the adapter the device was created on
+pub unsafe fn adapter_luid(device: &ID3D11Device) -> Option<u64> {
+    let mut desc = DXGI_ADAPTER_DESC::default();
+    let adapter = device
+        .cast::<IDXGIDevice>()
+        .and_then(|dxgi_device| dxgi_device.GetAdapter())
+        .and_then(|adapter| adapter.GetDesc(&mut desc));
+    match adapter {
+        Ok(()) => Some(luid_to_u64(desc.AdapterLuid)),
+        Err(e) => {
+            warn!("Failed to get the device's adapter: {:?}", e);
+            None
+        }
+    }
+}
+
+/// LUID of the adapter driving the monitor `hwnd` is on, or None if no adapter output shows that
+/// monitor
+pub unsafe fn window_adapter_luid(hwnd: HWND) -> Option<u64> {
+    let factory: IDXGIFactory1 = match CreateDXGIFactory1() {
+        Ok(factory) => factory,
+        Err(e) => {
+            warn!(
+                "Failed to create a DXGI factory to look up the window's adapter: {:?}",
+                e
+            );
+            return None;
+        }
+    };
+    let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST);
+    let mut adapter_index = 0;
+    while let Ok(adapter) = factory.EnumAdapters(adapter_index) {
+        let mut output_index = 0;
+        while let Ok(output) = adapter.EnumOutputs(output_index) {
+            let mut output_desc = DXGI_OUTPUT_DESC::default();
+            if output.GetDesc(&mut output_desc).is_ok() && output_desc.Monitor == monitor {
+                let mut desc = DXGI_ADAPTER_DESC::default();
+                adapter.GetDesc(&mut desc).ok()?;
+                debug!("Window's monitor is on adapter {}", adapter_index);
+                return Some(luid_to_u64(desc.AdapterLuid));
+            }
+            output_index += 1;
+        }
+        adapter_index += 1;
+    }
+    debug!("No adapter output found for the window's monitor");
+    None
+}
+
+/// Whether the monitor `hwnd` is on is now driven by another adapter than the device's, as
+/// when a hybrid-graphics laptop hands the window over between its GPUs; false if the
+/// monitor's adapter cannot be found
+pub unsafe fn window_adapter_changed(device: &ID3D11Device, hwnd: HWND) -> bool {
+    match (adapter_luid(device), window_adapter_luid(hwnd)) {
+        (Some(device_adapter), Some(window_adapter)) => {
+            let changed = device_adapter != window_adapter;
+            if changed {
+                debug!("Window's monitor moved to another adapter");
+            }
+            changed
+        }
+        _ => false,
+    }
+}
+
+/// Size of the frames a duplication delivers, which follows the display mode of the output
+pub unsafe fn duplication_size(duplication: &IDXGIOutputDuplication) -> (u32, u32) {
+    let mut desc = DXGI_OUTDUPL_DESC::default();
+    duplication.GetDesc(&mut desc);
+    (desc.ModeDesc.Width, desc.ModeDesc.Height)
+}
+
+/// Duplicate the output showing the monitor `hwnd` is on
+pub unsafe fn setup_dxgi_duplication(
+    device: &ID3D11Device,
+    hwnd: HWND,
+) -> Result<IDXGIOutputDuplication> {
+    duplicate_output(device, &capture_output(device, hwnd)?)
+}
+
+/// Duplicate `output`, which must belong to the device's adapter
+pub unsafe fn duplicate_output(
+    device: &ID3D11Device,
+    output: &IDXGIOutput,
+) -> Result<IDXGIOutputDuplication> {
     let output1: IDXGIOutput1 = output.cast()?;
 
     // Create duplication with flag to include cursor
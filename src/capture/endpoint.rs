@@ -0,0 +1,215 @@
+use crate::logging::{debug, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use windows::core::{implement, Result};
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+use windows::Win32::Foundation::E_FAIL;
+use windows::Win32::Media::Audio::*;
+use windows::Win32::Media::MediaFoundation::{
+    IMFMediaBuffer, IMFSample, MFCreateMemoryBuffer, MFCreateSample,
+};
+use windows::Win32::System::Com::*;
+use windows::Win32::System::Performance::QueryPerformanceCounter;
+
+use crate::error::ThreadSource;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::types::{hns_to_duration, SendableSample};
+
+/// How often a lost endpoint is looked for again
+pub(crate) const DEVICE_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest silence sample inserted for a gap, in 100ns units
+const MAX_SILENCE_CHUNK: i64 = 10_000_000;
+
+/// Notes changes of the default endpoint for one data flow
+#[derive(Clone)]
+#[implement(IMMNotificationClient)]
+struct AudioEndpointVolumeCallback {
+    flow: EDataFlow,
+    default_changed: Arc<AtomicBool>,
+}
+
+impl IMMNotificationClient_Impl for AudioEndpointVolumeCallback {
+    fn OnDeviceStateChanged(
+        &self,
+        _device_id: &windows::core::PCWSTR,
+        _new_state: u32,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn OnDeviceAdded(&self, _device_id: &windows::core::PCWSTR) -> Result<()> {
+        Ok(())
+    }
+    fn OnDeviceRemoved(&self, _device_id: &windows::core::PCWSTR) -> Result<()> {
+        Ok(())
+    }
+    fn OnDefaultDeviceChanged(
+        &self,
+        flow: EDataFlow,
+        role: ERole,
+        _default_device_id: &windows::core::PCWSTR,
+    ) -> Result<()> {
+        // Endpoints are opened with the console role
+        if flow == self.flow && role == eConsole {
+            self.default_changed.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+    fn OnPropertyValueChanged(
+        &self,
+        _device_id: &windows::core::PCWSTR,
+        _key: &windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Registration of an [`AudioEndpointVolumeCallback`], removed on drop
+pub(crate) struct DefaultDeviceWatcher {
+    enumerator: IMMDeviceEnumerator,
+    callback: IMMNotificationClient,
+    default_changed: Arc<AtomicBool>,
+}
+
+impl DefaultDeviceWatcher {
+    pub unsafe fn new(flow: EDataFlow) -> Result<Self> {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        let default_changed = Arc::new(AtomicBool::new(false));
+        let callback: IMMNotificationClient = AudioEndpointVolumeCallback {
+            flow,
+            default_changed: default_changed.clone(),
+        }
+        .into();
+        enumerator.RegisterEndpointNotificationCallback(&callback)?;
+        Ok(Self {
+            enumerator,
+            callback,
+            default_changed,
+        })
+    }
+
+    /// Whether the default endpoint changed since the last call
+    pub fn take_changed(&self) -> bool {
+        self.default_changed.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Drop for DefaultDeviceWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            if let Err(e) = self
+                .enumerator
+                .UnregisterEndpointNotificationCallback(&self.callback)
+            {
+                debug!("Failed to unregister endpoint notifications: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Whether `error` means the endpoint went away (unplugged, disabled or reconfigured)
+pub(crate) fn is_device_lost(error: &windows::core::Error) -> bool {
+    error.code() == AUDCLNT_E_DEVICE_INVALIDATED
+}
+
+/// Time since `start_qpc` in 100ns units
+pub(crate) unsafe fn elapsed_hns(start_qpc: u64, ticks_to_hns: f64) -> i64 {
+    let mut now_qpc: i64 = 0;
+    QueryPerformanceCounter(&mut now_qpc);
+    ((now_qpc as u64).saturating_sub(start_qpc) as f64 * ticks_to_hns) as i64
+}
+
+/// Friendly name of an audio endpoint, e.g. "Headset Microphone (USB Audio)"
+pub(crate) unsafe fn device_friendly_name(device: &IMMDevice) -> Option<String> {
+    let props = device.OpenPropertyStore(STGM_READ).ok()?;
+    let value = props.GetValue(&PKEY_Device_FriendlyName).ok()?;
+    value.Anonymous.Anonymous.Anonymous.pwszVal.to_string().ok()
+}
+
+/// Keeps an audio capture continuous across endpoint switches
+///
+/// Tracks where the delivered audio ends so the time spent switching can be filled with
+/// silence, and reports each switch as a `DeviceChanged` event.
+pub(crate) struct EndpointSwitcher {
+    source: ThreadSource,
+    events: EventEmitter,
+    block_align: u32,
+    samples_per_sec: u32,
+    /// End of the audio delivered so far, in 100ns units since the recording started
+    delivered_end: Option<i64>,
+}
+
+impl EndpointSwitcher {
+    pub fn new(source: ThreadSource, events: EventEmitter, wave_format: &WAVEFORMATEX) -> Self {
+        Self {
+            source,
+            events,
+            block_align: wave_format.nBlockAlign as u32,
+            samples_per_sec: wave_format.nSamplesPerSec,
+            delivered_end: None,
+        }
+    }
+
+    /// Remember that `frames` frames starting at `time_hns` were delivered
+    pub fn delivered(&mut self, time_hns: i64, frames: u32) {
+        let end = time_hns + frames as i64 * 10_000_000 / self.samples_per_sec as i64;
+        self.delivered_end = Some(self.delivered_end.map_or(end, |last| last.max(end)));
+    }
+
+    /// Fill the time between the last delivered audio and `now_hns` with silence, then report the
+    /// switch to `device_name`
+    pub unsafe fn switched(
+        &mut self,
+        now_hns: i64,
+        device_name: Option<String>,
+        send: &Sender<SendableSample>,
+    ) -> Result<()> {
+        info!(
+            "{:?} capture switched to {:?}",
+            self.source,
+            device_name.as_deref().unwrap_or("unknown device")
+        );
+        if let Some(mut start) = self.delivered_end {
+            if now_hns > start {
+                debug!("Filling {} hns of audio with silence", now_hns - start);
+            }
+            while start < now_hns {
+                let duration = (now_hns - start).min(MAX_SILENCE_CHUNK);
+                let sample = self.silence_sample(start, duration)?;
+                send.send(SendableSample::new(sample)).map_err(|_| {
+                    warn!("Failed to send silence, receiver likely dropped");
+                    windows::core::Error::from(E_FAIL)
+                })?;
+                start += duration;
+            }
+            self.delivered_end = Some(start.max(now_hns));
+        }
+        self.events.emit(RecorderEvent::DeviceChanged {
+            source: self.source,
+            device_name,
+            timestamp: hns_to_duration(now_hns.max(0)),
+        });
+        Ok(())
+    }
+
+    unsafe fn silence_sample(&self, time_hns: i64, duration_hns: i64) -> Result<IMFSample> {
+        let frames = (duration_hns * self.samples_per_sec as i64 / 10_000_000) as u32;
+        let size = frames.max(1) * self.block_align;
+
+        let media_buffer: IMFMediaBuffer = MFCreateMemoryBuffer(size)?;
+        let mut data = std::ptr::null_mut();
+        media_buffer.Lock(&mut data, None, None)?;
+        std::ptr::write_bytes(data, 0, size as usize);
+        media_buffer.SetCurrentLength(size)?;
+        media_buffer.Unlock()?;
+
+        let sample: IMFSample = MFCreateSample()?;
+        sample.AddBuffer(&media_buffer)?;
+        sample.SetSampleTime(time_hns)?;
+        sample.SetSampleDuration(duration_hns)?;
+        Ok(sample)
+    }
+}
@@ -0,0 +1,137 @@
+use crate::logging::{debug, info, warn};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use windows::Win32::Foundation::{HMODULE, HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::Performance::QueryPerformanceCounter;
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+/// How often the hook thread checks whether the recording is still running, in milliseconds
+const STOP_CHECK_INTERVAL_MS: u32 = 100;
+
+type HookProcedure = unsafe extern "system" fn(i32, WPARAM, LPARAM) -> LRESULT;
+
+/// Mouse or keyboard input seen by the low-level hooks, stamped with the QPC it arrived at
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum InputEvent {
+    /// A mouse button went down at (x, y) in pixels of the captured frame
+    Click { x: i32, y: i32, qpc: u64 },
+    /// A key went down or up; `vk` is the virtual-key code
+    Key { vk: u32, down: bool, qpc: u64 },
+}
+
+thread_local! {
+    // Where the hook procedures of this thread send their events
+    static INPUT_SINK: RefCell<Option<Sender<InputEvent>>> = RefCell::new(None);
+    // Desktop position of the captured output, subtracted from click positions
+    static FRAME_ORIGIN: Cell<(i32, i32)> = Cell::new((0, 0));
+}
+
+fn now_qpc() -> u64 {
+    let mut qpc = 0i64;
+    unsafe { QueryPerformanceCounter(&mut qpc) };
+    qpc as u64
+}
+
+fn send(event: InputEvent) {
+    INPUT_SINK.with(|sink| {
+        if let Some(sender) = sink.borrow().as_ref() {
+            // The processing thread may already be gone while stopping
+            let _ = sender.send(event);
+        }
+    });
+}
+
+unsafe extern "system" fn mouse_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if code == HC_ACTION as i32 {
+        if matches!(
+            wparam.0 as u32,
+            WM_LBUTTONDOWN | WM_RBUTTONDOWN | WM_MBUTTONDOWN | WM_XBUTTONDOWN
+        ) {
+            let info = &*(lparam.0 as *const MSLLHOOKSTRUCT);
+            let (origin_x, origin_y) = FRAME_ORIGIN.with(Cell::get);
+            send(InputEvent::Click {
+                x: info.pt.x - origin_x,
+                y: info.pt.y - origin_y,
+                qpc: now_qpc(),
+            });
+        }
+    }
+    CallNextHookEx(HHOOK::default(), code, wparam, lparam)
+}
+
+unsafe extern "system" fn keyboard_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if code == HC_ACTION as i32 {
+        let down = match wparam.0 as u32 {
+            WM_KEYDOWN | WM_SYSKEYDOWN => Some(true),
+            WM_KEYUP | WM_SYSKEYUP => Some(false),
+            _ => None,
+        };
+        if let Some(down) = down {
+            let info = &*(lparam.0 as *const KBDLLHOOKSTRUCT);
+            send(InputEvent::Key {
+                vk: info.vkCode,
+                down,
+                qpc: now_qpc(),
+            });
+        }
+    }
+    CallNextHookEx(HHOOK::default(), code, wparam, lparam)
+}
+
+/// Install low-level mouse and/or keyboard hooks on a new thread and forward what they see to
+/// `send` until `recording` is cleared; clicks are mapped into the frame of the output whose
+/// top-left corner is at `frame_origin` on the desktop
+///
+/// Low-level hooks are called on the thread that installed them, which must pump messages, so
+/// the thread runs a message loop woken by a timer to notice the end of the recording.
+pub(crate) fn spawn_input_hooks(
+    send: Sender<InputEvent>,
+    recording: Arc<AtomicBool>,
+    mouse: bool,
+    keyboard: bool,
+    frame_origin: (i32, i32),
+) {
+    std::thread::spawn(move || unsafe {
+        info!(
+            "Input hook thread started (mouse: {}, keyboard: {})",
+            mouse, keyboard
+        );
+        INPUT_SINK.with(|sink| *sink.borrow_mut() = Some(send));
+        FRAME_ORIGIN.with(|origin| origin.set(frame_origin));
+
+        let mut hooks = Vec::new();
+        for (enabled, id, procedure, name) in [
+            (mouse, WH_MOUSE_LL, mouse_hook as HookProcedure, "mouse"),
+            (keyboard, WH_KEYBOARD_LL, keyboard_hook as HookProcedure, "keyboard"),
+        ] {
+            if !enabled {
+                continue;
+            }
+            match SetWindowsHookExW(id, Some(procedure), HMODULE::default(), 0) {
+                Ok(hook) => hooks.push(hook),
+                Err(e) => warn!("Failed to install the {} hook: {:?}", name, e),
+            }
+        }
+
+        if !hooks.is_empty() {
+            let timer = SetTimer(HWND::default(), 0, STOP_CHECK_INTERVAL_MS, None);
+            let mut message = MSG::default();
+            while recording.load(Ordering::Relaxed)
+                && GetMessageW(&mut message, HWND::default(), 0, 0).as_bool()
+            {
+                DispatchMessageW(&message);
+            }
+            KillTimer(HWND::default(), timer);
+        }
+
+        for hook in hooks {
+            if !UnhookWindowsHookEx(hook).as_bool() {
+                debug!("Failed to remove an input hook");
+            }
+        }
+        INPUT_SINK.with(|sink| *sink.borrow_mut() = None);
+        info!("Input hook thread finished");
+    });
+}
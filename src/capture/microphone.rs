@@ -1,12 +1,10 @@
-use log::{debug, error, info, trace};
+use crate::logging::{debug, error, info, trace, warn};
 use std::ptr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
-use std::sync::Barrier;
-use windows::core::implement;
+use std::time::Instant;
 use windows::core::Result;
-use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
 use windows::Win32::Foundation::*;
 use windows::Win32::Media::Audio::*;
 use windows::Win32::Media::MediaFoundation::{
@@ -16,50 +14,33 @@ use windows::Win32::System::Com::*;
 use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
 use windows::Win32::System::Threading::*;
 
+use super::audio::AudioCaptureContext;
+use super::endpoint::{
+    device_friendly_name, elapsed_hns, is_device_lost, DefaultDeviceWatcher, EndpointSwitcher,
+    DEVICE_RETRY_INTERVAL,
+};
+use crate::error::ThreadSource;
 use crate::types::SendableSample;
 
-#[derive(Clone)]
-#[implement(IMMNotificationClient)]
-struct AudioEndpointVolumeCallback;
-
-impl IMMNotificationClient_Impl for AudioEndpointVolumeCallback {
-    fn OnDeviceStateChanged(
-        &self,
-        _device_id: &windows::core::PCWSTR,
-        _new_state: u32,
-    ) -> Result<()> {
-        Ok(())
-    }
-    fn OnDeviceAdded(&self, _device_id: &windows::core::PCWSTR) -> Result<()> {
-        Ok(())
-    }
-    fn OnDeviceRemoved(&self, _device_id: &windows::core::PCWSTR) -> Result<()> {
-        Ok(())
-    }
-    fn OnDefaultDeviceChanged(
-        &self,
-        _flow: EDataFlow,
-        _role: ERole,
-        _default_device_id: &windows::core::PCWSTR,
-    ) -> Result<()> {
-        Ok(())
-    }
-    fn OnPropertyValueChanged(
-        &self,
-        _device_id: &windows::core::PCWSTR,
-        _key: &windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY,
-    ) -> Result<()> {
-        Ok(())
-    }
-}
+/// Most latency compensated, so a bogus driver value cannot shift the microphone by seconds,
+/// in 100ns units
+const MAX_LATENCY_COMPENSATION_HNS: i64 = 5_000_000;
 
 pub unsafe fn collect_microphone(
     send: Sender<SendableSample>,
-    recording: Arc<AtomicBool>,
-    started: Arc<Barrier>,
-    shared_start_qpc: Option<u64>,
+    capture: AudioCaptureContext,
     device_id: Option<&str>,
+    fallback_to_default: bool,
+    hotplug: bool,
+    compensate_latency: bool,
+    attached: Arc<AtomicBool>,
 ) -> Result<()> {
+    let AudioCaptureContext {
+        recording,
+        started,
+        start_qpc: shared_start_qpc,
+        events,
+    } = capture;
     // Validate thread priority setting
     let priority_result = SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL);
     if priority_result.as_bool() == false {
@@ -81,39 +62,6 @@ pub unsafe fn collect_microphone(
         qpc_freq, ticks_to_hns
     );
 
-    // First get the microphone client to obtain its format
-    let microphone_client = match setup_microphone_client_for_device(device_id) {
-        Ok(client) => client,
-        Err(e) => {
-            info!("Failed to setup audio client: {:?}", e);
-            
-            // Wait on the barrier even when we can't get a microphone
-            // This prevents the other threads from waiting forever
-            info!("Waiting on barrier before returning from microphone thread with error");
-            started.wait();
-            info!("Barrier passed, microphone thread will now exit with error");
-            
-            return Err(e);
-        }
-    };
-    // Get the device's mix format
-    /*let mix_format_ptr = microphone_client.GetMixFormat()?;
-    let wave_format = *mix_format_ptr;
-    let format_tag = wave_format.wFormatTag;
-    let channels = wave_format.nChannels;
-    let samples_per_sec = wave_format.nSamplesPerSec;
-    let bits_per_sample = wave_format.wBitsPerSample;
-    let block_align = wave_format.nBlockAlign;
-    let avg_bytes_per_sec = wave_format.nAvgBytesPerSec;
-
-    info!("Mix Format:");
-    info!("  Format Tag: {}", format_tag);
-    info!("  Channels: {}", channels);
-    info!("  Samples Per Second: {} Hz", samples_per_sec);
-    info!("  Bits Per Sample: {}", bits_per_sample);
-    info!("  Block Align: {}", block_align);
-    info!("  Average Bytes Per Second: {}", avg_bytes_per_sec);*/
-
     // Use hard-coded format instead of getting from device
     let wave_format = WAVEFORMATEX {
         wFormatTag: WAVE_FORMAT_PCM.try_into().unwrap(),
@@ -125,10 +73,31 @@ pub unsafe fn collect_microphone(
         cbSize: 0,
     };
 
-    let capture_client: IAudioCaptureClient = match microphone_client.GetService() {
-        Ok(client) => client,
+    // Open the microphone first so a missing device is reported before recording starts
+    let mut latency_hns = 0;
+    let stream = match open_microphone(device_id, fallback_to_default) {
+        Ok((microphone_client, capture_client, _)) => {
+            if compensate_latency {
+                latency_hns = capture_latency_hns(&microphone_client);
+            }
+            Some((microphone_client, capture_client))
+        }
+        Err(e) if hotplug => {
+            // Keep the thread alive and attach once a microphone is plugged in; until then the
+            // mixer passes system audio through on its own
+            info!("No microphone available yet, waiting for one to be plugged in: {:?}", e);
+            attached.store(false, Ordering::Relaxed);
+            None
+        }
         Err(e) => {
-            info!("Failed to get capture client: {:?}", e);
+            info!("Failed to setup audio client: {:?}", e);
+            
+            // Wait on the barrier even when we can't get a microphone
+            // This prevents the other threads from waiting forever
+            info!("Waiting on barrier before returning from microphone thread with error");
+            started.wait();
+            info!("Barrier passed, microphone thread will now exit with error");
+            
             return Err(e);
         }
     };
@@ -175,19 +144,61 @@ pub unsafe fn collect_microphone(
     let mut zero_packet_count = 0;
     let mut total_packets = 0;
 
-    match microphone_client.Start() {
-        Ok(_) => info!("Audio client started successfully"),
+    // Follow the default microphone unless a specific one was requested
+    let follow_default = device_id.is_none();
+    let watcher = match DefaultDeviceWatcher::new(eCapture) {
+        Ok(watcher) => Some(watcher),
         Err(e) => {
-            info!("Failed to start audio client: {:?}", e);
-            return Err(e);
+            info!("Failed to watch for default microphone changes: {:?}", e);
+            None
         }
-    }
+    };
+    let mut switcher = EndpointSwitcher::new(ThreadSource::Microphone, events, &wave_format);
+    let mut stream = stream;
+    let mut last_attempt = Instant::now();
 
     started.wait();
 
     while recording.load(Ordering::Relaxed) {
+        let default_changed = watcher.as_ref().is_some_and(|w| w.take_changed());
+        if stream.is_none() || (follow_default && default_changed) {
+            if stream.is_some() {
+                info!("Default microphone changed, switching");
+            } else if last_attempt.elapsed() < DEVICE_RETRY_INTERVAL {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+            if let Some((client, _)) = stream.take() {
+                let _ = client.Stop();
+            }
+            last_attempt = Instant::now();
+            match open_microphone(device_id, fallback_to_default) {
+                Ok((client, capture, device_name)) => {
+                    if compensate_latency {
+                        latency_hns = capture_latency_hns(&client);
+                    }
+                    let now_hns = elapsed_hns(start_qpc, ticks_to_hns);
+                    switcher.switched(now_hns, device_name, &send)?;
+                    stream = Some((client, capture));
+                    attached.store(true, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    debug!("No microphone to switch to yet: {:?}", e);
+                    continue;
+                }
+            }
+        }
+        let Some((_, capture_client)) = stream.as_ref() else {
+            continue;
+        };
+
         let next_packet_size = match capture_client.GetNextPacketSize() {
             Ok(size) => size,
+            Err(e) if is_device_lost(&e) => {
+                warn!("Microphone was removed, waiting for another one: {:?}", e);
+                stream = None;
+                continue;
+            }
             Err(e) => {
                 info!("Failed to get next packet size: {:?}", e);
                 return Err(e);
@@ -213,16 +224,19 @@ pub unsafe fn collect_microphone(
             ) {
                 Ok(_) => {
                     if qpc_position <= last_packet_time {
-                        info!(
-                            "QPC time went backwards: current={}, last={}",
-                            qpc_position, last_packet_time
+                        debug!(
+                            qpc = qpc_position,
+                            last_qpc = last_packet_time,
+                            "QPC time went backwards"
                         );
                     }
                     last_packet_time = qpc_position;
 
                     if (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) == 0 {
                         let relative_qpc = qpc_position - start_qpc;
-                        let time_hns = (relative_qpc as f64 * ticks_to_hns) as i64;
+                        // The sound reached the microphone that much before the packet was stamped
+                        let time_hns =
+                            ((relative_qpc as f64 * ticks_to_hns) as i64 - latency_hns).max(0);
 
                         match create_microphone_sample(
                             buffer,
@@ -236,6 +250,7 @@ pub unsafe fn collect_microphone(
                                     info!("Failed to send audio sample, receiver likely dropped: {:?}", e);
                                     return Err(E_FAIL.into());
                                 }
+                                switcher.delivered(time_hns, num_frames_available);
                             }
                             Err(e) => {
                                 info!("Failed to create audio sample: {:?}", e);
@@ -246,12 +261,20 @@ pub unsafe fn collect_microphone(
 
                     match capture_client.ReleaseBuffer(num_frames_available) {
                         Ok(_) => {}
+                        Err(e) if is_device_lost(&e) => {
+                            warn!("Microphone was removed, waiting for another one: {:?}", e);
+                            stream = None;
+                        }
                         Err(e) => {
                             info!("Failed to release buffer: {:?}", e);
                             return Err(e);
                         }
                     }
                 }
+                Err(e) if is_device_lost(&e) => {
+                    warn!("Microphone was removed, waiting for another one: {:?}", e);
+                    stream = None;
+                }
                 Err(e) => {
                     info!("Failed to get buffer: {:?}", e);
                     return Err(e);
@@ -260,7 +283,8 @@ pub unsafe fn collect_microphone(
         } else {
             zero_packet_count += 1;
             if zero_packet_count >= 1000 {
-                info!(
+                debug!(
+                    checks = zero_packet_count,
                     "No audio data received for {} consecutive checks",
                     zero_packet_count
                 );
@@ -274,15 +298,67 @@ pub unsafe fn collect_microphone(
         "Recording stopped. Total packets processed: {}",
         total_packets
     );
-    match microphone_client.Stop() {
-        Ok(_) => info!("Audio client stopped successfully"),
-        Err(e) => info!("Error stopping audio client: {:?}", e),
+    if let Some((microphone_client, _)) = stream {
+        match microphone_client.Stop() {
+            Ok(_) => info!("Audio client stopped successfully"),
+            Err(e) => info!("Error stopping audio client: {:?}", e),
+        }
     }
 
     Ok(())
 }
 
-unsafe fn setup_microphone_client_for_device(device_id: Option<&str>) -> Result<IAudioClient> {
+/// Delay between sound reaching the microphone and WASAPI stamping its packet, in 100ns units:
+/// the stream latency plus one device period, capped at `MAX_LATENCY_COMPENSATION_HNS`
+unsafe fn capture_latency_hns(client: &IAudioClient) -> i64 {
+    let stream_latency = client.GetStreamLatency().unwrap_or_else(|e| {
+        debug!("Failed to get microphone stream latency: {:?}", e);
+        0
+    });
+    let mut period = 0;
+    if let Err(e) = client.GetDevicePeriod(Some(&mut period), None) {
+        debug!("Failed to get microphone device period: {:?}", e);
+    }
+    let latency = (stream_latency + period).clamp(0, MAX_LATENCY_COMPENSATION_HNS);
+    info!(
+        "Compensating {:.1} ms of microphone latency (stream {:.1} ms, period {:.1} ms)",
+        latency as f64 / 10_000.0,
+        stream_latency as f64 / 10_000.0,
+        period as f64 / 10_000.0
+    );
+    latency
+}
+
+/// Set up and start capturing from a microphone, returning its name as well
+unsafe fn open_microphone(
+    device_id: Option<&str>,
+    fallback_to_default: bool,
+) -> Result<(IAudioClient, IAudioCaptureClient, Option<String>)> {
+    let (microphone_client, device_name) =
+        setup_microphone_client_for_device(device_id, fallback_to_default)?;
+
+    let capture_client: IAudioCaptureClient = match microphone_client.GetService() {
+        Ok(client) => client,
+        Err(e) => {
+            info!("Failed to get capture client: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    match microphone_client.Start() {
+        Ok(_) => info!("Audio client started successfully"),
+        Err(e) => {
+            info!("Failed to start audio client: {:?}", e);
+            return Err(e);
+        }
+    }
+    Ok((microphone_client, capture_client, device_name))
+}
+
+unsafe fn setup_microphone_client_for_device(
+    device_id: Option<&str>,
+    fallback_to_default: bool,
+) -> Result<(IAudioClient, Option<String>)> {
     // Initialize COM if not already initialized
     let coinit_result = CoInitializeEx(None, COINIT_MULTITHREADED);
     match coinit_result {
@@ -311,6 +387,10 @@ unsafe fn setup_microphone_client_for_device(device_id: Option<&str>) -> Result<
             windows::core::HSTRING::from(id).as_ptr(),
         )) {
             Ok(dev) => dev,
+            Err(e) if !fallback_to_default => {
+                info!("Failed to get device with ID {}: {:?}", id, e);
+                return Err(e);
+            }
             Err(e) => {
                 info!(
                     "Failed to get device with ID {}: {:?}. Falling back to default.",
@@ -356,24 +436,9 @@ unsafe fn setup_microphone_client_for_device(device_id: Option<&str>) -> Result<
         info!("Selected audio input device ID: {}", id);
     }
 
-    if let Ok(props) = device.OpenPropertyStore(STGM_READ) {
-        if let Ok(prop_value) = props.GetValue(&PKEY_Device_FriendlyName) {
-            let name = prop_value
-                .Anonymous
-                .Anonymous
-                .Anonymous
-                .pwszVal
-                .to_string()
-                .unwrap_or_default();
-            info!("Selected audio input device name: {}", name);
-        }
-    }
-
-    let callback = AudioEndpointVolumeCallback;
-    let callback_interface: IMMNotificationClient = callback.into();
-    if let Err(e) = enumerator.RegisterEndpointNotificationCallback(&callback_interface) {
-        info!("Failed to register callback: {:?}", e);
-        return Err(e);
+    let device_name = device_friendly_name(&device);
+    if let Some(name) = &device_name {
+        info!("Selected audio input device name: {}", name);
     }
 
     let audio_client: IAudioClient = match device.Activate(CLSCTX_ALL, None) {
@@ -419,7 +484,7 @@ unsafe fn setup_microphone_client_for_device(device_id: Option<&str>) -> Result<
         Ok(_) => {
             let event = CreateEventW(None, false, false, None)?;
             audio_client.SetEventHandle(event)?;
-            Ok(audio_client)
+            Ok((audio_client, device_name))
         }
         Err(e) => {
             info!("Failed to initialize audio client: {:?}", e);
@@ -1,12 +1,27 @@
 mod audio;
 mod dxgi;
+mod endpoint;
+mod input;
 mod video;
 pub mod window;
 mod microphone;
 mod monitor;
+pub mod scene;
+pub mod source;
+#[cfg(feature = "synthetic")]
+pub mod synthetic;
+pub mod timing;
+mod webcam;
 
-pub use audio::collect_audio;
+pub use audio::{collect_audio, AudioCaptureContext, AudioPacket};
+pub(crate) use audio::process_loopback_supported;
 pub use microphone::collect_microphone;
-pub use video::get_frames;
-pub use window::{get_window_by_string, get_window_by_exact_string};
-pub use monitor::{get_primary_monitor_resolution, get_window_monitor_resolution};
+pub use video::{get_frames, WindowCaptureContext};
+pub use monitor::{
+    get_primary_monitor_resolution, get_window_monitor_name, get_window_monitor_resolution,
+};
+pub(crate) use dxgi::{
+    adapter_luid, capture_origin, capture_output, duplicate_output, window_adapter_changed,
+    window_adapter_luid,
+};
+pub(crate) use input::{spawn_input_hooks, InputEvent};
@@ -1,4 +1,4 @@
-use log::info;
+use crate::logging::info;
 use windows::Win32::Foundation::{HWND, POINT, RECT};
 use windows::Win32::Graphics::Gdi::{GetMonitorInfoW, MonitorFromWindow, MONITORINFOEXW, MONITOR_DEFAULTTOPRIMARY};
 use windows::Win32::UI::WindowsAndMessaging::{GetDesktopWindow, MONITORINFOF_PRIMARY};
@@ -34,6 +34,27 @@ pub fn get_primary_monitor_resolution() -> (u32, u32) {
     }
 }
 
+/// Gets the device name (e.g. `\\.\DISPLAY1`) of the monitor that contains the specified window
+pub fn get_window_monitor_name(hwnd: HWND) -> String {
+    unsafe {
+        let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTOPRIMARY);
+
+        let mut monitor_info: MONITORINFOEXW = Default::default();
+        monitor_info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+
+        if GetMonitorInfoW(monitor, &mut monitor_info as *mut MONITORINFOEXW as *mut _).as_bool() {
+            let length = monitor_info
+                .szDevice
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(monitor_info.szDevice.len());
+            String::from_utf16_lossy(&monitor_info.szDevice[..length])
+        } else {
+            String::new()
+        }
+    }
+}
+
 /// Gets the resolution of the monitor that contains the specified window
 /// Returns a tuple of (width, height)
 pub fn get_window_monitor_resolution(hwnd: HWND) -> (u32, u32) {
@@ -58,4 +79,17 @@ pub fn get_window_monitor_resolution(hwnd: HWND) -> (u32, u32) {
             get_primary_monitor_resolution()
         }
     }
-}
\ No newline at end of file
+}
+/// Gets the desktop rectangle of the monitor that contains the specified window
+pub fn get_window_monitor_rect(hwnd: HWND) -> Option<RECT> {
+    unsafe {
+        let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTOPRIMARY);
+
+        let mut monitor_info: MONITORINFOEXW = Default::default();
+        monitor_info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+
+        GetMonitorInfoW(monitor, &mut monitor_info as *mut MONITORINFOEXW as *mut _)
+            .as_bool()
+            .then_some(monitor_info.monitorInfo.rcMonitor)
+    }
+}
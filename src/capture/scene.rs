@@ -0,0 +1,508 @@
+use crate::logging::{debug, info, trace, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
+use windows::core::{ComInterface, Result};
+use windows::Win32::Foundation::{HWND, RECT};
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
+use windows::Win32::Graphics::Dxgi::{
+    IDXGIAdapter, IDXGIDevice, IDXGIOutput1, IDXGIOutputDuplication, IDXGIResource,
+    DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_WAIT_TIMEOUT, DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTPUT_DESC,
+};
+
+use super::source::{FrameInjector, FrameSource};
+use super::timing::{FramePacer, FramePacing};
+use super::webcam::WebcamReader;
+use super::window::{get_capture_region, WindowCorners};
+use crate::processing::compositor::{CompositeLayer, SceneCompositor};
+use crate::types::FrameClock;
+
+/// What a scene layer shows
+#[derive(Clone)]
+pub enum LayerSource {
+    /// A window, cut out of the monitor it is on and following it as it moves
+    Window(HWND),
+    /// A whole monitor, by its output index on the recording device's adapter
+    Monitor(u32),
+    /// A fixed rectangle of the virtual desktop, in physical pixels
+    Region(RECT),
+    /// The webcam whose name contains the given text, or the first one found
+    Webcam(Option<String>),
+    /// Frames pulled from an application-provided source; the latest one stays on screen
+    Custom(Arc<Mutex<dyn FrameSource>>),
+}
+
+impl fmt::Debug for LayerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Window(hwnd) => write!(f, "Window({:?})", hwnd),
+            Self::Monitor(index) => write!(f, "Monitor({})", index),
+            Self::Region(rect) => write!(f, "Region({:?})", rect),
+            Self::Webcam(name) => write!(f, "Webcam({:?})", name),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// One source placed on the scene canvas
+#[derive(Clone, Debug)]
+pub struct SceneLayer {
+    pub source: LayerSource,
+    /// Top-left corner on the canvas; may be negative or past the canvas to show part of it
+    pub position: (i32, i32),
+    /// Size of the layer relative to the source's own pixels
+    pub scale: f32,
+    /// Layers with a higher z-order are drawn over lower ones
+    pub z_order: i32,
+}
+
+/// Several capture sources composited into one output frame on the GPU
+#[derive(Clone, Debug)]
+pub struct Scene {
+    width: u32,
+    height: u32,
+    /// Layers sorted bottom to top
+    layers: Vec<SceneLayer>,
+}
+
+impl Scene {
+    /// Start a scene with a `width` x `height` canvas
+    pub fn builder(width: u32, height: u32) -> SceneBuilder {
+        SceneBuilder {
+            width,
+            height,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    /// The layers, bottom to top
+    pub fn layers(&self) -> &[SceneLayer] {
+        &self.layers
+    }
+}
+
+/// Builds a [`Scene`] layer by layer
+pub struct SceneBuilder {
+    width: u32,
+    height: u32,
+    layers: Vec<SceneLayer>,
+}
+
+impl SceneBuilder {
+    /// Add `source` at `position` on the canvas, scaled by `scale`
+    ///
+    /// Layers with the same `z_order` are drawn in the order they were added.
+    pub fn layer(
+        mut self,
+        source: LayerSource,
+        position: (i32, i32),
+        scale: f32,
+        z_order: i32,
+    ) -> Self {
+        self.layers.push(SceneLayer {
+            source,
+            position,
+            scale,
+            z_order,
+        });
+        self
+    }
+
+    pub fn build(mut self) -> Scene {
+        self.layers.sort_by_key(|layer| layer.z_order);
+        Scene {
+            width: self.width,
+            height: self.height,
+            layers: self.layers,
+        }
+    }
+}
+
+/// An output of the recording adapter, duplicated once a layer shows part of it
+struct DesktopOutput {
+    output: IDXGIOutput1,
+    /// Desktop coordinates of the output
+    bounds: RECT,
+    duplication: Option<IDXGIOutputDuplication>,
+    /// Copy of the latest desktop image, kept when no new frame arrives
+    texture: Option<ID3D11Texture2D>,
+    /// Whether the copy was refreshed for the frame being composed
+    refreshed: bool,
+}
+
+/// What a layer draws from, with the texture it last produced
+enum LayerInput {
+    /// A window, monitor or region read from the output duplications
+    Desktop,
+    Webcam {
+        reader: WebcamReader,
+        texture: Option<ID3D11Texture2D>,
+        sequence: u64,
+    },
+    Custom {
+        source: Arc<Mutex<dyn FrameSource>>,
+        texture: Option<ID3D11Texture2D>,
+    },
+}
+
+/// The recording a scene is composed for
+pub(crate) struct SceneCaptureContext {
+    pub device: Arc<ID3D11Device>,
+    pub context_mutex: Arc<Mutex<ID3D11DeviceContext>>,
+    /// Feeds composed frames into the pipeline
+    pub injector: Arc<FrameInjector>,
+    pub fps_num: u32,
+    pub fps_den: u32,
+    pub pacing: FramePacing,
+    /// Cleared when the recording stops
+    pub recording: Arc<AtomicBool>,
+    /// Passed once every capture thread is ready
+    pub started: Arc<Barrier>,
+}
+
+/// Composite the layers of `scene` at the frame rate and feed the result into the pipeline until
+/// the recording stops
+pub(crate) fn compose_scene(scene: Scene, capture: SceneCaptureContext) -> Result<()> {
+    let SceneCaptureContext {
+        device,
+        context_mutex,
+        injector,
+        fps_num,
+        fps_den,
+        pacing,
+        recording,
+        started,
+    } = capture;
+    started.wait();
+    info!(
+        "Composing a {}x{} scene of {} layer(s)",
+        scene.width,
+        scene.height,
+        scene.layers.len()
+    );
+
+    let compositor = unsafe { SceneCompositor::new(&device, scene.width, scene.height) }?;
+    let mut outputs = unsafe { enumerate_outputs(&device) }?;
+    let mut inputs = Vec::with_capacity(scene.layers.len());
+    for layer in &scene.layers {
+        inputs.push(match &layer.source {
+            LayerSource::Webcam(name) => LayerInput::Webcam {
+                reader: WebcamReader::start(name.clone())?,
+                texture: None,
+                sequence: 0,
+            },
+            LayerSource::Custom(source) => LayerInput::Custom {
+                source: source.clone(),
+                texture: None,
+            },
+            _ => LayerInput::Desktop,
+        });
+    }
+
+    let pacer = FramePacer::new(pacing);
+    let start = Instant::now();
+    let mut clock = FrameClock::new(fps_num, fps_den);
+    while recording.load(Ordering::Relaxed) {
+        for output in outputs.iter_mut() {
+            output.refreshed = false;
+        }
+
+        let mut placed = Vec::with_capacity(scene.layers.len());
+        for (layer, input) in scene.layers.iter().zip(inputs.iter_mut()) {
+            let drawn =
+                unsafe { update_layer(layer, input, &device, &context_mutex, &mut outputs) };
+            match drawn {
+                Ok(Some((texture, source_rect, offset))) => {
+                    placed.push((layer, texture, source_rect, offset))
+                }
+                Ok(None) => trace!("Scene layer {:?} has nothing to show", layer.source),
+                Err(e) => warn!("Failed to update scene layer {:?}: {:?}", layer.source, e),
+            }
+        }
+
+        let layers: Vec<CompositeLayer> = placed
+            .iter()
+            .map(|(layer, texture, source_rect, offset)| CompositeLayer {
+                texture,
+                source_rect: *source_rect,
+                position: (
+                    layer.position.0 + (offset.0 as f32 * layer.scale).round() as i32,
+                    layer.position.1 + (offset.1 as f32 * layer.scale).round() as i32,
+                ),
+                scale: layer.scale,
+            })
+            .collect();
+        let result = unsafe {
+            compositor
+                .compose(&layers)
+                .and_then(|canvas| injector.submit(canvas, start.elapsed()))
+        };
+        if let Err(e) = result {
+            // The processing thread is gone once the recording stops
+            if !recording.load(Ordering::Relaxed) {
+                break;
+            }
+            return Err(e);
+        }
+
+        clock.advance();
+        let now = Instant::now();
+        if clock.due() > now {
+            pacer.sleep(clock.due() - now);
+        } else {
+            // Behind schedule; skip the missed frames instead of bursting to catch up
+            clock.skip_to(now);
+        }
+    }
+    debug!("Scene composition finished");
+    Ok(())
+}
+
+/// The outputs of the device's adapter
+unsafe fn enumerate_outputs(device: &ID3D11Device) -> Result<Vec<DesktopOutput>> {
+    let dxgi_device: IDXGIDevice = device.cast()?;
+    let adapter: IDXGIAdapter = dxgi_device.GetAdapter()?;
+    let mut outputs = Vec::new();
+    let mut index = 0;
+    while let Ok(output) = adapter.EnumOutputs(index) {
+        let mut desc = DXGI_OUTPUT_DESC::default();
+        output.GetDesc(&mut desc)?;
+        debug!("Scene output {}: {:?}", index, desc.DesktopCoordinates);
+        outputs.push(DesktopOutput {
+            output: output.cast()?,
+            bounds: desc.DesktopCoordinates,
+            duplication: None,
+            texture: None,
+            refreshed: false,
+        });
+        index += 1;
+    }
+    Ok(outputs)
+}
+
+/// A layer's texture, the region of it to draw, and how far that region starts from the layer's
+/// own top-left corner
+type LayerFrame = (ID3D11Texture2D, RECT, (i32, i32));
+
+/// Bring the texture of `layer` up to date
+///
+/// Returns the texture with the region of it to draw and how far that region starts from the
+/// layer's own top-left corner, or None if the layer has nothing to show this frame.
+unsafe fn update_layer(
+    layer: &SceneLayer,
+    input: &mut LayerInput,
+    device: &ID3D11Device,
+    context_mutex: &Mutex<ID3D11DeviceContext>,
+    outputs: &mut [DesktopOutput],
+) -> Result<Option<LayerFrame>> {
+    match input {
+        LayerInput::Desktop => {
+            let (rect, index) = match &layer.source {
+                LayerSource::Monitor(index) => match outputs.get(*index as usize) {
+                    Some(output) => (output.bounds, *index as usize),
+                    None => {
+                        warn!(
+                            "Scene monitor {} does not exist on the recording adapter",
+                            index
+                        );
+                        return Ok(None);
+                    }
+                },
+                LayerSource::Window(hwnd) => {
+                    // Minimized windows leave their layer empty
+                    let Some(region) = get_capture_region(*hwnd, false, WindowCorners::Keep) else {
+                        return Ok(None);
+                    };
+                    let (x, y, width, height) = region.rect;
+                    let rect = RECT {
+                        left: x,
+                        top: y,
+                        right: x + width as i32,
+                        bottom: y + height as i32,
+                    };
+                    match output_containing(outputs, &rect) {
+                        Some(index) => (rect, index),
+                        None => return Ok(None),
+                    }
+                }
+                LayerSource::Region(rect) => match output_containing(outputs, rect) {
+                    Some(index) => (*rect, index),
+                    None => return Ok(None),
+                },
+                _ => return Ok(None),
+            };
+
+            let output = &mut outputs[index];
+            if !output.refreshed {
+                refresh_output(output, device, context_mutex)?;
+                output.refreshed = true;
+            }
+            let Some(texture) = output.texture.clone() else {
+                return Ok(None);
+            };
+
+            // Only the part of the rect on this output is drawn, shifted to where it belongs
+            let bounds = output.bounds;
+            let source_rect = RECT {
+                left: rect.left.max(bounds.left) - bounds.left,
+                top: rect.top.max(bounds.top) - bounds.top,
+                right: rect.right.min(bounds.right) - bounds.left,
+                bottom: rect.bottom.min(bounds.bottom) - bounds.top,
+            };
+            if source_rect.right <= source_rect.left || source_rect.bottom <= source_rect.top {
+                return Ok(None);
+            }
+            let offset = (
+                source_rect.left + bounds.left - rect.left,
+                source_rect.top + bounds.top - rect.top,
+            );
+            Ok(Some((texture, source_rect, offset)))
+        }
+        LayerInput::Webcam {
+            reader,
+            texture,
+            sequence,
+        } => {
+            let latest = reader.latest().lock().unwrap();
+            let Some(frame) = latest.as_ref() else {
+                return Ok(None);
+            };
+            if frame.sequence != *sequence {
+                let target = layer_texture(texture, device, frame.width, frame.height)?;
+                let context = context_mutex.lock().unwrap();
+                context.UpdateSubresource(
+                    &target,
+                    0,
+                    None,
+                    frame.data.as_ptr() as *const _,
+                    frame.width * 4,
+                    0,
+                );
+                *sequence = frame.sequence;
+            }
+            Ok(texture
+                .clone()
+                .map(|texture| (texture, full_rect(frame.width, frame.height), (0, 0))))
+        }
+        LayerInput::Custom { source, texture } => {
+            let frame = source.lock().unwrap().next_frame(Duration::ZERO);
+            if let Some(frame) = frame {
+                let mut desc = D3D11_TEXTURE2D_DESC::default();
+                frame.texture.GetDesc(&mut desc);
+                let target = layer_texture(texture, device, desc.Width, desc.Height)?;
+                let context = context_mutex.lock().unwrap();
+                context.CopySubresourceRegion(&target, 0, 0, 0, 0, &frame.texture, 0, None);
+            }
+            Ok(texture.clone().map(|texture| {
+                let mut desc = D3D11_TEXTURE2D_DESC::default();
+                texture.GetDesc(&mut desc);
+                (texture, full_rect(desc.Width, desc.Height), (0, 0))
+            }))
+        }
+    }
+}
+
+/// Index of the output the center of `rect` is on
+fn output_containing(outputs: &[DesktopOutput], rect: &RECT) -> Option<usize> {
+    let center_x = (rect.left + rect.right) / 2;
+    let center_y = (rect.top + rect.bottom) / 2;
+    outputs.iter().position(|output| {
+        let bounds = output.bounds;
+        center_x >= bounds.left
+            && center_x < bounds.right
+            && center_y >= bounds.top
+            && center_y < bounds.bottom
+    })
+}
+
+/// Copy the latest desktop image of `output`, duplicating it on first use
+unsafe fn refresh_output(
+    output: &mut DesktopOutput,
+    device: &ID3D11Device,
+    context_mutex: &Mutex<ID3D11DeviceContext>,
+) -> Result<()> {
+    if output.duplication.is_none() {
+        info!("Duplicating scene output at {:?}", output.bounds);
+        output.duplication = Some(output.output.DuplicateOutput(device)?);
+    }
+    let duplication = output.duplication.as_ref().unwrap();
+
+    let mut frame_info = DXGI_OUTDUPL_FRAME_INFO::default();
+    let mut resource: Option<IDXGIResource> = None;
+    match duplication.AcquireNextFrame(0, &mut frame_info, &mut resource) {
+        Ok(()) => {}
+        // Nothing changed on the output; the last copy is still current
+        Err(e) if e.code() == DXGI_ERROR_WAIT_TIMEOUT => return Ok(()),
+        Err(e) if e.code() == DXGI_ERROR_ACCESS_LOST => {
+            debug!("Scene output duplication lost, recreating it next frame");
+            output.duplication = None;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+
+    let result = (|| {
+        let frame: ID3D11Texture2D = resource.unwrap().cast()?;
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        frame.GetDesc(&mut desc);
+        let target = layer_texture(&mut output.texture, device, desc.Width, desc.Height)?;
+        let context = context_mutex.lock().unwrap();
+        context.CopyResource(&target, &frame);
+        Ok(())
+    })();
+    duplication.ReleaseFrame()?;
+    result
+}
+
+/// The texture in `slot`, (re)created when it is not `width` x `height`
+unsafe fn layer_texture(
+    slot: &mut Option<ID3D11Texture2D>,
+    device: &ID3D11Device,
+    width: u32,
+    height: u32,
+) -> Result<ID3D11Texture2D> {
+    if let Some(texture) = slot {
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        if desc.Width == width && desc.Height == height {
+            return Ok(texture.clone());
+        }
+    }
+
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: width,
+        Height: height,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_SHADER_RESOURCE,
+        CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+        MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+    };
+    let mut texture = None;
+    device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+    *slot = texture;
+    Ok(slot.clone().unwrap())
+}
+
+fn full_rect(width: u32, height: u32) -> RECT {
+    RECT {
+        left: 0,
+        top: 0,
+        right: width as i32,
+        bottom: height as i32,
+    }
+}
@@ -0,0 +1,276 @@
+use crate::logging::{debug, info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::Duration;
+use windows::core::{ComInterface, Error, Result};
+use windows::Win32::Foundation::{E_FAIL, E_INVALIDARG, TRUE};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BOX, D3D11_TEXTURE2D_DESC,
+};
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM;
+use windows::Win32::Graphics::Dxgi::IDXGISurface;
+use windows::Win32::Media::MediaFoundation::{
+    MFCreateDXGISurfaceBuffer, MFSampleExtension_DeviceTimestamp,
+};
+use windows::Win32::System::Performance::QueryPerformanceCounter;
+
+use super::scene::Scene;
+use crate::types::{
+    duration_to_hns, frame_time_hns, BufferPoolSizes, FrameCounters, FrameSender, MemoryUsage,
+    PoolStats, SamplePool, SendableSample, TexturePool, WindowGeometry,
+};
+
+/// How long the capture thread waits for a custom source before checking whether to stop
+const SOURCE_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A frame rendered by the application
+pub struct SourceFrame {
+    /// BGRA (`DXGI_FORMAT_B8G8R8A8_UNORM`) texture on the recording device
+    pub texture: ID3D11Texture2D,
+    /// Time since the recording started
+    pub timestamp: Duration,
+}
+
+/// Supplies the frames to record in place of desktop duplication
+pub trait FrameSource: Send {
+    /// Wait up to `timeout` for the next frame; None if there is none yet
+    ///
+    /// The texture is copied before the next call, so the source may reuse it afterwards.
+    fn next_frame(&mut self, timeout: Duration) -> Option<SourceFrame>;
+}
+
+/// Where the recorded video frames come from
+#[derive(Clone, Default)]
+pub enum VideoSource {
+    /// Capture the target window with DXGI desktop duplication
+    #[default]
+    Duplication,
+    /// Frames pushed by the application with `Recorder::submit_frame`
+    Submitted,
+    /// Frames pulled from an application-provided source on the capture thread
+    Custom(Arc<Mutex<dyn FrameSource>>),
+    /// Several windows, monitors, regions and webcams composited on the GPU; the scene's
+    /// canvas size is the capture size
+    Scene(Scene),
+}
+
+impl VideoSource {
+    pub fn custom(source: impl FrameSource + 'static) -> Self {
+        Self::Custom(Arc::new(Mutex::new(source)))
+    }
+}
+
+impl fmt::Debug for VideoSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplication => write!(f, "Duplication"),
+            Self::Submitted => write!(f, "Submitted"),
+            Self::Custom(_) => write!(f, "Custom"),
+            Self::Scene(scene) => write!(f, "Scene({:?})", scene),
+        }
+    }
+}
+
+struct InjectorState {
+    send: FrameSender,
+    window_info_sender: Sender<WindowGeometry>,
+    /// Size of the last frame, sent to the processing thread as the window size when it changes
+    size: Option<(u32, u32)>,
+    last_timestamp: Option<i64>,
+}
+
+/// Copies application frames into pooled textures and hands them to the processing thread
+pub(crate) struct FrameInjector {
+    device: Arc<ID3D11Device>,
+    context_mutex: Arc<Mutex<ID3D11DeviceContext>>,
+    texture_pool: Arc<TexturePool>,
+    sample_pool: Arc<SamplePool>,
+    input_width: u32,
+    input_height: u32,
+    frame_duration: i64,
+    counters: Arc<FrameCounters>,
+    state: Mutex<InjectorState>,
+}
+
+// The device is multithread protected and the context is only used under its mutex
+unsafe impl Send for FrameInjector {}
+unsafe impl Sync for FrameInjector {}
+
+/// The recording a [`FrameInjector`] feeds
+pub(crate) struct InjectorContext {
+    pub device: Arc<ID3D11Device>,
+    pub context_mutex: Arc<Mutex<ID3D11DeviceContext>>,
+    /// Capture size, larger frames are cropped to it
+    pub input_width: u32,
+    pub input_height: u32,
+    pub fps_num: u32,
+    pub fps_den: u32,
+    /// Frames to the processing thread
+    pub send: FrameSender,
+    /// Frame size to the processing thread whenever it changes
+    pub window_info_sender: Sender<WindowGeometry>,
+    pub counters: Arc<FrameCounters>,
+    pub pool_sizes: BufferPoolSizes,
+    pub pool_stats: Arc<Mutex<PoolStats>>,
+    pub memory_usage: Arc<Mutex<MemoryUsage>>,
+}
+
+impl FrameInjector {
+    pub fn new(context: InjectorContext) -> Result<Self> {
+        let InjectorContext {
+            device,
+            context_mutex,
+            input_width,
+            input_height,
+            fps_num,
+            fps_den,
+            send,
+            window_info_sender,
+            counters,
+            pool_sizes,
+            pool_stats,
+            memory_usage,
+        } = context;
+        info!(
+            "Recording application frames at {}x{}",
+            input_width, input_height
+        );
+        let mut texture_pool = TexturePool::new(
+            device.clone(),
+            pool_sizes.capture_textures,
+            0,
+            input_width,
+            input_height,
+            DXGI_FORMAT_B8G8R8A8_UNORM,
+        )?;
+        texture_pool.set_acquire_timeout(pool_sizes.acquire_timeout);
+        texture_pool.set_stats_sink(pool_stats);
+        texture_pool.set_memory_sink(memory_usage);
+        Ok(Self {
+            device,
+            context_mutex,
+            texture_pool: Arc::new(texture_pool),
+            sample_pool: Arc::new(SamplePool::new(fps_num, fps_den, 10)),
+            input_width,
+            input_height,
+            frame_duration: frame_time_hns(1, fps_num, fps_den),
+            counters,
+            state: Mutex::new(InjectorState {
+                send,
+                window_info_sender,
+                size: None,
+                last_timestamp: None,
+            }),
+        })
+    }
+
+    /// Copy `texture` and queue it for encoding at `timestamp`
+    ///
+    /// Frames that do not come after the previous one are dropped. A texture larger than the
+    /// capture size is cropped to its top-left corner.
+    pub unsafe fn submit(&self, texture: &ID3D11Texture2D, timestamp: Duration) -> Result<()> {
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        if desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM {
+            return Err(Error::new(
+                E_INVALIDARG,
+                format!("Submitted frames must be BGRA, got {:?}", desc.Format).into(),
+            ));
+        }
+        if texture.GetDevice()? != *self.device {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Submitted frames must be created on the recording device; pass it with \
+                 external_device"
+                    .into(),
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let time = duration_to_hns(timestamp);
+        if state.last_timestamp.is_some_and(|last| time <= last) {
+            debug!("Dropping submitted frame at {:?}, not after the previous one", timestamp);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let width = desc.Width.min(self.input_width);
+        let height = desc.Height.min(self.input_height);
+        if state.size != Some((width, height)) {
+            info!("Submitted frame size is now {}x{}", width, height);
+            state.size = Some((width, height));
+            if let Err(e) = state
+                .window_info_sender
+                .send((Some((0, 0)), Some((width, height))))
+            {
+                warn!("Failed to send submitted frame size: {:?}", e);
+            }
+        }
+
+        let Some(pooled) = self.texture_pool.acquire_acquisition_texture() else {
+            debug!("Dropping submitted frame at {:?}, no free capture texture", timestamp);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        };
+        let pooled_texture = pooled.texture();
+        {
+            let context = self.context_mutex.lock().unwrap();
+            let region = D3D11_BOX {
+                left: 0,
+                top: 0,
+                front: 0,
+                right: width,
+                bottom: height,
+                back: 1,
+            };
+            context.CopySubresourceRegion(pooled_texture, 0, 0, 0, 0, texture, 0, Some(&region));
+        }
+        self.counters.captured.fetch_add(1, Ordering::Relaxed);
+
+        let sample = self.sample_pool.acquire_sample()?;
+        let surface: IDXGISurface = pooled_texture.cast()?;
+        let buffer = MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &surface, 0, TRUE)?;
+        sample.RemoveAllBuffers()?;
+        sample.AddBuffer(&buffer)?;
+        sample.SetSampleTime(time)?;
+        sample.SetSampleDuration(self.frame_duration)?;
+        let mut capture_qpc = 0i64;
+        QueryPerformanceCounter(&mut capture_qpc);
+        sample.SetUINT64(&MFSampleExtension_DeviceTimestamp, capture_qpc as u64)?;
+
+        state.last_timestamp = Some(time);
+        let sendable = SendableSample::new_pooled(sample, self.sample_pool.clone())
+            .holding(Some(Arc::new(pooled)));
+        state
+            .send
+            .send(sendable)
+            .map_err(|_| Error::new(E_FAIL, "Recording has stopped".into()))
+    }
+}
+
+/// Feed frames from `source` into the pipeline until the recording stops
+pub(crate) fn pull_frames(
+    source: Arc<Mutex<dyn FrameSource>>,
+    injector: Arc<FrameInjector>,
+    recording: Arc<AtomicBool>,
+    started: Arc<Barrier>,
+) -> Result<()> {
+    started.wait();
+    info!("Pulling frames from the application frame source");
+    while recording.load(Ordering::Relaxed) {
+        let frame = source.lock().unwrap().next_frame(SOURCE_POLL_TIMEOUT);
+        if let Some(frame) = frame {
+            if let Err(e) = unsafe { injector.submit(&frame.texture, frame.timestamp) } {
+                // The processing thread is gone once the recording stops
+                if !recording.load(Ordering::Relaxed) {
+                    break;
+                }
+                return Err(e);
+            }
+        }
+    }
+    debug!("Frame source finished");
+    Ok(())
+}
@@ -0,0 +1,244 @@
+//! Deterministic video and audio for exercising the pipeline without a display, a game or an
+//! audio device
+//!
+//! `SyntheticSource` is a `FrameSource` drawing color bars with the frame number in a strip
+//! below them; `AudioSource::Synthetic` replaces the system audio with a sine wave. Both run in
+//! real time from the start of the recording and stamp their samples with exact times, so a
+//! recording of them can be checked for its length, frame count and A/V sync.
+
+use crate::logging::{debug, info};
+use std::f64::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+use windows::core::Result;
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BIND_SHADER_RESOURCE,
+    D3D11_CPU_ACCESS_FLAG, D3D11_RESOURCE_MISC_FLAG, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+};
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
+
+use super::audio::{create_audio_sample, loopback_format};
+use super::source::{FrameSource, SourceFrame};
+use crate::types::{frame_time, SendableSample};
+
+/// The bars from left to right as BGRA: white, yellow, cyan, green, magenta, red, blue, black
+pub const COLOR_BARS: [[u8; 4]; 8] = [
+    [255, 255, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 0, 255, 255],
+    [0, 0, 255, 255],
+    [255, 0, 0, 255],
+    [0, 0, 0, 255],
+];
+
+/// Bits of the frame number drawn below the bars, most significant first
+pub const FRAME_NUMBER_BITS: u32 = 16;
+
+/// Length of each generated audio packet
+const AUDIO_PACKET: Duration = Duration::from_millis(10);
+
+/// Tone recorded by `AudioSource::Synthetic`, on both channels
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SineWave {
+    /// Frequency in Hz
+    pub frequency: f32,
+    /// Peak level, 0.0..=1.0 of full scale
+    pub amplitude: f32,
+}
+
+impl Default for SineWave {
+    fn default() -> Self {
+        Self {
+            frequency: 440.0,
+            amplitude: 0.5,
+        }
+    }
+}
+
+/// A `FrameSource` producing color bar frames at an exact frame rate
+///
+/// The top 7/8 of each frame holds the `COLOR_BARS`; the bottom 1/8 is a strip of
+/// `FRAME_NUMBER_BITS` blocks, white for a set bit, giving the number of the frame. Frames are
+/// handed out when they are due, starting with the first call, and carry the time of their
+/// frame number rather than when they were drawn.
+pub struct SyntheticSource {
+    context: ID3D11DeviceContext,
+    texture: ID3D11Texture2D,
+    width: u32,
+    height: u32,
+    fps_num: u32,
+    fps_den: u32,
+    /// The frame without its frame number, BGRA rows without padding
+    bars: Vec<u8>,
+    /// Scratch copy of `bars` the frame number is drawn into
+    pixels: Vec<u8>,
+    frame: u64,
+    start: Option<Instant>,
+}
+
+// The recording device has multithread protection enabled, see `external_device`
+unsafe impl Send for SyntheticSource {}
+
+impl SyntheticSource {
+    /// A source of `width` x `height` frames at `fps_num / fps_den` on `device`, which must be
+    /// the recording device passed as `external_device`
+    pub fn new(
+        device: &ID3D11Device,
+        width: u32,
+        height: u32,
+        fps_num: u32,
+        fps_den: u32,
+    ) -> Result<Self> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+        };
+        let mut texture = None;
+        let context = unsafe {
+            device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+            device.GetImmediateContext()?
+        };
+
+        let bars = (0..height)
+            .flat_map(|_| (0..width).flat_map(move |x| Self::bar_at(x, width)))
+            .collect::<Vec<u8>>();
+        info!(
+            "Synthetic source drawing {}x{} color bars at {}/{} fps",
+            width, height, fps_num, fps_den
+        );
+        Ok(Self {
+            context,
+            texture: texture.unwrap(),
+            width,
+            height,
+            fps_num,
+            fps_den,
+            pixels: bars.clone(),
+            bars,
+            frame: 0,
+            start: None,
+        })
+    }
+
+    /// Color of the bar column `x` of a frame `width` pixels wide falls in
+    pub fn bar_at(x: u32, width: u32) -> [u8; 4] {
+        let bar = x as usize * COLOR_BARS.len() / width.max(1) as usize;
+        COLOR_BARS[bar.min(COLOR_BARS.len() - 1)]
+    }
+
+    /// Draw the bars with `frame` in the bottom strip into `pixels`
+    fn draw(&mut self, frame: u64) {
+        self.pixels.copy_from_slice(&self.bars);
+        let strip_top = self.height - self.height / 8;
+        for y in strip_top..self.height {
+            for x in 0..self.width {
+                let bit = x * FRAME_NUMBER_BITS / self.width.max(1);
+                let set = (frame >> (FRAME_NUMBER_BITS - 1 - bit)) & 1 == 1;
+                let value = if set { 255 } else { 0 };
+                let offset = (y * self.width + x) as usize * 4;
+                self.pixels[offset..offset + 4].copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn next_frame(&mut self, timeout: Duration) -> Option<SourceFrame> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let timestamp = frame_time(self.frame, self.fps_num, self.fps_den);
+        let wait = (start + timestamp).saturating_duration_since(Instant::now());
+        if wait > timeout {
+            spin_sleep::sleep(timeout);
+            return None;
+        }
+        spin_sleep::sleep(wait);
+
+        self.draw(self.frame);
+        unsafe {
+            self.context.UpdateSubresource(
+                &self.texture,
+                0,
+                None,
+                self.pixels.as_ptr() as *const _,
+                self.width * 4,
+                0,
+            );
+        }
+        self.frame += 1;
+        Some(SourceFrame {
+            texture: self.texture.clone(),
+            timestamp,
+        })
+    }
+}
+
+/// Send `tone` in place of the captured system audio until the recording stops
+///
+/// The packets are in the loopback capture's format and sent in real time, timed from when the
+/// capture threads start by the number of samples generated.
+pub(crate) fn generate_audio(
+    send: Sender<SendableSample>,
+    recording: Arc<AtomicBool>,
+    started: Arc<Barrier>,
+    tone: SineWave,
+) -> Result<()> {
+    let format = loopback_format();
+    let sample_rate = format.nSamplesPerSec as u64;
+    let channels = format.nChannels as usize;
+    let packet_frames = (sample_rate * AUDIO_PACKET.as_millis() as u64 / 1000) as usize;
+    let time_hns = |position: u64| (position * 10_000_000 / sample_rate) as i64;
+    let amplitude = tone.amplitude.clamp(0.0, 1.0) as f64;
+    let mut samples = vec![0i16; packet_frames * channels];
+
+    started.wait();
+    info!(
+        "Generating a {} Hz tone as the system audio",
+        tone.frequency
+    );
+    let start = Instant::now();
+    let mut position = 0u64;
+    while recording.load(Ordering::Relaxed) {
+        for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+            let cycles = (position + i as u64) as f64 * tone.frequency as f64 / sample_rate as f64;
+            let level = (cycles.fract() * TAU).sin() * amplitude * i16::MAX as f64;
+            frame.fill(level as i16);
+        }
+        let time = time_hns(position);
+        let next = time_hns(position + packet_frames as u64);
+        let sample = unsafe {
+            create_audio_sample(
+                samples.as_mut_ptr() as *mut u8,
+                packet_frames as u32,
+                &format,
+                time,
+                next - time,
+            )?
+        };
+        if send.send(SendableSample::new(sample)).is_err() {
+            break;
+        }
+        position += packet_frames as u64;
+
+        // Keep to real time like a capture device would
+        let due = start + Duration::from_nanos(next as u64 * 100);
+        spin_sleep::sleep(due.saturating_duration_since(Instant::now()));
+    }
+    debug!("Synthetic audio stopped after {} samples", position);
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use crate::logging::{info, warn};
+use std::time::{Duration, Instant};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{CloseHandle, FALSE, HANDLE};
+use windows::Win32::Graphics::Dxgi::IDXGIOutput;
+use windows::Win32::System::Threading::{
+    CreateWaitableTimerExW, SetWaitableTimer, WaitForSingleObject,
+    CREATE_WAITABLE_TIMER_HIGH_RESOLUTION, INFINITE, TIMER_ALL_ACCESS,
+};
+
+/// How the capture thread waits until the next frame is due
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FramePacing {
+    /// Sleep most of the interval and spin the remainder; most precise, highest CPU use
+    #[default]
+    SpinSleep,
+    /// Plain thread sleep; cheapest, but only as precise as the system timer resolution
+    Sleep,
+    /// High-resolution waitable timer; precise without spinning, recommended for low frame rates
+    WaitableTimer,
+}
+
+/// Frame acquisition and pacing settings of the video capture thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct CaptureTiming {
+    /// How long `AcquireNextFrame` waits for a new desktop frame
+    pub acquire_timeout: Duration,
+    /// How to wait for the next frame
+    pub pacing: FramePacing,
+}
+
+impl Default for CaptureTiming {
+    fn default() -> Self {
+        Self {
+            acquire_timeout: Duration::from_millis(16),
+            pacing: FramePacing::default(),
+        }
+    }
+}
+
+/// Vertical blanks timed to measure the refresh period of the captured output
+const VBLANK_CALIBRATION_FRAMES: u32 = 8;
+
+/// Sleeps the capture thread according to a [`FramePacing`] strategy
+pub(crate) struct FramePacer {
+    pacing: FramePacing,
+    timer: Option<HANDLE>,
+    /// Output whose vertical blanks wake the thread, with its measured refresh period
+    vblank: Option<(IDXGIOutput, Duration)>,
+}
+
+impl FramePacer {
+    pub fn new(pacing: FramePacing) -> Self {
+        let timer = if pacing == FramePacing::WaitableTimer {
+            let timer = unsafe { create_waitable_timer() };
+            if timer.is_none() {
+                warn!("Failed to create a waitable timer, falling back to spin-sleep pacing");
+            }
+            timer
+        } else {
+            None
+        };
+
+        let pacing = match (pacing, timer) {
+            (FramePacing::WaitableTimer, None) => FramePacing::SpinSleep,
+            (pacing, _) => pacing,
+        };
+        info!("Capture frame pacing: {:?}", pacing);
+
+        Self {
+            pacing,
+            timer,
+            vblank: None,
+        }
+    }
+
+    /// Wake on the vertical blank of `output` closest to each frame's due time instead, so
+    /// frames are taken in step with the display refresh
+    pub unsafe fn sync_to_vblank(&mut self, output: IDXGIOutput) {
+        // Start timing right after a blank so the first interval is a whole one
+        if let Err(e) = output.WaitForVBlank() {
+            warn!(
+                "Cannot wait for vertical blanks, keeping {:?} pacing: {:?}",
+                self.pacing, e
+            );
+            return;
+        }
+        let start = Instant::now();
+        for _ in 0..VBLANK_CALIBRATION_FRAMES {
+            if let Err(e) = output.WaitForVBlank() {
+                warn!(
+                    "Cannot wait for vertical blanks, keeping {:?} pacing: {:?}",
+                    self.pacing, e
+                );
+                return;
+            }
+        }
+        let period = start.elapsed() / VBLANK_CALIBRATION_FRAMES;
+        info!(
+            "Capture frame pacing: vertical blank ({:.2} Hz)",
+            1.0 / period.as_secs_f64()
+        );
+        self.vblank = Some((output, period));
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        let Some((output, period)) = &self.vblank else {
+            return self.wait(duration);
+        };
+        let deadline = Instant::now() + duration;
+        // Each wait ends on the next blank; stop at the one within half a refresh of the
+        // deadline so beat-frequency judder between the two clocks cannot build up
+        while Instant::now() + *period / 2 < deadline {
+            if unsafe { output.WaitForVBlank() }.is_err() {
+                self.wait(deadline.saturating_duration_since(Instant::now()));
+                return;
+            }
+        }
+    }
+
+    fn wait(&self, duration: Duration) {
+        match (self.pacing, self.timer) {
+            (FramePacing::WaitableTimer, Some(timer)) => unsafe {
+                // Negative due times are relative, in 100ns units
+                let due_time = -((duration.as_nanos() / 100) as i64).max(1);
+                if SetWaitableTimer(timer, &due_time, 0, None, None, FALSE).as_bool() {
+                    WaitForSingleObject(timer, INFINITE);
+                } else {
+                    spin_sleep::sleep(duration);
+                }
+            },
+            (FramePacing::Sleep, _) => std::thread::sleep(duration),
+            _ => spin_sleep::sleep(duration),
+        }
+    }
+}
+
+impl Drop for FramePacer {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            unsafe {
+                CloseHandle(timer);
+            }
+        }
+    }
+}
+
+unsafe fn create_waitable_timer() -> Option<HANDLE> {
+    // High resolution timers need Windows 10 1803 or later; fall back to a regular timer
+    [CREATE_WAITABLE_TIMER_HIGH_RESOLUTION, 0]
+        .into_iter()
+        .find_map(|flags| {
+            CreateWaitableTimerExW(None, PCWSTR::null(), flags, TIMER_ALL_ACCESS.0).ok()
+        })
+}
@@ -1,4 +1,4 @@
-use log::{debug, error, info, trace, warn};
+use crate::logging::{debug, error, info, trace, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{SendError, Sender};
 use std::sync::Arc;
@@ -6,26 +6,54 @@ use std::sync::{Barrier, Mutex};
 use std::time::{Duration, Instant};
 use windows::core::Error;
 use windows::core::{ComInterface, Error as WindowsError, Result};
-use windows::Win32::Foundation::{BOOL, HWND, TRUE};
-use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D};
+use windows::Win32::Foundation::{BOOL, HWND, RECT, TRUE};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BOX, D3D11_TEXTURE2D_DESC,
+};
 use windows::Win32::Graphics::Dxgi::{
     IDXGIOutputDuplication, IDXGIResource, IDXGISurface, DXGI_ERROR_ACCESS_LOST,
     DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_RESET, DXGI_ERROR_WAIT_TIMEOUT,
     DXGI_OUTDUPL_FRAME_INFO,
 };
-use windows::Win32::Media::MediaFoundation::MFCreateDXGISurfaceBuffer;
+use windows::Win32::Media::MediaFoundation::{
+    MFCreateDXGISurfaceBuffer, MFSampleExtension_DeviceTimestamp,
+};
+use windows::Win32::System::Performance::QueryPerformanceCounter;
 use windows::Win32::System::Threading::*;
 use windows::Win32::UI::WindowsAndMessaging::GetForegroundWindow;
 
 use super::dxgi::setup_dxgi_duplication;
-use super::window::{get_window_rect, get_window_title, is_window_valid};
-use crate::types::{SamplePool, SendableSample, TexturePool};
+use super::timing::{CaptureTiming, FramePacer};
+use super::window::{
+    get_capture_region, get_window_title, is_window_minimized, is_window_valid, DisplayMode,
+    MinimizedPolicy, ReacquirePolicy, ReacquireTimeout, WindowCorners, WindowTarget,
+};
+use crate::processing::profiler::{PipelineProfiler, PipelineStage};
+use crate::processing::video::window_source_rect;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::types::{
+    frame_time, BufferPoolSizes, FrameClock, FrameCounters, FrameSender, MemoryUsage, PoolStats,
+    PooledTexture, SamplePool, SendableSample, TexturePool, WindowGeometry,
+};
+
+/// Consecutive AcquireNextFrame timeouts before an event is emitted (and again at each multiple)
+const ACQUIRE_TIMEOUT_EVENT_THRESHOLD: u32 = 30;
+
+/// Consecutive lost or failed duplications before checking whether the window moved to an output
+/// of another adapter
+const ADAPTER_CHECK_THRESHOLD: u32 = 3;
+
+/// Longest delay between two attempts to find a lost window
+const MAX_REACQUIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pixels copied around the window region so scaling filter taps never read stale texels
+const COPY_MARGIN: i32 = 16;
 
 /// Struct to manage window target state
 struct WindowTracker {
     /// The original target window handle
     hwnd: HWND,
-    /// The process name to search for if the window handle becomes invalid
+    /// Name of the target, for logging
     process_name: String,
     /// The last time we checked if the window is still valid
     last_check: Instant,
@@ -33,9 +61,19 @@ struct WindowTracker {
     check_interval: Duration,
     /// Whether we have seen the window in focus at least once
     ever_focused: bool,
-    /// Whether to use exact matching
-    use_exact_match: bool,
-    /// Window position (x, y)
+    /// Where to search for the window if the handle becomes invalid
+    target: WindowTarget,
+    /// Desktop position of the captured output, subtracted to map window rects into the frame
+    origin: (i32, i32),
+    /// Whether to track the client area instead of the whole window
+    client_area_only: bool,
+    /// How rounded window corners are left out
+    corners: WindowCorners,
+    /// Corner radii (top, bottom) the processing thread masks, shared with it
+    corner_radii: Arc<Mutex<(u32, u32)>>,
+    /// Whether the window is windowed or fullscreen, as of the last rect check
+    display_mode: DisplayMode,
+    /// Window position (x, y) in the captured frame
     position: Option<(i32, i32)>,
     /// Window size (width, height)
     size: Option<(u32, u32)>,
@@ -43,21 +81,59 @@ struct WindowTracker {
     last_rect_check: Instant,
     /// Time between window rect checks
     rect_check_interval: Duration,
+    /// Region copied out of the previous frame
+    last_copy_region: Option<RECT>,
+    /// Focus state logged last, per tracker so concurrent recorders do not share it
+    last_focus_state: Option<bool>,
+    /// Whether the window was minimized at the last frame
+    minimized: bool,
+    /// Last captured frame, kept for `MinimizedPolicy::LastFrame`
+    last_frame: Option<(ID3D11Texture2D, Option<Arc<PooledTexture>>)>,
 }
 
 impl WindowTracker {
     /// Create a new window tracker
-    fn new(hwnd: HWND, process_name: &str) -> Self {
-        Self::new_with_exact_match(hwnd, process_name, false)
+    fn new(
+        hwnd: HWND,
+        process_name: &str,
+        origin: (i32, i32),
+        client_area_only: bool,
+        corners: WindowCorners,
+        corner_radii: Arc<Mutex<(u32, u32)>>,
+    ) -> Self {
+        Self::new_with_target(
+            hwnd,
+            process_name,
+            WindowTarget::Title {
+                title: process_name.to_string(),
+                exact_match: false,
+            },
+            origin,
+            client_area_only,
+            corners,
+            corner_radii,
+        )
     }
 
-    /// Create a new window tracker with option for exact matching
-    fn new_with_exact_match(hwnd: HWND, process_name: &str, use_exact_match: bool) -> Self {
+    /// Create a new window tracker that finds the window again through `target`
+    fn new_with_target(
+        hwnd: HWND,
+        process_name: &str,
+        target: WindowTarget,
+        origin: (i32, i32),
+        client_area_only: bool,
+        corners: WindowCorners,
+        corner_radii: Arc<Mutex<(u32, u32)>>,
+    ) -> Self {
         // Try to get initial window rect
-        let (position, size) = if let Some((x, y, width, height)) = get_window_rect(hwnd) {
+        let region = get_capture_region(hwnd, client_area_only, corners);
+        let (position, size) = if let Some(region) = region {
+            *corner_radii.lock().unwrap() = region.corner_radii;
+            let (x, y, width, height) = region.rect;
+            let (x, y) = (x - origin.0, y - origin.1);
             info!(
-                "WindowTracker: Initial window rect for '{}' - Position: [{}, {}], Size: {}x{}",
-                process_name, x, y, width, height
+                "WindowTracker: Initial window rect for '{}' ({:?}) - Position: [{}, {}], Size: {}x{}",
+                process_name, region.display_mode, x, y, width, height
             );
             (Some((x, y)), Some((width, height)))
         } else {
@@ -74,11 +150,20 @@ impl WindowTracker {
             last_check: Instant::now(),
             check_interval: Duration::from_secs(2), // Check every 2 seconds
             ever_focused: false,
-            use_exact_match,
+            target,
+            origin,
+            client_area_only,
+            corners,
+            corner_radii,
+            display_mode: region.map_or(DisplayMode::Windowed, |region| region.display_mode),
             position,
             size,
             last_rect_check: Instant::now(),
             rect_check_interval: Duration::from_millis(500), // Check window rect every 500ms
+            last_copy_region: None,
+            last_focus_state: None,
+            minimized: false,
+            last_frame: None,
         }
     }
 
@@ -92,8 +177,27 @@ impl WindowTracker {
         }
 
         self.last_rect_check = now;
+        self.refresh_window_rect();
+    }
+
+    /// Read the window position and size now
+    fn refresh_window_rect(&mut self) {
+        // A minimized window is parked off screen; keep cropping where it was
+        if self.minimized {
+            return;
+        }
 
-        if let Some((x, y, width, height)) = get_window_rect(self.hwnd) {
+        if let Some(region) = get_capture_region(self.hwnd, self.client_area_only, self.corners) {
+            if region.display_mode != self.display_mode {
+                info!(
+                    "WindowTracker: Window '{}' switched from {:?} to {:?}",
+                    self.process_name, self.display_mode, region.display_mode
+                );
+                self.display_mode = region.display_mode;
+            }
+            *self.corner_radii.lock().unwrap() = region.corner_radii;
+            let (x, y, width, height) = region.rect;
+            let (x, y) = (x - self.origin.0, y - self.origin.1);
             // Check if values have changed before logging
             let position_changed = self.position != Some((x, y));
             let size_changed = self.size != Some((width, height));
@@ -115,6 +219,11 @@ impl WindowTracker {
         }
     }
 
+    /// Whether the window is windowed or fullscreen
+    fn display_mode(&self) -> DisplayMode {
+        self.display_mode
+    }
+
     /// Get the current window position
     fn get_position(&self) -> Option<(i32, i32)> {
         trace!(
@@ -135,12 +244,61 @@ impl WindowTracker {
         self.size
     }
 
+    /// Region of a `width` x `height` desktop frame that has to be copied for the processing
+    /// thread, which only reads the window area
+    ///
+    /// Covers the previous region too: the processing thread picks up window moves
+    /// asynchronously and may still crop a frame or two with the old rectangle.
+    fn copy_region(&mut self, width: u32, height: u32) -> D3D11_BOX {
+        let window = window_source_rect(width, height, self.position, self.size);
+        let mut region = RECT {
+            left: (window.left - COPY_MARGIN).max(0),
+            top: (window.top - COPY_MARGIN).max(0),
+            right: (window.right + COPY_MARGIN).min(width as i32),
+            bottom: (window.bottom + COPY_MARGIN).min(height as i32),
+        };
+        if let Some(last) = self.last_copy_region.replace(region) {
+            region = RECT {
+                left: region.left.min(last.left),
+                top: region.top.min(last.top),
+                right: region.right.max(last.right),
+                bottom: region.bottom.max(last.bottom),
+            };
+        }
+
+        D3D11_BOX {
+            left: region.left as u32,
+            top: region.top as u32,
+            front: 0,
+            right: region.right as u32,
+            bottom: region.bottom as u32,
+            back: 1,
+        }
+    }
+
+    /// Check whether the window is minimized, picking up its rect again when it is restored
+    fn update_minimized(&mut self) -> bool {
+        let minimized = is_window_minimized(self.hwnd);
+        if minimized != self.minimized {
+            info!(
+                "WindowTracker: Window '{}' {}",
+                self.process_name,
+                if minimized { "minimized" } else { "restored" }
+            );
+            self.minimized = minimized;
+            if !minimized {
+                self.refresh_window_rect();
+            }
+        }
+        minimized
+    }
+
     /// Check if the window is currently in focus
     fn is_focused(&mut self) -> bool {
         let foreground_window = unsafe { GetForegroundWindow() };
         let is_target_window = foreground_window == self.hwnd;
 
-        log::debug!(
+        crate::logging::debug!(
             "Foreground window: {:?}, Target window: {:?}, Is target in focus: {}",
             foreground_window,
             self.hwnd,
@@ -172,28 +330,14 @@ impl WindowTracker {
         }
 
         // If not, try to find the window again
-        if self.use_exact_match {
-            debug!(
-                "Window handle no longer valid, attempting to find '{}' again with exact match",
-                self.process_name
-            );
-
-            if let Some(new_hwnd) = super::window::get_window_by_exact_string(&self.process_name) {
-                debug!("Found window again with new handle: {:?}", new_hwnd);
-                self.hwnd = new_hwnd;
-                return true;
-            }
-        } else {
-            debug!(
-                "Window handle no longer valid, attempting to find '{}' again with substring match",
-                self.process_name
-            );
-
-            if let Some(new_hwnd) = super::window::get_window_by_string(&self.process_name) {
-                debug!("Found window again with new handle: {:?}", new_hwnd);
-                self.hwnd = new_hwnd;
-                return true;
-            }
+        debug!(
+            "Window handle no longer valid, attempting to find {:?} again",
+            self.target
+        );
+        if let Some(new_hwnd) = self.target.resolve() {
+            debug!("Found window again with new handle: {:?}", new_hwnd);
+            self.hwnd = new_hwnd;
+            return true;
         }
 
         debug!("Failed to find window '{}'", self.process_name);
@@ -222,54 +366,141 @@ impl From<WindowsError> for FrameError {
     }
 }
 
-pub unsafe fn get_frames(
-    send: Sender<SendableSample>,
-    recording: Arc<AtomicBool>,
-    hwnd: HWND,
-    process_name: &str,
-    fps_num: u32,
-    fps_den: u32,
-    input_width: u32,
-    input_height: u32,
-    started: Arc<Barrier>,
-    device: Arc<ID3D11Device>,
-    context_mutex: Arc<Mutex<ID3D11DeviceContext>>,
-    use_exact_match: bool,
-    capture_cursor: bool,
-    window_info_sender: Sender<(Option<(i32, i32)>, Option<(u32, u32)>)>,
-) -> Result<()> {
+/// The recording a window is captured for by desktop duplication
+pub struct WindowCaptureContext {
+    /// Cleared when the recording stops
+    pub recording: Arc<AtomicBool>,
+    pub hwnd: HWND,
+    /// Name of the target, for logging and finding the window again
+    pub process_name: String,
+    pub fps_num: u32,
+    pub fps_den: u32,
+    /// Size of the captured frames
+    pub input_width: u32,
+    pub input_height: u32,
+    /// Passed once every capture thread is ready
+    pub started: Arc<Barrier>,
+    pub device: Arc<ID3D11Device>,
+    pub context_mutex: Arc<Mutex<ID3D11DeviceContext>>,
+    /// Whether the capture may move to a new device when the window changes adapter; not for
+    /// a caller-supplied device
+    pub replaceable_device: bool,
+    /// Finds the window again once it is lost
+    pub relocate: WindowTarget,
+    pub capture_cursor: bool,
+    pub client_area_only: bool,
+    pub corners: WindowCorners,
+    /// Updated with the radii of the window's rounded corners
+    pub corner_radii: Arc<Mutex<(u32, u32)>>,
+    pub minimized_policy: MinimizedPolicy,
+    pub reacquire: ReacquirePolicy,
+    /// Set when the window did not come back in time and the recording should stop
+    pub window_gone: Arc<AtomicBool>,
+    /// Position and size of the window whenever they are known
+    pub window_info_sender: Sender<WindowGeometry>,
+    pub events: EventEmitter,
+    pub timing: CaptureTiming,
+    pub vsync_pacing: bool,
+    pub profiler: Option<Arc<PipelineProfiler>>,
+    pub counters: Arc<FrameCounters>,
+    pub pool_sizes: BufferPoolSizes,
+    pub pool_stats: Arc<Mutex<PoolStats>>,
+    pub memory_usage: Arc<Mutex<MemoryUsage>>,
+}
+
+pub unsafe fn get_frames(send: FrameSender, capture: WindowCaptureContext) -> Result<()> {
+    let WindowCaptureContext {
+        recording,
+        hwnd,
+        process_name,
+        fps_num,
+        fps_den,
+        input_width,
+        input_height,
+        started,
+        mut device,
+        context_mutex,
+        replaceable_device,
+        relocate,
+        capture_cursor,
+        client_area_only,
+        corners,
+        corner_radii,
+        minimized_policy,
+        reacquire,
+        window_gone,
+        window_info_sender,
+        events,
+        timing,
+        vsync_pacing,
+        profiler,
+        counters,
+        pool_sizes,
+        pool_stats,
+        memory_usage,
+    } = capture;
+    let process_name: &str = &process_name;
     info!(
         "Starting frame collection for window: '{}'",
         get_window_title(hwnd)
     );
     SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_ABOVE_NORMAL);
 
+    // Window rects are desktop coordinates; the frame starts at the output's corner
+    let mut origin = super::dxgi::capture_origin(&device, hwnd);
+    info!("Captured output origin on the desktop: {:?}", origin);
+
     // Create window tracker to handle focus and window validity
-    let mut window_tracker =
-        WindowTracker::new_with_exact_match(hwnd, process_name, use_exact_match);
+    let mut window_tracker = WindowTracker::new_with_target(
+        hwnd,
+        process_name,
+        relocate.clone(),
+        origin,
+        client_area_only,
+        corners,
+        corner_radii.clone(),
+    );
 
-    let frame_duration = Duration::from_nanos(1_000_000_000 * fps_den as u64 / fps_num as u64);
-    let mut next_frame_time = Instant::now();
+    let mut clock = FrameClock::new(fps_num, fps_den);
     let mut frame_count = 0;
+    let mut display_mode = window_tracker.display_mode();
     let mut accumulated_delay = Duration::ZERO;
     let mut num_duped = 0;
+    let mut consecutive_timeouts: u32 = 0;
+    let mut pacer = FramePacer::new(timing.pacing);
+    if vsync_pacing {
+        match super::dxgi::capture_output(&device, hwnd) {
+            Ok(output) => pacer.sync_to_vblank(output),
+            Err(e) => warn!("Failed to get the captured output for vsync pacing: {:?}", e),
+        }
+    }
+    let acquire_timeout_ms = timing.acquire_timeout.as_millis().min(u32::MAX as u128) as u32;
+    info!("AcquireNextFrame timeout: {} ms", acquire_timeout_ms);
 
     // Initialize texture pool for reusable textures
     use windows::Win32::Graphics::Direct3D11::*;
     use windows::Win32::Graphics::Dxgi::Common::*;
 
-    // Create a pool with capacity of 1 acquisition textures - adjust based on expected frame rate and processing time
-    let texture_pool = TexturePool::new(
-        device.clone(),
-        10, // Acquisition capacity
-        input_width,
-        input_height,
-        DXGI_FORMAT_B8G8R8A8_UNORM,
-    )?;
-    let texture_pool = Arc::new(texture_pool);
+    // Each acquisition texture holds one frame until the processing thread is done with it
+    let new_texture_pool =
+        |device: &Arc<ID3D11Device>, width: u32, height: u32| -> Result<Arc<TexturePool>> {
+            let mut texture_pool = TexturePool::new(
+                device.clone(),
+                pool_sizes.capture_textures,
+                0, // Frames are converted by the processing thread's pool
+                width,
+                height,
+                DXGI_FORMAT_B8G8R8A8_UNORM,
+            )?;
+            texture_pool.set_acquire_timeout(pool_sizes.acquire_timeout);
+            texture_pool.set_stats_sink(pool_stats.clone());
+            texture_pool.set_memory_sink(memory_usage.clone());
+            Ok(Arc::new(texture_pool))
+        };
+    let mut texture_pool = new_texture_pool(&device, input_width, input_height)?;
 
     // Create a pool for IMFSample objects that are bound to the textures
-    let sample_pool = SamplePool::new(fps_num, 10);
+    let sample_pool = SamplePool::new(fps_num, fps_den, 10);
     let sample_pool = Arc::new(sample_pool);
 
     // Signal that we're ready
@@ -277,38 +508,97 @@ pub unsafe fn get_frames(
 
     // The device was already created for the correct adapter in inner.rs, so just set up duplication
     // We can use the simpler setup since the device already knows which adapter to use
-    let mut duplication_result = unsafe { super::dxgi::setup_dxgi_duplication(&device) };
+    let mut duplication_result = unsafe { super::dxgi::setup_dxgi_duplication(&device, hwnd) };
+    // Size of the duplicated frames, to notice display mode changes
+    let mut capture_size = duplication_result
+        .as_ref()
+        .ok()
+        .map(|duplication| super::dxgi::duplication_size(duplication));
+    // Duplications lost or failed in a row, and whether the device itself was removed
+    let mut lost_duplications: u32 = 0;
+    let mut device_lost = false;
+    // When the window was lost, the delay before the next search and whether the reacquire
+    // timeout was reported
+    let mut window_lost_at: Option<Instant> = None;
+    let mut reacquire_interval = reacquire.interval;
+    let mut reacquire_timed_out = false;
 
     // Main recording loop
     while recording.load(Ordering::Relaxed) {
-        // Periodically check if window is still valid
-        if !window_tracker.ensure_valid_window() {
-            // Window is no longer valid, try to find it again
-            warn!(
-                "Window no longer valid, attempting to find '{}'",
-                process_name
-            );
-            if let Some(new_hwnd) = if use_exact_match {
-                super::window::get_window_by_exact_string(process_name)
-            } else {
-                super::window::get_window_by_string(process_name)
-            } {
-                info!(
-                    "Found window '{}' again, continuing recording",
+        // Periodically check if window is still valid; once lost, search for it until found
+        if window_lost_at.is_some() || !window_tracker.ensure_valid_window() {
+            let lost_at = *window_lost_at.get_or_insert_with(|| {
+                warn!(
+                    "Window no longer valid, attempting to find '{}'",
                     process_name
                 );
-                window_tracker =
-                    WindowTracker::new_with_exact_match(new_hwnd, process_name, use_exact_match);
+                events.emit(RecorderEvent::WindowLost {
+                    timestamp: frame_time(frame_count, fps_num, fps_den),
+                });
+                Instant::now()
+            });
+            if let Some(new_hwnd) = relocate.resolve() {
+                info!(
+                    "Found window '{}' again after {:?}, continuing recording",
+                    process_name,
+                    lost_at.elapsed()
+                );
+                events.emit(RecorderEvent::WindowReacquired {
+                    hwnd: new_hwnd,
+                    title: get_window_title(new_hwnd),
+                    lost_for: lost_at.elapsed(),
+                });
+                window_lost_at = None;
+                reacquire_interval = reacquire.interval;
+                reacquire_timed_out = false;
+                // The window may have come back on another monitor
+                origin = super::dxgi::capture_origin(&device, new_hwnd);
+                window_tracker = WindowTracker::new_with_target(
+                    new_hwnd,
+                    process_name,
+                    relocate.clone(),
+                    origin,
+                    client_area_only,
+                    corners,
+                    corner_radii.clone(),
+                );
 
                 // Recreate the duplication for the new window
                 // Since we're using the same device (which was created for the correct adapter),
                 // we can use the simpler duplication setup
                 info!("Recreating DXGI duplication");
-                duplication_result = unsafe { super::dxgi::setup_dxgi_duplication(&device) };
+                duplication_result =
+                    unsafe { super::dxgi::setup_dxgi_duplication(&device, new_hwnd) };
             } else {
-                // Can't find window, wait and retry
-                warn!("Window '{}' not found, will retry", process_name);
-                spin_sleep::sleep(Duration::from_millis(1));
+                if !reacquire_timed_out && lost_at.elapsed() >= reacquire.timeout {
+                    reacquire_timed_out = true;
+                    warn!(
+                        "Window '{}' did not come back within {:?}",
+                        process_name, reacquire.timeout
+                    );
+                    match reacquire.on_timeout {
+                        // The supervisor reports the timeout and stops the recording
+                        ReacquireTimeout::StopRecording => {
+                            window_gone.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        ReacquireTimeout::KeepWaiting => {
+                            events.emit(RecorderEvent::WindowReacquireTimedOut {
+                                timeout: reacquire.timeout,
+                            });
+                        }
+                    }
+                }
+
+                // Can't find window, wait and retry with backoff
+                debug!(
+                    "Window '{}' not found, retrying in {:?}",
+                    process_name, reacquire_interval
+                );
+                spin_sleep::sleep(reacquire_interval);
+                reacquire_interval = (reacquire_interval * 2)
+                    .min(MAX_REACQUIRE_INTERVAL)
+                    .max(reacquire.interval);
                 continue;
             }
         }
@@ -317,6 +607,14 @@ pub unsafe fn get_frames(
         window_tracker.update_window_rect();
         let position = window_tracker.get_position();
         let size = window_tracker.get_size();
+        if window_tracker.display_mode() != display_mode {
+            events.emit(RecorderEvent::DisplayModeChanged {
+                previous: display_mode,
+                mode: window_tracker.display_mode(),
+                timestamp: frame_time(frame_count, fps_num, fps_den),
+            });
+            display_mode = window_tracker.display_mode();
+        }
 
         // Only send window info if we have both position and size
         if position.is_some() && size.is_some() {
@@ -334,12 +632,60 @@ pub unsafe fn get_frames(
             }
         }
 
+        // On hybrid-graphics laptops the window can move to an output of the other GPU, which
+        // this device cannot duplicate; a removed device cannot be used at all. Either way the
+        // capture moves to a new device for the window's adapter.
+        if replaceable_device && (device_lost || lost_duplications >= ADAPTER_CHECK_THRESHOLD) {
+            lost_duplications = 0;
+            if device_lost || super::dxgi::window_adapter_changed(&device, window_tracker.hwnd) {
+                match crate::recorder::create_d3d11_device_for_window(window_tracker.hwnd) {
+                    Ok((new_device, context)) => {
+                        let adapter = super::dxgi::adapter_name(&new_device);
+                        info!("Moving the capture to adapter '{}'", adapter);
+                        device = Arc::new(new_device);
+                        *context_mutex.lock().unwrap() = context;
+                        device_lost = false;
+
+                        origin = super::dxgi::capture_origin(&device, window_tracker.hwnd);
+                        window_tracker.origin = origin;
+                        window_tracker.refresh_window_rect();
+                        let (width, height) = capture_size.unwrap_or((input_width, input_height));
+                        texture_pool = new_texture_pool(&device, width, height)?;
+                        window_tracker.last_frame = None;
+                        if vsync_pacing {
+                            match super::dxgi::capture_output(&device, window_tracker.hwnd) {
+                                Ok(output) => pacer.sync_to_vblank(output),
+                                Err(e) => warn!(
+                                    "Failed to get the captured output for vsync pacing: {:?}",
+                                    e
+                                ),
+                            }
+                        }
+                        duplication_result = setup_dxgi_duplication(&device, window_tracker.hwnd);
+                        events.emit(RecorderEvent::AdapterChanged {
+                            adapter,
+                            timestamp: frame_time(frame_count, fps_num, fps_den),
+                        });
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to create a device for the window's adapter: {:?}",
+                            e
+                        );
+                        spin_sleep::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                }
+            }
+        }
+
         // Check if we need to recreate the duplication interface
         if duplication_result.is_err() {
             info!("Recreating DXGI duplication interface after previous failure");
-            duplication_result = setup_dxgi_duplication(&device);
+            duplication_result = setup_dxgi_duplication(&device, window_tracker.hwnd);
             if let Err(e) = &duplication_result {
                 warn!("Failed to recreate DXGI duplication interface: {:?}", e);
+                lost_duplications += 1;
                 // Wait a bit before trying again to avoid spinning too fast
                 spin_sleep::sleep(Duration::from_millis(100));
                 continue;
@@ -349,22 +695,77 @@ pub unsafe fn get_frames(
 
         let duplication = duplication_result.as_ref().unwrap();
 
+        // A resolution or display mode change loses the duplication, and the recreated one
+        // delivers frames of the new size; the processing thread adapts to their size
+        let size = super::dxgi::duplication_size(duplication);
+        if let Some(previous) = capture_size.filter(|previous| *previous != size) {
+            info!(
+                "Captured output changed from {}x{} to {}x{}, recreating capture textures",
+                previous.0, previous.1, size.0, size.1
+            );
+            texture_pool = new_texture_pool(&device, size.0, size.1)?;
+            // A frame of the old size must not be repeated into the new ones
+            window_tracker.last_frame = None;
+            events.emit(RecorderEvent::CaptureResolutionChanged {
+                previous,
+                size,
+                timestamp: frame_time(frame_count, fps_num, fps_den),
+            });
+        }
+        capture_size = Some(size);
+
+        let duped_before = num_duped;
+        let capture = FrameCaptureContext {
+            context_mutex: &context_mutex,
+            send: &send,
+            texture_pool: &texture_pool,
+            sample_pool: &sample_pool,
+            pacer: &pacer,
+            profiler: profiler.as_deref(),
+            counters: &counters,
+            fps_num,
+            capture_cursor,
+            minimized_policy,
+            acquire_timeout_ms,
+        };
         match process_frame(
             duplication,
-            &context_mutex,
+            capture,
             &mut window_tracker,
-            fps_num,
-            &send,
             frame_count,
-            &mut next_frame_time,
-            frame_duration,
+            &mut clock,
             &mut accumulated_delay,
             &mut num_duped,
-            &texture_pool,
-            &sample_pool,
-            capture_cursor,
         ) {
-            Ok(_) => {
+            Ok(sent) => {
+                lost_duplications = 0;
+                let timestamp = frame_time(frame_count, fps_num, fps_den);
+                if num_duped > duped_before {
+                    counters.duplicated.store(num_duped, Ordering::Relaxed);
+                    events.emit(RecorderEvent::FramesDuplicated {
+                        timestamp,
+                        count: num_duped - duped_before,
+                        total: num_duped,
+                    });
+                }
+
+                // Nothing is acquired while paused for a minimized window
+                if sent || window_tracker.minimized {
+                    consecutive_timeouts = 0;
+                } else {
+                    consecutive_timeouts += 1;
+                    if consecutive_timeouts % ACQUIRE_TIMEOUT_EVENT_THRESHOLD == 0 {
+                        warn!(
+                            "AcquireNextFrame timed out {} times in a row",
+                            consecutive_timeouts
+                        );
+                        events.emit(RecorderEvent::AcquireTimeouts {
+                            timestamp,
+                            consecutive: consecutive_timeouts,
+                        });
+                    }
+                }
+
                 frame_count += 1;
                 //trace!("Collected frame {}", frame_count);
             }
@@ -383,6 +784,18 @@ pub unsafe fn get_frames(
                         warn!("DXGI access lost (possibly keyed mutex abandoned), recreating duplication interface");
                         // Mark the duplication interface as invalid and recreate it on the next loop iteration
                         duplication_result = Err(e);
+                        lost_duplications += 1;
+                        continue;
+                    }
+
+                    // A removed device is replaced on the next loop iteration if it is our own
+                    if replaceable_device
+                        && (e.code() == DXGI_ERROR_DEVICE_REMOVED
+                            || e.code() == DXGI_ERROR_DEVICE_RESET)
+                    {
+                        warn!("D3D11 device lost ({:?}), recreating it", e.code());
+                        duplication_result = Err(e);
+                        device_lost = true;
                         continue;
                     }
 
@@ -407,7 +820,7 @@ pub unsafe fn get_frames(
 
 // Draw cursor using GDI
 unsafe fn draw_cursor_gdi(texture: &ID3D11Texture2D) -> Result<()> {
-    use log::{debug, error, trace, warn};
+    use crate::logging::{debug, error, trace, warn};
     use std::mem::size_of;
     use windows::Win32::Foundation::{BOOL, POINT};
     use windows::Win32::Graphics::Direct3D11::{
@@ -495,25 +908,48 @@ unsafe fn draw_cursor_gdi(texture: &ID3D11Texture2D) -> Result<()> {
     Ok(())
 }
 
+/// What the capture loop hands `process_frame` besides its running counts
+#[derive(Clone, Copy)]
+struct FrameCaptureContext<'a> {
+    context_mutex: &'a Arc<Mutex<ID3D11DeviceContext>>,
+    send: &'a FrameSender,
+    texture_pool: &'a Arc<TexturePool>,
+    sample_pool: &'a Arc<SamplePool>,
+    pacer: &'a FramePacer,
+    profiler: Option<&'a PipelineProfiler>,
+    counters: &'a FrameCounters,
+    fps_num: u32,
+    capture_cursor: bool,
+    minimized_policy: MinimizedPolicy,
+    acquire_timeout_ms: u32,
+}
+
 unsafe fn process_frame(
     duplication: &IDXGIOutputDuplication,
-    context_mutex: &Arc<Mutex<ID3D11DeviceContext>>,
+    capture: FrameCaptureContext<'_>,
     window_tracker: &mut WindowTracker,
-    fps_num: u32,
-    send: &Sender<SendableSample>,
     frame_count: u64,
-    next_frame_time: &mut Instant,
-    frame_duration: Duration,
+    clock: &mut FrameClock,
     accumulated_delay: &mut Duration,
     num_duped: &mut u64,
-    texture_pool: &Arc<TexturePool>,
-    sample_pool: &Arc<SamplePool>,
-    capture_cursor: bool,
-) -> std::result::Result<(), FrameError> {
+) -> std::result::Result<bool, FrameError> {
+    let FrameCaptureContext {
+        context_mutex,
+        send,
+        texture_pool,
+        sample_pool,
+        pacer,
+        profiler,
+        counters,
+        fps_num,
+        capture_cursor,
+        minimized_policy,
+        acquire_timeout_ms,
+    } = capture;
     // 1. Check Focus & Log Focus Change
-    let is_window_focused = window_tracker.is_focused();
-    static mut LAST_FOCUS_STATE: Option<bool> = None; // Keep static focus tracking
-    let focus_changed = unsafe { LAST_FOCUS_STATE != Some(is_window_focused) };
+    let minimized = window_tracker.update_minimized();
+    let is_window_focused = !minimized && window_tracker.is_focused();
+    let focus_changed = window_tracker.last_focus_state != Some(is_window_focused);
     if focus_changed {
         info!(
             "Window '{}' focus state changed: {}",
@@ -524,14 +960,13 @@ unsafe fn process_frame(
                 "Unfocused"
             }
         );
-        unsafe {
-            LAST_FOCUS_STATE = Some(is_window_focused);
-        }
+        window_tracker.last_focus_state = Some(is_window_focused);
     }
 
     // Variables to hold results through the steps
     let mut final_texture: Option<ID3D11Texture2D> = None;
     let mut needs_release_to_pool = false; // Track if texture came from acquisition pool
+    let mut lease: Option<Arc<PooledTexture>> = None; // Returns the texture once the frame is processed
     let mut acquired_resource_holder: Option<IDXGIResource> = None; // Temp holder for resource
                                                                     // *** Track if AcquireNextFrame returned Ok(_) ***
     let mut frame_acquire_returned_ok = false;
@@ -543,7 +978,7 @@ unsafe fn process_frame(
         trace!("Window focused, attempting to acquire frame.");
         let mut info = DXGI_OUTDUPL_FRAME_INFO::default();
         let acquire_result =
-            duplication.AcquireNextFrame(16, &mut info, &mut acquired_resource_holder);
+            duplication.AcquireNextFrame(acquire_timeout_ms, &mut info, &mut acquired_resource_holder);
 
         // *** Store Ok status directly ***
         frame_acquire_returned_ok = acquire_result.is_ok();
@@ -594,25 +1029,42 @@ unsafe fn process_frame(
                 match acquired_resource.cast::<ID3D11Texture2D>() {
                     Ok(source_texture) => {
                         match texture_pool.acquire_acquisition_texture() {
-                            Ok(pooled_texture) => {
-                                trace!("Copying acquired frame to pooled texture.");
-                                context_guard.CopyResource(&pooled_texture, &source_texture);
+                            Some(pooled) => {
+                                let pooled_texture = pooled.texture().clone();
+                                // Only the window area is read downstream, so skip the rest of
+                                // the desktop to save bandwidth on large outputs
+                                let mut desc = D3D11_TEXTURE2D_DESC::default();
+                                source_texture.GetDesc(&mut desc);
+                                let region = window_tracker.copy_region(desc.Width, desc.Height);
+                                trace!("Copying window region {:?} to pooled texture.", region);
+                                let scope =
+                                    profiler.map(|p| p.begin(PipelineStage::CaptureCopy));
+                                context_guard.CopySubresourceRegion(
+                                    &pooled_texture,
+                                    0,
+                                    region.left,
+                                    region.top,
+                                    0,
+                                    &source_texture,
+                                    0,
+                                    Some(&region),
+                                );
+                                if let (Some(profiler), Some(scope)) = (profiler, scope) {
+                                    profiler.end(scope);
+                                }
+                                lease = Some(Arc::new(pooled));
+                                if minimized_policy == MinimizedPolicy::LastFrame {
+                                    window_tracker.last_frame =
+                                        Some((pooled_texture.clone(), lease.clone()));
+                                }
                                 final_texture = Some(pooled_texture);
                                 needs_release_to_pool = true;
+                                counters.captured.fetch_add(1, Ordering::Relaxed);
                             }
-                            Err(pool_err) => {
-                                error!("Failed to acquire texture from pool: {:?}", pool_err);
-                                drop(context_guard);
-                                drop(acquired_resource_holder);
-                                // Only release if acquire *was* Ok
-                                if frame_acquire_returned_ok {
-                                    if let Err(rel_err) = duplication.ReleaseFrame() {
-                                        warn!("Error releasing frame after texture pool failure: {:?}", rel_err);
-                                        // If release fails here, maybe propagate *that* error instead?
-                                        // return Err(rel_err.into()); // Or just continue and return pool error
-                                    }
-                                }
-                                return Err(FrameError::TexturePoolError);
+                            None => {
+                                // Every texture still holds an unprocessed frame; drop this one
+                                // and release the DXGI frame as usual below
+                                debug!(frame = frame_count, "No free capture texture, dropping frame");
                             }
                         }
                     }
@@ -671,13 +1123,13 @@ unsafe fn process_frame(
         if capture_cursor && frame_has_content {
             if let Some(ref tex_to_draw_on) = final_texture {
                 trace!("Drawing cursor onto prepared frame.");
-                debug!("Acquiring D3D context lock BEFORE GDI draw...");
+                trace!("Acquiring D3D context lock BEFORE GDI draw...");
                 let _gdi_context_guard = context_mutex.lock().unwrap(); // Lock D3D context
-                debug!(" -> D3D context lock acquired for GDI.");
+                trace!(" -> D3D context lock acquired for GDI.");
                 if let Err(e) = draw_cursor_gdi(tex_to_draw_on) {
-                    debug!("Failed to draw cursor using GDI: {:?}", e);
+                    debug!(frame = frame_count, "Failed to draw cursor using GDI: {:?}", e);
                 }
-                debug!(" -> Releasing D3D context lock AFTER GDI draw.");
+                trace!(" -> Releasing D3D context lock AFTER GDI draw.");
                 // D3D context lock released here (_gdi_context_guard goes out of scope)
             } else {
                 warn!("Capture cursor is true and frame had content, but final_texture is None - skipping cursor draw.");
@@ -685,28 +1137,46 @@ unsafe fn process_frame(
         }
         // End of focused path logic
     } else {
-        // --- Unfocused Path: Use Blank Frame ---
-        trace!("Window unfocused, using blank frame.");
-        final_texture = Some(texture_pool.get_blank_texture().map_err(|e| {
-            error!("Failed to get blank texture from pool: {:?}", e);
-            FrameError::TexturePoolError
-        })?);
-        needs_release_to_pool = false; // Blank texture isn't released back to acquisition pool
+        match (minimized, minimized_policy, &window_tracker.last_frame) {
+            (true, MinimizedPolicy::Pause, _) => {
+                trace!("Window minimized, pausing the video.");
+            }
+            (true, MinimizedPolicy::LastFrame, Some((last_texture, last_lease))) => {
+                trace!("Window minimized, repeating the last frame.");
+                final_texture = Some(last_texture.clone());
+                lease = last_lease.clone();
+            }
+            _ => {
+                // --- Unfocused Path: Use Blank Frame ---
+                trace!("Window unfocused, using blank frame.");
+                final_texture = Some(texture_pool.get_blank_texture().map_err(|e| {
+                    error!("Failed to get blank texture from pool: {:?}", e);
+                    FrameError::TexturePoolError
+                })?);
+                needs_release_to_pool = false; // Blank texture isn't released back to acquisition pool
+            }
+        }
     }
 
     // 6. Frame Sending and Timing Logic (Common path)
+    let frame_sent = final_texture.is_some();
     if let Some(texture_to_send) = final_texture {
+        // Duplicated frames carry the same capture time as the frame they repeat
+        let mut capture_qpc = 0i64;
+        QueryPerformanceCounter(&mut capture_qpc);
+
         // Handle frame timing duplication BEFORE sending the current frame
-        while *accumulated_delay >= frame_duration {
+        while *accumulated_delay >= clock.frame_duration() {
             debug!(
-                "Duping a frame to catch up (accumulated delay: {:?})",
-                *accumulated_delay
+                frame = frame_count,
+                delay_us = accumulated_delay.as_micros() as u64,
+                "Duping a frame to catch up"
             );
             // Use the *same* texture_to_send for duplication
-            match send_frame(&texture_to_send, frame_count, send, sample_pool) {
+            match send_frame(&texture_to_send, &lease, frame_count, capture_qpc, send, sample_pool) {
                 Ok(_) => {
-                    *next_frame_time += frame_duration;
-                    *accumulated_delay -= frame_duration;
+                    *accumulated_delay -= clock.frame_duration();
+                    clock.advance();
                     *num_duped += 1;
                 }
                 Err(_) => {
@@ -719,20 +1189,17 @@ unsafe fn process_frame(
 
         // Send the actual current frame
         trace!(
-            "Sending frame {} ({}).",
-            frame_count,
-            if needs_release_to_pool {
-                "Captured"
-            } else {
-                "Blank"
-            } // Log based on pool origin
+            frame = frame_count,
+            qpc = capture_qpc,
+            blank = !needs_release_to_pool, // Log based on pool origin
+            "Sending frame"
         );
-        match send_frame(&texture_to_send, frame_count, send, sample_pool) {
+        match send_frame(&texture_to_send, &lease, frame_count, capture_qpc, send, sample_pool) {
             Ok(_) => {
                 // Success! Rely on SendableSample Drop to release texture back to pool when done.
                 trace!(
-                    "Frame {} sent, SendableSample will release resources.",
-                    frame_count
+                    frame = frame_count,
+                    "Frame sent, SendableSample will release resources."
                 );
             }
             Err(_) => {
@@ -743,24 +1210,23 @@ unsafe fn process_frame(
         }
     } else {
         // No frame was prepared (e.g., focused window but AcquireNextFrame timed out or failed early)
-        trace!(
-            "No final texture prepared for frame {}, skipping send.",
-            frame_count
-        );
+        trace!(frame = frame_count, "No final texture prepared, skipping send.");
     }
 
     // 7. Advance Frame Timing
-    *next_frame_time += frame_duration;
+    clock.advance();
     let current_time = Instant::now();
-    handle_frame_timing(current_time, *next_frame_time, accumulated_delay);
+    handle_frame_timing(current_time, clock.due(), accumulated_delay, pacer);
 
-    Ok(())
+    Ok(frame_sent)
 }
 
 unsafe fn send_frame(
     texture: &ID3D11Texture2D,
+    lease: &Option<Arc<PooledTexture>>,
     frame_count: u64,
-    send: &Sender<SendableSample>,
+    capture_qpc: i64,
+    send: &FrameSender,
     sample_pool: &Arc<SamplePool>,
 ) -> Result<()> {
     // Get a sample from the pool
@@ -780,11 +1246,11 @@ unsafe fn send_frame(
     drop(surface);
 
     // Set the sample time and duration
-    sample_pool.set_sample_time(&sample, frame_count)?;
-    sample.SetSampleDuration(10_000_000 / sample_pool.fps_num as i64)?;
+    sample_pool.set_sample_timing(&sample, frame_count)?;
+    sample.SetUINT64(&MFSampleExtension_DeviceTimestamp, capture_qpc as u64)?;
 
     // Create a pooled SendableSample that will return the sample to the pool when dropped
-    let sendable = SendableSample::new_pooled(sample, sample_pool.clone());
+    let sendable = SendableSample::new_pooled(sample, sample_pool.clone()).holding(lease.clone());
 
     // Send the sample (it will be returned to pool via Drop if sending fails)
     match send.send(sendable) {
@@ -800,12 +1266,13 @@ fn handle_frame_timing(
     current_time: Instant,
     next_frame_time: Instant,
     accumulated_delay: &mut Duration,
+    pacer: &FramePacer,
 ) {
     if current_time > next_frame_time {
         let overrun = current_time.duration_since(next_frame_time);
         *accumulated_delay += overrun;
     } else {
         let sleep_time = next_frame_time.duration_since(current_time);
-        spin_sleep::sleep(sleep_time);
+        pacer.sleep(sleep_time);
     }
 }
@@ -0,0 +1,276 @@
+use crate::logging::{debug, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use windows::core::{ComInterface, Error, Result, PWSTR};
+use windows::Win32::Foundation::E_INVALIDARG;
+use windows::Win32::Media::MediaFoundation::*;
+use windows::Win32::System::Com::CoTaskMemFree;
+
+use crate::processing::media::{init_media_foundation, shutdown_media_foundation};
+
+/// Latest webcam frame as tightly packed top-down BGRX rows
+pub(crate) struct WebcamFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// Increases with every frame read, so readers can tell a new frame from the last one
+    pub sequence: u64,
+}
+
+/// Reads frames from a webcam on its own thread, keeping only the latest one
+pub(crate) struct WebcamReader {
+    latest: Arc<Mutex<Option<WebcamFrame>>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WebcamReader {
+    /// Open the webcam whose name contains `device_name` (the first one if None) and start
+    /// reading it
+    pub fn start(device_name: Option<String>) -> Result<Self> {
+        let latest = Arc::new(Mutex::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+        let (opened_sender, opened_receiver) = sync_channel(1);
+
+        let thread_latest = latest.clone();
+        let thread_running = running.clone();
+        let handle = std::thread::spawn(move || unsafe {
+            if let Err(e) = init_media_foundation() {
+                let _ = opened_sender.send(Err(e));
+                return;
+            }
+            match open_webcam(device_name.as_deref()) {
+                Ok((reader, width, height, stride)) => {
+                    let _ = opened_sender.send(Ok(()));
+                    read_frames(
+                        &reader,
+                        width,
+                        height,
+                        stride,
+                        &thread_latest,
+                        &thread_running,
+                    );
+                }
+                Err(e) => {
+                    let _ = opened_sender.send(Err(e));
+                }
+            }
+            if let Err(e) = shutdown_media_foundation() {
+                warn!(
+                    "Failed to release Media Foundation after webcam capture: {:?}",
+                    e
+                );
+            }
+        });
+
+        let opened = opened_receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Error::new(E_INVALIDARG, "Webcam thread exited".into())));
+        let reader = Self {
+            latest,
+            running,
+            handle: Some(handle),
+        };
+        // On failure the thread has already exited and dropping the reader joins it
+        opened?;
+        Ok(reader)
+    }
+
+    /// The latest frame read, None until the first one arrives
+    pub fn latest(&self) -> &Mutex<Option<WebcamFrame>> {
+        &self.latest
+    }
+
+    /// Stop reading and wait for the reader thread, which finishes its current read first
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for WebcamReader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Source reader of the webcam converting to RGB32, with the frame size and default stride
+unsafe fn open_webcam(device_name: Option<&str>) -> Result<(IMFSourceReader, u32, u32, i32)> {
+    let mut attributes = None;
+    MFCreateAttributes(&mut attributes, 1)?;
+    let attributes = attributes.unwrap();
+    attributes.SetGUID(
+        &MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE,
+        &MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE_VIDCAP_GUID,
+    )?;
+
+    let mut activate_array: *mut Option<IMFActivate> = std::ptr::null_mut();
+    let mut count = 0u32;
+    MFEnumDeviceSources(&attributes, &mut activate_array, &mut count)?;
+    let devices: Vec<IMFActivate> = if activate_array.is_null() {
+        Vec::new()
+    } else {
+        let devices = std::slice::from_raw_parts_mut(activate_array, count as usize)
+            .iter_mut()
+            .filter_map(Option::take)
+            .collect();
+        CoTaskMemFree(Some(activate_array as *const _));
+        devices
+    };
+    debug!("Found {} webcam(s)", devices.len());
+
+    let mut chosen = None;
+    for device in devices {
+        let name = friendly_name(&device);
+        let matches =
+            device_name.is_none_or(|wanted| name.to_lowercase().contains(&wanted.to_lowercase()));
+        if matches {
+            info!("Capturing webcam '{}'", name);
+            chosen = Some(device);
+            break;
+        }
+    }
+    let Some(device) = chosen else {
+        return Err(Error::new(
+            E_INVALIDARG,
+            format!("No webcam found matching {:?}", device_name).into(),
+        ));
+    };
+    let source: IMFMediaSource = device.ActivateObject()?;
+
+    let mut reader_attributes = None;
+    MFCreateAttributes(&mut reader_attributes, 1)?;
+    let reader_attributes = reader_attributes.unwrap();
+    // Let the reader convert whatever the camera delivers (NV12, YUY2, MJPG) to RGB32
+    reader_attributes.SetUINT32(&MF_SOURCE_READER_ENABLE_VIDEO_PROCESSING, 1)?;
+    let reader = MFCreateSourceReaderFromMediaSource(&source, &reader_attributes)?;
+
+    let stream = MF_SOURCE_READER_FIRST_VIDEO_STREAM.0 as u32;
+    let media_type = MFCreateMediaType()?;
+    media_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+    media_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_RGB32)?;
+    reader.SetCurrentMediaType(stream, None, &media_type)?;
+
+    let current = reader.GetCurrentMediaType(stream)?;
+    let frame_size = current.GetUINT64(&MF_MT_FRAME_SIZE)?;
+    let (width, height) = ((frame_size >> 32) as u32, frame_size as u32);
+    let stride = current
+        .GetUINT32(&MF_MT_DEFAULT_STRIDE)
+        .map(|stride| stride as i32)
+        .unwrap_or(width as i32 * 4);
+    info!(
+        "Webcam delivers {}x{} frames (stride {})",
+        width, height, stride
+    );
+    Ok((reader, width, height, stride))
+}
+
+unsafe fn friendly_name(device: &IMFActivate) -> String {
+    let mut name_ptr = PWSTR::null();
+    let mut length = 0u32;
+    if device
+        .GetAllocatedString(
+            &MF_DEVSOURCE_ATTRIBUTE_FRIENDLY_NAME,
+            &mut name_ptr,
+            &mut length,
+        )
+        .is_err()
+        || name_ptr.is_null()
+    {
+        return String::new();
+    }
+    let name = name_ptr.to_string().unwrap_or_default();
+    CoTaskMemFree(Some(name_ptr.as_ptr() as *const _));
+    name
+}
+
+/// Read frames into `latest` until `running` clears or the webcam fails
+unsafe fn read_frames(
+    reader: &IMFSourceReader,
+    width: u32,
+    height: u32,
+    default_stride: i32,
+    latest: &Mutex<Option<WebcamFrame>>,
+    running: &AtomicBool,
+) {
+    let stream = MF_SOURCE_READER_FIRST_VIDEO_STREAM.0 as u32;
+    let mut sequence = 0u64;
+    while running.load(Ordering::Relaxed) {
+        let mut flags = 0u32;
+        let mut sample = None;
+        if let Err(e) =
+            reader.ReadSample(stream, 0, None, Some(&mut flags), None, Some(&mut sample))
+        {
+            warn!("Failed to read a webcam frame: {:?}", e);
+            break;
+        }
+        if flags & MF_SOURCE_READERF_ENDOFSTREAM.0 as u32 != 0 {
+            info!("Webcam stream ended");
+            break;
+        }
+        let Some(sample) = sample else {
+            continue;
+        };
+
+        match copy_frame(&sample, width, height, default_stride) {
+            Ok(data) => {
+                sequence += 1;
+                *latest.lock().unwrap() = Some(WebcamFrame {
+                    width,
+                    height,
+                    data,
+                    sequence,
+                });
+            }
+            Err(e) => warn!("Failed to copy a webcam frame: {:?}", e),
+        }
+    }
+    debug!("Webcam reader finished after {} frames", sequence);
+}
+
+/// The frame in `sample` as top-down rows of `width * 4` bytes
+unsafe fn copy_frame(
+    sample: &IMFSample,
+    width: u32,
+    height: u32,
+    default_stride: i32,
+) -> Result<Vec<u8>> {
+    let buffer = sample.ConvertToContiguousBuffer()?;
+    let row_bytes = width as usize * 4;
+    let mut data = vec![0u8; row_bytes * height as usize];
+
+    // 2D buffers report the first row and its pitch, which is negative for bottom-up images
+    let (scanline0, pitch, buffer_2d) = match buffer.cast::<IMF2DBuffer>() {
+        Ok(buffer_2d) => {
+            let mut scanline0 = std::ptr::null_mut();
+            let mut pitch = 0i32;
+            buffer_2d.Lock2D(&mut scanline0, &mut pitch)?;
+            (scanline0 as *const u8, pitch, Some(buffer_2d))
+        }
+        Err(_) => {
+            let mut start = std::ptr::null_mut();
+            buffer.Lock(&mut start, None, None)?;
+            let first_row = if default_stride < 0 {
+                start.add((height as usize - 1) * default_stride.unsigned_abs() as usize)
+            } else {
+                start
+            };
+            (first_row as *const u8, default_stride, None)
+        }
+    };
+
+    for (row, target) in data.chunks_exact_mut(row_bytes).enumerate() {
+        let source = scanline0.offset(row as isize * pitch as isize);
+        std::ptr::copy_nonoverlapping(source, target.as_mut_ptr(), row_bytes);
+    }
+
+    match buffer_2d {
+        Some(buffer_2d) => buffer_2d.Unlock2D()?,
+        None => buffer.Unlock()?,
+    }
+    Ok(data)
+}
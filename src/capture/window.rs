@@ -1,10 +1,154 @@
-use log::{debug, info, trace, warn};
+use crate::logging::{debug, info, trace, warn};
 use std::sync::atomic::{AtomicIsize, Ordering};
-use windows::Win32::Foundation::{BOOL, HWND, LPARAM, RECT};
+use std::sync::Arc;
+use std::time::Duration;
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{
+    CloseHandle, BOOL, FALSE, HWND, LPARAM, POINT, RECT, STILL_ACTIVE,
+};
+use windows::Win32::Graphics::Dwm::{
+    DwmGetWindowAttribute, DWMWA_EXTENDED_FRAME_BOUNDS, DWMWA_WINDOW_CORNER_PREFERENCE,
+    DWMWCP_DONOTROUND, DWMWCP_ROUNDSMALL, DWM_WINDOW_CORNER_PREFERENCE,
+};
+use windows::Win32::Graphics::Gdi::ClientToScreen;
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+    TH32CS_SNAPPROCESS,
+};
+use windows::Win32::System::Threading::{
+    GetExitCodeProcess, OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+    PROCESS_QUERY_LIMITED_INFORMATION,
+};
+use windows::Win32::UI::HiDpi::{
+    GetDpiForWindow, SetThreadDpiAwarenessContext, DPI_AWARENESS_CONTEXT,
+    DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+};
+use windows::Win32::UI::Shell::{SHQueryUserNotificationState, QUNS_RUNNING_D3D_FULL_SCREEN};
 use windows::Win32::UI::WindowsAndMessaging::{
-    GetClientRect, GetWindowRect, GetWindowTextW, IsWindow, IsWindowVisible,
+    EnumWindows, GetClientRect, GetForegroundWindow, GetWindow, GetWindowDisplayAffinity,
+    GetWindowLongW, GetWindowRect, GetWindowTextW, GetWindowThreadProcessId, IsIconic, IsWindow,
+    IsWindowVisible, IsZoomed, SetWindowDisplayAffinity, GWL_EXSTYLE, GW_OWNER,
+    WDA_EXCLUDEFROMCAPTURE, WINDOW_DISPLAY_AFFINITY, WS_EX_TOOLWINDOW,
 };
 
+/// Radius of the rounded window corners Windows 11 draws, in pixels at 96 DPI
+const CORNER_RADIUS: u32 = 8;
+/// Radius of the corners of windows that asked for `DWMWCP_ROUNDSMALL`
+const SMALL_CORNER_RADIUS: u32 = 4;
+/// Width of the border Windows 11 draws inside the frame of rounded windows
+const ROUNDED_BORDER: u32 = 1;
+
+/// How the rounded corners Windows 11 draws around windows are recorded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WindowCorners {
+    /// Record the corners as they are, with whatever is behind the window showing through
+    #[default]
+    Keep,
+    /// Shrink the recorded area just enough to leave out the corners and the window border
+    Crop,
+    /// Keep the recorded area and paint the pixels outside the rounded corners black
+    Mask,
+}
+
+/// What is recorded while the target window is minimized
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MinimizedPolicy {
+    /// Record black frames
+    #[default]
+    Blank,
+    /// Keep repeating the last frame captured before the window was minimized
+    LastFrame,
+    /// Record no frames, leaving a gap in the video where players hold the last frame
+    Pause,
+}
+
+/// What happens when a lost target window has not come back within `ReacquirePolicy::timeout`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReacquireTimeout {
+    /// Stop and finalize the recording
+    StopRecording,
+    /// Keep looking for the window for as long as the recording runs
+    #[default]
+    KeepWaiting,
+}
+
+/// How the capture thread looks for the target window again after it was closed or recreated
+///
+/// Retries start `interval` apart and back off exponentially up to one second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ReacquirePolicy {
+    /// Delay before the first retry
+    pub interval: Duration,
+    /// How long the window may stay lost before `on_timeout` applies
+    pub timeout: Duration,
+    pub on_timeout: ReacquireTimeout,
+}
+
+impl Default for ReacquirePolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_secs(30),
+            on_timeout: ReacquireTimeout::default(),
+        }
+    }
+}
+
+/// Picks the window to record among several matches, by index into the slice; None cancels
+/// the start
+pub type WindowPickCallback = Arc<dyn Fn(&[CapturableWindow]) -> Option<usize> + Send + Sync>;
+
+/// Which window is recorded when several windows match the target
+#[derive(Clone, Default)]
+pub enum WindowPick {
+    /// The first match found, the one highest in Z order
+    #[default]
+    First,
+    /// The match with the largest window on screen
+    Largest,
+    /// The foreground window if it matches, otherwise the match activated last
+    MostRecentlyActive,
+    /// Let the application choose, e.g. by asking the user; called with the matches in Z order,
+    /// only when there is more than one
+    Interactive(WindowPickCallback),
+    /// The match at this position in Z order, 0 being the topmost
+    Index(usize),
+}
+
+impl std::fmt::Debug for WindowPick {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowPick::First => write!(f, "First"),
+            WindowPick::Largest => write!(f, "Largest"),
+            WindowPick::MostRecentlyActive => write!(f, "MostRecentlyActive"),
+            WindowPick::Interactive(_) => write!(f, "Interactive(..)"),
+            WindowPick::Index(index) => f.debug_tuple("Index").field(index).finish(),
+        }
+    }
+}
+
+/// How the target window is shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DisplayMode {
+    /// A window on the desktop, recorded by cropping to it
+    #[default]
+    Windowed,
+    /// A frameless window covering its whole monitor, recorded as the whole monitor
+    Borderless,
+    /// Exclusive fullscreen, where the game owns the display and may change its resolution;
+    /// recorded as the whole monitor
+    ExclusiveFullscreen,
+}
+
 /// Defines how window titles should be matched
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WindowMatchType {
@@ -20,6 +164,338 @@ impl Default for WindowMatchType {
     }
 }
 
+/// Identifies the window to record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowTarget {
+    /// The first visible window whose title matches
+    Title { title: String, exact_match: bool },
+    /// The main window of a process
+    ProcessId(u32),
+    /// The main window of the first process started from an executable, given as a file name
+    /// such as `game.exe` (case-insensitive, `.exe` optional) or as a full path
+    Executable(String),
+    /// A specific window
+    Handle(HWND),
+}
+
+impl WindowTarget {
+    /// Find the window handle for this target
+    pub fn resolve(&self) -> Option<HWND> {
+        match self {
+            WindowTarget::Title { title, exact_match } => {
+                if *exact_match {
+                    get_window_by_exact_string(title)
+                } else {
+                    get_window_by_string(title)
+                }
+            }
+            WindowTarget::ProcessId(process_id) => get_window_by_process_id(*process_id),
+            WindowTarget::Executable(executable) => get_window_by_executable(executable),
+            WindowTarget::Handle(hwnd) => is_window_valid(*hwnd).then_some(*hwnd),
+        }
+    }
+
+    /// The capturable windows matching this target, in Z order
+    pub fn matching_windows(&self) -> Vec<CapturableWindow> {
+        let windows = enumerate_capturable_windows().into_iter();
+        match self {
+            WindowTarget::Title { title, exact_match } => {
+                let lowercase_title = title.to_lowercase();
+                windows
+                    .filter(|window| {
+                        if *exact_match {
+                            window.title == *title
+                        } else {
+                            window.title.to_lowercase().contains(&lowercase_title)
+                        }
+                    })
+                    .collect()
+            }
+            WindowTarget::ProcessId(process_id) => windows
+                .filter(|window| window.process_id == *process_id)
+                .collect(),
+            WindowTarget::Executable(executable) => windows
+                .filter(|window| {
+                    get_process_image_path(window.process_id)
+                        .is_some_and(|path| executable_matches(&path, executable))
+                })
+                .collect(),
+            WindowTarget::Handle(hwnd) => windows.filter(|window| window.hwnd == *hwnd).collect(),
+        }
+    }
+
+    /// Find the window to record, choosing among several matches with `pick`
+    pub fn pick(&self, pick: &WindowPick) -> Option<CapturableWindow> {
+        // A handle names one window, and the first match is what `resolve` finds
+        if matches!(self, WindowTarget::Handle(_)) || matches!(pick, WindowPick::First) {
+            return self.resolve().map(CapturableWindow::from_hwnd);
+        }
+
+        let mut matches = self.matching_windows();
+        if matches.len() > 1 {
+            info!(
+                "{} windows match {:?}, picking with {:?}",
+                matches.len(),
+                self,
+                pick
+            );
+        }
+        let index = match pick {
+            WindowPick::Largest => matches
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, window)| (window_area(window.hwnd), std::cmp::Reverse(*i)))
+                .map(|(i, _)| i)?,
+            WindowPick::MostRecentlyActive => {
+                let foreground = unsafe { GetForegroundWindow() };
+                matches
+                    .iter()
+                    .position(|window| window.hwnd == foreground)
+                    .unwrap_or(0)
+            }
+            WindowPick::Interactive(callback) if matches.len() > 1 => match callback(&matches) {
+                Some(index) => index,
+                None => {
+                    info!("Window pick cancelled");
+                    return None;
+                }
+            },
+            WindowPick::Index(index) => *index,
+            _ => 0,
+        };
+        if index >= matches.len() {
+            warn!(
+                "No window at index {} of {} matching {:?}",
+                index,
+                matches.len(),
+                self
+            );
+            return None;
+        }
+        Some(matches.swap_remove(index))
+    }
+}
+
+/// Area of a window on screen in pixels, 0 if its rect cannot be read
+fn window_area(hwnd: HWND) -> u64 {
+    get_window_rect(hwnd, false).map_or(0, |(_, _, width, height)| width as u64 * height as u64)
+}
+
+/// A top-level window that can be recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturableWindow {
+    pub hwnd: HWND,
+    pub title: String,
+    pub process_id: u32,
+    /// Executable file name, e.g. `game.exe`; empty if the process could not be queried
+    pub exe_name: String,
+}
+
+impl CapturableWindow {
+    /// Details of a window
+    pub fn from_hwnd(hwnd: HWND) -> Self {
+        let process_id = get_window_process_id(hwnd);
+        Self {
+            hwnd,
+            title: get_window_title(hwnd),
+            process_id,
+            exe_name: get_process_exe_name(process_id).unwrap_or_default(),
+        }
+    }
+}
+
+/// Lists the visible, titled top-level windows a recording can target, in Z order
+pub fn enumerate_capturable_windows() -> Vec<CapturableWindow> {
+    let mut handles: Vec<HWND> = Vec::new();
+    unsafe {
+        EnumWindows(
+            Some(collect_windows_callback),
+            LPARAM(&mut handles as *mut _ as isize),
+        );
+    }
+
+    handles
+        .into_iter()
+        .filter(|&hwnd| is_capturable_window(hwnd))
+        .map(CapturableWindow::from_hwnd)
+        .collect()
+}
+
+/// Gets the main window of a process: its first capturable top-level window
+pub fn get_window_by_process_id(process_id: u32) -> Option<HWND> {
+    let hwnd = enumerate_capturable_windows()
+        .into_iter()
+        .find(|window| window.process_id == process_id)
+        .map(|window| window.hwnd);
+
+    match hwnd {
+        Some(hwnd) => debug!("Found window {:?} for process {}", hwnd, process_id),
+        None => debug!("No window found for process {}", process_id),
+    }
+    hwnd
+}
+
+/// Gets the first capturable top-level window of a process started from `executable`
+///
+/// Matches on the process image, so it keeps working for localized titles and windows that
+/// change their title.
+pub fn get_window_by_executable(executable: &str) -> Option<HWND> {
+    let mut handles: Vec<HWND> = Vec::new();
+    unsafe {
+        EnumWindows(
+            Some(collect_windows_callback),
+            LPARAM(&mut handles as *mut _ as isize),
+        );
+    }
+
+    let hwnd = handles.into_iter().find(|&hwnd| {
+        is_capturable_window(hwnd)
+            && get_process_image_path(get_window_process_id(hwnd))
+                .is_some_and(|path| executable_matches(&path, executable))
+    });
+
+    match hwnd {
+        Some(hwnd) => debug!("Found window {:?} for executable '{}'", hwnd, executable),
+        None => debug!("No window found for executable '{}'", executable),
+    }
+    hwnd
+}
+
+/// Whether a process image path is the executable a target names: the same full path if the
+/// target is a path, otherwise the same file name, ignoring case and a missing `.exe`
+fn executable_matches(image_path: &str, executable: &str) -> bool {
+    let executable = executable.trim();
+    if executable.contains(['\\', '/']) {
+        return image_path.eq_ignore_ascii_case(&executable.replace('/', "\\"));
+    }
+
+    let file_name = std::path::Path::new(image_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let executable = executable.to_lowercase();
+    file_name == executable || file_name.strip_suffix(".exe") == Some(executable.as_str())
+}
+
+/// Gets the ID of the process that owns a window
+pub fn get_window_process_id(hwnd: HWND) -> u32 {
+    let mut process_id: u32 = 0;
+    unsafe {
+        GetWindowThreadProcessId(hwnd, Some(&mut process_id));
+    }
+    process_id
+}
+
+/// Gets the executable file name of a process, e.g. `game.exe`
+pub fn get_process_exe_name(process_id: u32) -> Option<String> {
+    let path = get_process_image_path(process_id)?;
+    std::path::Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+/// Gets the full path of the executable a process was started from
+pub fn get_process_image_path(process_id: u32) -> Option<String> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id).ok()?;
+        let mut buffer = [0u16; 1024];
+        let mut length = buffer.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut length,
+        );
+        CloseHandle(process);
+
+        if !result.as_bool() {
+            trace!("Failed to query image name of process {}", process_id);
+            return None;
+        }
+
+        Some(String::from_utf16_lossy(&buffer[..length as usize]))
+    }
+}
+
+/// Whether the process with this ID is still running
+pub fn is_process_running(process_id: u32) -> bool {
+    unsafe {
+        let Ok(process) = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id) else {
+            return false;
+        };
+        let mut exit_code = 0u32;
+        let result = GetExitCodeProcess(process, &mut exit_code);
+        CloseHandle(process);
+        result.as_bool() && exit_code == STILL_ACTIVE.0 as u32
+    }
+}
+
+/// IDs of the running processes started from an executable, given as for
+/// `WindowTarget::Executable`. Processes started by another match are left out, so every
+/// process tree is listed once
+pub fn find_processes_by_executable(executable: &str) -> Vec<u32> {
+    let mut processes = Vec::new();
+    unsafe {
+        let Ok(snapshot) = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) else {
+            warn!("Failed to list the running processes");
+            return Vec::new();
+        };
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut more = Process32FirstW(snapshot, &mut entry).as_bool();
+        while more {
+            let name_len = entry
+                .szExeFile
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            let name = String::from_utf16_lossy(&entry.szExeFile[..name_len]);
+            // The snapshot only has file names; a path needs the full image name
+            let matched = if executable.contains(['\\', '/']) {
+                get_process_image_path(entry.th32ProcessID)
+                    .is_some_and(|path| executable_matches(&path, executable))
+            } else {
+                executable_matches(&name, executable)
+            };
+            if matched {
+                processes.push((entry.th32ProcessID, entry.th32ParentProcessID));
+            }
+            more = Process32NextW(snapshot, &mut entry).as_bool();
+        }
+        CloseHandle(snapshot);
+    }
+
+    let roots: Vec<u32> = processes
+        .iter()
+        .filter(|(_, parent)| !processes.iter().any(|(id, _)| id == parent))
+        .map(|(id, _)| *id)
+        .collect();
+    debug!("Processes of executable '{}': {:?}", executable, roots);
+    roots
+}
+
+/// Visible, titled, unowned windows that are not tool windows (the windows shown in Alt+Tab)
+fn is_capturable_window(hwnd: HWND) -> bool {
+    unsafe {
+        if !IsWindowVisible(hwnd).as_bool() || GetWindow(hwnd, GW_OWNER).0 != 0 {
+            return false;
+        }
+        let ex_style = GetWindowLongW(hwnd, GWL_EXSTYLE) as u32;
+        if ex_style & WS_EX_TOOLWINDOW.0 != 0 {
+            return false;
+        }
+    }
+    !get_window_title(hwnd).is_empty()
+}
+
+unsafe extern "system" fn collect_windows_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let handles = &mut *(lparam.0 as *mut Vec<HWND>);
+    handles.push(hwnd);
+    BOOL(1) // Continue enumeration
+}
+
 struct SearchContext {
     search_string: String,
     match_type: WindowMatchType,
@@ -98,6 +574,50 @@ pub fn is_window_valid(hwnd: HWND) -> bool {
     }
 }
 
+/// Hides a window from screen capture using WDA_EXCLUDEFROMCAPTURE (Windows 10 2004+)
+/// The window must belong to the calling process
+/// Returns the previous display affinity so it can be restored later
+pub fn exclude_window_from_capture(hwnd: HWND) -> windows::core::Result<u32> {
+    unsafe {
+        let mut previous: u32 = 0;
+        if !GetWindowDisplayAffinity(hwnd, &mut previous).as_bool() {
+            return Err(windows::core::Error::from_win32());
+        }
+
+        if !SetWindowDisplayAffinity(hwnd, WDA_EXCLUDEFROMCAPTURE).as_bool() {
+            return Err(windows::core::Error::from_win32());
+        }
+
+        info!(
+            "Excluded window '{}' ({:?}) from capture (previous affinity: {})",
+            get_window_title(hwnd),
+            hwnd,
+            previous
+        );
+        Ok(previous)
+    }
+}
+
+/// Restores a display affinity saved by `exclude_window_from_capture`
+pub fn restore_window_display_affinity(hwnd: HWND, affinity: u32) {
+    unsafe {
+        if !IsWindow(hwnd).as_bool() {
+            debug!("Window {:?} no longer exists, skipping affinity restore", hwnd);
+            return;
+        }
+
+        if SetWindowDisplayAffinity(hwnd, WINDOW_DISPLAY_AFFINITY(affinity)).as_bool() {
+            info!("Restored display affinity {} for window {:?}", affinity, hwnd);
+        } else {
+            warn!(
+                "Failed to restore display affinity for window {:?}: {:?}",
+                hwnd,
+                windows::core::Error::from_win32()
+            );
+        }
+    }
+}
+
 /// Tries to get the window title for debugging purposes
 pub fn get_window_title(hwnd: HWND) -> String {
     unsafe {
@@ -107,55 +627,271 @@ pub fn get_window_title(hwnd: HWND) -> String {
     }
 }
 
-/// Gets the window position and size
-pub fn get_window_rect(hwnd: HWND) -> Option<(i32, i32, u32, u32)> {
-    unsafe {
-        let mut client_rect = RECT::default();
-        let mut window_rect = RECT::default();
+/// Makes the calling thread per-monitor DPI aware until dropped
+///
+/// Window geometry queried by a thread that is not DPI aware is scaled to the DPI of the
+/// primary monitor, which does not match the pixels of a monitor with another scale factor.
+struct PhysicalPixels(DPI_AWARENESS_CONTEXT);
 
-        // Get both the client rect and window rect
-        if GetClientRect(hwnd, &mut client_rect).as_bool()
-            && GetWindowRect(hwnd, &mut window_rect).as_bool()
-        {
-            // Use the client area dimensions (what your app expects)
-            let width = (client_rect.right - client_rect.left) as u32;
-            let height = (client_rect.bottom - client_rect.top) as u32;
-
-            // But keep the window position from the window rect
-            let left = window_rect.left;
-            let top = window_rect.top;
-
-            // Get window title for better logging
-            let title = get_window_title(hwnd);
-            let title_str = if title.is_empty() {
-                "<Unnamed>"
-            } else {
-                &title
-            };
+impl PhysicalPixels {
+    fn enter() -> Self {
+        // Returns the previous context, or an invalid one before Windows 10 1703
+        Self(unsafe { SetThreadDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) })
+    }
+}
 
-            info!(
-                "Window '{}' rect: [{}, {}, {}, {}] - {}x{}",
-                title_str,
-                left,
-                top,
-                left + width as i32,
-                top + height as i32,
-                width,
-                height
-            );
+impl Drop for PhysicalPixels {
+    fn drop(&mut self) {
+        if self.0 .0 != 0 {
+            unsafe { SetThreadDpiAwarenessContext(self.0) };
+        }
+    }
+}
 
-            Some((left, top, width, height))
-        } else {
+/// Gets the position and size of the window on the desktop, in physical pixels
+///
+/// This is the frame DWM draws, with title bar and borders but without the drop shadow. With
+/// `client_area_only` it is the client area clipped to that frame, which also leaves out the
+/// invisible resize borders of windows that draw their own title bar.
+pub fn get_window_rect(hwnd: HWND, client_area_only: bool) -> Option<(i32, i32, u32, u32)> {
+    let _physical = PhysicalPixels::enter();
+    unsafe {
+        let Some(frame) = visible_frame(hwnd) else {
             let error_code = windows::Win32::Foundation::GetLastError();
             warn!(
                 "Failed to get window rect for hwnd: {:?}, error: {:?}",
                 hwnd, error_code
             );
-            None
+            return None;
+        };
+
+        let rect = if client_area_only {
+            let mut client_rect = RECT::default();
+            let mut origin = POINT::default();
+            if !GetClientRect(hwnd, &mut client_rect).as_bool()
+                || !ClientToScreen(hwnd, &mut origin).as_bool()
+            {
+                warn!("Failed to get the client area of hwnd: {:?}", hwnd);
+                return None;
+            }
+            let left = origin.x.max(frame.left);
+            let top = origin.y.max(frame.top);
+            RECT {
+                left,
+                top,
+                right: (origin.x + client_rect.right).min(frame.right).max(left),
+                bottom: (origin.y + client_rect.bottom).min(frame.bottom).max(top),
+            }
+        } else {
+            frame
+        };
+
+        let width = (rect.right - rect.left) as u32;
+        let height = (rect.bottom - rect.top) as u32;
+
+        // Get window title for better logging
+        let title = get_window_title(hwnd);
+        let title_str = if title.is_empty() {
+            "<Unnamed>"
+        } else {
+            &title
+        };
+
+        info!(
+            "Window '{}' rect: [{}, {}, {}, {}] - {}x{}",
+            title_str, rect.left, rect.top, rect.right, rect.bottom, width, height
+        );
+
+        Some((rect.left, rect.top, width, height))
+    }
+}
+
+/// The visible bounds of a window: DWM's extended frame bounds, or the window rect (which
+/// includes the invisible resize borders) when DWM cannot report them
+unsafe fn visible_frame(hwnd: HWND) -> Option<RECT> {
+    let mut frame = RECT::default();
+    match DwmGetWindowAttribute(
+        hwnd,
+        DWMWA_EXTENDED_FRAME_BOUNDS,
+        &mut frame as *mut RECT as *mut _,
+        std::mem::size_of::<RECT>() as u32,
+    ) {
+        Ok(()) => Some(frame),
+        Err(e) => {
+            debug!("No DWM frame bounds for hwnd {:?}: {:?}", hwnd, e);
+            GetWindowRect(hwnd, &mut frame).as_bool().then_some(frame)
+        }
+    }
+}
+
+/// Radius of the rounded corners Windows draws around `hwnd`, in physical pixels, 0 if its
+/// corners are square
+///
+/// Windows before 11 do not know the corner preference, so failing to read it means square
+/// corners. Maximized and full screen windows are never rounded.
+pub fn window_corner_radius(hwnd: HWND) -> u32 {
+    unsafe {
+        let mut preference = DWM_WINDOW_CORNER_PREFERENCE::default();
+        if DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_WINDOW_CORNER_PREFERENCE,
+            &mut preference as *mut DWM_WINDOW_CORNER_PREFERENCE as *mut _,
+            std::mem::size_of::<DWM_WINDOW_CORNER_PREFERENCE>() as u32,
+        )
+        .is_err()
+        {
+            return 0;
+        }
+
+        let radius = match preference {
+            DWMWCP_DONOTROUND => return 0,
+            DWMWCP_ROUNDSMALL => SMALL_CORNER_RADIUS,
+            _ => CORNER_RADIUS,
+        };
+        if IsZoomed(hwnd).as_bool() {
+            return 0;
         }
+        let _physical = PhysicalPixels::enter();
+        if let (Some(frame), Some(monitor)) = (
+            visible_frame(hwnd),
+            super::monitor::get_window_monitor_rect(hwnd),
+        ) {
+            if frame == monitor {
+                return 0;
+            }
+        }
+        radius * GetDpiForWindow(hwnd).max(96) / 96
     }
 }
 
+/// Whether `hwnd` is minimized, in which case its rect is parked far off screen (-32000, -32000)
+pub fn is_window_minimized(hwnd: HWND) -> bool {
+    unsafe { IsIconic(hwnd).as_bool() }
+}
+
+/// Whether `hwnd` is windowed, borderless or exclusive fullscreen
+///
+/// Exclusive fullscreen is what the shell reports as a running full screen Direct3D app while
+/// the window is in the foreground; a window whose visible frame covers its monitor is borderless.
+pub fn get_display_mode(hwnd: HWND) -> DisplayMode {
+    unsafe {
+        if GetForegroundWindow() == hwnd
+            && SHQueryUserNotificationState().ok() == Some(QUNS_RUNNING_D3D_FULL_SCREEN)
+        {
+            return DisplayMode::ExclusiveFullscreen;
+        }
+
+        let _physical = PhysicalPixels::enter();
+        match (
+            visible_frame(hwnd),
+            super::monitor::get_window_monitor_rect(hwnd),
+        ) {
+            (Some(frame), Some(monitor))
+                if frame.left <= monitor.left
+                    && frame.top <= monitor.top
+                    && frame.right >= monitor.right
+                    && frame.bottom >= monitor.bottom =>
+            {
+                DisplayMode::Borderless
+            }
+            _ => DisplayMode::Windowed,
+        }
+    }
+}
+
+/// Gets the rect of the monitor `hwnd` is on, in physical pixels, as (x, y, width, height)
+pub fn get_monitor_rect(hwnd: HWND) -> Option<(i32, i32, u32, u32)> {
+    let _physical = PhysicalPixels::enter();
+    let monitor = super::monitor::get_window_monitor_rect(hwnd)?;
+    Some((
+        monitor.left,
+        monitor.top,
+        (monitor.right - monitor.left) as u32,
+        (monitor.bottom - monitor.top) as u32,
+    ))
+}
+
+/// Desktop rect (x, y, width, height) and the radii (top, bottom) of the corners still to mask
+pub type CaptureRect = ((i32, i32, u32, u32), (u32, u32));
+
+/// Gets the window rect recorded for `hwnd` (see [`get_window_rect`]) with `corners` applied,
+/// along with the corner radii (top, bottom) the processing still has to mask
+///
+/// Only the bottom corners of the client area are rounded; the top ones belong to the title bar.
+pub fn get_capture_rect(
+    hwnd: HWND,
+    client_area_only: bool,
+    corners: WindowCorners,
+) -> Option<CaptureRect> {
+    let rect = get_window_rect(hwnd, client_area_only)?;
+    let radius = match corners {
+        WindowCorners::Keep => 0,
+        WindowCorners::Crop | WindowCorners::Mask => window_corner_radius(hwnd),
+    };
+    if radius == 0 {
+        return Some((rect, (0, 0)));
+    }
+    let top_radius = if client_area_only { 0 } else { radius };
+
+    if corners == WindowCorners::Mask {
+        return Some((rect, (top_radius, radius)));
+    }
+
+    // A rectangle inset by r(1 - 1/sqrt(2)) has its corners on the arc of a radius r corner
+    let inset =
+        |radius: u32| (radius as f32 * (1.0 - std::f32::consts::FRAC_1_SQRT_2)).ceil() as u32;
+    let border = if client_area_only { 0 } else { ROUNDED_BORDER };
+    let side = inset(radius) + border;
+    let top = inset(top_radius) + border;
+    let (x, y, width, height) = rect;
+    let cropped = (
+        x + side as i32,
+        y + top as i32,
+        width.saturating_sub(2 * side),
+        height.saturating_sub(top + side),
+    );
+    debug!(
+        "Cropped window rect {:?} to {:?} for corners of radius {}",
+        rect, cropped, radius
+    );
+    Some((cropped, (0, 0)))
+}
+
+/// What is recorded of a window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureRegion {
+    /// Desktop rect (x, y, width, height) in physical pixels
+    pub rect: (i32, i32, u32, u32),
+    /// Radii (top, bottom) of the corners the processing still has to mask
+    pub corner_radii: (u32, u32),
+    pub display_mode: DisplayMode,
+}
+
+/// Gets what is recorded of `hwnd`: the rect from [`get_capture_rect`] while it is windowed, or
+/// its whole monitor while it is fullscreen, since the window rect of a game that just switched
+/// modes may not match the frame. None while the window is minimized.
+pub fn get_capture_region(
+    hwnd: HWND,
+    client_area_only: bool,
+    corners: WindowCorners,
+) -> Option<CaptureRegion> {
+    if is_window_minimized(hwnd) {
+        return None;
+    }
+    let display_mode = get_display_mode(hwnd);
+    let (rect, corner_radii) = match display_mode {
+        DisplayMode::Windowed => get_capture_rect(hwnd, client_area_only, corners)?,
+        DisplayMode::Borderless | DisplayMode::ExclusiveFullscreen => {
+            (get_monitor_rect(hwnd)?, (0, 0))
+        }
+    };
+    Some(CaptureRegion {
+        rect,
+        corner_radii,
+        display_mode,
+    })
+}
+
 unsafe extern "system" fn window_enumeration_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
     let context = &*(lparam.0 as *const SearchContext);
 
@@ -176,7 +912,7 @@ unsafe extern "system" fn window_enumeration_callback(hwnd: HWND, lparam: LPARAM
 
     // Print both strings for debugging
     if context.match_type == WindowMatchType::ExactMatch {
-        log::debug!(
+        crate::logging::debug!(
             "Exact matching: '{}' vs '{}'",
             window_text,
             context.search_string
@@ -1,4 +1,4 @@
-use log::info;
+use crate::logging::info;
 use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
 use windows::Win32::Foundation::CO_E_ALREADYINITIALIZED;
 use std::ffi::OsString;
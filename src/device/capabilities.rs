@@ -0,0 +1,465 @@
+use crate::error::RecorderError;
+use crate::Result;
+use crate::logging::{debug, info, warn};
+use windows::{
+    core::{ComInterface, GUID, PWSTR},
+    Win32::{
+        Media::MediaFoundation::*,
+        System::Com::{CoTaskMemFree, VARIANT, VT_I4, VT_UI4},
+    },
+};
+
+use super::video::{VideoEncoder, VideoEncoderType};
+use crate::device::ensure_com_initialized;
+use crate::processing::media;
+
+/// Resolutions probed for the maximum supported size, largest first
+const PROBE_RESOLUTIONS: [(u32, u32); 6] = [
+    (7680, 4320),
+    (4096, 2160),
+    (3840, 2160),
+    (2560, 1440),
+    (1920, 1080),
+    (1280, 720),
+];
+
+/// Frame rates probed for the maximum supported rate, highest first
+const PROBE_FRAME_RATES: [u32; 7] = [240, 165, 144, 120, 90, 60, 30];
+
+/// Bitrate set on probe media types; some encoders reject output types without one
+const PROBE_BITRATE: u32 = 5_000_000;
+
+/// Encoding profile of an H.264 or HEVC stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VideoProfile {
+    /// H.264 Baseline
+    Baseline,
+    /// H.264 Main or HEVC Main
+    Main,
+    /// H.264 High
+    High,
+    /// HEVC Main 10
+    Main10,
+    /// H.264 High 4:4:4 Predictive
+    High444,
+    /// HEVC Main 4:4:4
+    Main444,
+}
+
+impl VideoProfile {
+    /// Profiles that exist for an encoder type
+    pub(crate) fn candidates(encoder_type: VideoEncoderType) -> &'static [VideoProfile] {
+        match encoder_type {
+            VideoEncoderType::H264 => &[Self::Baseline, Self::Main, Self::High, Self::High444],
+            VideoEncoderType::HEVC => &[Self::Main, Self::Main10, Self::Main444],
+        }
+    }
+
+    /// The 4:4:4 profile of an encoder type
+    pub(crate) fn full_chroma(encoder_type: VideoEncoderType) -> VideoProfile {
+        match encoder_type {
+            VideoEncoderType::H264 => Self::High444,
+            VideoEncoderType::HEVC => Self::Main444,
+        }
+    }
+
+    /// Value of `MF_MT_VIDEO_PROFILE` for this profile
+    pub(crate) fn media_type_value(&self, encoder_type: VideoEncoderType) -> u32 {
+        match (encoder_type, self) {
+            (VideoEncoderType::H264, Self::Baseline) => eAVEncH264VProfile_Base.0 as u32,
+            (VideoEncoderType::H264, Self::High) => eAVEncH264VProfile_High.0 as u32,
+            (VideoEncoderType::H264, Self::High444) => eAVEncH264VProfile_444.0 as u32,
+            (VideoEncoderType::H264, _) => eAVEncH264VProfile_Main.0 as u32,
+            (VideoEncoderType::HEVC, Self::Main10) => eAVEncH265VProfile_Main_420_10.0 as u32,
+            (VideoEncoderType::HEVC, Self::Main444) => eAVEncH265VProfile_Main_444_8.0 as u32,
+            (VideoEncoderType::HEVC, _) => eAVEncH265VProfile_Main_420_8.0 as u32,
+        }
+    }
+}
+
+/// Rate control modes exposed through `CODECAPI_AVEncCommonRateControlMode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateControlMode {
+    Cbr,
+    PeakConstrainedVbr,
+    UnconstrainedVbr,
+    Quality,
+    LowDelayVbr,
+    GlobalVbr,
+    GlobalLowDelayVbr,
+}
+
+impl RateControlMode {
+    const ALL: [RateControlMode; 7] = [
+        Self::Cbr,
+        Self::PeakConstrainedVbr,
+        Self::UnconstrainedVbr,
+        Self::Quality,
+        Self::LowDelayVbr,
+        Self::GlobalVbr,
+        Self::GlobalLowDelayVbr,
+    ];
+
+    fn from_value(value: u32) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    fn value(&self) -> u32 {
+        Self::ALL.iter().position(|mode| mode == self).unwrap() as u32
+    }
+}
+
+/// What a video encoder reports it can do
+///
+/// Values come from test-only media type negotiation and `ICodecAPI`. Hardware encoders
+/// sometimes accept settings they later fail on, so treat these as an upper bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderCapabilities {
+    /// Whether this is a hardware (GPU) encoder
+    pub hardware: bool,
+    /// Largest probed resolution the encoder accepts
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Highest probed frame rate the encoder accepts at 1080p (or its max resolution if smaller)
+    pub max_fps: u32,
+    pub profiles: Vec<VideoProfile>,
+    /// Supported levels times ten, e.g. `51` for level 5.1
+    pub levels: Vec<u32>,
+    pub rate_control_modes: Vec<RateControlMode>,
+    /// Whether B-frames can be enabled
+    pub b_frames: bool,
+    /// Whether the encoder has a low-latency mode
+    pub low_latency: bool,
+    /// Whether the encoder takes BGRA frames and converts them itself, see
+    /// [`encoder_accepts_rgb_input`]
+    pub rgb_input: bool,
+}
+
+impl VideoEncoder {
+    /// Query what this encoder supports, see [`query_encoder_capabilities`]
+    pub fn capabilities(&self) -> Result<EncoderCapabilities> {
+        query_encoder_capabilities(self)
+    }
+}
+
+/// Instantiate an encoder and probe its supported resolutions, frame rates, profiles, levels and
+/// rate control modes
+pub fn query_encoder_capabilities(encoder: &VideoEncoder) -> Result<EncoderCapabilities> {
+    info!("Querying capabilities of encoder '{}'", encoder.name);
+    ensure_com_initialized()?;
+
+    unsafe {
+        media::init_media_foundation()?;
+        let result = probe_encoder(encoder);
+        let _ = media::shutdown_media_foundation();
+        result
+    }
+}
+
+/// Whether the encoder registers `MFVideoFormat_ARGB32` as an input type, so captured BGRA
+/// frames can skip the NV12 conversion
+pub fn encoder_accepts_rgb_input(encoder: &VideoEncoder) -> bool {
+    let result = ensure_com_initialized()
+        .and_then(|()| unsafe { find_encoder_activate(encoder, Some(&MFVideoFormat_ARGB32)) });
+    match result {
+        Ok(activate) => {
+            debug!(
+                "Encoder '{}' accepts RGB input: {}",
+                encoder.name,
+                activate.is_some()
+            );
+            activate.is_some()
+        }
+        Err(e) => {
+            warn!(
+                "Failed to check RGB input of encoder '{}': {:?}",
+                encoder.name, e
+            );
+            false
+        }
+    }
+}
+
+/// Create the encoder once and shut it down again, which loads its DLL and driver so the sink
+/// writer creates it faster later
+pub(crate) fn load_encoder(encoder: &VideoEncoder) -> Result<()> {
+    ensure_com_initialized()?;
+    unsafe {
+        let activate = find_encoder_activate(encoder, None)?.ok_or_else(|| {
+            RecorderError::Generic(format!("Video encoder '{}' not found", encoder.name))
+        })?;
+        let _transform: IMFTransform = activate.ActivateObject()?;
+        let _ = activate.ShutdownObject();
+    }
+    debug!("Loaded encoder '{}'", encoder.name);
+    Ok(())
+}
+
+unsafe fn probe_encoder(encoder: &VideoEncoder) -> Result<EncoderCapabilities> {
+    let activate = find_encoder_activate(encoder, None)?.ok_or_else(|| {
+        RecorderError::Generic(format!("Video encoder '{}' not found", encoder.name))
+    })?;
+
+    let flags = activate.GetUINT32(&MF_TRANSFORM_FLAGS_Attribute).unwrap_or(0);
+    let hardware = flags & MFT_ENUM_FLAG_HARDWARE.0 as u32 != 0;
+
+    let transform: IMFTransform = activate.ActivateObject()?;
+    // Hardware encoders are asynchronous and refuse calls until unlocked
+    if let Ok(attributes) = transform.GetAttributes() {
+        if attributes.GetUINT32(&MF_TRANSFORM_ASYNC).unwrap_or(0) != 0 {
+            attributes.SetUINT32(&MF_TRANSFORM_ASYNC_UNLOCK, 1)?;
+        }
+    }
+
+    let encoder_type = encoder.encoder_type;
+    // The profiles the recorder itself encodes with
+    let default_profile = match encoder_type {
+        VideoEncoderType::H264 => VideoProfile::High,
+        VideoEncoderType::HEVC => VideoProfile::Main,
+    };
+    let accepts = |width, height, fps, profile: VideoProfile, level: Option<u32>| {
+        test_output_type(&transform, encoder, width, height, fps, profile, level)
+    };
+
+    let (max_width, max_height) = PROBE_RESOLUTIONS
+        .iter()
+        .copied()
+        .find(|&(width, height)| accepts(width, height, 30, default_profile, None))
+        .unwrap_or((0, 0));
+
+    let (fps_width, fps_height) = if max_width >= 1920 {
+        (1920, 1080)
+    } else {
+        (max_width, max_height)
+    };
+    let max_fps = PROBE_FRAME_RATES
+        .iter()
+        .copied()
+        .find(|&fps| accepts(fps_width, fps_height, fps, default_profile, None))
+        .unwrap_or(0);
+
+    let profiles: Vec<VideoProfile> = VideoProfile::candidates(encoder_type)
+        .iter()
+        .copied()
+        .filter(|&profile| accepts(1280, 720, 30, profile, None))
+        .collect();
+
+    let level_values: &[i32] = match encoder_type {
+        VideoEncoderType::H264 => &[
+            eAVEncH264VLevel3.0,
+            eAVEncH264VLevel3_1.0,
+            eAVEncH264VLevel4.0,
+            eAVEncH264VLevel4_1.0,
+            eAVEncH264VLevel4_2.0,
+            eAVEncH264VLevel5.0,
+            eAVEncH264VLevel5_1.0,
+            eAVEncH264VLevel5_2.0,
+        ],
+        VideoEncoderType::HEVC => &[
+            eAVEncH265VLevel3.0,
+            eAVEncH265VLevel3_1.0,
+            eAVEncH265VLevel4.0,
+            eAVEncH265VLevel4_1.0,
+            eAVEncH265VLevel5.0,
+            eAVEncH265VLevel5_1.0,
+            eAVEncH265VLevel5_2.0,
+            eAVEncH265VLevel6.0,
+            eAVEncH265VLevel6_1.0,
+            eAVEncH265VLevel6_2.0,
+        ],
+    };
+    let levels = level_values
+        .iter()
+        .filter(|&&level| accepts(1280, 720, 30, default_profile, Some(level as u32)))
+        .map(|&level| match encoder_type {
+            // HEVC levels are stored as level times thirty
+            VideoEncoderType::HEVC => level as u32 / 3,
+            VideoEncoderType::H264 => level as u32,
+        })
+        .collect();
+
+    let (rate_control_modes, b_frames, low_latency) = match transform.cast::<ICodecAPI>() {
+        Ok(codec_api) => (
+            query_rate_control_modes(&codec_api),
+            codec_api
+                .IsSupported(&CODECAPI_AVEncMPVDefaultBPictureCount)
+                .is_ok(),
+            codec_api.IsSupported(&CODECAPI_AVLowLatencyMode).is_ok()
+                || codec_api
+                    .IsSupported(&CODECAPI_AVEncCommonLowLatency)
+                    .is_ok(),
+        ),
+        Err(e) => {
+            warn!("Encoder '{}' does not expose ICodecAPI: {:?}", encoder.name, e);
+            (Vec::new(), false, false)
+        }
+    };
+
+    let _ = activate.ShutdownObject();
+    let rgb_input = encoder_accepts_rgb_input(encoder);
+
+    let capabilities = EncoderCapabilities {
+        hardware,
+        max_width,
+        max_height,
+        max_fps,
+        profiles,
+        levels,
+        rate_control_modes,
+        b_frames,
+        low_latency,
+        rgb_input,
+    };
+    info!("Encoder '{}' capabilities: {:?}", encoder.name, capabilities);
+    Ok(capabilities)
+}
+
+/// Find the activation object of the encoder with the given name and output format, and the
+/// given input format if any
+unsafe fn find_encoder_activate(
+    encoder: &VideoEncoder,
+    input_subtype: Option<&GUID>,
+) -> windows::core::Result<Option<IMFActivate>> {
+    let input_type_info = input_subtype.map(|subtype| MFT_REGISTER_TYPE_INFO {
+        guidMajorType: MFMediaType_Video,
+        guidSubtype: *subtype,
+    });
+    let output_type_info = MFT_REGISTER_TYPE_INFO {
+        guidMajorType: MFMediaType_Video,
+        guidSubtype: encoder.output_format_guid,
+    };
+
+    let mut activates_ptr: *mut Option<IMFActivate> = std::ptr::null_mut();
+    let mut count: u32 = 0;
+    MFTEnumEx(
+        MFT_CATEGORY_VIDEO_ENCODER,
+        MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER,
+        input_type_info.as_ref().map(|info| info as *const _),
+        Some(&output_type_info),
+        &mut activates_ptr,
+        &mut count,
+    )?;
+    if activates_ptr.is_null() {
+        return Ok(None);
+    }
+
+    let mut found = None;
+    for i in 0..count as usize {
+        // Take ownership so every activate is released
+        let Some(activate) = std::ptr::read(activates_ptr.add(i)) else {
+            continue;
+        };
+        if found.is_some() {
+            continue;
+        }
+
+        let mut name_ptr = PWSTR::null();
+        let mut length = 0;
+        if activate
+            .GetAllocatedString(&MFT_FRIENDLY_NAME_Attribute, &mut name_ptr, &mut length)
+            .is_ok()
+        {
+            let name = name_ptr.to_string().unwrap_or_default();
+            CoTaskMemFree(Some(name_ptr.as_ptr() as *const _));
+            if name == encoder.name {
+                found = Some(activate);
+            }
+        }
+    }
+    CoTaskMemFree(Some(activates_ptr as *const _));
+
+    Ok(found)
+}
+
+/// Whether the encoder accepts an output type, without changing its state
+#[allow(clippy::too_many_arguments)]
+unsafe fn test_output_type(
+    transform: &IMFTransform,
+    encoder: &VideoEncoder,
+    width: u32,
+    height: u32,
+    fps: u32,
+    profile: VideoProfile,
+    level: Option<u32>,
+) -> bool {
+    let output_type = || -> windows::core::Result<IMFMediaType> {
+        let output_type = MFCreateMediaType()?;
+        output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+        output_type.SetGUID(&MF_MT_SUBTYPE, &encoder.output_format_guid)?;
+        output_type.SetUINT64(&MF_MT_FRAME_SIZE, ((width as u64) << 32) | (height as u64))?;
+        output_type.SetUINT64(&MF_MT_FRAME_RATE, ((fps as u64) << 32) | 1)?;
+        output_type.SetUINT64(&MF_MT_PIXEL_ASPECT_RATIO, (1 << 32) | 1u64)?;
+        output_type.SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
+        output_type.SetUINT32(&MF_MT_AVG_BITRATE, PROBE_BITRATE)?;
+        output_type.SetUINT32(
+            &MF_MT_VIDEO_PROFILE,
+            profile.media_type_value(encoder.encoder_type),
+        )?;
+        if let Some(level) = level {
+            output_type.SetUINT32(&MF_MT_VIDEO_LEVEL, level)?;
+        }
+        Ok(output_type)
+    };
+
+    let result = output_type().and_then(|output_type| {
+        transform.SetOutputType(0, &output_type, MFT_SET_TYPE_TEST_ONLY.0 as u32)
+    });
+    debug!(
+        "Probe {}x{}@{} {:?} level {:?}: {:?}",
+        width, height, fps, profile, level, result
+    );
+    result.is_ok()
+}
+
+/// Read the supported rate control modes, probing each one if the encoder does not list them
+unsafe fn query_rate_control_modes(codec_api: &ICodecAPI) -> Vec<RateControlMode> {
+    if codec_api
+        .IsSupported(&CODECAPI_AVEncCommonRateControlMode)
+        .is_err()
+    {
+        return Vec::new();
+    }
+
+    let mut values: *mut VARIANT = std::ptr::null_mut();
+    let mut count = 0;
+    if codec_api
+        .GetParameterValues(&CODECAPI_AVEncCommonRateControlMode, &mut values, &mut count)
+        .is_ok()
+        && !values.is_null()
+    {
+        let modes = std::slice::from_raw_parts(values, count as usize)
+            .iter()
+            .filter_map(|value| {
+                let value = &value.Anonymous.Anonymous;
+                match value.vt {
+                    VT_UI4 => Some(value.Anonymous.ulVal),
+                    VT_I4 => Some(value.Anonymous.lVal as u32),
+                    _ => None,
+                }
+            })
+            .filter_map(RateControlMode::from_value)
+            .collect();
+        CoTaskMemFree(Some(values as *const _));
+        return modes;
+    }
+
+    // Restore whatever mode was set once probing is done
+    let original = codec_api.GetValue(&CODECAPI_AVEncCommonRateControlMode).ok();
+    let modes = RateControlMode::ALL
+        .iter()
+        .copied()
+        .filter(|mode| {
+            let mut value = VARIANT::default();
+            (*value.Anonymous.Anonymous).vt = VT_UI4;
+            (*value.Anonymous.Anonymous).Anonymous.ulVal = mode.value();
+            codec_api
+                .SetValue(&CODECAPI_AVEncCommonRateControlMode, &value)
+                .is_ok()
+        })
+        .collect();
+    if let Some(original) = original {
+        let _ = codec_api.SetValue(&CODECAPI_AVEncCommonRateControlMode, &original);
+    }
+    modes
+}
@@ -0,0 +1,178 @@
+use crate::logging::{debug, info, warn};
+use windows::core::{w, ComInterface, GUID};
+use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_COLOR_SPACE_RGB_FULL_G2084_NONE_P2020;
+use windows::Win32::Graphics::Dxgi::{
+    CreateDXGIFactory1, IDXGIFactory1, IDXGIOutput6, DXGI_OUTPUT_DESC1,
+};
+use windows::Win32::Media::MediaFoundation::{
+    IMFActivate, MFMediaType_Video, MFTEnumEx, MFVideoFormat_AV1, MFVideoFormat_H264,
+    MFVideoFormat_HEVC, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG, MFT_ENUM_FLAG_ASYNCMFT,
+    MFT_ENUM_FLAG_HARDWARE, MFT_ENUM_FLAG_SYNCMFT, MFT_REGISTER_TYPE_INFO,
+};
+use windows::Win32::System::Com::CoTaskMemFree;
+use windows::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+
+use crate::capture::process_loopback_supported;
+use crate::device::ensure_com_initialized;
+use crate::Result;
+
+/// First Windows build with `Windows.Graphics.Capture` for windows and monitors (1903)
+const GRAPHICS_CAPTURE_BUILD: u32 = 18362;
+
+/// Encoders installed for one codec
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodecSupport {
+    /// A GPU encoder is installed
+    pub hardware: bool,
+    /// A software encoder is installed
+    pub software: bool,
+}
+
+impl CodecSupport {
+    /// Whether any encoder is installed
+    pub fn available(&self) -> bool {
+        self.hardware || self.software
+    }
+}
+
+/// Features of the running system the recorder depends on, see [`system_capabilities`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemCapabilities {
+    /// Windows build number, e.g. 22631, or None if it could not be read
+    pub windows_build: Option<u32>,
+    /// Capturing the audio of single processes (`AudioSource::ActiveWindow` and
+    /// `AudioSource::Processes`), which needs Windows 10 build 20348 or later. Without it
+    /// `LoopbackMode::Auto` records the whole desktop audio
+    pub process_loopback_audio: bool,
+    /// `Windows.Graphics.Capture` is available (Windows 10 1903 or later). The recorder itself
+    /// captures with desktop duplication
+    pub graphics_capture: bool,
+    pub h264: CodecSupport,
+    pub hevc: CodecSupport,
+    /// AV1 encoders; the recorder does not encode AV1 yet
+    pub av1: CodecSupport,
+    /// A display is in HDR mode. Duplication delivers 8-bit BGRA, so HDR content is recorded as
+    /// Windows maps it to SDR
+    pub hdr_display: bool,
+}
+
+/// Report which features the running Windows version and GPUs support, so options that would
+/// fail in `start_recording` can be disabled up front
+///
+/// Process loopback is probed by activating a capture of this process, and encoders are looked
+/// up without being created, so this is cheap enough to call when a settings UI opens.
+pub fn system_capabilities() -> Result<SystemCapabilities> {
+    info!("Querying system capabilities");
+    ensure_com_initialized()?;
+
+    let windows_build = windows_build();
+    let capabilities = unsafe {
+        SystemCapabilities {
+            windows_build,
+            process_loopback_audio: process_loopback_supported(),
+            graphics_capture: windows_build.is_some_and(|build| build >= GRAPHICS_CAPTURE_BUILD),
+            h264: codec_support(MFVideoFormat_H264),
+            hevc: codec_support(MFVideoFormat_HEVC),
+            av1: codec_support(MFVideoFormat_AV1),
+            hdr_display: hdr_display_active(),
+        }
+    };
+    info!("System capabilities: {:?}", capabilities);
+    Ok(capabilities)
+}
+
+/// Build number of the running Windows, read from the registry since `GetVersionEx` reports
+/// Windows 8 to applications without a compatibility manifest
+fn windows_build() -> Option<u32> {
+    let mut buffer = [0u16; 16];
+    let mut size = std::mem::size_of_val(&buffer) as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            w!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"),
+            w!("CurrentBuildNumber"),
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&mut size),
+        )
+    };
+    if status != ERROR_SUCCESS {
+        warn!("Failed to read the Windows build number: {:?}", status);
+        return None;
+    }
+    let length = (size as usize / 2).min(buffer.len());
+    String::from_utf16_lossy(&buffer[..length])
+        .trim_end_matches('\0')
+        .parse()
+        .ok()
+}
+
+/// Hardware and software encoders registered for the `subtype` output format
+unsafe fn codec_support(subtype: GUID) -> CodecSupport {
+    CodecSupport {
+        hardware: encoder_registered(subtype, MFT_ENUM_FLAG_HARDWARE),
+        software: encoder_registered(subtype, MFT_ENUM_FLAG_SYNCMFT | MFT_ENUM_FLAG_ASYNCMFT),
+    }
+}
+
+unsafe fn encoder_registered(subtype: GUID, flags: MFT_ENUM_FLAG) -> bool {
+    let output_type_info = MFT_REGISTER_TYPE_INFO {
+        guidMajorType: MFMediaType_Video,
+        guidSubtype: subtype,
+    };
+    let mut activates_ptr: *mut Option<IMFActivate> = std::ptr::null_mut();
+    let mut count: u32 = 0;
+    let result = MFTEnumEx(
+        MFT_CATEGORY_VIDEO_ENCODER,
+        flags,
+        None,
+        Some(&output_type_info),
+        &mut activates_ptr,
+        &mut count,
+    );
+    if let Err(e) = result {
+        debug!("No encoders for {:?} ({:?}): {:?}", subtype, flags, e);
+        return false;
+    }
+    if activates_ptr.is_null() {
+        return false;
+    }
+    for i in 0..count as usize {
+        // Release every activate
+        drop(std::ptr::read(activates_ptr.add(i)));
+    }
+    CoTaskMemFree(Some(activates_ptr as *const _));
+    count > 0
+}
+
+/// Whether an output of any adapter is in HDR mode (advanced color with the PQ curve)
+unsafe fn hdr_display_active() -> bool {
+    let factory: IDXGIFactory1 = match CreateDXGIFactory1() {
+        Ok(factory) => factory,
+        Err(e) => {
+            warn!(
+                "Failed to create a DXGI factory to look for HDR displays: {:?}",
+                e
+            );
+            return false;
+        }
+    };
+    let mut adapter_index = 0;
+    while let Ok(adapter) = factory.EnumAdapters(adapter_index) {
+        let mut output_index = 0;
+        while let Ok(output) = adapter.EnumOutputs(output_index) {
+            let mut desc = DXGI_OUTPUT_DESC1::default();
+            let described = output
+                .cast::<IDXGIOutput6>()
+                .and_then(|output| output.GetDesc1(&mut desc));
+            if described.is_ok() && desc.ColorSpace == DXGI_COLOR_SPACE_RGB_FULL_G2084_NONE_P2020 {
+                return true;
+            }
+            output_index += 1;
+        }
+        adapter_index += 1;
+    }
+    false
+}
@@ -1,9 +1,9 @@
-use crate::Result; use log::{info, warn};
+use crate::Result; use crate::logging::{info, warn};
 use windows::{
     core::{GUID, PWSTR},
     Win32::{
         Media::MediaFoundation::{
-            IMFActivate, MFMediaType_Video, MFTEnumEx, MFT_FRIENDLY_NAME_Attribute, MFVideoFormat_H264, MFVideoFormat_HEVC, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG_ALL, MFT_ENUM_FLAG_SORTANDFILTER, MFT_REGISTER_TYPE_INFO, MF_E_NOT_FOUND// Use sorting/filtering to potentially get preferred encoders first
+            IMFActivate, IMFAttributes, MFCreateAttributes, MFMediaType_Video, MFTEnum2, MFTEnumEx, MFT_ENUM_ADAPTER_LUID, MFT_FRIENDLY_NAME_Attribute, MFVideoFormat_H264, MFVideoFormat_HEVC, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG_ALL, MFT_ENUM_FLAG_SORTANDFILTER, MFT_REGISTER_TYPE_INFO, MF_E_NOT_FOUND// Use sorting/filtering to potentially get preferred encoders first
         }, System::Com::CoTaskMemFree
     },
 };
@@ -19,10 +19,13 @@ pub struct VideoEncoder {
     pub name: String,
     /// The specific type enum corresponding to the output_format_guid
     pub encoder_type: VideoEncoderType,
+    /// LUID of the adapter a hardware encoder runs on, None for software encoders
+    pub adapter_luid: Option<u64>,
 }
 
 /// Available video encoder types that the recorder supports querying for
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VideoEncoderType {
     /// H.264 Advanced Video Coding
     H264,
@@ -59,7 +62,21 @@ impl VideoEncoderType {
 
 /// Returns a list of available video encoders found that match the specified types
 pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
-    info!("Starting video encoder enumeration");
+    enumerate_encoders(None)
+}
+
+/// Returns the video encoders usable on the adapter with `adapter_luid` (see
+/// `DXGI_ADAPTER_DESC::AdapterLuid`): its hardware encoders, followed by the software ones
+///
+/// On multi-GPU systems, an encoder on the adapter doing the capture takes the frames without a
+/// copy between adapters.
+pub fn enumerate_video_encoders_for_adapter(adapter_luid: u64) -> Result<Vec<VideoEncoder>> {
+    enumerate_encoders(Some(adapter_luid))
+}
+
+/// Enumerates the encoders of every adapter, or only those usable on `adapter_luid`
+fn enumerate_encoders(adapter_luid: Option<u64>) -> Result<Vec<VideoEncoder>> {
+    info!("Starting video encoder enumeration (adapter: {:?})", adapter_luid);
 
     // Ensure COM is initialized for the current thread
     info!("Ensuring COM is initialized");
@@ -68,9 +85,23 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
 
     let mut available_encoders = Vec::new();
     // HS to prevent adding duplicates
-    let mut found_encoders = HashSet::<(String, GUID)>::new();
+    let mut found_encoders = HashSet::<(String, GUID, Option<u64>)>::new();
     info!("Initialized collections for tracking encoders");
 
+    // MFTEnum2 only lists the hardware encoders of the adapter named in its attributes
+    let adapter_attributes = match adapter_luid {
+        Some(luid) => unsafe {
+            let mut attributes: Option<IMFAttributes> = None;
+            MFCreateAttributes(&mut attributes, 1)?;
+            let attributes = attributes.ok_or_else(|| {
+                crate::error::RecorderError::Generic("Failed to create enumeration attributes".to_string())
+            })?;
+            attributes.SetUINT64(&MFT_ENUM_ADAPTER_LUID, luid)?;
+            Some(attributes)
+        },
+        None => None,
+    };
+
     let types_to_check = vec![VideoEncoderType::H264, VideoEncoderType::HEVC];
     info!("Will check for encoder types: {:?}", types_to_check);
 
@@ -89,19 +120,30 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
         let mut count: u32 = 0;
         info!("Initialized p_activate_array_ptr to null and count to 0");
 
-        info!("About to call MFTEnumEx with category: MFT_CATEGORY_VIDEO_ENCODER and flags: MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER");
+        info!("About to enumerate with category: MFT_CATEGORY_VIDEO_ENCODER and flags: MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER");
         let enum_result: windows::core::Result<()> = unsafe {
-            MFTEnumEx(
-                MFT_CATEGORY_VIDEO_ENCODER,
-                MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER, // Pass flags directly
-                None,                       // pInputType: No input type constraint
-                Some(&output_type_info),    // pOutputType: Constrain to H.264 or HEVC output
-                &mut p_activate_array_ptr,  // Pass the address OF p_activate_array_ptr
-                &mut count,                 // Receives the count
-            )
+            match &adapter_attributes {
+                Some(attributes) => MFTEnum2(
+                    MFT_CATEGORY_VIDEO_ENCODER,
+                    MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER,
+                    None,
+                    Some(&output_type_info),
+                    attributes,
+                    &mut p_activate_array_ptr,
+                    &mut count,
+                ),
+                None => MFTEnumEx(
+                    MFT_CATEGORY_VIDEO_ENCODER,
+                    MFT_ENUM_FLAG_ALL | MFT_ENUM_FLAG_SORTANDFILTER, // Pass flags directly
+                    None,                       // pInputType: No input type constraint
+                    Some(&output_type_info),    // pOutputType: Constrain to H.264 or HEVC output
+                    &mut p_activate_array_ptr,  // Pass the address OF p_activate_array_ptr
+                    &mut count,                 // Receives the count
+                ),
+            }
         };
 
-        info!("MFTEnumEx call completed with result: {:?}, count: {}", enum_result, count);
+        info!("Enumeration completed with result: {:?}, count: {}", enum_result, count);
         info!("p_activate_array_ptr is null: {}", p_activate_array_ptr.is_null());
 
         if let Err(e) = &enum_result {
@@ -110,7 +152,7 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
                 continue; // Continue to the next encoder type
             } else {
                 // Log the specific error but let the `?` propagate it
-                warn!("MFTEnumEx failed with error: {:?}", e);
+                warn!("Encoder enumeration failed with error: {:?}", e);
             }
         }
         // Propagate errors
@@ -151,7 +193,10 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
 
 
                         if !encoder_name.is_empty() {
-                            let key = (encoder_name.clone(), output_format_guid);
+                            // Hardware encoders carry the LUID of their adapter
+                            let encoder_adapter =
+                                unsafe { activate.GetUINT64(&MFT_ENUM_ADAPTER_LUID) }.ok();
+                            let key = (encoder_name.clone(), output_format_guid, encoder_adapter);
                             let is_new = found_encoders.insert(key);
                             if is_new {
                                 info!("Adding new encoder: '{}' for type: {:?}", encoder_name, encoder_type);
@@ -159,6 +204,7 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
                                     output_format_guid,
                                     name: encoder_name,
                                     encoder_type,
+                                    adapter_luid: encoder_adapter,
                                 });
                             } else {
                                 info!("Encoder '{}' for type: {:?} already in the list (different GUID or duplicate listing), skipping add",
@@ -187,7 +233,7 @@ pub fn enumerate_video_encoders() -> Result<Vec<VideoEncoder>> {
         } else if count == 0 {
              info!("No encoders found for type: {:?} (count returned 0)", encoder_type);
         } else {
-            warn!("MFTEnumEx returned Ok with count {} but the activate pointer is null. This is unexpected.", count);
+            warn!("Encoder enumeration returned Ok with count {} but the activate pointer is null. This is unexpected.", count);
         }
         info!("Completed processing for encoder type: {:?}", encoder_type);
     }
@@ -228,7 +274,7 @@ pub fn get_video_encoder_by_type(
 ) -> std::result::Result<VideoEncoder, crate::error::RecorderError> {
     get_preferred_video_encoder_by_type(encoder_type)
         .ok_or_else(|| {
-            log::error!("No video encoder found for type: {:?}", encoder_type);
+            crate::logging::error!("No video encoder found for type: {:?}", encoder_type);
             crate::error::RecorderError::Generic(format!("No video encoder found for type: {:?}", encoder_type))
         })
 }
\ No newline at end of file
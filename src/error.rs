@@ -6,6 +6,57 @@ use windows::core::HSTRING;
 
 pub type Result<T> = std::result::Result<T, RecorderError>;
 
+/// Recorder thread a failure originated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ThreadSource {
+    Video,
+    Audio,
+    Microphone,
+    Process,
+    Encoder,
+}
+
+/// Step of starting a recording, reported when it fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartStage {
+    /// Reading the config, creating the replay buffer and hiding excluded windows
+    Setup,
+    MediaFoundation,
+    /// Finding the video encoder and the pixel pipeline it takes
+    Encoder,
+    /// Creating the output file's sink writer and starting to write
+    SinkWriter,
+    /// Creating the D3D11 device
+    Device,
+    /// Starting the video, audio and microphone capture threads
+    Capture,
+    /// Creating the processing resources and starting the processing thread
+    Processing,
+}
+
+/// A problem found while validating a [`RecorderConfig`](crate::RecorderConfig)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    /// Builder option the issue is about, e.g. `fps`
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+fn format_issues(issues: &[ConfigIssue]) -> String {
+    issues
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
 #[derive(Debug, Error)]
 pub enum RecorderError {
     #[error("Windows API error: {0}")]
@@ -17,6 +68,14 @@ pub enum RecorderError {
     #[error("Failed to start the recording process, reason: {0}")]
     FailedToStart(String),
 
+    /// Starting failed at `stage`; everything set up before it was torn down and the output
+    /// file deleted
+    #[error("Failed to start the recording during {stage:?}: {source}")]
+    StartFailed {
+        stage: StartStage,
+        source: Box<RecorderError>,
+    },
+
     #[error("Failed to stop the recording process")]
     FailedToStop,
 
@@ -26,11 +85,26 @@ pub enum RecorderError {
     #[error("Called to stop when the recorder is already stopped")]
     RecorderAlreadyStopped,
 
+    #[error("Called while a recording is already running")]
+    RecordingInProgress,
+
     #[error("No process specified for the recorder")]
     NoProcessSpecified,
 
+    #[error("Not enough free space on the output volume: {free_bytes} bytes free, {required} required")]
+    InsufficientDiskSpace { free_bytes: u64, required: u64 },
+
+    #[error("Target window did not appear within {0:?}")]
+    WindowWaitTimedOut(std::time::Duration),
+
     #[error("Logger error: {0}")]
     LoggerError(String),
+
+    #[error("{thread:?} thread failed: {inner}")]
+    ThreadFailed { thread: ThreadSource, inner: String },
+
+    #[error("Invalid recorder configuration: {}", format_issues(.0))]
+    InvalidConfig(Vec<ConfigIssue>),
 }
 
 impl From<RecorderError> for WindowsError {
@@ -0,0 +1,355 @@
+//! C ABI for embedding the recorder from other languages
+//!
+//! Handles are opaque pointers created by `wr_config_new` / `wr_recorder_new` and released with
+//! the matching `_free` function. Functions returning `i32` return `WR_OK` on success and a
+//! negative status otherwise; the message of the last failure on the calling thread is available
+//! through `wr_last_error`. Strings are NUL-terminated UTF-8 and events are polled as the same
+//! JSON lines the `event_pipe` option writes. A panic never unwinds into the caller: it is
+//! reported like any other failure. The `windows-record-ffi` package builds these functions into
+//! a DLL, and `ffi/include/windows_record.h` declares them.
+//!
+//! Every pointer passed in must be null or valid for the duration of the call, and a handle must
+//! not be used after it is freed.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::{c_char, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::error::RecorderError;
+use crate::recorder::event_pipe;
+use crate::recorder::{Recorder, RecorderConfig, RecorderConfigBuilder};
+
+pub const WR_OK: i32 = 0;
+pub const WR_ERROR: i32 = -1;
+pub const WR_INVALID_ARGUMENT: i32 = -2;
+pub const WR_NOT_RECORDING: i32 = -3;
+
+/// Events kept for `wr_recorder_poll_event`; older ones are discarded
+const MAX_QUEUED_EVENTS: usize = 256;
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Recorder settings being assembled by the caller
+pub struct WrConfig {
+    builder: RecorderConfigBuilder,
+}
+
+/// A recorder and the events it emitted that have not been polled yet
+pub struct WrRecorder {
+    recorder: Recorder,
+    events: Arc<Mutex<VecDeque<String>>>,
+}
+
+fn set_last_error(message: impl Into<String>) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = message.into());
+}
+
+/// Run the body of an exported function, returning `on_panic` with the panic as the last error
+/// instead of unwinding across the C boundary
+fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            set_last_error(format!("Panicked: {}", message));
+            on_panic
+        }
+    }
+}
+
+fn status(result: crate::error::Result<()>) -> i32 {
+    match result {
+        Ok(()) => WR_OK,
+        Err(e) => {
+            set_last_error(e.to_string());
+            match e {
+                RecorderError::NoRecorderBound | RecorderError::RecorderAlreadyStopped => {
+                    WR_NOT_RECORDING
+                }
+                _ => WR_ERROR,
+            }
+        }
+    }
+}
+
+unsafe fn read_str<'a>(value: *const c_char, name: &str) -> Option<&'a str> {
+    if value.is_null() {
+        set_last_error(format!("{} is null", name));
+        return None;
+    }
+    match CStr::from_ptr(value).to_str() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            set_last_error(format!("{} is not valid UTF-8", name));
+            None
+        }
+    }
+}
+
+/// Copy `text` NUL-terminated into `buffer`; returns the bytes needed including the NUL, so a
+/// result larger than `capacity` means nothing was written
+unsafe fn write_str(text: &str, buffer: *mut c_char, capacity: usize) -> usize {
+    let needed = text.len() + 1;
+    if !buffer.is_null() && needed <= capacity {
+        std::ptr::copy_nonoverlapping(text.as_ptr(), buffer as *mut u8, text.len());
+        *buffer.add(text.len()) = 0;
+    }
+    needed
+}
+
+/// Update the builder behind `config`, failing if the handle is null
+unsafe fn update_config(
+    config: *mut WrConfig,
+    update: impl FnOnce(RecorderConfigBuilder) -> RecorderConfigBuilder,
+) -> i32 {
+    let Some(config) = config.as_mut() else {
+        set_last_error("config is null");
+        return WR_INVALID_ARGUMENT;
+    };
+    config.builder = update(std::mem::take(&mut config.builder));
+    WR_OK
+}
+
+/// Copy the message of the last failed call on this thread into `buffer`
+///
+/// Returns the size needed including the NUL; nothing is written if it exceeds `capacity`.
+#[no_mangle]
+pub unsafe extern "C" fn wr_last_error(buffer: *mut c_char, capacity: usize) -> usize {
+    guard(0, || {
+        LAST_ERROR.with(|last| write_str(&last.borrow(), buffer, capacity))
+    })
+}
+
+/// New settings with every option at its default
+#[no_mangle]
+pub extern "C" fn wr_config_new() -> *mut WrConfig {
+    guard(std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(WrConfig {
+            builder: RecorderConfig::builder(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_free(config: *mut WrConfig) {
+    guard((), || {
+        if !config.is_null() {
+            drop(Box::from_raw(config));
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_output_path(
+    config: *mut WrConfig,
+    path: *const c_char,
+) -> i32 {
+    guard(WR_ERROR, || {
+        let Some(path) = read_str(path, "path") else {
+            return WR_INVALID_ARGUMENT;
+        };
+        update_config(config, |builder| builder.output_path(PathBuf::from(path)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_fps(config: *mut WrConfig, num: u32, den: u32) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| builder.fps(num, den))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_output_dimensions(
+    config: *mut WrConfig,
+    width: u32,
+    height: u32,
+) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| builder.output_dimensions(width, height))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_video_bitrate(config: *mut WrConfig, bitrate: u32) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| builder.video_bitrate(bitrate))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_capture_audio(config: *mut WrConfig, enabled: bool) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| builder.capture_audio(enabled))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_capture_microphone(
+    config: *mut WrConfig,
+    enabled: bool,
+) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| builder.capture_microphone(enabled))
+    })
+}
+
+/// Keep the last `seconds` in memory for `wr_recorder_save_replay`; 0 disables the buffer
+#[no_mangle]
+pub unsafe extern "C" fn wr_config_set_replay_buffer(config: *mut WrConfig, seconds: u32) -> i32 {
+    guard(WR_ERROR, || {
+        update_config(config, |builder| {
+            builder
+                .enable_replay_buffer(seconds > 0)
+                .replay_buffer_seconds(seconds)
+        })
+    })
+}
+
+/// Create a recorder for the window whose title contains `process_name`
+///
+/// `config` is validated and copied, so it can be freed or reused afterwards. Returns null on
+/// failure.
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_new(
+    config: *const WrConfig,
+    process_name: *const c_char,
+) -> *mut WrRecorder {
+    guard(std::ptr::null_mut(), || {
+        let Some(config) = config.as_ref() else {
+            set_last_error("config is null");
+            return std::ptr::null_mut();
+        };
+        let Some(process_name) = read_str(process_name, "process_name") else {
+            return std::ptr::null_mut();
+        };
+
+        let events = Arc::new(Mutex::new(VecDeque::new()));
+        let queue = events.clone();
+        let built = config
+            .builder
+            .clone()
+            .event_callback(move |event| {
+                if let Ok(mut queue) = queue.lock() {
+                    if queue.len() == MAX_QUEUED_EVENTS {
+                        queue.pop_front();
+                    }
+                    match event_pipe::to_json(event) {
+                        Ok(line) => queue.push_back(line),
+                        Err(e) => crate::logging::warn!("Failed to serialize {:?}: {}", event, e),
+                    }
+                }
+            })
+            .build()
+            .and_then(Recorder::new);
+        match built {
+            Ok(recorder) => Box::into_raw(Box::new(WrRecorder {
+                recorder: recorder.with_process_name(process_name),
+                events,
+            })),
+            Err(e) => {
+                set_last_error(e.to_string());
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Stop the recording if it is running and release the recorder
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_free(recorder: *mut WrRecorder) {
+    guard((), || {
+        if recorder.is_null() {
+            return;
+        }
+        let recorder = Box::from_raw(recorder);
+        match recorder.recorder.stop_recording() {
+            Ok(())
+            | Err(RecorderError::NoRecorderBound)
+            | Err(RecorderError::RecorderAlreadyStopped) => {}
+            Err(e) => {
+                crate::logging::warn!("Failed to stop recording while freeing the recorder: {}", e)
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_start(recorder: *mut WrRecorder) -> i32 {
+    guard(WR_ERROR, || match recorder.as_ref() {
+        Some(recorder) => status(recorder.recorder.start_recording().map(|_| ())),
+        None => {
+            set_last_error("recorder is null");
+            WR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Stop recording and finalize the output file
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_stop(recorder: *mut WrRecorder) -> i32 {
+    guard(WR_ERROR, || match recorder.as_ref() {
+        Some(recorder) => status(recorder.recorder.stop_recording()),
+        None => {
+            set_last_error("recorder is null");
+            WR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Write the replay buffer to `path`
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_save_replay(
+    recorder: *mut WrRecorder,
+    path: *const c_char,
+) -> i32 {
+    guard(WR_ERROR, || {
+        let Some(recorder) = recorder.as_ref() else {
+            set_last_error("recorder is null");
+            return WR_INVALID_ARGUMENT;
+        };
+        let Some(path) = read_str(path, "path") else {
+            return WR_INVALID_ARGUMENT;
+        };
+        status(recorder.recorder.save_replay(path))
+    })
+}
+
+/// Copy the oldest unread event, as a line of JSON, into `buffer`
+///
+/// Returns 0 when there is no event, otherwise the size needed including the NUL. When that is
+/// larger than `capacity` the event stays queued so it can be fetched with a larger buffer.
+#[no_mangle]
+pub unsafe extern "C" fn wr_recorder_poll_event(
+    recorder: *mut WrRecorder,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> usize {
+    guard(0, || {
+        let Some(recorder) = recorder.as_ref() else {
+            set_last_error("recorder is null");
+            return 0;
+        };
+        let Ok(mut events) = recorder.events.lock() else {
+            return 0;
+        };
+        let Some(event) = events.front() else {
+            return 0;
+        };
+        let needed = write_str(event, buffer, capacity);
+        if needed <= capacity {
+            events.pop_front();
+        }
+        needed
+    })
+}
@@ -2,11 +2,64 @@
 mod capture;
 mod device;
 mod error;
+mod logging;
 mod processing;
 mod recorder;
 mod types;
 
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "remote")]
+pub mod remote;
+
+pub use capture::scene::{LayerSource, Scene, SceneBuilder, SceneLayer};
+pub use capture::source::{FrameSource, SourceFrame, VideoSource};
+#[cfg(feature = "synthetic")]
+pub use capture::synthetic::{SineWave, SyntheticSource};
+pub use capture::timing::{CaptureTiming, FramePacing};
+pub use capture::window::{
+    enumerate_capturable_windows, CapturableWindow, DisplayMode, MinimizedPolicy, ReacquirePolicy,
+    ReacquireTimeout, WindowCorners, WindowPick, WindowPickCallback,
+};
 pub use device::audio::{AudioInputDevice, enumerate_audio_input_devices};
-pub use device::video::{VideoEncoder, VideoEncoderType, enumerate_video_encoders, get_preferred_video_encoder_by_type};
-pub use error::{RecorderError, Result};
-pub use recorder::{Recorder, RecorderConfig, RecorderConfigBuilder, AudioSource};
\ No newline at end of file
+pub use device::capabilities::{query_encoder_capabilities, EncoderCapabilities, RateControlMode, VideoProfile};
+pub use device::system::{system_capabilities, CodecSupport, SystemCapabilities};
+pub use device::video::{VideoEncoder, VideoEncoderType, enumerate_video_encoders, enumerate_video_encoders_for_adapter, get_preferred_video_encoder_by_type};
+pub use error::{ConfigIssue, RecorderError, Result, StartStage, ThreadSource};
+pub use logging::{log_level, set_log_level, LogSubsystem};
+pub use tracing::level_filters::LevelFilter;
+pub use processing::audio::{
+    AudioLevels, AutoGain, MicrophoneFilters, MixerStats, NoiseGate, MICROPHONE_SOURCE,
+    SYSTEM_AUDIO_SOURCE,
+};
+pub use processing::audio_filter::{AudioBlock, AudioFilter, AUDIO_MIX};
+pub use processing::encoder::{
+    BitrateDeviation, EncoderSettings, EncoderStats, EntropyCoding, StreamHeader,
+};
+pub use processing::filter::{
+    ColorLutFilter, CropFilter, FilterFrame, ShaderFilter, SharpenFilter, VideoFilter,
+};
+pub use processing::high_fps::HighFpsCapture;
+pub use processing::idle::{IdleAction, IdlePolicy};
+pub use processing::image::{ImageFormat, OutputMode, ThumbnailSpec};
+pub use processing::mask::{MaskRect, MaskStyle};
+pub use processing::media::{AudioCodec, QualityProfile, SinkWriterOptions};
+pub use processing::overlay::{InputOverlayConfig, OverlayCorner};
+pub use processing::preview::{PreviewFrame, PreviewSpec, SharedPreviewTexture};
+pub use processing::profiler::{PipelineStage, ProfileReport, StageTiming};
+pub use processing::scaler::ScalingQuality;
+pub use processing::tap::{EncodedFrame, EncodedFrameCallback};
+pub use processing::video::{
+    AspectMode, ColorSpace, CropInsets, PixelPipeline, Rotation, VideoTransform,
+};
+pub use recorder::{Recorder, RecorderConfig, RecorderConfigBuilder, AudioProcess, AudioSource, EncodingProfile, LoopbackMode, FrameTag, Marker, replay_capture_dump};
+pub use recorder::{repair_mp4, Mp4RepairReport, ReplaySaveHandle};
+pub use recorder::{bitrate_for_file_size, Preset};
+pub use recorder::{AsyncRecorder, EventStream, NextEvent, RecorderTask};
+pub use recorder::{EventCallback, RecorderEvent, RecorderSupervisor, StopHandle, StopStage};
+#[cfg(feature = "serde")]
+pub use recorder::EventPipe;
+pub use recorder::{list_windows, list_windows_with_thumbnail_size, WindowInfo, WindowThumbnail};
+pub use types::frame_queue::{BackpressurePolicy, FrameQueueStats};
+pub use types::texture_pool::{BufferPoolSizes, PoolStats};
+pub use types::memory::{MemoryLimits, MemoryUsage};
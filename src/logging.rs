@@ -0,0 +1,179 @@
+//! Log macros that can be filtered per subsystem at runtime
+//!
+//! The crate emits `tracing` events, so the application's subscriber decides where messages go;
+//! without one they are forwarded to the `log` facade. On top of that each subsystem has its own
+//! maximum level, checked before an event is emitted, so a production build can keep warnings
+//! from the capture loop without getting every informational message of the sink writer setup.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+
+/// Part of the recorder a log message comes from, chosen by the module that logs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogSubsystem {
+    /// Video, audio and microphone capture threads, windows and monitors
+    Capture,
+    /// Audio mixing, filters, drift correction and gap filling
+    Mixer,
+    /// Video processing and encoding: scaling, conversion, overlays and encoder settings
+    Encoder,
+    /// Output files: sink writers, segments, the replay buffer, capture dumps and repair
+    Writer,
+    /// Everything else: starting and stopping, devices, events and configuration
+    Recorder,
+}
+
+impl LogSubsystem {
+    const ALL: [LogSubsystem; 5] = [
+        Self::Capture,
+        Self::Mixer,
+        Self::Encoder,
+        Self::Writer,
+        Self::Recorder,
+    ];
+
+    /// The subsystem of a module path such as `windows_record::capture::video`
+    ///
+    /// A `const fn`, so the log macros resolve it once at compile time for each call site.
+    pub(crate) const fn of(module_path: &str) -> Self {
+        let path = module_path.as_bytes();
+        let crate_name = env!("CARGO_CRATE_NAME");
+        let skip = if in_module(path, 0, crate_name) && path.len() > crate_name.len() {
+            crate_name.len() + 2
+        } else {
+            0
+        };
+        if in_module(path, skip, "capture") {
+            Self::Capture
+        } else if in_module(path, skip, "processing::audio")
+            || in_module(path, skip, "processing::audio_filter")
+            || in_module(path, skip, "processing::drift")
+            || in_module(path, skip, "processing::gap")
+        {
+            Self::Mixer
+        } else if in_module(path, skip, "processing::media")
+            || in_module(path, skip, "processing::segment")
+            || in_module(path, skip, "processing::replay")
+            || in_module(path, skip, "processing::tap")
+            || in_module(path, skip, "processing::dump")
+            || in_module(path, skip, "recorder::repair")
+        {
+            Self::Writer
+        } else if in_module(path, skip, "processing") {
+            Self::Encoder
+        } else {
+            Self::Recorder
+        }
+    }
+
+    fn index(self) -> usize {
+        Self::ALL
+            .iter()
+            .position(|subsystem| *subsystem == self)
+            .unwrap()
+    }
+
+    /// Whether events at `level` from this subsystem are emitted
+    pub(crate) fn enabled(self, level: Level) -> bool {
+        filter_index(LevelFilter::from_level(level)) <= LEVELS[self.index()].load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the module path `path`, from byte `skip` on, is module `name` or inside it
+const fn in_module(path: &[u8], skip: usize, name: &str) -> bool {
+    let name = name.as_bytes();
+    if path.len() < skip + name.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < name.len() {
+        if path[skip + i] != name[i] {
+            return false;
+        }
+        i += 1;
+    }
+    let end = skip + name.len();
+    end == path.len() || path[end] == b':'
+}
+
+/// Position of `level` from `OFF` (0) to `TRACE` (5)
+fn filter_index(level: LevelFilter) -> usize {
+    match level.into_level() {
+        None => 0,
+        Some(Level::ERROR) => 1,
+        Some(Level::WARN) => 2,
+        Some(Level::INFO) => 3,
+        Some(Level::DEBUG) => 4,
+        Some(Level::TRACE) => 5,
+    }
+}
+
+/// Maximum level per subsystem as a `filter_index`, indexed like `LogSubsystem::ALL`; everything
+/// passes by default
+static LEVELS: [AtomicUsize; 5] = [
+    AtomicUsize::new(5),
+    AtomicUsize::new(5),
+    AtomicUsize::new(5),
+    AtomicUsize::new(5),
+    AtomicUsize::new(5),
+];
+
+/// Only emit events of `subsystem` at `level` or more severe
+///
+/// The filter is process-wide and takes effect immediately, also for running recordings. The
+/// application's subscriber or logger still applies its own filter afterwards.
+pub fn set_log_level(subsystem: LogSubsystem, level: LevelFilter) {
+    LEVELS[subsystem.index()].store(filter_index(level), Ordering::Relaxed);
+}
+
+/// The level set for `subsystem` with `set_log_level`
+pub fn log_level(subsystem: LogSubsystem) -> LevelFilter {
+    match LEVELS[subsystem.index()].load(Ordering::Relaxed) {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Emit a `tracing` event if the calling module's subsystem lets `level` through. Takes the
+/// same arguments as the `tracing` macros, including `key = value,` fields before the message.
+macro_rules! log_filtered {
+    ($level:expr, $($arg:tt)+) => {{
+        const SUBSYSTEM: $crate::logging::LogSubsystem =
+            $crate::logging::LogSubsystem::of(module_path!());
+        if SUBSYSTEM.enabled($level) {
+            ::tracing::event!($level, $($arg)+);
+        }
+    }};
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::logging::log_filtered!(::tracing::Level::ERROR, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::logging::log_filtered!(::tracing::Level::WARN, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::logging::log_filtered!(::tracing::Level::INFO, $($arg)+) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::logging::log_filtered!(::tracing::Level::DEBUG, $($arg)+) };
+}
+
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::logging::log_filtered!(::tracing::Level::TRACE, $($arg)+) };
+}
+
+// Named `log_*` here, since a macro called `warn` would be ambiguous with the built-in
+// attribute in this module
+pub(crate) use {
+    log_debug as debug, log_error as error, log_filtered, log_info as info, log_trace as trace,
+    log_warn as warn,
+};
@@ -0,0 +1,457 @@
+//! `record`, a command line front end for the recorder
+//!
+//! ```text
+//! record start --window "Notepad" --fps 60 --output out.mp4
+//! record start --exe game.exe --replay-buffer 30 --control 127.0.0.1:7878
+//! record replay-save --control 127.0.0.1:7878 --output clip.mp4
+//! record list-windows --json
+//! record list-encoders
+//! record save-config preset.toml --fps 60 --size 1920x1080 --codec hevc
+//! record start --window "Notepad" --config preset.toml
+//! ```
+//!
+//! Results go to stdout, logs and errors to stderr. A failing command exits with status 1.
+
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use windows::Win32::Foundation::HWND;
+use windows_record::remote::RemoteServer;
+use windows_record::{
+    enumerate_video_encoders, list_windows_with_thumbnail_size, AudioProcess, AudioSource,
+    EventPipe, Recorder, RecorderConfig, RecorderConfigBuilder, RecorderError, Result,
+    VideoEncoderType,
+};
+
+/// Address `start --control` listens on and `replay-save` connects to by default
+const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:7878";
+/// How often `start` checks for Ctrl+C, the duration and a recording stopped by other means
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "record",
+    version,
+    about = "Record a window with audio on Windows"
+)]
+struct Cli {
+    /// Log more (-v for info, -vv for debug, -vvv for trace); RUST_LOG overrides this
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Record a window until Ctrl+C, the duration elapses or a `stop` control command
+    Start(StartArgs),
+    /// List the windows that can be recorded
+    ListWindows {
+        /// Print one JSON object per line instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the hardware and software video encoders
+    ListEncoders {
+        /// Print one JSON object per line instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Save recording settings to a TOML or JSON file for `start --config`
+    SaveConfig {
+        /// File to write, as TOML if it ends in `.toml` and as JSON otherwise
+        file: PathBuf,
+        #[command(flatten)]
+        settings: Settings,
+    },
+    /// Save the replay buffer of a recording started with `--control`
+    ReplaySave {
+        /// File to write, the recording's output template if omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Control address of the recording
+        #[arg(long, default_value = DEFAULT_CONTROL_ADDR)]
+        control: String,
+        /// Token the recording was started with
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("target").required(true)))]
+struct StartArgs {
+    /// Record the first window whose title contains this text
+    #[arg(long, group = "target")]
+    window: Option<String>,
+    /// Record the main window of the process with this id
+    #[arg(long, group = "target")]
+    pid: Option<u32>,
+    /// Record the main window of the first process started from this executable
+    #[arg(long, group = "target")]
+    exe: Option<String>,
+    /// Record the window with this handle, as printed by `list-windows`
+    #[arg(long, group = "target")]
+    hwnd: Option<isize>,
+    /// Match `--window` against the whole title
+    #[arg(long, requires = "window")]
+    exact: bool,
+
+    #[command(flatten)]
+    settings: Settings,
+    /// Stop after this many seconds
+    #[arg(long)]
+    duration: Option<f64>,
+
+    /// Accept control commands (`replay-save`, remote::RemoteServer clients) on ADDR
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = DEFAULT_CONTROL_ADDR)]
+    control: Option<String>,
+    /// Token control clients must send
+    #[arg(long, requires = "control")]
+    token: Option<String>,
+    /// Write every recorder event to stdout as a line of JSON
+    #[arg(long)]
+    events: bool,
+    /// With --events, also emit a `Stats` event every SECONDS
+    #[arg(long, value_name = "SECONDS", requires = "events")]
+    stats_interval: Option<f64>,
+}
+
+/// Recording settings, on top of the library defaults or a `--config` file
+#[derive(Debug, Args)]
+struct Settings {
+    /// Start from the settings in this TOML or JSON file; the options below override them
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Output file [default: output.mp4]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Frames per second, e.g. 60, 59.94 or 30000/1001 [default: 30]
+    #[arg(long, value_parser = parse_fps)]
+    fps: Option<(u32, u32)>,
+    /// Output resolution, e.g. 1920x1080
+    #[arg(long, value_parser = parse_size)]
+    size: Option<(u32, u32)>,
+    /// Video bitrate in bits per second
+    #[arg(long)]
+    bitrate: Option<u32>,
+    /// Video codec [default: h264]
+    #[arg(long, value_enum)]
+    codec: Option<Codec>,
+    /// Audio to record besides the microphone [default: desktop]
+    #[arg(long, value_enum)]
+    audio: Option<Audio>,
+    /// Record the audio of this process instead, by ID or executable; repeat to mix several
+    #[arg(long, value_name = "PID|EXE", conflicts_with = "audio")]
+    audio_process: Vec<String>,
+    /// Mix in the default microphone
+    #[arg(long)]
+    microphone: bool,
+    /// Draw the mouse cursor
+    #[arg(long)]
+    cursor: bool,
+    /// Keep the last SECONDS in memory for `replay-save`
+    #[arg(long, value_name = "SECONDS")]
+    replay_buffer: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Codec {
+    H264,
+    Hevc,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Audio {
+    /// Everything playing on the default output device
+    Desktop,
+    /// Only the recorded window's process
+    Window,
+    /// No system audio
+    None,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let default_filter = match cli.verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .init();
+
+    let result = match cli.command {
+        Command::Start(args) => start(args),
+        Command::ListWindows { json } => list_windows(json),
+        Command::ListEncoders { json } => list_encoders(json),
+        Command::SaveConfig { file, settings } => settings_builder(&settings)
+            .and_then(|builder| builder.build())
+            .and_then(|config| config.to_file(&file)),
+        Command::ReplaySave {
+            output,
+            control,
+            token,
+        } => replay_save(output, &control, token),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Builder with `settings` applied over the config file they name or the CLI defaults
+fn settings_builder(settings: &Settings) -> Result<RecorderConfigBuilder> {
+    let mut builder = match &settings.config {
+        Some(path) => RecorderConfigBuilder::from_config(RecorderConfig::from_file(path)?),
+        None => Recorder::builder()
+            .output_path("output.mp4")
+            .audio_source(AudioSource::Desktop)
+            .capture_cursor(false),
+    };
+    if let Some(output) = &settings.output {
+        builder = builder.output_path(output);
+    }
+    if let Some((num, den)) = settings.fps {
+        builder = builder.fps(num, den);
+    }
+    if let Some((width, height)) = settings.size {
+        builder = builder.output_dimensions(width, height);
+    }
+    if let Some(bitrate) = settings.bitrate {
+        builder = builder.video_bitrate(bitrate);
+    }
+    if let Some(codec) = settings.codec {
+        builder = builder.video_encoder(match codec {
+            Codec::H264 => VideoEncoderType::H264,
+            Codec::Hevc => VideoEncoderType::HEVC,
+        });
+    }
+    if let Some(audio) = settings.audio {
+        builder = match audio {
+            Audio::Desktop => builder
+                .capture_audio(true)
+                .audio_source(AudioSource::Desktop),
+            Audio::Window => builder
+                .capture_audio(true)
+                .audio_source(AudioSource::ActiveWindow),
+            Audio::None => builder.capture_audio(false),
+        };
+    }
+    if !settings.audio_process.is_empty() {
+        let processes = settings
+            .audio_process
+            .iter()
+            .map(|process| match process.parse() {
+                Ok(process_id) => AudioProcess::Id(process_id),
+                Err(_) => AudioProcess::Executable(process.clone()),
+            })
+            .collect();
+        builder = builder
+            .capture_audio(true)
+            .audio_source(AudioSource::Processes(processes));
+    }
+    if settings.microphone {
+        builder = builder.capture_microphone(true);
+    }
+    if settings.cursor {
+        builder = builder.capture_cursor(true);
+    }
+    if let Some(seconds) = settings.replay_buffer {
+        builder = builder
+            .enable_replay_buffer(true)
+            .replay_buffer_seconds(seconds);
+    }
+    Ok(builder)
+}
+
+fn start(args: StartArgs) -> Result<()> {
+    let mut builder = settings_builder(&args.settings)?;
+    if args.events {
+        builder = builder.event_pipe(EventPipe::Stdout);
+    }
+    if let Some(seconds) = args.stats_interval {
+        builder = builder.stats_interval(parse_seconds(seconds)?);
+    }
+    let config = builder.build()?;
+    let output = config.output_path().clone();
+
+    let recorder = Recorder::new(config)?;
+    let recorder = match (args.window, args.pid, args.exe, args.hwnd) {
+        (Some(title), ..) => recorder
+            .with_process_name(&title)
+            .with_exact_match(args.exact),
+        (_, Some(pid), ..) => recorder.with_process_id(pid),
+        (_, _, Some(exe), _) => recorder.with_process_exe(&exe),
+        (.., Some(hwnd)) => recorder.with_window_handle(HWND(hwnd)),
+        _ => unreachable!("clap requires a target"),
+    };
+    let recorder = Arc::new(recorder);
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::Relaxed)).map_err(|e| {
+        RecorderError::Generic(format!("Failed to install the Ctrl+C handler: {}", e))
+    })?;
+
+    recorder.start_recording()?;
+    eprintln!("Recording to {}, press Ctrl+C to stop", output.display());
+    // Bound after starting, so a client never sees a recorder that is not running yet
+    let _server = args
+        .control
+        .map(|addr| -> Result<RemoteServer> {
+            let server = RemoteServer::bind(recorder.clone(), addr.as_str(), args.token.clone())?;
+            eprintln!("Accepting control commands on {}", server.local_addr());
+            Ok(server)
+        })
+        .transpose()?;
+
+    let deadline = args
+        .duration
+        .map(parse_seconds)
+        .transpose()?
+        .map(|duration| Instant::now() + duration);
+    while recorder.is_recording()
+        && !interrupted.load(Ordering::Relaxed)
+        && deadline.is_none_or(|deadline| Instant::now() < deadline)
+    {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    // Also stopped by a `stop` control command or a configured limit
+    if recorder.is_recording() {
+        recorder.stop_recording()?;
+    }
+    eprintln!("Saved {}", output.display());
+    Ok(())
+}
+
+fn list_windows(json: bool) -> Result<()> {
+    // Thumbnails are not printed, so skip grabbing them
+    for window in list_windows_with_thumbnail_size(0, 0) {
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "hwnd": window.hwnd.0,
+                    "process_id": window.process_id,
+                    "process_name": window.process_name,
+                    "monitor": window.monitor,
+                    "title": window.title,
+                })
+            );
+        } else {
+            println!(
+                "{:>10}  {:>6}  {:<24}  {}",
+                window.hwnd.0, window.process_id, window.process_name, window.title
+            );
+        }
+    }
+    Ok(())
+}
+
+fn list_encoders(json: bool) -> Result<()> {
+    for encoder in enumerate_video_encoders()? {
+        let codec = match encoder.encoder_type {
+            VideoEncoderType::H264 => "h264",
+            VideoEncoderType::HEVC => "hevc",
+        };
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({ "codec": codec, "name": encoder.name })
+            );
+        } else {
+            println!("{:<5}  {}", codec, encoder.name);
+        }
+    }
+    Ok(())
+}
+
+/// Ask the recording listening on `control` to save its replay buffer, printing the file written
+fn replay_save(output: Option<PathBuf>, control: &str, token: Option<String>) -> Result<()> {
+    let connection_error =
+        |e: std::io::Error| RecorderError::Generic(format!("Failed to reach {}: {}", control, e));
+    let mut stream = TcpStream::connect(control).map_err(connection_error)?;
+
+    let mut request = serde_json::json!({ "id": 1, "command": "save_replay" });
+    if let Some(output) = output {
+        request["path"] = serde_json::json!(output);
+    }
+    if let Some(token) = token {
+        request["token"] = serde_json::json!(token);
+    }
+    writeln!(stream, "{}", request).map_err(connection_error)?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(connection_error)?;
+    let response: serde_json::Value = serde_json::from_str(&line)
+        .map_err(|e| RecorderError::Generic(format!("Invalid response from {}: {}", control, e)))?;
+    if response["ok"] != true {
+        return Err(RecorderError::Generic(
+            response["error"]
+                .as_str()
+                .unwrap_or("Replay could not be saved")
+                .to_string(),
+        ));
+    }
+    println!("{}", response["path"].as_str().unwrap_or_default());
+    Ok(())
+}
+
+/// Parse `WIDTHxHEIGHT`
+fn parse_size(size: &str) -> std::result::Result<(u32, u32), String> {
+    let (width, height) = size
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {:?}", size))?;
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&value| value > 0 && value % 2 == 0)
+            .ok_or_else(|| format!("{:?} is not a positive even number", value))
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Parse a whole frame rate, `NUM/DEN`, or one of the NTSC rates 23.976, 29.97, 59.94 and
+/// 119.88, which are exactly 1000/1001 of the next whole rate
+fn parse_fps(fps: &str) -> std::result::Result<(u32, u32), String> {
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&value| value > 0)
+            .ok_or_else(|| format!("{:?} is not a positive whole number", value))
+    };
+    match fps.trim() {
+        "23.976" | "23.98" => Ok((24_000, 1001)),
+        "29.97" => Ok((30_000, 1001)),
+        "47.952" | "47.95" => Ok((48_000, 1001)),
+        "59.94" => Ok((60_000, 1001)),
+        "119.88" => Ok((120_000, 1001)),
+        fps => match fps.split_once('/') {
+            Some((num, den)) => Ok((parse(num)?, parse(den)?)),
+            None => Ok((parse(fps)?, 1)),
+        },
+    }
+}
+
+fn parse_seconds(seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| RecorderError::Generic(format!("Invalid number of seconds: {}", seconds)))
+}
@@ -1,410 +1,1182 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use windows::core::Result;
 use windows::Win32::Media::MediaFoundation::{IMFSample, MFCreateSample, MFCreateMemoryBuffer};
-use std::sync::Arc;
-use crate::types::SendableSample;
-use log::{error, info, debug, trace};
+use std::sync::{Arc, Mutex};
+use crate::types::{frame_time_hns, SendableSample};
+use super::drift::DriftCorrector;
+use crate::logging::{error, info, debug, trace, warn};
 
+/// Most recent per-source audio levels, normalized to 0.0..=1.0 of full scale (after gain)
+///
+/// Captured processes show on the system meters and extra microphones on the microphone ones,
+/// each meter following the loudest of its sources.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AudioLevels {
+    pub system_peak: f32,
+    pub system_rms: f32,
+    pub mic_peak: f32,
+    pub mic_rms: f32,
+}
+
+// Peak and RMS of a block of 16-bit PCM samples after applying gain
+fn measure_levels(samples: &[i16], gain: f32) -> (f32, f32) {
+    if samples.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let mut peak = 0.0f32;
+    let mut sum_squares = 0.0f64;
+    for &s in samples {
+        let v = (s as f32 * gain / i16::MAX as f32).clamp(-1.0, 1.0);
+        peak = peak.max(v.abs());
+        sum_squares += (v * v) as f64;
+    }
+
+    (peak, (sum_squares / samples.len() as f64).sqrt() as f32)
+}
+
+/// Running totals of the audio mixer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MixerStats {
+    /// System audio packets received, including those of each captured process
+    pub system_packets: u64,
+    /// Microphone packets received, including those of extra microphones
+    pub microphone_packets: u64,
+    /// Packets output with more than one source mixed
+    pub mixed_packets: u64,
+    /// Packets output with only system audio
+    pub system_only_packets: u64,
+    /// Packets output with only the microphone
+    pub microphone_only_packets: u64,
+    /// Packets that failed to mix
+    pub mix_errors: u64,
+    /// Frames mixed with silence for the microphone because it had delivered nothing for them
+    pub microphone_frames_missing: u64,
+    /// Microphone frames dropped because they overlapped audio already queued or mixed
+    pub microphone_frames_discarded: u64,
+    /// Packets waiting in each queue after the last mix
+    pub system_queue_depth: usize,
+    pub microphone_queue_depth: usize,
+    /// Microphone drift against system audio not yet corrected, in milliseconds
+    pub microphone_drift_ms: f64,
+    /// Rate change currently applied to the microphone, in parts per million
+    pub drift_correction_ppm: f64,
+}
+
+/// Name of the system audio source in the mixer, for `Recorder::set_audio_gain` and
+/// `Recorder::set_audio_muted`
+pub const SYSTEM_AUDIO_SOURCE: &str = "system";
+/// Name of the microphone source in the mixer
+pub const MICROPHONE_SOURCE: &str = "microphone";
+
+/// Frames in each packet the mixer outputs, one AAC frame
+const OUTPUT_FRAMES: usize = 1024;
+/// Packets starting this close to where the previous one of their source ended are joined to it,
+/// absorbing timestamp jitter, in 100ns units
+const JOIN_TOLERANCE_HNS: i64 = 50_000;
+/// Audio one source may queue past the mix while an active source delivers nothing, before the
+/// mix continues with silence for the stalled one, in 100ns units
+const MAX_STALL_HNS: i64 = 3_000_000;
+
+/// Gain and mute flag of a mixer source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SourceControl {
+    pub gain: f32,
+    pub muted: bool,
+}
+
+/// Gain and mute flag of every mixer source by name, shared with the recorder so they can be
+/// changed while recording
+pub(crate) type MixerControls = Arc<Mutex<HashMap<String, SourceControl>>>;
+
+/// Controls for the sources a recording mixes, starting at the configured volumes; extra
+/// microphones start at the microphone volume
+pub(crate) fn mixer_controls(
+    capture_audio: bool,
+    capture_microphone: bool,
+    system_volume: Option<f32>,
+    microphone_volume: Option<f32>,
+    extra_microphones: &[String],
+) -> MixerControls {
+    let mut controls = HashMap::new();
+    let extra = extra_microphones
+        .iter()
+        .map(|name| (capture_microphone, name.as_str(), microphone_volume));
+    for (enabled, name, volume) in [
+        (capture_audio, SYSTEM_AUDIO_SOURCE, system_volume),
+        (capture_microphone, MICROPHONE_SOURCE, microphone_volume),
+    ]
+    .into_iter()
+    .chain(extra)
+    {
+        if enabled {
+            controls.insert(
+                name.to_string(),
+                SourceControl {
+                    gain: volume.unwrap_or(1.0),
+                    muted: false,
+                },
+            );
+        }
+    }
+    Arc::new(Mutex::new(controls))
+}
+
+/// Handle of a source added with [`AudioMixer::add_source`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceId(usize);
+
+/// Which meters and statistics a mixer source counts towards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SourceKind {
+    System,
+    Microphone,
+}
+
+struct MixerSource {
+    name: String,
+    kind: SourceKind,
+    /// Interleaved PCM not mixed yet with the timeline frame each packet starts at, in order and
+    /// without overlap
+    queue: VecDeque<(i64, Vec<i16>)>,
+    /// Clamped to 0.0..=2.0 so it can also amplify
+    gain: f32,
+    /// A muted source keeps its place in the mix but adds silence
+    muted: bool,
+    /// Whether the mix waits for a packet of this source
+    active: bool,
+}
+
+impl MixerSource {
+    // Gain the source is mixed with, silent while muted
+    fn effective_gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.gain
+        }
+    }
+
+    // Timeline frame after the last one queued
+    fn end(&self, channels: usize) -> Option<i64> {
+        self.queue
+            .back()
+            .map(|(start, pcm)| start + (pcm.len() / channels) as i64)
+    }
+}
+
+/// Mixes any number of named sources into one track on a shared timeline
+///
+/// Packets are placed at the frame their timestamp falls on, whatever their size, and the mix is
+/// output in packets of `OUTPUT_FRAMES` once every active source has delivered audio up to their
+/// end. A source without audio for part of a packet adds silence there; one that falls
+/// `MAX_STALL_HNS` behind is not waited for until it delivers again.
 pub struct AudioMixer {
-    system_audio_queue: VecDeque<SendableSample>,
-    microphone_queue: VecDeque<SendableSample>,
+    sources: Vec<MixerSource>,
     sample_rate: u32,
     channels: u16,
-    // Volume
-    system_volume: f32,
-    microphone_volume: f32,
-    // Important for process_next_sample
-    both_sources_active: bool,
+    // Timeline frame the next output packet starts at, None until the first is mixed
+    position: Option<i64>,
+    // Live meters shared with the recorder
+    levels: Arc<Mutex<AudioLevels>>,
+    // Keeps one source's clock in step with the reference source while both are mixed
+    drift: DriftCorrector,
+    drift_source: Option<SourceId>,
+    // Totals shared with the recorder
+    stats: Arc<Mutex<MixerStats>>,
+    // Gains and mute flags changed while recording
+    controls: Option<MixerControls>,
+    normalizer: Option<LoudnessNormalizer>,
 }
 
 impl AudioMixer {
-    pub fn new(sample_rate: u32, bits_per_sample: u16, channels: u16, both_sources_active: bool) -> Self {
-        info!("Creating AudioMixer: sample_rate={}, bits_per_sample={}, channels={}, both_sources={}", 
-              sample_rate, bits_per_sample, channels, both_sources_active);
+    pub fn new(sample_rate: u32, bits_per_sample: u16, channels: u16) -> Self {
+        info!(
+            "Creating AudioMixer: sample_rate={}, bits_per_sample={}, channels={}",
+            sample_rate, bits_per_sample, channels
+        );
         Self {
-            system_audio_queue: VecDeque::new(),
-            microphone_queue: VecDeque::new(),
+            sources: Vec::new(),
             sample_rate,
             channels,
-            // Default volume levels (neutral/1.0)
-            system_volume: 1.0,
-            microphone_volume: 1.0,
-            both_sources_active,
+            position: None,
+            levels: Arc::new(Mutex::new(AudioLevels::default())),
+            drift: DriftCorrector::new(sample_rate, channels),
+            drift_source: None,
+            stats: Arc::new(Mutex::new(MixerStats::default())),
+            controls: None,
+            normalizer: None,
         }
     }
-    
-    // Allow dynamically changing whether both sources are required
-    pub fn set_both_sources_active(&mut self, both_active: bool) {
-        if self.both_sources_active != both_active {
-            info!("Changing AudioMixer mixing mode - both_sources_active: {} -> {}", 
-                  self.both_sources_active, both_active);
-            self.both_sources_active = both_active;
-        }
+
+    /// Add a source mixed at unity gain; it starts active, so the mix waits for its packets
+    ///
+    /// It counts as system audio unless it is the `MICROPHONE_SOURCE` or set to a microphone
+    /// with `set_kind`.
+    pub fn add_source(&mut self, name: &str) -> SourceId {
+        info!("Adding audio mixer source '{}'", name);
+        let kind = if name == MICROPHONE_SOURCE {
+            SourceKind::Microphone
+        } else {
+            SourceKind::System
+        };
+        self.sources.push(MixerSource {
+            name: name.to_string(),
+            kind,
+            queue: VecDeque::new(),
+            gain: 1.0,
+            muted: false,
+            active: true,
+        });
+        SourceId(self.sources.len() - 1)
     }
 
-    // Add methods to set volume levels
-    pub fn set_system_volume(&mut self, gain: f32) {
-        // Clamp gain between 0.0 and 2.0 (allow amplification)
-        self.system_volume = gain.max(0.0).min(2.0);
-        info!("System audio gain set to {:.2}", self.system_volume);
+    /// Count `source` towards the meters and statistics of `kind`
+    pub(crate) fn set_kind(&mut self, source: SourceId, kind: SourceKind) {
+        self.sources[source.0].kind = kind;
     }
 
-    pub fn set_microphone_volume(&mut self, gain: f32) {
-        // Clamp gain between 0.0 and 2.0 (allow amplification)
-        self.microphone_volume = gain.max(0.0).min(2.0);
-        info!("Microphone gain set to {:.2}", self.microphone_volume);
+    // Share the level meters with the recorder so they can be read while mixing
+    pub fn set_level_meter(&mut self, levels: Arc<Mutex<AudioLevels>>) {
+        self.levels = levels;
     }
 
-    pub fn add_system_audio(&mut self, sample: SendableSample) {
-        trace!("Adding system audio sample to queue (queue size: {})", self.system_audio_queue.len());
-        self.system_audio_queue.push_back(sample);
+    // Share the statistics with the recorder so they can be read while mixing
+    pub fn set_stats_sink(&mut self, stats: Arc<Mutex<MixerStats>>) {
+        self.stats = stats;
     }
 
-    pub fn add_microphone_audio(&mut self, sample: SendableSample) {
-        trace!("Adding microphone audio sample to queue (queue size: {})", self.microphone_queue.len());
-        self.microphone_queue.push_back(sample);
+    /// Take the gains and mute flags from `controls`, picking up changes before every mix
+    pub(crate) fn set_controls(&mut self, controls: MixerControls) {
+        self.controls = Some(controls);
+        self.sync_controls();
     }
 
-    pub unsafe fn process_next_sample(&mut self) -> Option<Result<Arc<IMFSample>>> {
-        trace!("Processing next sample - sys queue: {}, mic queue: {}", 
-               self.system_audio_queue.len(), self.microphone_queue.len());
-    
-        // If both sources are active, we need samples from both to mix
-        if self.both_sources_active {
-            // If either queue is empty, return None to wait for the other source
-            if self.system_audio_queue.is_empty() || self.microphone_queue.is_empty() {
-                trace!("Both sources active but waiting for samples from both queues to prevent desync");
-                return None;
+    /// Level the mix towards `target_lufs` and limit its peaks before it is written
+    pub fn set_normalization(&mut self, target_lufs: f32) {
+        self.normalizer = Some(LoudnessNormalizer::new(
+            target_lufs,
+            self.sample_rate,
+            self.channels,
+        ));
+    }
+
+    /// Resample `source` to the clock of the first source added while both are mixed
+    pub fn correct_drift(&mut self, source: SourceId) {
+        self.drift_source = (source.0 != 0).then_some(source);
+        self.drift.reset();
+    }
+
+    fn sync_controls(&mut self) {
+        let Some(controls) = &self.controls else {
+            return;
+        };
+        let Ok(controls) = controls.lock() else {
+            return;
+        };
+        for source in self.sources.iter_mut() {
+            let Some(control) = controls.get(&source.name) else {
+                continue;
+            };
+            let gain = control.gain.clamp(0.0, 2.0);
+            if gain != source.gain || control.muted != source.muted {
+                info!(
+                    "Audio source '{}' gain set to {:.2}{}",
+                    source.name,
+                    gain,
+                    if control.muted { ", muted" } else { "" }
+                );
+                source.gain = gain;
+                source.muted = control.muted;
             }
-            
-            // Mix them because we have samples from both sources
-            trace!("Mixing system and microphone audio");
-            let sys_sample = self.system_audio_queue.pop_front().unwrap();
-            let mic_sample = self.microphone_queue.pop_front().unwrap();
-            
-            // Mix the samples
-            match self.mix_samples(&sys_sample.sample, &mic_sample.sample) {
-                Ok(mixed) => {
-                    trace!("Successfully mixed audio samples");
-                    Some(Ok(Arc::new(mixed)))
-                },
+        }
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut MixerStats)) {
+        if let Ok(mut stats) = self.stats.lock() {
+            update(&mut stats);
+            stats.system_queue_depth = self.queue_depth(SourceKind::System);
+            stats.microphone_queue_depth = self.queue_depth(SourceKind::Microphone);
+            stats.microphone_drift_ms = self.drift.drift_hns() / 10_000.0;
+            stats.drift_correction_ppm = self.drift.correction_ppm();
+        }
+    }
+
+    fn queue_depth(&self, kind: SourceKind) -> usize {
+        self.sources
+            .iter()
+            .filter(|source| source.kind == kind)
+            .map(|source| source.queue.len())
+            .sum()
+    }
+
+    /// Whether no audio is ready to be mixed; the end of a sound shorter than an output packet
+    /// waits for more audio
+    pub fn is_idle(&self) -> bool {
+        self.next_packet().is_none()
+    }
+
+    /// Set whether the mix waits for `source`, e.g. once a hot-plugged microphone has attached
+    pub fn set_active(&mut self, source: SourceId, active: bool) {
+        let entry = &mut self.sources[source.0];
+        if entry.active != active {
+            info!(
+                "Audio mixer {} waiting for source '{}'",
+                if active { "started" } else { "stopped" },
+                entry.name
+            );
+            entry.active = active;
+            // Drift is only measured while both sources are mixed
+            if source.0 == 0 || Some(source) == self.drift_source {
+                self.drift.reset();
+            }
+        }
+    }
+
+    // Whether the drift corrected source and the reference are both mixed
+    fn measuring_drift(&self) -> bool {
+        self.drift_source
+            .is_some_and(|source| self.sources[0].active && self.sources[source.0].active)
+    }
+
+    /// Queue a packet of `source` for mixing
+    pub fn push(&mut self, source: SourceId, sample: SendableSample) {
+        let sample = if !self.measuring_drift() {
+            sample
+        } else if Some(source) == self.drift_source {
+            // Resample to the reference clock before the packet is placed on the timeline
+            match unsafe { self.drift.correct_microphone(&sample.sample) } {
+                Ok(corrected) => SendableSample::new(corrected),
                 Err(e) => {
-                    error!("Error mixing audio samples: {:?}", e);
-                    Some(Err(e))
-                },
+                    warn!("Failed to correct audio drift: {:?}", e);
+                    sample
+                }
             }
         } else {
-            // Only one source is active, process samples normally
-            
-            // Handle cases where only one source is available
-            if self.system_audio_queue.is_empty() && self.microphone_queue.is_empty() {
-                debug!("Both audio queues empty, returning None");
-                return None;
+            if source.0 == 0 {
+                if let Err(e) = unsafe { self.drift.observe_system(&sample.sample) } {
+                    warn!("Failed to measure the reference audio clock: {:?}", e);
+                }
             }
-            
-            // If only microphone audio is available, apply volume and return
-            if self.system_audio_queue.is_empty() && !self.microphone_queue.is_empty() {
-                debug!("Only microphone audio available, applying volume");
-                let mic_sample = self.microphone_queue.pop_front().unwrap();
-                
-                // If volume is 1.0 (default), no need to process
-                if (self.microphone_volume - 1.0).abs() < 0.001 {
-                    debug!("Using default microphone volume, passing through");
-                    return Some(Ok(mic_sample.sample.clone()));
+            sample
+        };
+
+        let channels = self.channels.max(1) as usize;
+        let packet = unsafe {
+            sample
+                .sample
+                .GetSampleTime()
+                .and_then(|time| read_pcm(&sample.sample).map(|pcm| (time, pcm)))
+        };
+        let (time, mut pcm) = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to read audio packet: {:?}", e);
+                self.update_stats(|stats| stats.mix_errors += 1);
+                return;
+            }
+        };
+        let mut start = self.hns_to_frames(time);
+        let tolerance = self.hns_to_frames(JOIN_TOLERANCE_HNS);
+
+        let entry = &mut self.sources[source.0];
+        let queued_end = entry.end(channels);
+        if let Some(end) = queued_end {
+            if (start - end).abs() <= tolerance {
+                start = end;
+            }
+        }
+        // Drop what overlaps audio already queued or mixed
+        let covered = queued_end.max(self.position).unwrap_or(start);
+        let discarded = ((covered - start).max(0) as usize).min(pcm.len() / channels);
+        if discarded > 0 {
+            debug!(
+                "Dropping {} overlapping frames of '{}' audio",
+                discarded, entry.name
+            );
+            pcm.drain(..discarded * channels);
+            start += discarded as i64;
+        }
+        trace!(
+            "Adding '{}' audio at frame {} to queue (queue size: {})",
+            entry.name,
+            start,
+            entry.queue.len()
+        );
+        if !pcm.is_empty() {
+            entry.queue.push_back((start, pcm));
+        }
+
+        let kind = entry.kind;
+        self.update_stats(|stats| match kind {
+            SourceKind::System => stats.system_packets += 1,
+            SourceKind::Microphone => {
+                stats.microphone_packets += 1;
+                stats.microphone_frames_discarded += discarded as u64;
+            }
+        });
+    }
+
+    fn hns_to_frames(&self, hns: i64) -> i64 {
+        (hns as i128 * self.sample_rate as i128 / 10_000_000) as i64
+    }
+
+    // Timeline frame the next output packet starts at, if it can be mixed yet
+    //
+    // Stretches no source has audio for are skipped; the gap filler covers them with silence.
+    fn next_packet(&self) -> Option<i64> {
+        let channels = self.channels.max(1) as usize;
+        let first = self
+            .sources
+            .iter()
+            .filter_map(|source| source.queue.front().map(|(start, _)| *start))
+            .min()?;
+        let position = self.position.map_or(first, |position| position.max(first));
+        let end = position + OUTPUT_FRAMES as i64;
+        let covers = |source: &MixerSource| source.end(channels).is_some_and(|e| e >= end);
+
+        let mut active = self
+            .sources
+            .iter()
+            .filter(|source| source.active)
+            .peekable();
+        let ready = if active.peek().is_some() {
+            active.all(covers)
+        } else {
+            self.sources.iter().any(covers)
+        };
+        if ready {
+            return Some(position);
+        }
+
+        // Unless a source has stalled (e.g. it is muted at the device and delivers nothing);
+        // then the others go out without it instead of piling up
+        let stall = self.hns_to_frames(MAX_STALL_HNS);
+        if self
+            .sources
+            .iter()
+            .any(|source| source.end(channels).is_some_and(|e| e - position > stall))
+        {
+            debug!("An audio source stalled, mixing the others without it");
+            return Some(position);
+        }
+        trace!("Waiting for audio from every active source to prevent desync");
+        None
+    }
+
+    /// Mix the next output packet, once every active source has delivered audio for it
+    pub unsafe fn process_next_sample(&mut self) -> Option<Result<Arc<IMFSample>>> {
+        self.sync_controls();
+        let position = self.next_packet()?;
+        let end = position + OUTPUT_FRAMES as i64;
+        self.position = Some(end);
+
+        let channels = self.channels.max(1) as usize;
+        let mut mixed = vec![0.0f32; OUTPUT_FRAMES * channels];
+        let mut contributors = Vec::new();
+        let mut microphone_missing = 0u64;
+        // Peak and RMS of the loudest source of each kind
+        let mut system_levels = None;
+        let mut microphone_levels = None;
+        for index in 0..self.sources.len() {
+            let source = &mut self.sources[index];
+            let mut block = vec![0i16; OUTPUT_FRAMES * channels];
+            let mut covered = 0;
+            while let Some((start, pcm)) = source.queue.front_mut() {
+                if *start >= end {
+                    break;
                 }
-                
-                // Apply microphone volume
-                match self.apply_volume_to_sample(&mic_sample.sample, self.microphone_volume) {
-                    Ok(processed) => {
-                        debug!("Successfully applied volume to microphone sample");
-                        return Some(Ok(Arc::new(processed)));
-                    },
-                    Err(e) => {
-                        error!("Error applying volume to microphone sample: {:?}", e);
-                        return Some(Err(e));
-                    }
+                // Queued audio never starts before the mix position
+                let offset = (*start - position) as usize;
+                let frames = (pcm.len() / channels).min(OUTPUT_FRAMES - offset);
+                block[offset * channels..(offset + frames) * channels]
+                    .copy_from_slice(&pcm[..frames * channels]);
+                covered += frames;
+                if frames * channels < pcm.len() {
+                    pcm.drain(..frames * channels);
+                    *start += frames as i64;
+                    break;
                 }
+                source.queue.pop_front();
             }
-            
-            // If only system audio is available, apply volume and return
-            if !self.system_audio_queue.is_empty() && self.microphone_queue.is_empty() {
-                debug!("Only system audio available, applying volume");
-                let sys_sample = self.system_audio_queue.pop_front().unwrap();
-                
-                // If volume is 1.0 (default), no need to process
-                if (self.system_volume - 1.0).abs() < 0.001 {
-                    debug!("Using default system volume, passing through");
-                    return Some(Ok(sys_sample.sample.clone()));
-                }
-                
-                // Apply system volume
-                match self.apply_volume_to_sample(&sys_sample.sample, self.system_volume) {
-                    Ok(processed) => {
-                        debug!("Successfully applied volume to system sample");
-                        return Some(Ok(Arc::new(processed)));
-                    },
-                    Err(e) => {
-                        error!("Error applying volume to system sample: {:?}", e);
-                        return Some(Err(e));
+            if source.active && source.kind == SourceKind::Microphone {
+                microphone_missing += (OUTPUT_FRAMES - covered) as u64;
+            }
+            if covered == 0 {
+                continue;
+            }
+
+            let gain = source.effective_gain();
+            for (out, &s) in mixed.iter_mut().zip(&block) {
+                *out += s as f32 * gain;
+            }
+            let (peak, rms) = measure_levels(&block, gain);
+            let levels = match source.kind {
+                SourceKind::System => &mut system_levels,
+                SourceKind::Microphone => &mut microphone_levels,
+            };
+            let (loudest_peak, loudest_rms) = levels.unwrap_or((0.0f32, 0.0f32));
+            *levels = Some((loudest_peak.max(peak), loudest_rms.max(rms)));
+            contributors.push(index);
+        }
+        self.update_levels(system_levels, microphone_levels);
+
+        if let Some(normalizer) = &mut self.normalizer {
+            normalizer.process(&mut mixed);
+        }
+
+        // Clamp to i16 range to prevent clipping
+        let output: Vec<i16> = mixed
+            .iter()
+            .map(|&s| (s as i32).clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .collect();
+        let time = frame_time_hns(position.max(0) as u64, self.sample_rate, 1);
+        let duration = frame_time_hns(end.max(0) as u64, self.sample_rate, 1) - time;
+        let result = pcm_sample(&output, time, duration).map(Arc::new);
+
+        match &result {
+            Ok(_) => {
+                trace!("Mixed {} audio sources at {} hns", contributors.len(), time);
+                let only = match contributors.as_slice() {
+                    [index] => Some(self.sources[*index].kind),
+                    _ => None,
+                };
+                self.update_stats(|stats| {
+                    stats.microphone_frames_missing += microphone_missing;
+                    match only {
+                        None => stats.mixed_packets += 1,
+                        Some(SourceKind::System) => stats.system_only_packets += 1,
+                        Some(SourceKind::Microphone) => stats.microphone_only_packets += 1,
                     }
-                }
+                });
+            }
+            Err(e) => {
+                error!("Error mixing audio samples: {:?}", e);
+                self.update_stats(|stats| stats.mix_errors += 1);
             }
-            
-            // Shouldn't happen
-            error!("Unexpected state in process_next_sample");
-            None
         }
+        Some(result)
     }
 
-    // Method to apply volume to a single audio sample
-    unsafe fn apply_volume_to_sample(&self, sample: &IMFSample, volume: f32) -> Result<IMFSample> {
-        debug!("Applying volume {:.2} to sample", volume);
-        
-        // Get sample time and duration
-        let sample_time = sample.GetSampleTime()?;
-        let sample_duration = sample.GetSampleDuration()?;
-        
-        // Get the buffer from the sample
-        let buffer = sample.GetBufferByIndex(0)?;
-        
-        // Get the data from the buffer
-        let mut data: *mut u8 = std::ptr::null_mut();
-        let mut length: u32 = 0;
-        
-        // Lock buffer
-        buffer.Lock(&mut data, None, Some(&mut length))?;
-        
-        // Create new sample and buffer for processed audio
-        let output_sample = MFCreateSample()?;
-        let output_buffer = MFCreateMemoryBuffer(length)?;
-        
-        // Lock output buffer
-        let mut output_data: *mut u8 = std::ptr::null_mut();
-        output_buffer.Lock(&mut output_data, None, None)?;
-        
-        // Access audio data as 16-bit PCM
-        let samples = std::slice::from_raw_parts(
-            data as *const i16,
-            length as usize / 2
-        );
-        
-        // Get output as mutable slice of i16
-        let output_samples = std::slice::from_raw_parts_mut(
-            output_data as *mut i16, 
-            length as usize / 2
-        );
-        
-        // Apply volume to each sample
-        for i in 0..samples.len() {
-            // Apply volume
-            let adjusted = (samples[i] as f32 * volume) as i32;
-            
-            // Clamp to i16 range to prevent clipping
-            output_samples[i] = adjusted.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    // Update the meters of the kinds that had audio in an output packet
+    fn update_levels(&self, system: Option<(f32, f32)>, microphone: Option<(f32, f32)>) {
+        let mut levels = self.levels.lock().unwrap();
+        if let Some((peak, rms)) = system {
+            levels.system_peak = peak;
+            levels.system_rms = rms;
         }
-        
-        // Unlock input buffer
-        let input_unlock_result = buffer.Unlock();
-        
-        // Set output buffer length and unlock
-        output_buffer.SetCurrentLength(length)?;
-        output_buffer.Unlock()?;
-        
-        // Check if unlocking input buffer failed
-        if let Err(e) = input_unlock_result {
-            return Err(e);
+        if let Some((peak, rms)) = microphone {
+            levels.mic_peak = peak;
+            levels.mic_rms = rms;
+        }
+    }
+}
+
+// Copy the 16-bit PCM data out of a sample
+unsafe fn read_pcm(sample: &IMFSample) -> Result<Vec<i16>> {
+    let buffer = sample.GetBufferByIndex(0)?;
+    let mut data: *mut u8 = std::ptr::null_mut();
+    let mut length: u32 = 0;
+    buffer.Lock(&mut data, None, Some(&mut length))?;
+    let samples = std::slice::from_raw_parts(data as *const i16, length as usize / 2).to_vec();
+    buffer.Unlock()?;
+    Ok(samples)
+}
+
+// A new sample holding 16-bit PCM data
+unsafe fn pcm_sample(samples: &[i16], time: i64, duration: i64) -> Result<IMFSample> {
+    let length = (samples.len() * 2) as u32;
+    let output_sample = MFCreateSample()?;
+    let output_buffer = MFCreateMemoryBuffer(length)?;
+    let mut output_data: *mut u8 = std::ptr::null_mut();
+    output_buffer.Lock(&mut output_data, None, None)?;
+    std::ptr::copy_nonoverlapping(samples.as_ptr(), output_data as *mut i16, samples.len());
+    output_buffer.SetCurrentLength(length)?;
+    output_buffer.Unlock()?;
+
+    output_sample.AddBuffer(&output_buffer)?;
+    output_sample.SetSampleTime(time)?;
+    output_sample.SetSampleDuration(duration)?;
+    Ok(output_sample)
+}
+/// Settings for the filter chain applied to microphone audio before mixing
+///
+/// Filters run in order: high-pass, noise suppression, noise gate, automatic gain control.
+/// Everything is disabled by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct MicrophoneFilters {
+    /// Cutoff of a 2nd order high-pass filter in Hz (removes rumble and DC offset, ~80 Hz)
+    pub high_pass_hz: Option<f32>,
+    /// Mute the microphone while its level stays below a threshold
+    pub noise_gate: Option<NoiseGate>,
+    /// Attenuate steady background noise by tracking the noise floor
+    ///
+    /// This is a lightweight downward expander, not a neural denoiser.
+    pub noise_suppression: bool,
+    /// Automatically level the microphone towards a target loudness
+    pub auto_gain: Option<AutoGain>,
+}
+
+impl MicrophoneFilters {
+    pub fn is_enabled(&self) -> bool {
+        self.high_pass_hz.is_some()
+            || self.noise_gate.is_some()
+            || self.noise_suppression
+            || self.auto_gain.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct NoiseGate {
+    /// Level below which the gate closes, in dBFS
+    pub threshold_db: f32,
+    /// Time the gate stays open after the level drops below the threshold
+    pub hold_ms: f32,
+    /// Time to fade out once the hold has expired
+    pub release_ms: f32,
+}
+
+impl Default for NoiseGate {
+    fn default() -> Self {
+        Self {
+            threshold_db: -45.0,
+            hold_ms: 150.0,
+            release_ms: 100.0,
         }
-        
-        // Add buffer to sample and set timing info
-        output_sample.AddBuffer(&output_buffer)?;
-        output_sample.SetSampleTime(sample_time)?;
-        output_sample.SetSampleDuration(sample_duration)?;
-        
-        trace!("Volume application completed successfully");
-        Ok(output_sample)
-    }
-
-    unsafe fn mix_samples(&self, sys_sample: &IMFSample, mic_sample: &IMFSample) -> Result<IMFSample> {
-        trace!("Creating mixed buffer");
-        
-        // Get sample time and duration from system audio
-        let sample_time = sys_sample.GetSampleTime()?;
-        let sample_duration = sys_sample.GetSampleDuration()?;
-        
-        // Get the buffers from both samples
-        let sys_buffer = sys_sample.GetBufferByIndex(0)?;
-        let mic_buffer = mic_sample.GetBufferByIndex(0)?;
-        
-        // Get the data from the buffers
-        let mut sys_data: *mut u8 = std::ptr::null_mut();
-        let mut mic_data: *mut u8 = std::ptr::null_mut();
-        let mut sys_length: u32 = 0;
-        let mut mic_length: u32 = 0;
-        
-        // Lock and process buffers without using catch_unwind
-        sys_buffer.Lock(&mut sys_data, None, Some(&mut sys_length))?;
-        
-        // Let's use a simpler approach without catch_unwind
-        let result = (|| {
-            // Lock microphone buffer
-            if let Err(e) = mic_buffer.Lock(&mut mic_data, None, Some(&mut mic_length)) {
-                error!("Failed to lock microphone buffer: {:?}", e);
-                return Err(e);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct AutoGain {
+    /// Target RMS level in dBFS
+    pub target_db: f32,
+    /// Upper bound on the applied gain, in dB
+    pub max_gain_db: f32,
+}
+
+impl Default for AutoGain {
+    fn default() -> Self {
+        Self {
+            target_db: -18.0,
+            max_gain_db: 20.0,
+        }
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Per-channel biquad state (RBJ cookbook high-pass and high-shelf)
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn high_pass(sample_rate: u32, cutoff: f32, q: f32) -> Self {
+        let w0 = 2.0 * std::f32::consts::PI * cutoff / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 + cos_w0) / 2.0 / a0,
+            b1: -(1.0 + cos_w0) / a0,
+            b2: (1.0 + cos_w0) / 2.0 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+            ..Default::default()
+        }
+    }
+
+    fn high_shelf(sample_rate: u32, frequency: f32, gain_db: f32, q: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f32::consts::PI * frequency / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        let a0 = (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha;
+        Self {
+            b0: a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha) / a0,
+            b1: -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0) / a0,
+            b2: a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha) / a0,
+            a1: 2.0 * ((a - 1.0) - (a + 1.0) * cos_w0) / a0,
+            a2: ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha) / a0,
+            ..Default::default()
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Stateful implementation of [`MicrophoneFilters`] for interleaved 16-bit PCM
+pub struct MicrophoneFilterChain {
+    settings: MicrophoneFilters,
+    sample_rate: u32,
+    channels: usize,
+    high_pass: Vec<Biquad>,
+    // Fast envelope of the input (linear, 0..1)
+    envelope: f32,
+    // Slowly rising / quickly falling estimate of the noise floor
+    noise_floor: f32,
+    gate_gain: f32,
+    gate_hold_remaining: u32,
+    agc_gain: f32,
+    agc_power: f32,
+}
+
+impl MicrophoneFilterChain {
+    pub fn new(settings: MicrophoneFilters, sample_rate: u32, channels: u16) -> Self {
+        info!("Creating microphone filter chain: {:?}", settings);
+        let channels = channels.max(1) as usize;
+        let high_pass = match settings.high_pass_hz {
+            Some(cutoff) => {
+                let filter =
+                    Biquad::high_pass(sample_rate, cutoff, std::f32::consts::FRAC_1_SQRT_2);
+                vec![filter; channels]
             }
-            
-            trace!("Buffer sizes - System: {} bytes, Microphone: {} bytes", sys_length, mic_length);
-            
-            // Create a new sample and buffer for the mixed audio
-            let output_sample = match MFCreateSample() {
-                Ok(sample) => sample,
-                Err(e) => {
-                    error!("Failed to create output sample: {:?}", e);
-                    let _ = mic_buffer.Unlock(); // Ignore unlock error here
-                    return Err(e);
+            None => Vec::new(),
+        };
+
+        Self {
+            settings,
+            sample_rate,
+            channels,
+            high_pass,
+            envelope: 0.0,
+            noise_floor: db_to_linear(-60.0),
+            gate_gain: 1.0,
+            gate_hold_remaining: 0,
+            agc_gain: 1.0,
+            agc_power: 0.0,
+        }
+    }
+
+    // One-pole smoothing coefficient for a time constant in milliseconds
+    fn coefficient(&self, ms: f32) -> f32 {
+        if ms <= 0.0 {
+            return 0.0;
+        }
+        (-1.0 / (ms * 0.001 * self.sample_rate as f32)).exp()
+    }
+
+    /// Filter interleaved samples in place
+    pub fn process(&mut self, samples: &mut [i16]) {
+        let attack = self.coefficient(5.0);
+        let release = self.coefficient(50.0);
+        let floor_rise = self.coefficient(2000.0);
+        let gate_release = self
+            .settings
+            .noise_gate
+            .map(|g| self.coefficient(g.release_ms))
+            .unwrap_or(0.0);
+        let gate_attack = self.coefficient(1.0);
+        let agc_smooth = self.coefficient(400.0);
+        let agc_adapt = self.coefficient(1000.0);
+
+        for frame in samples.chunks_mut(self.channels) {
+            let mut values = [0f32; 8];
+            let n = frame.len().min(values.len());
+            for (c, value) in values.iter_mut().enumerate().take(n) {
+                *value = frame[c] as f32 / i16::MAX as f32;
+                if let Some(filter) = self.high_pass.get_mut(c) {
+                    *value = filter.process(*value);
                 }
-            };
-            
-            let output_buffer = match MFCreateMemoryBuffer(sys_length) {
-                Ok(buffer) => buffer,
-                Err(e) => {
-                    error!("Failed to create output buffer: {:?}", e);
-                    let _ = mic_buffer.Unlock(); // Ignore unlock error here
-                    return Err(e);
+            }
+
+            // Envelope follower on the loudest channel
+            let level = values[..n].iter().fold(0.0f32, |m, v| m.max(v.abs()));
+            let coeff = if level > self.envelope { attack } else { release };
+            self.envelope = coeff * self.envelope + (1.0 - coeff) * level;
+
+            let mut gain = 1.0;
+
+            if self.settings.noise_suppression {
+                // The floor follows dips immediately and rises slowly
+                if self.envelope < self.noise_floor {
+                    self.noise_floor = self.envelope.max(1e-6);
+                } else {
+                    self.noise_floor =
+                        floor_rise * self.noise_floor + (1.0 - floor_rise) * self.envelope;
                 }
-            };
-            
-            // Lock output buffer
-            let mut output_data: *mut u8 = std::ptr::null_mut();
-            if let Err(e) = output_buffer.Lock(&mut output_data, None, None) {
-                error!("Failed to lock output buffer: {:?}", e);
-                let _ = mic_buffer.Unlock(); // Ignore unlock error here
-                return Err(e);
+                // Expand signals close to the floor: full attenuation at the floor,
+                // unity gain from 4x (~12 dB) above it
+                let ratio = self.envelope / (self.noise_floor * 4.0);
+                gain *= ratio.clamp(0.1, 1.0);
             }
-            
-            // Mix the audio
-            if let Err(e) = self.mix_pcm_audio(
-                sys_data, 
-                mic_data, 
-                output_data, 
-                sys_length, 
-                mic_length
-            ) {
-                error!("Failed to mix audio: {:?}", e);
-                let _ = mic_buffer.Unlock(); // Ignore unlock error here
-                let _ = output_buffer.Unlock(); // Ignore unlock error here
-                return Err(e);
+
+            if let Some(gate) = self.settings.noise_gate {
+                let target = if self.envelope >= db_to_linear(gate.threshold_db) {
+                    self.gate_hold_remaining =
+                        (gate.hold_ms * 0.001 * self.sample_rate as f32) as u32;
+                    1.0
+                } else if self.gate_hold_remaining > 0 {
+                    self.gate_hold_remaining -= 1;
+                    1.0
+                } else {
+                    0.0
+                };
+                let coeff = if target > self.gate_gain {
+                    gate_attack
+                } else {
+                    gate_release
+                };
+                self.gate_gain = coeff * self.gate_gain + (1.0 - coeff) * target;
+                gain *= self.gate_gain;
             }
-            
-            // Unlock microphone buffer
-            if let Err(e) = mic_buffer.Unlock() {
-                error!("Failed to unlock microphone buffer: {:?}", e);
-                let _ = output_buffer.Unlock(); // Ignore unlock error here
-                return Err(e);
+
+            if let Some(agc) = self.settings.auto_gain {
+                let power = values[..n].iter().map(|v| v * v).sum::<f32>() / n.max(1) as f32;
+                self.agc_power = agc_smooth * self.agc_power + (1.0 - agc_smooth) * power;
+                // Only adapt on speech-level input so silence isn't boosted
+                if self.agc_power.sqrt() > db_to_linear(-50.0) {
+                    let desired = (db_to_linear(agc.target_db) / self.agc_power.sqrt())
+                        .min(db_to_linear(agc.max_gain_db));
+                    self.agc_gain = agc_adapt * self.agc_gain + (1.0 - agc_adapt) * desired;
+                }
+                gain *= self.agc_gain;
             }
-            
-            // Set buffer length and unlock output buffer
-            if let Err(e) = output_buffer.SetCurrentLength(sys_length) {
-                error!("Failed to set output buffer length: {:?}", e);
-                let _ = output_buffer.Unlock(); // Ignore unlock error here
-                return Err(e);
+
+            for (c, sample) in frame.iter_mut().enumerate().take(n) {
+                let out = (values[c] * gain * i16::MAX as f32) as i32;
+                *sample = out.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
             }
-            
-            if let Err(e) = output_buffer.Unlock() {
-                error!("Failed to unlock output buffer: {:?}", e);
-                return Err(e);
+        }
+    }
+
+    /// Filter the PCM data of a sample in place
+    pub unsafe fn process_sample(&mut self, sample: &IMFSample) -> Result<()> {
+        let buffer = sample.GetBufferByIndex(0)?;
+
+        let mut data: *mut u8 = std::ptr::null_mut();
+        let mut length: u32 = 0;
+        buffer.Lock(&mut data, None, Some(&mut length))?;
+
+        let samples = std::slice::from_raw_parts_mut(data as *mut i16, length as usize / 2);
+        self.process(samples);
+        trace!("Filtered {} microphone samples", samples.len());
+
+        buffer.Unlock()
+    }
+}
+
+/// Length of the loudness measurement blocks, in milliseconds
+const LOUDNESS_BLOCK_MS: u32 = 100;
+/// Blocks the loudness is measured over, 3 s like the EBU R128 short-term loudness
+const LOUDNESS_WINDOW_BLOCKS: usize = 30;
+/// Blocks quieter than this are left out of the measurement, so pauses are not boosted
+const LOUDNESS_GATE_LUFS: f32 = -70.0;
+/// Largest gain applied to reach the target, in dB
+const MAX_NORMALIZATION_GAIN_DB: f32 = 12.0;
+/// How fast the gain follows the measured loudness, in dB per second
+const NORMALIZATION_SLEW_DB: f32 = 3.0;
+/// Level the limiter keeps peaks under, in dBFS
+const LIMITER_CEILING_DB: f32 = -1.0;
+/// Time the limiter takes to let go after a peak, in milliseconds
+const LIMITER_RELEASE_MS: f32 = 100.0;
+
+/// Levels the mixed track towards a loudness target and limits its peaks
+///
+/// Loudness is measured as in ITU-R BS.1770: K-weighted mean square over 100 ms blocks, gated
+/// at -70 LUFS and averaged over the last 3 s. The gain follows it slowly so speech and music
+/// are not pumped, and a limiter with instant attack catches what would still clip.
+pub(crate) struct LoudnessNormalizer {
+    target_lufs: f32,
+    sample_rate: u32,
+    channels: usize,
+    // Pre-filter (high shelf) and RLB high-pass of each channel
+    k_weighting: Vec<[Biquad; 2]>,
+    // K-weighted mean square of the last blocks
+    blocks: VecDeque<f64>,
+    block_power: f64,
+    block_frames: u32,
+    gain_db: f32,
+    limiter_gain: f32,
+}
+
+impl LoudnessNormalizer {
+    pub fn new(target_lufs: f32, sample_rate: u32, channels: u16) -> Self {
+        info!("Normalizing the audio mix to {:.1} LUFS", target_lufs);
+        let channels = channels.max(1) as usize;
+        let k_weighting = [
+            Biquad::high_shelf(sample_rate, 1500.0, 4.0, 0.7072),
+            Biquad::high_pass(sample_rate, 38.14, 0.5003),
+        ];
+        Self {
+            target_lufs,
+            sample_rate,
+            channels,
+            k_weighting: vec![k_weighting; channels],
+            blocks: VecDeque::with_capacity(LOUDNESS_WINDOW_BLOCKS),
+            block_power: 0.0,
+            block_frames: 0,
+            gain_db: 0.0,
+            limiter_gain: 1.0,
+        }
+    }
+
+    // Loudness of the measured window, None while it only holds silence
+    fn loudness(&self) -> Option<f32> {
+        let gate = 10f64.powf((LOUDNESS_GATE_LUFS as f64 + 0.691) / 10.0);
+        let (sum, count) = self
+            .blocks
+            .iter()
+            .filter(|&&power| power > gate)
+            .fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+        (count > 0).then(|| (-0.691 + 10.0 * (sum / count as f64).log10()) as f32)
+    }
+
+    /// Measure and level interleaved samples in place, in 16-bit range before clamping
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let block_len = self.sample_rate * LOUDNESS_BLOCK_MS / 1000;
+        for frame in samples.chunks(self.channels) {
+            for (value, filters) in frame.iter().zip(self.k_weighting.iter_mut()) {
+                let weighted = filters
+                    .iter_mut()
+                    .fold(value / i16::MAX as f32, |x, filter| filter.process(x));
+                self.block_power += (weighted * weighted) as f64;
             }
-            
-            // Add buffer to sample and set timing info
-            if let Err(e) = output_sample.AddBuffer(&output_buffer) {
-                error!("Failed to add buffer to output sample: {:?}", e);
-                return Err(e);
+            self.block_frames += 1;
+            if self.block_frames >= block_len {
+                if self.blocks.len() == LOUDNESS_WINDOW_BLOCKS {
+                    self.blocks.pop_front();
+                }
+                self.blocks
+                    .push_back(self.block_power / self.block_frames as f64);
+                self.block_power = 0.0;
+                self.block_frames = 0;
             }
-            
-            if let Err(e) = output_sample.SetSampleTime(sample_time) {
-                error!("Failed to set output sample time: {:?}", e);
-                return Err(e);
+        }
+
+        // Move the gain towards the target at a limited rate
+        if let Some(loudness) = self.loudness() {
+            let desired = (self.target_lufs - loudness)
+                .clamp(-MAX_NORMALIZATION_GAIN_DB, MAX_NORMALIZATION_GAIN_DB);
+            let frames = (samples.len() / self.channels) as f32;
+            let step = NORMALIZATION_SLEW_DB * frames / self.sample_rate as f32;
+            self.gain_db += (desired - self.gain_db).clamp(-step, step);
+        }
+
+        let gain = db_to_linear(self.gain_db);
+        let ceiling = db_to_linear(LIMITER_CEILING_DB) * i16::MAX as f32;
+        let release = 1.0 - (-1.0 / (LIMITER_RELEASE_MS * 0.001 * self.sample_rate as f32)).exp();
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |m, v| m.max(v.abs())) * gain;
+            self.limiter_gain += (1.0 - self.limiter_gain) * release;
+            if peak * self.limiter_gain > ceiling {
+                self.limiter_gain = ceiling / peak;
             }
-            
-            if let Err(e) = output_sample.SetSampleDuration(sample_duration) {
-                error!("Failed to set output sample duration: {:?}", e);
-                return Err(e);
+            for value in frame.iter_mut() {
+                *value *= gain * self.limiter_gain;
             }
-            
-            Ok(output_sample)
-        })();
-        
-        // Always unlock system buffer
-        let sys_unlock_result = sys_buffer.Unlock();
-        
-        // Return the mixing result or propagate error
-        match (result, sys_unlock_result) {
-            (Ok(output_sample), Ok(())) => Ok(output_sample),
-            (Err(e), _) => Err(e),
-            (_, Err(e)) => Err(e),
         }
     }
-    
-    // Mixing function with volume controls allowing amplification
-    unsafe fn mix_pcm_audio(
-        &self,
-        sys_data: *mut u8,     // System audio (16-bit PCM)
-        mic_data: *mut u8,     // Microphone audio (16-bit PCM)
-        output_data: *mut u8,  // Output buffer (16-bit PCM)
-        sys_length: u32,
-        mic_length: u32
-    ) -> Result<()> {
-        trace!("Mixing with system volume:{:.2} mic volume:{:.2}", 
-               self.system_volume, self.microphone_volume);
-        
-        // Access the audio data as 16-bit PCM
-        let sys_samples = std::slice::from_raw_parts(
-            sys_data as *const i16,
-            sys_length as usize / 2
-        );
-        
-        let mic_samples = std::slice::from_raw_parts(
-            mic_data as *const i16,
-            mic_length as usize / 2
-        );
-        
-        // Get output as mutable slice of i16
-        let output_samples = std::slice::from_raw_parts_mut(
-            output_data as *mut i16, 
-            sys_length as usize / 2  // Use system length for output
-        );
-        
-        // Determine how many samples to mix
-        let mix_len = std::cmp::min(
-            sys_samples.len(),
-            std::cmp::min(
-                mic_samples.len(),
-                output_samples.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono at 10 kHz, so each frame lasts 1000 hns
+    const RATE: u32 = 10_000;
+    const FRAME_HNS: i64 = 1_000;
+    const PACKET: usize = OUTPUT_FRAMES;
+
+    fn mixer() -> AudioMixer {
+        AudioMixer::new(RATE, 16, 1)
+    }
+
+    /// A packet of `frames` frames of `value` starting at timeline frame `start`
+    fn packet(start: i64, frames: usize, value: i16) -> SendableSample {
+        let sample = unsafe {
+            pcm_sample(
+                &vec![value; frames],
+                start * FRAME_HNS,
+                frames as i64 * FRAME_HNS,
             )
-        );
-        
-        // Mix the samples with gain controls
-        for i in 0..mix_len {
-            // Apply gains to each source - this can amplify the signal
-            let mixed_val = (sys_samples[i] as f32 * self.system_volume + 
-                            mic_samples[i] as f32 * self.microphone_volume) as i32;
-            
-            // Clamp to i16 range to prevent clipping
-            let clamped = mixed_val.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-            
-            // Write to output
-            output_samples[i] = clamped;
+        };
+        SendableSample::new(sample.unwrap())
+    }
+
+    /// Start frame and PCM of the next mixed packet, None while the mixer waits
+    fn mix(mixer: &mut AudioMixer) -> Option<(i64, Vec<i16>)> {
+        let sample = unsafe { mixer.process_next_sample() }?.unwrap();
+        unsafe {
+            Some((
+                sample.GetSampleTime().unwrap() / FRAME_HNS,
+                read_pcm(&sample).unwrap(),
+            ))
         }
-        
-        // If sys_samples is longer than mic_samples, fill the rest with system audio
-        if sys_samples.len() > mix_len {
-            for i in mix_len..std::cmp::min(sys_samples.len(), output_samples.len()) {
-                // Apply system gain to remaining samples
-                let val = (sys_samples[i] as f32 * self.system_volume) as i32;
-                output_samples[i] = val.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-            }
+    }
+
+    /// `frames` frames of `value` followed by `rest` frames of `other`
+    fn pcm(frames: usize, value: i16, rest: usize, other: i16) -> Vec<i16> {
+        let mut pcm = vec![value; frames];
+        pcm.extend(vec![other; rest]);
+        pcm
+    }
+
+    #[test]
+    fn sums_every_source() {
+        let mut mixer = mixer();
+        let sources: Vec<SourceId> = ["system", "game", "voice"]
+            .into_iter()
+            .map(|name| mixer.add_source(name))
+            .collect();
+        for (source, value) in sources.iter().zip([100, 200, 400]) {
+            mixer.push(*source, packet(0, PACKET, value));
+        }
+        assert_eq!(mix(&mut mixer), Some((0, vec![700; PACKET])));
+        assert_eq!(mix(&mut mixer), None);
+    }
+
+    #[test]
+    fn waits_for_every_active_source() {
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        let microphone = mixer.add_source(MICROPHONE_SOURCE);
+        let game = mixer.add_source("game");
+
+        mixer.push(system, packet(0, PACKET, 100));
+        mixer.push(microphone, packet(0, PACKET, 10));
+        assert!(mixer.is_idle());
+        assert_eq!(mix(&mut mixer), None);
+
+        mixer.set_active(game, false);
+        assert_eq!(mix(&mut mixer), Some((0, vec![110; PACKET])));
+    }
+
+    #[test]
+    fn places_packets_at_their_timestamp() {
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        let microphone = mixer.add_source(MICROPHONE_SOURCE);
+        mixer.push(system, packet(0, 2 * PACKET, 100));
+        mixer.push(microphone, packet(512, PACKET + 512, 10));
+
+        assert_eq!(mix(&mut mixer), Some((0, pcm(512, 100, 512, 110))));
+        assert_eq!(mix(&mut mixer), Some((1024, vec![110; PACKET])));
+    }
+
+    #[test]
+    fn fills_gaps_with_silence() {
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        let microphone = mixer.add_source(MICROPHONE_SOURCE);
+        mixer.push(system, packet(0, PACKET, 100));
+        mixer.push(microphone, packet(0, 256, 10));
+        mixer.push(microphone, packet(768, 256, 10));
+
+        let mut expected = pcm(256, 110, 512, 100);
+        expected.extend(vec![110; 256]);
+        assert_eq!(mix(&mut mixer), Some((0, expected)));
+    }
+
+    #[test]
+    fn joins_packets_with_timestamp_jitter() {
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        mixer.push(system, packet(0, PACKET, 100));
+        // 2 ms late, within the join tolerance
+        mixer.push(system, packet(PACKET as i64 + 20, PACKET, 50));
+
+        assert_eq!(mix(&mut mixer), Some((0, vec![100; PACKET])));
+        assert_eq!(mix(&mut mixer), Some((1024, vec![50; PACKET])));
+    }
+
+    #[test]
+    fn drops_overlapping_audio() {
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        mixer.push(system, packet(0, PACKET, 100));
+        mixer.push(system, packet(512, PACKET, 50));
+        mixer.push(system, packet(1536, 512, 50));
+
+        assert_eq!(mix(&mut mixer), Some((0, vec![100; PACKET])));
+        assert_eq!(mix(&mut mixer), Some((1024, vec![50; PACKET])));
+        // Audio for time already mixed is dropped as well
+        mixer.push(system, packet(0, 3 * PACKET, 25));
+        assert_eq!(mix(&mut mixer), Some((2048, vec![25; PACKET])));
+    }
+
+    #[test]
+    fn mixes_without_a_stalled_source() {
+        let stats = Arc::new(Mutex::new(MixerStats::default()));
+        let mut mixer = mixer();
+        mixer.set_stats_sink(stats.clone());
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        let microphone = mixer.add_source(MICROPHONE_SOURCE);
+
+        // Less than the stall limit queued: keep waiting for the microphone
+        mixer.push(system, packet(0, 2 * PACKET, 100));
+        assert_eq!(mix(&mut mixer), None);
+
+        // More than the stall limit: go on with silence for the microphone
+        mixer.push(system, packet(2 * PACKET as i64, 2 * PACKET, 100));
+        assert_eq!(mix(&mut mixer), Some((0, vec![100; PACKET])));
+        {
+            let stats = stats.lock().unwrap();
+            assert_eq!(stats.system_only_packets, 1);
+            assert_eq!(stats.microphone_frames_missing, PACKET as u64);
         }
-        
-        trace!("PCM mixing completed successfully");
-        Ok(())
+
+        // Once it delivers again it is mixed from the current position on
+        mixer.push(microphone, packet(0, 2 * PACKET, 10));
+        assert_eq!(mix(&mut mixer), Some((1024, vec![110; PACKET])));
+        assert_eq!(stats.lock().unwrap().mixed_packets, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn applies_gain_and_mute_from_controls() {
+        let controls = mixer_controls(true, true, Some(0.5), None, &["headset".to_string()]);
+        let mut mixer = mixer();
+        let system = mixer.add_source(SYSTEM_AUDIO_SOURCE);
+        let microphone = mixer.add_source(MICROPHONE_SOURCE);
+        let headset = mixer.add_source("headset");
+        mixer.set_controls(controls.clone());
+        controls
+            .lock()
+            .unwrap()
+            .get_mut(MICROPHONE_SOURCE)
+            .unwrap()
+            .muted = true;
+
+        mixer.push(system, packet(0, PACKET, 100));
+        mixer.push(microphone, packet(0, PACKET, 1000));
+        mixer.push(headset, packet(0, PACKET, 10));
+        // The muted microphone is still waited for, but adds silence
+        assert_eq!(mix(&mut mixer), Some((0, vec![60; PACKET])));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::logging::{info, trace};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::IMFSample;
+
+use super::audio::{MICROPHONE_SOURCE, SYSTEM_AUDIO_SOURCE};
+use crate::types::hns_to_duration;
+
+/// Name of the mixed audio track for `RecorderConfigBuilder::audio_filters`, next to
+/// `SYSTEM_AUDIO_SOURCE` and `MICROPHONE_SOURCE`
+pub const AUDIO_MIX: &str = "mix";
+
+/// A packet of audio going through an [`AudioFilter`]
+pub struct AudioBlock<'a> {
+    /// Interleaved 16-bit PCM, filtered in place
+    pub samples: &'a mut [i16],
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Position of the first frame in the recording
+    pub timestamp: Duration,
+}
+
+/// Custom processing of one audio source or of the mixed track, e.g. an equalizer or compressor
+///
+/// Filters run in order on the processing thread for every packet, after the built-in microphone
+/// filters and before the source is mixed, or on the mix before it is encoded.
+pub trait AudioFilter: Send {
+    /// Name used in log messages
+    fn name(&self) -> &str;
+
+    /// Filter `block.samples` in place
+    fn process(&mut self, block: &mut AudioBlock<'_>);
+}
+
+type FilterList = Arc<Mutex<Vec<Box<dyn AudioFilter>>>>;
+
+/// The filters set with `RecorderConfigBuilder::audio_filters` for each source, shared by the
+/// config's copies
+#[derive(Clone, Default)]
+pub(crate) struct AudioFilters(Vec<(String, FilterList)>);
+
+impl AudioFilters {
+    /// Replace the filters of `source`
+    pub fn set(&mut self, source: &str, filters: Vec<Box<dyn AudioFilter>>) {
+        self.0.retain(|(name, _)| name != source);
+        if !filters.is_empty() {
+            self.0
+                .push((source.to_string(), Arc::new(Mutex::new(filters))));
+        }
+    }
+
+    /// Sources with filters that are neither `SYSTEM_AUDIO_SOURCE`, `MICROPHONE_SOURCE`,
+    /// `AUDIO_MIX` nor one of `named`, the captured processes and extra microphones
+    pub fn unknown_sources(&self, named: &[String]) -> Vec<&str> {
+        self.0
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| ![SYSTEM_AUDIO_SOURCE, MICROPHONE_SOURCE, AUDIO_MIX].contains(name))
+            .filter(|name| !named.iter().any(|named| named == name))
+            .collect()
+    }
+
+    /// The chain running the filters of `source`, None if it has none
+    pub fn chain(&self, source: &str, sample_rate: u32, channels: u16) -> Option<AudioFilterChain> {
+        let (name, filters) = self.0.iter().find(|(name, _)| name == source)?;
+        let names: Vec<String> = filters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.name().to_string())
+            .collect();
+        info!("Audio filters for '{}': {}", name, names.join(", "));
+        Some(AudioFilterChain {
+            source: name.clone(),
+            filters: filters.clone(),
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+/// Runs the audio filters of one source over its packets
+pub(crate) struct AudioFilterChain {
+    source: String,
+    filters: FilterList,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl AudioFilterChain {
+    /// Filter the PCM data of a sample in place
+    pub unsafe fn process_sample(&mut self, sample: &IMFSample) -> Result<()> {
+        let timestamp = sample.GetSampleTime()?;
+        let buffer = sample.GetBufferByIndex(0)?;
+
+        let mut data: *mut u8 = std::ptr::null_mut();
+        let mut length: u32 = 0;
+        buffer.Lock(&mut data, None, Some(&mut length))?;
+
+        let samples = std::slice::from_raw_parts_mut(data as *mut i16, length as usize / 2);
+        let mut block = AudioBlock {
+            samples,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp: hns_to_duration(timestamp),
+        };
+        for filter in self.filters.lock().unwrap().iter_mut() {
+            filter.process(&mut block);
+        }
+        trace!(
+            "Filtered {} '{}' audio samples",
+            block.samples.len(),
+            self.source
+        );
+
+        buffer.Unlock()
+    }
+}
@@ -0,0 +1,157 @@
+use crate::logging::{info, trace};
+use windows::core::{ComInterface, Result};
+use windows::Win32::Foundation::RECT;
+use windows::Win32::Graphics::Direct3D::D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM;
+
+use super::scaler::{
+    blob_bytes, compile_shader, create_render_target, RenderTarget, ScalerParams, SCALER_HLSL,
+};
+
+/// A texture drawn onto the scene canvas
+pub(crate) struct CompositeLayer<'a> {
+    /// BGRA texture on the compositor's device, bindable as a shader resource
+    pub texture: &'a ID3D11Texture2D,
+    /// Region of the texture that is drawn, in texels
+    pub source_rect: RECT,
+    /// Top-left corner of the layer on the canvas
+    pub position: (i32, i32),
+    pub scale: f32,
+}
+
+/// Draws scene layers into one BGRA canvas on the GPU
+pub(crate) struct SceneCompositor {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    vertex_shader: ID3D11VertexShader,
+    pixel_shader: ID3D11PixelShader,
+    sampler: ID3D11SamplerState,
+    constants: ID3D11Buffer,
+    canvas: RenderTarget,
+}
+
+impl SceneCompositor {
+    pub unsafe fn new(device: &ID3D11Device, width: u32, height: u32) -> Result<Self> {
+        info!(
+            "Creating scene compositor with a {}x{} canvas",
+            width, height
+        );
+        let context = device.GetImmediateContext()?;
+
+        let vs_blob = compile_shader(SCALER_HLSL, "vs_main", "vs_5_0")?;
+        let mut vertex_shader = None;
+        device.CreateVertexShader(blob_bytes(&vs_blob), None, Some(&mut vertex_shader))?;
+        let ps_blob = compile_shader(SCALER_HLSL, "ps_bilinear", "ps_5_0")?;
+        let mut pixel_shader = None;
+        device.CreatePixelShader(blob_bytes(&ps_blob), None, Some(&mut pixel_shader))?;
+
+        let sampler_desc = D3D11_SAMPLER_DESC {
+            Filter: D3D11_FILTER_MIN_MAG_MIP_LINEAR,
+            AddressU: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressV: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressW: D3D11_TEXTURE_ADDRESS_CLAMP,
+            MaxLOD: f32::MAX,
+            ..Default::default()
+        };
+        let mut sampler = None;
+        device.CreateSamplerState(&sampler_desc, Some(&mut sampler))?;
+
+        let buffer_desc = D3D11_BUFFER_DESC {
+            ByteWidth: std::mem::size_of::<ScalerParams>() as u32,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+            ..Default::default()
+        };
+        let mut constants = None;
+        device.CreateBuffer(&buffer_desc, None, Some(&mut constants))?;
+
+        let canvas = create_render_target(device, width, height, DXGI_FORMAT_B8G8R8A8_UNORM)?;
+
+        Ok(Self {
+            device: device.clone(),
+            context,
+            vertex_shader: vertex_shader.unwrap(),
+            pixel_shader: pixel_shader.unwrap(),
+            sampler: sampler.unwrap(),
+            constants: constants.unwrap(),
+            canvas,
+        })
+    }
+
+    /// Draw `layers` in order, each over the previous ones, onto a black canvas
+    ///
+    /// The returned canvas is overwritten by the next call, so it must be copied before then.
+    pub unsafe fn compose(&self, layers: &[CompositeLayer]) -> Result<&ID3D11Texture2D> {
+        // Keep the pipeline state atomic with respect to other threads
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+        let result = self.draw_layers(layers);
+        // Unbind so the canvas can be copied and the sources written again
+        self.context.PSSetShaderResources(0, Some(&[None]));
+        self.context.OMSetRenderTargets(None, None);
+        multithread.Leave();
+        result.map(|()| &self.canvas.texture)
+    }
+
+    unsafe fn draw_layers(&self, layers: &[CompositeLayer]) -> Result<()> {
+        self.context
+            .IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        self.context.IASetInputLayout(None);
+        self.context.VSSetShader(&self.vertex_shader, None);
+        self.context.PSSetShader(&self.pixel_shader, None);
+        self.context
+            .PSSetSamplers(0, Some(&[Some(self.sampler.clone())]));
+        self.context
+            .PSSetConstantBuffers(0, Some(&[Some(self.constants.clone())]));
+        self.context
+            .OMSetRenderTargets(Some(&[Some(self.canvas.rtv.clone())]), None);
+        self.context
+            .ClearRenderTargetView(&self.canvas.rtv, [0.0, 0.0, 0.0, 1.0].as_ptr());
+
+        for layer in layers {
+            let rect = layer.source_rect;
+            let rect_width = (rect.right - rect.left) as f32;
+            let rect_height = (rect.bottom - rect.top) as f32;
+            let dst_width = rect_width * layer.scale;
+            let dst_height = rect_height * layer.scale;
+            if dst_width < 1.0 || dst_height < 1.0 {
+                trace!("Skipping empty scene layer with source rect {:?}", rect);
+                continue;
+            }
+
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            layer.texture.GetDesc(&mut desc);
+            let mut srv = None;
+            self.device
+                .CreateShaderResourceView(layer.texture, None, Some(&mut srv))?;
+
+            let params = ScalerParams {
+                src_rect: [rect.left as f32, rect.top as f32, rect_width, rect_height],
+                src_size: [desc.Width as f32, desc.Height as f32],
+                dst_size: [dst_width, dst_height],
+                ..Default::default()
+            };
+            self.context.UpdateSubresource(
+                &self.constants,
+                0,
+                None,
+                &params as *const _ as *const _,
+                0,
+                0,
+            );
+            self.context.PSSetShaderResources(0, Some(&[srv]));
+            // Parts of the layer outside the canvas are clipped by the rasterizer
+            self.context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+                TopLeftX: layer.position.0 as f32,
+                TopLeftY: layer.position.1 as f32,
+                Width: dst_width,
+                Height: dst_height,
+                MinDepth: 0.0,
+                MaxDepth: 1.0,
+            }]));
+            self.context.Draw(3, 0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::logging::{debug, info};
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::{IMFSample, MFCreateMemoryBuffer, MFCreateSample};
+
+/// Packets further apart than their length plus this are a gap (silence or a device switch), not
+/// clock drift, in 100ns units
+const GAP_TOLERANCE_HNS: f64 = 100_000.0;
+/// Time over which an accumulated drift is corrected, in 100ns units
+const CORRECTION_HORIZON_HNS: f64 = 100_000_000.0;
+/// Largest rate change applied to the microphone, in parts per million
+const MAX_CORRECTION_PPM: f64 = 1000.0;
+/// Change in correction that is worth a log line
+const LOG_STEP_PPM: f64 = 25.0;
+
+/// How far an audio device clock has run against QPC
+#[derive(Default)]
+struct ClockTracker {
+    /// Time and frame count of the previous packet
+    last: Option<(i64, usize)>,
+    /// Real time elapsed minus the time the delivered frames cover, in 100ns units
+    deviation_hns: f64,
+}
+
+impl ClockTracker {
+    fn observe(&mut self, time_hns: i64, frames: usize, sample_rate: u32) {
+        if let Some((last_time, last_frames)) = self.last {
+            let nominal = last_frames as f64 * 10_000_000.0 / sample_rate as f64;
+            let elapsed = (time_hns - last_time) as f64;
+            if (elapsed - nominal).abs() < GAP_TOLERANCE_HNS {
+                self.deviation_hns += elapsed - nominal;
+            }
+        }
+        self.last = Some((time_hns, frames));
+    }
+}
+
+/// Keeps the microphone in step with system audio while they are mixed
+///
+/// The microphone and the render device run on separate clocks, so over hours one delivers
+/// measurably more audio than the other. Both are compared against QPC, and the microphone is
+/// resampled by a few ppm to make up the difference gradually instead of letting sync degrade.
+pub(crate) struct DriftCorrector {
+    sample_rate: u32,
+    channels: usize,
+    system: ClockTracker,
+    microphone: ClockTracker,
+    /// Microphone frames added (positive) or removed by resampling so far
+    adjusted_frames: f64,
+    /// Read position into the current packet; frame 0 is the last frame of the previous one
+    position: f64,
+    previous: Vec<i16>,
+    correction_ppm: f64,
+    logged_ppm: f64,
+}
+
+impl DriftCorrector {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels: channels.max(1) as usize,
+            system: ClockTracker::default(),
+            microphone: ClockTracker::default(),
+            adjusted_frames: 0.0,
+            position: 0.0,
+            previous: Vec::new(),
+            correction_ppm: 0.0,
+            logged_ppm: 0.0,
+        }
+    }
+
+    /// Forget the measured drift, e.g. when mixing stops or starts
+    pub fn reset(&mut self) {
+        debug!("Resetting audio drift correction");
+        *self = Self::new(self.sample_rate, self.channels as u16);
+    }
+
+    /// Rate change currently applied to the microphone, in parts per million
+    pub fn correction_ppm(&self) -> f64 {
+        self.correction_ppm
+    }
+
+    /// Drift of the microphone against system audio not yet corrected, in 100ns units
+    pub fn drift_hns(&self) -> f64 {
+        self.microphone.deviation_hns
+            - self.adjusted_frames * 10_000_000.0 / self.sample_rate as f64
+            - self.system.deviation_hns
+    }
+
+    pub unsafe fn observe_system(&mut self, sample: &IMFSample) -> Result<()> {
+        let frames = self.frame_count(sample)?;
+        self.system
+            .observe(sample.GetSampleTime()?, frames, self.sample_rate);
+        Ok(())
+    }
+
+    /// Resample a 16-bit PCM microphone packet to follow the system audio clock
+    pub unsafe fn correct_microphone(&mut self, sample: &IMFSample) -> Result<IMFSample> {
+        let sample_time = sample.GetSampleTime()?;
+        let input = sample.GetBufferByIndex(0)?;
+        let mut data: *mut u8 = std::ptr::null_mut();
+        let mut length: u32 = 0;
+        input.Lock(&mut data, None, Some(&mut length))?;
+        let samples = std::slice::from_raw_parts(data as *const i16, length as usize / 2).to_vec();
+        input.Unlock()?;
+
+        let in_frames = samples.len() / self.channels;
+        self.microphone
+            .observe(sample_time, in_frames, self.sample_rate);
+        self.update_correction();
+
+        let step = 1.0 / (1.0 + self.correction_ppm / 1_000_000.0);
+        let output = self.resample(&samples, step);
+        let out_frames = output.len() / self.channels;
+        self.adjusted_frames += out_frames as f64 - in_frames as f64;
+
+        let byte_length = (output.len() * 2) as u32;
+        let output_buffer = MFCreateMemoryBuffer(byte_length.max(1))?;
+        let mut output_data: *mut u8 = std::ptr::null_mut();
+        output_buffer.Lock(&mut output_data, None, None)?;
+        std::ptr::copy_nonoverlapping(output.as_ptr(), output_data as *mut i16, output.len());
+        output_buffer.SetCurrentLength(byte_length)?;
+        output_buffer.Unlock()?;
+
+        let output_sample = MFCreateSample()?;
+        output_sample.AddBuffer(&output_buffer)?;
+        output_sample.SetSampleTime(sample_time)?;
+        output_sample
+            .SetSampleDuration(out_frames as i64 * 10_000_000 / self.sample_rate as i64)?;
+        Ok(output_sample)
+    }
+
+    unsafe fn frame_count(&self, sample: &IMFSample) -> Result<usize> {
+        let length = sample.GetTotalLength()? as usize;
+        Ok(length / 2 / self.channels)
+    }
+
+    // Correct the remaining drift over the next CORRECTION_HORIZON_HNS
+    fn update_correction(&mut self) {
+        let ppm = (self.drift_hns() / CORRECTION_HORIZON_HNS * 1_000_000.0)
+            .clamp(-MAX_CORRECTION_PPM, MAX_CORRECTION_PPM);
+        self.correction_ppm = ppm;
+        if (ppm - self.logged_ppm).abs() >= LOG_STEP_PPM {
+            info!(
+                "Microphone drift {:.2} ms against system audio, resampling by {:+.0} ppm",
+                self.drift_hns() / 10_000.0,
+                ppm
+            );
+            self.logged_ppm = ppm;
+        }
+    }
+
+    // Linear interpolation reading `step` input frames per output frame, continuing from the
+    // previous packet so there are no seams
+    fn resample(&mut self, input: &[i16], step: f64) -> Vec<i16> {
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return Vec::new();
+        }
+        if self.previous.len() != channels {
+            self.previous = input[..channels].to_vec();
+        }
+
+        let frame = |index: usize| -> &[i16] {
+            if index == 0 {
+                &self.previous
+            } else {
+                &input[(index - 1) * channels..index * channels]
+            }
+        };
+
+        let mut output = Vec::with_capacity((frames as f64 / step) as usize * channels + channels);
+        let mut position = self.position;
+        while position < frames as f64 {
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let (a, b) = (frame(index), frame(index + 1));
+            for channel in 0..channels {
+                let value = a[channel] as f32 + (b[channel] as f32 - a[channel] as f32) * fraction;
+                output.push(value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            }
+            position += step;
+        }
+
+        self.position = position - frames as f64;
+        self.previous = input[(frames - 1) * channels..].to_vec();
+        output
+    }
+}
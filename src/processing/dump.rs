@@ -0,0 +1,389 @@
+use crate::logging::{debug, info, trace, warn};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use windows::core::{ComInterface, Interface, Result};
+use windows::Win32::Foundation::E_FAIL;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
+use windows::Win32::Media::MediaFoundation::*;
+
+// Capture dump file layout (all values little endian):
+//
+//   header:  b"WRDUMP01", input_width u32, input_height u32, fps_num u32, fps_den u32
+//   records: kind u8, sample_time i64, sample_duration i64, payload_len u32, payload
+//
+// Video payloads are BGRA frames packed with a 32-bit PackBits variant, audio payloads are
+// the raw PCM bytes, and window info payloads are x i32, y i32, width u32, height u32.
+
+const DUMP_MAGIC: &[u8; 8] = b"WRDUMP01";
+
+/// Kind of a record stored in a capture dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpRecordKind {
+    Video = 0,
+    SystemAudio = 1,
+    Microphone = 2,
+    WindowInfo = 3,
+}
+
+impl DumpRecordKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Video),
+            1 => Some(Self::SystemAudio),
+            2 => Some(Self::Microphone),
+            3 => Some(Self::WindowInfo),
+            _ => None,
+        }
+    }
+}
+
+/// Stream parameters stored at the start of a capture dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpHeader {
+    pub input_width: u32,
+    pub input_height: u32,
+    pub fps_num: u32,
+    pub fps_den: u32,
+}
+
+/// A single record read back from a capture dump
+#[derive(Debug, Clone)]
+pub struct DumpRecord {
+    pub kind: DumpRecordKind,
+    pub sample_time: i64,
+    pub sample_duration: i64,
+    /// Decompressed payload (BGRA pixels, PCM bytes or packed window info)
+    pub payload: Vec<u8>,
+}
+
+impl DumpRecord {
+    /// Decode a window info payload into (position, size)
+    pub fn window_info(&self) -> Option<((i32, i32), (u32, u32))> {
+        if self.kind != DumpRecordKind::WindowInfo || self.payload.len() != 16 {
+            return None;
+        }
+        let p = &self.payload;
+        let x = i32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+        let y = i32::from_le_bytes([p[4], p[5], p[6], p[7]]);
+        let w = u32::from_le_bytes([p[8], p[9], p[10], p[11]]);
+        let h = u32::from_le_bytes([p[12], p[13], p[14], p[15]]);
+        Some(((x, y), (w, h)))
+    }
+}
+
+fn io_error(e: std::io::Error) -> windows::core::Error {
+    windows::core::Error::new(E_FAIL, format!("Capture dump I/O error: {}", e).into())
+}
+
+/// Writes raw captured frames and audio packets to disk for offline replay
+pub struct CaptureDumpWriter {
+    file: BufWriter<File>,
+    staging: Option<ID3D11Texture2D>,
+    width: u32,
+    height: u32,
+    records_written: u64,
+}
+
+impl CaptureDumpWriter {
+    /// Create a new dump file, truncating any existing file at the path
+    pub fn create(path: &Path, header: DumpHeader) -> Result<Self> {
+        info!("Creating capture dump at {}", path.display());
+        let file = File::create(path).map_err(io_error)?;
+        let mut file = BufWriter::new(file);
+
+        file.write_all(DUMP_MAGIC).map_err(io_error)?;
+        for value in [
+            header.input_width,
+            header.input_height,
+            header.fps_num,
+            header.fps_den,
+        ] {
+            file.write_all(&value.to_le_bytes()).map_err(io_error)?;
+        }
+
+        Ok(Self {
+            file,
+            staging: None,
+            width: header.input_width,
+            height: header.input_height,
+            records_written: 0,
+        })
+    }
+
+    fn write_record(
+        &mut self,
+        kind: DumpRecordKind,
+        sample_time: i64,
+        sample_duration: i64,
+        payload: &[u8],
+    ) -> Result<()> {
+        self.file.write_all(&[kind as u8]).map_err(io_error)?;
+        self.file
+            .write_all(&sample_time.to_le_bytes())
+            .map_err(io_error)?;
+        self.file
+            .write_all(&sample_duration.to_le_bytes())
+            .map_err(io_error)?;
+        self.file
+            .write_all(&(payload.len() as u32).to_le_bytes())
+            .map_err(io_error)?;
+        self.file.write_all(payload).map_err(io_error)?;
+        self.records_written += 1;
+        Ok(())
+    }
+
+    /// Read back a captured BGRA frame from the GPU and append it to the dump
+    pub unsafe fn write_video(&mut self, device: &ID3D11Device, sample: &IMFSample) -> Result<()> {
+        let sample_time = sample.GetSampleTime()?;
+        let sample_duration = sample.GetSampleDuration().unwrap_or(0);
+
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+        let subresource = dxgi_buffer.GetSubresourceIndex()?;
+
+        if self.staging.is_none() {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: self.width,
+                Height: self.height,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    Quality: 0,
+                },
+                Usage: D3D11_USAGE_STAGING,
+                BindFlags: D3D11_BIND_FLAG(0),
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+            };
+            let mut staging = None;
+            device.CreateTexture2D(&desc, None, Some(&mut staging))?;
+            debug!("Created capture dump staging texture");
+            self.staging = staging;
+        }
+        let staging = self.staging.as_ref().unwrap();
+
+        let context = device.GetImmediateContext()?;
+        context.CopySubresourceRegion(staging, 0, 0, 0, 0, &texture, subresource, None);
+
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        context.Map(staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped))?;
+
+        let row_bytes = self.width as usize * 4;
+        let mut pixels = Vec::with_capacity(row_bytes * self.height as usize);
+        for row in 0..self.height as usize {
+            let src = (mapped.pData as *const u8).add(row * mapped.RowPitch as usize);
+            pixels.extend_from_slice(std::slice::from_raw_parts(src, row_bytes));
+        }
+        context.Unmap(staging, 0);
+
+        let packed = pack_pixels(&pixels);
+        trace!(
+            "Dumping video frame at {} ({} -> {} bytes)",
+            sample_time,
+            pixels.len(),
+            packed.len()
+        );
+        self.write_record(DumpRecordKind::Video, sample_time, sample_duration, &packed)
+    }
+
+    /// Append a PCM audio packet to the dump
+    pub unsafe fn write_audio(&mut self, kind: DumpRecordKind, sample: &IMFSample) -> Result<()> {
+        let sample_time = sample.GetSampleTime()?;
+        let sample_duration = sample.GetSampleDuration().unwrap_or(0);
+
+        let buffer = sample.ConvertToContiguousBuffer()?;
+        let mut data: *mut u8 = std::ptr::null_mut();
+        let mut length: u32 = 0;
+        buffer.Lock(&mut data, None, Some(&mut length))?;
+        let bytes = std::slice::from_raw_parts(data, length as usize).to_vec();
+        buffer.Unlock()?;
+
+        self.write_record(kind, sample_time, sample_duration, &bytes)
+    }
+
+    /// Append a window position/size update to the dump
+    pub fn write_window_info(&mut self, position: (i32, i32), size: (u32, u32)) -> Result<()> {
+        let mut payload = Vec::with_capacity(16);
+        payload.extend_from_slice(&position.0.to_le_bytes());
+        payload.extend_from_slice(&position.1.to_le_bytes());
+        payload.extend_from_slice(&size.0.to_le_bytes());
+        payload.extend_from_slice(&size.1.to_le_bytes());
+        self.write_record(DumpRecordKind::WindowInfo, 0, 0, &payload)
+    }
+
+    /// Flush buffered records to disk
+    pub fn finish(&mut self) -> Result<()> {
+        self.file.flush().map_err(io_error)?;
+        info!(
+            "Capture dump finished with {} records",
+            self.records_written
+        );
+        Ok(())
+    }
+}
+
+/// Reads records back from a capture dump file
+pub struct CaptureDumpReader {
+    file: BufReader<File>,
+    header: DumpHeader,
+}
+
+impl CaptureDumpReader {
+    /// Open a dump file and validate its header
+    pub fn open(path: &Path) -> Result<Self> {
+        info!("Opening capture dump {}", path.display());
+        let file = File::open(path).map_err(io_error)?;
+        let mut file = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic).map_err(io_error)?;
+        if &magic != DUMP_MAGIC {
+            return Err(windows::core::Error::new(
+                E_FAIL,
+                "Not a capture dump file".into(),
+            ));
+        }
+
+        let mut values = [0u32; 4];
+        for value in values.iter_mut() {
+            let mut bytes = [0u8; 4];
+            file.read_exact(&mut bytes).map_err(io_error)?;
+            *value = u32::from_le_bytes(bytes);
+        }
+
+        let header = DumpHeader {
+            input_width: values[0],
+            input_height: values[1],
+            fps_num: values[2],
+            fps_den: values[3],
+        };
+        info!("Capture dump header: {:?}", header);
+
+        Ok(Self { file, header })
+    }
+
+    pub fn header(&self) -> DumpHeader {
+        self.header
+    }
+
+    /// Read the next record, returning None at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<DumpRecord>> {
+        let mut kind = [0u8; 1];
+        match self.file.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(io_error(e)),
+        }
+        let kind = DumpRecordKind::from_u8(kind[0]).ok_or_else(|| {
+            windows::core::Error::new(E_FAIL, "Corrupt capture dump record".into())
+        })?;
+
+        let mut i64_bytes = [0u8; 8];
+        self.file.read_exact(&mut i64_bytes).map_err(io_error)?;
+        let sample_time = i64::from_le_bytes(i64_bytes);
+        self.file.read_exact(&mut i64_bytes).map_err(io_error)?;
+        let sample_duration = i64::from_le_bytes(i64_bytes);
+
+        let mut len_bytes = [0u8; 4];
+        self.file.read_exact(&mut len_bytes).map_err(io_error)?;
+        let mut payload = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+        self.file.read_exact(&mut payload).map_err(io_error)?;
+
+        if kind == DumpRecordKind::Video {
+            let expected = self.header.input_width as usize * self.header.input_height as usize * 4;
+            payload = unpack_pixels(&payload, expected);
+            if payload.len() != expected {
+                warn!(
+                    "Capture dump frame at {} has {} bytes, expected {}",
+                    sample_time,
+                    payload.len(),
+                    expected
+                );
+            }
+        }
+
+        Ok(Some(DumpRecord {
+            kind,
+            sample_time,
+            sample_duration,
+            payload,
+        }))
+    }
+}
+
+/// Compress BGRA pixels with a PackBits variant operating on whole 32-bit pixels
+///
+/// Control byte `n < 128` is followed by `n + 1` literal pixels, `n >= 128` by a single
+/// pixel repeated `n - 126` times.
+pub fn pack_pixels(bytes: &[u8]) -> Vec<u8> {
+    let pixels: Vec<[u8; 4]> = bytes
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+    let mut out = Vec::with_capacity(bytes.len() / 4);
+    let mut i = 0;
+
+    while i < pixels.len() {
+        let mut run = 1;
+        while i + run < pixels.len() && run < 129 && pixels[i + run] == pixels[i] {
+            run += 1;
+        }
+
+        if run >= 2 {
+            out.push((run + 126) as u8);
+            out.extend_from_slice(&pixels[i]);
+            i += run;
+        } else {
+            let start = i;
+            let mut count = 0;
+            while i < pixels.len() && count < 128 {
+                if i + 1 < pixels.len() && pixels[i + 1] == pixels[i] {
+                    break;
+                }
+                i += 1;
+                count += 1;
+            }
+            out.push((count - 1) as u8);
+            for pixel in &pixels[start..start + count] {
+                out.extend_from_slice(pixel);
+            }
+        }
+    }
+
+    out
+}
+
+/// Inverse of [`pack_pixels`]
+pub fn unpack_pixels(packed: &[u8], expected_len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+
+    while i < packed.len() {
+        let control = packed[i] as usize;
+        i += 1;
+        if control < 128 {
+            let len = (control + 1) * 4;
+            let end = (i + len).min(packed.len());
+            out.extend_from_slice(&packed[i..end]);
+            i = end;
+        } else {
+            if i + 4 > packed.len() {
+                break;
+            }
+            let pixel = &packed[i..i + 4];
+            for _ in 0..(control - 126) {
+                out.extend_from_slice(pixel);
+            }
+            i += 4;
+        }
+    }
+
+    out
+}
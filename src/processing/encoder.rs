@@ -0,0 +1,418 @@
+use crate::logging::{debug, info, warn};
+use std::collections::VecDeque;
+use std::time::Duration;
+use windows::core::{ComInterface, Result, GUID};
+use windows::Win32::Foundation::{VARIANT_FALSE, VARIANT_TRUE};
+use windows::Win32::Media::MediaFoundation::*;
+use windows::Win32::System::Com::{VARIANT, VT_BOOL, VT_UI4};
+
+use crate::device::capabilities::VideoProfile;
+use crate::device::VideoEncoderType;
+use crate::types::hns_to_duration;
+
+/// Codec private data (parameter sets) produced by the video encoder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// The encoder type the header belongs to
+    pub encoder_type: VideoEncoderType,
+    /// Raw MF_MT_MPEG_SEQUENCE_HEADER blob as reported by the encoder (Annex-B)
+    pub raw: Vec<u8>,
+    /// Video parameter sets (HEVC only), without start codes
+    pub vps: Vec<Vec<u8>>,
+    /// Sequence parameter sets, without start codes
+    pub sps: Vec<Vec<u8>>,
+    /// Picture parameter sets, without start codes
+    pub pps: Vec<Vec<u8>>,
+}
+
+impl StreamHeader {
+    /// Parse an Annex-B sequence header into its parameter sets
+    pub fn parse(raw: &[u8], encoder_type: VideoEncoderType) -> Self {
+        let mut header = StreamHeader {
+            encoder_type,
+            raw: raw.to_vec(),
+            ..Default::default()
+        };
+
+        for nal in split_annex_b(raw) {
+            if nal.is_empty() {
+                continue;
+            }
+            match encoder_type {
+                VideoEncoderType::H264 => match nal[0] & 0x1f {
+                    7 => header.sps.push(nal.to_vec()),
+                    8 => header.pps.push(nal.to_vec()),
+                    _ => {}
+                },
+                VideoEncoderType::HEVC => match (nal[0] >> 1) & 0x3f {
+                    32 => header.vps.push(nal.to_vec()),
+                    33 => header.sps.push(nal.to_vec()),
+                    34 => header.pps.push(nal.to_vec()),
+                    _ => {}
+                },
+            }
+        }
+
+        header
+    }
+
+    /// Whether the header carries the parameter sets needed to initialize a decoder
+    pub fn is_complete(&self) -> bool {
+        let has_vps = match self.encoder_type {
+            VideoEncoderType::H264 => true,
+            VideoEncoderType::HEVC => !self.vps.is_empty(),
+        };
+        has_vps && !self.sps.is_empty() && !self.pps.is_empty()
+    }
+}
+
+/// Entropy coder of an H.264 stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EntropyCoding {
+    /// Arithmetic coding, smaller files at the same quality; Main and High profiles only
+    Cabac,
+    /// Variable-length coding, cheaper to decode; the only one in the Baseline profile
+    Cavlc,
+}
+
+/// H.264 levels times ten
+const H264_LEVELS: [u32; 19] = [
+    10, 11, 12, 13, 20, 21, 22, 30, 31, 32, 40, 41, 42, 50, 51, 52, 60, 61, 62,
+];
+/// HEVC levels times ten
+const HEVC_LEVELS: [u32; 13] = [10, 20, 21, 30, 31, 40, 41, 50, 51, 52, 60, 61, 62];
+
+/// Profile, level and entropy coding asked of the video encoder, for players and services that
+/// only decode some of them (e.g. older hardware decoders or WebRTC); None leaves the choice to
+/// the encoder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct EncoderSettings {
+    /// Baseline, Main or High for H.264; Main or Main10 for HEVC
+    pub profile: Option<VideoProfile>,
+    /// Level times ten, e.g. `41` for level 4.1, as in `EncoderCapabilities::levels`
+    pub level: Option<u32>,
+    /// H.264 only
+    pub entropy_coding: Option<EntropyCoding>,
+}
+
+impl EncoderSettings {
+    /// Why these settings cannot be encoded as `encoder_type`, if they cannot
+    pub(crate) fn check(&self, encoder_type: VideoEncoderType) -> std::result::Result<(), String> {
+        if let Some(profile) = self.profile {
+            if !VideoProfile::candidates(encoder_type).contains(&profile) {
+                return Err(format!(
+                    "{:?} is not a profile of {:?}",
+                    profile, encoder_type
+                ));
+            }
+        }
+        if let Some(level) = self.level {
+            let levels: &[u32] = match encoder_type {
+                VideoEncoderType::H264 => &H264_LEVELS,
+                VideoEncoderType::HEVC => &HEVC_LEVELS,
+            };
+            if !levels.contains(&level) {
+                return Err(format!(
+                    "{:?} has no level {}.{}, use one of {:?} (level times ten)",
+                    encoder_type,
+                    level / 10,
+                    level % 10,
+                    levels
+                ));
+            }
+        }
+        match (self.entropy_coding, encoder_type, self.profile) {
+            (Some(_), VideoEncoderType::HEVC, _) => {
+                Err("entropy coding can only be chosen for H.264".to_string())
+            }
+            (Some(EntropyCoding::Cabac), _, Some(VideoProfile::Baseline)) => {
+                Err("the Baseline profile has no CABAC, use CAVLC".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Value of `MF_MT_VIDEO_LEVEL` for a level times ten
+pub(crate) fn level_value(level: u32, encoder_type: VideoEncoderType) -> u32 {
+    match encoder_type {
+        VideoEncoderType::H264 => level,
+        // HEVC levels are stored as level times thirty
+        VideoEncoderType::HEVC => level * 3,
+    }
+}
+
+/// Running totals of the video encoder, as reported by the sink writer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderStats {
+    /// Frames handed to the encoder
+    pub frames_received: u64,
+    /// Frames the encoder has output
+    pub frames_encoded: u64,
+    /// Encoded bytes written to the file
+    pub bytes_written: u64,
+    /// Timestamp of the last encoded frame, i.e. how much video the byte count covers
+    pub encoded_duration: Duration,
+}
+
+impl EncoderStats {
+    /// Average bitrate actually produced so far, in bits per second
+    pub fn effective_bitrate(&self) -> u64 {
+        match self.encoded_duration.as_micros() {
+            0 => 0,
+            micros => (self.bytes_written as u128 * 8 * 1_000_000 / micros) as u64,
+        }
+    }
+}
+
+/// Media time the produced bitrate is averaged over
+const BITRATE_WINDOW: Duration = Duration::from_secs(10);
+/// Produced bitrate above `target * BITRATE_OVER_RATIO` is reported as over budget
+const BITRATE_OVER_RATIO: f64 = 1.5;
+/// Produced bitrate below `target * BITRATE_UNDER_RATIO` is reported as under budget
+const BITRATE_UNDER_RATIO: f64 = 0.5;
+
+/// Direction in which the produced bitrate left the configured target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum BitrateDeviation {
+    /// More data than budgeted, e.g. the disk may not keep up
+    Over,
+    /// Much less data than budgeted, e.g. the encoder is ignoring the rate control settings
+    Under,
+}
+
+/// Compares the bitrate the encoder produces over a sliding window with the target
+pub struct BitrateMonitor {
+    target: u64,
+    /// (media time, bytes written) samples covering the window
+    samples: VecDeque<(Duration, u64)>,
+    deviation: Option<BitrateDeviation>,
+}
+
+impl BitrateMonitor {
+    pub fn new(target_bps: u32) -> Self {
+        Self {
+            target: target_bps as u64,
+            samples: VecDeque::new(),
+            deviation: None,
+        }
+    }
+
+    /// Feed the latest encoder totals
+    ///
+    /// Returns the deviation and the measured bitrate when the bitrate leaves the tolerated
+    /// range. Nothing is reported again until it has come back into range.
+    pub fn update(&mut self, stats: &EncoderStats) -> Option<(BitrateDeviation, u64)> {
+        let now = (stats.encoded_duration, stats.bytes_written);
+        if self.samples.back().is_some_and(|&(time, _)| time >= now.0) {
+            return None;
+        }
+        self.samples.push_back(now);
+        while self.samples.len() > 2 && now.0 - self.samples[1].0 >= BITRATE_WINDOW {
+            self.samples.pop_front();
+        }
+
+        // Judge only over a full window so the first keyframe does not skew the result
+        let &(start_time, start_bytes) = self.samples.front()?;
+        let span = now.0 - start_time;
+        if span < BITRATE_WINDOW {
+            return None;
+        }
+        let measured = ((now.1 - start_bytes) as f64 * 8.0 / span.as_secs_f64()) as u64;
+
+        let deviation = if measured as f64 > self.target as f64 * BITRATE_OVER_RATIO {
+            Some(BitrateDeviation::Over)
+        } else if (measured as f64) < self.target as f64 * BITRATE_UNDER_RATIO {
+            Some(BitrateDeviation::Under)
+        } else {
+            None
+        };
+        if deviation == self.deviation {
+            return None;
+        }
+        self.deviation = deviation;
+        deviation.map(|deviation| (deviation, measured))
+    }
+}
+
+/// Read the encoder counters of a sink writer stream
+pub unsafe fn get_encoder_stats(sink_writer: &IMFSinkWriter, stream_index: u32) -> Result<EncoderStats> {
+    let mut stats = MF_SINK_WRITER_STATISTICS {
+        cb: std::mem::size_of::<MF_SINK_WRITER_STATISTICS>() as u32,
+        ..Default::default()
+    };
+    sink_writer.GetStatistics(stream_index, &mut stats)?;
+
+    Ok(EncoderStats {
+        frames_received: stats.qwNumSamplesReceived,
+        frames_encoded: stats.qwNumSamplesEncoded,
+        bytes_written: stats.qwByteCountProcessed,
+        encoded_duration: hns_to_duration(stats.llLastTimestampEncoded.max(0)),
+    })
+}
+
+/// Split an Annex-B byte stream into NAL units (start codes removed)
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut i = 0;
+
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                // Trailing zero belongs to a 4-byte start code of the next NAL
+                let mut end = i;
+                while end > s && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                nals.push(&data[s..end]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    if let Some(s) = start {
+        if s < data.len() {
+            nals.push(&data[s..]);
+        }
+    }
+
+    nals
+}
+
+/// Retrieves the sequence header from the encoder MFT that the sink writer inserted for a stream
+///
+/// Returns `Ok(None)` when the encoder has not published a header (yet). The sink writer only
+/// instantiates its encoder after `SetInputMediaType`, so this must be called after setup.
+pub unsafe fn get_video_stream_header(
+    sink_writer: &IMFSinkWriter,
+    stream_index: u32,
+    encoder_type: VideoEncoderType,
+) -> Result<Option<StreamHeader>> {
+    match find_encoder_transform(sink_writer, stream_index)? {
+        Some(transform) => {
+            let output_type = transform.GetOutputCurrentType(0)?;
+            read_sequence_header(&output_type, encoder_type)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Ask the encoder the sink writer inserted for a stream to make the next frame a keyframe
+pub unsafe fn force_keyframe(sink_writer: &IMFSinkWriter, stream_index: u32) -> Result<()> {
+    let Some(transform) = find_encoder_transform(sink_writer, stream_index)? else {
+        return Err(windows::core::Error::new(
+            MF_E_NOT_FOUND,
+            "No encoder transform found".into(),
+        ));
+    };
+    let codec_api: ICodecAPI = transform.cast()?;
+    let mut value = VARIANT::default();
+    (*value.Anonymous.Anonymous).vt = VT_UI4;
+    (*value.Anonymous.Anonymous).Anonymous.ulVal = 1;
+    codec_api.SetValue(&CODECAPI_AVEncVideoForceKeyFrame, &value)
+}
+
+/// Switch the encoder the sink writer inserted for a stream between CABAC and CAVLC
+///
+/// The encoder only exists once the stream's input type is set, and must be told before the
+/// first frame.
+pub unsafe fn set_entropy_coding(
+    sink_writer: &IMFSinkWriter,
+    stream_index: u32,
+    coding: EntropyCoding,
+) -> Result<()> {
+    let Some(transform) = find_encoder_transform(sink_writer, stream_index)? else {
+        return Err(windows::core::Error::new(
+            MF_E_NOT_FOUND,
+            "No encoder transform found".into(),
+        ));
+    };
+    let codec_api: ICodecAPI = transform.cast()?;
+    let mut value = VARIANT::default();
+    (*value.Anonymous.Anonymous).vt = VT_BOOL;
+    (*value.Anonymous.Anonymous).Anonymous.boolVal = match coding {
+        EntropyCoding::Cabac => VARIANT_TRUE,
+        EntropyCoding::Cavlc => VARIANT_FALSE,
+    };
+    info!(
+        "set_entropy_coding - Setting {:?} on stream {}",
+        coding, stream_index
+    );
+    codec_api.SetValue(&CODECAPI_AVEncH264CABACEnable, &value)
+}
+
+/// Finds the encoder MFT in the transform chain of a sink writer stream
+unsafe fn find_encoder_transform(
+    sink_writer: &IMFSinkWriter,
+    stream_index: u32,
+) -> Result<Option<IMFTransform>> {
+    let sink_writer_ex: IMFSinkWriterEx = sink_writer.cast()?;
+
+    let mut transform_index = 0;
+    loop {
+        let mut category = GUID::zeroed();
+        let mut transform: Option<IMFTransform> = None;
+        if let Err(e) = sink_writer_ex.GetTransformForStream(
+            stream_index,
+            transform_index,
+            Some(&mut category),
+            &mut transform,
+        ) {
+            debug!(
+                "find_encoder_transform - No transform at index {}: {:?}",
+                transform_index, e
+            );
+            break;
+        }
+
+        if category == MFT_CATEGORY_VIDEO_ENCODER {
+            if let Some(transform) = transform {
+                debug!(
+                    "find_encoder_transform - Found encoder transform at index {}",
+                    transform_index
+                );
+                return Ok(Some(transform));
+            }
+        }
+
+        transform_index += 1;
+    }
+
+    warn!(
+        "find_encoder_transform - No encoder transform found for stream {}",
+        stream_index
+    );
+    Ok(None)
+}
+
+/// Reads MF_MT_MPEG_SEQUENCE_HEADER from a media type, if present
+pub unsafe fn read_sequence_header(
+    media_type: &IMFMediaType,
+    encoder_type: VideoEncoderType,
+) -> Result<Option<StreamHeader>> {
+    let size = match media_type.GetBlobSize(&MF_MT_MPEG_SEQUENCE_HEADER) {
+        Ok(size) => size,
+        Err(e) if e.code() == MF_E_ATTRIBUTENOTFOUND => {
+            debug!("read_sequence_header - Media type has no sequence header");
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut raw = vec![0u8; size as usize];
+    media_type.GetBlob(&MF_MT_MPEG_SEQUENCE_HEADER, &mut raw, None)?;
+    info!("read_sequence_header - Read {} byte sequence header", size);
+
+    Ok(Some(StreamHeader::parse(&raw, encoder_type)))
+}
@@ -0,0 +1,601 @@
+use crate::logging::{debug, info};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::{ComInterface, Error, Result};
+use windows::Win32::Graphics::Direct3D::D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT, DXGI_FORMAT_R32G32B32A32_FLOAT};
+use windows::Win32::Media::MediaFoundation::IMFSample;
+
+use super::scaler::{blob_bytes, compile_shader, create_render_target, RenderTarget};
+use super::video::sample_texture;
+use crate::error::RecorderError;
+use crate::types::hns_to_duration;
+
+/// Declarations every filter shader starts with, see [`ShaderFilter`]
+const FILTER_HLSL_PRELUDE: &str = r#"
+cbuffer FilterParams : register(b0)
+{
+    float2 size;   // frame size in pixels
+    float time;    // seconds since the recording started
+    float padding;
+    float4 params; // filter specific
+};
+
+Texture2D<float4> src : register(t0);
+SamplerState linear_sampler : register(s0);
+
+struct VsOut
+{
+    float4 pos : SV_Position;
+    float2 uv : TEXCOORD0;
+};
+
+VsOut vs_main(uint id : SV_VertexID)
+{
+    VsOut o;
+    o.uv = float2((id << 1) & 2, id & 2);
+    o.pos = float4(o.uv * float2(2, -2) + float2(-1, 1), 0, 1);
+    return o;
+}
+"#;
+
+const CROP_HLSL: &str = r#"
+float4 ps_crop(VsOut i) : SV_Target
+{
+    return src.SampleLevel(linear_sampler, (params.xy + i.uv * params.zw) / size, 0);
+}
+"#;
+
+const SHARPEN_HLSL: &str = r#"
+float4 ps_sharpen(VsOut i) : SV_Target
+{
+    float2 texel = 1 / size;
+    float4 c = src.SampleLevel(linear_sampler, i.uv, 0);
+    float3 blur = (src.SampleLevel(linear_sampler, i.uv + float2(texel.x, 0), 0).rgb
+        + src.SampleLevel(linear_sampler, i.uv - float2(texel.x, 0), 0).rgb
+        + src.SampleLevel(linear_sampler, i.uv + float2(0, texel.y), 0).rgb
+        + src.SampleLevel(linear_sampler, i.uv - float2(0, texel.y), 0).rgb) / 4;
+    return float4(saturate(c.rgb + (c.rgb - blur) * params.x), c.a);
+}
+"#;
+
+const LUT_HLSL: &str = r#"
+Texture3D<float4> lut : register(t1);
+
+float4 ps_lut(VsOut i) : SV_Target
+{
+    float4 c = src.SampleLevel(linear_sampler, i.uv, 0);
+    // Sample the texel centres so the first and last LUT entries map to 0 and 1
+    float3 coord = (saturate(c.rgb) * (params.x - 1) + 0.5) / params.x;
+    float3 graded = lut.SampleLevel(linear_sampler, coord, 0).rgb;
+    return float4(lerp(c.rgb, graded, params.y), c.a);
+}
+"#;
+
+/// A frame going through a [`VideoFilter`]
+///
+/// Both textures have the size and format of the captured frame (normally
+/// `DXGI_FORMAT_B8G8R8A8_UNORM`) and live on `device`. The filter must write every pixel of
+/// `output`; its previous contents are undefined.
+pub struct FilterFrame<'a> {
+    pub device: &'a ID3D11Device,
+    pub context: &'a ID3D11DeviceContext,
+    /// The captured frame, or the previous filter's output
+    pub input: &'a ID3D11ShaderResourceView,
+    pub input_texture: &'a ID3D11Texture2D,
+    pub output: &'a ID3D11RenderTargetView,
+    pub output_texture: &'a ID3D11Texture2D,
+    pub width: u32,
+    pub height: u32,
+    /// Position of the frame in the recording
+    pub timestamp: Duration,
+}
+
+/// A GPU stage applied to every captured frame before it is scaled and encoded
+///
+/// Filters run in order on the processing thread with the device's multithread lock held, each
+/// reading the previous one's output. A failing filter leaves that frame unfiltered.
+pub trait VideoFilter: Send {
+    /// Name used in log messages
+    fn name(&self) -> &str;
+
+    /// Render `frame.input` into `frame.output`
+    fn apply(&mut self, frame: &FilterFrame<'_>) -> Result<()>;
+}
+
+/// The filters set with `RecorderConfigBuilder::video_filters`, shared by the config's copies
+#[derive(Clone, Default)]
+pub(crate) struct VideoFilters(Arc<Mutex<Vec<Box<dyn VideoFilter>>>>);
+
+impl VideoFilters {
+    pub fn new(filters: Vec<Box<dyn VideoFilter>>) -> Self {
+        Self(Arc::new(Mutex::new(filters)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+/// Ping-pong targets the filters render into, and what they were created for
+struct FilterTargets {
+    device: ID3D11Device,
+    /// Width, height and format
+    size: (u32, u32, DXGI_FORMAT),
+    targets: [RenderTarget; 2],
+}
+
+/// Runs the video filters over each captured frame, writing the result back into it
+pub(crate) struct VideoFilterChain {
+    filters: VideoFilters,
+    targets: Option<FilterTargets>,
+}
+
+// Created without targets and only used on the processing thread it is moved to
+unsafe impl Send for VideoFilterChain {}
+
+impl VideoFilterChain {
+    pub fn new(filters: VideoFilters) -> Self {
+        let names: Vec<String> = filters
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.name().to_string())
+            .collect();
+        info!("Video filters: {}", names.join(", "));
+        Self {
+            filters,
+            targets: None,
+        }
+    }
+
+    /// Filter the captured frame in `sample` at `timestamp` (100ns units from the start of the
+    /// recording)
+    pub unsafe fn apply(&mut self, sample: &IMFSample, timestamp: i64) -> Result<()> {
+        let mut filters = self.filters.0.lock().unwrap();
+        if filters.is_empty() {
+            return Ok(());
+        }
+        let texture = sample_texture(sample)?;
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        let device = texture.GetDevice()?;
+        let size = (desc.Width, desc.Height, desc.Format);
+        if !matches!(&self.targets, Some(t) if t.device == device && t.size == size) {
+            debug!(
+                "Creating {}x{} {:?} video filter targets",
+                desc.Width, desc.Height, desc.Format
+            );
+            self.targets = Some(FilterTargets {
+                device: device.clone(),
+                size,
+                targets: [
+                    create_render_target(&device, desc.Width, desc.Height, desc.Format)?,
+                    create_render_target(&device, desc.Width, desc.Height, desc.Format)?,
+                ],
+            });
+        }
+        let targets = &self.targets.as_ref().unwrap().targets;
+        let context = device.GetImmediateContext()?;
+        let mut input_srv = None;
+        device.CreateShaderResourceView(&texture, None, Some(&mut input_srv))?;
+        let input_srv = input_srv.unwrap();
+
+        // Keep the filters' pipeline state atomic with respect to other threads
+        let multithread: ID3D11Multithread = device.cast()?;
+        multithread.Enter();
+        let mut result = Ok(());
+        for (i, filter) in filters.iter_mut().enumerate() {
+            let (input, input_texture) = match i {
+                0 => (&input_srv, &texture),
+                _ => (&targets[(i - 1) % 2].srv, &targets[(i - 1) % 2].texture),
+            };
+            let output = &targets[i % 2];
+            // Unbind the previous output before it is read and vice versa
+            unbind(&context);
+            let frame = FilterFrame {
+                device: &device,
+                context: &context,
+                input,
+                input_texture,
+                output: &output.rtv,
+                output_texture: &output.texture,
+                width: desc.Width,
+                height: desc.Height,
+                timestamp: hns_to_duration(timestamp),
+            };
+            if let Err(e) = filter.apply(&frame) {
+                result = Err(Error::new(
+                    e.code(),
+                    format!("{}: {}", filter.name(), e.message()).into(),
+                ));
+                break;
+            }
+        }
+        unbind(&context);
+        if result.is_ok() {
+            context.CopyResource(&texture, &targets[(filters.len() - 1) % 2].texture);
+        }
+        multithread.Leave();
+        result
+    }
+}
+
+unsafe fn unbind(context: &ID3D11DeviceContext) {
+    context.PSSetShaderResources(0, Some(&[None, None]));
+    context.OMSetRenderTargets(None, None);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FilterParams {
+    size: [f32; 2],
+    time: f32,
+    padding: f32,
+    params: [f32; 4],
+}
+
+/// Shaders and state of a full-frame pass, compiled for one device
+struct CompiledPass {
+    vertex_shader: ID3D11VertexShader,
+    pixel_shader: ID3D11PixelShader,
+    sampler: ID3D11SamplerState,
+    constants: ID3D11Buffer,
+}
+
+/// A pixel shader drawn over the whole frame, compiled on first use and again for a new device
+struct ShaderPass {
+    hlsl: String,
+    entry_point: String,
+    compiled: Option<(ID3D11Device, Result<CompiledPass>)>,
+}
+
+// D3D11 devices and the shaders and buffers created on them are free-threaded
+unsafe impl Send for ShaderPass {}
+
+impl ShaderPass {
+    fn new(source: &str, entry_point: &str) -> Self {
+        Self {
+            hlsl: format!("{}{}", FILTER_HLSL_PRELUDE, source),
+            entry_point: entry_point.to_string(),
+            compiled: None,
+        }
+    }
+
+    /// Draw the pass from `frame.input` into `frame.output`, with `resource` bound at t1
+    unsafe fn draw(
+        &mut self,
+        frame: &FilterFrame<'_>,
+        params: [f32; 4],
+        resource: Option<ID3D11ShaderResourceView>,
+    ) -> Result<()> {
+        if !matches!(&self.compiled, Some((device, _)) if device == frame.device) {
+            // A failed compile is kept, so a broken shader is not recompiled for every frame
+            let compiled = self.compile(frame.device);
+            self.compiled = Some((frame.device.clone(), compiled));
+        }
+        let pass = match &self.compiled {
+            Some((_, Ok(pass))) => pass,
+            Some((_, Err(e))) => return Err(e.clone()),
+            None => unreachable!(),
+        };
+
+        let constants = FilterParams {
+            size: [frame.width as f32, frame.height as f32],
+            time: frame.timestamp.as_secs_f32(),
+            padding: 0.0,
+            params,
+        };
+        let context = frame.context;
+        context.UpdateSubresource(
+            &pass.constants,
+            0,
+            None,
+            &constants as *const _ as *const _,
+            0,
+            0,
+        );
+        context.IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        context.IASetInputLayout(None);
+        context.VSSetShader(&pass.vertex_shader, None);
+        context.PSSetShader(&pass.pixel_shader, None);
+        context.PSSetSamplers(0, Some(&[Some(pass.sampler.clone())]));
+        context.PSSetConstantBuffers(0, Some(&[Some(pass.constants.clone())]));
+        context.OMSetRenderTargets(Some(&[Some(frame.output.clone())]), None);
+        context.PSSetShaderResources(0, Some(&[Some(frame.input.clone()), resource]));
+        context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+            TopLeftX: 0.0,
+            TopLeftY: 0.0,
+            Width: frame.width as f32,
+            Height: frame.height as f32,
+            MinDepth: 0.0,
+            MaxDepth: 1.0,
+        }]));
+        context.Draw(3, 0);
+        Ok(())
+    }
+
+    unsafe fn compile(&self, device: &ID3D11Device) -> Result<CompiledPass> {
+        info!("Compiling video filter shader {}", self.entry_point);
+        let vs_blob = compile_shader(&self.hlsl, "vs_main", "vs_5_0")?;
+        let mut vertex_shader = None;
+        device.CreateVertexShader(blob_bytes(&vs_blob), None, Some(&mut vertex_shader))?;
+        let ps_blob = compile_shader(&self.hlsl, &self.entry_point, "ps_5_0")?;
+        let mut pixel_shader = None;
+        device.CreatePixelShader(blob_bytes(&ps_blob), None, Some(&mut pixel_shader))?;
+
+        let sampler_desc = D3D11_SAMPLER_DESC {
+            Filter: D3D11_FILTER_MIN_MAG_MIP_LINEAR,
+            AddressU: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressV: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressW: D3D11_TEXTURE_ADDRESS_CLAMP,
+            MaxLOD: f32::MAX,
+            ..Default::default()
+        };
+        let mut sampler = None;
+        device.CreateSamplerState(&sampler_desc, Some(&mut sampler))?;
+
+        let buffer_desc = D3D11_BUFFER_DESC {
+            ByteWidth: std::mem::size_of::<FilterParams>() as u32,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+            ..Default::default()
+        };
+        let mut constants = None;
+        device.CreateBuffer(&buffer_desc, None, Some(&mut constants))?;
+
+        Ok(CompiledPass {
+            vertex_shader: vertex_shader.unwrap(),
+            pixel_shader: pixel_shader.unwrap(),
+            sampler: sampler.unwrap(),
+            constants: constants.unwrap(),
+        })
+    }
+}
+
+/// Crops the frame to a rectangle in captured pixels, stretched back to the full frame
+pub struct CropFilter {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    pass: ShaderPass,
+}
+
+impl CropFilter {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+            pass: ShaderPass::new(CROP_HLSL, "ps_crop"),
+        }
+    }
+}
+
+impl VideoFilter for CropFilter {
+    fn name(&self) -> &str {
+        "crop"
+    }
+
+    fn apply(&mut self, frame: &FilterFrame<'_>) -> Result<()> {
+        // Keep the rectangle inside frames smaller than it was set up for
+        let x = self.x.min(frame.width.saturating_sub(1));
+        let y = self.y.min(frame.height.saturating_sub(1));
+        let width = self.width.clamp(1, frame.width - x);
+        let height = self.height.clamp(1, frame.height - y);
+        let rect = [x as f32, y as f32, width as f32, height as f32];
+        unsafe { self.pass.draw(frame, rect, None) }
+    }
+}
+
+/// Sharpens the frame with an unsharp mask
+pub struct SharpenFilter {
+    amount: f32,
+    pass: ShaderPass,
+}
+
+impl SharpenFilter {
+    /// `amount` scales the added detail, 0.5 is subtle and 2.0 strong
+    pub fn new(amount: f32) -> Self {
+        Self {
+            amount,
+            pass: ShaderPass::new(SHARPEN_HLSL, "ps_sharpen"),
+        }
+    }
+}
+
+impl VideoFilter for SharpenFilter {
+    fn name(&self) -> &str {
+        "sharpen"
+    }
+
+    fn apply(&mut self, frame: &FilterFrame<'_>) -> Result<()> {
+        unsafe { self.pass.draw(frame, [self.amount, 0.0, 0.0, 0.0], None) }
+    }
+}
+
+/// Grades the frame with a 3D color lookup table
+pub struct ColorLutFilter {
+    /// Entries per axis
+    size: u32,
+    /// RGBA entries, red varying fastest and blue slowest
+    table: Vec<f32>,
+    strength: f32,
+    /// The table as a texture, and the device it was created on
+    texture: Option<(ID3D11Device, ID3D11ShaderResourceView)>,
+    pass: ShaderPass,
+}
+
+// D3D11 devices and the textures created on them are free-threaded
+unsafe impl Send for ColorLutFilter {}
+
+impl ColorLutFilter {
+    /// Load a `.cube` LUT file as exported by DaVinci Resolve, Photoshop and most grading tools
+    pub fn from_cube_file(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            RecorderError::Generic(format!("Failed to read LUT file {}: {}", path.display(), e))
+        })?;
+        let (size, table) = parse_cube(&text).map_err(|e| {
+            RecorderError::Generic(format!("Invalid LUT file {}: {}", path.display(), e))
+        })?;
+        Ok(Self {
+            size,
+            table,
+            strength: 1.0,
+            texture: None,
+            pass: ShaderPass::new(LUT_HLSL, "ps_lut"),
+        })
+    }
+
+    /// Blend between the original (0.0) and the graded frame (1.0, the default)
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength.clamp(0.0, 1.0);
+        self
+    }
+
+    unsafe fn create_texture(&self, device: &ID3D11Device) -> Result<ID3D11ShaderResourceView> {
+        let desc = D3D11_TEXTURE3D_DESC {
+            Width: self.size,
+            Height: self.size,
+            Depth: self.size,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_R32G32B32A32_FLOAT,
+            Usage: D3D11_USAGE_IMMUTABLE,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+        };
+        let row_pitch = self.size * 16;
+        let data = D3D11_SUBRESOURCE_DATA {
+            pSysMem: self.table.as_ptr() as *const _,
+            SysMemPitch: row_pitch,
+            SysMemSlicePitch: row_pitch * self.size,
+        };
+        let mut texture = None;
+        device.CreateTexture3D(&desc, Some(&data), Some(&mut texture))?;
+        let mut srv = None;
+        device.CreateShaderResourceView(&texture.unwrap(), None, Some(&mut srv))?;
+        Ok(srv.unwrap())
+    }
+}
+
+impl VideoFilter for ColorLutFilter {
+    fn name(&self) -> &str {
+        "color LUT"
+    }
+
+    fn apply(&mut self, frame: &FilterFrame<'_>) -> Result<()> {
+        unsafe {
+            if !matches!(&self.texture, Some((device, _)) if device == frame.device) {
+                let srv = self.create_texture(frame.device)?;
+                self.texture = Some((frame.device.clone(), srv));
+            }
+            let lut = self.texture.as_ref().map(|(_, srv)| srv.clone());
+            let params = [self.size as f32, self.strength, 0.0, 0.0];
+            self.pass.draw(frame, params, lut)
+        }
+    }
+}
+
+/// Parse a `.cube` 3D LUT into its size and RGBA table
+fn parse_cube(text: &str) -> std::result::Result<(u32, Vec<f32>), String> {
+    let mut size = None;
+    let mut table = Vec::new();
+    for line in text.lines().map(str::trim) {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            None => continue,
+            Some(keyword) if keyword.starts_with('#') || keyword == "TITLE" => continue,
+            Some("LUT_3D_SIZE") => {
+                let value = fields.next().and_then(|v| v.parse::<u32>().ok());
+                match value {
+                    Some(n) if (2..=256).contains(&n) => size = Some(n),
+                    _ => return Err(format!("bad LUT_3D_SIZE line '{}'", line)),
+                }
+            }
+            Some("LUT_1D_SIZE") => return Err("1D LUTs are not supported".to_string()),
+            Some(keyword @ ("DOMAIN_MIN" | "DOMAIN_MAX")) => {
+                let expected = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                if fields.any(|v| v.parse::<f32>() != Ok(expected)) {
+                    return Err(format!("only a 0 to 1 domain is supported, got '{}'", line));
+                }
+            }
+            Some(first) => {
+                let entry = std::iter::once(first)
+                    .chain(fields)
+                    .map(|v| v.parse::<f32>())
+                    .collect::<std::result::Result<Vec<_>, _>>();
+                match entry {
+                    Ok(rgb) if rgb.len() == 3 => table.extend([rgb[0], rgb[1], rgb[2], 1.0]),
+                    _ => return Err(format!("unexpected line '{}'", line)),
+                }
+            }
+        }
+    }
+    let size = size.ok_or("missing LUT_3D_SIZE")?;
+    let expected = (size * size * size) as usize;
+    if table.len() / 4 != expected {
+        return Err(format!(
+            "expected {} entries for size {}, found {}",
+            expected,
+            size,
+            table.len() / 4
+        ));
+    }
+    Ok((size, table))
+}
+
+/// A user-written HLSL pixel shader
+///
+/// The source is appended to declarations of the `FilterParams` constant buffer at `b0` (`size`
+/// of the frame in pixels, `time` in seconds and the four `params`), the input frame as
+/// `Texture2D<float4> src` at `t0`, a bilinear clamping `linear_sampler` at `s0` and the
+/// `VsOut { float4 pos : SV_Position; float2 uv : TEXCOORD0; }` input of the full-frame
+/// triangle. The entry point takes a `VsOut` and returns the output color:
+///
+/// ```hlsl
+/// float4 ps_invert(VsOut i) : SV_Target
+/// {
+///     float4 c = src.SampleLevel(linear_sampler, i.uv, 0);
+///     return float4(1 - c.rgb, c.a);
+/// }
+/// ```
+///
+/// Shaders are compiled on the first frame; compile errors are logged and leave the frames
+/// unfiltered.
+pub struct ShaderFilter {
+    name: String,
+    params: [f32; 4],
+    pass: ShaderPass,
+}
+
+impl ShaderFilter {
+    pub fn new(hlsl: &str, entry_point: &str) -> Self {
+        Self {
+            name: entry_point.to_string(),
+            params: [0.0; 4],
+            pass: ShaderPass::new(hlsl, entry_point),
+        }
+    }
+
+    /// Values of `params` in the shader, all 0 by default
+    pub fn with_params(mut self, params: [f32; 4]) -> Self {
+        self.params = params;
+        self
+    }
+}
+
+impl VideoFilter for ShaderFilter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&mut self, frame: &FilterFrame<'_>) -> Result<()> {
+        unsafe { self.pass.draw(frame, self.params, None) }
+    }
+}
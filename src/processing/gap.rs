@@ -0,0 +1,131 @@
+use crate::logging::{debug, info};
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::{IMFSample, MFCreateMemoryBuffer, MFCreateSample};
+
+/// Gaps shorter than this are left alone, in 100ns units
+const MIN_GAP_HNS: i64 = 200_000;
+/// Longest silence sample inserted at once, in 100ns units
+const MAX_SILENCE_CHUNK_HNS: i64 = 1_000_000;
+/// How long the audio may fall behind real time before the stall is filled, in 100ns units
+const STALL_THRESHOLD_HNS: i64 = 3_000_000;
+/// Distance kept from real time when filling a stall, so packets still in flight are not
+/// overwritten, in 100ns units
+const STALL_MARGIN_HNS: i64 = 1_000_000;
+
+/// Keeps the written audio timeline continuous
+///
+/// Capture delivers nothing while a source stalls or is silent, which leaves holes in the AAC
+/// stream that desync it from the video. Holes are filled with silence timestamped to follow the
+/// audio written so far, and packets arriving after their time was filled are trimmed.
+pub(crate) struct AudioGapFiller {
+    sample_rate: u32,
+    block_align: u32,
+    /// End of the audio written so far, in 100ns units
+    written_end: Option<i64>,
+    /// Silence inserted so far, in 100ns units
+    inserted_hns: i64,
+}
+
+impl AudioGapFiller {
+    pub fn new(sample_rate: u32, channels: u16, bits_per_sample: u16) -> Self {
+        Self {
+            sample_rate,
+            block_align: channels as u32 * bits_per_sample as u32 / 8,
+            written_end: None,
+            inserted_hns: 0,
+        }
+    }
+
+    /// Silence to write before `sample`, followed by the sample itself
+    ///
+    /// A sample that starts inside audio already written is moved to follow it; one that lies
+    /// entirely inside is dropped.
+    pub unsafe fn before_sample(&mut self, sample: &IMFSample) -> Result<Vec<IMFSample>> {
+        let time = sample.GetSampleTime()?;
+        let duration = self.duration_of(sample)?;
+        let mut output = self.fill_to(time)?;
+
+        match self.written_end {
+            Some(end) if time + duration <= end => {
+                debug!("Dropping audio packet at {} hns, its time was already filled", time);
+                return Ok(output);
+            }
+            Some(end) if time < end => {
+                debug!("Moving audio packet from {} to {} hns", time, end);
+                sample.SetSampleTime(end)?;
+                self.written_end = Some(end + duration);
+            }
+            _ => self.written_end = Some(time + duration),
+        }
+        output.push(sample.clone());
+        Ok(output)
+    }
+
+    /// Silence covering a stall, if the written audio has fallen too far behind `now_hns`
+    pub unsafe fn fill_stall(&mut self, now_hns: i64) -> Result<Vec<IMFSample>> {
+        let end = self.written_end.unwrap_or(0);
+        if now_hns - end < STALL_THRESHOLD_HNS {
+            return Ok(Vec::new());
+        }
+        if self.written_end.is_none() {
+            self.written_end = Some(0);
+        }
+        self.fill_to(now_hns - STALL_MARGIN_HNS)
+    }
+
+    /// Silence inserted since the recording started, in 100ns units
+    pub fn inserted_hns(&self) -> i64 {
+        self.inserted_hns
+    }
+
+    unsafe fn fill_to(&mut self, time_hns: i64) -> Result<Vec<IMFSample>> {
+        let mut output = Vec::new();
+        let Some(mut end) = self.written_end else {
+            return Ok(output);
+        };
+        if time_hns - end < MIN_GAP_HNS {
+            return Ok(output);
+        }
+
+        info!(
+            "Filling {:.1} ms audio gap at {:.3} s with silence",
+            (time_hns - end) as f64 / 10_000.0,
+            end as f64 / 10_000_000.0
+        );
+        while end < time_hns {
+            let duration = (time_hns - end).min(MAX_SILENCE_CHUNK_HNS);
+            let sample = self.silence(end, duration)?;
+            let written = self.duration_of(&sample)?;
+            output.push(sample);
+            end += written;
+            self.inserted_hns += written;
+        }
+        self.written_end = Some(end);
+        Ok(output)
+    }
+
+    // Duration of the PCM in a sample, computed from its size since capture durations are
+    // not reliable
+    unsafe fn duration_of(&self, sample: &IMFSample) -> Result<i64> {
+        let frames = sample.GetTotalLength()? as i64 / self.block_align.max(1) as i64;
+        Ok(frames * 10_000_000 / self.sample_rate as i64)
+    }
+
+    unsafe fn silence(&self, time_hns: i64, duration_hns: i64) -> Result<IMFSample> {
+        let frames = (duration_hns * self.sample_rate as i64 / 10_000_000).max(1) as u32;
+        let size = frames * self.block_align;
+
+        let buffer = MFCreateMemoryBuffer(size)?;
+        let mut data: *mut u8 = std::ptr::null_mut();
+        buffer.Lock(&mut data, None, None)?;
+        std::ptr::write_bytes(data, 0, size as usize);
+        buffer.SetCurrentLength(size)?;
+        buffer.Unlock()?;
+
+        let sample = MFCreateSample()?;
+        sample.AddBuffer(&buffer)?;
+        sample.SetSampleTime(time_hns)?;
+        sample.SetSampleDuration(frames as i64 * 10_000_000 / self.sample_rate as i64)?;
+        Ok(sample)
+    }
+}
@@ -0,0 +1,82 @@
+use crate::logging::trace;
+use std::path::PathBuf;
+
+use crate::types::frame_time_hns;
+
+/// Capture faster than the recording's frame rate for slow-motion editing
+///
+/// Frames are captured at `capture_fps`; the output file keeps the configured `fps` by taking
+/// the captured frame closest to each of its frames. With a `secondary_output`, every captured
+/// frame is also encoded into a video-only file at `capture_fps`, at a bitrate high enough to be
+/// close to lossless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct HighFpsCapture {
+    /// Frames captured per second, e.g. 120 or 240
+    pub capture_fps: u32,
+    /// File every captured frame is written to
+    pub secondary_output: Option<PathBuf>,
+    /// Video bitrate of the secondary file in bits per second
+    pub secondary_bitrate: u32,
+}
+
+impl Default for HighFpsCapture {
+    fn default() -> Self {
+        Self {
+            capture_fps: 120,
+            secondary_output: None,
+            secondary_bitrate: 100_000_000,
+        }
+    }
+}
+
+/// Picks the captured frames that make up the output when capturing faster than it
+///
+/// A frame is kept once it reaches the next output frame's time, less half a capture interval
+/// so frames landing a rounding error early still count, and the next output frame is the one
+/// after the kept frame's time. Frames kept this way carry the output frame duration.
+pub(crate) struct OutputDecimator {
+    fps_num: u32,
+    fps_den: u32,
+    /// Half the time between two captured frames, in 100ns units
+    tolerance: i64,
+    /// Index of the next output frame to fill
+    next: u64,
+}
+
+impl OutputDecimator {
+    pub fn new(fps_num: u32, fps_den: u32, capture_fps: u32) -> Self {
+        Self {
+            fps_num,
+            fps_den,
+            tolerance: frame_time_hns(1, capture_fps, 1) / 2,
+            next: 0,
+        }
+    }
+
+    /// Length of an output frame, in 100ns units
+    pub fn frame_duration(&self) -> i64 {
+        frame_time_hns(1, self.fps_num, self.fps_den)
+    }
+
+    /// Whether the captured frame at `timestamp` goes into the output
+    pub fn admit(&mut self, timestamp: i64) -> bool {
+        let due = frame_time_hns(self.next, self.fps_num, self.fps_den);
+        if timestamp < due - self.tolerance {
+            trace!(
+                timestamp = timestamp,
+                "Leaving high fps frame out of the output"
+            );
+            return false;
+        }
+        // Output frames the capture skipped past stay empty rather than being filled late
+        let current = ((timestamp + self.tolerance).max(0) as u128 * self.fps_num as u128
+            / (self.fps_den.max(1) as u128 * 10_000_000)) as u64;
+        self.next = current.max(self.next) + 1;
+        true
+    }
+}
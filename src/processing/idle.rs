@@ -0,0 +1,231 @@
+use crate::logging::{debug, error, info};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::audio::AudioLevels;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::recorder::markers::Marker;
+use crate::types::{duration_to_hns, FrameCounters};
+
+/// How often the idle monitor samples the frame counters and audio levels
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What the recorder does while the recording is idle
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IdleAction {
+    /// Add an "Idle" chapter marker where the idle period starts and a "Resumed" marker where it
+    /// ends
+    Marker,
+    /// Encode at most `fps` frames per second until something changes again; the skipped frames
+    /// leave gaps in the timestamps, so the file has a variable frame rate
+    ReduceFrameRate { fps: u32 },
+}
+
+/// When a recording counts as idle and what to do about it
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct IdlePolicy {
+    /// How long the video must stay static and the audio silent
+    pub threshold: Duration,
+    /// Peak level (0.0..=1.0 of full scale) below which system audio and microphone count as
+    /// silent
+    pub silence_level: f32,
+    pub action: IdleAction,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(30),
+            // About -40 dBFS
+            silence_level: 0.01,
+            action: IdleAction::Marker,
+        }
+    }
+}
+
+/// Frame skipping applied by the processing thread while the recording is idle
+pub(crate) struct IdleThrottle {
+    idle: AtomicBool,
+    /// Shortest gap between two encoded frames while idle, in 100ns units
+    frame_interval: i64,
+    /// Timestamp of the last frame that was not skipped
+    last_kept: AtomicI64,
+}
+
+impl IdleThrottle {
+    pub fn new(fps: u32) -> Self {
+        Self {
+            idle: AtomicBool::new(false),
+            frame_interval: duration_to_hns(Duration::from_secs(1)) / fps.max(1) as i64,
+            last_kept: AtomicI64::new(i64::MIN),
+        }
+    }
+
+    fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Relaxed);
+    }
+
+    /// Whether the frame at `timestamp` should be left out of the recording
+    pub fn skip(&self, timestamp: i64) -> bool {
+        let last_kept = self.last_kept.load(Ordering::Relaxed);
+        if self.idle.load(Ordering::Relaxed)
+            && timestamp.saturating_sub(last_kept) < self.frame_interval
+        {
+            return true;
+        }
+        self.last_kept.store(timestamp, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Change in the idle state reported by [`IdleDetector::update`]
+enum IdleTransition {
+    /// Nothing changed since `since`
+    Started { since: Instant },
+    /// Activity resumed after being idle since `since`
+    Ended { since: Instant },
+}
+
+/// Tracks how long the video has been static and the audio silent
+struct IdleDetector {
+    policy: IdlePolicy,
+    last_captured: u64,
+    last_levels: AudioLevels,
+    last_activity: Instant,
+    idle_since: Option<Instant>,
+}
+
+impl IdleDetector {
+    fn new(policy: IdlePolicy, now: Instant) -> Self {
+        Self {
+            policy,
+            last_captured: 0,
+            last_levels: AudioLevels::default(),
+            last_activity: now,
+            idle_since: None,
+        }
+    }
+
+    /// Feed the latest frame count and audio levels
+    fn update(&mut self, captured: u64, levels: AudioLevels, now: Instant) -> Option<IdleTransition> {
+        // The levels keep their last value when a source stops delivering audio, so an
+        // unchanged reading is not counted as sound
+        let loud = levels != self.last_levels
+            && (levels.system_peak > self.policy.silence_level
+                || levels.mic_peak > self.policy.silence_level);
+        let active = captured != self.last_captured || loud;
+        self.last_captured = captured;
+        self.last_levels = levels;
+
+        if active {
+            self.last_activity = now;
+            return self
+                .idle_since
+                .take()
+                .map(|since| IdleTransition::Ended { since });
+        }
+        if self.idle_since.is_none()
+            && now.duration_since(self.last_activity) >= self.policy.threshold
+        {
+            self.idle_since = Some(self.last_activity);
+            return Some(IdleTransition::Started {
+                since: self.last_activity,
+            });
+        }
+        None
+    }
+}
+
+/// The recording an idle monitor watches and acts on
+pub(crate) struct IdleMonitorContext {
+    /// Cleared when the recording stops
+    pub recording: Arc<AtomicBool>,
+    /// Idle timestamps count from here
+    pub start_time: Instant,
+    /// The captured frame count stands still while the video is static
+    pub counters: Arc<FrameCounters>,
+    pub audio_levels: Arc<Mutex<AudioLevels>>,
+    /// Lowers the frame rate while idle, for `IdleAction::ReduceFrameRate`
+    pub throttle: Option<Arc<IdleThrottle>>,
+    /// Where `IdleAction::Marker` adds its markers
+    pub markers: Arc<Mutex<Vec<Marker>>>,
+    pub events: EventEmitter,
+}
+
+/// Watch for static video and silent audio until the recording stops, emitting `IdleStarted` and
+/// `IdleEnded` and applying the policy's action
+pub(crate) fn spawn_idle_monitor(policy: IdlePolicy, context: IdleMonitorContext) {
+    let IdleMonitorContext {
+        recording,
+        start_time,
+        counters,
+        audio_levels,
+        throttle,
+        markers,
+        events,
+    } = context;
+    let add_marker = move |time: Duration, label: &str| {
+        if policy.action != IdleAction::Marker {
+            return;
+        }
+        // The idle marker lands before markers added while the threshold was running
+        if let Ok(mut markers) = markers.lock() {
+            let index = markers.partition_point(|marker| marker.time <= time);
+            markers.insert(
+                index,
+                Marker {
+                    time,
+                    label: label.to_string(),
+                },
+            );
+        }
+    };
+
+    let spawned = std::thread::Builder::new()
+        .name("windows-record-idle".to_string())
+        .spawn(move || {
+            let mut detector = IdleDetector::new(policy, Instant::now());
+            while recording.load(Ordering::Relaxed) {
+                std::thread::sleep(IDLE_POLL_INTERVAL);
+                let now = Instant::now();
+                let captured = counters.captured.load(Ordering::Relaxed);
+                let levels = audio_levels.lock().map(|l| *l).unwrap_or_default();
+                match detector.update(captured, levels, now) {
+                    Some(IdleTransition::Started { since }) => {
+                        let timestamp = since.saturating_duration_since(start_time);
+                        info!("Recording idle since {:?}", timestamp);
+                        if let Some(throttle) = &throttle {
+                            throttle.set_idle(true);
+                        }
+                        add_marker(timestamp, "Idle");
+                        events.emit(RecorderEvent::IdleStarted { timestamp });
+                    }
+                    Some(IdleTransition::Ended { since }) => {
+                        let timestamp = now.saturating_duration_since(start_time);
+                        let idle_for = now.saturating_duration_since(since);
+                        info!("Recording active again at {:?} after {:?}", timestamp, idle_for);
+                        if let Some(throttle) = &throttle {
+                            throttle.set_idle(false);
+                        }
+                        add_marker(timestamp, "Resumed");
+                        events.emit(RecorderEvent::IdleEnded {
+                            timestamp,
+                            idle_for,
+                        });
+                    }
+                    None => {}
+                }
+            }
+            debug!("Idle monitor finished");
+        });
+    if let Err(e) = spawned {
+        error!("Failed to spawn the idle monitor: {}", e);
+    }
+}
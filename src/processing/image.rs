@@ -0,0 +1,545 @@
+use crate::logging::{debug, info};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use windows::core::{ComInterface, Interface, Result, HSTRING};
+use windows::Win32::Foundation::{E_FAIL, FALSE, GENERIC_WRITE};
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM;
+use windows::Win32::Graphics::Imaging::*;
+use windows::Win32::Media::MediaFoundation::{IMFDXGIBuffer, IMFSample};
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CLSCTX_INPROC_SERVER, VARENUM, VT_UI1, VT_UI2, VT_VECTOR,
+};
+
+use crate::types::duration_to_hns;
+
+/// A frame read back from the GPU as tightly packed BGRA rows
+pub struct BgraFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reads processed NV12 (or, with the RGB pipeline, BGRA) samples back from the GPU, reusing
+/// one staging texture
+#[derive(Default)]
+pub struct Nv12Reader {
+    staging: Option<(ID3D11Texture2D, u32, u32)>,
+}
+
+impl Nv12Reader {
+    /// Copy the NV12 or BGRA texture behind `sample` to the CPU as BGRA, downscaled to at most
+    /// `max_width` pixels wide (0 keeps the full size)
+    pub unsafe fn read(&mut self, sample: &IMFSample, max_width: u32) -> Result<BgraFrame> {
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+        let subresource = dxgi_buffer.GetSubresourceIndex()?;
+
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        let device = texture.GetDevice()?;
+
+        let staging = match &self.staging {
+            Some((staging, width, height)) if *width == desc.Width && *height == desc.Height => {
+                staging.clone()
+            }
+            _ => {
+                let staging_desc = D3D11_TEXTURE2D_DESC {
+                    MipLevels: 1,
+                    ArraySize: 1,
+                    Usage: D3D11_USAGE_STAGING,
+                    BindFlags: D3D11_BIND_FLAG(0),
+                    CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                    MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+                    ..desc
+                };
+                let mut staging = None;
+                device.CreateTexture2D(&staging_desc, None, Some(&mut staging))?;
+                let staging = staging.unwrap();
+                debug!(
+                    "Created {}x{} {:?} readback texture",
+                    desc.Width, desc.Height, desc.Format
+                );
+                self.staging = Some((staging.clone(), desc.Width, desc.Height));
+                staging
+            }
+        };
+
+        let context = device.GetImmediateContext()?;
+        context.CopySubresourceRegion(&staging, 0, 0, 0, 0, &texture, subresource, None);
+
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        context.Map(&staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped))?;
+        let pitch = mapped.RowPitch as usize;
+        let frame = if desc.Format == DXGI_FORMAT_B8G8R8A8_UNORM {
+            let plane =
+                std::slice::from_raw_parts(mapped.pData as *const u8, pitch * desc.Height as usize);
+            downscale_bgra(plane, pitch, desc.Width, desc.Height, max_width)
+        } else {
+            // The interleaved chroma plane follows the luma plane at full height
+            let plane = std::slice::from_raw_parts(
+                mapped.pData as *const u8,
+                pitch * desc.Height as usize * 3 / 2,
+            );
+            nv12_to_bgra(plane, pitch, desc.Width, desc.Height, max_width)
+        };
+        context.Unmap(&staging, 0);
+        Ok(frame)
+    }
+}
+
+/// Size of a `width` x `height` image downscaled to at most `max_width` (0 for no limit)
+fn downscaled_size(width: u32, height: u32, max_width: u32) -> (u32, u32) {
+    if max_width == 0 || max_width >= width {
+        (width, height)
+    } else {
+        let out_height = (height as u64 * max_width as u64 / width as u64).max(1) as u32;
+        (max_width, out_height)
+    }
+}
+
+/// Pack the rows of a BGRA image, nearest-neighbour downscaling it
+fn downscale_bgra(
+    plane: &[u8],
+    pitch: usize,
+    width: u32,
+    height: u32,
+    max_width: u32,
+) -> BgraFrame {
+    let (out_width, out_height) = downscaled_size(width, height, max_width);
+    let mut data = Vec::with_capacity(out_width as usize * out_height as usize * 4);
+    for y in 0..out_height as usize {
+        let row = &plane[y * height as usize / out_height as usize * pitch..];
+        for x in 0..out_width as usize {
+            let sx = x * width as usize / out_width as usize * 4;
+            data.extend([row[sx], row[sx + 1], row[sx + 2], 255]);
+        }
+    }
+
+    BgraFrame {
+        width: out_width,
+        height: out_height,
+        data,
+    }
+}
+
+/// Convert a limited range BT.709 NV12 image to BGRA, nearest-neighbour downscaling it
+fn nv12_to_bgra(plane: &[u8], pitch: usize, width: u32, height: u32, max_width: u32) -> BgraFrame {
+    let (out_width, out_height) = downscaled_size(width, height, max_width);
+
+    let chroma = pitch * height as usize;
+    let mut data = Vec::with_capacity(out_width as usize * out_height as usize * 4);
+    for y in 0..out_height as usize {
+        let sy = y * height as usize / out_height as usize;
+        for x in 0..out_width as usize {
+            let sx = x * width as usize / out_width as usize;
+            let luma = plane[sy * pitch + sx] as f32;
+            let uv = chroma + (sy / 2) * pitch + (sx & !1);
+            let u = plane[uv] as f32 - 128.0;
+            let v = plane[uv + 1] as f32 - 128.0;
+
+            let c = 1.164 * (luma - 16.0);
+            let r = c + 1.793 * v;
+            let g = c - 0.213 * u - 0.533 * v;
+            let b = c + 2.112 * u;
+            data.extend([
+                b.clamp(0.0, 255.0) as u8,
+                g.clamp(0.0, 255.0) as u8,
+                r.clamp(0.0, 255.0) as u8,
+                255,
+            ]);
+        }
+    }
+
+    BgraFrame {
+        width: out_width,
+        height: out_height,
+        data,
+    }
+}
+
+unsafe fn create_wic_factory() -> Result<IWICImagingFactory> {
+    crate::device::ensure_com_initialized()?;
+    CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)
+}
+
+unsafe fn create_encoder(
+    factory: &IWICImagingFactory,
+    path: &str,
+    container: &windows::core::GUID,
+) -> Result<IWICBitmapEncoder> {
+    let stream = factory.CreateStream()?;
+    stream.InitializeFromFilename(&HSTRING::from(path), GENERIC_WRITE.0)?;
+    let encoder = factory.CreateEncoder(container, std::ptr::null())?;
+    encoder.Initialize(&stream, WICBitmapEncoderNoCache)?;
+    Ok(encoder)
+}
+
+unsafe fn create_bitmap(factory: &IWICImagingFactory, frame: &BgraFrame) -> Result<IWICBitmap> {
+    factory.CreateBitmapFromMemory(
+        frame.width,
+        frame.height,
+        &GUID_WICPixelFormat32bppBGRA,
+        frame.width * 4,
+        &frame.data,
+    )
+}
+
+/// Write `frames` as an endlessly looping animated GIF, showing each for `frame_delay`
+pub unsafe fn write_gif(path: &str, frames: &[BgraFrame], frame_delay: Duration) -> Result<()> {
+    info!("Writing {} frames to GIF {}", frames.len(), path);
+    let factory = create_wic_factory()?;
+    let encoder = create_encoder(&factory, path, &GUID_ContainerFormatGif)?;
+
+    // NETSCAPE2.0 application extension with a loop count of 0 (forever)
+    let metadata = encoder.GetMetadataQueryWriter()?;
+    let mut application = *b"NETSCAPE2.0";
+    let mut loop_data = [3u8, 1, 0, 0, 0];
+    metadata.SetMetadataByName(
+        &HSTRING::from("/appext/Application"),
+        &byte_vector(&mut application),
+    )?;
+    metadata.SetMetadataByName(&HSTRING::from("/appext/Data"), &byte_vector(&mut loop_data))?;
+
+    // GIF delays are in hundredths of a second
+    let delay = (frame_delay.as_millis() / 10).clamp(2, u16::MAX as u128) as u16;
+    let mut delay_value = PROPVARIANT::default();
+    (*delay_value.Anonymous.Anonymous).vt = VT_UI2;
+    (*delay_value.Anonymous.Anonymous).Anonymous.uiVal = delay;
+
+    for frame in frames {
+        let bitmap = create_bitmap(&factory, frame)?;
+        let palette = factory.CreatePalette()?;
+        palette.InitializeFromBitmap(&bitmap, 256, FALSE)?;
+        let indexed = factory.CreateFormatConverter()?;
+        indexed.Initialize(
+            &bitmap,
+            &GUID_WICPixelFormat8bppIndexed,
+            WICBitmapDitherTypeErrorDiffusion,
+            &palette,
+            0.0,
+            WICBitmapPaletteTypeCustom,
+        )?;
+
+        let mut frame_encode = None;
+        encoder.CreateNewFrame(&mut frame_encode, std::ptr::null_mut())?;
+        let frame_encode = frame_encode.unwrap();
+        frame_encode.Initialize(None)?;
+        frame_encode.SetSize(frame.width, frame.height)?;
+        let mut format = GUID_WICPixelFormat8bppIndexed;
+        frame_encode.SetPixelFormat(&mut format)?;
+        frame_encode.SetPalette(&palette)?;
+        frame_encode
+            .GetMetadataQueryWriter()?
+            .SetMetadataByName(&HSTRING::from("/grctlext/Delay"), &delay_value)?;
+        frame_encode.WriteSource(&indexed, std::ptr::null())?;
+        frame_encode.Commit()?;
+    }
+
+    encoder.Commit()
+}
+
+/// A VT_VECTOR | VT_UI1 property pointing at `bytes`, which must outlive it
+fn byte_vector(bytes: &mut [u8]) -> PROPVARIANT {
+    let mut value = PROPVARIANT::default();
+    unsafe {
+        (*value.Anonymous.Anonymous).vt = VARENUM(VT_VECTOR.0 | VT_UI1.0);
+        (*value.Anonymous.Anonymous).Anonymous.caub.cElems = bytes.len() as u32;
+        (*value.Anonymous.Anonymous).Anonymous.caub.pElems = bytes.as_mut_ptr();
+    }
+    value
+}
+
+/// Write `frame` as a JPEG file
+pub unsafe fn write_jpeg(path: &str, frame: &BgraFrame) -> Result<()> {
+    let factory = create_wic_factory()?;
+    // The JPEG encoder takes no alpha channel
+    write_single_frame(
+        &factory,
+        path,
+        &GUID_ContainerFormatJpeg,
+        GUID_WICPixelFormat24bppBGR,
+        frame,
+    )
+}
+
+/// Write `frame` as the only frame of a `container` file, converted to `pixel_format`
+unsafe fn write_single_frame(
+    factory: &IWICImagingFactory,
+    path: &str,
+    container: &windows::core::GUID,
+    pixel_format: windows::core::GUID,
+    frame: &BgraFrame,
+) -> Result<()> {
+    let encoder = create_encoder(factory, path, container)?;
+    let bitmap = create_bitmap(factory, frame)?;
+    let converted = factory.CreateFormatConverter()?;
+    converted.Initialize(
+        &bitmap,
+        &pixel_format,
+        WICBitmapDitherTypeNone,
+        None,
+        0.0,
+        WICBitmapPaletteTypeCustom,
+    )?;
+
+    let mut frame_encode = None;
+    encoder.CreateNewFrame(&mut frame_encode, std::ptr::null_mut())?;
+    let frame_encode = frame_encode.unwrap();
+    frame_encode.Initialize(None)?;
+    frame_encode.SetSize(frame.width, frame.height)?;
+    let mut format = pixel_format;
+    frame_encode.SetPixelFormat(&mut format)?;
+    frame_encode.WriteSource(&converted, std::ptr::null())?;
+    frame_encode.Commit()?;
+    encoder.Commit()
+}
+
+/// File format of the images written by `OutputMode::ImageSequence`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ImageFormat {
+    /// Lossless 24-bit PNG
+    #[default]
+    Png,
+    /// Uncompressed BGRA DDS, ready to be uploaded as a `DXGI_FORMAT_B8G8R8A8_UNORM` texture
+    Dds,
+}
+
+impl ImageFormat {
+    /// File extension of the format, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Dds => "dds",
+        }
+    }
+
+    fn container(&self) -> windows::core::GUID {
+        match self {
+            ImageFormat::Png => GUID_ContainerFormatPng,
+            ImageFormat::Dds => GUID_ContainerFormatDds,
+        }
+    }
+
+    fn pixel_format(&self) -> windows::core::GUID {
+        match self {
+            // Frames are opaque, so the alpha channel would only grow the files
+            ImageFormat::Png => GUID_WICPixelFormat24bppBGR,
+            ImageFormat::Dds => GUID_WICPixelFormat32bppBGRA,
+        }
+    }
+}
+
+/// What a recording produces
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputMode {
+    /// An encoded video file at the output path
+    #[default]
+    Video,
+    /// Individual images of the processed frames in `dir`, named `frame_000000.png` and so on
+    /// counting up from 0, instead of a video file
+    ImageSequence {
+        dir: PathBuf,
+        format: ImageFormat,
+        /// Keep one frame in this many, e.g. 30 for one image a second of a 30 fps recording
+        every_nth: u32,
+    },
+}
+
+/// Path of the `index`th image of a sequence
+pub fn image_sequence_path(dir: &Path, format: ImageFormat, index: u64) -> PathBuf {
+    dir.join(format!("frame_{:06}.{}", index, format.extension()))
+}
+
+fn io_error(e: std::io::Error) -> windows::core::Error {
+    windows::core::Error::new(E_FAIL, format!("Image sequence I/O error: {}", e).into())
+}
+
+/// Writes the frames of an `OutputMode::ImageSequence` recording
+///
+/// Frames are read back on the processing thread, since the pooled textures are reused for
+/// later frames, and compressed and written on a thread of their own. Up to
+/// `MAX_PENDING_IMAGES` frames wait for it before the processing thread blocks.
+pub struct ImageSequenceWriter {
+    every_nth: u64,
+    reader: Nv12Reader,
+    offered: u64,
+    frames: Option<SyncSender<BgraFrame>>,
+    handle: Option<JoinHandle<Result<u64>>>,
+}
+
+/// Frames read back but not yet written before `ImageSequenceWriter::offer` blocks
+const MAX_PENDING_IMAGES: usize = 4;
+
+impl ImageSequenceWriter {
+    /// Start writing to `dir`, creating it if needed
+    pub fn new(dir: &Path, format: ImageFormat, every_nth: u32) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(io_error)?;
+        let (frames, queue) = sync_channel::<BgraFrame>(MAX_PENDING_IMAGES);
+        let images_dir = dir.to_path_buf();
+        let handle = std::thread::Builder::new()
+            .name("windows-record-image-sequence".to_string())
+            .spawn(move || -> Result<u64> {
+                let factory = unsafe { create_wic_factory()? };
+                let mut written = 0u64;
+                for frame in queue {
+                    let path = image_sequence_path(&images_dir, format, written);
+                    unsafe {
+                        write_single_frame(
+                            &factory,
+                            &path.to_string_lossy(),
+                            &format.container(),
+                            format.pixel_format(),
+                            &frame,
+                        )?
+                    };
+                    written += 1;
+                }
+                Ok(written)
+            })
+            .map_err(io_error)?;
+        info!(
+            "Writing every {} frame(s) as {:?} images to {}",
+            every_nth,
+            format,
+            dir.display()
+        );
+
+        Ok(Self {
+            every_nth: every_nth.max(1) as u64,
+            reader: Nv12Reader::default(),
+            offered: 0,
+            frames: Some(frames),
+            handle: Some(handle),
+        })
+    }
+
+    /// Offer a processed frame, which is written if it is one of the `every_nth`
+    pub unsafe fn offer(&mut self, sample: &IMFSample) -> Result<()> {
+        let index = self.offered;
+        self.offered += 1;
+        if index % self.every_nth != 0 {
+            return Ok(());
+        }
+        let frame = self.reader.read(sample, 0)?;
+        let sent = self
+            .frames
+            .as_ref()
+            .is_some_and(|frames| frames.send(frame).is_ok());
+        if sent {
+            Ok(())
+        } else {
+            // The writer thread only stops early on an error, which `finish` reports
+            self.finish().map(|_| ())
+        }
+    }
+
+    /// Wait for the pending frames to be written, returning how many images were written
+    pub fn finish(&mut self) -> Result<u64> {
+        self.frames = None;
+        let Some(handle) = self.handle.take() else {
+            return Ok(0);
+        };
+        let written = handle.join().unwrap_or_else(|_| {
+            Err(windows::core::Error::new(
+                E_FAIL,
+                "Image sequence writer panicked".into(),
+            ))
+        })?;
+        info!("Wrote {} images of {} frames", written, self.offered);
+        Ok(written)
+    }
+}
+
+/// Which frame a recording's thumbnail is made of and how large it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct ThumbnailSpec {
+    /// Position of the frame in the recording; the first frame is used for shorter recordings
+    pub at: Duration,
+    /// Width of the thumbnail in pixels, the height follows the aspect ratio
+    pub width: u32,
+}
+
+impl Default for ThumbnailSpec {
+    fn default() -> Self {
+        Self {
+            at: Duration::ZERO,
+            width: 320,
+        }
+    }
+}
+
+/// Path of the thumbnail written next to a recording, e.g. `clip.jpg` for `clip.mp4`
+pub fn thumbnail_path(video_path: &Path) -> PathBuf {
+    video_path.with_extension("jpg")
+}
+
+#[derive(Default)]
+struct ThumbnailState {
+    reader: Nv12Reader,
+    frame: Option<BgraFrame>,
+    reached: bool,
+}
+
+/// Stashes the thumbnail frame of a recording while it is processed
+///
+/// The pooled textures are reused for later frames, so the frame is read back right
+/// away. Only the first frame and the first one at or after `spec.at` are read back.
+pub struct ThumbnailCapture {
+    spec: ThumbnailSpec,
+    state: Mutex<ThumbnailState>,
+}
+
+impl ThumbnailCapture {
+    pub fn new(spec: ThumbnailSpec) -> Self {
+        Self {
+            spec,
+            state: Mutex::new(ThumbnailState::default()),
+        }
+    }
+
+    /// Offer a processed frame at `timestamp` (100ns units from the start of the recording)
+    pub unsafe fn offer(&self, sample: &IMFSample, timestamp: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.reached {
+            return Ok(());
+        }
+        let reached = timestamp >= duration_to_hns(self.spec.at);
+        if state.frame.is_some() && !reached {
+            return Ok(());
+        }
+        let frame = state.reader.read(sample, self.spec.width)?;
+        state.frame = Some(frame);
+        state.reached = reached;
+        if reached {
+            debug!("Captured thumbnail frame at {}", timestamp);
+        }
+        Ok(())
+    }
+
+    /// Write the stashed frame next to `video_path`, returning the thumbnail path
+    pub fn write(&self, video_path: &Path) -> Result<Option<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        let Some(frame) = &state.frame else {
+            return Ok(None);
+        };
+        let path = thumbnail_path(video_path);
+        unsafe { write_jpeg(&path.to_string_lossy(), frame)? };
+        info!("Thumbnail written to {}", path.display());
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,376 @@
+use crate::logging::{debug, info};
+use windows::core::{ComInterface, Interface, Result};
+use windows::Win32::Graphics::Direct3D::D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Media::MediaFoundation::{IMFDXGIBuffer, IMFSample};
+
+use super::scaler::{blob_bytes, compile_shader};
+
+/// How a masked region is hidden
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MaskStyle {
+    /// Fill the region with black
+    Blackout,
+    /// Replace the region with blocks of `block_size` pixels, each the colour of its centre
+    Pixelate { block_size: u32 },
+}
+
+/// A region of the captured monitor hidden from the recording
+///
+/// Coordinates are pixels of the captured monitor, the same space as the window position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MaskRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub style: MaskStyle,
+}
+
+const MASK_HLSL: &str = r#"
+cbuffer Params : register(b0)
+{
+    float4 rect;     // x, y, width, height of the masked region in pixels
+    float block;     // pixelation block size in pixels
+    float2 corners;  // radius of the top and bottom corners in pixels
+    float padding;
+};
+
+Texture2D<float4> src : register(t0);
+
+float4 vs_main(uint id : SV_VertexID) : SV_Position
+{
+    float2 uv = float2((id << 1) & 2, id & 2);
+    return float4(uv * float2(2, -2) + float2(-1, 1), 0, 1);
+}
+
+float4 ps_blackout(float4 pos : SV_Position) : SV_Target
+{
+    return float4(0, 0, 0, 1);
+}
+
+float4 ps_pixelate(float4 pos : SV_Position) : SV_Target
+{
+    // Blocks are aligned to the region so they do not shift when it is resized
+    float2 local = floor((pos.xy - rect.xy) / block) * block + block * 0.5;
+    float2 p = rect.xy + min(local, rect.zw - 1);
+    return float4(src.Load(int3(p, 0)).rgb, 1);
+}
+
+float4 ps_corners(float4 pos : SV_Position) : SV_Target
+{
+    // Keep pixels within a corner's radius of the centre of its circle
+    float2 local = pos.xy - rect.xy;
+    float radius = local.y < rect.w * 0.5 ? corners.x : corners.y;
+    float2 centre = clamp(local, radius, rect.zw - radius);
+    if (length(local - centre) <= radius)
+        discard;
+    return float4(0, 0, 0, 1);
+}
+"#;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct MaskParams {
+    rect: [f32; 4],
+    block: f32,
+    corners: [f32; 2],
+    padding: f32,
+}
+
+/// Copy of the frame the pixelation reads from, since a texture cannot be sampled while it is
+/// being rendered to
+struct Scratch {
+    texture: ID3D11Texture2D,
+    srv: ID3D11ShaderResourceView,
+    width: u32,
+    height: u32,
+}
+
+/// Blacks out or pixelates regions of captured BGRA frames in place, and blacks out the rounded
+/// corners of the captured window
+pub(crate) struct PrivacyMasker {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    vertex_shader: ID3D11VertexShader,
+    blackout_shader: ID3D11PixelShader,
+    pixelate_shader: ID3D11PixelShader,
+    corners_shader: ID3D11PixelShader,
+    constants: ID3D11Buffer,
+    scratch: Option<Scratch>,
+}
+
+impl PrivacyMasker {
+    pub unsafe fn new(device: &ID3D11Device) -> Result<Self> {
+        info!("Creating privacy masker");
+        let vs_blob = compile_shader(MASK_HLSL, "vs_main", "vs_5_0")?;
+        let mut vertex_shader = None;
+        device.CreateVertexShader(blob_bytes(&vs_blob), None, Some(&mut vertex_shader))?;
+
+        let mut pixel_shaders = Vec::new();
+        for entry in ["ps_blackout", "ps_pixelate", "ps_corners"] {
+            let ps_blob = compile_shader(MASK_HLSL, entry, "ps_5_0")?;
+            let mut pixel_shader = None;
+            device.CreatePixelShader(blob_bytes(&ps_blob), None, Some(&mut pixel_shader))?;
+            pixel_shaders.push(pixel_shader.unwrap());
+        }
+        let corners_shader = pixel_shaders.pop().unwrap();
+        let pixelate_shader = pixel_shaders.pop().unwrap();
+        let blackout_shader = pixel_shaders.pop().unwrap();
+
+        let buffer_desc = D3D11_BUFFER_DESC {
+            ByteWidth: std::mem::size_of::<MaskParams>() as u32,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+            ..Default::default()
+        };
+        let mut constants = None;
+        device.CreateBuffer(&buffer_desc, None, Some(&mut constants))?;
+
+        Ok(Self {
+            device: device.clone(),
+            context: device.GetImmediateContext()?,
+            vertex_shader: vertex_shader.unwrap(),
+            blackout_shader,
+            pixelate_shader,
+            corners_shader,
+            constants: constants.unwrap(),
+            scratch: None,
+        })
+    }
+
+    /// Hide `masks` in the BGRA texture behind `sample`
+    pub unsafe fn apply(&mut self, sample: &IMFSample, masks: &[MaskRect]) -> Result<()> {
+        let (texture, desc, rtv) = self.render_target(sample)?;
+
+        let pixelate = masks
+            .iter()
+            .any(|mask| matches!(mask.style, MaskStyle::Pixelate { .. }));
+        if pixelate
+            && self
+                .scratch
+                .as_ref()
+                .is_none_or(|scratch| (scratch.width, scratch.height) != (desc.Width, desc.Height))
+        {
+            self.scratch = Some(self.create_scratch(&desc)?);
+        }
+
+        // Keep the pipeline state atomic with respect to other threads
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+
+        self.context
+            .IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        self.context.IASetInputLayout(None);
+        self.context.VSSetShader(&self.vertex_shader, None);
+        self.context
+            .PSSetConstantBuffers(0, Some(&[Some(self.constants.clone())]));
+
+        for mask in masks {
+            let Some(region) = clip(mask, desc.Width, desc.Height) else {
+                continue;
+            };
+            let (shader, block, source) = match (mask.style, &self.scratch) {
+                (MaskStyle::Pixelate { block_size }, Some(scratch)) => {
+                    // Snapshot the region before drawing over it
+                    self.context.PSSetShaderResources(0, Some(&[None]));
+                    self.context.OMSetRenderTargets(None, None);
+                    self.context.CopySubresourceRegion(
+                        &scratch.texture,
+                        0,
+                        region.left,
+                        region.top,
+                        0,
+                        &texture,
+                        0,
+                        Some(&region),
+                    );
+                    (
+                        &self.pixelate_shader,
+                        block_size.max(1) as f32,
+                        Some(scratch.srv.clone()),
+                    )
+                }
+                _ => (&self.blackout_shader, 1.0, None),
+            };
+
+            let params = MaskParams {
+                rect: [
+                    region.left as f32,
+                    region.top as f32,
+                    (region.right - region.left) as f32,
+                    (region.bottom - region.top) as f32,
+                ],
+                block,
+                ..Default::default()
+            };
+            self.context.UpdateSubresource(
+                &self.constants,
+                0,
+                None,
+                &params as *const _ as *const _,
+                0,
+                0,
+            );
+            self.context.PSSetShader(shader, None);
+            self.context
+                .OMSetRenderTargets(Some(&[Some(rtv.clone())]), None);
+            self.context.PSSetShaderResources(0, Some(&[source]));
+            self.context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+                TopLeftX: params.rect[0],
+                TopLeftY: params.rect[1],
+                Width: params.rect[2],
+                Height: params.rect[3],
+                MinDepth: 0.0,
+                MaxDepth: 1.0,
+            }]));
+            self.context.Draw(3, 0);
+        }
+
+        // Unbind so the texture can be used as MFT input again
+        self.context.PSSetShaderResources(0, Some(&[None]));
+        self.context.OMSetRenderTargets(None, None);
+        multithread.Leave();
+        Ok(())
+    }
+
+    /// Black out the pixels of the window at `position` with size `size` that lie outside its
+    /// rounded corners, whose radii at the top and bottom are `radii`
+    pub unsafe fn mask_corners(
+        &mut self,
+        sample: &IMFSample,
+        position: (i32, i32),
+        size: (u32, u32),
+        radii: (u32, u32),
+    ) -> Result<()> {
+        let (_, desc, rtv) = self.render_target(sample)?;
+        let window = MaskRect {
+            x: position.0,
+            y: position.1,
+            width: size.0,
+            height: size.1,
+            style: MaskStyle::Blackout,
+        };
+        if clip(&window, desc.Width, desc.Height).is_none() {
+            return Ok(());
+        }
+
+        let params = MaskParams {
+            rect: [
+                position.0 as f32,
+                position.1 as f32,
+                size.0 as f32,
+                size.1 as f32,
+            ],
+            corners: [radii.0 as f32, radii.1 as f32],
+            ..Default::default()
+        };
+
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+
+        self.context
+            .IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        self.context.IASetInputLayout(None);
+        self.context.VSSetShader(&self.vertex_shader, None);
+        self.context.UpdateSubresource(
+            &self.constants,
+            0,
+            None,
+            &params as *const _ as *const _,
+            0,
+            0,
+        );
+        self.context
+            .PSSetConstantBuffers(0, Some(&[Some(self.constants.clone())]));
+        self.context.PSSetShader(&self.corners_shader, None);
+        self.context.OMSetRenderTargets(Some(&[Some(rtv)]), None);
+        // The viewport covers the whole window; the parts off the frame are simply not drawn
+        self.context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+            TopLeftX: params.rect[0],
+            TopLeftY: params.rect[1],
+            Width: params.rect[2],
+            Height: params.rect[3],
+            MinDepth: 0.0,
+            MaxDepth: 1.0,
+        }]));
+        self.context.Draw(3, 0);
+
+        self.context.OMSetRenderTargets(None, None);
+        multithread.Leave();
+        Ok(())
+    }
+
+    /// The BGRA texture behind `sample`, its description and a render target view of it
+    unsafe fn render_target(
+        &self,
+        sample: &IMFSample,
+    ) -> Result<(
+        ID3D11Texture2D,
+        D3D11_TEXTURE2D_DESC,
+        ID3D11RenderTargetView,
+    )> {
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        let mut rtv = None;
+        self.device
+            .CreateRenderTargetView(&texture, None, Some(&mut rtv))?;
+        Ok((texture, desc, rtv.unwrap()))
+    }
+
+    unsafe fn create_scratch(&self, desc: &D3D11_TEXTURE2D_DESC) -> Result<Scratch> {
+        debug!(
+            "Creating {}x{} privacy mask scratch texture",
+            desc.Width, desc.Height
+        );
+        let scratch_desc = D3D11_TEXTURE2D_DESC {
+            MipLevels: 1,
+            ArraySize: 1,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+            ..*desc
+        };
+        let mut texture = None;
+        self.device
+            .CreateTexture2D(&scratch_desc, None, Some(&mut texture))?;
+        let texture = texture.unwrap();
+        let mut srv = None;
+        self.device
+            .CreateShaderResourceView(&texture, None, Some(&mut srv))?;
+        Ok(Scratch {
+            texture,
+            srv: srv.unwrap(),
+            width: desc.Width,
+            height: desc.Height,
+        })
+    }
+}
+
+/// The part of `mask` inside a `width` x `height` texture, None if it is outside
+fn clip(mask: &MaskRect, width: u32, height: u32) -> Option<D3D11_BOX> {
+    let left = mask.x.max(0) as i64;
+    let top = mask.y.max(0) as i64;
+    let right = (mask.x as i64 + mask.width as i64).min(width as i64);
+    let bottom = (mask.y as i64 + mask.height as i64).min(height as i64);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(D3D11_BOX {
+        left: left as u32,
+        top: top as u32,
+        front: 0,
+        right: right as u32,
+        bottom: bottom as u32,
+        back: 1,
+    })
+}
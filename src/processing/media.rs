@@ -1,40 +1,202 @@
-use std::ptr;
+use std::sync::Mutex;
 
-use log::{error, info};
-use windows::core::{Result, GUID};
+use crate::logging::{error, info, warn};
+
+use super::encoder::{self, EntropyCoding};
+use super::tap::{EncodedFrameCallback, TapMediaSink};
+use super::video::{ColorSpace, PixelPipeline};
+use crate::device::capabilities::VideoProfile;
+use crate::device::{ensure_com_initialized, VideoEncoderType};
+use windows::core::{ComInterface, Result, GUID};
 use windows::Win32::Media::Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM};
 use windows::Win32::Media::MediaFoundation::*;
 
+/// Codec of the recorded audio track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AudioCodec {
+    /// AAC-LC, playable everywhere; `bitrate` in bits per second, one of
+    /// [`AudioCodec::AAC_BITRATES`]
+    Aac { bitrate: u32 },
+    /// Lossless FLAC, needs the FLAC encoder of Windows 10 1709 or later
+    Flac,
+    /// Uncompressed 16-bit PCM, the easiest to edit
+    Pcm,
+}
+
+impl AudioCodec {
+    /// Bitrates the Media Foundation AAC encoder supports
+    pub const AAC_BITRATES: [u32; 4] = [96_000, 128_000, 160_000, 192_000];
+}
+
+impl Default for AudioCodec {
+    fn default() -> Self {
+        Self::Aac { bitrate: 128_000 }
+    }
+}
+
+/// How the video encoder trades file size for quality
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QualityProfile {
+    /// Variable bitrate around `video_bitrate`
+    #[default]
+    Standard,
+    /// Constant quantizer at the lowest QP for visually lossless video, ignoring
+    /// `video_bitrate`. Uses the encoder's 4:4:4 profile when frames reach it as RGB and it has
+    /// one. Files are many times larger than with `Standard`; the encoder must support constant
+    /// quality rate control
+    Lossless,
+}
+
+/// Quantizer used for `QualityProfile::Lossless`
+pub(crate) const LOSSLESS_QP: u64 = 0;
+
+/// Profile, level and rate control a video stream is encoded with, resolved from the
+/// `EncoderSettings`, the `QualityProfile` and what the encoder supports. The default is the
+/// recorder's usual High (H.264) or Main (HEVC) profile at the configured bitrate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct VideoEncoding {
+    /// Profile of the stream, or None for the default of its codec
+    pub profile: Option<VideoProfile>,
+    /// Level times ten, or None to let the encoder pick
+    pub level: Option<u32>,
+    /// H.264 entropy coder, or None to let the encoder pick
+    pub entropy_coding: Option<EntropyCoding>,
+    /// Encode at this QP instead of a bitrate
+    pub constant_qp: Option<u64>,
+}
+
+/// Attributes the sink writer is created with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct SinkWriterOptions {
+    /// Let the sink writer use hardware encoders and converters
+    /// (`MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS`)
+    pub hardware_transforms: bool,
+    /// Queue samples instead of blocking `WriteSample` while the encoder or the file falls
+    /// behind (`MF_SINK_WRITER_DISABLE_THROTTLING`); the queue grows in memory meanwhile
+    pub disable_throttling: bool,
+    /// Set `MF_LOW_LATENCY` on the sink writer, or follow the `low_latency` option if None
+    pub low_latency: Option<bool>,
+}
+
+impl Default for SinkWriterOptions {
+    fn default() -> Self {
+        Self {
+            hardware_transforms: true,
+            disable_throttling: false,
+            low_latency: None,
+        }
+    }
+}
+
+/// Streams and encoding of a file written by [`create_sink_writer`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SinkWriterSettings {
+    pub fps_num: u32,
+    pub fps_den: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Add the mixed audio stream as stream 1
+    pub audio: bool,
+    pub video_bitrate: u32,
+    /// Hold the encoder to `video_bitrate` as a maximum, for a file size budget
+    pub capped_bitrate: bool,
+    /// Output format of the video encoder
+    pub video_encoder: GUID,
+    pub low_latency: bool,
+    /// Write a fragmented MP4
+    pub fragmented: bool,
+    pub sink_options: SinkWriterOptions,
+    pub color_space: ColorSpace,
+    pub pixel_pipeline: PixelPipeline,
+    pub video_encoding: VideoEncoding,
+    pub audio_codec: AudioCodec,
+}
+
 pub unsafe fn create_sink_writer(
     output_path: &str,
-    fps_num: u32,
-    fps_den: u32,
-    output_width: u32,
-    output_height: u32,
-    capture_audio: bool,
-    capture_microphone: bool,
-    video_bitrate: u32,
-    video_encoder_guid: &GUID,
+    settings: &SinkWriterSettings,
+    encoded_frames: Option<EncodedFrameCallback>,
 ) -> Result<IMFSinkWriter> {
     info!("create_sink_writer - Starting with path: {}", output_path);
-    info!("create_sink_writer - Parameters: fps={}/{}, resolution={}x{}, audio={}, mic={}, bitrate={}, encoder={:?}", 
-          fps_num, fps_den, output_width, output_height, capture_audio, capture_microphone, video_bitrate, video_encoder_guid);
+    info!("create_sink_writer - Settings: {:?}", settings);
+    let SinkWriterSettings {
+        fps_num,
+        fps_den,
+        output_width,
+        output_height,
+        audio: has_audio,
+        video_encoder,
+        low_latency,
+        fragmented,
+        sink_options,
+        color_space,
+        video_encoding,
+        audio_codec,
+        ..
+    } = *settings;
 
     // Create and configure attributes
     info!("create_sink_writer - Creating sink attributes");
-    let attributes = create_sink_attributes()?;
+    let attributes = create_sink_attributes(
+        sink_options.low_latency.unwrap_or(low_latency),
+        fragmented,
+        &sink_options,
+    )?;
     info!("create_sink_writer - Sink attributes created successfully");
 
-    // Create sink writer
-    info!(
-        "create_sink_writer - Creating sink writer from URL: {}",
-        output_path
-    );
-    let sink_writer: IMFSinkWriter = MFCreateSinkWriterFromURL(
-        &windows::core::HSTRING::from(output_path),
-        None,
-        attributes.as_ref(),
-    )?;
+    // With a frame callback the MP4 sink is created here and wrapped, which fixes its streams
+    let fixed_streams = encoded_frames.is_some();
+    let sink_writer: IMFSinkWriter = match encoded_frames {
+        Some(callback) => {
+            info!(
+                "create_sink_writer - Creating tapped MP4 sink for: {}",
+                output_path
+            );
+            let media_sink = create_mp4_sink(
+                output_path,
+                &create_video_output_type(
+                    fps_num,
+                    fps_den,
+                    output_width,
+                    output_height,
+                    &video_encoder,
+                    color_space,
+                    video_encoding,
+                )?,
+                if has_audio {
+                    Some(create_audio_output_type_for(audio_codec)?)
+                } else {
+                    None
+                },
+                fragmented,
+            )?;
+            let encoder_type = if video_encoder == MFVideoFormat_HEVC {
+                VideoEncoderType::HEVC
+            } else {
+                VideoEncoderType::H264
+            };
+            let media_sink = TapMediaSink::wrap(media_sink, encoder_type, callback)?;
+            MFCreateSinkWriterFromMediaSink(&media_sink, attributes.as_ref())?
+        }
+        None => {
+            info!(
+                "create_sink_writer - Creating sink writer from URL: {}",
+                output_path
+            );
+            MFCreateSinkWriterFromURL(
+                &windows::core::HSTRING::from(output_path),
+                None,
+                attributes.as_ref(),
+            )?
+        }
+    };
     info!("create_sink_writer - Sink writer created successfully");
 
     let mut current_stream_index = 0;
@@ -44,23 +206,20 @@ pub unsafe fn create_sink_writer(
         "create_sink_writer - Configuring video stream at index {}",
         current_stream_index
     );
-    configure_video_stream(
-        &sink_writer,
-        fps_num,
-        fps_den,
-        output_width,
-        output_height,
-        video_bitrate,
-        video_encoder_guid,
-    )?;
+    configure_video_stream(&sink_writer, settings, fixed_streams)?;
     info!("create_sink_writer - Video stream configured successfully");
     current_stream_index += 1;
 
     // Configure a single audio stream if either audio source is enabled
-    if capture_audio || capture_microphone {
+    if has_audio {
         info!("create_sink_writer - Audio capture enabled, configuring mixed audio stream at index {}", current_stream_index);
         // Use a new function that configures a stream suitable for mixed audio
-        configure_mixed_audio_stream(&sink_writer, current_stream_index)?;
+        configure_mixed_audio_stream(
+            &sink_writer,
+            current_stream_index,
+            audio_codec,
+            fixed_streams,
+        )?;
         info!("create_sink_writer - Mixed audio stream configured successfully");
         // current_stream_index += 1;
     } else {
@@ -74,6 +233,8 @@ pub unsafe fn create_sink_writer(
 unsafe fn configure_mixed_audio_stream(
     sink_writer: &IMFSinkWriter,
     stream_index: u32,
+    audio_codec: AudioCodec,
+    fixed_streams: bool,
 ) -> Result<()> {
     info!(
         "configure_mixed_audio_stream - Starting for stream index {}",
@@ -81,8 +242,8 @@ unsafe fn configure_mixed_audio_stream(
     );
 
     // Create output type
-    info!("configure_mixed_audio_stream - Creating audio output type");
-    let audio_output_type = create_audio_output_type()?;
+    info!("configure_mixed_audio_stream - Creating {:?} audio output type", audio_codec);
+    let audio_output_type = create_audio_output_type_for(audio_codec)?;
     info!("configure_mixed_audio_stream - Audio output type created successfully");
 
     // Create input type suitable for mixed audio (stereo, 16-bit, 44100Hz)
@@ -91,9 +252,13 @@ unsafe fn configure_mixed_audio_stream(
     info!("configure_mixed_audio_stream - Mixed audio input type created successfully");
 
     // Add stream and set input type
-    info!("configure_mixed_audio_stream - Adding audio stream to sink writer");
-    sink_writer.AddStream(&audio_output_type)?;
-    info!("configure_mixed_audio_stream - Audio stream added successfully");
+    if fixed_streams {
+        info!("configure_mixed_audio_stream - Audio stream provided by the media sink");
+    } else {
+        info!("configure_mixed_audio_stream - Adding audio stream to sink writer");
+        sink_writer.AddStream(&audio_output_type)?;
+        info!("configure_mixed_audio_stream - Audio stream added successfully");
+    }
 
     info!(
         "configure_mixed_audio_stream - Setting input media type for stream {}",
@@ -106,6 +271,41 @@ unsafe fn configure_mixed_audio_stream(
     Ok(())
 }
 
+unsafe fn create_audio_output_type_for(audio_codec: AudioCodec) -> Result<IMFMediaType> {
+    match audio_codec {
+        AudioCodec::Aac { bitrate } => create_audio_output_type(bitrate),
+        AudioCodec::Flac => create_flac_output_type(),
+        // Written as captured, no encoder
+        AudioCodec::Pcm => create_mixed_audio_input_type(),
+    }
+}
+
+/// Create the (fragmented) MP4 media sink the sink writer would otherwise create from the URL
+unsafe fn create_mp4_sink(
+    output_path: &str,
+    video_type: &IMFMediaType,
+    audio_type: Option<IMFMediaType>,
+    fragmented: bool,
+) -> Result<IMFMediaSink> {
+    let byte_stream = MFCreateFile(
+        MF_ACCESSMODE_READWRITE,
+        MF_OPENMODE_DELETE_IF_EXIST,
+        MF_FILEFLAGS_NONE,
+        &windows::core::HSTRING::from(output_path),
+    )?;
+    if !fragmented {
+        return MFCreateMPEG4MediaSink(&byte_stream, video_type, audio_type.as_ref());
+    }
+
+    let media_sink = MFCreateFMPEG4MediaSink(&byte_stream, video_type, audio_type.as_ref())?;
+    // Same one GOP per fragment as the sink writer attributes ask for
+    match media_sink.cast::<IMFAttributes>() {
+        Ok(attrs) => attrs.SetUINT32(&MF_MPEG4SINK_MAX_CODED_SEQUENCES_PER_FRAGMENT, 1)?,
+        Err(_) => warn!("create_mp4_sink - Fragment size cannot be set on the MP4 sink"),
+    }
+    Ok(media_sink)
+}
+
 unsafe fn create_mixed_audio_input_type() -> Result<IMFMediaType> {
     info!("create_mixed_audio_input_type - Starting");
     let input_type: IMFMediaType = MFCreateMediaType()?;
@@ -136,7 +336,11 @@ unsafe fn create_mixed_audio_input_type() -> Result<IMFMediaType> {
     Ok(input_type)
 }
 
-unsafe fn create_sink_attributes() -> Result<Option<IMFAttributes>> {
+unsafe fn create_sink_attributes(
+    low_latency: bool,
+    fragmented: bool,
+    options: &SinkWriterOptions,
+) -> Result<Option<IMFAttributes>> {
     info!("create_sink_attributes - Starting");
     let mut attributes: Option<IMFAttributes> = None;
     info!("create_sink_attributes - Creating attributes");
@@ -144,8 +348,32 @@ unsafe fn create_sink_attributes() -> Result<Option<IMFAttributes>> {
     info!("create_sink_attributes - Attributes created");
 
     if let Some(attrs) = &attributes {
-        info!("create_sink_attributes - Setting MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS to 1");
-        attrs.SetUINT32(&MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, 1)?;
+        info!(
+            "create_sink_attributes - Setting MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS to {}",
+            options.hardware_transforms as u32
+        );
+        attrs.SetUINT32(
+            &MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS,
+            options.hardware_transforms as u32,
+        )?;
+        if options.disable_throttling {
+            // WriteSample returns right away and the sink writer buffers whatever the encoder
+            // has not taken yet, instead of stalling the processing thread at high bitrates
+            info!("create_sink_attributes - Setting MF_SINK_WRITER_DISABLE_THROTTLING to 1");
+            attrs.SetUINT32(&MF_SINK_WRITER_DISABLE_THROTTLING, 1)?;
+        }
+        if low_latency {
+            // Also forwarded to the encoder MFT
+            info!("create_sink_attributes - Setting MF_LOW_LATENCY to 1");
+            attrs.SetUINT32(&MF_LOW_LATENCY, 1)?;
+        }
+        if fragmented {
+            // Every GOP is written out as a self-contained moof/mdat fragment, so everything up
+            // to the last keyframe survives a crash before Finalize
+            info!("create_sink_attributes - Writing fragmented MP4, one GOP per fragment");
+            attrs.SetGUID(&MF_TRANSCODE_CONTAINERTYPE, &MFTranscodeContainerType_FMPEG4)?;
+            attrs.SetUINT32(&MF_MPEG4SINK_MAX_CODED_SEQUENCES_PER_FRAGMENT, 1)?;
+        }
         info!("create_sink_attributes - All attributes set successfully");
     } else {
         info!("create_sink_attributes - Attributes object is None, skipping settings");
@@ -157,13 +385,23 @@ unsafe fn create_sink_attributes() -> Result<Option<IMFAttributes>> {
 
 unsafe fn configure_video_stream(
     sink_writer: &IMFSinkWriter,
-    fps_num: u32,
-    fps_den: u32,
-    output_width: u32,
-    output_height: u32,
-    video_bitrate: u32,
-    video_encoder_id: &GUID,
+    settings: &SinkWriterSettings,
+    fixed_streams: bool,
 ) -> Result<()> {
+    let SinkWriterSettings {
+        fps_num,
+        fps_den,
+        output_width,
+        output_height,
+        video_bitrate,
+        capped_bitrate,
+        video_encoder: video_encoder_id,
+        low_latency,
+        color_space,
+        pixel_pipeline,
+        video_encoding,
+        ..
+    } = *settings;
     info!("configure_video_stream - Starting with fps={}/{}, resolution={}x{}, bitrate={}, encoder={:?}", 
           fps_num, fps_den, output_width, output_height, video_bitrate, video_encoder_id);
 
@@ -174,13 +412,22 @@ unsafe fn configure_video_stream(
         fps_den,
         output_width,
         output_height,
-        video_encoder_id,
+        &video_encoder_id,
+        color_space,
+        video_encoding,
     )?;
     info!("configure_video_stream - Video output type created successfully");
 
     // Create input media type
     info!("configure_video_stream - Creating video input type");
-    let video_input_type = create_video_input_type(fps_num, fps_den, output_width, output_height)?;
+    let video_input_type = create_video_input_type(
+        fps_num,
+        fps_den,
+        output_width,
+        output_height,
+        color_space,
+        pixel_pipeline,
+    )?;
     info!("configure_video_stream - Video input type created successfully");
 
     // Configure encoder with default settings
@@ -188,13 +435,22 @@ unsafe fn configure_video_stream(
         "configure_video_stream - Creating encoder configuration with bitrate {}",
         video_bitrate
     );
-    let config_attrs = create_encoder_config(video_bitrate)?;
+    let config_attrs = create_encoder_config(
+        video_bitrate,
+        capped_bitrate,
+        low_latency,
+        video_encoding.constant_qp,
+    )?;
     info!("configure_video_stream - Encoder configuration created successfully");
 
     // First add stream, then set input type
-    info!("configure_video_stream - Adding video stream to sink writer");
-    sink_writer.AddStream(&video_output_type)?;
-    info!("configure_video_stream - Video stream added successfully");
+    if fixed_streams {
+        info!("configure_video_stream - Video stream provided by the media sink");
+    } else {
+        info!("configure_video_stream - Adding video stream to sink writer");
+        sink_writer.AddStream(&video_output_type)?;
+        info!("configure_video_stream - Video stream added successfully");
+    }
 
     info!("configure_video_stream - Setting input media type for stream 0");
     match sink_writer.SetInputMediaType(0, &video_input_type, config_attrs.as_ref()) {
@@ -203,6 +459,10 @@ unsafe fn configure_video_stream(
     }
     info!("configure_video_stream - Input media type set successfully");
 
+    if let Some(coding) = video_encoding.entropy_coding {
+        encoder::set_entropy_coding(sink_writer, 0, coding)?;
+    }
+
     info!("configure_video_stream - Completed successfully");
     Ok(())
 }
@@ -213,6 +473,8 @@ unsafe fn create_video_output_type(
     output_width: u32,
     output_height: u32,
     video_encoder_id: &GUID,
+    color_space: ColorSpace,
+    video_encoding: VideoEncoding,
 ) -> Result<IMFMediaType> {
     info!(
         "create_video_output_type - Starting with fps={}/{}, resolution={}x{}, encoder={:?}",
@@ -255,8 +517,26 @@ unsafe fn create_video_output_type(
         MFVideoInterlace_Progressive.0.try_into().unwrap(),
     )?;
 
+    info!(
+        "create_video_output_type - Setting color space {:?}",
+        color_space
+    );
+    color_space.apply(&output_type)?;
+
+    let encoder_type = if video_encoder_id == &MFVideoFormat_HEVC {
+        VideoEncoderType::HEVC
+    } else {
+        VideoEncoderType::H264
+    };
+
     // Set the appropriate profile based on the encoder type
-    if video_encoder_id == &MFVideoFormat_H264 {
+    if let Some(profile) = video_encoding.profile {
+        info!(
+            "create_video_output_type - Setting requested profile {:?}",
+            profile
+        );
+        output_type.SetUINT32(&MF_MT_VIDEO_PROFILE, profile.media_type_value(encoder_type))?;
+    } else if video_encoder_id == &MFVideoFormat_H264 {
         info!("create_video_output_type - Detected H264 encoder, setting profile to High");
         output_type.SetUINT32(
             &MF_MT_VIDEO_PROFILE,
@@ -274,6 +554,18 @@ unsafe fn create_video_output_type(
         info!("create_video_output_type - Unknown encoder type, no specific profile set");
     }
 
+    if let Some(level) = video_encoding.level {
+        info!(
+            "create_video_output_type - Setting level {}.{}",
+            level / 10,
+            level % 10
+        );
+        output_type.SetUINT32(
+            &MF_MT_VIDEO_LEVEL,
+            encoder::level_value(level, encoder_type),
+        )?;
+    }
+
     info!("create_video_output_type - Completed successfully");
     Ok(output_type)
 }
@@ -283,6 +575,8 @@ unsafe fn create_video_input_type(
     fps_den: u32,
     output_width: u32,
     output_height: u32,
+    color_space: ColorSpace,
+    pixel_pipeline: PixelPipeline,
 ) -> Result<IMFMediaType> {
     info!(
         "create_video_input_type - Starting with fps={}/{}, resolution={}x{}",
@@ -295,8 +589,18 @@ unsafe fn create_video_input_type(
 
     info!("create_video_input_type - Setting MF_MT_MAJOR_TYPE to MFMediaType_Video");
     input_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
-    info!("create_video_input_type - Setting MF_MT_SUBTYPE to MFVideoFormat_NV12");
-    input_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_NV12)?;
+    // BGRA frames are handed over as they were captured and the encoder converts them
+    let rgb_input = pixel_pipeline == PixelPipeline::Rgb;
+    let (subtype, bytes_per_pixel) = if rgb_input {
+        (MFVideoFormat_ARGB32, 4)
+    } else {
+        (MFVideoFormat_NV12, 1)
+    };
+    info!(
+        "create_video_input_type - Setting MF_MT_SUBTYPE to {:?}",
+        subtype
+    );
+    input_type.SetGUID(&MF_MT_SUBTYPE, &subtype)?;
 
     info!(
         "create_video_input_type - Setting MF_MT_FRAME_RATE to {}/{}",
@@ -325,17 +629,37 @@ unsafe fn create_video_input_type(
     info!("create_video_input_type - Setting MF_MT_PIXEL_ASPECT_RATIO to 1:1");
     input_type.SetUINT64(&MF_MT_PIXEL_ASPECT_RATIO, (1u64 << 32) | 1u64)?;
 
+    let stride = output_width * bytes_per_pixel;
     info!(
         "create_video_input_type - Setting MF_MT_DEFAULT_STRIDE to {}",
-        output_width
+        stride
     );
-    input_type.SetUINT32(&MF_MT_DEFAULT_STRIDE, output_width as u32)?;
+    input_type.SetUINT32(&MF_MT_DEFAULT_STRIDE, stride)?;
+
+    if rgb_input {
+        info!("create_video_input_type - Setting full range for RGB input");
+        input_type.SetUINT32(&MF_MT_VIDEO_NOMINAL_RANGE, MFNominalRange_0_255.0 as u32)?;
+    } else {
+        info!(
+            "create_video_input_type - Setting color space {:?}",
+            color_space
+        );
+        color_space.apply(&input_type)?;
+    }
 
     info!("create_video_input_type - Completed successfully");
     Ok(input_type)
 }
 
-unsafe fn create_encoder_config(video_bitrate: u32) -> Result<Option<IMFAttributes>> {
+/// With `capped_bitrate` the encoder runs in peak constrained VBR with `video_bitrate` as both
+/// mean and peak, so the file cannot outgrow the size it was budgeted for. With a
+/// `constant_qp` it encodes every frame at that QP and the bitrate is not set at all
+unsafe fn create_encoder_config(
+    video_bitrate: u32,
+    capped_bitrate: bool,
+    low_latency: bool,
+    constant_qp: Option<u64>,
+) -> Result<Option<IMFAttributes>> {
     info!(
         "create_encoder_config - Starting with bitrate {}",
         video_bitrate
@@ -347,24 +671,55 @@ unsafe fn create_encoder_config(video_bitrate: u32) -> Result<Option<IMFAttribut
     info!("create_encoder_config - Attributes created");
 
     if let Some(attrs) = &config_attrs {
-        info!("create_encoder_config - Setting CODECAPI_AVEncCommonRateControlMode to GlobalVBR");
-        attrs.SetUINT32(
-            &CODECAPI_AVEncCommonRateControlMode,
-            eAVEncCommonRateControlMode_GlobalVBR.0.try_into().unwrap(),
-        )?;
-
-        info!(
-            "create_encoder_config - Setting CODECAPI_AVEncCommonMeanBitRate to {}",
-            video_bitrate
-        );
-        attrs.SetUINT32(&CODECAPI_AVEncCommonMeanBitRate, video_bitrate)?;
-
+        if let Some(qp) = constant_qp {
+            info!("create_encoder_config - Setting CODECAPI_AVEncCommonRateControlMode to Quality at QP {}", qp);
+            attrs.SetUINT32(
+                &CODECAPI_AVEncCommonRateControlMode,
+                eAVEncCommonRateControlMode_Quality.0.try_into().unwrap(),
+            )?;
+            attrs.SetUINT64(&CODECAPI_AVEncVideoEncodeQP, qp)?;
+        } else if capped_bitrate {
+            info!("create_encoder_config - Setting CODECAPI_AVEncCommonRateControlMode to PeakConstrainedVBR");
+            attrs.SetUINT32(
+                &CODECAPI_AVEncCommonRateControlMode,
+                eAVEncCommonRateControlMode_PeakConstrainedVBR.0.try_into().unwrap(),
+            )?;
+        } else {
+            info!("create_encoder_config - Setting CODECAPI_AVEncCommonRateControlMode to GlobalVBR");
+            attrs.SetUINT32(
+                &CODECAPI_AVEncCommonRateControlMode,
+                eAVEncCommonRateControlMode_GlobalVBR.0.try_into().unwrap(),
+            )?;
+        }
+
+        if constant_qp.is_none() {
+            info!(
+                "create_encoder_config - Setting CODECAPI_AVEncCommonMeanBitRate to {}",
+                video_bitrate
+            );
+            attrs.SetUINT32(&CODECAPI_AVEncCommonMeanBitRate, video_bitrate)?;
+        }
+
+        if capped_bitrate && constant_qp.is_none() {
+            info!(
+                "create_encoder_config - Setting CODECAPI_AVEncCommonMaxBitRate to {}",
+                video_bitrate
+            );
+            attrs.SetUINT32(&CODECAPI_AVEncCommonMaxBitRate, video_bitrate)?;
+        }
+
+        // B-frames delay output by the reorder depth, so they stay off in every mode
         info!("create_encoder_config - Setting CODECAPI_AVEncMPVDefaultBPictureCount to 0");
         attrs.SetUINT32(&CODECAPI_AVEncMPVDefaultBPictureCount, 0)?;
 
         info!("create_encoder_config - Setting CODECAPI_AVEncCommonLowLatency to 1");
         attrs.SetUINT32(&CODECAPI_AVEncCommonLowLatency, 1)?;
 
+        if low_latency {
+            info!("create_encoder_config - Setting CODECAPI_AVLowLatencyMode to 1");
+            attrs.SetUINT32(&CODECAPI_AVLowLatencyMode, 1)?;
+        }
+
         info!("create_encoder_config - All encoder attributes set successfully");
     } else {
         info!("create_encoder_config - Attributes object is None, skipping settings");
@@ -374,7 +729,7 @@ unsafe fn create_encoder_config(video_bitrate: u32) -> Result<Option<IMFAttribut
     Ok(config_attrs)
 }
 
-unsafe fn create_audio_output_type() -> Result<IMFMediaType> {
+unsafe fn create_audio_output_type(bitrate: u32) -> Result<IMFMediaType> {
     info!("create_audio_output_type - Starting");
 
     info!("create_audio_output_type - Creating media type");
@@ -396,43 +751,76 @@ unsafe fn create_audio_output_type() -> Result<IMFMediaType> {
     info!("create_audio_output_type - Setting MF_MT_AUDIO_NUM_CHANNELS to 2");
     output_type.SetUINT32(&MF_MT_AUDIO_NUM_CHANNELS, 2)?;
 
-    info!("create_audio_output_type - Setting MF_MT_AUDIO_AVG_BYTES_PER_SECOND to 16000");
-    output_type.SetUINT32(&MF_MT_AUDIO_AVG_BYTES_PER_SECOND, 16000)?;
+    info!(
+        "create_audio_output_type - Setting MF_MT_AUDIO_AVG_BYTES_PER_SECOND to {}",
+        bitrate / 8
+    );
+    output_type.SetUINT32(&MF_MT_AUDIO_AVG_BYTES_PER_SECOND, bitrate / 8)?;
 
     info!("create_audio_output_type - Completed successfully");
     Ok(output_type)
 }
 
+unsafe fn create_flac_output_type() -> Result<IMFMediaType> {
+    info!("create_flac_output_type - Starting");
+    let output_type: IMFMediaType = MFCreateMediaType()?;
+    output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Audio)?;
+    output_type.SetGUID(&MF_MT_SUBTYPE, &MFAudioFormat_FLAC)?;
+    output_type.SetUINT32(&MF_MT_AUDIO_BITS_PER_SAMPLE, 16)?;
+    output_type.SetUINT32(&MF_MT_AUDIO_SAMPLES_PER_SECOND, 44100)?;
+    output_type.SetUINT32(&MF_MT_AUDIO_NUM_CHANNELS, 2)?;
+    info!("create_flac_output_type - Completed successfully");
+    Ok(output_type)
+}
+
+/// Recorders (and other users) currently holding Media Foundation started
+static MEDIA_FOUNDATION_USERS: Mutex<usize> = Mutex::new(0);
+
+/// Start Media Foundation for one user, initializing COM on the calling thread
+///
+/// Media Foundation is started by the first user and shut down by `shutdown_media_foundation`
+/// when the last one is done, so several recorders can run in the same process.
 pub unsafe fn init_media_foundation() -> Result<()> {
     info!("init_media_foundation - Starting");
 
-    use windows::Win32::System::Com::*;
-
     info!("init_media_foundation - Initializing COM with COINIT_MULTITHREADED");
-    CoInitializeEx(Some(ptr::null()), COINIT_MULTITHREADED)?;
+    ensure_com_initialized()?;
     info!("init_media_foundation - COM initialized successfully");
 
-    info!("init_media_foundation - Starting Media Foundation with MF_VERSION and MFSTARTUP_FULL");
-    MFStartup(MF_VERSION, MFSTARTUP_FULL)?;
-    info!("init_media_foundation - Media Foundation started successfully");
+    let mut users = MEDIA_FOUNDATION_USERS.lock().unwrap();
+    if *users == 0 {
+        info!("init_media_foundation - Starting Media Foundation with MF_VERSION and MFSTARTUP_FULL");
+        MFStartup(MF_VERSION, MFSTARTUP_FULL)?;
+        info!("init_media_foundation - Media Foundation started successfully");
+    }
+    *users += 1;
 
-    info!("init_media_foundation - Completed successfully");
+    info!("init_media_foundation - Completed successfully ({} users)", *users);
     Ok(())
 }
 
+/// Release one user's hold on Media Foundation, shutting it down after the last user
+///
+/// COM is left initialized, since this may run on a different thread than the matching
+/// `init_media_foundation`.
 pub unsafe fn shutdown_media_foundation() -> Result<()> {
     info!("shutdown_media_foundation - Starting");
 
-    use windows::Win32::System::Com::*;
+    let mut users = MEDIA_FOUNDATION_USERS.lock().unwrap();
+    if *users == 0 {
+        warn!("shutdown_media_foundation - Media Foundation is not started");
+        return Ok(());
+    }
+    *users -= 1;
+    if *users > 0 {
+        info!("shutdown_media_foundation - Still used by {} others", *users);
+        return Ok(());
+    }
 
     info!("shutdown_media_foundation - Shutting down Media Foundation");
     MFShutdown()?;
     info!("shutdown_media_foundation - Media Foundation shut down successfully");
 
-    info!("shutdown_media_foundation - Uninitializing COM");
-    CoUninitialize();
-    info!("shutdown_media_foundation - COM uninitialized");
-
     info!("shutdown_media_foundation - Completed successfully");
     Ok(())
 }
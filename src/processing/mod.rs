@@ -1,44 +1,255 @@
 pub mod audio;
+pub mod audio_filter;
+pub mod compositor;
+pub mod drift;
+pub mod dump;
+pub mod encoder;
+pub mod filter;
+pub mod gap;
+pub mod high_fps;
+pub mod idle;
+pub mod image;
+pub mod mask;
 pub mod media;
+pub mod overlay;
+pub mod pause;
+pub mod preview;
+pub mod profiler;
+pub mod replay;
+pub mod scaler;
+pub mod scene;
+pub mod segment;
+pub mod stamp;
+pub mod start;
+pub mod tags;
+pub mod tap;
 pub mod video;
 
-use audio::AudioMixer;
-use log::{debug, error, info, trace, warn};
-use std::sync::atomic::{AtomicBool, Ordering};
+use audio::{
+    AudioLevels, AudioMixer, MicrophoneFilterChain, MicrophoneFilters, MixerControls, MixerStats,
+    SourceId, SourceKind, MICROPHONE_SOURCE, SYSTEM_AUDIO_SOURCE,
+};
+use audio_filter::{AudioFilterChain, AudioFilters, AUDIO_MIX};
+use dump::{CaptureDumpWriter, DumpRecordKind};
+use filter::VideoFilterChain;
+use gap::AudioGapFiller;
+use idle::IdleThrottle;
+use high_fps::OutputDecimator;
+use image::{ImageSequenceWriter, ThumbnailCapture};
+use mask::{MaskRect, PrivacyMasker};
+use overlay::{InputOverlay, InputOverlayConfig};
+use pause::{PauseGate, PauseStream};
+use preview::{PreviewTap, SharedPreviewWriter};
+use profiler::{PipelineProfiler, PipelineStage};
+use replay::ReplayBuffer;
+use scaler::{GpuScaler, ScalingQuality};
+use scene::SceneCutDetector;
+use segment::SegmentOutput;
+use stamp::FrameStamper;
+use start::StartGate;
+use tags::FrameTagger;
+use video::{AspectMode, ColorSpace, ConverterLayout, PixelPipeline, Rotation, VideoTransform};
+use crate::logging::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::{Arc, Mutex};
 use windows::core::Result;
+use windows::Win32::Foundation::RECT;
 use windows::Win32::Graphics::Direct3D11::ID3D11Device;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_NV12;
+use windows::Win32::Media::MediaFoundation::{IMFSample, IMFTransform};
 
-use crate::types::{ReplayBuffer, SendableSample, SendableWriter, TexturePool};
-
-pub fn process_samples(
-    writer: SendableWriter,
-    rec_video: Receiver<SendableSample>,
-    rec_audio: Receiver<SendableSample>,
-    rec_microphone: Receiver<SendableSample>,
-    rec_window_info: Receiver<(Option<(i32, i32)>, Option<(u32, u32)>)>,
-    recording: Arc<AtomicBool>,
-    input_width: u32,
-    input_height: u32,
-    output_width: u32,
-    output_height: u32,
-    device: Arc<ID3D11Device>,
-    capture_audio: bool,
-    capture_microphone: bool,
-    system_volume: Option<f32>,
-    microphone_volume: Option<f32>,
-    replay_buffer: Option<Arc<ReplayBuffer>>,
-    initial_window_position: Option<(i32, i32)>,
-    initial_window_size: Option<(u32, u32)>,
-    texture_pool: Arc<TexturePool>,
-) -> Result<()> {
+use crate::capture::{AudioPacket, InputEvent};
+use crate::error::ThreadSource;
+use crate::recorder::errors::ErrorCollector;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::types::memory::texture_bytes;
+use crate::types::{
+    hns_to_duration, FrameCounters, FrameReceiver, SendableSample, SendableWriter, TexturePool,
+    WindowGeometry,
+};
+
+/// Lag behind real time after which the encoder is reported as back-pressuring
+const BACKPRESSURE_LAG: std::time::Duration = std::time::Duration::from_millis(500);
+/// Minimum time between two back-pressure events
+const BACKPRESSURE_EVENT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// How often the produced bitrate is checked against the target
+const BITRATE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// Time covered by each pipeline profile event
+const PROFILE_EVENT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Everything the processing thread of one recording works with, gathered once when the
+/// recording starts
+pub struct ProcessingContext {
+    /// Frames from the capture thread
+    pub rec_video: FrameReceiver,
+    pub rec_audio: Receiver<SendableSample>,
+    pub rec_microphone: Receiver<SendableSample>,
+    /// Audio of each process `AudioSource::Processes` captures
+    pub rec_process_audio: Receiver<AudioPacket>,
+    /// Extra microphones by name
+    pub rec_extra_microphones: Vec<(String, Receiver<SendableSample>)>,
+    /// Position and size of the captured window whenever they change
+    pub rec_window_info: Receiver<WindowGeometry>,
+    /// Cleared when the recording stops
+    pub recording: Arc<AtomicBool>,
+    /// Size of the captured frames
+    pub input_width: u32,
+    pub input_height: u32,
+    /// Size of the encoded video
+    pub output_width: u32,
+    pub output_height: u32,
+    pub device: Arc<ID3D11Device>,
+    pub capture_audio: bool,
+    /// Whether system audio arrives per captured process, so the mixer only has a `system`
+    /// source if the capture falls back to desktop audio
+    pub per_process_audio: bool,
+    pub capture_microphone: bool,
+    /// Whether the microphone has delivered audio yet; the mixer only waits for it once set
+    pub microphone_attached: Arc<AtomicBool>,
+    /// Performance counter value the audio timestamps count from, None when not recording live
+    pub audio_start_qpc: Option<u64>,
+    /// Drop frames until the first non-blank one and start both video and audio there
+    pub start_gate: bool,
+    pub mixer_controls: MixerControls,
+    /// Loudness the mix is normalized to, in LUFS
+    pub audio_normalization: Option<f32>,
+    /// Keeps the last seconds of unencoded NV12 frames and audio for replay saves
+    pub replay_buffer: Option<Arc<ReplayBuffer>>,
+    /// Size of the frames encoded for the replay buffer
+    pub replay_size: Option<(u32, u32)>,
+    /// Where the window was when the recording started
+    pub initial_window_position: Option<(i32, i32)>,
+    pub initial_window_size: Option<(u32, u32)>,
+    pub texture_pool: Arc<TexturePool>,
+    /// Also writes every captured frame and sample to a capture dump
+    pub capture_dump: Option<Arc<Mutex<CaptureDumpWriter>>>,
+    pub audio_levels: Arc<Mutex<AudioLevels>>,
+    pub mixer_stats: Arc<Mutex<MixerStats>>,
+    pub microphone_filters: MicrophoneFilters,
+    /// Custom DSP on each audio source and on the mix
+    pub audio_filters: AudioFilters,
+    /// Pre-roll segment being written alongside the recording, if one was started
+    pub segment_output: Arc<Mutex<Option<SegmentOutput>>>,
+    pub privacy_masks: Arc<Mutex<Vec<MaskRect>>>,
+    /// Rounded window corners masked out of each frame
+    pub corner_radii: Arc<Mutex<(u32, u32)>>,
+    /// Clicks and keys drawn over the video, with the events from the input hooks
+    pub input_overlay: Option<(InputOverlayConfig, Receiver<InputEvent>)>,
+    pub scaling_quality: ScalingQuality,
+    pub aspect_mode: AspectMode,
+    pub video_transform: VideoTransform,
+    pub color_space: ColorSpace,
+    pub pixel_pipeline: PixelPipeline,
+    /// Burn the frame number and timestamp into each frame
+    pub debug_frame_stamp: bool,
+    pub low_latency: bool,
+    /// Change between frames above which a keyframe is forced
+    pub scene_cut_threshold: Option<f32>,
+    /// Bitrate the produced video is checked against
+    pub target_bitrate: u32,
+    pub thumbnail: Option<Arc<ThumbnailCapture>>,
+    /// Also saves frames as images
+    pub image_sequence: Option<ImageSequenceWriter>,
+    /// Drops frames down to the output frame rate when capturing faster
+    pub output_decimator: Option<OutputDecimator>,
+    /// Also writes every captured frame when capturing faster than the output
+    pub high_fps_output: Option<SegmentOutput>,
+    pub preview: Option<PreviewTap>,
+    /// Preview frames shared with other processes
+    pub shared_preview: Option<SharedPreviewWriter>,
+    /// Custom shaders run on each frame before encoding
+    pub video_filters: Option<VideoFilterChain>,
+    /// Lowers the frame rate while nothing on screen changes
+    pub idle_throttle: Option<Arc<IdleThrottle>>,
+    /// Samples are dropped while it is set
+    pub paused: Arc<AtomicBool>,
+    /// Shift applied to audio timestamps, in 100ns units
+    pub av_sync_offset: Arc<AtomicI64>,
+    /// How often a `Stats` event is emitted
+    pub stats_interval: Option<std::time::Duration>,
+    /// Attaches tags and markers to the frames they were added during
+    pub frame_tagger: Option<FrameTagger>,
+    pub counters: Arc<FrameCounters>,
+    pub profiler: Option<Arc<PipelineProfiler>>,
+    pub events: EventEmitter,
+    pub errors: ErrorCollector,
+}
+
+pub fn process_samples(writer: Option<SendableWriter>, context: ProcessingContext) -> Result<()> {
+    let ProcessingContext {
+        rec_video,
+        rec_audio,
+        rec_microphone,
+        rec_process_audio,
+        mut rec_extra_microphones,
+        rec_window_info,
+        recording,
+        mut input_width,
+        mut input_height,
+        output_width,
+        output_height,
+        mut device,
+        capture_audio,
+        per_process_audio,
+        capture_microphone,
+        microphone_attached,
+        audio_start_qpc,
+        start_gate,
+        mixer_controls,
+        audio_normalization,
+        replay_buffer,
+        replay_size,
+        initial_window_position,
+        initial_window_size,
+        mut texture_pool,
+        capture_dump,
+        audio_levels,
+        mixer_stats,
+        microphone_filters,
+        audio_filters,
+        segment_output,
+        privacy_masks,
+        corner_radii,
+        input_overlay,
+        scaling_quality,
+        aspect_mode,
+        video_transform,
+        color_space,
+        pixel_pipeline,
+        debug_frame_stamp,
+        low_latency,
+        scene_cut_threshold,
+        target_bitrate,
+        thumbnail,
+        mut image_sequence,
+        mut output_decimator,
+        mut high_fps_output,
+        mut preview,
+        mut shared_preview,
+        mut video_filters,
+        idle_throttle,
+        paused,
+        av_sync_offset,
+        stats_interval,
+        mut frame_tagger,
+        counters,
+        profiler,
+        events,
+        errors,
+    } = context;
     info!("Starting sample processing");
 
     unsafe {
+        // In low latency mode the encoder must keep up with capture
         windows::Win32::System::Threading::SetThreadPriority(
             windows::Win32::System::Threading::GetCurrentThread(),
-            windows::Win32::System::Threading::THREAD_PRIORITY_BELOW_NORMAL,
+            if low_latency {
+                windows::Win32::System::Threading::THREAD_PRIORITY_NORMAL
+            } else {
+                windows::Win32::System::Threading::THREAD_PRIORITY_BELOW_NORMAL
+            },
         );
     }
 
@@ -58,25 +269,56 @@ pub fn process_samples(
     );
 
     // Create audio mixer if we need to mix audio
+    let mut system_source = None;
+    let mut microphone_source = None;
     let mut audio_mixer = if capture_audio || capture_microphone {
-        let mut mixer = AudioMixer::new(44100, 16, 2, capture_audio && capture_microphone);
+        let mut mixer = AudioMixer::new(44100, 16, 2);
+        // Captured processes are added as they deliver audio, each as a source of its own
+        system_source =
+            (capture_audio && !per_process_audio).then(|| mixer.add_source(SYSTEM_AUDIO_SOURCE));
+        microphone_source = capture_microphone.then(|| mixer.add_source(MICROPHONE_SOURCE));
+        if let (Some(_), Some(microphone)) = (system_source, microphone_source) {
+            // Only wait for the microphone once it has attached, and keep it on the system clock
+            mixer.set_active(microphone, microphone_attached.load(Ordering::Relaxed));
+            mixer.correct_drift(microphone);
+        }
 
-        // Set the volume/gain levels from parameters, using default of 1.0 if None
-        let sys_vol = system_volume.unwrap_or(1.0);
-        let mic_vol = microphone_volume.unwrap_or(1.0);
+        // Gains and mute flags can be changed while recording
+        mixer.set_controls(mixer_controls.clone());
+        mixer.set_level_meter(audio_levels);
+        mixer.set_stats_sink(mixer_stats);
+        if let Some(target_lufs) = audio_normalization {
+            mixer.set_normalization(target_lufs);
+        }
 
-        mixer.set_system_volume(sys_vol);
-        mixer.set_microphone_volume(mic_vol);
+        Some(mixer)
+    } else {
+        None
+    };
 
-        info!(
-            "Audio mixer created with system gain: {:.2}, microphone gain: {:.2}",
-            sys_vol, mic_vol
-        );
+    // Keeps the written audio continuous across stalls; only live recordings are compared
+    // against real time
+    let mut gap_filler = AudioGapFiller::new(44100, 2, 16);
+    let ticks_to_hns = {
+        let mut frequency = 0i64;
+        unsafe { windows::Win32::System::Performance::QueryPerformanceFrequency(&mut frequency) };
+        10_000_000.0 / frequency.max(1) as f64
+    };
 
-        Some(mixer)
+    // Create the microphone filter chain if any filter is enabled
+    let mut microphone_filter_chain = if capture_microphone && microphone_filters.is_enabled() {
+        Some(MicrophoneFilterChain::new(microphone_filters, 44100, 2))
     } else {
         None
     };
+    let mut system_audio_filters = audio_filters.chain(SYSTEM_AUDIO_SOURCE, 44100, 2);
+    let mut microphone_audio_filters = audio_filters.chain(MICROPHONE_SOURCE, 44100, 2);
+    let mut mix_audio_filters = audio_filters.chain(AUDIO_MIX, 44100, 2);
+    let mut named_sources = NamedSources {
+        sources: HashMap::new(),
+        filters: audio_filters,
+        controls: mixer_controls.clone(),
+    };
 
     // Create a mutex to store current window position and size with the initial values
     info!("Initializing window position mutex with: {:?}", initial_window_position);
@@ -92,6 +334,7 @@ pub fn process_samples(
     let window_position_clone = window_position.clone();
     let window_size_clone = window_size.clone();
     let recording_clone = recording.clone();
+    let capture_dump_clone = capture_dump.clone();
 
     std::thread::spawn(move || {
         info!("Window info monitoring thread started");
@@ -131,6 +374,18 @@ pub fn process_samples(
                     if position_changed || size_changed {
                         info!("Processing: Window position or size changed, marking for converter update");
                         window_changed_clone.store(true, Ordering::SeqCst);
+
+                        if let Some(dump) = &capture_dump_clone {
+                            let position = *window_position_clone.lock().unwrap();
+                            let size = *window_size_clone.lock().unwrap();
+                            if let (Some(position), Some(size)) = (position, size) {
+                                if let Err(e) =
+                                    dump.lock().unwrap().write_window_info(position, size)
+                                {
+                                    warn!("Failed to dump window info: {:?}", e);
+                                }
+                            }
+                        }
                     }
                 }
                 Err(TryRecvError::Empty) => {
@@ -146,23 +401,221 @@ pub fn process_samples(
         info!("Window info monitoring thread finished");
     });
 
-    let converter = unsafe {
-        video::setup_video_converter(
-            input_width,
-            input_height,
+    // With a custom scaling filter the GPU scaler crops and scales, and the video processor
+    // only rotates and converts output-sized BGRA frames to NV12. Frames turned on their side
+    // are scaled to the output size on its side. Without the video processor (RGB pipeline) the
+    // scaler does any cropping, bilinear unless another filter is set.
+    let (scaled_width, scaled_height) = if video_transform.rotation.is_quarter_turn() {
+        (output_height, output_width)
+    } else {
+        (output_width, output_height)
+    };
+    let create_scaler =
+        |device: &ID3D11Device, input_width: u32, input_height: u32| -> Result<Option<GpuScaler>> {
+            let quality = match (scaling_quality, pixel_pipeline) {
+                (ScalingQuality::Default, PixelPipeline::Rgb) => ScalingQuality::Bilinear,
+                (ScalingQuality::Default, _) => return Ok(None),
+                (quality, _) => quality,
+            };
+            let mut scaler = unsafe {
+                GpuScaler::new(
+                    device,
+                    quality,
+                    input_width,
+                    input_height,
+                    scaled_width,
+                    scaled_height,
+                )
+            }?;
+            let (source_rect, destination_rect) = video::fit_aspect(
+                aspect_mode,
+                video::cropped_source_rect(
+                    input_width,
+                    input_height,
+                    *window_position.lock().unwrap(),
+                    *window_size.lock().unwrap(),
+                    video_transform.crop,
+                ),
+                scaled_width,
+                scaled_height,
+                Rotation::None,
+            );
+            scaler.set_source_rect(source_rect);
+            scaler.set_destination_rect(destination_rect);
+            Ok(Some(scaler))
+        };
+    let mut scaler = create_scaler(&device, input_width, input_height)?;
+
+    // Replays with their own resolution get a second conversion of every frame
+    let replay_size = replay_size.filter(|size| *size != (output_width, output_height));
+    let create_converter = |input_width: u32,
+                            input_height: u32,
+                            scaled: bool,
+                            (width, height): (u32, u32)|
+     -> Result<IMFTransform> {
+        unsafe {
+            if scaled {
+                video::setup_video_converter(
+                    &ConverterLayout {
+                        input_width: scaled_width,
+                        input_height: scaled_height,
+                        output_width: width,
+                        output_height: height,
+                        window_position: None,
+                        window_size: None,
+                        // Scaled frames are already fitted to the output, only a replay of
+                        // another size is fitted again
+                        aspect_mode: if (width, height) == (output_width, output_height) {
+                            AspectMode::Stretch
+                        } else {
+                            aspect_mode
+                        },
+                        transform: video_transform.without_crop(),
+                    },
+                    color_space,
+                )
+            } else {
+                video::setup_video_converter(
+                    &ConverterLayout {
+                        input_width,
+                        input_height,
+                        output_width: width,
+                        output_height: height,
+                        window_position: *window_position.lock().unwrap(),
+                        window_size: *window_size.lock().unwrap(),
+                        aspect_mode,
+                        transform: video_transform,
+                    },
+                    color_space,
+                )
+            }
+        }
+    };
+    // The RGB pipeline hands BGRA frames to the encoder, which converts them itself
+    let create_output_converter =
+        |input_width: u32, input_height: u32, scaled: bool| -> Result<Option<IMFTransform>> {
+            if pixel_pipeline == PixelPipeline::Rgb {
+                return Ok(None);
+            }
+            create_converter(
+                input_width,
+                input_height,
+                scaled,
+                (output_width, output_height),
+            )
+            .map(Some)
+        };
+    let mut converter = create_output_converter(input_width, input_height, scaler.is_some())?;
+    if converter.is_some() {
+        info!("Video processor transform created and configured");
+    }
+    // Frames that already are the whole output are copied as they are, without the scaler
+    let is_direct_copy = move |(input_width, input_height): (u32, u32),
+                               position: Option<(i32, i32)>,
+                               size: Option<(u32, u32)>| {
+        pixel_pipeline == PixelPipeline::Rgb
+            && (input_width, input_height) == (output_width, output_height)
+            && video::cropped_source_rect(
+                input_width,
+                input_height,
+                position,
+                size,
+                video_transform.crop,
+            ) == RECT {
+                left: 0,
+                top: 0,
+                right: input_width as i32,
+                bottom: input_height as i32,
+            }
+    };
+    let mut direct_copy = is_direct_copy(
+        (input_width, input_height),
+        initial_window_position,
+        initial_window_size,
+    );
+    let mut replay_converter = match replay_size {
+        Some(size) => {
+            info!("Converting replay buffer frames to {}x{}", size.0, size.1);
+            Some(create_converter(
+                input_width,
+                input_height,
+                scaler.is_some(),
+                size,
+            )?)
+        }
+        None => None,
+    };
+
+    // The stamp goes in the top-left corner of the recorded region. Without the GPU scaler it is
+    // drawn on the full capture, so it is enlarged to survive the downscale.
+    let scaler_active = scaler.is_some();
+    let stamp_origin = move |(input_width, input_height): (u32, u32),
+                             position: Option<(i32, i32)>,
+                             size: Option<(u32, u32)>| {
+        if scaler_active {
+            return (8, 8);
+        }
+        let (source_rect, _) = video::fit_aspect(
+            aspect_mode,
+            video::cropped_source_rect(
+                input_width,
+                input_height,
+                position,
+                size,
+                video_transform.crop,
+            ),
             output_width,
             output_height,
-            window_position.clone(),
-            window_size.clone(),
-        )
-    }?;
-    info!("Video processor transform created and configured");
+            video_transform.rotation,
+        );
+        (source_rect.left as u32 + 8, source_rect.top as u32 + 8)
+    };
+    let mut frame_stamper = if debug_frame_stamp {
+        let scale = if scaler_active {
+            2
+        } else {
+            2 * ((input_width + output_width - 1) / output_width.max(1)).max(1)
+        };
+        Some(unsafe { FrameStamper::new(&device, scale) }?)
+    } else {
+        None
+    };
+    let mut stamp_position = stamp_origin(
+        (input_width, input_height),
+        initial_window_position,
+        initial_window_size,
+    );
+    let mut privacy_masker: Option<PrivacyMasker> = None;
+    let mut input_overlay = match input_overlay {
+        Some((config, events)) => Some(unsafe { InputOverlay::new(&device, config, events) }?),
+        None => None,
+    };
+    let mut scene_cut_detector = match scene_cut_threshold {
+        Some(threshold) => Some(unsafe { SceneCutDetector::new(&device, threshold) }?),
+        None => None,
+    };
+    // Only live recordings have a capture start to align to
+    let mut start_gate = match audio_start_qpc.filter(|_| start_gate) {
+        Some(start_qpc) => {
+            Some(unsafe { StartGate::new(&device, events.clone(), start_qpc, ticks_to_hns) }?)
+        }
+        None => None,
+    };
+    let mut pause_gate = PauseGate::new(paused, av_sync_offset, events.clone());
 
     let mut frame_count = 0;
     let start_time = std::time::Instant::now();
 
+    // First video timestamp and when it was processed, to measure how far behind real time we are
+    let mut first_video_frame: Option<(i64, std::time::Instant)> = None;
+    let mut last_backpressure_event: Option<std::time::Instant> = None;
+    let mut bitrate_monitor = encoder::BitrateMonitor::new(target_bitrate);
+    let mut last_bitrate_check = std::time::Instant::now();
+    let mut last_stats_event = std::time::Instant::now();
+
     let mut microphone_disconnected = false;
     let mut audio_disconnected = false;
+    let mut process_audio_disconnected = false;
 
     // Timestamp to track when we last checked for window changes
     let mut last_window_check = std::time::Instant::now();
@@ -170,6 +623,7 @@ pub fn process_samples(
 
     while recording.load(Ordering::Relaxed) {
         let mut had_work = false;
+        pause_gate.update();
 
         // Check if window has changed and update converter if needed
         let now = std::time::Instant::now();
@@ -180,49 +634,420 @@ pub fn process_samples(
                 info!("Main thread: Detected window change, updating video converter");
                 let current_pos = *window_position.lock().unwrap();
                 let current_size = *window_size.lock().unwrap();
+                stamp_position =
+                    stamp_origin((input_width, input_height), current_pos, current_size);
+                direct_copy =
+                    is_direct_copy((input_width, input_height), current_pos, current_size);
 
-                unsafe {
-                    if let Err(e) = video::update_video_converter(
-                        &converter,
-                        input_width,
-                        input_height,
-                        current_pos,
-                        current_size,
-                    ) {
-                        warn!("Failed to update video converter: {:?}", e);
+                if let Some(scaler) = &mut scaler {
+                    let (source_rect, destination_rect) = video::fit_aspect(
+                        aspect_mode,
+                        video::cropped_source_rect(
+                            input_width,
+                            input_height,
+                            current_pos,
+                            current_size,
+                            video_transform.crop,
+                        ),
+                        scaled_width,
+                        scaled_height,
+                        Rotation::None,
+                    );
+                    scaler.set_source_rect(source_rect);
+                    scaler.set_destination_rect(destination_rect);
+                } else if let Some(converter) = &converter {
+                    unsafe {
+                        let layout = ConverterLayout {
+                            input_width,
+                            input_height,
+                            output_width,
+                            output_height,
+                            window_position: current_pos,
+                            window_size: current_size,
+                            aspect_mode,
+                            transform: video_transform,
+                        };
+                        if let Err(e) = video::update_video_converter(converter, &layout) {
+                            warn!("Failed to update video converter: {:?}", e);
+                        }
+                        if let (Some(replay_converter), Some((replay_width, replay_height))) =
+                            (&replay_converter, replay_size)
+                        {
+                            if let Err(e) = video::update_video_converter(
+                                replay_converter,
+                                &ConverterLayout {
+                                    output_width: replay_width,
+                                    output_height: replay_height,
+                                    ..layout
+                                },
+                            ) {
+                                warn!("Failed to update replay video converter: {:?}", e);
+                            }
+                        }
                     }
                 }
             }
         }
 
         // Process video samples - video is required
-        match rec_video.try_recv() {
+        let next_video = match rec_video.try_recv() {
+            Ok(samp) if low_latency => Ok(newest_sample(samp, &rec_video, &counters)),
+            next => next,
+        };
+        match next_video {
             Ok(samp) => {
                 had_work = true;
-                // Extract timestamp for the replay buffer
-                let timestamp: i64 = unsafe { samp.sample.GetSampleTime() }?;
+
+                // Frames come from a new device once the capture moved to another adapter; every
+                // GPU resource of this thread is recreated on it
+                let frame_device = unsafe { video::sample_device(&samp.sample) }?;
+                let device_changed = frame_device != *device;
+                if device_changed {
+                    info!("Frames now come from another D3D11 device, recreating GPU resources");
+                    device = Arc::new(frame_device);
+                    texture_pool = Arc::new(texture_pool.recreate_on(device.clone())?);
+                    privacy_masker = None;
+                    unsafe {
+                        if let Some(stamper) = &mut frame_stamper {
+                            stamper.set_device(&device)?;
+                        }
+                        if let Some(overlay) = &mut input_overlay {
+                            overlay.set_device(&device)?;
+                        }
+                        if let Some(detector) = &mut scene_cut_detector {
+                            detector.set_device(&device)?;
+                        }
+                        if let Some(gate) = &mut start_gate {
+                            gate.set_device(&device)?;
+                        }
+                    }
+                }
+
+                // Frames change size when the captured monitor changes resolution; the output
+                // keeps its size and the new frames are fitted into it
+                let frame_size = unsafe { video::sample_size(&samp.sample) }?;
+                if device_changed || frame_size != (input_width, input_height) {
+                    info!(
+                        "Frames changed from {}x{} to {}x{}, reconfiguring the video processor",
+                        input_width, input_height, frame_size.0, frame_size.1
+                    );
+                    (input_width, input_height) = frame_size;
+                    scaler = create_scaler(&device, input_width, input_height)?;
+                    converter =
+                        create_output_converter(input_width, input_height, scaler.is_some())?;
+                    if let Some(size) = replay_size {
+                        replay_converter = Some(create_converter(
+                            input_width,
+                            input_height,
+                            scaler.is_some(),
+                            size,
+                        )?);
+                    }
+                    stamp_position = stamp_origin(
+                        frame_size,
+                        *window_position.lock().unwrap(),
+                        *window_size.lock().unwrap(),
+                    );
+                    direct_copy = is_direct_copy(
+                        frame_size,
+                        *window_position.lock().unwrap(),
+                        *window_size.lock().unwrap(),
+                    );
+                }
+                let mut timestamp: i64 = unsafe { samp.sample.GetSampleTime() }?;
+                if let Some(gate) = &mut start_gate {
+                    match unsafe { gate.admit_video(&samp.sample, timestamp) } {
+                        Ok(Some(retimed)) => timestamp = retimed,
+                        Ok(None) => {
+                            trace!(timestamp = timestamp, "Dropping blank frame before the start");
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Start gate failed to read the frame, dropping it: {:?}", e);
+                            continue;
+                        }
+                    }
+                }
+                // Continue with the retimed frame; the capture may still hold the original
+                let frame = match unsafe {
+                    pause_gate.admit(PauseStream::Video, &samp.sample, timestamp)
+                } {
+                    Ok(Some((retimed, frame))) => {
+                        timestamp = retimed;
+                        frame
+                    }
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!(
+                            "Failed to retime the frame after a pause, dropping it: {:?}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+                if idle_throttle.as_ref().is_some_and(|t| t.skip(timestamp)) {
+                    trace!(timestamp = timestamp, "Skipping frame while idle");
+                    continue;
+                }
+
+                // Masked regions are hidden before the frame goes anywhere else, the capture
+                // dump included
+                let masks = privacy_masks.lock().unwrap().clone();
+                if !masks.is_empty() {
+                    if privacy_masker.is_none() {
+                        privacy_masker = Some(unsafe { PrivacyMasker::new(&device) }?);
+                    }
+                    if let Some(masker) = &mut privacy_masker {
+                        if let Err(e) = unsafe { masker.apply(&frame, &masks) } {
+                            warn!("Failed to apply privacy masks, dropping frame: {:?}", e);
+                            continue;
+                        }
+                    }
+                }
+
+                // Rounded window corners are masked like privacy masks, in the captured frame
+                let radii = *corner_radii.lock().unwrap();
+                if radii != (0, 0) {
+                    let position = *window_position.lock().unwrap();
+                    let size = *window_size.lock().unwrap();
+                    if privacy_masker.is_none() {
+                        privacy_masker = Some(unsafe { PrivacyMasker::new(&device) }?);
+                    }
+                    if let (Some(masker), Some(position), Some(size)) =
+                        (&mut privacy_masker, position, size)
+                    {
+                        if let Err(e) =
+                            unsafe { masker.mask_corners(&frame, position, size, radii) }
+                        {
+                            warn!("Failed to mask the window corners: {:?}", e);
+                        }
+                    }
+                }
+
+                // Dump the raw captured frame before any processing
+                if let Some(dump) = &capture_dump {
+                    if let Err(e) = unsafe { dump.lock().unwrap().write_video(&device, &frame) } {
+                        warn!("Failed to dump video frame: {:?}", e);
+                    }
+                }
+
+                if let Some(filters) = &mut video_filters {
+                    if let Err(e) = unsafe { filters.apply(&frame, timestamp) } {
+                        warn!("Video filter failed, leaving the frame unfiltered: {:?}", e);
+                    }
+                }
+
+                if let Some(overlay) = &mut input_overlay {
+                    let region = video::cropped_source_rect(
+                        input_width,
+                        input_height,
+                        *window_position.lock().unwrap(),
+                        *window_size.lock().unwrap(),
+                        video_transform.crop,
+                    );
+                    if let Err(e) = unsafe { overlay.apply(&frame, region) } {
+                        warn!("Failed to draw the input overlay: {:?}", e);
+                    }
+                }
+
+                let convert_scope = profiler
+                    .as_ref()
+                    .map(|p| unsafe { p.begin(PipelineStage::Convert) });
+
+                // Scale with the configured filter first if requested
+                let scaled = match &scaler {
+                    Some(scaler) if !direct_copy => Some(unsafe { scaler.scale(&frame) }?),
+                    _ => None,
+                };
+
+                if let Some(stamper) = &frame_stamper {
+                    let (x, y) = stamp_position;
+                    let target = scaled.as_ref().unwrap_or(&frame);
+                    if let Err(e) = unsafe { stamper.stamp(target, frame_count as u64, x, y) } {
+                        warn!("Failed to stamp frame: {:?}", e);
+                    }
+                }
+
+                let scene_cut = match &mut scene_cut_detector {
+                    Some(detector) => unsafe {
+                        detector.is_scene_cut(scaled.as_ref().unwrap_or(&frame), timestamp)
+                    }
+                    .unwrap_or_else(|e| {
+                        warn!("Scene cut detection failed: {:?}", e);
+                        false
+                    }),
+                    None => false,
+                };
 
                 // Convert and write to file as usual
                 let converted = unsafe {
-                    video::convert_bgra_to_nv12(
-                        &device,
-                        &converter,
-                        &*samp.sample,
-                        output_width,
-                        output_height,
-                        &texture_pool,
-                    )?
+                    match &converter {
+                        Some(converter) => video::convert_bgra_to_nv12(
+                            &device,
+                            converter,
+                            scaled.as_ref().unwrap_or(&frame),
+                            output_width,
+                            output_height,
+                            &texture_pool,
+                        )?,
+                        None => video::copy_bgra_to_pooled(
+                            &device,
+                            scaled.as_ref().unwrap_or(&frame),
+                            &texture_pool,
+                        )?,
+                    }
                 };
+                if let (Some(profiler), Some(scope)) = (&profiler, convert_scope) {
+                    unsafe { profiler.end(scope) };
+                }
+                if let Some(preview) = &mut preview {
+                    if let Err(e) = unsafe { preview.offer(&converted, timestamp) } {
+                        warn!("Failed to read back preview frame: {:?}", e);
+                    }
+                }
+                if let Some(shared_preview) = &mut shared_preview {
+                    match unsafe { shared_preview.offer(&converted) } {
+                        Ok(true) => events.emit(RecorderEvent::SharedPreviewTextureChanged {
+                            timestamp: hns_to_duration(timestamp),
+                        }),
+                        Ok(false) => {}
+                        Err(e) => warn!("Failed to update the shared preview texture: {:?}", e),
+                    }
+                }
+                // The high fps file takes every frame; the rest only the ones at the output rate.
+                // It writes a copy of the sample, so the duration can then be changed in place
+                if let Some(output) = &mut high_fps_output {
+                    if let Err(e) = unsafe { output.write_video(&converted) } {
+                        warn!("Failed to write video sample to the high fps file: {:?}", e);
+                    }
+                }
+                if let Some(decimator) = &mut output_decimator {
+                    if !decimator.admit(timestamp) {
+                        continue;
+                    }
+                    unsafe { converted.SetSampleDuration(decimator.frame_duration()) }?;
+                }
+                if let Some(thumbnail) = &thumbnail {
+                    if let Err(e) = unsafe { thumbnail.offer(&converted, timestamp) } {
+                        warn!("Failed to read back thumbnail frame: {:?}", e);
+                    }
+                }
                 // Add to replay buffer if enabled
                 if let Some(buffer) = &replay_buffer {
-                    // Clone the IMFSample directly
-                    buffer.add_video_sample(SendableSample::new(converted.clone()), timestamp)?;
+                    let (replay_sample, owned_bytes) = match (&replay_converter, replay_size) {
+                        (Some(replay_converter), Some((replay_width, replay_height))) => unsafe {
+                            let sample = video::convert_bgra_to_new_nv12(
+                                &device,
+                                replay_converter,
+                                scaled.as_ref().unwrap_or(&frame),
+                                replay_width,
+                                replay_height,
+                            )?;
+                            (sample, texture_bytes(replay_width, replay_height, DXGI_FORMAT_NV12))
+                        },
+                        // Clone the IMFSample directly
+                        _ => (converted.clone(), 0),
+                    };
+                    buffer.add_video_sample(&replay_sample, owned_bytes);
+                }
+                if let (true, Some(writer)) = (scene_cut, &writer) {
+                    if let Err(e) = unsafe { encoder::force_keyframe(&writer.0, video_stream_index) } {
+                        warn!("Failed to force a keyframe at a scene cut: {:?}", e);
+                    }
+                }
+                let submit_scope = profiler
+                    .as_ref()
+                    .map(|p| unsafe { p.begin(PipelineStage::EncodeSubmit) });
+                if let Some(writer) = &writer {
+                    unsafe { write_sample(writer, video_stream_index, &converted, &errors)? };
+                }
+                if let Some(images) = &mut image_sequence {
+                    unsafe { images.offer(&converted) }.inspect_err(|e| {
+                        errors.report(ThreadSource::Encoder, e.to_string());
+                    })?;
+                }
+                if let (Some(profiler), Some(scope)) = (&profiler, submit_scope) {
+                    unsafe { profiler.end(scope) };
+                }
+                if let Some(segment) = segment_output.lock().unwrap().as_mut() {
+                    if let Err(e) = unsafe { segment.write_video(&converted) } {
+                        warn!("Failed to write video sample to segment: {:?}", e);
+                    }
+                }
+                if let Some(tagger) = &mut frame_tagger {
+                    unsafe { tagger.frame_written(&frame, timestamp) };
                 }
-                unsafe { writer.0.WriteSample(video_stream_index, &converted)? };
 
                 frame_count += 1;
+                counters.written.fetch_add(1, Ordering::Relaxed);
+
+                let (first_timestamp, first_instant) =
+                    *first_video_frame.get_or_insert((timestamp, std::time::Instant::now()));
+                // Time spent paused counts as written
+                let media_elapsed =
+                    hns_to_duration((timestamp + pause_gate.offset() - first_timestamp).max(0));
+                let lag = first_instant.elapsed().saturating_sub(media_elapsed);
+                if lag >= BACKPRESSURE_LAG
+                    && last_backpressure_event
+                        .is_none_or(|last| last.elapsed() >= BACKPRESSURE_EVENT_INTERVAL)
+                {
+                    warn!("Processing is {:?} behind the capture", lag);
+                    events.emit(RecorderEvent::EncoderBackpressure {
+                        timestamp: hns_to_duration(timestamp.max(0)),
+                        lag,
+                        frames_written: frame_count as u64,
+                    });
+                    last_backpressure_event = Some(std::time::Instant::now());
+                }
+
+                if last_bitrate_check.elapsed() >= BITRATE_CHECK_INTERVAL {
+                    last_bitrate_check = std::time::Instant::now();
+                    let stats = writer.as_ref().and_then(|writer| unsafe {
+                        encoder::get_encoder_stats(&writer.0, video_stream_index).ok()
+                    });
+                    if let Some((deviation, measured)) =
+                        stats.and_then(|stats| bitrate_monitor.update(&stats))
+                    {
+                        warn!(
+                            "Encoder bitrate {} bps is {:?} the {} bps target",
+                            measured, deviation, target_bitrate
+                        );
+                        events.emit(RecorderEvent::BitrateOutOfRange {
+                            timestamp: hns_to_duration(timestamp.max(0)),
+                            deviation,
+                            measured,
+                            target: target_bitrate as u64,
+                        });
+                    }
+                }
+
+                if stats_interval.is_some_and(|interval| last_stats_event.elapsed() >= interval) {
+                    last_stats_event = std::time::Instant::now();
+                    let stats = writer.as_ref().and_then(|writer| unsafe {
+                        encoder::get_encoder_stats(&writer.0, video_stream_index).ok()
+                    });
+                    events.emit(RecorderEvent::Stats {
+                        timestamp: hns_to_duration(timestamp.max(0)),
+                        frames_captured: counters.captured.load(Ordering::Relaxed),
+                        frames_duplicated: counters.duplicated.load(Ordering::Relaxed),
+                        frames_dropped: counters.dropped.load(Ordering::Relaxed),
+                        frames_written: counters.written.load(Ordering::Relaxed),
+                        bytes_written: stats.map(|stats| stats.bytes_written),
+                    });
+                }
+
+                if let Some(profiler) = &profiler {
+                    if profiler.window_elapsed() >= PROFILE_EVENT_INTERVAL {
+                        let report = profiler.take_window_report();
+                        info!("Pipeline profile {}", report);
+                        events.emit(RecorderEvent::PipelineProfile { report });
+                    }
+                }
+
                 if frame_count % 100 == 0 {
-                    info!(
+                    debug!(
+                        frame = frame_count,
+                        timestamp = timestamp,
                         "Processed {} frames in {:?}",
                         frame_count,
                         start_time.elapsed()
@@ -236,35 +1061,66 @@ pub fn process_samples(
             }
         }
 
+        // Audio waits in its channel until the start gate knows where the recording begins
+        let audio_open = start_gate.as_ref().is_none_or(StartGate::is_open);
+
         // Process audio samples from system audio
-        if !audio_disconnected && capture_audio {
+        if !audio_disconnected && capture_audio && audio_open {
             match rec_audio.try_recv() {
                 Ok(audio_samp) => {
                     had_work = true;
+                    let Some(audio_samp) = admit_audio(
+                        &start_gate,
+                        &mut pause_gate,
+                        PauseStream::SystemAudio,
+                        &audio_samp.sample,
+                    )?
+                    else {
+                        continue;
+                    };
 
-                    // Extract timestamp for the replay buffer
-                    let timestamp: i64 = unsafe { audio_samp.sample.GetSampleTime() }?;
+                    if let Some(dump) = &capture_dump {
+                        if let Err(e) = unsafe {
+                            dump.lock()
+                                .unwrap()
+                                .write_audio(DumpRecordKind::SystemAudio, &audio_samp.sample)
+                        } {
+                            warn!("Failed to dump system audio: {:?}", e);
+                        }
+                    }
+
+                    if let Some(chain) = &mut system_audio_filters {
+                        if let Err(e) = unsafe { chain.process_sample(&audio_samp.sample) } {
+                            warn!("Failed to filter system audio sample: {:?}", e);
+                        }
+                    }
 
                     // Only add to replay buffer if we're NOT mixing (otherwise we'll add the mixed sample later)
                     if audio_mixer.is_none() {
                         if let Some(buffer) = &replay_buffer {
-                            // Clone the IMFSample from the Arc
-                            let cloned_sample = audio_samp.sample.as_ref().clone();
-                            buffer
-                                .add_audio_sample(SendableSample::new(cloned_sample), timestamp)?;
+                            buffer.add_audio_sample(&audio_samp.sample);
                         }
                     }
 
                     if let Some(mixer) = &mut audio_mixer {
-                        // Add to mixer if we need to mix
-                        mixer.add_system_audio(audio_samp);
-                    } else if let Some(stream_index) = audio_stream_index {
+                        // Captured processes fall back to desktop audio where process loopback
+                        // is unavailable
+                        let source = *system_source
+                            .get_or_insert_with(|| mixer.add_source(SYSTEM_AUDIO_SOURCE));
+                        mixer.push(source, audio_samp);
+                    } else if let (Some(stream_index), Some(writer)) = (audio_stream_index, &writer)
+                    {
                         // Write directly if no mixing needed
                         let write_start = std::time::Instant::now();
-                        unsafe { writer.0.WriteSample(stream_index, &*audio_samp.sample)? };
-                        debug!(
-                            "Process audio sample written in {:?}",
-                            write_start.elapsed()
+                        unsafe { write_sample(writer, stream_index, &audio_samp.sample, &errors)? };
+                        if let Some(segment) = segment_output.lock().unwrap().as_mut() {
+                            if let Err(e) = unsafe { segment.write_audio(&audio_samp.sample) } {
+                                warn!("Failed to write audio sample to segment: {:?}", e);
+                            }
+                        }
+                        trace!(
+                            write_us = write_start.elapsed().as_micros() as u64,
+                            "Process audio sample written"
                         );
                     }
                 }
@@ -277,32 +1133,73 @@ pub fn process_samples(
             }
         }
 
+        // Mix both sources once a hot-plugged microphone has attached
+        if capture_audio && capture_microphone && !microphone_disconnected {
+            if let (Some(mixer), Some(source)) = (&mut audio_mixer, microphone_source) {
+                mixer.set_active(source, microphone_attached.load(Ordering::Relaxed));
+            }
+        }
+
         // Process microphone samples
-        if !microphone_disconnected && capture_microphone {
+        if !microphone_disconnected && capture_microphone && audio_open {
             match rec_microphone.try_recv() {
                 Ok(mic_samp) => {
                     had_work = true;
+                    let Some(mic_samp) = admit_audio(
+                        &start_gate,
+                        &mut pause_gate,
+                        PauseStream::Microphone,
+                        &mic_samp.sample,
+                    )?
+                    else {
+                        continue;
+                    };
 
-                    // Extract timestamp for the replay buffer
-                    let timestamp: i64 = unsafe { mic_samp.sample.GetSampleTime() }?;
+                    if let Some(dump) = &capture_dump {
+                        if let Err(e) = unsafe {
+                            dump.lock()
+                                .unwrap()
+                                .write_audio(DumpRecordKind::Microphone, &mic_samp.sample)
+                        } {
+                            warn!("Failed to dump microphone audio: {:?}", e);
+                        }
+                    }
+
+                    // Filter the microphone before it reaches the mixer or the replay buffer
+                    if let Some(chain) = &mut microphone_filter_chain {
+                        if let Err(e) = unsafe { chain.process_sample(&mic_samp.sample) } {
+                            warn!("Failed to filter microphone sample: {:?}", e);
+                        }
+                    }
+                    if let Some(chain) = &mut microphone_audio_filters {
+                        if let Err(e) = unsafe { chain.process_sample(&mic_samp.sample) } {
+                            warn!("Failed to filter microphone sample: {:?}", e);
+                        }
+                    }
 
                     // Only add to replay buffer if we're NOT mixing
                     if audio_mixer.is_none() {
                         if let Some(buffer) = &replay_buffer {
-                            // Clone the IMFSample from the Arc
-                            let cloned_sample = mic_samp.sample.as_ref().clone();
-                            buffer
-                                .add_audio_sample(SendableSample::new(cloned_sample), timestamp)?;
+                            buffer.add_audio_sample(&mic_samp.sample);
                         }
                     }
 
                     // Rest remains the same
-                    if let Some(mixer) = &mut audio_mixer {
-                        mixer.add_microphone_audio(mic_samp);
-                    } else if let Some(stream_index) = audio_stream_index {
+                    if let (Some(mixer), Some(source)) = (&mut audio_mixer, microphone_source) {
+                        mixer.push(source, mic_samp);
+                    } else if let (Some(stream_index), Some(writer)) = (audio_stream_index, &writer)
+                    {
                         let write_start = std::time::Instant::now();
-                        unsafe { writer.0.WriteSample(stream_index, &*mic_samp.sample)? };
-                        debug!("Microphone sample written in {:?}", write_start.elapsed());
+                        unsafe { write_sample(writer, stream_index, &mic_samp.sample, &errors)? };
+                        if let Some(segment) = segment_output.lock().unwrap().as_mut() {
+                            if let Err(e) = unsafe { segment.write_audio(&mic_samp.sample) } {
+                                warn!("Failed to write microphone sample to segment: {:?}", e);
+                            }
+                        }
+                        trace!(
+                            write_us = write_start.elapsed().as_micros() as u64,
+                            "Microphone sample written"
+                        );
                     }
                 }
                 // Error handling remains the same
@@ -313,56 +1210,130 @@ pub fn process_samples(
                     microphone_disconnected = true;
                     
                     // If we're supposed to be mixing, update the AudioMixer to not wait for mic data
-                    if let Some(mixer) = &mut audio_mixer {
+                    if let (Some(mixer), Some(source)) = (&mut audio_mixer, microphone_source) {
                         if capture_audio && capture_microphone {
                             info!("Updating AudioMixer to no longer wait for microphone samples");
-                            mixer.set_both_sources_active(false);
+                            mixer.set_active(source, false);
                         }
                     }
                 }
             }
         }
 
+        // Audio of each captured process, mixed as a source of its own
+        if !process_audio_disconnected && capture_audio && audio_open {
+            match rec_process_audio.try_recv() {
+                Ok(AudioPacket::Sample { source, sample }) => {
+                    had_work = true;
+                    if let Some(mixer) = &mut audio_mixer {
+                        named_sources.push(
+                            mixer,
+                            &source,
+                            SourceKind::System,
+                            sample,
+                            &start_gate,
+                            &mut pause_gate,
+                        )?;
+                    }
+                }
+                Ok(AudioPacket::Ended { source }) => {
+                    if let Some(mixer) = &mut audio_mixer {
+                        named_sources.end(mixer, &source);
+                    }
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => process_audio_disconnected = true,
+            }
+        }
+
+        // Extra microphones, each mixed as a source of its own
+        let mut index = 0;
+        while audio_open && index < rec_extra_microphones.len() {
+            let (name, receiver) = &rec_extra_microphones[index];
+            match receiver.try_recv() {
+                Ok(sample) => {
+                    had_work = true;
+                    if let Some(mixer) = &mut audio_mixer {
+                        named_sources.push(
+                            mixer,
+                            name,
+                            SourceKind::Microphone,
+                            sample,
+                            &start_gate,
+                            &mut pause_gate,
+                        )?;
+                    }
+                    index += 1;
+                }
+                Err(TryRecvError::Empty) => index += 1,
+                Err(TryRecvError::Disconnected) => {
+                    info!("Microphone '{}' channel disconnected", name);
+                    if let Some(mixer) = &mut audio_mixer {
+                        named_sources.end(mixer, name);
+                    }
+                    rec_extra_microphones.remove(index);
+                }
+            }
+        }
+
         // Process any available mixed samples
         if let Some(mixer) = &mut audio_mixer {
-            if let Some(stream_index) = audio_stream_index {
+            let _mixer_span = tracing::trace_span!("mixer").entered();
+            if let (Some(stream_index), Some(writer)) = (audio_stream_index, &writer) {
                 // Process mixed samples until there are none available
                 while let Some(mixed_result) = unsafe { mixer.process_next_sample() } {
                     had_work = true;
                     match mixed_result {
                         Ok(mixed_sample) => {
-                            let write_start = std::time::Instant::now();
-
-                            // Add mixed sample to replay buffer with current timestamp
-                            if let Some(buffer) = &replay_buffer {
-                                // Get timestamp from the sample if possible or use system time
-                                let timestamp = unsafe {
-                                    mixed_sample.GetSampleTime().unwrap_or_else(|_| {
-                                        // Fallback to system time if GetSampleTime fails
-                                        std::time::SystemTime::now()
-                                            .duration_since(std::time::UNIX_EPOCH)
-                                            .unwrap_or_default()
-                                            .as_nanos()
-                                            as i64
-                                    })
-                                };
-                                // Extract the IMFSample from the Arc
-                                let cloned_sample = mixed_sample.as_ref().clone();
-                                buffer.add_audio_sample(
-                                    SendableSample::new(cloned_sample),
-                                    timestamp,
+                            if let Some(chain) = &mut mix_audio_filters {
+                                if let Err(e) = unsafe { chain.process_sample(&mixed_sample) } {
+                                    warn!("Failed to filter mixed audio sample: {:?}", e);
+                                }
+                            }
+                            // Fill any hole before the sample with silence
+                            for sample in unsafe { gap_filler.before_sample(&mixed_sample)? } {
+                                write_audio_output(
+                                    writer,
+                                    stream_index,
+                                    &sample,
+                                    &replay_buffer,
+                                    &segment_output,
+                                    &errors,
                                 )?;
                             }
-
-                            // Write the mixed sample
-                            unsafe { writer.0.WriteSample(stream_index, &*mixed_sample)? };
-                            trace!("Mixed audio sample written in {:?}", write_start.elapsed());
                         }
                         Err(e) => {
                             error!("Error mixing audio samples: {:?}", e);
                         }
                     }
                 }
+
+                // Nothing arrived for a while: both sources stalled or are silent. While paused
+                // the pause itself is left out instead
+                if let (Some(start_qpc), true, true, false) = (
+                    audio_start_qpc,
+                    mixer.is_idle(),
+                    audio_open,
+                    pause_gate.is_paused(),
+                ) {
+                    let mut now_qpc = 0i64;
+                    unsafe { windows::Win32::System::Performance::QueryPerformanceCounter(&mut now_qpc) };
+                    let now_hns = ((now_qpc as u64).saturating_sub(start_qpc) as f64 * ticks_to_hns)
+                        as i64
+                        - start_gate.as_ref().map_or(0, StartGate::audio_offset)
+                        - pause_gate.offset();
+                    for sample in unsafe { gap_filler.fill_stall(now_hns)? } {
+                        had_work = true;
+                        write_audio_output(
+                            writer,
+                            stream_index,
+                            &sample,
+                            &replay_buffer,
+                            &segment_output,
+                            &errors,
+                        )?;
+                    }
+                }
             }
         }
 
@@ -376,6 +1347,192 @@ pub fn process_samples(
         frame_count,
         start_time.elapsed()
     );
-    unsafe { writer.0.Finalize()? };
+    if let Some(tagger) = &mut frame_tagger {
+        tagger.finish();
+    }
+    if gap_filler.inserted_hns() > 0 {
+        info!(
+            "Inserted {:.1} ms of silence into audio gaps",
+            gap_filler.inserted_hns() as f64 / 10_000.0
+        );
+    }
+    if let Some(writer) = &writer {
+        match unsafe { encoder::get_encoder_stats(&writer.0, video_stream_index) } {
+            Ok(stats) => info!(
+                "Encoder encoded {} of {} frames, {} bytes ({} bps effective)",
+                stats.frames_encoded,
+                stats.frames_received,
+                stats.bytes_written,
+                stats.effective_bitrate()
+            ),
+            Err(e) => debug!("Failed to read encoder statistics: {:?}", e),
+        }
+    }
+    let _writer_span = tracing::info_span!("writer").entered();
+    if let Some(dump) = &capture_dump {
+        if let Err(e) = dump.lock().unwrap().finish() {
+            warn!("Failed to finish capture dump: {:?}", e);
+        }
+    }
+    if let Some(segment) = segment_output.lock().unwrap().take() {
+        if let Err(e) = unsafe { segment.finalize() } {
+            warn!("Failed to finalize segment output: {:?}", e);
+        }
+    }
+    if let Some(output) = high_fps_output {
+        if let Err(e) = unsafe { output.finalize() } {
+            warn!("Failed to finalize the high fps file: {:?}", e);
+        }
+    }
+    if let Some(images) = &mut image_sequence {
+        images.finish().inspect_err(|e| {
+            errors.report(ThreadSource::Encoder, e.to_string());
+        })?;
+    }
+    if let Some(writer) = &writer {
+        unsafe { writer.0.Finalize() }.inspect_err(|e| {
+            errors.report(ThreadSource::Encoder, e.to_string());
+        })?;
+    }
+    Ok(())
+}
+
+/// Skip to the newest frame waiting in `receiver`, dropping the backlog in between so the
+/// encoder never works on stale frames
+fn newest_sample(
+    mut sample: SendableSample,
+    receiver: &FrameReceiver,
+    counters: &FrameCounters,
+) -> SendableSample {
+    let mut dropped = 0u64;
+    while let Ok(newer) = receiver.try_recv() {
+        sample = newer;
+        dropped += 1;
+    }
+    if dropped > 0 {
+        counters.dropped.fetch_add(dropped, Ordering::Relaxed);
+        debug!("Low latency: dropped {} queued video frame(s)", dropped);
+    }
+    sample
+}
+
+// Retime an audio sample past the start gate and any pauses, returning the sample to continue
+// with, or None if it must be dropped
+fn admit_audio(
+    start_gate: &Option<StartGate>,
+    pause_gate: &mut PauseGate,
+    stream: PauseStream,
+    sample: &IMFSample,
+) -> Result<Option<SendableSample>> {
+    let started = match start_gate {
+        Some(gate) => unsafe { gate.admit_audio(sample) }?,
+        None => true,
+    };
+    if !started {
+        return Ok(None);
+    }
+    unsafe {
+        let time = sample.GetSampleTime()?;
+        Ok(pause_gate
+            .admit(stream, sample, time)?
+            .map(|(_, sample)| SendableSample::new(sample)))
+    }
+}
+
+/// Mixer sources besides `system` and `microphone`, added once their first packet arrives: the
+/// processes `AudioSource::Processes` captures and the extra microphones
+///
+/// Unlike the two main sources they are not written to capture dumps.
+struct NamedSources {
+    sources: HashMap<String, (SourceId, Option<AudioFilterChain>)>,
+    filters: AudioFilters,
+    controls: MixerControls,
+}
+
+impl NamedSources {
+    /// Retime, filter and queue a packet of the source `name`, adding it to `mixer` if it is new
+    fn push(
+        &mut self,
+        mixer: &mut AudioMixer,
+        name: &str,
+        kind: SourceKind,
+        sample: SendableSample,
+        start_gate: &Option<StartGate>,
+        pause_gate: &mut PauseGate,
+    ) -> Result<()> {
+        let (source, chain) = self.sources.entry(name.to_string()).or_insert_with(|| {
+            let source = mixer.add_source(name);
+            mixer.set_kind(source, kind);
+            // A captured process starts out at the gain and mute flag of the system audio
+            let like = match kind {
+                SourceKind::System => SYSTEM_AUDIO_SOURCE,
+                SourceKind::Microphone => MICROPHONE_SOURCE,
+            };
+            if let Ok(mut controls) = self.controls.lock() {
+                if let Some(control) = controls.get(like).copied() {
+                    controls.entry(name.to_string()).or_insert(control);
+                }
+            }
+            (source, self.filters.chain(name, 44100, 2))
+        });
+        // A process that exited is waited for again once it is back
+        mixer.set_active(*source, true);
+
+        let Some(sample) =
+            admit_audio(start_gate, pause_gate, PauseStream::Source(*source), &sample.sample)?
+        else {
+            return Ok(());
+        };
+        if let Some(chain) = chain {
+            if let Err(e) = unsafe { chain.process_sample(&sample.sample) } {
+                warn!("Failed to filter '{}' audio sample: {:?}", name, e);
+            }
+        }
+        mixer.push(*source, sample);
+        Ok(())
+    }
+
+    /// Stop waiting for the source `name`, whose capture ended
+    fn end(&mut self, mixer: &mut AudioMixer, name: &str) {
+        if let Some((source, _)) = self.sources.get(name) {
+            mixer.set_active(*source, false);
+        }
+    }
+}
+
+// Write an output audio sample to the file, the replay buffer and the current segment
+fn write_audio_output(
+    writer: &SendableWriter,
+    stream_index: u32,
+    sample: &IMFSample,
+    replay_buffer: &Option<Arc<ReplayBuffer>>,
+    segment_output: &Mutex<Option<SegmentOutput>>,
+    errors: &ErrorCollector,
+) -> Result<()> {
+    let write_start = std::time::Instant::now();
+
+    if let Some(buffer) = replay_buffer {
+        buffer.add_audio_sample(sample);
+    }
+
+    unsafe { write_sample(writer, stream_index, sample, errors)? };
+    if let Some(segment) = segment_output.lock().unwrap().as_mut() {
+        if let Err(e) = unsafe { segment.write_audio(sample) } {
+            warn!("Failed to write mixed audio to segment: {:?}", e);
+        }
+    }
+    trace!("Mixed audio sample written in {:?}", write_start.elapsed());
     Ok(())
 }
+
+/// Write a sample to the sink writer, reporting a failure as an encoder error
+unsafe fn write_sample(
+    writer: &SendableWriter,
+    stream_index: u32,
+    sample: &IMFSample,
+    errors: &ErrorCollector,
+) -> Result<()> {
+    writer.0.WriteSample(stream_index, sample).inspect_err(|e| {
+        errors.report(ThreadSource::Encoder, e.to_string());
+    })
+}
@@ -0,0 +1,505 @@
+use crate::logging::{debug, info};
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use windows::core::{ComInterface, Interface, Result};
+use windows::Win32::Foundation::RECT;
+use windows::Win32::Graphics::Direct3D::D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Media::MediaFoundation::{
+    IMFDXGIBuffer, IMFSample, MFSampleExtension_DeviceTimestamp,
+};
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+use super::scaler::{blob_bytes, compile_shader};
+use super::stamp::render_text;
+use crate::capture::InputEvent;
+
+/// Longest keystroke text shown; older characters scroll out on the left
+const MAX_CAPTION_CHARS: usize = 24;
+/// Distance of the keystroke display from the edge of the recorded region, in pixels
+const CAPTION_MARGIN: i32 = 16;
+/// Width of the click ring, in pixels
+const RING_WIDTH: f32 = 3.0;
+
+/// Corner of the recorded region the keystroke display is drawn in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverlayCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// What the input overlay shows and how
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct InputOverlayConfig {
+    /// Draw an expanding ring where a mouse button is pressed
+    pub show_clicks: bool,
+    /// Radius the ring grows to, in pixels of the captured monitor
+    pub click_radius: u32,
+    /// How long a ring stays visible
+    pub click_duration: Duration,
+    /// Ring colour as RGB
+    pub click_color: [u8; 3],
+    /// Show the keys being pressed
+    pub show_keystrokes: bool,
+    /// Only show shortcuts (keys pressed with Ctrl, Alt or Win), keeping typed text such as
+    /// passwords out of the recording
+    pub shortcuts_only: bool,
+    /// How long keys stay visible after the last press
+    pub keystroke_duration: Duration,
+    /// Size in pixels of one cell of the 3x5 glyphs
+    pub keystroke_scale: u32,
+    pub keystroke_corner: OverlayCorner,
+}
+
+impl Default for InputOverlayConfig {
+    fn default() -> Self {
+        Self {
+            show_clicks: true,
+            click_radius: 30,
+            click_duration: Duration::from_millis(400),
+            click_color: [255, 200, 0],
+            show_keystrokes: true,
+            shortcuts_only: true,
+            keystroke_duration: Duration::from_millis(1500),
+            keystroke_scale: 6,
+            keystroke_corner: OverlayCorner::BottomRight,
+        }
+    }
+}
+
+const RIPPLE_HLSL: &str = r#"
+cbuffer Params : register(b0)
+{
+    float4 ripple;   // centre x, y and radius in pixels, opacity
+    float4 color;    // rgb, ring width in pixels
+};
+
+float4 vs_main(uint id : SV_VertexID) : SV_Position
+{
+    float2 uv = float2((id << 1) & 2, id & 2);
+    return float4(uv * float2(2, -2) + float2(-1, 1), 0, 1);
+}
+
+float4 ps_ripple(float4 pos : SV_Position) : SV_Target
+{
+    float distance = length(pos.xy - ripple.xy);
+    float ring = 1 - saturate(abs(distance - ripple.z) / color.w);
+    float fill = distance < ripple.z ? 0.25 : 0;
+    float alpha = max(ring, fill) * ripple.w;
+    if (alpha <= 0)
+        discard;
+    return float4(color.rgb, alpha);
+}
+"#;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RippleParams {
+    ripple: [f32; 4],
+    color: [f32; 4],
+}
+
+/// Modifier keys held down
+#[derive(Default)]
+struct Modifiers {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    win: bool,
+}
+
+impl Modifiers {
+    /// Record a modifier going down or up; false if `vk` is not a modifier
+    fn update(&mut self, vk: u32, down: bool) -> bool {
+        let held = match vk {
+            0x10 | 0xA0 | 0xA1 => &mut self.shift,
+            0x11 | 0xA2 | 0xA3 => &mut self.ctrl,
+            0x12 | 0xA4 | 0xA5 => &mut self.alt,
+            0x5B | 0x5C => &mut self.win,
+            _ => return false,
+        };
+        *held = down;
+        true
+    }
+
+    fn prefix(&self) -> String {
+        let mut prefix = String::new();
+        for (held, name) in [
+            (self.ctrl, "CTRL+"),
+            (self.alt, "ALT+"),
+            (self.shift, "SHIFT+"),
+            (self.win, "WIN+"),
+        ] {
+            if held {
+                prefix.push_str(name);
+            }
+        }
+        prefix
+    }
+}
+
+/// Draws mouse click ripples and the keys being pressed onto captured BGRA frames
+///
+/// Input arrives from the low-level hooks stamped with a QPC; each frame only shows the input
+/// that happened before it was captured, so the overlay lines up with the picture.
+pub(crate) struct InputOverlay {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    vertex_shader: ID3D11VertexShader,
+    pixel_shader: ID3D11PixelShader,
+    constants: ID3D11Buffer,
+    blend_state: ID3D11BlendState,
+    config: InputOverlayConfig,
+    events: Receiver<InputEvent>,
+    /// Events not yet reached by the frames, oldest first
+    pending: VecDeque<InputEvent>,
+    /// Position and QPC of the clicks still shown
+    clicks: VecDeque<(i32, i32, u64)>,
+    modifiers: Modifiers,
+    caption: String,
+    /// QPC of the last key added to the caption
+    caption_qpc: u64,
+    /// Whether the caption is typed text that further keys are appended to
+    caption_is_text: bool,
+    qpc_frequency: u64,
+}
+
+/// Shaders, constant buffer and blend state of the overlay on `device`
+unsafe fn create_resources(
+    device: &ID3D11Device,
+) -> Result<(
+    ID3D11VertexShader,
+    ID3D11PixelShader,
+    ID3D11Buffer,
+    ID3D11BlendState,
+)> {
+    let vs_blob = compile_shader(RIPPLE_HLSL, "vs_main", "vs_5_0")?;
+    let mut vertex_shader = None;
+    device.CreateVertexShader(blob_bytes(&vs_blob), None, Some(&mut vertex_shader))?;
+    let ps_blob = compile_shader(RIPPLE_HLSL, "ps_ripple", "ps_5_0")?;
+    let mut pixel_shader = None;
+    device.CreatePixelShader(blob_bytes(&ps_blob), None, Some(&mut pixel_shader))?;
+
+    let buffer_desc = D3D11_BUFFER_DESC {
+        ByteWidth: std::mem::size_of::<RippleParams>() as u32,
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+        ..Default::default()
+    };
+    let mut constants = None;
+    device.CreateBuffer(&buffer_desc, None, Some(&mut constants))?;
+
+    let mut blend_desc = D3D11_BLEND_DESC::default();
+    blend_desc.RenderTarget[0] = D3D11_RENDER_TARGET_BLEND_DESC {
+        BlendEnable: true.into(),
+        SrcBlend: D3D11_BLEND_SRC_ALPHA,
+        DestBlend: D3D11_BLEND_INV_SRC_ALPHA,
+        BlendOp: D3D11_BLEND_OP_ADD,
+        SrcBlendAlpha: D3D11_BLEND_ZERO,
+        DestBlendAlpha: D3D11_BLEND_ONE,
+        BlendOpAlpha: D3D11_BLEND_OP_ADD,
+        RenderTargetWriteMask: D3D11_COLOR_WRITE_ENABLE_ALL.0 as u8,
+    };
+    let mut blend_state = None;
+    device.CreateBlendState(&blend_desc, Some(&mut blend_state))?;
+
+    Ok((
+        vertex_shader.unwrap(),
+        pixel_shader.unwrap(),
+        constants.unwrap(),
+        blend_state.unwrap(),
+    ))
+}
+
+impl InputOverlay {
+    pub unsafe fn new(
+        device: &ID3D11Device,
+        config: InputOverlayConfig,
+        events: Receiver<InputEvent>,
+    ) -> Result<Self> {
+        info!("Creating input overlay: {:?}", config);
+        let (vertex_shader, pixel_shader, constants, blend_state) = create_resources(device)?;
+
+        let mut frequency = 0i64;
+        QueryPerformanceFrequency(&mut frequency);
+
+        Ok(Self {
+            device: device.clone(),
+            context: device.GetImmediateContext()?,
+            vertex_shader,
+            pixel_shader,
+            constants,
+            blend_state,
+            config,
+            events,
+            pending: VecDeque::new(),
+            clicks: VecDeque::new(),
+            modifiers: Modifiers::default(),
+            caption: String::new(),
+            caption_qpc: 0,
+            caption_is_text: false,
+            qpc_frequency: frequency.max(1) as u64,
+        })
+    }
+
+    /// Draw onto frames from `device` from now on, keeping the clicks and keys still shown
+    pub unsafe fn set_device(&mut self, device: &ID3D11Device) -> Result<()> {
+        let (vertex_shader, pixel_shader, constants, blend_state) = create_resources(device)?;
+        self.context = device.GetImmediateContext()?;
+        self.device = device.clone();
+        self.vertex_shader = vertex_shader;
+        self.pixel_shader = pixel_shader;
+        self.constants = constants;
+        self.blend_state = blend_state;
+        Ok(())
+    }
+
+    /// Draw the input up to the capture time of `sample` onto its BGRA texture; the keystroke
+    /// display is placed in a corner of `region`, the recorded part of the frame
+    pub unsafe fn apply(&mut self, sample: &IMFSample, region: RECT) -> Result<()> {
+        let frame_qpc = sample
+            .GetUINT64(&MFSampleExtension_DeviceTimestamp)
+            .unwrap_or_else(|_| {
+                let mut now = 0i64;
+                QueryPerformanceCounter(&mut now);
+                now as u64
+            });
+        self.pending.extend(self.events.try_iter());
+        while let Some(event) = self.pending.front().copied() {
+            let qpc = match event {
+                InputEvent::Click { qpc, .. } | InputEvent::Key { qpc, .. } => qpc,
+            };
+            if qpc > frame_qpc {
+                break;
+            }
+            self.pending.pop_front();
+            self.handle(event);
+        }
+
+        let click_ticks = self.ticks(self.config.click_duration);
+        self.clicks
+            .retain(|&(_, _, qpc)| frame_qpc.saturating_sub(qpc) < click_ticks);
+        let caption_visible = !self.caption.is_empty()
+            && frame_qpc.saturating_sub(self.caption_qpc) < self.ticks(self.config.keystroke_duration);
+        if !caption_visible {
+            self.caption.clear();
+            self.caption_is_text = false;
+        }
+        if self.clicks.is_empty() && self.caption.is_empty() {
+            return Ok(());
+        }
+
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+        let result = self.draw_clicks(&texture, &desc, frame_qpc);
+        if result.is_ok() && !self.caption.is_empty() {
+            self.draw_caption(&texture, &desc, region);
+        }
+        multithread.Leave();
+        result
+    }
+
+    fn handle(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Click { x, y, qpc } if self.config.show_clicks => {
+                self.clicks.push_back((x, y, qpc));
+            }
+            InputEvent::Key { vk, down, qpc } if self.config.show_keystrokes => {
+                if self.modifiers.update(vk, down) || !down {
+                    return;
+                }
+                let shortcut = self.modifiers.ctrl || self.modifiers.alt || self.modifiers.win;
+                if self.config.shortcuts_only && !shortcut {
+                    return;
+                }
+                let name = match key_name(vk) {
+                    name if shortcut && name == " " => "SPACE".to_string(),
+                    name => name,
+                };
+                // Typed characters run together; anything else replaces the display
+                let typed = !shortcut && name.chars().count() == 1;
+                if typed && self.caption_is_text {
+                    self.caption.push_str(&name);
+                    let excess = self.caption.chars().count().saturating_sub(MAX_CAPTION_CHARS);
+                    self.caption.drain(..excess);
+                } else {
+                    self.caption = format!("{}{}", self.modifiers.prefix(), name);
+                }
+                self.caption_is_text = typed;
+                self.caption_qpc = qpc;
+            }
+            _ => {}
+        }
+    }
+
+    unsafe fn draw_clicks(
+        &self,
+        texture: &ID3D11Texture2D,
+        desc: &D3D11_TEXTURE2D_DESC,
+        frame_qpc: u64,
+    ) -> Result<()> {
+        if self.clicks.is_empty() {
+            return Ok(());
+        }
+        let mut rtv = None;
+        self.device
+            .CreateRenderTargetView(texture, None, Some(&mut rtv))?;
+
+        self.context
+            .IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        self.context.IASetInputLayout(None);
+        self.context.VSSetShader(&self.vertex_shader, None);
+        self.context.PSSetShader(&self.pixel_shader, None);
+        self.context
+            .PSSetConstantBuffers(0, Some(&[Some(self.constants.clone())]));
+        self.context.OMSetRenderTargets(Some(&[rtv]), None);
+        self.context
+            .OMSetBlendState(&self.blend_state, None, 0xffff_ffff);
+
+        let duration = self.ticks(self.config.click_duration).max(1) as f32;
+        let [r, g, b] = self.config.click_color;
+        for &(x, y, qpc) in &self.clicks {
+            let progress = (frame_qpc.saturating_sub(qpc) as f32 / duration).min(1.0);
+            let radius = self.config.click_radius as f32 * (0.3 + 0.7 * progress);
+            let reach = radius + RING_WIDTH;
+
+            // Only the ring's bounding box inside the texture is drawn
+            let left = (x as f32 - reach).max(0.0);
+            let top = (y as f32 - reach).max(0.0);
+            let right = (x as f32 + reach).min(desc.Width as f32);
+            let bottom = (y as f32 + reach).min(desc.Height as f32);
+            if right <= left || bottom <= top {
+                continue;
+            }
+
+            let params = RippleParams {
+                ripple: [x as f32, y as f32, radius, 1.0 - progress],
+                color: [
+                    r as f32 / 255.0,
+                    g as f32 / 255.0,
+                    b as f32 / 255.0,
+                    RING_WIDTH,
+                ],
+            };
+            self.context.UpdateSubresource(
+                &self.constants,
+                0,
+                None,
+                &params as *const _ as *const _,
+                0,
+                0,
+            );
+            self.context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+                TopLeftX: left,
+                TopLeftY: top,
+                Width: right - left,
+                Height: bottom - top,
+                MinDepth: 0.0,
+                MaxDepth: 1.0,
+            }]));
+            self.context.Draw(3, 0);
+        }
+
+        // Restore the default blending and unbind so the texture can be used as MFT input again
+        self.context.OMSetBlendState(None, None, 0xffff_ffff);
+        self.context.OMSetRenderTargets(None, None);
+        Ok(())
+    }
+
+    unsafe fn draw_caption(
+        &self,
+        texture: &ID3D11Texture2D,
+        desc: &D3D11_TEXTURE2D_DESC,
+        region: RECT,
+    ) {
+        let scale = self.config.keystroke_scale.max(1) as usize;
+        let (pixels, width, height) = render_text(&self.caption, scale);
+        let (x, y) = match self.config.keystroke_corner {
+            OverlayCorner::TopLeft => (region.left + CAPTION_MARGIN, region.top + CAPTION_MARGIN),
+            OverlayCorner::TopRight => (
+                region.right - CAPTION_MARGIN - width as i32,
+                region.top + CAPTION_MARGIN,
+            ),
+            OverlayCorner::BottomLeft => (
+                region.left + CAPTION_MARGIN,
+                region.bottom - CAPTION_MARGIN - height as i32,
+            ),
+            OverlayCorner::BottomRight => (
+                region.right - CAPTION_MARGIN - width as i32,
+                region.bottom - CAPTION_MARGIN - height as i32,
+            ),
+        };
+
+        // Keep the top-left corner inside the texture and clip the rest
+        let (x, y) = (x.max(0) as u32, y.max(0) as u32);
+        if x >= desc.Width || y >= desc.Height {
+            debug!("Keystroke display at ({}, {}) is outside the frame", x, y);
+            return;
+        }
+        let region = D3D11_BOX {
+            left: x,
+            top: y,
+            front: 0,
+            right: x + width.min(desc.Width - x),
+            bottom: y + height.min(desc.Height - y),
+            back: 1,
+        };
+        self.context.UpdateSubresource(
+            texture,
+            0,
+            Some(&region),
+            pixels.as_ptr() as *const _,
+            width * 4,
+            0,
+        );
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        (duration.as_secs_f64() * self.qpc_frequency as f64) as u64
+    }
+}
+
+/// Label of a virtual-key code in the keystroke display
+fn key_name(vk: u32) -> String {
+    match vk {
+        0x30..=0x39 | 0x41..=0x5A => char::from(vk as u8).to_string(),
+        0x60..=0x69 => format!("NUM{}", vk - 0x60),
+        0x70..=0x87 => format!("F{}", vk - 0x6F),
+        0x08 => "BKSP".to_string(),
+        0x09 => "TAB".to_string(),
+        0x0D => "ENTER".to_string(),
+        0x1B => "ESC".to_string(),
+        0x20 => " ".to_string(),
+        0x21 => "PGUP".to_string(),
+        0x22 => "PGDN".to_string(),
+        0x23 => "END".to_string(),
+        0x24 => "HOME".to_string(),
+        0x25 => "LEFT".to_string(),
+        0x26 => "UP".to_string(),
+        0x27 => "RIGHT".to_string(),
+        0x28 => "DOWN".to_string(),
+        0x2D => "INS".to_string(),
+        0x2E => "DEL".to_string(),
+        0xBB => "+".to_string(),
+        0xBD => "-".to_string(),
+        _ => format!("KEY{}", vk),
+    }
+}
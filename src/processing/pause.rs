@@ -0,0 +1,139 @@
+use crate::logging::info;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::IMFSample;
+
+use super::audio::SourceId;
+use super::segment::retime_sample;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::types::{duration_to_hns, hns_to_duration};
+
+/// Streams retimed by the pause gate, each with its own timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PauseStream {
+    Video,
+    SystemAudio,
+    Microphone,
+    /// Another mixer source: a captured process or an extra microphone
+    Source(SourceId),
+}
+
+/// Drops samples while the recording is paused and moves later ones back by the time spent
+/// paused, so the file continues seamlessly where it was paused
+///
+/// `Recorder::pause_recording` only sets the flag; the gate picks it up on the processing thread.
+/// Capture keeps running in the meantime, so resuming takes effect with the next frame.
+///
+/// Audio is also shifted by the A/V sync offset, which can change at any time.
+pub(crate) struct PauseGate {
+    paused: Arc<AtomicBool>,
+    /// Shift of the audio streams, in 100ns units
+    av_sync_offset: Arc<AtomicI64>,
+    events: EventEmitter,
+    /// When the current pause began, None while recording
+    paused_since: Option<Instant>,
+    /// Total time spent paused, in 100ns units
+    offset: i64,
+    /// Last admitted timestamp of each stream
+    last: HashMap<PauseStream, i64>,
+}
+
+impl PauseGate {
+    pub fn new(
+        paused: Arc<AtomicBool>,
+        av_sync_offset: Arc<AtomicI64>,
+        events: EventEmitter,
+    ) -> Self {
+        Self {
+            paused,
+            av_sync_offset,
+            events,
+            paused_since: None,
+            offset: 0,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Pick up a pause or resume requested since the last call
+    pub fn update(&mut self) {
+        let timestamp = hns_to_duration(
+            self.last
+                .get(&PauseStream::Video)
+                .copied()
+                .unwrap_or(0)
+                .max(0),
+        );
+        match (self.paused.load(Ordering::Relaxed), self.paused_since) {
+            (true, None) => {
+                info!("Recording paused at {:?}", timestamp);
+                self.paused_since = Some(Instant::now());
+                self.events.emit(RecorderEvent::Paused { timestamp });
+            }
+            (false, Some(since)) => {
+                let paused_for = since.elapsed();
+                info!(
+                    "Recording resumed at {:?} after {:?}",
+                    timestamp, paused_for
+                );
+                self.paused_since = None;
+                self.offset += duration_to_hns(paused_for);
+                self.events.emit(RecorderEvent::Resumed {
+                    timestamp,
+                    paused_for,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether samples are being dropped
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    /// Time spent paused so far, in 100ns units
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    /// Retime `sample` of `stream`, currently at `time`, past the pauses so far, returning its
+    /// new timestamp and the sample to continue with, or None if it must be dropped because the
+    /// recording is paused
+    ///
+    /// A sample that moves is replaced by a retimed copy sharing its buffers, as the capture may
+    /// still hold the original.
+    pub unsafe fn admit(
+        &mut self,
+        stream: PauseStream,
+        sample: &IMFSample,
+        time: i64,
+    ) -> Result<Option<(i64, IMFSample)>> {
+        if self.is_paused() {
+            return Ok(None);
+        }
+        let shift = match stream {
+            PauseStream::Video => 0,
+            PauseStream::SystemAudio | PauseStream::Microphone | PauseStream::Source(_) => {
+                self.av_sync_offset.load(Ordering::Relaxed)
+            }
+        };
+        if self.offset == 0 && shift == 0 {
+            self.last.insert(stream, time);
+            return Ok(Some((time, sample.clone())));
+        }
+
+        // Samples captured during the pause but only processed after it, or moved earlier by a
+        // lowered sync offset, would go back in time; audio moved before the start is cut off
+        let retimed = time - self.offset + shift;
+        let last = self.last.get(&stream).copied();
+        if retimed < 0 || last.is_some_and(|last| retimed <= last) {
+            return Ok(None);
+        }
+        let copy = retime_sample(sample, retimed)?;
+        self.last.insert(stream, retimed);
+        Ok(Some((retimed, copy)))
+    }
+}
@@ -0,0 +1,275 @@
+use crate::logging::{debug, info, warn};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::{ComInterface, Interface, Result, HRESULT, PCWSTR};
+use windows::Win32::Foundation::{CloseHandle, HANDLE, WAIT_TIMEOUT};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11Texture2D, D3D11_BIND_SHADER_RESOURCE, D3D11_CPU_ACCESS_FLAG,
+    D3D11_RESOURCE_MISC_SHARED_KEYEDMUTEX, D3D11_RESOURCE_MISC_SHARED_NTHANDLE,
+    D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+};
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT;
+use windows::Win32::Graphics::Dxgi::{
+    IDXGIKeyedMutex, IDXGIResource1, DXGI_SHARED_RESOURCE_READ, DXGI_SHARED_RESOURCE_WRITE,
+};
+use windows::Win32::Media::MediaFoundation::IMFSample;
+
+use super::image::Nv12Reader;
+use super::video::sample_texture;
+use crate::types::{frame_time_hns, hns_to_duration};
+
+/// Frames a preview receiver may fall behind by before newer frames are dropped for it
+const PREVIEW_QUEUE: usize = 2;
+
+/// Rate and size of the frames handed to `Recorder::preview_receiver`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct PreviewSpec {
+    /// Preview frames per second, independent of the recording's frame rate
+    pub fps: u32,
+    /// Width of the preview frames in pixels, the height follows the aspect ratio; 0 keeps the
+    /// output size
+    pub width: u32,
+}
+
+impl Default for PreviewSpec {
+    fn default() -> Self {
+        Self {
+            fps: 10,
+            width: 640,
+        }
+    }
+}
+
+/// A downscaled frame of what is being recorded, for showing a live preview
+#[derive(Debug, Clone)]
+pub struct PreviewFrame {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed RGBA rows
+    pub data: Vec<u8>,
+    /// Position of the frame in the recording
+    pub timestamp: Duration,
+}
+
+/// The receivers handed out by `Recorder::preview_receiver`, kept across recordings
+#[derive(Clone, Default)]
+pub(crate) struct PreviewSubscribers(Arc<Mutex<Vec<SyncSender<PreviewFrame>>>>);
+
+impl PreviewSubscribers {
+    pub fn subscribe(&self) -> Receiver<PreviewFrame> {
+        let (sender, receiver) = sync_channel(PREVIEW_QUEUE);
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Hand `frame` to every receiver with room for it, forgetting the dropped ones
+    fn send(&self, frame: PreviewFrame) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|sender| match sender.try_send(frame.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => {
+                    debug!("Preview receiver dropped");
+                    false
+                }
+            });
+    }
+}
+
+/// Reads processed frames back at the preview rate for the preview receivers
+///
+/// Nothing is read back while no receiver is listening, so an unused preview costs nothing.
+pub(crate) struct PreviewTap {
+    spec: PreviewSpec,
+    subscribers: PreviewSubscribers,
+    reader: Nv12Reader,
+    /// Time the next preview frame is due, in 100ns units
+    next: i64,
+}
+
+impl PreviewTap {
+    pub fn new(spec: PreviewSpec, subscribers: PreviewSubscribers) -> Self {
+        Self {
+            spec,
+            subscribers,
+            reader: Nv12Reader::default(),
+            next: 0,
+        }
+    }
+
+    /// Offer a processed frame at `timestamp` (100ns units from the start of the recording)
+    pub unsafe fn offer(&mut self, sample: &IMFSample, timestamp: i64) -> Result<()> {
+        // A frame a little early still counts, so capture jitter does not skip preview frames
+        let interval = frame_time_hns(1, self.spec.fps.max(1), 1);
+        if timestamp < self.next - interval / 4 || self.subscribers.is_empty() {
+            return Ok(());
+        }
+        // Keep to the preview rate, unless frames came too slowly to
+        self.next = (self.next + interval).max(timestamp + interval / 2);
+
+        let frame = self.reader.read(sample, self.spec.width)?;
+        let mut data = frame.data;
+        for pixel in data.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+        self.subscribers.send(PreviewFrame {
+            width: frame.width,
+            height: frame.height,
+            data,
+            timestamp: hns_to_duration(timestamp),
+        });
+        Ok(())
+    }
+}
+
+/// A shared texture holding the latest frame as it goes to the encoder, see
+/// `Recorder::shared_preview_texture`
+///
+/// Open it with `ID3D11Device1::OpenSharedResource1` and acquire its `IDXGIKeyedMutex` with key
+/// 0 around reading it, releasing it with key 0 again; frames arriving while it is held are not
+/// copied into it. The texture is `format` at the output size: BGRA with the RGB pixel pipeline,
+/// otherwise NV12, whose planes can be read through `R8_UNORM` and `R8G8_UNORM` views.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedPreviewTexture {
+    /// NT handle of the texture, closed by the recorder when the recording stops or the texture
+    /// is replaced; a texture opened from it stays valid
+    pub handle: HANDLE,
+    pub width: u32,
+    pub height: u32,
+    pub format: DXGI_FORMAT,
+}
+
+/// The shared texture and what it was created for
+struct SharedTexture {
+    device: ID3D11Device,
+    texture: ID3D11Texture2D,
+    mutex: IDXGIKeyedMutex,
+    shared: SharedPreviewTexture,
+}
+
+/// Copies every processed frame into the shared preview texture
+///
+/// The texture is created on the first frame and again when frames arrive on another device,
+/// e.g. after the capture moved to another adapter.
+pub(crate) struct SharedPreviewWriter {
+    current: Arc<Mutex<Option<SharedPreviewTexture>>>,
+    texture: Option<SharedTexture>,
+}
+
+// Created without a texture and only used on the processing thread it is moved to
+unsafe impl Send for SharedPreviewWriter {}
+
+impl SharedPreviewWriter {
+    /// Publish the texture in `current` for `Recorder::shared_preview_texture`
+    pub fn new(current: Arc<Mutex<Option<SharedPreviewTexture>>>) -> Self {
+        Self {
+            current,
+            texture: None,
+        }
+    }
+
+    /// Copy a processed frame into the shared texture, returning whether the texture was
+    /// (re)created for it
+    pub unsafe fn offer(&mut self, sample: &IMFSample) -> Result<bool> {
+        let source = sample_texture(sample)?;
+        let device = source.GetDevice()?;
+        let created = match &self.texture {
+            Some(texture) if texture.device == device => false,
+            _ => {
+                self.replace(create_shared_texture(&device, &source)?);
+                true
+            }
+        };
+        let texture = self.texture.as_ref().unwrap();
+
+        // Never wait for the reader, the frame just misses the preview
+        let acquired = (Interface::vtable(&texture.mutex).AcquireSync)(
+            Interface::as_raw(&texture.mutex),
+            0,
+            0,
+        );
+        if acquired == HRESULT(WAIT_TIMEOUT.0 as i32) {
+            return Ok(created);
+        }
+        acquired.ok()?;
+        device
+            .GetImmediateContext()?
+            .CopyResource(&texture.texture, &source);
+        texture.mutex.ReleaseSync(0)?;
+        Ok(created)
+    }
+
+    fn replace(&mut self, texture: SharedTexture) {
+        *self.current.lock().unwrap() = Some(texture.shared);
+        if let Some(previous) = self.texture.replace(texture) {
+            close_shared_handle(previous.shared.handle);
+        }
+    }
+}
+
+impl Drop for SharedPreviewWriter {
+    fn drop(&mut self) {
+        *self.current.lock().unwrap() = None;
+        if let Some(texture) = self.texture.take() {
+            close_shared_handle(texture.shared.handle);
+        }
+    }
+}
+
+/// Create a shareable texture of the size and format of `source` on `device`
+unsafe fn create_shared_texture(
+    device: &ID3D11Device,
+    source: &ID3D11Texture2D,
+) -> Result<SharedTexture> {
+    let mut source_desc = D3D11_TEXTURE2D_DESC::default();
+    source.GetDesc(&mut source_desc);
+    let desc = D3D11_TEXTURE2D_DESC {
+        MipLevels: 1,
+        ArraySize: 1,
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_SHADER_RESOURCE,
+        CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+        MiscFlags: D3D11_RESOURCE_MISC_SHARED_NTHANDLE | D3D11_RESOURCE_MISC_SHARED_KEYEDMUTEX,
+        ..source_desc
+    };
+    let mut texture = None;
+    device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+    let texture = texture.unwrap();
+    let handle = texture.cast::<IDXGIResource1>()?.CreateSharedHandle(
+        None,
+        DXGI_SHARED_RESOURCE_READ | DXGI_SHARED_RESOURCE_WRITE,
+        PCWSTR::null(),
+    )?;
+    info!(
+        "Created {}x{} {:?} shared preview texture",
+        desc.Width, desc.Height, desc.Format
+    );
+    Ok(SharedTexture {
+        device: device.clone(),
+        mutex: texture.cast()?,
+        texture,
+        shared: SharedPreviewTexture {
+            handle,
+            width: desc.Width,
+            height: desc.Height,
+            format: desc.Format,
+        },
+    })
+}
+
+fn close_shared_handle(handle: HANDLE) {
+    if let Err(e) = unsafe { CloseHandle(handle) }.ok() {
+        warn!("Failed to close the shared preview texture handle: {:?}", e);
+    }
+}
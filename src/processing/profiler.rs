@@ -0,0 +1,322 @@
+use crate::logging::{debug, info, warn};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use windows::core::Interface;
+use windows::Win32::Foundation::S_OK;
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11DeviceContext, ID3D11Query, D3D11_ASYNC_GETDATA_DONOTFLUSH,
+    D3D11_QUERY_DATA_TIMESTAMP_DISJOINT, D3D11_QUERY_DESC, D3D11_QUERY_TIMESTAMP,
+    D3D11_QUERY_TIMESTAMP_DISJOINT,
+};
+
+/// Unresolved GPU measurements kept before new ones are dropped
+const MAX_PENDING_QUERIES: usize = 64;
+
+/// Pipeline stage measured by the profiler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PipelineStage {
+    /// Copying the duplicated desktop frame into a pool texture
+    CaptureCopy,
+    /// Scaling and BGRA to NV12 conversion
+    Convert,
+    /// Handing the converted frame to the sink writer
+    EncodeSubmit,
+}
+
+impl PipelineStage {
+    const ALL: [PipelineStage; 3] = [Self::CaptureCopy, Self::Convert, Self::EncodeSubmit];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Timing of one pipeline stage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StageTiming {
+    /// Frames measured
+    pub frames: u64,
+    /// Wall-clock time the calling thread spent in the stage
+    pub cpu_average: Duration,
+    pub cpu_max: Duration,
+    /// GPU time between timestamp queries around the stage, None if no query resolved
+    pub gpu_average: Option<Duration>,
+    pub gpu_max: Option<Duration>,
+}
+
+/// Per-stage timings of the capture pipeline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProfileReport {
+    /// Time covered by the report
+    pub window: Duration,
+    pub capture_copy: StageTiming,
+    pub convert: StageTiming,
+    pub encode_submit: StageTiming,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StageAccumulator {
+    frames: u64,
+    cpu_total: Duration,
+    cpu_max: Duration,
+    gpu_frames: u64,
+    gpu_total: Duration,
+    gpu_max: Duration,
+}
+
+impl StageAccumulator {
+    fn add_cpu(&mut self, elapsed: Duration) {
+        self.frames += 1;
+        self.cpu_total += elapsed;
+        self.cpu_max = self.cpu_max.max(elapsed);
+    }
+
+    fn add_gpu(&mut self, elapsed: Duration) {
+        self.gpu_frames += 1;
+        self.gpu_total += elapsed;
+        self.gpu_max = self.gpu_max.max(elapsed);
+    }
+
+    fn timing(&self) -> StageTiming {
+        let average = |total: Duration, count: u64| match count {
+            0 => Duration::ZERO,
+            count => total / count as u32,
+        };
+        StageTiming {
+            frames: self.frames,
+            cpu_average: average(self.cpu_total, self.frames),
+            cpu_max: self.cpu_max,
+            gpu_average: (self.gpu_frames > 0).then(|| average(self.gpu_total, self.gpu_frames)),
+            gpu_max: (self.gpu_frames > 0).then_some(self.gpu_max),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Accumulators {
+    stages: [StageAccumulator; 3],
+    since: Option<Instant>,
+}
+
+impl Accumulators {
+    fn report(&self) -> ProfileReport {
+        ProfileReport {
+            window: self.since.map(|since| since.elapsed()).unwrap_or_default(),
+            capture_copy: self.stages[PipelineStage::CaptureCopy.index()].timing(),
+            convert: self.stages[PipelineStage::Convert.index()].timing(),
+            encode_submit: self.stages[PipelineStage::EncodeSubmit.index()].timing(),
+        }
+    }
+}
+
+/// Timestamp queries bracketing one measurement
+struct QuerySet {
+    disjoint: ID3D11Query,
+    start: ID3D11Query,
+    end: ID3D11Query,
+}
+
+/// A stage measurement in progress, returned by [`PipelineProfiler::begin`]
+pub(crate) struct StageScope {
+    stage: PipelineStage,
+    started: Instant,
+    queries: Option<QuerySet>,
+}
+
+/// Measures CPU and GPU time of the pipeline stages
+///
+/// GPU times come from D3D11 timestamp queries on the immediate context, which the capture and
+/// processing threads share. They are resolved lazily so the profiler never stalls the pipeline.
+pub(crate) struct PipelineProfiler {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    /// Measurements since the recording started
+    total: Mutex<Accumulators>,
+    /// Measurements since the last periodic report
+    window: Mutex<Accumulators>,
+    pending: Mutex<VecDeque<(PipelineStage, QuerySet)>>,
+    free_queries: Mutex<Vec<QuerySet>>,
+}
+
+impl PipelineProfiler {
+    pub unsafe fn new(device: &ID3D11Device) -> windows::core::Result<Self> {
+        info!("Pipeline profiling enabled");
+        Ok(Self {
+            device: device.clone(),
+            context: device.GetImmediateContext()?,
+            total: Mutex::new(Accumulators::default()),
+            window: Mutex::new(Accumulators::default()),
+            pending: Mutex::new(VecDeque::new()),
+            free_queries: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Start measuring a stage; the caller must hold whatever lock guards the GPU work
+    pub unsafe fn begin(&self, stage: PipelineStage) -> StageScope {
+        let queries = match self.acquire_queries() {
+            Ok(queries) => {
+                self.context.Begin(&queries.disjoint);
+                self.context.End(&queries.start);
+                Some(queries)
+            }
+            Err(e) => {
+                debug!("Failed to create timestamp queries: {:?}", e);
+                None
+            }
+        };
+        StageScope {
+            stage,
+            started: Instant::now(),
+            queries,
+        }
+    }
+
+    /// Finish measuring a stage started with [`begin`](Self::begin)
+    pub unsafe fn end(&self, scope: StageScope) {
+        let elapsed = scope.started.elapsed();
+        if let Some(queries) = scope.queries {
+            self.context.End(&queries.end);
+            self.context.End(&queries.disjoint);
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() < MAX_PENDING_QUERIES {
+                pending.push_back((scope.stage, queries));
+            }
+        }
+        // Earlier frames have usually finished on the GPU by now
+        self.resolve_queries();
+
+        for accumulators in [&self.total, &self.window] {
+            let mut accumulators = accumulators.lock().unwrap();
+            accumulators.since.get_or_insert(scope.started);
+            accumulators.stages[scope.stage.index()].add_cpu(elapsed);
+        }
+    }
+
+    /// Timings since the recording started
+    pub fn report(&self) -> ProfileReport {
+        unsafe { self.resolve_queries() };
+        self.total.lock().unwrap().report()
+    }
+
+    /// How long the current reporting window has been running
+    pub fn window_elapsed(&self) -> Duration {
+        self.window
+            .lock()
+            .unwrap()
+            .since
+            .map(|since| since.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Timings since the previous call, starting a new window
+    pub fn take_window_report(&self) -> ProfileReport {
+        unsafe { self.resolve_queries() };
+        let mut window = self.window.lock().unwrap();
+        let report = window.report();
+        *window = Accumulators {
+            since: Some(Instant::now()),
+            ..Default::default()
+        };
+        report
+    }
+
+    unsafe fn acquire_queries(&self) -> windows::core::Result<QuerySet> {
+        if let Some(queries) = self.free_queries.lock().unwrap().pop() {
+            return Ok(queries);
+        }
+        let create = |query| -> windows::core::Result<ID3D11Query> {
+            let desc = D3D11_QUERY_DESC {
+                Query: query,
+                MiscFlags: 0,
+            };
+            let mut created = None;
+            self.device.CreateQuery(&desc, Some(&mut created))?;
+            Ok(created.unwrap())
+        };
+        Ok(QuerySet {
+            disjoint: create(D3D11_QUERY_TIMESTAMP_DISJOINT)?,
+            start: create(D3D11_QUERY_TIMESTAMP)?,
+            end: create(D3D11_QUERY_TIMESTAMP)?,
+        })
+    }
+
+    /// Collect the GPU results that are ready, oldest first
+    unsafe fn resolve_queries(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while let Some((stage, queries)) = pending.front() {
+            let mut disjoint = D3D11_QUERY_DATA_TIMESTAMP_DISJOINT::default();
+            let mut start = 0u64;
+            let mut end = 0u64;
+            let results = [
+                self.get_data(&queries.disjoint, &mut disjoint),
+                self.get_data(&queries.start, &mut start),
+                self.get_data(&queries.end, &mut end),
+            ];
+            if results.contains(&Some(false)) {
+                break;
+            }
+
+            let stage = *stage;
+            let (_, queries) = pending.pop_front().unwrap();
+            self.free_queries.lock().unwrap().push(queries);
+
+            // Timestamps are meaningless if the GPU clock changed in between
+            if results.contains(&None)
+                || disjoint.Disjoint.as_bool()
+                || disjoint.Frequency == 0
+                || end < start
+            {
+                continue;
+            }
+            let nanos = (end - start) as u128 * 1_000_000_000 / disjoint.Frequency as u128;
+            let elapsed = Duration::from_nanos(nanos as u64);
+            for accumulators in [&self.total, &self.window] {
+                accumulators.lock().unwrap().stages[stage.index()].add_gpu(elapsed);
+            }
+        }
+    }
+
+    /// Read a query result without flushing
+    ///
+    /// Returns false if the GPU has not reached the query yet and None if it failed.
+    unsafe fn get_data<T>(&self, query: &ID3D11Query, data: &mut T) -> Option<bool> {
+        // The generated wrapper maps S_FALSE ("not ready yet") to Ok, so check the HRESULT
+        let hr = (Interface::vtable(&self.context).GetData)(
+            Interface::as_raw(&self.context),
+            query.as_raw(),
+            data as *mut T as *mut _,
+            std::mem::size_of::<T>() as u32,
+            D3D11_ASYNC_GETDATA_DONOTFLUSH.0 as u32,
+        );
+        if hr.is_err() {
+            warn!("Timestamp query failed: {:?}", hr);
+            return None;
+        }
+        Some(hr == S_OK)
+    }
+}
+
+impl std::fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "over {:?}:", self.window)?;
+        for (stage, timing) in PipelineStage::ALL.iter().zip([
+            self.capture_copy,
+            self.convert,
+            self.encode_submit,
+        ]) {
+            write!(
+                f,
+                " {:?} cpu {:?} avg / {:?} max",
+                stage, timing.cpu_average, timing.cpu_max
+            )?;
+            if let (Some(average), Some(max)) = (timing.gpu_average, timing.gpu_max) {
+                write!(f, ", gpu {:?} avg / {:?} max", average, max)?;
+            }
+            write!(f, ";")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+use crate::logging::{debug, info, trace, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::{IMFSample, IMFSinkWriter};
+
+use super::segment::retime_sample;
+use crate::types::{duration_to_hns, hns_to_duration, MemoryLimits, MemoryUsage, SendableSample};
+
+/// Stream the replay file's video is written to
+const VIDEO_STREAM_INDEX: u32 = 0;
+
+/// Samples of one stream in timestamp order, with the timestamp and the bytes held by each
+type Track = VecDeque<(SendableSample, i64, u64)>;
+
+/// A circular buffer of the most recent processed video frames and output audio samples
+///
+/// Samples are stored with the timestamp they carry and only in increasing order per stream,
+/// so anything read back from the buffer can be written to a file as is.
+pub struct ReplayBuffer {
+    /// Maximum duration to keep in the buffer
+    max_duration: Duration,
+    video_samples: Mutex<Track>,
+    audio_samples: Mutex<Track>,
+    /// Caps on the bytes held, past which the oldest samples are dropped early
+    limits: MemoryLimits,
+    /// Bytes held, shared with the texture pools for the recorder's `memory_usage`
+    memory: Arc<Mutex<MemoryUsage>>,
+}
+
+impl ReplayBuffer {
+    /// Create a new replay buffer with a specified duration
+    pub fn new(
+        max_duration: Duration,
+        initial_video_limit: usize,
+        initial_audio_limit: usize,
+        limits: MemoryLimits,
+        memory: Arc<Mutex<MemoryUsage>>,
+    ) -> Self {
+        info!(
+            "Creating replay buffer with max duration of {:?}",
+            max_duration
+        );
+        Self {
+            max_duration,
+            video_samples: Mutex::new(VecDeque::with_capacity(initial_video_limit)),
+            audio_samples: Mutex::new(VecDeque::with_capacity(initial_audio_limit)),
+            limits,
+            memory,
+        }
+    }
+
+    /// Add a processed (NV12) video frame at the time it carries, with the bytes of the texture
+    /// it owns, or 0 if it shares one of the conversion textures
+    pub fn add_video_sample(&self, sample: &IMFSample, texture_bytes: u64) {
+        self.add(&self.video_samples, sample, texture_bytes, true);
+    }
+
+    /// Add an output audio sample (mixed, or the only source) at the time it carries
+    pub fn add_audio_sample(&self, sample: &IMFSample) {
+        let bytes = unsafe { sample.GetTotalLength() }.unwrap_or(0) as u64;
+        self.add(&self.audio_samples, sample, bytes, false);
+    }
+
+    fn add(&self, track: &Mutex<Track>, sample: &IMFSample, bytes: u64, video: bool) {
+        let kind = if video { "video" } else { "audio" };
+        let timestamp = match unsafe { sample.GetSampleTime() } {
+            Ok(timestamp) => timestamp,
+            Err(e) => {
+                warn!("Not buffering {} sample without a timestamp: {:?}", kind, e);
+                return;
+            }
+        };
+
+        let mut samples = track.lock().unwrap();
+        if let Some((_, last, _)) = samples.back() {
+            if timestamp <= *last {
+                debug!(
+                    "Not buffering {} sample at {}, the buffer is already at {}",
+                    kind, timestamp, last
+                );
+                return;
+            }
+        }
+        samples.push_back((SendableSample::new(sample.clone()), timestamp, bytes));
+        let mut memory = self.memory.lock().unwrap();
+        *held_bytes(&mut memory, video) += bytes;
+
+        // Remove samples that are too old
+        let cutoff = timestamp - duration_to_hns(self.max_duration);
+        while samples.front().is_some_and(|(_, time, _)| *time < cutoff) {
+            let (_, _, bytes) = samples.pop_front().unwrap();
+            *held_bytes(&mut memory, video) -= bytes;
+        }
+
+        // Then the oldest ones past the memory limits, always keeping the newest sample
+        while samples.len() > 1 && self.over_limits(&memory, video) {
+            let (_, _, bytes) = samples.pop_front().unwrap();
+            *held_bytes(&mut memory, video) -= bytes;
+            memory.replay_samples_dropped += 1;
+            trace!(
+                "Dropped the oldest buffered {} sample to stay within the memory limits",
+                kind
+            );
+        }
+    }
+
+    /// Whether the samples held break a memory limit that dropping `video` samples can fix
+    fn over_limits(&self, memory: &MemoryUsage, video: bool) -> bool {
+        let over = |limit: Option<u64>, held: u64| limit.is_some_and(|limit| held > limit);
+        let buffered = memory.replay_buffer_bytes();
+        over(self.limits.replay_buffer_bytes, buffered)
+            || (video && over(self.limits.gpu_bytes, memory.gpu_bytes()))
+    }
+
+    /// The buffered video frames, oldest first
+    pub fn video_samples(&self) -> Vec<(SendableSample, i64)> {
+        copy_track(&self.video_samples.lock().unwrap(), i64::MIN)
+    }
+
+    /// The last `duration` of the buffer (or all of it), starting at a video frame
+    ///
+    /// None if no video has been buffered yet.
+    pub fn clip(&self, duration: Option<Duration>) -> Option<ReplayClip> {
+        let video_samples = self.video_samples.lock().unwrap();
+        let latest = video_samples.back()?.1;
+        let since = duration.map_or(i64::MIN, |duration| latest - duration_to_hns(duration));
+        let video = copy_track(&video_samples, since);
+        let start = video.first()?.1;
+        // Audio before the first frame would make the clip open without a picture
+        let audio = copy_track(&self.audio_samples.lock().unwrap(), start);
+        Some(ReplayClip {
+            start,
+            video,
+            audio,
+        })
+    }
+}
+
+/// Bytes of the replay buffer's video or audio samples in `memory`
+fn held_bytes(memory: &mut MemoryUsage, video: bool) -> &mut u64 {
+    if video {
+        &mut memory.replay_video_bytes
+    } else {
+        &mut memory.replay_audio_bytes
+    }
+}
+
+/// Copies of the samples of `track` at or after `since`
+///
+/// The copies do not return anything to the capture pools when they are dropped.
+fn copy_track(track: &Track, since: i64) -> Vec<(SendableSample, i64)> {
+    track
+        .iter()
+        .filter(|(_, timestamp, _)| *timestamp >= since)
+        .map(|(sample, timestamp, _)| {
+            (
+                SendableSample::new(sample.sample.as_ref().clone()),
+                *timestamp,
+            )
+        })
+        .collect()
+}
+
+/// Samples taken out of the replay buffer to be saved, from the first video frame on
+pub struct ReplayClip {
+    /// Timestamp of the first video frame, where the saved file starts
+    pub start: i64,
+    pub video: Vec<(SendableSample, i64)>,
+    pub audio: Vec<(SendableSample, i64)>,
+}
+
+impl ReplayClip {
+    /// Length of the clip from its first frame to the end of its last sample
+    pub fn duration(&self) -> Duration {
+        let end = [&self.video, &self.audio]
+            .into_iter()
+            .filter_map(|track| {
+                let durations = sample_durations(track);
+                Some(track.last()?.1 + durations.last().copied().unwrap_or(0))
+            })
+            .max()
+            .unwrap_or(self.start);
+        hns_to_duration(end - self.start)
+    }
+
+    /// Write the clip to `writer` starting at zero, video on stream 0 and audio on
+    /// `audio_stream_index` (dropped if None), interleaved in timestamp order
+    ///
+    /// The buffered samples may still be queued in the main recording's encoder, so each one is
+    /// written as a retimed copy and the originals are left untouched. `progress` is called
+    /// with the samples written so far and the total after each one.
+    pub unsafe fn write_to(
+        &self,
+        writer: &IMFSinkWriter,
+        audio_stream_index: Option<u32>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        let samples = self.interleaved(audio_stream_index);
+        let total = samples.len();
+        for (written, (stream_index, sample, timestamp, duration)) in
+            samples.into_iter().enumerate()
+        {
+            let retimed = retime_sample(&sample.sample, timestamp - self.start)?;
+            retimed.SetSampleDuration(duration)?;
+            trace!(
+                "Writing replay sample at {} to stream {}",
+                timestamp - self.start,
+                stream_index
+            );
+            writer.WriteSample(stream_index, &retimed)?;
+            progress(written + 1, total);
+        }
+        Ok(())
+    }
+
+    /// The samples in the order `write_to` writes them, each with its stream, timestamp and
+    /// duration: by timestamp, video first at equal times, and without audio if there is no
+    /// `audio_stream_index`
+    fn interleaved(
+        &self,
+        audio_stream_index: Option<u32>,
+    ) -> Vec<(u32, &SendableSample, i64, i64)> {
+        let video_durations = sample_durations(&self.video);
+        let audio_durations = sample_durations(&self.audio);
+        let mut video = self.video.iter().zip(video_durations).peekable();
+        let mut audio = self
+            .audio
+            .iter()
+            .zip(audio_durations)
+            .filter(|_| audio_stream_index.is_some())
+            .peekable();
+
+        let mut samples = Vec::with_capacity(self.video.len() + self.audio.len());
+        loop {
+            let take_video = match (video.peek(), audio.peek()) {
+                (Some(((_, video_time), _)), Some(((_, audio_time), _))) => {
+                    video_time <= audio_time
+                }
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let (stream_index, ((sample, timestamp), duration)) = if take_video {
+                (VIDEO_STREAM_INDEX, video.next().unwrap())
+            } else {
+                (audio_stream_index.unwrap(), audio.next().unwrap())
+            };
+            samples.push((stream_index, sample, *timestamp, duration));
+        }
+        samples
+    }
+}
+
+/// Duration of each sample of `track`: the one it carries, or the distance to the next sample
+/// (the previous distance for the last one) if it carries none
+fn sample_durations(track: &[(SendableSample, i64)]) -> Vec<i64> {
+    let mut durations: Vec<i64> = track.windows(2).map(|pair| pair[1].1 - pair[0].1).collect();
+    durations.push(durations.last().copied().unwrap_or(0));
+    track
+        .iter()
+        .zip(durations)
+        .map(|((sample, _), gap)| {
+            unsafe { sample.sample.GetSampleDuration() }
+                .ok()
+                .filter(|duration| *duration > 0)
+                .unwrap_or(gap)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use windows::Win32::Media::MediaFoundation::MFCreateSample;
+
+    const MS: i64 = 10_000;
+
+    fn buffer(max_duration: Duration) -> ReplayBuffer {
+        ReplayBuffer::new(
+            max_duration,
+            0,
+            0,
+            MemoryLimits::default(),
+            Arc::new(Mutex::new(MemoryUsage::default())),
+        )
+    }
+
+    /// An empty sample at `time`, carrying `duration` unless it is 0
+    fn sample(time: i64, duration: i64) -> IMFSample {
+        unsafe {
+            let sample = MFCreateSample().unwrap();
+            sample.SetSampleTime(time).unwrap();
+            if duration > 0 {
+                sample.SetSampleDuration(duration).unwrap();
+            }
+            sample
+        }
+    }
+
+    fn track(times: &[i64]) -> Vec<(SendableSample, i64)> {
+        times
+            .iter()
+            .map(|&time| (SendableSample::new(sample(time, 0)), time))
+            .collect()
+    }
+
+    fn times(track: &[(SendableSample, i64)]) -> Vec<i64> {
+        track.iter().map(|(_, time)| *time).collect()
+    }
+
+    #[test]
+    fn add_skips_samples_that_do_not_advance() {
+        let buffer = buffer(Duration::from_secs(10));
+        for time in [0, 100 * MS, 100 * MS, 50 * MS, 200 * MS] {
+            buffer.add_video_sample(&sample(time, 0), 10);
+        }
+        assert_eq!(times(&buffer.video_samples()), [0, 100 * MS, 200 * MS]);
+        assert_eq!(buffer.memory.lock().unwrap().replay_video_bytes, 30);
+    }
+
+    #[test]
+    fn add_trims_to_max_duration() {
+        let buffer = buffer(Duration::from_secs(1));
+        for time in (0..=15).map(|tenth| tenth * 100 * MS) {
+            buffer.add_video_sample(&sample(time, 0), 10);
+        }
+        let kept = times(&buffer.video_samples());
+        assert_eq!(kept.first(), Some(&(500 * MS)));
+        assert_eq!(kept.last(), Some(&(1500 * MS)));
+        assert_eq!(
+            buffer.memory.lock().unwrap().replay_video_bytes,
+            10 * kept.len() as u64
+        );
+    }
+
+    #[test]
+    fn clip_starts_at_a_video_frame() {
+        let buffer = buffer(Duration::from_secs(10));
+        for time in [0, 100 * MS, 200 * MS, 300 * MS] {
+            buffer.add_video_sample(&sample(time, 0), 0);
+        }
+        for time in [50 * MS, 150 * MS, 220 * MS, 290 * MS] {
+            buffer.add_audio_sample(&sample(time, 0));
+        }
+
+        let clip = buffer.clip(Some(Duration::from_millis(150))).unwrap();
+        assert_eq!(clip.start, 200 * MS);
+        assert_eq!(times(&clip.video), [200 * MS, 300 * MS]);
+        assert_eq!(times(&clip.audio), [220 * MS, 290 * MS]);
+
+        let whole = buffer.clip(None).unwrap();
+        assert_eq!(whole.start, 0);
+        assert_eq!(whole.audio.len(), 4);
+    }
+
+    #[test]
+    fn clip_needs_video() {
+        let buffer = buffer(Duration::from_secs(10));
+        buffer.add_audio_sample(&sample(0, 0));
+        assert!(buffer.clip(None).is_none());
+    }
+
+    #[test]
+    fn sample_durations_fall_back_to_gaps() {
+        let mut samples = track(&[0, 100, 250]);
+        assert_eq!(sample_durations(&samples), [100, 150, 150]);
+
+        samples[1] = (SendableSample::new(sample(100, 40)), 100);
+        assert_eq!(sample_durations(&samples), [100, 40, 150]);
+
+        assert_eq!(sample_durations(&track(&[7])), [0]);
+    }
+
+    #[test]
+    fn write_order_interleaves_video_and_audio() {
+        let clip = ReplayClip {
+            start: 0,
+            video: track(&[0, 333, 666]),
+            audio: track(&[0, 200, 400, 600]),
+        };
+        let order: Vec<(u32, i64)> = clip
+            .interleaved(Some(1))
+            .into_iter()
+            .map(|(stream, _, time, _)| (stream, time))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (0, 0),
+                (1, 0),
+                (1, 200),
+                (0, 333),
+                (1, 400),
+                (1, 600),
+                (0, 666)
+            ]
+        );
+
+        let video_only: Vec<u32> = clip
+            .interleaved(None)
+            .into_iter()
+            .map(|(stream, ..)| stream)
+            .collect();
+        assert_eq!(video_only, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,465 @@
+use crate::logging::{debug, error, info};
+use windows::core::{ComInterface, Interface, Result, PCSTR};
+use windows::Win32::Foundation::{E_FAIL, FALSE, RECT};
+use windows::Win32::Graphics::Direct3D::Fxc::D3DCompile;
+use windows::Win32::Graphics::Direct3D::{ID3DBlob, D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST};
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{
+    DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R16G16B16A16_FLOAT, DXGI_SAMPLE_DESC,
+};
+use windows::Win32::Graphics::Dxgi::IDXGISurface;
+use windows::Win32::Media::MediaFoundation::{
+    IMFDXGIBuffer, IMFSample, MFCreateDXGISurfaceBuffer, MFCreateSample,
+    MFSampleExtension_DeviceTimestamp,
+};
+
+/// Filter used to scale captured frames to the output resolution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScalingQuality {
+    /// Let the Media Foundation video processor scale (driver dependent)
+    #[default]
+    Default,
+    /// Nearest neighbour; fastest, aliases heavily when downscaling
+    Point,
+    /// Single bilinear tap per pixel
+    Bilinear,
+    /// Two-pass Lanczos-3 filter, recommended for large downscales such as 4K to 1080p
+    HighQuality,
+}
+
+pub(super) const SCALER_HLSL: &str = r#"
+cbuffer Params : register(b0)
+{
+    float4 src_rect; // x, y, width, height of the sampled region in texels
+    float2 src_size; // size of the bound source texture
+    float2 dst_size; // size of the viewport being rendered
+    float2 axis;     // (1, 0) for the horizontal Lanczos pass, (0, 1) for the vertical one
+    float2 padding;
+};
+
+Texture2D<float4> src : register(t0);
+SamplerState linear_sampler : register(s0);
+
+struct VsOut
+{
+    float4 pos : SV_Position;
+    float2 uv : TEXCOORD0;
+};
+
+VsOut vs_main(uint id : SV_VertexID)
+{
+    VsOut o;
+    o.uv = float2((id << 1) & 2, id & 2);
+    o.pos = float4(o.uv * float2(2, -2) + float2(-1, 1), 0, 1);
+    return o;
+}
+
+float2 source_pos(float2 uv)
+{
+    return src_rect.xy + uv * src_rect.zw;
+}
+
+float4 ps_point(VsOut i) : SV_Target
+{
+    int2 p = int2(min(floor(source_pos(i.uv)), src_rect.xy + src_rect.zw - 1));
+    return float4(src.Load(int3(p, 0)).rgb, 1);
+}
+
+float4 ps_bilinear(VsOut i) : SV_Target
+{
+    return float4(src.SampleLevel(linear_sampler, source_pos(i.uv) / src_size, 0).rgb, 1);
+}
+
+static const float PI = 3.14159265;
+
+float lanczos3(float x)
+{
+    x = abs(x);
+    if (x < 1e-5) return 1.0;
+    if (x >= 3.0) return 0.0;
+    float px = PI * x;
+    return 3.0 * sin(px) * sin(px / 3.0) / (px * px);
+}
+
+float4 ps_lanczos(VsOut i) : SV_Target
+{
+    float2 pos = source_pos(i.uv);
+    float src_len = dot(src_rect.zw, axis);
+    float dst_len = dot(dst_size, axis);
+    float center = dot(pos, axis);
+    float lo = dot(src_rect.xy, axis);
+    float hi = lo + src_len - 1;
+
+    // Widen the kernel when downscaling so every source texel contributes
+    float stretch = max(src_len / dst_len, 1.0);
+    float support = 3.0 * stretch;
+
+    // The coordinate across the filter axis is a fixed texel
+    float2 other = floor(pos) * (1 - axis);
+
+    float4 sum = 0;
+    float weight_sum = 0;
+    int first = (int)floor(center - support);
+    int last = (int)ceil(center + support);
+    [loop]
+    for (int t = first; t <= last; t++)
+    {
+        float w = lanczos3((t + 0.5 - center) / stretch);
+        float2 p = other + axis * clamp((float)t, lo, hi);
+        sum += src.Load(int3(p, 0)) * w;
+        weight_sum += w;
+    }
+
+    return float4(saturate(sum.rgb / weight_sum), 1);
+}
+"#;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(super) struct ScalerParams {
+    pub src_rect: [f32; 4],
+    pub src_size: [f32; 2],
+    pub dst_size: [f32; 2],
+    pub axis: [f32; 2],
+    pub padding: [f32; 2],
+}
+
+pub(super) struct RenderTarget {
+    pub texture: ID3D11Texture2D,
+    pub rtv: ID3D11RenderTargetView,
+    pub srv: ID3D11ShaderResourceView,
+}
+
+/// Scales BGRA frames on the GPU with a selectable filter before NV12 conversion
+pub struct GpuScaler {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    vertex_shader: ID3D11VertexShader,
+    pixel_shader: ID3D11PixelShader,
+    sampler: ID3D11SamplerState,
+    constants: ID3D11Buffer,
+    // Horizontal Lanczos result (output width x input height)
+    intermediate: Option<RenderTarget>,
+    output: RenderTarget,
+    input_width: u32,
+    input_height: u32,
+    output_width: u32,
+    source_rect: RECT,
+    destination_rect: RECT,
+}
+
+impl GpuScaler {
+    pub unsafe fn new(
+        device: &ID3D11Device,
+        quality: ScalingQuality,
+        input_width: u32,
+        input_height: u32,
+        output_width: u32,
+        output_height: u32,
+    ) -> Result<Self> {
+        info!(
+            "Creating GPU scaler ({:?}): {}x{} -> {}x{}",
+            quality, input_width, input_height, output_width, output_height
+        );
+        let context = device.GetImmediateContext()?;
+
+        let vs_blob = compile_shader(SCALER_HLSL, "vs_main", "vs_5_0")?;
+        let mut vertex_shader = None;
+        device.CreateVertexShader(blob_bytes(&vs_blob), None, Some(&mut vertex_shader))?;
+
+        let entry = match quality {
+            ScalingQuality::Point => "ps_point",
+            ScalingQuality::Bilinear | ScalingQuality::Default => "ps_bilinear",
+            ScalingQuality::HighQuality => "ps_lanczos",
+        };
+        let ps_blob = compile_shader(SCALER_HLSL, entry, "ps_5_0")?;
+        let mut pixel_shader = None;
+        device.CreatePixelShader(blob_bytes(&ps_blob), None, Some(&mut pixel_shader))?;
+
+        let sampler_desc = D3D11_SAMPLER_DESC {
+            Filter: D3D11_FILTER_MIN_MAG_MIP_LINEAR,
+            AddressU: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressV: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressW: D3D11_TEXTURE_ADDRESS_CLAMP,
+            MaxLOD: f32::MAX,
+            ..Default::default()
+        };
+        let mut sampler = None;
+        device.CreateSamplerState(&sampler_desc, Some(&mut sampler))?;
+
+        let buffer_desc = D3D11_BUFFER_DESC {
+            ByteWidth: std::mem::size_of::<ScalerParams>() as u32,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+            ..Default::default()
+        };
+        let mut constants = None;
+        device.CreateBuffer(&buffer_desc, None, Some(&mut constants))?;
+
+        let intermediate = if quality == ScalingQuality::HighQuality {
+            Some(create_render_target(
+                device,
+                output_width,
+                input_height,
+                DXGI_FORMAT_R16G16B16A16_FLOAT,
+            )?)
+        } else {
+            None
+        };
+        let output = create_render_target(
+            device,
+            output_width,
+            output_height,
+            DXGI_FORMAT_B8G8R8A8_UNORM,
+        )?;
+
+        Ok(Self {
+            device: device.clone(),
+            context,
+            vertex_shader: vertex_shader.unwrap(),
+            pixel_shader: pixel_shader.unwrap(),
+            sampler: sampler.unwrap(),
+            constants: constants.unwrap(),
+            intermediate,
+            output,
+            input_width,
+            input_height,
+            output_width,
+            source_rect: RECT {
+                left: 0,
+                top: 0,
+                right: input_width as i32,
+                bottom: input_height as i32,
+            },
+            destination_rect: RECT {
+                left: 0,
+                top: 0,
+                right: output_width as i32,
+                bottom: output_height as i32,
+            },
+        })
+    }
+
+    /// Region of the captured frame that is scaled to the output
+    pub fn set_source_rect(&mut self, rect: RECT) {
+        debug!("GPU scaler source rect set to {:?}", rect);
+        self.source_rect = rect;
+    }
+
+    /// Region of the output frame the source is scaled into; the rest is filled with black
+    pub fn set_destination_rect(&mut self, rect: RECT) {
+        debug!("GPU scaler destination rect set to {:?}", rect);
+        self.destination_rect = rect;
+    }
+
+    /// Scale the frame in `sample` into the scaler's output texture
+    ///
+    /// The returned sample references the output texture, which is overwritten by the next
+    /// call, so it must be consumed before scaling another frame.
+    pub unsafe fn scale(&self, sample: &IMFSample) -> Result<IMFSample> {
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let input = ID3D11Texture2D::from_raw(raw);
+
+        let mut input_srv = None;
+        self.device
+            .CreateShaderResourceView(&input, None, Some(&mut input_srv))?;
+
+        let rect = self.source_rect;
+        let rect_width = (rect.right - rect.left).max(1) as f32;
+        let rect_height = (rect.bottom - rect.top).max(1) as f32;
+        let dst = self.destination_rect;
+        let dst_origin = [dst.left as f32, dst.top as f32];
+        let dst_width = (dst.right - dst.left).max(1) as f32;
+        let dst_height = (dst.bottom - dst.top).max(1) as f32;
+
+        // Keep the multi-pass pipeline state atomic with respect to other threads
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+
+        self.context
+            .IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+        self.context.IASetInputLayout(None);
+        self.context.VSSetShader(&self.vertex_shader, None);
+        self.context.PSSetShader(&self.pixel_shader, None);
+        self.context
+            .PSSetSamplers(0, Some(&[Some(self.sampler.clone())]));
+        self.context
+            .PSSetConstantBuffers(0, Some(&[Some(self.constants.clone())]));
+        // Letterbox bars stay black
+        self.context
+            .ClearRenderTargetView(&self.output.rtv, [0.0, 0.0, 0.0, 1.0].as_ptr());
+
+        match &self.intermediate {
+            Some(intermediate) => {
+                // Horizontal pass: source rect -> destination width x source rect height
+                self.draw_pass(
+                    input_srv.clone(),
+                    &intermediate.rtv,
+                    ScalerParams {
+                        src_rect: [rect.left as f32, rect.top as f32, rect_width, rect_height],
+                        src_size: [self.input_width as f32, self.input_height as f32],
+                        dst_size: [dst_width, rect_height],
+                        axis: [1.0, 0.0],
+                        ..Default::default()
+                    },
+                    [0.0, 0.0],
+                );
+                // Vertical pass: intermediate -> destination rect of the output
+                self.draw_pass(
+                    Some(intermediate.srv.clone()),
+                    &self.output.rtv,
+                    ScalerParams {
+                        src_rect: [0.0, 0.0, dst_width, rect_height],
+                        src_size: [self.output_width as f32, self.input_height as f32],
+                        dst_size: [dst_width, dst_height],
+                        axis: [0.0, 1.0],
+                        ..Default::default()
+                    },
+                    dst_origin,
+                );
+            }
+            None => {
+                self.draw_pass(
+                    input_srv.clone(),
+                    &self.output.rtv,
+                    ScalerParams {
+                        src_rect: [rect.left as f32, rect.top as f32, rect_width, rect_height],
+                        src_size: [self.input_width as f32, self.input_height as f32],
+                        dst_size: [dst_width, dst_height],
+                        ..Default::default()
+                    },
+                    dst_origin,
+                );
+            }
+        }
+
+        // Unbind so the textures can be used as render targets / MFT input again
+        self.context.PSSetShaderResources(0, Some(&[None]));
+        self.context.OMSetRenderTargets(None, None);
+        multithread.Leave();
+
+        let output_sample = create_sample_from_texture(&self.output.texture)?;
+        output_sample.SetSampleTime(sample.GetSampleTime()?)?;
+        output_sample.SetSampleDuration(sample.GetSampleDuration()?)?;
+        if let Ok(capture_qpc) = sample.GetUINT64(&MFSampleExtension_DeviceTimestamp) {
+            output_sample.SetUINT64(&MFSampleExtension_DeviceTimestamp, capture_qpc)?;
+        }
+        Ok(output_sample)
+    }
+
+    unsafe fn draw_pass(
+        &self,
+        source: Option<ID3D11ShaderResourceView>,
+        target: &ID3D11RenderTargetView,
+        params: ScalerParams,
+        origin: [f32; 2],
+    ) {
+        self.context.UpdateSubresource(
+            &self.constants,
+            0,
+            None,
+            &params as *const _ as *const _,
+            0,
+            0,
+        );
+        // Unbind the previous source before it becomes a render target and vice versa
+        self.context.PSSetShaderResources(0, Some(&[None]));
+        self.context
+            .OMSetRenderTargets(Some(&[Some(target.clone())]), None);
+        self.context.PSSetShaderResources(0, Some(&[source]));
+        self.context.RSSetViewports(Some(&[D3D11_VIEWPORT {
+            TopLeftX: origin[0],
+            TopLeftY: origin[1],
+            Width: params.dst_size[0],
+            Height: params.dst_size[1],
+            MinDepth: 0.0,
+            MaxDepth: 1.0,
+        }]));
+        self.context.Draw(3, 0);
+    }
+}
+
+/// Compile one entry point of an HLSL source
+pub(super) unsafe fn compile_shader(hlsl: &str, entry_point: &str, target: &str) -> Result<ID3DBlob> {
+    let entry = format!("{}\0", entry_point);
+    let target = format!("{}\0", target);
+    let mut code: Option<ID3DBlob> = None;
+    let mut errors: Option<ID3DBlob> = None;
+
+    let result = D3DCompile(
+        hlsl.as_ptr() as *const _,
+        hlsl.len(),
+        None,
+        None,
+        None,
+        PCSTR(entry.as_ptr()),
+        PCSTR(target.as_ptr()),
+        0,
+        0,
+        &mut code,
+        Some(&mut errors),
+    );
+
+    if let Err(e) = result {
+        let message = errors
+            .map(|blob| String::from_utf8_lossy(blob_bytes(&blob)).to_string())
+            .unwrap_or_default();
+        error!("Failed to compile shader {}: {}", entry_point, message);
+        return Err(e);
+    }
+
+    code.ok_or_else(|| windows::core::Error::new(E_FAIL, "Shader compiler returned no code".into()))
+}
+
+pub(super) unsafe fn blob_bytes(blob: &ID3DBlob) -> &[u8] {
+    std::slice::from_raw_parts(blob.GetBufferPointer() as *const u8, blob.GetBufferSize())
+}
+
+pub(super) unsafe fn create_render_target(
+    device: &ID3D11Device,
+    width: u32,
+    height: u32,
+    format: DXGI_FORMAT,
+) -> Result<RenderTarget> {
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: width,
+        Height: height,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: format,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_RENDER_TARGET | D3D11_BIND_SHADER_RESOURCE,
+        CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+        MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+    };
+    let mut texture = None;
+    device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+    let texture = texture.unwrap();
+
+    let mut rtv = None;
+    device.CreateRenderTargetView(&texture, None, Some(&mut rtv))?;
+    let mut srv = None;
+    device.CreateShaderResourceView(&texture, None, Some(&mut srv))?;
+
+    Ok(RenderTarget {
+        texture,
+        rtv: rtv.unwrap(),
+        srv: srv.unwrap(),
+    })
+}
+
+unsafe fn create_sample_from_texture(texture: &ID3D11Texture2D) -> Result<IMFSample> {
+    let sample = MFCreateSample()?;
+    let surface: IDXGISurface = texture.cast()?;
+    let buffer = MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &surface, 0, FALSE)?;
+    sample.AddBuffer(&buffer)?;
+    Ok(sample)
+}
@@ -0,0 +1,232 @@
+use crate::logging::{debug, info};
+use windows::core::{ComInterface, Interface, Result};
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
+use windows::Win32::Media::MediaFoundation::{IMFDXGIBuffer, IMFSample};
+
+/// The frames are compared once they are downscaled to at most this many pixels wide
+const SCENE_SAMPLE_MAX_WIDTH: u32 = 64;
+
+/// Shortest gap between two forced keyframes, in 100ns units
+const MIN_SCENE_CUT_INTERVAL: i64 = 5_000_000;
+
+/// Mip chain a captured frame is copied into and reduced by the GPU
+struct MipChain {
+    texture: ID3D11Texture2D,
+    view: ID3D11ShaderResourceView,
+    staging: ID3D11Texture2D,
+    /// Mip level that is read back
+    level: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Reads back a GPU-downscaled luma image of captured frames
+pub(crate) struct LumaSampler {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    chain: Option<MipChain>,
+}
+
+impl LumaSampler {
+    pub unsafe fn new(device: &ID3D11Device) -> Result<Self> {
+        Ok(Self {
+            device: device.clone(),
+            context: device.GetImmediateContext()?,
+            chain: None,
+        })
+    }
+
+    /// Sample frames from `device` from now on
+    pub unsafe fn set_device(&mut self, device: &ID3D11Device) -> Result<()> {
+        self.context = device.GetImmediateContext()?;
+        self.device = device.clone();
+        self.chain = None;
+        Ok(())
+    }
+
+    /// Luma of the BGRA frame in `sample`, downscaled to at most `SCENE_SAMPLE_MAX_WIDTH` pixels
+    /// wide
+    pub unsafe fn sample(&mut self, sample: &IMFSample) -> Result<Vec<u8>> {
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+        let subresource = dxgi_buffer.GetSubresourceIndex()?;
+
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        if self
+            .chain
+            .as_ref()
+            .is_none_or(|chain| (chain.width, chain.height) != (desc.Width, desc.Height))
+        {
+            self.chain = Some(self.create_chain(desc.Width, desc.Height)?);
+        }
+        let chain = self.chain.as_ref().unwrap();
+
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+        self.context
+            .CopySubresourceRegion(&chain.texture, 0, 0, 0, 0, &texture, subresource, None);
+        self.context.GenerateMips(&chain.view);
+        self.context
+            .CopySubresourceRegion(&chain.staging, 0, 0, 0, 0, &chain.texture, chain.level, None);
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        let luma = match self
+            .context
+            .Map(&chain.staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped))
+        {
+            Ok(()) => {
+                let (width, height) = mip_size(chain.width, chain.height, chain.level);
+                let luma = read_luma(&mapped, width, height);
+                self.context.Unmap(&chain.staging, 0);
+                Ok(luma)
+            }
+            Err(e) => Err(e),
+        };
+        multithread.Leave();
+        luma
+    }
+}
+
+/// Detects scene changes by comparing a GPU-downscaled copy of each frame with the previous one
+pub(crate) struct SceneCutDetector {
+    sampler: LumaSampler,
+    /// Mean absolute luma difference (0.0..=1.0) at which a frame counts as a new scene
+    threshold: f32,
+    previous: Option<Vec<u8>>,
+    last_cut: Option<i64>,
+}
+
+impl SceneCutDetector {
+    pub unsafe fn new(device: &ID3D11Device, threshold: f32) -> Result<Self> {
+        info!("Scene cut keyframes enabled (threshold {:.2})", threshold);
+        Ok(Self {
+            sampler: LumaSampler::new(device)?,
+            threshold,
+            previous: None,
+            last_cut: None,
+        })
+    }
+
+    /// Read frames from `device` from now on; the previous frame is still compared against
+    pub unsafe fn set_device(&mut self, device: &ID3D11Device) -> Result<()> {
+        self.sampler.set_device(device)
+    }
+
+    /// Whether the BGRA frame in `sample` starts a new scene and should be encoded as a keyframe
+    pub unsafe fn is_scene_cut(&mut self, sample: &IMFSample, timestamp: i64) -> Result<bool> {
+        let luma = self.sampler.sample(sample)?;
+
+        let Some(previous) = self.previous.replace(luma) else {
+            return Ok(false);
+        };
+        let current = self.previous.as_ref().unwrap();
+        // Frames of another size are not compared
+        if previous.len() != current.len() {
+            return Ok(false);
+        }
+        let difference = previous
+            .iter()
+            .zip(current)
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum::<u64>() as f32
+            / (current.len().max(1) as f32 * 255.0);
+
+        if difference < self.threshold
+            || self
+                .last_cut
+                .is_some_and(|last| timestamp - last < MIN_SCENE_CUT_INTERVAL)
+        {
+            return Ok(false);
+        }
+        debug!(
+            "Scene cut at {} (difference {:.3})",
+            timestamp, difference
+        );
+        self.last_cut = Some(timestamp);
+        Ok(true)
+    }
+
+}
+
+impl LumaSampler {
+    unsafe fn create_chain(&self, width: u32, height: u32) -> Result<MipChain> {
+        // Stop at the first level that is narrow enough
+        let mut level = 0;
+        while mip_size(width, height, level).0 > SCENE_SAMPLE_MAX_WIDTH {
+            level += 1;
+        }
+        let (level_width, level_height) = mip_size(width, height, level);
+
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            MipLevels: level + 1,
+            ArraySize: 1,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: D3D11_RESOURCE_MISC_GENERATE_MIPS,
+        };
+        let mut texture = None;
+        self.device
+            .CreateTexture2D(&desc, None, Some(&mut texture))?;
+        let texture = texture.unwrap();
+        let mut view = None;
+        self.device
+            .CreateShaderResourceView(&texture, None, Some(&mut view))?;
+
+        let staging_desc = D3D11_TEXTURE2D_DESC {
+            Width: level_width,
+            Height: level_height,
+            MipLevels: 1,
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: D3D11_BIND_FLAG(0),
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+            MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+            ..desc
+        };
+        let mut staging = None;
+        self.device
+            .CreateTexture2D(&staging_desc, None, Some(&mut staging))?;
+
+        debug!(
+            "Comparing {}x{} frames at mip level {} ({}x{})",
+            width, height, level, level_width, level_height
+        );
+        Ok(MipChain {
+            texture,
+            view: view.unwrap(),
+            staging: staging.unwrap(),
+            level,
+            width,
+            height,
+        })
+    }
+}
+
+fn mip_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// BT.709 luma of each pixel of a mapped BGRA texture
+unsafe fn read_luma(mapped: &D3D11_MAPPED_SUBRESOURCE, width: u32, height: u32) -> Vec<u8> {
+    let pitch = mapped.RowPitch as usize;
+    let data = std::slice::from_raw_parts(mapped.pData as *const u8, pitch * height as usize);
+    let mut luma = Vec::with_capacity((width * height) as usize);
+    for row in data.chunks(pitch).take(height as usize) {
+        for pixel in row[..width as usize * 4].chunks_exact(4) {
+            let (b, g, r) = (pixel[0] as u32, pixel[1] as u32, pixel[2] as u32);
+            luma.push(((r * 54 + g * 183 + b * 19) >> 8) as u8);
+        }
+    }
+    luma
+}
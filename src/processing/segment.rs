@@ -0,0 +1,89 @@
+use crate::logging::{debug, info};
+use windows::core::Result;
+use windows::Win32::Media::MediaFoundation::{IMFSample, MFCreateSample};
+
+use crate::types::SendableWriter;
+
+/// A secondary output file fed with the same processed samples as the main recording
+///
+/// Timestamps are shifted by `time_offset` so the segment starts at zero, and samples at or
+/// before the last written timestamp are skipped so pre-roll and live samples never overlap.
+pub struct SegmentOutput {
+    writer: SendableWriter,
+    time_offset: i64,
+    audio_stream_index: Option<u32>,
+    last_video_time: Option<i64>,
+    last_audio_time: Option<i64>,
+    video_frames: u64,
+}
+
+impl SegmentOutput {
+    pub fn new(writer: SendableWriter, time_offset: i64, audio_stream_index: Option<u32>) -> Self {
+        info!(
+            "Creating segment output with time offset {} (audio stream: {:?})",
+            time_offset, audio_stream_index
+        );
+        Self {
+            writer,
+            time_offset,
+            audio_stream_index,
+            last_video_time: None,
+            last_audio_time: None,
+            video_frames: 0,
+        }
+    }
+
+    /// Write a processed (NV12) video sample
+    pub unsafe fn write_video(&mut self, sample: &IMFSample) -> Result<()> {
+        let time = sample.GetSampleTime()?;
+        if time < self.time_offset || self.last_video_time.is_some_and(|last| time <= last) {
+            return Ok(());
+        }
+
+        let retimed = retime_sample(sample, time - self.time_offset)?;
+        self.writer.0.WriteSample(0, &retimed)?;
+        self.last_video_time = Some(time);
+        self.video_frames += 1;
+        Ok(())
+    }
+
+    /// Write a mixed audio sample
+    pub unsafe fn write_audio(&mut self, sample: &IMFSample) -> Result<()> {
+        let Some(stream_index) = self.audio_stream_index else {
+            return Ok(());
+        };
+
+        let time = sample.GetSampleTime()?;
+        if time < self.time_offset || self.last_audio_time.is_some_and(|last| time <= last) {
+            return Ok(());
+        }
+
+        let retimed = retime_sample(sample, time - self.time_offset)?;
+        self.writer.0.WriteSample(stream_index, &retimed)?;
+        self.last_audio_time = Some(time);
+        Ok(())
+    }
+
+    /// Finalize the segment file
+    pub unsafe fn finalize(self) -> Result<()> {
+        info!("Finalizing segment output with {} video frames", self.video_frames);
+        self.writer.0.Finalize()
+    }
+}
+
+/// Create a new sample sharing the buffers of `sample` with a different timestamp
+///
+/// Samples may also be held by the main writer or the replay buffer, so they are never
+/// retimed in place.
+pub unsafe fn retime_sample(sample: &IMFSample, time: i64) -> Result<IMFSample> {
+    let retimed = MFCreateSample()?;
+    for i in 0..sample.GetBufferCount()? {
+        retimed.AddBuffer(&sample.GetBufferByIndex(i)?)?;
+    }
+    retimed.SetSampleTime(time)?;
+    if let Ok(duration) = sample.GetSampleDuration() {
+        retimed.SetSampleDuration(duration)?;
+    }
+    debug!("Retimed sample to {}", time);
+    Ok(retimed)
+}
@@ -0,0 +1,162 @@
+use crate::logging::info;
+use windows::core::{ComInterface, Interface, Result};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11DeviceContext, ID3D11Multithread, ID3D11Texture2D, D3D11_BOX,
+    D3D11_TEXTURE2D_DESC,
+};
+use windows::Win32::Media::MediaFoundation::{
+    IMFDXGIBuffer, IMFSample, MFSampleExtension_DeviceTimestamp,
+};
+
+/// Glyphs are 3x5 cells, one bit per cell, most significant bit on the left
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Burns the frame index and capture QPC time into a corner of each frame
+pub struct FrameStamper {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    /// Size in pixels of one glyph cell
+    scale: usize,
+}
+
+impl FrameStamper {
+    pub unsafe fn new(device: &ID3D11Device, scale: u32) -> Result<Self> {
+        let scale = scale.max(1) as usize;
+        info!("Debug frame stamp enabled (glyph scale {})", scale);
+        Ok(Self {
+            device: device.clone(),
+            context: device.GetImmediateContext()?,
+            scale,
+        })
+    }
+
+    /// Stamp frames from `device` from now on
+    pub unsafe fn set_device(&mut self, device: &ID3D11Device) -> Result<()> {
+        self.context = device.GetImmediateContext()?;
+        self.device = device.clone();
+        Ok(())
+    }
+
+    /// Draw the stamp onto the BGRA texture in `sample` with its top-left corner at (x, y)
+    pub unsafe fn stamp(&self, sample: &IMFSample, frame_index: u64, x: u32, y: u32) -> Result<()> {
+        let capture_qpc = sample
+            .GetUINT64(&MFSampleExtension_DeviceTimestamp)
+            .unwrap_or(0);
+        let text = format!("F{:06} T{}", frame_index, capture_qpc);
+
+        let buffer = sample.GetBufferByIndex(0)?;
+        let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+        let mut raw = std::ptr::null_mut();
+        dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+        let texture = ID3D11Texture2D::from_raw(raw);
+
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+
+        // Clip to the texture so the copy region stays valid
+        if x >= desc.Width || y >= desc.Height {
+            return Ok(());
+        }
+        let (pixels, text_width, text_height) = render_text(&text, self.scale);
+        let width = text_width.min(desc.Width - x);
+        let height = text_height.min(desc.Height - y);
+
+        let region = D3D11_BOX {
+            left: x,
+            top: y,
+            front: 0,
+            right: x + width,
+            bottom: y + height,
+            back: 1,
+        };
+
+        let multithread: ID3D11Multithread = self.device.cast()?;
+        multithread.Enter();
+        self.context.UpdateSubresource(
+            &texture,
+            0,
+            Some(&region),
+            pixels.as_ptr() as *const _,
+            text_width * 4,
+            0,
+        );
+        multithread.Leave();
+        Ok(())
+    }
+}
+
+/// Render white text on a black box into a BGRA buffer, each glyph cell `scale` pixels wide;
+/// returns the pixels, width and height
+pub(super) fn render_text(text: &str, scale: usize) -> (Vec<u8>, u32, u32) {
+    // One cell of padding around the text and one cell between glyphs
+    let width = (text.chars().count() * (GLYPH_WIDTH + 1) + 1) * scale;
+    let height = (GLYPH_HEIGHT + 2) * scale;
+    let mut pixels = vec![0u8; width * height * 4];
+    for alpha in pixels.iter_mut().skip(3).step_by(4) {
+        *alpha = 255;
+    }
+
+    for (index, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        let origin_x = (index * (GLYPH_WIDTH + 1) + 1) * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    let py = (row + 1) * scale + dy;
+                    let start = (py * width + origin_x + col * scale) * 4;
+                    pixels[start..start + scale * 4].fill(255);
+                }
+            }
+        }
+    }
+
+    (pixels, width as u32, height as u32)
+}
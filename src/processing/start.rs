@@ -0,0 +1,122 @@
+use crate::logging::{debug, info};
+use windows::core::Result;
+use windows::Win32::Graphics::Direct3D11::ID3D11Device;
+use windows::Win32::Media::MediaFoundation::{IMFSample, MFSampleExtension_DeviceTimestamp};
+
+use super::scene::LumaSampler;
+use crate::recorder::events::{EventEmitter, RecorderEvent};
+use crate::types::hns_to_duration;
+
+/// Frames whose brightest downscaled pixel is at or below this luma are blank
+const BLANK_MAX_LUMA: u8 = 16;
+
+/// Holds the recording back until the first frame with content arrives
+///
+/// The capture thread starts delivering as soon as it passes the start barrier, so the first
+/// frames are often black or stale. Frames are dropped until one is not blank; from there on
+/// video and audio are shifted so both start at zero at the QPC that frame was captured at.
+pub(crate) struct StartGate {
+    sampler: LumaSampler,
+    events: EventEmitter,
+    audio_start_qpc: u64,
+    ticks_to_hns: f64,
+    frames_skipped: u64,
+    /// Timestamp of the first written frame, None while the gate is closed
+    video_offset: Option<i64>,
+    /// Audio time at the first written frame, in 100ns units
+    audio_offset: i64,
+}
+
+impl StartGate {
+    pub unsafe fn new(
+        device: &ID3D11Device,
+        events: EventEmitter,
+        audio_start_qpc: u64,
+        ticks_to_hns: f64,
+    ) -> Result<Self> {
+        info!("Start gate enabled, waiting for the first non-blank frame");
+        Ok(Self {
+            sampler: LumaSampler::new(device)?,
+            events,
+            audio_start_qpc,
+            ticks_to_hns,
+            frames_skipped: 0,
+            video_offset: None,
+            audio_offset: 0,
+        })
+    }
+
+    /// Read frames from `device` from now on
+    pub unsafe fn set_device(&mut self, device: &ID3D11Device) -> Result<()> {
+        self.sampler.set_device(device)
+    }
+
+    /// Whether writing has begun
+    pub fn is_open(&self) -> bool {
+        self.video_offset.is_some()
+    }
+
+    /// Audio time the recording starts at, in 100ns units since capture began
+    pub fn audio_offset(&self) -> i64 {
+        self.audio_offset
+    }
+
+    /// Retime the BGRA frame in `sample` onto the recording timeline, returning its new
+    /// timestamp, or None if it comes before the first non-blank frame and must be dropped
+    pub unsafe fn admit_video(&mut self, sample: &IMFSample, timestamp: i64) -> Result<Option<i64>> {
+        let offset = match self.video_offset {
+            Some(offset) => offset,
+            None => {
+                let luma = self.sampler.sample(sample)?;
+                if luma.iter().all(|&value| value <= BLANK_MAX_LUMA) {
+                    self.frames_skipped += 1;
+                    return Ok(None);
+                }
+                self.open(sample, timestamp);
+                timestamp
+            }
+        };
+        let retimed = timestamp - offset;
+        sample.SetSampleTime(retimed)?;
+        Ok(Some(retimed))
+    }
+
+    /// Retime an audio sample onto the recording timeline; false if it was captured before the
+    /// first written frame and must be dropped
+    pub unsafe fn admit_audio(&self, sample: &IMFSample) -> Result<bool> {
+        let time = sample.GetSampleTime()?;
+        if time < self.audio_offset {
+            return Ok(false);
+        }
+        sample.SetSampleTime(time - self.audio_offset)?;
+        Ok(true)
+    }
+
+    unsafe fn open(&mut self, sample: &IMFSample, timestamp: i64) {
+        // Frames carry the QPC they were captured at; without it the video timeline is the
+        // best estimate of the audio one
+        let start_qpc = sample
+            .GetUINT64(&MFSampleExtension_DeviceTimestamp)
+            .unwrap_or_else(|_| {
+                self.audio_start_qpc + (timestamp.max(0) as f64 / self.ticks_to_hns) as u64
+            });
+        self.audio_offset =
+            (start_qpc.saturating_sub(self.audio_start_qpc) as f64 * self.ticks_to_hns) as i64;
+        self.video_offset = Some(timestamp);
+
+        info!(
+            "First non-blank frame after {} skipped frame(s), recording starts {:.3} s in",
+            self.frames_skipped,
+            self.audio_offset as f64 / 10_000_000.0
+        );
+        debug!(
+            "Start gate offsets: video {} hns, audio {} hns",
+            timestamp, self.audio_offset
+        );
+        self.events.emit(RecorderEvent::CaptureStarted {
+            skipped: hns_to_duration(self.audio_offset),
+            frames_skipped: self.frames_skipped,
+            start_qpc,
+        });
+    }
+}
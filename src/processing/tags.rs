@@ -0,0 +1,107 @@
+use crate::logging::{debug, info};
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use windows::Win32::Media::MediaFoundation::{IMFSample, MFSampleExtension_DeviceTimestamp};
+use windows::Win32::System::Performance::QueryPerformanceCounter;
+
+use crate::recorder::markers::{FrameTag, Marker};
+use crate::types::hns_to_duration;
+
+/// A tag added by the application, waiting for the first frame captured at or after it
+pub(crate) struct PendingTag {
+    pub name: String,
+    pub payload: String,
+    /// QPC value when the tag was added
+    pub qpc: u64,
+}
+
+/// Places tags on the video timeline of the processing thread
+///
+/// Each tag lands on the timestamp of the first written frame captured at or after the moment
+/// it was added, and is also added as a chapter marker named after the tag.
+pub(crate) struct FrameTagger {
+    receiver: Receiver<PendingTag>,
+    pending: VecDeque<PendingTag>,
+    tags: Arc<Mutex<Vec<FrameTag>>>,
+    markers: Arc<Mutex<Vec<Marker>>>,
+    /// Timestamp of the last frame written, for tags still pending when the recording stops
+    last_timestamp: i64,
+}
+
+impl FrameTagger {
+    pub fn new(
+        receiver: Receiver<PendingTag>,
+        tags: Arc<Mutex<Vec<FrameTag>>>,
+        markers: Arc<Mutex<Vec<Marker>>>,
+    ) -> Self {
+        Self {
+            receiver,
+            pending: VecDeque::new(),
+            tags,
+            markers,
+            last_timestamp: 0,
+        }
+    }
+
+    /// Resolve the tags added up to the capture time of `sample`, written at `timestamp`
+    pub unsafe fn frame_written(&mut self, sample: &IMFSample, timestamp: i64) {
+        self.last_timestamp = timestamp;
+        self.pending.extend(self.receiver.try_iter());
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let frame_qpc = sample
+            .GetUINT64(&MFSampleExtension_DeviceTimestamp)
+            .unwrap_or_else(|_| {
+                let mut now = 0i64;
+                QueryPerformanceCounter(&mut now);
+                now as u64
+            });
+        while let Some(tag) = self.pending.pop_front() {
+            if tag.qpc > frame_qpc {
+                self.pending.push_front(tag);
+                break;
+            }
+            self.place(tag, timestamp);
+        }
+    }
+
+    /// Place the tags still waiting for a frame on the last frame written
+    pub fn finish(&mut self) {
+        self.pending.extend(self.receiver.try_iter());
+        if !self.pending.is_empty() {
+            debug!(
+                "Placing {} tag(s) added after the last frame on it",
+                self.pending.len()
+            );
+        }
+        while let Some(tag) = self.pending.pop_front() {
+            self.place(tag, self.last_timestamp);
+        }
+    }
+
+    fn place(&self, tag: PendingTag, timestamp: i64) {
+        let time = hns_to_duration(timestamp);
+        info!("Tag '{}' placed at {:?}", tag.name, time);
+        if let Ok(mut markers) = self.markers.lock() {
+            // Markers added from the wall clock may already be past the tagged frame
+            let index = markers.partition_point(|marker| marker.time <= time);
+            markers.insert(
+                index,
+                Marker {
+                    time,
+                    label: tag.name.clone(),
+                },
+            );
+        }
+        if let Ok(mut tags) = self.tags.lock() {
+            tags.push(FrameTag {
+                time,
+                name: tag.name,
+                payload: tag.payload,
+            });
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::logging::{debug, info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::{implement, ComInterface, Error, IUnknown, Result, GUID, HRESULT};
+use windows::Win32::Foundation::E_NOTIMPL;
+use windows::Win32::Media::MediaFoundation::*;
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+
+use super::encoder::{read_sequence_header, StreamHeader};
+use crate::device::VideoEncoderType;
+use crate::types::hns_to_duration;
+
+/// An encoded video frame on its way into the output file
+#[derive(Debug)]
+pub struct EncodedFrame<'a> {
+    /// Presentation time of the frame in the recording
+    pub timestamp: Duration,
+    pub duration: Duration,
+    /// Whether a decoder can start at this frame (IDR)
+    pub keyframe: bool,
+    /// Annex-B bitstream of the frame as produced by the encoder; its length is the frame size
+    pub data: &'a [u8],
+    /// Parameter sets needed to decode from this frame on, set on keyframes
+    pub header: Option<StreamHeader>,
+}
+
+/// Callback invoked for every [`EncodedFrame`]
+///
+/// Called on the encoding thread before the frame is written, so it should return quickly.
+pub type EncodedFrameCallback = Arc<dyn Fn(&EncodedFrame) + Send + Sync>;
+
+/// Media sink that hands the video stream's samples to an [`EncodedFrameCallback`] and passes
+/// everything on to the MP4 sink it wraps
+///
+/// The sink writer keeps the encoder output to itself, so the only place the encoded samples can
+/// be seen is the media sink it writes them to.
+#[implement(IMFMediaSink, IMFFinalizableMediaSink)]
+pub(crate) struct TapMediaSink {
+    inner: IMFMediaSink,
+    /// Wrapped video stream sink
+    video: IMFStreamSink,
+    video_id: u32,
+}
+
+impl TapMediaSink {
+    /// Wrap `inner`, tapping its first stream sink, which carries the video
+    pub unsafe fn wrap(
+        inner: IMFMediaSink,
+        encoder_type: VideoEncoderType,
+        callback: EncodedFrameCallback,
+    ) -> Result<IMFMediaSink> {
+        let stream = inner.GetStreamSinkByIndex(0)?;
+        let video_id = stream.GetIdentifier()?;
+        let video: IMFStreamSink = TapStreamSink {
+            inner: stream,
+            encoder_type,
+            callback,
+            header: Mutex::new(None),
+        }
+        .into();
+        info!("Tapping encoded frames of stream {}", video_id);
+        Ok(Self {
+            inner,
+            video,
+            video_id,
+        }
+        .into())
+    }
+}
+
+impl IMFMediaSink_Impl for TapMediaSink {
+    fn GetCharacteristics(&self) -> Result<u32> {
+        unsafe { self.inner.GetCharacteristics() }
+    }
+
+    fn AddStreamSink(&self, id: u32, media_type: Option<&IMFMediaType>) -> Result<IMFStreamSink> {
+        unsafe { self.inner.AddStreamSink(id, media_type) }
+    }
+
+    fn RemoveStreamSink(&self, id: u32) -> Result<()> {
+        unsafe { self.inner.RemoveStreamSink(id) }
+    }
+
+    fn GetStreamSinkCount(&self) -> Result<u32> {
+        unsafe { self.inner.GetStreamSinkCount() }
+    }
+
+    fn GetStreamSinkByIndex(&self, index: u32) -> Result<IMFStreamSink> {
+        match index {
+            0 => Ok(self.video.clone()),
+            _ => unsafe { self.inner.GetStreamSinkByIndex(index) },
+        }
+    }
+
+    fn GetStreamSinkById(&self, id: u32) -> Result<IMFStreamSink> {
+        if id == self.video_id {
+            return Ok(self.video.clone());
+        }
+        unsafe { self.inner.GetStreamSinkById(id) }
+    }
+
+    fn SetPresentationClock(&self, clock: Option<&IMFPresentationClock>) -> Result<()> {
+        unsafe { self.inner.SetPresentationClock(clock) }
+    }
+
+    fn GetPresentationClock(&self) -> Result<IMFPresentationClock> {
+        unsafe { self.inner.GetPresentationClock() }
+    }
+
+    fn Shutdown(&self) -> Result<()> {
+        unsafe { self.inner.Shutdown() }
+    }
+}
+
+impl IMFFinalizableMediaSink_Impl for TapMediaSink {
+    fn BeginFinalize(
+        &self,
+        callback: Option<&IMFAsyncCallback>,
+        state: Option<&IUnknown>,
+    ) -> Result<()> {
+        unsafe { self.finalizable()?.BeginFinalize(callback, state) }
+    }
+
+    fn EndFinalize(&self, result: Option<&IMFAsyncResult>) -> Result<()> {
+        unsafe { self.finalizable()?.EndFinalize(result) }
+    }
+}
+
+impl TapMediaSink {
+    fn finalizable(&self) -> Result<IMFFinalizableMediaSink> {
+        self.inner
+            .cast()
+            .map_err(|_| Error::new(E_NOTIMPL, "Wrapped media sink cannot be finalized".into()))
+    }
+}
+
+#[implement(IMFStreamSink, IMFMediaEventGenerator)]
+struct TapStreamSink {
+    inner: IMFStreamSink,
+    encoder_type: VideoEncoderType,
+    callback: EncodedFrameCallback,
+    /// Sequence header of the stream's media type, read once for keyframes without in-band
+    /// parameter sets
+    header: Mutex<Option<StreamHeader>>,
+}
+
+impl TapStreamSink {
+    unsafe fn inspect(&self, sample: &IMFSample) -> Result<()> {
+        let keyframe = sample.GetUINT32(&MFSampleExtension_CleanPoint).unwrap_or(0) != 0;
+        let timestamp = hns_to_duration(sample.GetSampleTime()?.max(0));
+        let duration = hns_to_duration(sample.GetSampleDuration().unwrap_or(0).max(0));
+
+        let buffer = sample.ConvertToContiguousBuffer()?;
+        let mut data = std::ptr::null_mut();
+        let mut length = 0u32;
+        buffer.Lock(&mut data, None, Some(&mut length))?;
+        let bytes = std::slice::from_raw_parts(data, length as usize);
+
+        let header = if keyframe {
+            self.keyframe_header(bytes)
+        } else {
+            None
+        };
+        (self.callback)(&EncodedFrame {
+            timestamp,
+            duration,
+            keyframe,
+            data: bytes,
+            header,
+        });
+
+        buffer.Unlock()
+    }
+
+    /// Parameter sets for a keyframe: the ones it carries in-band, or else the stream's
+    unsafe fn keyframe_header(&self, bytes: &[u8]) -> Option<StreamHeader> {
+        let in_band = StreamHeader::parse(bytes, self.encoder_type);
+        if in_band.is_complete() {
+            return Some(in_band);
+        }
+
+        let mut header = self.header.lock().unwrap();
+        if header.is_none() {
+            *header = self
+                .inner
+                .GetMediaTypeHandler()
+                .and_then(|handler| handler.GetCurrentMediaType())
+                .and_then(|media_type| read_sequence_header(&media_type, self.encoder_type))
+                .unwrap_or_else(|e| {
+                    debug!("No sequence header on the video stream: {:?}", e);
+                    None
+                });
+        }
+        header.clone()
+    }
+}
+
+impl IMFMediaEventGenerator_Impl for TapStreamSink {
+    fn GetEvent(&self, flags: MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS) -> Result<IMFMediaEvent> {
+        unsafe { self.inner.GetEvent(flags) }
+    }
+
+    fn BeginGetEvent(
+        &self,
+        callback: Option<&IMFAsyncCallback>,
+        state: Option<&IUnknown>,
+    ) -> Result<()> {
+        unsafe { self.inner.BeginGetEvent(callback, state) }
+    }
+
+    fn EndGetEvent(&self, result: Option<&IMFAsyncResult>) -> Result<IMFMediaEvent> {
+        unsafe { self.inner.EndGetEvent(result) }
+    }
+
+    fn QueueEvent(
+        &self,
+        event_type: u32,
+        extended_type: *const GUID,
+        status: HRESULT,
+        value: *const PROPVARIANT,
+    ) -> Result<()> {
+        unsafe { self.inner.QueueEvent(event_type, extended_type, status, value) }
+    }
+}
+
+impl IMFStreamSink_Impl for TapStreamSink {
+    fn GetMediaSink(&self) -> Result<IMFMediaSink> {
+        unsafe { self.inner.GetMediaSink() }
+    }
+
+    fn GetIdentifier(&self) -> Result<u32> {
+        unsafe { self.inner.GetIdentifier() }
+    }
+
+    fn GetMediaTypeHandler(&self) -> Result<IMFMediaTypeHandler> {
+        unsafe { self.inner.GetMediaTypeHandler() }
+    }
+
+    fn ProcessSample(&self, sample: Option<&IMFSample>) -> Result<()> {
+        if let Some(sample) = sample {
+            // A failure to read the frame must not stop it from being written
+            if let Err(e) = unsafe { self.inspect(sample) } {
+                warn!("Failed to read an encoded frame: {:?}", e);
+            }
+        }
+        unsafe { self.inner.ProcessSample(sample) }
+    }
+
+    fn PlaceMarker(
+        &self,
+        marker_type: MFSTREAMSINK_MARKER_TYPE,
+        marker_value: *const PROPVARIANT,
+        context_value: *const PROPVARIANT,
+    ) -> Result<()> {
+        unsafe { self.inner.PlaceMarker(marker_type, marker_value, context_value) }
+    }
+
+    fn Flush(&self) -> Result<()> {
+        unsafe { self.inner.Flush() }
+    }
+}
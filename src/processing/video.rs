@@ -1,9 +1,12 @@
-use log::{debug, info, warn};
+use crate::logging::{debug, info, warn};
 use std::mem::ManuallyDrop;
-use std::sync::{Arc, Mutex};
-use windows::core::{ComInterface, Result};
+use windows::core::{ComInterface, Interface, Result};
 use windows::Win32::Foundation::{FALSE, RECT};
-use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11Texture2D, D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE,
+    D3D11_CPU_ACCESS_FLAG, D3D11_RESOURCE_MISC_FLAG, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+};
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC};
 use windows::Win32::Media::MediaFoundation::*;
 use windows::Win32::System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER};
 
@@ -25,26 +28,14 @@ unsafe fn set_video_processor_source_rectangle(
         window_width, window_height, input_width, input_height
     );
 
-    // Calculate the actual pixel values to use
-    let left = window_x;
-    let top = window_y;
-    // RECT uses right and bottom, not width and height directly
-    let right = window_x + window_width as i32;
-    let bottom = window_y + window_height as i32;
-
-    // Clamp values just in case
-    let final_left = left.max(0);
-    let final_top = top.max(0);
-    let final_right = right.min(input_width as i32);
-    let final_bottom = bottom.min(input_height as i32);
-
-    // Create RECT structure
-    let source_rect = RECT {
-        left: final_left,
-        top: final_top,
-        right: final_right,
-        bottom: final_bottom,
-    };
+    let source_rect = clamp_source_rect(
+        input_width,
+        input_height,
+        window_x,
+        window_y,
+        window_width,
+        window_height,
+    );
 
     info!(
         "Setting source rect via IMFVideoProcessorControl: left={}, top={}, right={}, bottom={}",
@@ -66,14 +57,438 @@ unsafe fn set_video_processor_source_rectangle(
     }
 }
 
-pub unsafe fn setup_video_converter(
+/// Clamps a window rectangle (position + size) to the captured frame
+pub fn clamp_source_rect(
     input_width: u32,
     input_height: u32,
+    window_x: i32,
+    window_y: i32,
+    window_width: u32,
+    window_height: u32,
+) -> RECT {
+    // RECT uses right and bottom, not width and height directly
+    RECT {
+        left: window_x.max(0),
+        top: window_y.max(0),
+        right: (window_x + window_width as i32).min(input_width as i32),
+        bottom: (window_y + window_height as i32).min(input_height as i32),
+    }
+}
+
+/// The region of the captured frame to record for the given window geometry
+/// Falls back to the full frame when the window is unknown or larger than the capture
+pub fn window_source_rect(
+    input_width: u32,
+    input_height: u32,
+    window_position: Option<(i32, i32)>,
+    window_size: Option<(u32, u32)>,
+) -> RECT {
+    match (window_position, window_size) {
+        (Some((x, y)), Some((width, height))) if width <= input_width && height <= input_height => {
+            clamp_source_rect(input_width, input_height, x, y, width, height)
+        }
+        _ => RECT {
+            left: 0,
+            top: 0,
+            right: input_width as i32,
+            bottom: input_height as i32,
+        },
+    }
+}
+
+/// YUV matrix and value range of the recorded video, signalled in the file so players decode
+/// the colors as they were captured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorSpace {
+    /// BT.709 with limited (16-235) range, what most players assume for HD video
+    #[default]
+    Bt709Limited,
+    /// BT.709 with full (0-255) range
+    Bt709Full,
+    /// BT.601 with limited range, for SD video and older players
+    Bt601,
+}
+
+impl ColorSpace {
+    pub fn is_full_range(self) -> bool {
+        self == Self::Bt709Full
+    }
+
+    /// Set the matrix, range, primaries and transfer function on a YUV or encoded media type
+    pub unsafe fn apply(self, media_type: &IMFMediaType) -> Result<()> {
+        let (matrix, primaries) = match self {
+            Self::Bt709Limited | Self::Bt709Full => {
+                (MFVideoTransferMatrix_BT709, MFVideoPrimaries_BT709)
+            }
+            Self::Bt601 => (MFVideoTransferMatrix_BT601, MFVideoPrimaries_SMPTE170M),
+        };
+        let range = if self.is_full_range() {
+            MFNominalRange_0_255
+        } else {
+            MFNominalRange_16_235
+        };
+        media_type.SetUINT32(&MF_MT_YUV_MATRIX, matrix.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_VIDEO_NOMINAL_RANGE, range.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_VIDEO_PRIMARIES, primaries.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_TRANSFER_FUNCTION, MFVideoTransFunc_709.0 as u32)?;
+        Ok(())
+    }
+}
+
+/// How captured BGRA frames reach the encoder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PixelPipeline {
+    /// Hand BGRA frames to the encoder when nothing else needs the video processor, otherwise
+    /// convert to NV12
+    #[default]
+    Auto,
+    /// Scale and convert to NV12 in the video processor
+    Nv12,
+    /// Hand BGRA frames to an encoder that accepts RGB input and converts on its own, skipping
+    /// the video processor. Frames take more than twice the memory of NV12 ones.
+    Rgb,
+}
+
+/// Pick the cheapest way to get frames to the encoder
+///
+/// Desktop duplication only delivers BGRA (or HDR formats), so the choice is between the video
+/// processor converting to NV12 and the encoder taking BGRA directly. The latter only works
+/// when the video processor has nothing else to do: no resize, rotation or flip, and no replay
+/// of another size. Crops are still done by the GPU scaler. The result is never `Auto`.
+pub fn negotiate_pixel_pipeline(
+    requested: PixelPipeline,
+    input_size: (u32, u32),
+    output_size: (u32, u32),
+    transform: VideoTransform,
+    color_space: ColorSpace,
+    replay_size: Option<(u32, u32)>,
+    encoder_accepts_rgb: impl FnOnce() -> bool,
+) -> PixelPipeline {
+    if requested == PixelPipeline::Nv12 {
+        return PixelPipeline::Nv12;
+    }
+    let blocker = if input_size != output_size {
+        Some("frames are resized")
+    } else if transform.without_crop() != VideoTransform::default() {
+        Some("frames are rotated or flipped")
+    } else if color_space != ColorSpace::default() {
+        Some("the encoder's own conversion cannot be set to another color space")
+    } else if replay_size.is_some_and(|size| size != output_size) {
+        Some("replay frames are resized")
+    } else if !encoder_accepts_rgb() {
+        Some("the encoder does not accept RGB input")
+    } else {
+        None
+    };
+    match (blocker, requested) {
+        (None, _) => {
+            info!("Handing BGRA frames to the encoder, skipping NV12 conversion");
+            PixelPipeline::Rgb
+        }
+        (Some(reason), PixelPipeline::Rgb) => {
+            warn!("RGB pipeline requested but {}, converting to NV12", reason);
+            PixelPipeline::Nv12
+        }
+        (Some(reason), _) => {
+            info!("Converting frames to NV12 since {}", reason);
+            PixelPipeline::Nv12
+        }
+    }
+}
+
+/// Pixels removed from each edge of the recorded region
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct CropInsets {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+/// Clockwise rotation of the recorded picture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn degrees(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Rotate90 => 90,
+            Self::Rotate180 => 180,
+            Self::Rotate270 => 270,
+        }
+    }
+
+    /// Whether the picture is turned on its side, so its width becomes the output height
+    pub fn is_quarter_turn(self) -> bool {
+        matches!(self, Self::Rotate90 | Self::Rotate270)
+    }
+}
+
+/// Crop, rotation and flips applied to the captured frames by the video processor, e.g. for
+/// portrait monitors or capture cards that deliver mirrored frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct VideoTransform {
+    /// Removed from the recorded region before it is scaled
+    pub crop: CropInsets,
+    pub rotation: Rotation,
+    /// Mirror left to right
+    pub flip_horizontal: bool,
+    /// Mirror top to bottom
+    pub flip_vertical: bool,
+}
+
+impl VideoTransform {
+    /// The same transform without the crop, for frames that were already cropped
+    pub fn without_crop(self) -> Self {
+        Self {
+            crop: CropInsets::default(),
+            ..self
+        }
+    }
+
+    /// Rotation in degrees and mirroring for the video processor; flipping both ways is the
+    /// same as a half turn
+    fn orientation(&self) -> (u32, MF_VIDEO_PROCESSOR_MIRROR) {
+        let degrees = self.rotation.degrees();
+        match (self.flip_horizontal, self.flip_vertical) {
+            (false, false) => (degrees, MIRROR_NONE),
+            (true, false) => (degrees, MIRROR_HORIZONTAL),
+            (false, true) => (degrees, MIRROR_VERTICAL),
+            (true, true) => ((degrees + 180) % 360, MIRROR_NONE),
+        }
+    }
+}
+
+/// The region of the captured frame to record: the window region with the crop insets removed
+///
+/// At least 2x2 pixels are kept whatever the insets.
+pub fn cropped_source_rect(
+    input_width: u32,
+    input_height: u32,
+    window_position: Option<(i32, i32)>,
+    window_size: Option<(u32, u32)>,
+    crop: CropInsets,
+) -> RECT {
+    let region = window_source_rect(input_width, input_height, window_position, window_size);
+    let left = (region.left + crop.left as i32).min(region.right - 2);
+    let top = (region.top + crop.top as i32).min(region.bottom - 2);
+    RECT {
+        left,
+        top,
+        right: (region.right - crop.right as i32).max(left + 2),
+        bottom: (region.bottom - crop.bottom as i32).max(top + 2),
+    }
+}
+
+/// Set the rotation and mirroring of the video processor
+unsafe fn apply_orientation(
+    control: &IMFVideoProcessorControl,
+    transform: VideoTransform,
+) -> Result<()> {
+    let (degrees, mirror) = transform.orientation();
+    info!(
+        "Video processor orientation: rotation {} degrees, mirror {:?}",
+        degrees, mirror
+    );
+    control.SetMirror(mirror)?;
+    match control.cast::<IMFVideoProcessorControl2>() {
+        Ok(control) => control.SetRotationOverride(degrees)?,
+        Err(_) if degrees == 0 => {}
+        Err(e) => {
+            warn!("Video processor cannot rotate frames: {:?}", e);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// How the recorded region is fitted into the output frame when the aspect ratios differ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AspectMode {
+    /// Scale the region to fill the output, distorting it if needed
+    #[default]
+    Stretch,
+    /// Fit the whole region inside the output, padding with black bars
+    Letterbox,
+    /// Fill the output and crop the overflowing edges of the region equally
+    CropCenter,
+}
+
+/// Source and destination rectangles that fit `source` into an output frame according to `mode`,
+/// with the source turned by `rotation` on its way to the output
+pub fn fit_aspect(
+    mode: AspectMode,
+    source: RECT,
     output_width: u32,
     output_height: u32,
-    window_position: Arc<Mutex<Option<(i32, i32)>>>,
-    window_size: Arc<Mutex<Option<(u32, u32)>>>,
+    rotation: Rotation,
+) -> (RECT, RECT) {
+    if rotation.is_quarter_turn() {
+        // Fit the unrotated source into the output on its side, then stand the destination up
+        let (source, destination) =
+            fit_aspect(mode, source, output_height, output_width, Rotation::None);
+        let destination = RECT {
+            left: destination.top,
+            top: destination.left,
+            right: destination.bottom,
+            bottom: destination.right,
+        };
+        return (source, destination);
+    }
+
+    let full_output = RECT {
+        left: 0,
+        top: 0,
+        right: output_width as i32,
+        bottom: output_height as i32,
+    };
+    let source_width = (source.right - source.left).max(1) as i64;
+    let source_height = (source.bottom - source.top).max(1) as i64;
+    let (output_w, output_h) = (output_width as i64, output_height as i64);
+
+    match mode {
+        AspectMode::Stretch => (source, full_output),
+        AspectMode::Letterbox => {
+            // Compare source_w / source_h against output_w / output_h without floats
+            let (width, height) = if source_width * output_h > output_w * source_height {
+                (output_w, output_w * source_height / source_width)
+            } else {
+                (output_h * source_width / source_height, output_h)
+            };
+            // Keep even dimensions and offsets so chroma planes line up
+            let width = (width & !1).max(2);
+            let height = (height & !1).max(2);
+            let left = ((output_w - width) / 2) & !1;
+            let top = ((output_h - height) / 2) & !1;
+            let destination = RECT {
+                left: left as i32,
+                top: top as i32,
+                right: (left + width) as i32,
+                bottom: (top + height) as i32,
+            };
+            (source, destination)
+        }
+        AspectMode::CropCenter => {
+            let (width, height) = if source_width * output_h > output_w * source_height {
+                (source_height * output_w / output_h, source_height)
+            } else {
+                (source_width, source_width * output_h / output_w)
+            };
+            let left = source.left as i64 + (source_width - width) / 2;
+            let top = source.top as i64 + (source_height - height) / 2;
+            let cropped = RECT {
+                left: left as i32,
+                top: top as i32,
+                right: (left + width) as i32,
+                bottom: (top + height) as i32,
+            };
+            (cropped, full_output)
+        }
+    }
+}
+
+/// How a video converter maps the window in the captured frames to the output
+#[derive(Debug, Clone, Copy)]
+pub struct ConverterLayout {
+    /// Size of the captured frames
+    pub input_width: u32,
+    pub input_height: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Where the window is in the captured frames, the whole frame if unknown
+    pub window_position: Option<(i32, i32)>,
+    pub window_size: Option<(u32, u32)>,
+    pub aspect_mode: AspectMode,
+    pub transform: VideoTransform,
+}
+
+/// Apply the aspect mode to the video processor's source and destination rectangles
+unsafe fn apply_aspect_mode(
+    control: &IMFVideoProcessorControl,
+    layout: &ConverterLayout,
+) -> Result<()> {
+    let ConverterLayout {
+        input_width,
+        input_height,
+        output_width,
+        output_height,
+        window_position,
+        window_size,
+        aspect_mode,
+        transform,
+    } = *layout;
+    if aspect_mode == AspectMode::Stretch {
+        return Ok(());
+    }
+
+    let region = cropped_source_rect(
+        input_width,
+        input_height,
+        window_position,
+        window_size,
+        transform.crop,
+    );
+    let (source_rect, destination_rect) = fit_aspect(
+        aspect_mode,
+        region,
+        output_width,
+        output_height,
+        transform.rotation,
+    );
+
+    info!(
+        "Applying {:?} aspect mode - source: {:?}, destination: {:?}",
+        aspect_mode, source_rect, destination_rect
+    );
+
+    // Bars are filled with the border colour
+    let black = MFARGB {
+        rgbBlue: 0,
+        rgbGreen: 0,
+        rgbRed: 0,
+        rgbAlpha: 255,
+    };
+    control.SetBorderColor(Some(&black))?;
+    control.SetSourceRectangle(Some(&source_rect))?;
+    control.SetDestinationRectangle(Some(&destination_rect))?;
+    Ok(())
+}
+
+pub unsafe fn setup_video_converter(
+    layout: &ConverterLayout,
+    color_space: ColorSpace,
 ) -> Result<IMFTransform> {
+    let ConverterLayout {
+        input_width,
+        input_height,
+        output_width,
+        output_height,
+        window_position,
+        window_size,
+        aspect_mode,
+        transform,
+    } = *layout;
     // Create converter
     let converter: IMFTransform =
         CoCreateInstance(&CLSID_VideoProcessorMFT, None, CLSCTX_INPROC_SERVER)?;
@@ -101,6 +516,7 @@ pub unsafe fn setup_video_converter(
         ((output_width as u64) << 32) | (output_height as u64),
     )?;
     output_type.SetUINT32(&MF_MT_DEFAULT_STRIDE, output_width as u32)?;
+    color_space.apply(&output_type)?;
     converter.SetOutputType(0, &output_type, 0)?;
 
     // Set input media type (BGRA)
@@ -113,6 +529,8 @@ pub unsafe fn setup_video_converter(
         ((input_width as u64) << 32) | (input_height as u64),
     )?;
     input_type.SetUINT32(&MF_MT_DEFAULT_STRIDE, (input_width * 4) as u32)?;
+    // Captured desktop pixels use the full range
+    input_type.SetUINT32(&MF_MT_VIDEO_NOMINAL_RANGE, MFNominalRange_0_255.0 as u32)?;
     converter.SetInputType(0, &input_type, 0)?;
 
     // Video Processor Control Interface
@@ -121,9 +539,6 @@ pub unsafe fn setup_video_converter(
     let attributes = converter.GetAttributes()?;
 
     // Configure the converter to use source/destination rectangles based on window position and size
-    let window_pos_lock = window_position.lock().unwrap();
-    let window_size_lock = window_size.lock().unwrap();
-
     info!(
         "Video converter setup: input: {}x{}, output: {}x{}",
         input_width, input_height, output_width, output_height
@@ -131,11 +546,11 @@ pub unsafe fn setup_video_converter(
 
     info!(
         "Video converter setup: Window position: {:?}, Window size: {:?}",
-        *window_pos_lock, *window_size_lock
+        window_position, window_size
     );
 
     if let (Some((window_x, window_y)), Some((window_width, window_height))) =
-        (*window_pos_lock, *window_size_lock)
+        (window_position, window_size)
     {
         info!(
             "Setting up converter with initial window info - position: [{}, {}], size: {}x{}",
@@ -175,6 +590,31 @@ pub unsafe fn setup_video_converter(
         // }
     }
 
+    if let Some(ref control) = video_control {
+        if transform.crop != CropInsets::default() {
+            let source_rect = cropped_source_rect(
+                input_width,
+                input_height,
+                window_position,
+                window_size,
+                transform.crop,
+            );
+            info!("Cropping the recorded region to {:?}", source_rect);
+            control.SetSourceRectangle(Some(&source_rect))?;
+        }
+        if let Err(e) = apply_aspect_mode(control, layout) {
+            warn!("Failed to apply aspect mode {:?}: {:?}", aspect_mode, e);
+        }
+        if transform != VideoTransform::default() {
+            apply_orientation(control, transform)?;
+        }
+    } else if transform != VideoTransform::default() {
+        warn!(
+            "IMFVideoProcessorControl interface not found on the MFT. Cannot apply {:?}",
+            transform
+        );
+    }
+
     // Initialize the converter - only flush once at the beginning instead of each frame
     // MFT_MESSAGE_NOTIFY_BEGIN_STREAMING might be better
     converter.ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
@@ -195,11 +635,17 @@ pub unsafe fn setup_video_converter(
 /// Updates the video converter with new window position and size
 pub unsafe fn update_video_converter(
     converter: &IMFTransform,
-    input_width: u32,
-    input_height: u32,
-    window_position: Option<(i32, i32)>,
-    window_size: Option<(u32, u32)>,
+    layout: &ConverterLayout,
 ) -> Result<bool> {
+    let ConverterLayout {
+        input_width,
+        input_height,
+        window_position,
+        window_size,
+        aspect_mode,
+        transform,
+        ..
+    } = *layout;
     info!(
         "Updating video converter with new window info - Position: {:?}, Size: {:?}",
         window_position, window_size
@@ -214,6 +660,34 @@ pub unsafe fn update_video_converter(
     }
     let control = video_control.unwrap();
 
+    // Non-stretch modes derive both rectangles from the window region
+    if aspect_mode != AspectMode::Stretch {
+        return match apply_aspect_mode(&control, layout) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                warn!("Failed to apply aspect mode {:?}: {:?}", aspect_mode, e);
+                Ok(false)
+            }
+        };
+    }
+
+    if transform.crop != CropInsets::default() {
+        let source_rect = cropped_source_rect(
+            input_width,
+            input_height,
+            window_position,
+            window_size,
+            transform.crop,
+        );
+        return match control.SetSourceRectangle(Some(&source_rect)) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                warn!("Failed to set the cropped source rectangle: {:?}", e);
+                Ok(false)
+            }
+        };
+    }
+
     // Only update if we have both position and size
     if let (Some((window_x, window_y)), Some((window_width, window_height))) =
         (window_position, window_size)
@@ -252,6 +726,45 @@ pub unsafe fn update_video_converter(
     Ok(false) // Indicate no change was made
 }
 
+/// The texture behind `sample`
+pub unsafe fn sample_texture(sample: &IMFSample) -> Result<ID3D11Texture2D> {
+    let buffer = sample.GetBufferByIndex(0)?;
+    let dxgi_buffer: IMFDXGIBuffer = buffer.cast()?;
+    let mut raw = std::ptr::null_mut();
+    dxgi_buffer.GetResource(&ID3D11Texture2D::IID, &mut raw)?;
+    Ok(ID3D11Texture2D::from_raw(raw))
+}
+
+/// Width and height of the texture behind `sample`
+pub unsafe fn sample_size(sample: &IMFSample) -> Result<(u32, u32)> {
+    let texture = sample_texture(sample)?;
+    let mut desc = D3D11_TEXTURE2D_DESC::default();
+    texture.GetDesc(&mut desc);
+    Ok((desc.Width, desc.Height))
+}
+
+/// The device the texture behind `sample` was created on
+pub unsafe fn sample_device(sample: &IMFSample) -> Result<ID3D11Device> {
+    sample_texture(sample)?.GetDevice()
+}
+
+/// Copy a BGRA frame of the pool size into the next pooled conversion texture, for encoders that
+/// take BGRA input
+pub unsafe fn copy_bgra_to_pooled(
+    device: &ID3D11Device,
+    sample: &IMFSample,
+    texture_pool: &crate::types::TexturePool,
+) -> Result<IMFSample> {
+    let source = sample_texture(sample)?;
+    let texture = texture_pool.get_conversion_texture()?;
+    device.GetImmediateContext()?.CopyResource(&texture, &source);
+
+    let output_sample = create_output_sample_from_texture(&texture)?;
+    output_sample.SetSampleTime(sample.GetSampleTime()?)?;
+    output_sample.SetSampleDuration(sample.GetSampleDuration()?)?;
+    Ok(output_sample)
+}
+
 pub unsafe fn convert_bgra_to_nv12(
     device: &ID3D11Device,
     converter: &IMFTransform,
@@ -260,14 +773,50 @@ pub unsafe fn convert_bgra_to_nv12(
     output_height: u32,
     texture_pool: &crate::types::TexturePool,
 ) -> Result<IMFSample> {
-    let duration = sample.GetSampleDuration()?;
-    let time = sample.GetSampleTime()?;
-
     // Get the conversion texture from the pool
     let nv12_texture = texture_pool.get_conversion_texture()?;
+    convert_into(device, converter, sample, &nv12_texture)
+}
+
+/// Convert into a newly created NV12 texture that is not shared with later frames
+pub unsafe fn convert_bgra_to_new_nv12(
+    device: &ID3D11Device,
+    converter: &IMFTransform,
+    sample: &IMFSample,
+    output_width: u32,
+    output_height: u32,
+) -> Result<IMFSample> {
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: output_width,
+        Height: output_height,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: DXGI_FORMAT_NV12,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET,
+        CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+        MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+    };
+    let mut nv12_texture = None;
+    device.CreateTexture2D(&desc, None, Some(&mut nv12_texture))?;
+    convert_into(device, converter, sample, &nv12_texture.unwrap())
+}
+
+unsafe fn convert_into(
+    device: &ID3D11Device,
+    converter: &IMFTransform,
+    sample: &IMFSample,
+    nv12_texture: &ID3D11Texture2D,
+) -> Result<IMFSample> {
+    let duration = sample.GetSampleDuration()?;
+    let time = sample.GetSampleTime()?;
 
     // Create output sample from the texture
-    let output_sample = create_output_sample_from_texture(&nv12_texture)?;
+    let output_sample = create_output_sample_from_texture(nv12_texture)?;
 
     // Process the frame
     converter.ProcessInput(0, sample, 0)?;
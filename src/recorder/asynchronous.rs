@@ -0,0 +1,232 @@
+use crate::logging::{debug, error};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use super::events::RecorderEvent;
+use super::Recorder;
+use crate::capture::window::CapturableWindow;
+use crate::error::{RecorderError, Result};
+
+/// Events kept for a stream that is not being polled; older ones are discarded
+const MAX_BUFFERED_EVENTS: usize = 1024;
+
+/// Async facade over [`Recorder`] for use from async runtimes such as tokio
+///
+/// Starting, stopping and saving replays run on background threads and complete as futures, so
+/// they never block the executor. Events are delivered through [`EventStream`]s. The futures
+/// and streams do not depend on a particular runtime.
+pub struct AsyncRecorder {
+    recorder: Arc<Recorder>,
+    subscribers: Arc<Mutex<Vec<Weak<Subscriber>>>>,
+}
+
+impl AsyncRecorder {
+    /// Wrap a recorder whose target is already set (`with_process_name`, `with_window_handle`,
+    /// ...); events still reach the config's own event callback as well
+    pub fn new(mut recorder: Recorder) -> Self {
+        let subscribers: Arc<Mutex<Vec<Weak<Subscriber>>>> = Arc::new(Mutex::new(Vec::new()));
+        let publisher = subscribers.clone();
+        let previous = recorder.config.event_callback().cloned();
+        recorder.config = recorder.config.with_event_callback(Arc::new(move |event| {
+            if let Some(previous) = &previous {
+                previous(event);
+            }
+            if let Ok(mut subscribers) = publisher.lock() {
+                subscribers.retain(|subscriber| match subscriber.upgrade() {
+                    Some(subscriber) => {
+                        subscriber.push(Some(event.clone()));
+                        true
+                    }
+                    None => false,
+                });
+            }
+        }));
+
+        Self {
+            recorder: Arc::new(recorder),
+            subscribers,
+        }
+    }
+
+    /// The wrapped recorder, for the calls that return immediately (markers, levels, stats, ...)
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+
+    /// Start recording; completes with the recorded window once capture is running
+    pub fn start_recording(&self) -> RecorderTask<CapturableWindow> {
+        let recorder = self.recorder.clone();
+        RecorderTask::spawn("start", move || recorder.start_recording())
+    }
+
+    /// Stop recording; completes once the file is finalized
+    pub fn stop_recording(&self) -> RecorderTask<()> {
+        let recorder = self.recorder.clone();
+        RecorderTask::spawn("stop", move || recorder.stop_recording())
+    }
+
+    /// Write the replay buffer to `output_path`; completes once the file is written
+    pub fn save_replay(&self, output_path: impl Into<PathBuf>) -> RecorderTask<()> {
+        let recorder = self.recorder.clone();
+        let output_path = output_path.into();
+        RecorderTask::spawn("save-replay", move || {
+            let output_path = output_path.to_str().ok_or_else(|| {
+                RecorderError::Generic("Invalid replay output path".to_string())
+            })?;
+            recorder.save_replay(output_path)
+        })
+    }
+
+    /// Stream of the events emitted from now on
+    ///
+    /// Every stream receives every event. The stream ends when the `AsyncRecorder` is dropped.
+    pub fn events(&self) -> EventStream {
+        let subscriber = Arc::new(Subscriber::default());
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(Arc::downgrade(&subscriber));
+        }
+        EventStream { subscriber }
+    }
+}
+
+impl From<Recorder> for AsyncRecorder {
+    fn from(recorder: Recorder) -> Self {
+        Self::new(recorder)
+    }
+}
+
+impl Drop for AsyncRecorder {
+    fn drop(&mut self) {
+        if let Ok(subscribers) = self.subscribers.lock() {
+            for subscriber in subscribers.iter().filter_map(Weak::upgrade) {
+                subscriber.push(None);
+            }
+        }
+    }
+}
+
+struct TaskState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Future of a recorder operation running on a background thread
+///
+/// The operation runs to completion even if the future is dropped.
+pub struct RecorderTask<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+impl<T: Send + 'static> RecorderTask<T> {
+    fn spawn<F>(name: &str, operation: F) -> Self
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(TaskState {
+            result: None,
+            waker: None,
+        }));
+        let thread_state = state.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("windows-record-{}", name))
+            .spawn(move || {
+                let result = operation();
+                debug!("Async recorder operation finished: {:?}", result.is_ok());
+                let mut state = thread_state.lock().unwrap();
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to spawn the {} thread: {}", name, e);
+            state.lock().unwrap().result = Some(Err(RecorderError::Generic(format!(
+                "Failed to spawn {} thread: {}",
+                name, e
+            ))));
+        }
+        Self { state }
+    }
+}
+
+impl<T> Future for RecorderTask<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Events queued for one [`EventStream`]; None marks the end of the stream
+#[derive(Default)]
+struct Subscriber {
+    queue: Mutex<(VecDeque<Option<RecorderEvent>>, Option<Waker>)>,
+}
+
+impl Subscriber {
+    fn push(&self, event: Option<RecorderEvent>) {
+        if let Ok(mut queue) = self.queue.lock() {
+            let (events, waker) = &mut *queue;
+            if events.len() == MAX_BUFFERED_EVENTS {
+                events.pop_front();
+            }
+            events.push_back(event);
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Asynchronous stream of [`RecorderEvent`]s from an [`AsyncRecorder`]
+///
+/// `poll_next` has the signature of `futures::Stream::poll_next`, so the stream can be adapted
+/// with `futures::stream::poll_fn` or `tokio_stream` wrappers.
+pub struct EventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl EventStream {
+    /// Next event, or None once the recorder has been dropped
+    pub fn recv(&mut self) -> NextEvent<'_> {
+        NextEvent { stream: self }
+    }
+
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<RecorderEvent>> {
+        let mut queue = self.subscriber.queue.lock().unwrap();
+        let (events, waker) = &mut *queue;
+        match events.front() {
+            // Leave the end marker in place so later polls also see the end
+            Some(None) => Poll::Ready(None),
+            Some(Some(_)) => Poll::Ready(events.pop_front().flatten()),
+            None => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Future returned by [`EventStream::recv`]
+pub struct NextEvent<'a> {
+    stream: &'a mut EventStream,
+}
+
+impl Future for NextEvent<'_> {
+    type Output = Option<RecorderEvent>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_next(cx)
+    }
+}
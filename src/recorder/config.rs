@@ -1,8 +1,45 @@
+use crate::logging::warn;
 use std::path::PathBuf;
+use windows::Win32::Graphics::Direct3D11::ID3D11Device;
 
+#[cfg(feature = "serde")]
+use super::event_pipe::EventPipe;
+use super::events::{EventCallback, RecorderEvent};
+use super::preset::{audio_budget, video_budget, Preset};
+use crate::capture::source::VideoSource;
+use crate::capture::timing::CaptureTiming;
+use crate::capture::window::{MinimizedPolicy, ReacquirePolicy, WindowCorners};
 use crate::device::VideoEncoderType;
+use crate::error::{ConfigIssue, RecorderError, Result};
+use crate::processing::audio::{MicrophoneFilters, MICROPHONE_SOURCE, SYSTEM_AUDIO_SOURCE};
+use crate::processing::audio_filter::{AudioFilter, AudioFilters, AUDIO_MIX};
+use crate::processing::encoder::EncoderSettings;
+use crate::processing::filter::{VideoFilter, VideoFilters};
+use crate::processing::high_fps::HighFpsCapture;
+use crate::processing::idle::{IdleAction, IdlePolicy};
+use crate::processing::image::{OutputMode, ThumbnailSpec};
+use crate::processing::mask::{MaskRect, MaskStyle};
+use crate::processing::overlay::InputOverlayConfig;
+use crate::processing::preview::PreviewSpec;
+use crate::processing::media::{AudioCodec, QualityProfile, SinkWriterOptions};
+use crate::processing::scaler::ScalingQuality;
+use crate::processing::tap::{EncodedFrame, EncodedFrameCallback};
+use crate::processing::video::{AspectMode, ColorSpace, PixelPipeline, VideoTransform};
+use crate::types::frame_queue::BackpressurePolicy;
+use crate::types::memory::MemoryLimits;
+use crate::types::texture_pool::BufferPoolSizes;
 
+/// Recorder settings, built with [`RecorderConfig::builder`]
+///
+/// With the `serde` feature the settings can be saved to and loaded from TOML or JSON files. The
+/// video source, the external device, the video and audio filters and the callbacks are not saved; a loaded config uses the
+/// defaults for them.
 #[derive(Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct RecorderConfig {
     // Video settings
     fps_num: u32,
@@ -12,9 +49,42 @@ pub struct RecorderConfig {
     output_width: u32,
     output_height: u32,
     video_bitrate: u32,
+    target_file_size: Option<(u64, std::time::Duration)>,
+    quality_profile: QualityProfile,
     video_encoder: VideoEncoderType,
     video_encoder_name: Option<String>,
+    encoder_settings: EncoderSettings,
+    encoder_adapter: Option<u64>,
+    encoder_on_capture_adapter: bool,
     capture_cursor: bool,
+    capture_client_area_only: bool,
+    window_corners: WindowCorners,
+    minimized_policy: MinimizedPolicy,
+    window_reacquire: ReacquirePolicy,
+    capture_timing: CaptureTiming,
+    vsync_pacing: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    video_source: VideoSource,
+    scaling_quality: ScalingQuality,
+    aspect_mode: AspectMode,
+    video_transform: VideoTransform,
+    color_space: ColorSpace,
+    pixel_pipeline: PixelPipeline,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    video_filters: VideoFilters,
+    low_latency: bool,
+    scene_cut_keyframes: Option<f32>,
+    start_gate: bool,
+    privacy_masks: Vec<MaskRect>,
+    input_overlay: Option<InputOverlayConfig>,
+    buffer_pool_sizes: BufferPoolSizes,
+    memory_limits: MemoryLimits,
+    backpressure_policy: BackpressurePolicy,
+    frame_queue_capacity: usize,
+    idle_detection: Option<IdlePolicy>,
+    high_fps_capture: Option<HighFpsCapture>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    external_device: Option<ID3D11Device>,
 
     // Audio settings
     capture_audio: bool,
@@ -22,22 +92,113 @@ pub struct RecorderConfig {
     microphone_volume: Option<f32>,
     system_volume: Option<f32>,
     audio_source: AudioSource,
+    loopback_mode: LoopbackMode,
     microphone_device: Option<String>,
+    extra_microphones: Vec<String>,
+    microphone_filters: MicrophoneFilters,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    audio_filters: AudioFilters,
+    microphone_hotplug: bool,
+    microphone_latency_compensation: bool,
+    audio_codec: AudioCodec,
+    av_sync_offset_ms: i64,
+    audio_normalization: Option<f32>,
 
     // Output settings
     output_path: PathBuf,
+    output_template: Option<String>,
+    output_mode: OutputMode,
     debug_mode: bool,
+    debug_frame_stamp: bool,
+    profiling: bool,
+    thumbnail: Option<ThumbnailSpec>,
+    preview: PreviewSpec,
+    shared_preview_texture: bool,
+    metadata_sidecar: bool,
+    fragmented_output: bool,
+    sink_writer_options: SinkWriterOptions,
+    capture_dump_path: Option<PathBuf>,
 
     // Replay buffer settings
     enable_replay_buffer: bool,
     replay_buffer_seconds: u32,
+    replay_buffer_encoding: Option<EncodingProfile>,
+
+    // Notifications
+    #[cfg_attr(feature = "serde", serde(skip))]
+    event_callback: Option<EventCallback>,
+    #[cfg(feature = "serde")]
+    event_pipe: Option<EventPipe>,
+    stats_interval: Option<std::time::Duration>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    encoded_frame_callback: Option<EncodedFrameCallback>,
+}
+
+/// Encode settings for the replay buffer when they should differ from the main recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodingProfile {
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Video bitrate in bits per second
+    pub video_bitrate: u32,
 }
 
 #[derive(Clone, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AudioSource {
     #[default]
     Desktop,
     ActiveWindow,
+    /// The audio of other processes than the recorded one, e.g. a game's video with a voice chat
+    /// app's audio. Each process is mixed as a source of its own, named by
+    /// `AudioProcess::source_name`; processes that start later are picked up while recording
+    Processes(Vec<AudioProcess>),
+    /// A generated tone instead of any captured audio, for testing the pipeline without an
+    /// audio device
+    #[cfg(feature = "synthetic")]
+    Synthetic(crate::capture::synthetic::SineWave),
+}
+
+/// How `AudioSource::ActiveWindow` and `AudioSource::Processes` capture the system audio
+///
+/// Process loopback needs Windows 10 build 20348 or later. The mode in use is reported with
+/// `RecorderEvent::AudioLoopbackSelected` when audio capture starts.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LoopbackMode {
+    /// Process loopback, or the whole desktop audio where Windows cannot activate it
+    #[default]
+    Auto,
+    /// Process loopback only; audio capture fails where Windows cannot activate it
+    Process,
+    /// The whole desktop audio of the default output device, even where process loopback works
+    Desktop,
+}
+
+/// A process whose audio `AudioSource::Processes` captures, together with the processes it
+/// started
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AudioProcess {
+    Id(u32),
+    /// Every running process started from this executable, given as a file name such as
+    /// `discord.exe` (case-insensitive, `.exe` optional) or as a full path
+    Executable(String),
+}
+
+impl AudioProcess {
+    /// Name the audio of the process is mixed under, for `Recorder::set_audio_gain` and
+    /// `audio_filters`: the executable as given, or `process <id>`
+    ///
+    /// A second running instance of the same executable gets its process id appended, e.g.
+    /// `discord.exe (1234)`.
+    pub fn source_name(&self) -> String {
+        match self {
+            AudioProcess::Id(process_id) => format!("process {}", process_id),
+            AudioProcess::Executable(executable) => executable.clone(),
+        }
+    }
 }
 
 impl Default for RecorderConfig {
@@ -52,17 +213,73 @@ impl Default for RecorderConfig {
             capture_audio: true,
             capture_microphone: false,
             output_path: PathBuf::from("."),
+            output_template: None,
+            output_mode: OutputMode::default(),
             debug_mode: false,
+            debug_frame_stamp: false,
+            profiling: false,
+            thumbnail: None,
+            preview: PreviewSpec::default(),
+            shared_preview_texture: false,
+            metadata_sidecar: false,
+            fragmented_output: false,
+            sink_writer_options: SinkWriterOptions::default(),
+            capture_dump_path: None,
             video_bitrate: 5000000,
+            target_file_size: None,
+            quality_profile: QualityProfile::default(),
             microphone_volume: None,
             audio_source: AudioSource::ActiveWindow,
+            loopback_mode: LoopbackMode::Auto,
             system_volume: None,
             microphone_device: None,
+            extra_microphones: Vec::new(),
+            microphone_filters: MicrophoneFilters::default(),
+            audio_filters: AudioFilters::default(),
+            microphone_hotplug: false,
+            microphone_latency_compensation: true,
+            audio_codec: AudioCodec::default(),
+            av_sync_offset_ms: 0,
+            audio_normalization: None,
             video_encoder: VideoEncoderType::default(),
+            encoder_settings: EncoderSettings::default(),
             video_encoder_name: None,
+            encoder_adapter: None,
+            encoder_on_capture_adapter: false,
             enable_replay_buffer: false,
             replay_buffer_seconds: 30,
+            replay_buffer_encoding: None,
             capture_cursor: true,
+            capture_client_area_only: false,
+            window_corners: WindowCorners::default(),
+            minimized_policy: MinimizedPolicy::default(),
+            window_reacquire: ReacquirePolicy::default(),
+            capture_timing: CaptureTiming::default(),
+            vsync_pacing: false,
+            video_source: VideoSource::default(),
+            scaling_quality: ScalingQuality::default(),
+            aspect_mode: AspectMode::default(),
+            video_transform: VideoTransform::default(),
+            color_space: ColorSpace::default(),
+            pixel_pipeline: PixelPipeline::default(),
+            video_filters: VideoFilters::default(),
+            low_latency: false,
+            scene_cut_keyframes: None,
+            start_gate: false,
+            privacy_masks: Vec::new(),
+            input_overlay: None,
+            buffer_pool_sizes: BufferPoolSizes::default(),
+            memory_limits: MemoryLimits::default(),
+            backpressure_policy: BackpressurePolicy::default(),
+            frame_queue_capacity: 16,
+            idle_detection: None,
+            high_fps_capture: None,
+            external_device: None,
+            event_callback: None,
+            #[cfg(feature = "serde")]
+            event_pipe: None,
+            stats_interval: None,
+            encoded_frame_callback: None,
         }
     }
 }
@@ -72,6 +289,395 @@ impl RecorderConfig {
         RecorderConfigBuilder::default()
     }
 
+    /// Load settings saved with `to_file`, as TOML if the file name ends in `.toml` and as JSON
+    /// otherwise. Options missing from the file keep their defaults, and the result is checked
+    /// like `RecorderConfigBuilder::build`
+    #[cfg(feature = "serde")]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            RecorderError::Generic(format!(
+                "Failed to read config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let parsed = if is_toml(path) {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        };
+        let config: RecorderConfig = parsed.map_err(|e| {
+            RecorderError::Generic(format!("Invalid config file {}: {}", path.display(), e))
+        })?;
+        RecorderConfigBuilder::from_config(config).build()
+    }
+
+    /// Save the settings to `path`, as TOML if the file name ends in `.toml` and as pretty JSON
+    /// otherwise
+    #[cfg(feature = "serde")]
+    pub fn to_file(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = if is_toml(path) {
+            toml::to_string_pretty(self).map_err(|e| e.to_string())
+        } else {
+            serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+        }
+        .map_err(|e| RecorderError::Generic(format!("Failed to serialize config: {}", e)))?;
+        std::fs::write(path, text).map_err(|e| {
+            RecorderError::Generic(format!(
+                "Failed to write config file {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    // Copy of this config with a different event callback (used by `AsyncRecorder`)
+    pub(crate) fn with_event_callback(&self, callback: EventCallback) -> Self {
+        let mut config = self.clone();
+        config.event_callback = Some(callback);
+        config
+    }
+
+    // Derive the video bitrate from the target file size, if one is set
+    fn apply_target_file_size(&mut self) -> std::result::Result<(), String> {
+        let Some((bytes, expected_duration)) = self.target_file_size else {
+            return Ok(());
+        };
+        if bytes == 0 || expected_duration.is_zero() {
+            return Err("size and expected duration must be greater than 0".to_string());
+        }
+        let audio_bitrate = self.budgeted_audio_bitrate().ok_or_else(|| {
+            "lossless audio has no predictable size, use AudioCodec::Aac".to_string()
+        })?;
+        self.video_bitrate = video_budget(bytes, expected_duration, audio_bitrate);
+        Ok(())
+    }
+
+    // Copy of this config writing to a different file (used for templated output paths)
+    pub(crate) fn with_output_path(&self, path: PathBuf) -> Self {
+        let mut config = self.clone();
+        config.output_path = path;
+        config
+    }
+
+    /// Round dimensions to encoder-legal values and collect everything that cannot be fixed
+    fn validate(&mut self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |field, message: String| issues.push(ConfigIssue { field, message });
+
+        if self.fps_num == 0 {
+            issue("fps", "frame rate numerator must be greater than 0".to_string());
+        }
+        if self.fps_den == 0 {
+            issue(
+                "fps",
+                "frame rate denominator must be greater than 0, use fps(30, 1) for 30 fps"
+                    .to_string(),
+            );
+        }
+
+        let (width, height) = (self.output_width & !1, self.output_height & !1);
+        if width == 0 || height == 0 {
+            issue(
+                "output_dimensions",
+                format!(
+                    "{}x{} is too small, both dimensions must be at least 2",
+                    self.output_width, self.output_height
+                ),
+            );
+        } else if (width, height) != (self.output_width, self.output_height) {
+            warn!(
+                "Rounding output dimensions {}x{} down to {}x{}, NV12 needs even dimensions",
+                self.output_width, self.output_height, width, height
+            );
+            self.output_width = width;
+            self.output_height = height;
+        }
+
+        if let (Some(0), _) | (_, Some(0)) = (self.input_width, self.input_height) {
+            issue(
+                "input_dimensions",
+                "dimensions must be greater than 0, or left unset to use the monitor resolution"
+                    .to_string(),
+            );
+        }
+
+        if let Err(message) = self.apply_target_file_size() {
+            issue("target_file_size", message);
+        }
+
+        if self.quality_profile == QualityProfile::Lossless && self.target_file_size.is_some() {
+            issue(
+                "quality_profile",
+                "a lossless recording has no predictable size, remove the target file size"
+                    .to_string(),
+            );
+        }
+
+        if self.preview.fps == 0 {
+            issue(
+                "preview",
+                "preview fps must be greater than 0, e.g. 10".to_string(),
+            );
+        }
+
+        let mut named_sources = self.extra_microphones.clone();
+        if let AudioSource::Processes(processes) = &self.audio_source {
+            named_sources.extend(processes.iter().map(AudioProcess::source_name));
+        }
+        for source in self.audio_filters.unknown_sources(&named_sources) {
+            issue(
+                "audio_filters",
+                format!(
+                    "unknown audio source '{}', expected system, microphone, mix, a captured \
+                     process or an extra microphone",
+                    source
+                ),
+            );
+        }
+
+        if !self.extra_microphones.is_empty() && !self.capture_microphone {
+            issue(
+                "extra_microphones",
+                "extra microphones are mixed with the microphone, enable capture_microphone"
+                    .to_string(),
+            );
+        }
+        for (index, name) in self.extra_microphones.iter().enumerate() {
+            if name.is_empty() {
+                issue(
+                    "extra_microphones",
+                    "device name must not be empty".to_string(),
+                );
+            } else if [SYSTEM_AUDIO_SOURCE, MICROPHONE_SOURCE, AUDIO_MIX].contains(&name.as_str())
+                || self.extra_microphones[..index].contains(name)
+            {
+                issue(
+                    "extra_microphones",
+                    format!("'{}' is already the name of another audio source", name),
+                );
+            }
+        }
+
+        if let Err(message) = self.encoder_settings.check(self.video_encoder) {
+            issue("encoder_settings", message);
+        }
+
+        if self.video_bitrate == 0 {
+            issue(
+                "video_bitrate",
+                "bitrate must be greater than 0 bits per second, e.g. 5_000_000".to_string(),
+            );
+        }
+
+        if let AudioCodec::Aac { bitrate } = self.audio_codec {
+            if !AudioCodec::AAC_BITRATES.contains(&bitrate) {
+                issue(
+                    "audio_codec",
+                    format!(
+                        "AAC bitrate {} is not supported, use one of {:?}",
+                        bitrate,
+                        AudioCodec::AAC_BITRATES
+                    ),
+                );
+            }
+        }
+
+        if let AudioSource::Processes(processes) = &self.audio_source {
+            if processes.is_empty() {
+                issue(
+                    "audio_source",
+                    "AudioSource::Processes needs at least one process".to_string(),
+                );
+            }
+        }
+
+        if self.enable_replay_buffer && self.replay_buffer_seconds == 0 {
+            issue(
+                "replay_buffer_seconds",
+                "must be at least 1 when the replay buffer is enabled".to_string(),
+            );
+        }
+
+        if let Some(profile) = &mut self.replay_buffer_encoding {
+            let (width, height) = (profile.output_width & !1, profile.output_height & !1);
+            if width == 0 || height == 0 {
+                issue(
+                    "replay_buffer_encoding",
+                    format!(
+                        "{}x{} is too small, both dimensions must be at least 2",
+                        profile.output_width, profile.output_height
+                    ),
+                );
+            } else {
+                profile.output_width = width;
+                profile.output_height = height;
+            }
+            if profile.video_bitrate == 0 {
+                issue(
+                    "replay_buffer_encoding",
+                    "bitrate must be greater than 0 bits per second".to_string(),
+                );
+            }
+        }
+
+        if let Some(threshold) = self
+            .scene_cut_keyframes
+            .filter(|t| !t.is_finite() || *t <= 0.0 || *t > 1.0)
+        {
+            issue(
+                "scene_cut_keyframes",
+                format!("{} is not a valid threshold, use a value above 0.0 and up to 1.0", threshold),
+            );
+        }
+
+        for mask in &self.privacy_masks {
+            if mask.width == 0 || mask.height == 0 {
+                issue(
+                    "privacy_masks",
+                    format!("mask at ({}, {}) must be at least 1x1 pixels", mask.x, mask.y),
+                );
+            }
+            if let MaskStyle::Pixelate { block_size: 0 } = mask.style {
+                issue(
+                    "privacy_masks",
+                    format!("mask at ({}, {}) needs a block size of at least 1", mask.x, mask.y),
+                );
+            }
+        }
+
+        if let Some(overlay) = &self.input_overlay {
+            if overlay.show_clicks && (overlay.click_radius == 0 || overlay.click_duration.is_zero())
+            {
+                issue(
+                    "input_overlay",
+                    "click_radius and click_duration must be greater than 0".to_string(),
+                );
+            }
+            if overlay.show_keystrokes
+                && (overlay.keystroke_scale == 0 || overlay.keystroke_duration.is_zero())
+            {
+                issue(
+                    "input_overlay",
+                    "keystroke_scale and keystroke_duration must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        if self.buffer_pool_sizes.capture_textures == 0
+            || self.buffer_pool_sizes.conversion_textures == 0
+        {
+            issue(
+                "buffer_pool_sizes",
+                "capture_textures and conversion_textures must be at least 1".to_string(),
+            );
+        }
+
+        if self.memory_limits.gpu_bytes == Some(0)
+            || self.memory_limits.replay_buffer_bytes == Some(0)
+        {
+            issue(
+                "memory_limits",
+                "gpu_bytes and replay_buffer_bytes must be greater than 0".to_string(),
+            );
+        }
+
+        if let OutputMode::ImageSequence { every_nth, .. } = &self.output_mode {
+            if *every_nth == 0 {
+                issue(
+                    "output_mode",
+                    "every_nth must be at least 1, use 1 to keep every frame".to_string(),
+                );
+            }
+            if self.capture_audio || self.capture_microphone {
+                issue(
+                    "output_mode",
+                    "an image sequence has no audio, disable capture_audio and capture_microphone"
+                        .to_string(),
+                );
+            }
+            if self.encoded_frame_callback.is_some() {
+                issue(
+                    "output_mode",
+                    "an image sequence is not encoded, remove the encoded_frame_callback"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.frame_queue_capacity == 0 {
+            issue(
+                "frame_queue_capacity",
+                "the frame queue must hold at least 1 frame".to_string(),
+            );
+        }
+
+        if self.window_reacquire.interval.is_zero() {
+            issue(
+                "window_reacquire",
+                "interval must be greater than 0, e.g. Duration::from_millis(50)".to_string(),
+            );
+        }
+
+        if let Some(policy) = &self.idle_detection {
+            if policy.threshold.is_zero() {
+                issue(
+                    "idle_detection",
+                    "threshold must be greater than 0, e.g. Duration::from_secs(30)".to_string(),
+                );
+            }
+            if let IdleAction::ReduceFrameRate { fps: 0 } = policy.action {
+                issue(
+                    "idle_detection",
+                    "the reduced frame rate must be at least 1 fps".to_string(),
+                );
+            }
+        }
+
+        if let Some(high_fps) = &self.high_fps_capture {
+            if (high_fps.capture_fps as u64) * (self.fps_den as u64) < self.fps_num as u64 {
+                issue(
+                    "high_fps_capture",
+                    format!(
+                        "capture_fps {} is below the output frame rate {}/{}",
+                        high_fps.capture_fps, self.fps_num, self.fps_den
+                    ),
+                );
+            }
+            if high_fps.secondary_output.is_some() && high_fps.secondary_bitrate == 0 {
+                issue(
+                    "high_fps_capture",
+                    "secondary_bitrate must be greater than 0 bits per second".to_string(),
+                );
+            }
+        }
+
+        for (field, volume) in [
+            ("system_volume", self.system_volume),
+            ("microphone_volume", self.microphone_volume),
+        ] {
+            if let Some(volume) = volume.filter(|v| !v.is_finite() || *v < 0.0) {
+                issue(field, format!("{} is not a valid volume, use 0.0 or more", volume));
+            }
+        }
+        if let Some(target) = self
+            .audio_normalization
+            .filter(|target| !(-70.0..=0.0).contains(target))
+        {
+            issue(
+                "audio_normalization",
+                format!(
+                    "{} LUFS is not a valid loudness target, use -70.0 to 0.0 (e.g. -14.0)",
+                    target
+                ),
+            );
+        }
+
+        issues
+    }
+
     // Getters
     pub fn fps_num(&self) -> u32 {
         self.fps_num
@@ -100,42 +706,231 @@ impl RecorderConfig {
     pub fn output_path(&self) -> &PathBuf {
         &self.output_path
     }
+    pub fn output_template(&self) -> Option<&str> {
+        self.output_template.as_deref()
+    }
+    pub fn output_mode(&self) -> &OutputMode {
+        &self.output_mode
+    }
     pub fn debug_mode(&self) -> bool {
         self.debug_mode
     }
+    pub fn debug_frame_stamp(&self) -> bool {
+        self.debug_frame_stamp
+    }
+    pub fn profiling(&self) -> bool {
+        self.profiling
+    }
+    pub fn thumbnail(&self) -> Option<&ThumbnailSpec> {
+        self.thumbnail.as_ref()
+    }
+    pub fn preview(&self) -> PreviewSpec {
+        self.preview
+    }
+    pub fn shared_preview_texture(&self) -> bool {
+        self.shared_preview_texture
+    }
+    pub fn metadata_sidecar(&self) -> bool {
+        self.metadata_sidecar
+    }
+    pub fn fragmented_output(&self) -> bool {
+        self.fragmented_output
+    }
+    pub fn sink_writer_options(&self) -> SinkWriterOptions {
+        self.sink_writer_options
+    }
+
+    pub fn capture_dump_path(&self) -> Option<&PathBuf> {
+        self.capture_dump_path.as_ref()
+    }
     pub fn video_bitrate(&self) -> u32 {
         self.video_bitrate
     }
+    pub fn target_file_size(&self) -> Option<(u64, std::time::Duration)> {
+        self.target_file_size
+    }
+    pub fn quality_profile(&self) -> QualityProfile {
+        self.quality_profile
+    }
+    // Video bitrate that keeps a file of `duration` within the target file size, if one is set
+    // and the audio leaves its size predictable (used for saved replays)
+    pub(crate) fn file_size_bitrate(&self, duration: std::time::Duration) -> Option<u32> {
+        let (bytes, _) = self.target_file_size?;
+        let audio_bitrate = self.budgeted_audio_bitrate()?;
+        Some(video_budget(bytes, duration, audio_bitrate))
+    }
+    // Audio bitrate a file size budget reserves, None for lossless audio whose size is unknown
+    fn budgeted_audio_bitrate(&self) -> Option<u32> {
+        if !self.capture_audio && !self.capture_microphone {
+            return Some(0);
+        }
+        audio_budget(self.audio_codec)
+    }
     pub fn video_encoder(&self) -> &VideoEncoderType {
         &self.video_encoder
     }
+    pub fn encoder_settings(&self) -> EncoderSettings {
+        self.encoder_settings
+    }
     pub fn video_encoder_name(&self) -> Option<&str> {
         self.video_encoder_name.as_deref()
     }
+    pub fn encoder_adapter(&self) -> Option<u64> {
+        self.encoder_adapter
+    }
+    pub fn encoder_on_capture_adapter(&self) -> bool {
+        self.encoder_on_capture_adapter
+    }
     pub fn microphone_volume(&self) -> Option<f32> {
         self.microphone_volume
     }
     pub fn audio_source(&self) -> &AudioSource {
         &self.audio_source
     }
+    pub fn loopback_mode(&self) -> LoopbackMode {
+        self.loopback_mode
+    }
     pub fn system_volume(&self) -> Option<f32> {
         self.system_volume
     }
     pub fn microphone_device(&self) -> Option<&str> {
         self.microphone_device.as_deref()
     }
+    pub fn extra_microphones(&self) -> &[String] {
+        &self.extra_microphones
+    }
+    pub fn microphone_filters(&self) -> &MicrophoneFilters {
+        &self.microphone_filters
+    }
+    pub(crate) fn audio_filters(&self) -> &AudioFilters {
+        &self.audio_filters
+    }
+    pub fn microphone_hotplug(&self) -> bool {
+        self.microphone_hotplug
+    }
+    pub fn microphone_latency_compensation(&self) -> bool {
+        self.microphone_latency_compensation
+    }
+    pub fn audio_codec(&self) -> AudioCodec {
+        self.audio_codec
+    }
+    pub fn av_sync_offset_ms(&self) -> i64 {
+        self.av_sync_offset_ms
+    }
+    pub fn audio_normalization(&self) -> Option<f32> {
+        self.audio_normalization
+    }
     pub fn enable_replay_buffer(&self) -> bool {
         self.enable_replay_buffer
     }
     pub fn replay_buffer_seconds(&self) -> u32 {
         self.replay_buffer_seconds
     }
+    pub fn replay_buffer_encoding(&self) -> Option<&EncodingProfile> {
+        self.replay_buffer_encoding.as_ref()
+    }
+    // Resolution and bitrate saved replays are encoded with
+    pub(crate) fn replay_output(&self) -> (u32, u32, u32) {
+        match &self.replay_buffer_encoding {
+            Some(profile) => (profile.output_width, profile.output_height, profile.video_bitrate),
+            None => (self.output_width, self.output_height, self.video_bitrate),
+        }
+    }
     pub fn capture_cursor(&self) -> bool {
         self.capture_cursor
     }
+    pub fn capture_client_area_only(&self) -> bool {
+        self.capture_client_area_only
+    }
+    pub fn window_corners(&self) -> WindowCorners {
+        self.window_corners
+    }
+    pub fn minimized_policy(&self) -> MinimizedPolicy {
+        self.minimized_policy
+    }
+    pub fn window_reacquire(&self) -> ReacquirePolicy {
+        self.window_reacquire
+    }
+    pub fn capture_timing(&self) -> CaptureTiming {
+        self.capture_timing
+    }
+    pub fn vsync_pacing(&self) -> bool {
+        self.vsync_pacing
+    }
+    pub fn video_source(&self) -> &VideoSource {
+        &self.video_source
+    }
+    pub fn scaling_quality(&self) -> ScalingQuality {
+        self.scaling_quality
+    }
+    pub fn aspect_mode(&self) -> AspectMode {
+        self.aspect_mode
+    }
+    pub fn video_transform(&self) -> VideoTransform {
+        self.video_transform
+    }
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+    pub fn pixel_pipeline(&self) -> PixelPipeline {
+        self.pixel_pipeline
+    }
+    pub(crate) fn video_filters(&self) -> &VideoFilters {
+        &self.video_filters
+    }
+    pub fn low_latency(&self) -> bool {
+        self.low_latency
+    }
+    pub fn scene_cut_keyframes(&self) -> Option<f32> {
+        self.scene_cut_keyframes
+    }
+    pub fn start_gate(&self) -> bool {
+        self.start_gate
+    }
+    pub fn privacy_masks(&self) -> &[MaskRect] {
+        &self.privacy_masks
+    }
+    pub fn input_overlay(&self) -> Option<InputOverlayConfig> {
+        self.input_overlay
+    }
+    pub fn buffer_pool_sizes(&self) -> BufferPoolSizes {
+        self.buffer_pool_sizes
+    }
+    pub fn memory_limits(&self) -> MemoryLimits {
+        self.memory_limits
+    }
+    pub fn backpressure_policy(&self) -> BackpressurePolicy {
+        self.backpressure_policy
+    }
+    pub fn frame_queue_capacity(&self) -> usize {
+        self.frame_queue_capacity
+    }
+    pub fn idle_detection(&self) -> Option<&IdlePolicy> {
+        self.idle_detection.as_ref()
+    }
+    pub fn high_fps_capture(&self) -> Option<&HighFpsCapture> {
+        self.high_fps_capture.as_ref()
+    }
+    pub fn external_device(&self) -> Option<&ID3D11Device> {
+        self.external_device.as_ref()
+    }
+
+    pub fn event_callback(&self) -> Option<&EventCallback> {
+        self.event_callback.as_ref()
+    }
+    #[cfg(feature = "serde")]
+    pub fn event_pipe(&self) -> Option<&EventPipe> {
+        self.event_pipe.as_ref()
+    }
+    pub fn stats_interval(&self) -> Option<std::time::Duration> {
+        self.stats_interval
+    }
+    pub fn encoded_frame_callback(&self) -> Option<&EncodedFrameCallback> {
+        self.encoded_frame_callback.as_ref()
+    }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct RecorderConfigBuilder {
     config: RecorderConfig,
 }
@@ -145,6 +940,18 @@ impl RecorderConfigBuilder {
         Self::default()
     }
 
+    /// Start from an existing config, e.g. one loaded with `RecorderConfig::from_file`, to
+    /// change some of its options
+    pub fn from_config(config: RecorderConfig) -> Self {
+        Self { config }
+    }
+
+    /// Apply the resolution, frame rate, codec, bitrate and audio codec of `preset`; options set
+    /// after it override the preset's
+    pub fn preset(self, preset: Preset) -> Self {
+        preset.apply(self)
+    }
+
     pub fn fps(mut self, num: u32, den: u32) -> Self {
         self.config.fps_num = num;
         self.config.fps_den = den;
@@ -178,13 +985,110 @@ impl RecorderConfigBuilder {
         self
     }
 
+    /// Derive a new output path for every recording and replay save from a template such as
+    /// `recordings/{process}_{date}_{time}_{index}.mp4`. Overrides `output_path` when set
+    pub fn output_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.config.output_template = Some(template.into());
+        self
+    }
+
+    /// Write the processed frames as a video file at `output_path`, or as numbered PNG or DDS
+    /// images in a directory for dataset capture and timelapses
+    pub fn output_mode(mut self, mode: OutputMode) -> Self {
+        self.config.output_mode = mode;
+        self
+    }
+
     pub fn debug_mode(mut self, enabled: bool) -> Self {
         self.config.debug_mode = enabled;
         self
     }
 
+    /// Burn the frame index and capture QPC time into the top-left corner of every frame
+    pub fn debug_frame_stamp(mut self, enabled: bool) -> Self {
+        self.config.debug_frame_stamp = enabled;
+        self
+    }
+
+    /// Time the capture copy, convert and encode submit stages with CPU timers and GPU timestamp
+    /// queries, reported through `Recorder::profile_report` and periodic `PipelineProfile` events
+    pub fn profiling(mut self, enabled: bool) -> Self {
+        self.config.profiling = enabled;
+        self
+    }
+
+    /// Write a JPEG thumbnail next to the output file (same name, `.jpg` extension) when a
+    /// recording stops or a replay is saved
+    pub fn thumbnail(mut self, spec: ThumbnailSpec) -> Self {
+        self.config.thumbnail = Some(spec);
+        self
+    }
+
+    /// Rate and size of the frames handed to `Recorder::preview_receiver`
+    pub fn preview(mut self, spec: PreviewSpec) -> Self {
+        self.config.preview = spec;
+        self
+    }
+
+    /// Copy every frame going to the encoder into a shared D3D11 texture, so Direct3D and
+    /// DirectComposition UIs can show a live preview without a copy to the CPU; see
+    /// `Recorder::shared_preview_texture`
+    pub fn shared_preview_texture(mut self, enabled: bool) -> Self {
+        self.config.shared_preview_texture = enabled;
+        self
+    }
+
+    /// Write a JSON file with the session details (process, window, resolutions, encoder, frame
+    /// statistics, markers, wall-clock start and end) next to the output file when recording stops
+    pub fn metadata_sidecar(mut self, enabled: bool) -> Self {
+        self.config.metadata_sidecar = enabled;
+        self
+    }
+
+    /// Write the recording as a fragmented MP4 so that everything up to the last keyframe is
+    /// playable (or recoverable with `repair_mp4`) if the process dies before the file is
+    /// finalized
+    pub fn fragmented_output(mut self, enabled: bool) -> Self {
+        self.config.fragmented_output = enabled;
+        self
+    }
+
+    /// Tune the attributes the sink writer is created with, e.g. disable its throttling when
+    /// `WriteSample` stalls the pipeline at high bitrates
+    pub fn sink_writer_options(mut self, options: SinkWriterOptions) -> Self {
+        self.config.sink_writer_options = options;
+        self
+    }
+
+    /// Dump raw captured frames and audio to this file so the session can be replayed later
+    /// with `replay_capture_dump`
+    pub fn capture_dump_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.capture_dump_path = Some(path.into());
+        self
+    }
+
+    /// Encode at this many bits per second. Replaces an earlier `target_file_size`, e.g. one
+    /// set by a preset
     pub fn video_bitrate(mut self, video_bitrate: u32) -> Self {
         self.config.video_bitrate = video_bitrate;
+        self.config.target_file_size = None;
+        self
+    }
+
+    /// Keep a recording of `expected_duration` under `bytes`, e.g. for a chat app's upload limit.
+    /// The video bitrate is derived from the budget left after the audio, replacing an earlier
+    /// `video_bitrate` (a later one removes the target again), and the encoder runs in capped VBR so its peaks stay within it. Saved
+    /// replays get the same limit, budgeted for the length of each clip. Needs AAC audio
+    pub fn target_file_size(mut self, bytes: u64, expected_duration: std::time::Duration) -> Self {
+        self.config.target_file_size = Some((bytes, expected_duration));
+        self
+    }
+
+    /// Encode for visually lossless quality: constant QP 0 instead of `video_bitrate`, and the
+    /// 4:4:4 profile where the encoder has it and frames reach it as RGB. Starting fails if the
+    /// encoder has no constant quality rate control
+    pub fn quality_profile(mut self, profile: QualityProfile) -> Self {
+        self.config.quality_profile = profile;
         self
     }
 
@@ -198,6 +1102,13 @@ impl RecorderConfigBuilder {
         self
     }
 
+    /// Force process loopback or desktop audio for the process sources, instead of falling back
+    /// to desktop audio only where process loopback is unavailable
+    pub fn loopback_mode(mut self, mode: LoopbackMode) -> Self {
+        self.config.loopback_mode = mode;
+        self
+    }
+
     pub fn system_volume(mut self, volume: impl Into<Option<f32>>) -> Self {
         self.config.system_volume = volume.into();
         self
@@ -208,6 +1119,64 @@ impl RecorderConfigBuilder {
         self
     }
 
+    /// More microphones recorded next to the main one, by device name, e.g. a webcam's
+    /// microphone. Each is mixed as a source named after its device, at the microphone volume;
+    /// one that is not plugged in is left out
+    pub fn extra_microphones<S: Into<String>>(mut self, device_names: Vec<S>) -> Self {
+        self.config.extra_microphones = device_names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Filters applied to the microphone before it is mixed (high-pass, noise gate,
+    /// noise suppression, automatic gain control)
+    pub fn microphone_filters(mut self, filters: MicrophoneFilters) -> Self {
+        self.config.microphone_filters = filters;
+        self
+    }
+
+    /// Custom filters run in order over `source` (`SYSTEM_AUDIO_SOURCE` or `MICROPHONE_SOURCE`,
+    /// before mixing) or over the mixed track (`AUDIO_MIX`), replacing any set before for it
+    pub fn audio_filters(mut self, source: &str, filters: Vec<Box<dyn AudioFilter>>) -> Self {
+        self.config.audio_filters.set(source, filters);
+        self
+    }
+
+    /// When no microphone is present at start, keep looking for one and start mixing it in
+    /// once it is plugged in, instead of recording without a microphone
+    pub fn microphone_hotplug(mut self, enabled: bool) -> Self {
+        self.config.microphone_hotplug = enabled;
+        self
+    }
+
+    /// Move microphone timestamps earlier by the latency the device reports (stream latency plus
+    /// one device period), so voice lines up with system audio without a manual offset
+    pub fn microphone_latency_compensation(mut self, enabled: bool) -> Self {
+        self.config.microphone_latency_compensation = enabled;
+        self
+    }
+
+    /// Codec of the audio track: AAC at a chosen bitrate, or lossless FLAC or PCM for editing
+    pub fn audio_codec(mut self, codec: AudioCodec) -> Self {
+        self.config.audio_codec = codec;
+        self
+    }
+
+    /// Shift the audio by `milliseconds` relative to the video before it is written: positive
+    /// values delay the audio, negative values move it earlier. For capture chains with a known
+    /// latency mismatch, e.g. an external audio interface
+    pub fn av_sync_offset(mut self, milliseconds: i64) -> Self {
+        self.config.av_sync_offset_ms = milliseconds;
+        self
+    }
+
+    /// Level the audio track towards `target_lufs` integrated over a few seconds (e.g. -14.0 for
+    /// YouTube, -23.0 for EBU R128) and limit its peaks to -1 dBFS, so loud sources summed by
+    /// the mixer no longer clip
+    pub fn audio_normalization(mut self, target_lufs: f32) -> Self {
+        self.config.audio_normalization = Some(target_lufs);
+        self
+    }
+
     pub fn video_encoder(mut self, encoder: VideoEncoderType) -> Self {
         self.config.video_encoder = encoder;
         self
@@ -218,6 +1187,27 @@ impl RecorderConfigBuilder {
         self
     }
 
+    /// Ask the encoder for a profile, level and (H.264) entropy coding, e.g. Baseline with CAVLC
+    /// for older decoders or WebRTC. Starting fails if the encoder does not support the profile
+    pub fn encoder_settings(mut self, settings: EncoderSettings) -> Self {
+        self.config.encoder_settings = settings;
+        self
+    }
+
+    /// Look the video encoder up on the adapter with `adapter_luid` (see
+    /// `enumerate_video_encoders_for_adapter`), falling back to any adapter if it has none
+    pub fn encoder_adapter(mut self, adapter_luid: u64) -> Self {
+        self.config.encoder_adapter = Some(adapter_luid);
+        self
+    }
+
+    /// Prefer a video encoder on the adapter doing the capture, which saves copying every frame
+    /// between GPUs; an explicit `encoder_adapter` wins
+    pub fn encoder_on_capture_adapter(mut self, enabled: bool) -> Self {
+        self.config.encoder_on_capture_adapter = enabled;
+        self
+    }
+
     pub fn enable_replay_buffer(mut self, enabled: bool) -> Self {
         self.config.enable_replay_buffer = enabled;
         self
@@ -228,12 +1218,242 @@ impl RecorderConfigBuilder {
         self
     }
 
+    /// Encode saved replays with a different resolution and bitrate than the main recording
+    /// Frames are converted a second time at this size for the buffer, so a smaller size also
+    /// lowers the buffer's GPU memory use
+    pub fn replay_buffer_encoding(mut self, profile: EncodingProfile) -> Self {
+        self.config.replay_buffer_encoding = Some(profile);
+        self
+    }
+
     pub fn capture_cursor(mut self, enabled: bool) -> Self {
         self.config.capture_cursor = enabled;
         self
     }
 
-    pub fn build(self) -> RecorderConfig {
-        self.config
+    /// Crop to the window's client area, leaving out the title bar and borders; by default the
+    /// visible window frame is recorded (without its drop shadow)
+    pub fn capture_client_area_only(mut self, enabled: bool) -> Self {
+        self.config.capture_client_area_only = enabled;
+        self
+    }
+
+    /// Leave the rounded corners of Windows 11 windows out of the recording by cropping inside
+    /// them or painting them black
+    pub fn window_corners(mut self, corners: WindowCorners) -> Self {
+        self.config.window_corners = corners;
+        self
+    }
+
+    /// What to record while the target window is minimized
+    pub fn minimized_policy(mut self, policy: MinimizedPolicy) -> Self {
+        self.config.minimized_policy = policy;
+        self
+    }
+
+    /// How often and how long to look for the target window again after it was closed, and
+    /// whether to stop the recording when it does not come back
+    pub fn window_reacquire(mut self, policy: ReacquirePolicy) -> Self {
+        self.config.window_reacquire = policy;
+        self
+    }
+
+    /// Frame acquisition timeout and pacing strategy of the capture thread
+    pub fn capture_timing(mut self, timing: CaptureTiming) -> Self {
+        self.config.capture_timing = timing;
+        self
+    }
+
+    /// Take frames on the vertical blank of the captured monitor instead of the pacing timer,
+    /// avoiding judder when recording at (a divisor of) the monitor's refresh rate
+    pub fn vsync_pacing(mut self, enabled: bool) -> Self {
+        self.config.vsync_pacing = enabled;
+        self
+    }
+
+    /// Record frames supplied by the application (`Recorder::submit_frame` or a `FrameSource`)
+    /// instead of duplicating the window's monitor
+    pub fn video_source(mut self, source: VideoSource) -> Self {
+        self.config.video_source = source;
+        self
+    }
+
+    /// Filter used when scaling the capture to the output size
+    pub fn scaling_quality(mut self, quality: ScalingQuality) -> Self {
+        self.config.scaling_quality = quality;
+        self
+    }
+
+    /// How the capture is fitted into the output when their aspect ratios differ
+    pub fn aspect_mode(mut self, mode: AspectMode) -> Self {
+        self.config.aspect_mode = mode;
+        self
+    }
+
+    /// Crop, rotate and flip the capture in the video processor. With a quarter turn the output
+    /// size is that of the rotated picture, e.g. 1080x1920 for a portrait monitor
+    pub fn video_transform(mut self, transform: VideoTransform) -> Self {
+        self.config.video_transform = transform;
+        self
+    }
+
+    /// YUV matrix and range the frames are converted to and the file is tagged with
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.config.color_space = color_space;
+        self
+    }
+
+    /// How frames reach the encoder. `Auto` hands BGRA frames straight to encoders that take
+    /// RGB input when the output is the capture size and nothing is rotated or flipped
+    pub fn pixel_pipeline(mut self, pipeline: PixelPipeline) -> Self {
+        self.config.pixel_pipeline = pipeline;
+        self
+    }
+
+    /// GPU filters run in order over every captured frame before it is scaled and encoded, e.g.
+    /// `vec![Box::new(SharpenFilter::new(0.5))]`
+    pub fn video_filters(mut self, filters: Vec<Box<dyn VideoFilter>>) -> Self {
+        self.config.video_filters = VideoFilters::new(filters);
+        self
+    }
+
+    /// Minimize glass-to-file latency: low-latency encoder and sink writer, and queued frames
+    /// are dropped rather than encoded late
+    pub fn low_latency(mut self, enabled: bool) -> Self {
+        self.config.low_latency = enabled;
+        self
+    }
+
+    /// Force a keyframe when consecutive frames differ by at least `threshold` (mean absolute
+    /// luma difference, 0.0..=1.0, e.g. 0.3), so seeking and saved replays start cleanly at cuts
+    pub fn scene_cut_keyframes(mut self, threshold: f32) -> Self {
+        self.config.scene_cut_keyframes = Some(threshold);
+        self
+    }
+
+    /// Drop frames until the first non-blank one and start the video and audio at the moment it
+    /// was captured, instead of with whatever the capture delivered first
+    pub fn start_gate(mut self, enabled: bool) -> Self {
+        self.config.start_gate = enabled;
+        self
+    }
+
+    /// Black out or pixelate these regions of the captured monitor on the GPU before encoding;
+    /// `Recorder::update_privacy_masks` replaces them while recording
+    pub fn privacy_masks(mut self, masks: Vec<MaskRect>) -> Self {
+        self.config.privacy_masks = masks;
+        self
     }
+
+    /// Draw mouse click ripples and the keys being pressed onto the recording, read with
+    /// low-level input hooks; see `InputOverlayConfig` for what is shown
+    pub fn input_overlay(mut self, overlay: InputOverlayConfig) -> Self {
+        self.config.input_overlay = Some(overlay);
+        self
+    }
+
+    /// Number of textures kept for captured frames and for color conversion. More capture
+    /// textures absorb longer encoder stalls at the cost of GPU memory; when all are in use a
+    /// frame waits up to `acquire_timeout` for one before it is dropped
+    pub fn buffer_pool_sizes(mut self, sizes: BufferPoolSizes) -> Self {
+        self.config.buffer_pool_sizes = sizes;
+        self
+    }
+
+    /// Caps on the GPU texture memory and on the bytes the replay buffer holds. Past a cap the
+    /// replay buffer drops its oldest samples, so it keeps less than `replay_buffer_seconds`
+    /// rather than growing without bound
+    pub fn memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.config.memory_limits = limits;
+        self
+    }
+
+    /// What the capture does when the encoder falls behind and the frame queue is full
+    pub fn backpressure_policy(mut self, policy: BackpressurePolicy) -> Self {
+        self.config.backpressure_policy = policy;
+        self
+    }
+
+    /// Number of captured frames that may wait for the encoder before the backpressure policy
+    /// applies
+    pub fn frame_queue_capacity(mut self, frames: usize) -> Self {
+        self.config.frame_queue_capacity = frames;
+        self
+    }
+
+    /// Watch for long stretches of static video and silent audio, emitting `IdleStarted` and
+    /// `IdleEnded` events and adding chapter markers or lowering the frame rate while idle
+    pub fn idle_detection(mut self, policy: IdlePolicy) -> Self {
+        self.config.idle_detection = Some(policy);
+        self
+    }
+
+    /// Capture at `capture_fps` while the output file keeps `fps`, optionally writing every
+    /// captured frame to a second, high bitrate file for slow-motion editing
+    pub fn high_fps_capture(mut self, high_fps: HighFpsCapture) -> Self {
+        self.config.high_fps_capture = Some(high_fps);
+        self
+    }
+
+    /// Capture, convert and encode on `device` instead of creating a new one, so an engine
+    /// embedding the recorder shares its device with it. The device must be created with
+    /// `D3D11_CREATE_DEVICE_BGRA_SUPPORT` on the adapter driving the captured monitor; a D3D12
+    /// renderer can pass the device from `D3D11On12CreateDevice`. Multithread protection is
+    /// enabled on it
+    pub fn external_device(mut self, device: ID3D11Device) -> Self {
+        self.config.external_device = Some(device);
+        self
+    }
+
+    /// Receive recorder events (stop progress, warnings, ...) on the recorder's threads
+    pub fn event_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&RecorderEvent) + Send + Sync + 'static,
+    {
+        self.config.event_callback = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Also write every event as a line of JSON to `pipe` (stdout or a file / named pipe), for
+    /// hosts that supervise the recorder from another language. Requires the `serde` feature
+    #[cfg(feature = "serde")]
+    pub fn event_pipe(mut self, pipe: EventPipe) -> Self {
+        self.config.event_pipe = Some(pipe);
+        self
+    }
+
+    /// Emit a `Stats` event with the frame counters and bytes written every `interval`
+    pub fn stats_interval(mut self, interval: std::time::Duration) -> Self {
+        self.config.stats_interval = Some(interval);
+        self
+    }
+
+    /// Receive every encoded video frame (bitstream, keyframe flag, parameter sets on keyframes)
+    /// as it is written, e.g. to mux or stream it yourself. Called on the encoding thread
+    pub fn encoded_frame_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&EncodedFrame) + Send + Sync + 'static,
+    {
+        self.config.encoded_frame_callback = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Validate the configuration
+    ///
+    /// Odd output dimensions are rounded down to even values, as required by the NV12 encoder
+    /// input. Every other problem is returned at once in [`RecorderError::InvalidConfig`].
+    pub fn build(mut self) -> Result<RecorderConfig> {
+        let issues = self.config.validate();
+        if issues.is_empty() {
+            Ok(self.config)
+        } else {
+            Err(RecorderError::InvalidConfig(issues))
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn is_toml(path: &std::path::Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"))
 }
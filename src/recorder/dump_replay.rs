@@ -0,0 +1,366 @@
+use crate::logging::{info, warn};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use windows::core::ComInterface;
+use windows::Win32::Foundation::TRUE;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
+use windows::Win32::Media::MediaFoundation::*;
+use windows::Win32::UI::WindowsAndMessaging::GetDesktopWindow;
+
+use super::config::RecorderConfig;
+use super::errors::ErrorCollector;
+use super::events::EventEmitter;
+use super::inner::{
+    recording_device, resolve_pixel_pipeline, resolve_video_encoder, resolve_video_encoding,
+    sink_writer_settings, target_encoder_adapter,
+};
+use crate::capture::AudioPacket;
+use crate::error::{RecorderError, Result};
+use crate::processing::audio::{mixer_controls, AudioLevels, MixerStats};
+use crate::processing::dump::{CaptureDumpReader, DumpRecordKind};
+use crate::processing::media::{self, SinkWriterSettings};
+use crate::processing::profiler::PipelineProfiler;
+use crate::processing::video::PixelPipeline;
+use crate::processing::{process_samples, ProcessingContext};
+use crate::types::frame_queue::{BackpressurePolicy, FrameQueueStats};
+use crate::types::{
+    frame_queue, FrameCounters, SendableSample, SendableWriter, TexturePool, WindowGeometry,
+};
+
+/// Feed a capture dump recorded with `capture_dump_path` back through the processing and
+/// encoding pipeline, writing the result to the config's output path
+///
+/// Records are submitted at the pace they were captured so the processing thread sees the same
+/// interleaving of video, audio and window updates as the original session.
+pub fn replay_capture_dump<P: AsRef<Path>>(dump_path: P, config: &RecorderConfig) -> Result<()> {
+    let dump_path = dump_path.as_ref();
+    info!("Replaying capture dump {}", dump_path.display());
+
+    let mut reader = CaptureDumpReader::open(dump_path)?;
+
+    let output_path = config
+        .output_path()
+        .to_str()
+        .ok_or_else(|| RecorderError::Generic("Invalid path string".to_string()))?;
+
+    unsafe {
+        media::init_media_foundation()?;
+        let result = replay_records(&mut reader, config, output_path);
+        let _ = media::shutdown_media_foundation();
+        result
+    }
+}
+
+unsafe fn replay_records(
+    reader: &mut CaptureDumpReader,
+    config: &RecorderConfig,
+    output_path: &str,
+) -> Result<()> {
+    let header = reader.header();
+    let capture_audio = config.capture_audio();
+    let capture_microphone = config.capture_microphone();
+
+    let encoder_adapter = target_encoder_adapter(config, GetDesktopWindow());
+    let video_encoder = resolve_video_encoder(config, encoder_adapter)?;
+    info!(
+        "Replay using video encoder: {} ({:?})",
+        video_encoder.name, video_encoder.encoder_type
+    );
+
+    let pixel_pipeline = resolve_pixel_pipeline(
+        config,
+        (header.input_width, header.input_height),
+        &video_encoder,
+    );
+    let video_encoding = resolve_video_encoding(config, &video_encoder, pixel_pipeline)?;
+
+    let settings = SinkWriterSettings {
+        fps_num: header.fps_num,
+        fps_den: header.fps_den,
+        ..sink_writer_settings(config, &video_encoder, pixel_pipeline, video_encoding)
+    };
+    let media_sink = media::create_sink_writer(
+        output_path,
+        &settings,
+        config.encoded_frame_callback().cloned(),
+    )?;
+    media_sink.BeginWriting()?;
+    let writer = SendableWriter(Arc::new(media_sink));
+
+    let (device, context) = recording_device(config, GetDesktopWindow())?;
+    drop(context);
+    let device = Arc::new(device);
+
+    let mut texture_pool = TexturePool::new(
+        device.clone(),
+        0,
+        config.buffer_pool_sizes().conversion_textures,
+        header.input_width,
+        header.input_height,
+        DXGI_FORMAT_B8G8R8A8_UNORM,
+    )?;
+    if pixel_pipeline == PixelPipeline::Rgb {
+        texture_pool.set_conversion_format(DXGI_FORMAT_B8G8R8A8_UNORM);
+    }
+    let texture_pool = Arc::new(texture_pool);
+
+    // Replaying is not real time, so the reader waits for the encoder instead of dropping
+    let counters = Arc::new(FrameCounters::default());
+    let (sender_video, receiver_video) = frame_queue(
+        config.frame_queue_capacity(),
+        BackpressurePolicy::BlockCapture,
+        Arc::new(Mutex::new(FrameQueueStats::default())),
+        counters.clone(),
+    );
+    let (sender_audio, receiver_audio) = channel::<SendableSample>();
+    let (sender_microphone, receiver_microphone) = channel::<SendableSample>();
+    let (sender_window_info, receiver_window_info) = channel::<WindowGeometry>();
+
+    // The first window info record (if any) seeds the initial converter geometry
+    let mut first_record = reader.next_record()?;
+    let (initial_position, initial_size) =
+        match first_record.as_ref().and_then(|r| r.window_info()) {
+            Some((position, size)) => {
+                first_record = None;
+                (Some(position), Some(size))
+            }
+            None => (None, None),
+        };
+    info!(
+        "Replay initial window position: {:?}, size: {:?}",
+        initial_position, initial_size
+    );
+
+    let input_width = header.input_width;
+    let input_height = header.input_height;
+    let recording = Arc::new(AtomicBool::new(true));
+    let events = EventEmitter::new(config.event_callback().cloned());
+    let context = ProcessingContext {
+        rec_video: receiver_video,
+        rec_audio: receiver_audio,
+        rec_microphone: receiver_microphone,
+        // Dumps hold the two main sources only
+        rec_process_audio: channel::<AudioPacket>().1,
+        rec_extra_microphones: Vec::new(),
+        rec_window_info: receiver_window_info,
+        recording: recording.clone(),
+        input_width,
+        input_height,
+        output_width: config.output_width(),
+        output_height: config.output_height(),
+        device: device.clone(),
+        capture_audio,
+        per_process_audio: false,
+        capture_microphone,
+        microphone_attached: Arc::new(AtomicBool::new(true)),
+        audio_start_qpc: None,
+        start_gate: false,
+        mixer_controls: mixer_controls(
+            capture_audio,
+            capture_microphone,
+            config.system_volume(),
+            config.microphone_volume(),
+            &[],
+        ),
+        audio_normalization: config.audio_normalization(),
+        replay_buffer: None,
+        replay_size: None,
+        initial_window_position: initial_position,
+        initial_window_size: initial_size,
+        texture_pool,
+        capture_dump: None,
+        audio_levels: Arc::new(Mutex::new(AudioLevels::default())),
+        mixer_stats: Arc::new(Mutex::new(MixerStats::default())),
+        microphone_filters: config.microphone_filters().clone(),
+        audio_filters: config.audio_filters().clone(),
+        segment_output: Arc::new(Mutex::new(None)),
+        privacy_masks: Arc::new(Mutex::new(config.privacy_masks().to_vec())),
+        // Dumped frames already have their corners masked
+        corner_radii: Arc::new(Mutex::new((0, 0))),
+        input_overlay: None,
+        scaling_quality: config.scaling_quality(),
+        aspect_mode: config.aspect_mode(),
+        video_transform: config.video_transform(),
+        color_space: config.color_space(),
+        pixel_pipeline,
+        debug_frame_stamp: config.debug_frame_stamp(),
+        low_latency: config.low_latency(),
+        scene_cut_threshold: config.scene_cut_keyframes(),
+        target_bitrate: config.video_bitrate(),
+        thumbnail: None,
+        image_sequence: None,
+        output_decimator: None,
+        high_fps_output: None,
+        preview: None,
+        shared_preview: None,
+        video_filters: None,
+        idle_throttle: None,
+        paused: Arc::new(AtomicBool::new(false)),
+        // Dumped audio was already shifted when it was recorded
+        av_sync_offset: Arc::new(AtomicI64::new(0)),
+        stats_interval: config.stats_interval(),
+        frame_tagger: None,
+        counters,
+        profiler: if config.profiling() {
+            Some(Arc::new(PipelineProfiler::new(&device)?))
+        } else {
+            None
+        },
+        events: events.clone(),
+        errors: ErrorCollector::new(events),
+    };
+
+    let process_handle = std::thread::spawn(move || process_samples(Some(writer), context));
+
+    let replay_start = Instant::now();
+    let mut first_timestamp: Option<i64> = None;
+    let mut record_count = 0;
+
+    let mut next = match first_record {
+        Some(record) => Some(record),
+        None => reader.next_record()?,
+    };
+
+    while let Some(record) = next {
+        record_count += 1;
+
+        // Pace submissions by the recorded timestamps (100ns units)
+        if record.kind != DumpRecordKind::WindowInfo {
+            let base = *first_timestamp.get_or_insert(record.sample_time);
+            let target = Duration::from_nanos(((record.sample_time - base).max(0) * 100) as u64);
+            if let Some(wait) = target.checked_sub(replay_start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        match record.kind {
+            DumpRecordKind::Video => {
+                let sample = create_video_sample(
+                    &device,
+                    input_width,
+                    input_height,
+                    &record.payload,
+                    record.sample_time,
+                    record.sample_duration,
+                )?;
+                let _ = sender_video.send(SendableSample::new(sample));
+            }
+            DumpRecordKind::SystemAudio => {
+                let sample = create_audio_sample(
+                    &record.payload,
+                    record.sample_time,
+                    record.sample_duration,
+                )?;
+                let _ = sender_audio.send(SendableSample::new(sample));
+            }
+            DumpRecordKind::Microphone => {
+                let sample = create_audio_sample(
+                    &record.payload,
+                    record.sample_time,
+                    record.sample_duration,
+                )?;
+                let _ = sender_microphone.send(SendableSample::new(sample));
+            }
+            DumpRecordKind::WindowInfo => {
+                if let Some((position, size)) = record.window_info() {
+                    let _ = sender_window_info.send((Some(position), Some(size)));
+                }
+            }
+        }
+
+        next = reader.next_record()?;
+    }
+
+    info!(
+        "Submitted {} dump records in {:?}, waiting for processing to drain",
+        record_count,
+        replay_start.elapsed()
+    );
+
+    // Give the processing thread time to drain its queues before stopping it
+    std::thread::sleep(Duration::from_millis(500));
+    recording.store(false, Ordering::Relaxed);
+
+    match process_handle.join() {
+        Ok(Ok(())) => {
+            info!("Capture dump replay finished, output written to {}", output_path);
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            warn!("Processing failed during capture dump replay: {:?}", e);
+            Err(e.into())
+        }
+        Err(_) => Err(RecorderError::Generic(
+            "Process Handle join failed".to_string(),
+        )),
+    }
+}
+
+/// Upload a dumped BGRA frame into a GPU texture wrapped in an IMFSample
+unsafe fn create_video_sample(
+    device: &ID3D11Device,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    sample_time: i64,
+    sample_duration: i64,
+) -> windows::core::Result<IMFSample> {
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: width,
+        Height: height,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_RENDER_TARGET | D3D11_BIND_SHADER_RESOURCE,
+        CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+        MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+    };
+    let initial_data = D3D11_SUBRESOURCE_DATA {
+        pSysMem: pixels.as_ptr() as *const _,
+        SysMemPitch: width * 4,
+        SysMemSlicePitch: 0,
+    };
+
+    let mut texture: Option<ID3D11Texture2D> = None;
+    device.CreateTexture2D(&desc, Some(&initial_data), Some(&mut texture))?;
+    let texture = texture.unwrap();
+
+    let surface: ID3D11Resource = texture.cast()?;
+    let buffer = MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &surface, 0, TRUE)?;
+
+    let sample = MFCreateSample()?;
+    sample.AddBuffer(&buffer)?;
+    sample.SetSampleTime(sample_time)?;
+    sample.SetSampleDuration(sample_duration)?;
+    Ok(sample)
+}
+
+/// Wrap dumped PCM bytes in a memory-backed IMFSample
+unsafe fn create_audio_sample(
+    bytes: &[u8],
+    sample_time: i64,
+    sample_duration: i64,
+) -> windows::core::Result<IMFSample> {
+    let sample = MFCreateSample()?;
+    let buffer = MFCreateMemoryBuffer(bytes.len() as u32)?;
+
+    let mut data = std::ptr::null_mut();
+    buffer.Lock(&mut data, None, None)?;
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+    buffer.SetCurrentLength(bytes.len() as u32)?;
+    buffer.Unlock()?;
+
+    sample.AddBuffer(&buffer)?;
+    sample.SetSampleTime(sample_time)?;
+    sample.SetSampleDuration(sample_duration)?;
+    Ok(sample)
+}
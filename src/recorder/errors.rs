@@ -0,0 +1,90 @@
+use crate::logging::{debug, error};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use windows::core::{Error, Result, HSTRING};
+use windows::Win32::Foundation::E_FAIL;
+
+use super::events::{EventEmitter, RecorderEvent};
+use crate::error::{RecorderError, ThreadSource};
+
+/// Records the first fatal error raised by any recorder thread
+///
+/// Every failure is reported through the event callback as it happens; the first one is kept so
+/// `stop_recording` can return it.
+#[derive(Clone)]
+pub(crate) struct ErrorCollector {
+    first: Arc<Mutex<Option<(ThreadSource, String)>>>,
+    events: EventEmitter,
+}
+
+impl ErrorCollector {
+    pub fn new(events: EventEmitter) -> Self {
+        Self {
+            first: Arc::new(Mutex::new(None)),
+            events,
+        }
+    }
+
+    pub fn report(&self, thread: ThreadSource, message: String) {
+        {
+            let mut first = self.first.lock().unwrap();
+            match first.as_ref() {
+                // An encoder failure also ends the processing thread with the same error
+                Some((_, first_message)) if *first_message == message => {
+                    debug!("{:?} thread failed with an already reported error", thread);
+                    return;
+                }
+                Some(_) => {}
+                None => *first = Some((thread, message.clone())),
+            }
+        }
+        error!("{:?} thread failed: {}", thread, message);
+        self.events.emit(RecorderEvent::ThreadFailed { thread, message });
+    }
+
+    /// The first reported failure, if any
+    pub fn first_error(&self) -> Option<RecorderError> {
+        self.first
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|(thread, inner)| RecorderError::ThreadFailed { thread, inner })
+    }
+}
+
+/// Spawn a recorder thread whose error or panic is reported to `errors` as soon as it happens
+pub(crate) fn spawn_reporting<F>(
+    thread: ThreadSource,
+    errors: ErrorCollector,
+    f: F,
+) -> JoinHandle<Result<()>>
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        // Everything the thread logs is attributed to its part of the pipeline
+        let span = match thread {
+            ThreadSource::Process | ThreadSource::Encoder => {
+                tracing::info_span!("encoder", thread = ?thread)
+            }
+            _ => tracing::info_span!("capture", thread = ?thread),
+        };
+        let _entered = span.entered();
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                errors.report(thread, e.to_string());
+                Err(e)
+            }
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                errors.report(thread, format!("panicked: {}", message));
+                Err(Error::new(E_FAIL, HSTRING::from(message)))
+            }
+        }
+    })
+}
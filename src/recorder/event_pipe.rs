@@ -0,0 +1,99 @@
+use crate::logging::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, TrySendError};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::config::RecorderConfig;
+use super::events::RecorderEvent;
+use crate::error::{RecorderError, Result};
+
+/// Events waiting for the writer thread; newer ones are dropped while it is full
+const MAX_PENDING_LINES: usize = 1024;
+
+/// Where `event_pipe` streams recorder events, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventPipe {
+    /// The process's standard output
+    Stdout,
+    /// A file, or a named pipe created by the host such as `\\.\pipe\recorder-events`
+    Path(PathBuf),
+}
+
+/// One NDJSON line: the event tagged with its name and when it was emitted
+#[derive(Serialize)]
+struct EventLine<'a> {
+    /// Milliseconds since the Unix epoch
+    time_ms: u128,
+    #[serde(flatten)]
+    event: &'a RecorderEvent,
+}
+
+/// `event` as one NDJSON line without the trailing newline, stamped with the current time
+pub(crate) fn to_json(event: &RecorderEvent) -> serde_json::Result<String> {
+    serde_json::to_string(&EventLine {
+        time_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis()),
+        event,
+    })
+}
+
+/// Copy of `config` whose event callback also streams every event to `pipe`
+///
+/// Lines are written by their own thread so a host that reads slowly never stalls the recorder;
+/// the thread exits once the last recorder using the callback is dropped.
+pub(crate) fn attach(config: &RecorderConfig, pipe: &EventPipe) -> Result<RecorderConfig> {
+    let mut output: Box<dyn Write + Send> = match pipe {
+        EventPipe::Stdout => Box::new(std::io::stdout()),
+        EventPipe::Path(path) => Box::new(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .map_err(|e| {
+                    RecorderError::Generic(format!(
+                        "Failed to open event pipe {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?,
+        ),
+    };
+
+    let (sender, lines) = sync_channel::<Vec<u8>>(MAX_PENDING_LINES);
+    std::thread::Builder::new()
+        .name("windows-record-event-pipe".to_string())
+        .spawn(move || {
+            for line in lines {
+                if let Err(e) = output.write_all(&line).and_then(|_| output.flush()) {
+                    warn!("Event pipe closed, no more events are written: {}", e);
+                    return;
+                }
+            }
+            debug!("Event pipe writer stopped");
+        })
+        .map_err(|e| RecorderError::Generic(format!("Failed to spawn event pipe thread: {}", e)))?;
+
+    let previous = config.event_callback().cloned();
+    Ok(config.with_event_callback(Arc::new(move |event| {
+        if let Some(previous) = &previous {
+            previous(event);
+        }
+        let mut encoded = match to_json(event) {
+            Ok(encoded) => encoded.into_bytes(),
+            Err(e) => {
+                warn!("Failed to serialize {:?}: {}", event, e);
+                return;
+            }
+        };
+        encoded.push(b'\n');
+        match sender.try_send(encoded) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => warn!("Event pipe is full, dropping {:?}", event),
+        }
+    })))
+}
@@ -0,0 +1,286 @@
+use crate::logging::{error, trace};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use windows::Win32::Foundation::HWND;
+
+use crate::capture::window::DisplayMode;
+use crate::error::ThreadSource;
+use crate::processing::encoder::BitrateDeviation;
+use crate::processing::profiler::ProfileReport;
+use crate::recorder::config::LoopbackMode;
+
+/// Callback invoked for every [`RecorderEvent`]
+///
+/// Called from the recorder's worker threads, so it should return quickly.
+pub type EventCallback = Arc<dyn Fn(&RecorderEvent) + Send + Sync>;
+
+/// Stages reported while a recording is being stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum StopStage {
+    /// Waiting for the capture threads to exit
+    StoppingCapture,
+    /// Waiting for the processing thread to drain its queues and finalize the file
+    Finalizing,
+    /// Writing sidecar files (chapters, metadata)
+    WritingMetadata,
+}
+
+/// Notifications emitted by the recorder through the configured event callback
+///
+/// With the `serde` feature events serialize as objects named by an `event` field, e.g.
+/// `{"event":"Stopped","output_path":"out.mp4"}`; window handles become integers.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event"))]
+pub enum RecorderEvent {
+    /// The target window appeared while `start_recording` was waiting for it
+    WindowFound {
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hwnd"))]
+        hwnd: HWND,
+        title: String,
+        /// How long it took the window to appear
+        waited: Duration,
+    },
+    /// Stopping has progressed to a new stage
+    StopProgress {
+        stage: StopStage,
+        /// Threads joined so far
+        completed: usize,
+        /// Total threads to join
+        total: usize,
+    },
+    /// The recording reached the `with_max_duration` limit and is being stopped
+    MaxDurationReached { max_duration: Duration },
+    /// Free space on the output volume fell below `with_min_free_space`; the recording is being
+    /// stopped so the file can still be finalized
+    LowDiskSpace { free_bytes: u64, min_free_space: u64 },
+    /// The video has been static and the audio silent for the `idle_detection` threshold
+    IdleStarted {
+        /// Position in the recording where nothing changed any more
+        timestamp: Duration,
+    },
+    /// The video or audio changed again after an idle period
+    IdleEnded {
+        /// Position in the recording where activity resumed
+        timestamp: Duration,
+        idle_for: Duration,
+    },
+    /// A process watched by `RecorderSupervisor` opened a window; a recording is being started
+    ProcessLaunched { process_name: String, process_id: u32 },
+    /// `RecorderSupervisor` could not start recording a launched process
+    AutoStartFailed {
+        process_name: String,
+        process_id: u32,
+        message: String,
+    },
+    /// A process recorded by `RecorderSupervisor` exited and its recording was finalized
+    ProcessExited {
+        process_name: String,
+        process_id: u32,
+        /// File the recording was written to, None if stopping failed
+        output_path: Option<PathBuf>,
+    },
+    /// The recording has been stopped and the file finalized
+    Stopped { output_path: PathBuf },
+    /// Stopping failed; the output file may be incomplete
+    StopFailed { message: String },
+    /// The recording set up with `Recorder::with_scheduled_start` could not be started when its
+    /// time came
+    ScheduledStartFailed { message: String },
+    /// The capture fell behind and repeated the last frame to keep the frame rate
+    FramesDuplicated {
+        /// Position in the recording of the duplicated frames
+        timestamp: Duration,
+        /// Frames duplicated in this catch-up
+        count: u64,
+        /// Frames duplicated since the recording started
+        total: u64,
+    },
+    /// AcquireNextFrame keeps timing out without delivering a new frame
+    AcquireTimeouts {
+        /// Position in the recording when the threshold was reached
+        timestamp: Duration,
+        /// Consecutive timeouts so far
+        consecutive: u32,
+    },
+    /// The processing and encoding thread is falling behind the capture
+    EncoderBackpressure {
+        /// Timestamp of the frame being written
+        timestamp: Duration,
+        /// How far behind real time the frame is being written
+        lag: Duration,
+        /// Frames written since the recording started
+        frames_written: u64,
+    },
+    /// The bitrate the encoder produced over the last 10 seconds is far from `video_bitrate`
+    /// Emitted once when leaving the tolerated range (50%..150% of the target)
+    BitrateOutOfRange {
+        /// Position in the recording when the deviation was measured
+        timestamp: Duration,
+        deviation: BitrateDeviation,
+        /// Measured bitrate in bits per second
+        measured: u64,
+        /// Configured bitrate in bits per second
+        target: u64,
+    },
+    /// Periodic pipeline timings, emitted when `profiling` is enabled
+    PipelineProfile { report: ProfileReport },
+    /// The default audio endpoint changed (e.g. a headset was unplugged) and capture switched to
+    /// the new one; the gap was filled with silence
+    DeviceChanged {
+        source: ThreadSource,
+        /// Friendly name of the new endpoint, None if unknown
+        device_name: Option<String>,
+        /// Position in the recording where the new endpoint took over
+        timestamp: Duration,
+    },
+    /// System audio capture started with `mode`, either `LoopbackMode::Process` or
+    /// `LoopbackMode::Desktop`
+    AudioLoopbackSelected {
+        mode: LoopbackMode,
+        /// Why process loopback could not be activated when `Auto` fell back to desktop audio
+        fallback_reason: Option<String>,
+    },
+    /// The first non-blank frame arrived and writing began (`start_gate`)
+    CaptureStarted {
+        /// Time from the start of capture to that frame; video and audio before it were dropped
+        skipped: Duration,
+        /// Blank frames dropped before it
+        frames_skipped: u64,
+        /// QPC the frame was captured at, where both video and audio now start
+        start_qpc: u64,
+    },
+    /// The target switched between windowed, borderless and exclusive fullscreen; the recorded
+    /// region follows (the window while windowed, its whole monitor while fullscreen)
+    DisplayModeChanged {
+        previous: DisplayMode,
+        mode: DisplayMode,
+        /// Position in the recording where the switch was seen
+        timestamp: Duration,
+    },
+    /// The resolution of the captured monitor changed (e.g. a game switched display modes);
+    /// frames are scaled into the unchanged output size from here on
+    CaptureResolutionChanged {
+        previous: (u32, u32),
+        size: (u32, u32),
+        /// Position in the recording where the new resolution took over
+        timestamp: Duration,
+    },
+    /// The target window moved to a monitor of another GPU (e.g. on a hybrid-graphics laptop)
+    /// or the device was lost, and capture continues on a new device for its adapter
+    AdapterChanged {
+        /// Description of the adapter now captured from
+        adapter: String,
+        /// Position in the recording where capture resumed on the new device
+        timestamp: Duration,
+    },
+    /// A shared preview texture was created (`shared_preview_texture`), at the first frame or
+    /// after the capture moved to another device; get it with `Recorder::shared_preview_texture`.
+    /// A previous texture is no longer updated
+    SharedPreviewTextureChanged {
+        /// Position in the recording of the first frame in it
+        timestamp: Duration,
+    },
+    /// The target window was closed or its handle became invalid; the capture looks for it
+    /// again according to `window_reacquire`
+    WindowLost {
+        /// Position in the recording where the window was lost
+        timestamp: Duration,
+    },
+    /// A lost target window was found again and capture continues on it
+    WindowReacquired {
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hwnd"))]
+        hwnd: HWND,
+        title: String,
+        /// How long the window was gone
+        lost_for: Duration,
+    },
+    /// A lost target window did not come back within the `window_reacquire` timeout; with
+    /// `ReacquireTimeout::StopRecording` the recording is being stopped
+    WindowReacquireTimedOut { timeout: Duration },
+    /// `pause_recording` took effect; samples are dropped until `resume_recording`
+    Paused {
+        /// Position in the recording where it was paused
+        timestamp: Duration,
+    },
+    /// `resume_recording` took effect; the file continues where it was paused, without a gap
+    Resumed {
+        /// Position in the recording where it continues
+        timestamp: Duration,
+        paused_for: Duration,
+    },
+    /// A replay being saved in the background has written another tenth of its samples
+    ReplaySaveProgress {
+        output_path: PathBuf,
+        /// Video frames and audio samples written so far
+        written: usize,
+        /// Video frames and audio samples in the replay
+        total: usize,
+    },
+    /// A replay has been written and finalized
+    ReplaySaved {
+        output_path: PathBuf,
+        /// Length of the saved clip
+        duration: Duration,
+        /// Time spent encoding and writing the file
+        elapsed: Duration,
+    },
+    /// Saving a replay failed; the file may be incomplete
+    ReplaySaveFailed {
+        output_path: PathBuf,
+        message: String,
+    },
+    /// Periodic counters, emitted every `stats_interval`
+    Stats {
+        /// Position in the recording
+        timestamp: Duration,
+        /// Frames with new content copied by the capture
+        frames_captured: u64,
+        /// Frames repeated to keep the frame rate
+        frames_duplicated: u64,
+        /// Frames dropped before encoding
+        frames_dropped: u64,
+        /// Frames handed to the encoder
+        frames_written: u64,
+        /// Bytes the encoder produced so far, None if it does not report them
+        bytes_written: Option<u64>,
+    },
+    /// A recorder thread failed; the recording is likely broken from this point on
+    ThreadFailed {
+        thread: ThreadSource,
+        message: String,
+    },
+}
+
+#[cfg(feature = "serde")]
+fn serialize_hwnd<S: serde::Serializer>(
+    hwnd: &HWND,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_i64(hwnd.0 as i64)
+}
+
+/// Dispatches events to the user callback, if any
+#[derive(Clone, Default)]
+pub(crate) struct EventEmitter {
+    callback: Option<EventCallback>,
+}
+
+impl EventEmitter {
+    pub fn new(callback: Option<EventCallback>) -> Self {
+        Self { callback }
+    }
+
+    pub fn emit(&self, event: RecorderEvent) {
+        trace!("Emitting recorder event: {:?}", event);
+        if let Some(callback) = &self.callback {
+            // A panicking callback must not take down a recorder thread
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(&event)));
+            if result.is_err() {
+                error!("Recorder event callback panicked while handling {:?}", event);
+            }
+        }
+    }
+}
@@ -1,28 +1,99 @@
-use log::{debug, error, info, warn};
+use crate::logging::{debug, error, info, warn};
 use windows::Win32::Graphics::Dxgi::{CreateDXGIFactory1, IDXGIAdapter, IDXGIFactory1, DXGI_OUTPUT_DESC};
 use windows::Win32::Graphics::Gdi::{MonitorFromWindow, MONITOR_DEFAULTTONEAREST};
 use windows::Win32::UI::WindowsAndMessaging::GetWindowRect;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::Arc;
 use std::sync::Barrier;
+use std::sync::Mutex;
 use std::sync::RwLock;
 use std::thread::JoinHandle;
-use windows::core::{ComInterface, Result};
-use windows::Win32::Foundation::{HWND, RECT, POINT};
+use std::time::Duration;
+use windows::core::{ComInterface, Result, HSTRING};
+use windows::Win32::Foundation::{E_INVALIDARG, HWND, RECT, POINT};
 use windows::Win32::Graphics::Direct3D::*;
 use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
 use windows::Win32::System::Performance::QueryPerformanceCounter;
-
-use super::config::RecorderConfig;
-use crate::capture::window::get_window_rect;
+use windows::Win32::System::SystemInformation::GetSystemTime;
+
+use super::config::{AudioSource, LoopbackMode, RecorderConfig};
+use super::errors::{spawn_reporting, ErrorCollector};
+use super::events::{EventEmitter, RecorderEvent, StopStage};
+use super::markers::{write_chapters_sidecar, FrameTag, Marker};
+use super::metadata::{write_metadata_sidecar, SessionInfo, SessionOutcome};
+use super::prepare::PreparedSession;
+use super::replay_save::{ReplaySaveHandle, ReplaySaver};
+use super::rollback::PartialStart;
+use super::stop::stop_and_notify;
+use crate::capture::window::{
+    exclude_window_from_capture, get_process_exe_name, get_window_process_id, get_capture_region,
+    get_window_title,
+    restore_window_display_affinity, WindowTarget,
+};
+use crate::capture::scene::{compose_scene, SceneCaptureContext};
+use crate::capture::source::{pull_frames, FrameInjector, InjectorContext, VideoSource};
 use crate::capture::{
-    collect_audio, collect_microphone, get_frames, get_window_by_exact_string, get_window_by_string,
+    adapter_luid, capture_origin, collect_audio, collect_microphone, get_frames, spawn_input_hooks,
+    window_adapter_luid, AudioCaptureContext, AudioPacket, InputEvent, WindowCaptureContext,
+};
+use crate::device::capabilities::{
+    encoder_accepts_rgb_input, query_encoder_capabilities, RateControlMode, VideoProfile,
+};
+use crate::device::{get_audio_input_device_by_name, VideoEncoderType};
+use crate::error::{ConfigIssue, RecorderError, StartStage, ThreadSource};
+use crate::processing::encoder::{self, EncoderStats, StreamHeader};
+use crate::processing::profiler::{PipelineProfiler, ProfileReport};
+use crate::processing::audio::{
+    mixer_controls, AudioLevels, MixerControls, MixerStats, SourceControl,
 };
-use crate::device::get_audio_input_device_by_name;
-use crate::error::RecorderError;
-use crate::processing::{media, process_samples};
-use crate::types::{ReplayBuffer, SendableSample, SendableWriter};
+use crate::types::frame_queue::FrameQueueStats;
+use crate::types::memory::MemoryUsage;
+use crate::types::texture_pool::PoolStats;
+use crate::types::WindowGeometry;
+use crate::processing::dump::{CaptureDumpWriter, DumpHeader};
+use crate::processing::filter::VideoFilterChain;
+use crate::processing::high_fps::OutputDecimator;
+use crate::processing::idle::{spawn_idle_monitor, IdleAction, IdleMonitorContext, IdleThrottle};
+use crate::processing::image::{
+    self, ImageSequenceWriter, Nv12Reader, OutputMode, ThumbnailCapture,
+};
+use crate::processing::mask::MaskRect;
+use crate::processing::media::{QualityProfile, SinkWriterSettings, VideoEncoding, LOSSLESS_QP};
+use crate::processing::preview::{
+    PreviewSubscribers, PreviewTap, SharedPreviewTexture, SharedPreviewWriter,
+};
+use crate::processing::replay::ReplayBuffer;
+use crate::processing::segment::SegmentOutput;
+use crate::processing::tags::{FrameTagger, PendingTag};
+use crate::processing::video::{self, PixelPipeline};
+use crate::processing::{media, process_samples, ProcessingContext};
+use crate::types::{duration_to_hns, frame_queue, FrameCounters, SendableSample, SendableWriter};
+
+/// Longest the supervisor sleeps before checking whether the recording ended
+const SUPERVISOR_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often the supervisor checks the free space on the output volume
+const DISK_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Conditions on which the supervisor thread stops a recording
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StopConditions {
+    pub max_duration: Option<Duration>,
+    /// Stop when the output volume has fewer free bytes than this
+    pub min_free_space: Option<u64>,
+    /// Stop when the capture thread gave up on a window lost for this long
+    pub window_reacquire_timeout: Option<Duration>,
+}
+
+impl StopConditions {
+    pub fn is_empty(&self) -> bool {
+        self.max_duration.is_none()
+            && self.min_free_space.is_none()
+            && self.window_reacquire_timeout.is_none()
+    }
+}
 
 pub struct RecorderInner {
     recording: Arc<AtomicBool>,
@@ -31,57 +102,133 @@ pub struct RecorderInner {
     collect_audio_handle: RwLock<Option<JoinHandle<Result<()>>>>,
     collect_microphone_handle: RwLock<Option<JoinHandle<Result<()>>>>,
     replay_buffer: RwLock<Option<Arc<ReplayBuffer>>>,
+    /// None when the recording writes an image sequence instead of a video file
+    writer: Option<SendableWriter>,
+    video_encoder_type: VideoEncoderType,
+    audio_levels: Arc<Mutex<AudioLevels>>,
+    mixer_stats: Arc<Mutex<MixerStats>>,
+    pool_stats: Arc<Mutex<PoolStats>>,
+    queue_stats: Arc<Mutex<FrameQueueStats>>,
+    memory_usage: Arc<Mutex<MemoryUsage>>,
+    shared_preview: Arc<Mutex<Option<SharedPreviewTexture>>>,
+    excluded_windows: Mutex<Vec<(HWND, u32)>>,
+    start_time: std::time::Instant,
+    markers: Arc<Mutex<Vec<Marker>>>,
+    /// Where `tag_frame` sends tags for the processing thread to place on the video timeline
+    tag_sender: Mutex<Sender<PendingTag>>,
+    frame_tags: Arc<Mutex<Vec<FrameTag>>>,
+    events: EventEmitter,
+    errors: ErrorCollector,
+    profiler: Option<Arc<PipelineProfiler>>,
+    thumbnail: Option<Arc<ThumbnailCapture>>,
+    counters: Arc<FrameCounters>,
+    session: SessionInfo,
+    segment_output: Arc<Mutex<Option<SegmentOutput>>>,
+    privacy_masks: Arc<Mutex<Vec<MaskRect>>>,
+    frame_injector: Option<Arc<FrameInjector>>,
+    /// Set by the capture thread when the target window did not come back in time
+    window_gone: Arc<AtomicBool>,
+    /// Read by the processing thread, which drops samples while it is set
+    paused: Arc<AtomicBool>,
+    /// Shift applied to audio timestamps by the processing thread, in 100ns units
+    av_sync_offset: Arc<AtomicI64>,
+    /// Gain and mute flag of each mixer source, read by the processing thread
+    mixer_controls: MixerControls,
+    /// How frames reach the encoder, which later writers of buffered frames must match
+    pixel_pipeline: PixelPipeline,
+    /// Profile and rate control of the video, checked against the encoder once at start
+    video_encoding: VideoEncoding,
+    /// Adapter the video encoder was looked up on, also used by later writers
+    encoder_adapter: Option<u64>,
+    /// Started by the first replay save
+    replay_saver: Mutex<Option<ReplaySaver>>,
     config: RecorderConfig,
 }
 
 impl RecorderInner {
-    pub fn init(config: &RecorderConfig, process_name: &str) -> Result<Self> {
-        info!("Starting init() with process: {}", process_name);
-        // By default, use substring matching
-        Self::init_with_exact_match(config, process_name, false)
+    /// Record `hwnd`, a window already picked among the matches of `target`, reusing what
+    /// `prepared` set up ahead of time where it still fits
+    ///
+    /// If a step fails, whatever was set up before it is torn down again and the error names
+    /// the step.
+    pub fn init_with_window(
+        config: &RecorderConfig,
+        target: &WindowTarget,
+        hwnd: HWND,
+        excluded_windows: &[HWND],
+        prepared: Option<&PreparedSession>,
+        preview: &PreviewSubscribers,
+    ) -> std::result::Result<Self, RecorderError> {
+        let mut started = PartialStart::new();
+        Self::start_with_window(
+            config,
+            target,
+            hwnd,
+            excluded_windows,
+            prepared,
+            preview,
+            &mut started,
+        )
+        .map_err(|e| started.rollback(e.into()))
     }
 
-    pub fn init_with_exact_match(
+    fn start_with_window(
         config: &RecorderConfig,
-        process_name: &str,
-        use_exact_match: bool,
+        target: &WindowTarget,
+        hwnd: HWND,
+        excluded_windows: &[HWND],
+        prepared: Option<&PreparedSession>,
+        preview: &PreviewSubscribers,
+        started: &mut PartialStart,
     ) -> Result<Self> {
-        info!(
-            "Initializing recorder for process: {} with exact match: {}",
-            process_name, use_exact_match
-        );
-        // Find target window
-        info!(
-            "Finding target window with name: {} (exact match: {})",
-            process_name, use_exact_match
-        );
-        let hwnd = if use_exact_match {
-            info!("Using exact string matching for window");
-            get_window_by_exact_string(process_name)
-        } else {
-            info!("Using substring matching for window");
-            get_window_by_string(process_name)
-        }
-        .ok_or_else(|| RecorderError::FailedToStart("No window found".to_string()))?;
         info!("Found window with handle: {:?}", hwnd);
 
+        // The capture thread relocates the window through the target if its handle becomes
+        // invalid; for process and handle targets that is the exact title of the resolved window
+        let (process_name, relocate) = match target {
+            WindowTarget::Title { title, .. } => (title.clone(), target.clone()),
+            WindowTarget::Executable(executable) => (executable.clone(), target.clone()),
+            WindowTarget::ProcessId(_) | WindowTarget::Handle(_) => {
+                let title = get_window_title(hwnd);
+                (
+                    title.clone(),
+                    WindowTarget::Title {
+                        title,
+                        exact_match: true,
+                    },
+                )
+            }
+        };
+        let process_name = process_name.as_str();
+
         // Determine input resolution (auto-detect if not specified)
-        let (actual_input_width, actual_input_height) =
-            match (config.input_width(), config.input_height()) {
-                (Some(width), Some(height)) => {
-                    info!(
-                        "Using user-specified input dimensions: {}x{}",
-                        width, height
-                    );
-                    (width, height)
-                }
-                _ => {
-                    info!("Input dimensions not specified, auto-detecting from monitor");
-                    let (width, height) = crate::capture::get_window_monitor_resolution(hwnd);
-                    info!("Auto-detected monitor resolution: {}x{}", width, height);
-                    (width, height)
-                }
-            };
+        let (actual_input_width, actual_input_height) = match (
+            config.input_width(),
+            config.input_height(),
+            config.video_source(),
+        ) {
+            (_, _, VideoSource::Scene(scene)) => {
+                info!(
+                    "Using the scene canvas as input dimensions: {}x{}",
+                    scene.width(),
+                    scene.height()
+                );
+                (scene.width(), scene.height())
+            }
+            (Some(width), Some(height), _) => {
+                info!(
+                    "Using user-specified input dimensions: {}x{}",
+                    width, height
+                );
+                (width, height)
+            }
+            _ => {
+                info!("Input dimensions not specified, auto-detecting from monitor");
+                let (width, height) = crate::capture::get_window_monitor_resolution(hwnd);
+                info!("Auto-detected monitor resolution: {}x{}", width, height);
+                (width, height)
+            }
+        };
 
         info!(
             "Config details - fps: {}/{}, input: {}x{}, output: {}x{}, audio: {}, mic: {}",
@@ -98,79 +245,29 @@ impl RecorderInner {
         // Clone the necessary values from config at the start
         let fps_num = config.fps_num();
         let fps_den = config.fps_den();
+        // High fps capture runs the capture faster than the file's frame rate
+        let (capture_fps_num, capture_fps_den) = config
+            .high_fps_capture()
+            .map_or((fps_num, fps_den), |high_fps| (high_fps.capture_fps, 1));
         let input_width = actual_input_width;
         let input_height = actual_input_height;
         let output_width = config.output_width();
         let output_height = config.output_height();
         let capture_audio = config.capture_audio();
         let capture_microphone = config.capture_microphone();
-        let video_bitrate = config.video_bitrate();
-        let system_volume = config.system_volume();
-        let microphone_volume = config.microphone_volume();
+        // Captured processes are mixed as separate sources unless forced to desktop audio
+        let per_process_audio = matches!(config.audio_source(), AudioSource::Processes(_))
+            && config.loopback_mode() != LoopbackMode::Desktop;
         info!("Config values cloned successfully");
 
-        info!("Checking for microphone device configuration");
-        let microphone_device = if let Some(device_name) = config.microphone_device() {
-            info!(
-                "Attempting to get device ID for microphone: '{}'",
-                device_name
-            );
-            match get_audio_input_device_by_name(Some(device_name)) {
-                Ok(device_id) => {
-                    info!("Found device ID for '{}': {}", device_name, device_id);
-                    Some(device_id)
-                }
-                Err(e) => {
-                    info!(
-                        "Could not get device ID for '{}', using default: {:?}",
-                        device_name, e
-                    );
-                    None
-                }
-            }
-        } else {
-            info!("No microphone device specified, using system default");
-            None
-        };
+        let (microphone_device, extra_microphones) = resolve_microphones(config);
 
-        // Create replay buffer if enabled
-        info!("Checking if replay buffer is enabled");
-        let replay_buffer = if config.enable_replay_buffer() {
-            info!(
-                "Replay buffer is enabled with {} seconds",
-                config.replay_buffer_seconds()
-            );
-            let buffer_duration =
-                std::time::Duration::from_secs(config.replay_buffer_seconds() as u64);
-            let fps = fps_num as f64 / fps_den as f64;
-
-            // Estimate the number of frames and audio samples in the buffer
-            let video_frames = (fps * buffer_duration.as_secs_f64()) as usize;
-            let audio_samples = if capture_audio || capture_microphone {
-                // Audio at 44.1kHz, assume ~10 packets per second for buffer capacity
-                (10.0 * buffer_duration.as_secs_f64()) as usize
-            } else {
-                0
-            };
+        // Filled in by the texture pools and the replay buffer as they allocate
+        let memory_usage = Arc::new(Mutex::new(MemoryUsage::default()));
+        // Filled in by the processing thread once the first frame is copied into the texture
+        let shared_preview = Arc::new(Mutex::new(None));
 
-            info!(
-                "Creating replay buffer for {} seconds ({} video frames, {} audio samples)",
-                config.replay_buffer_seconds(),
-                video_frames,
-                audio_samples
-            );
-
-            let buffer = Some(Arc::new(ReplayBuffer::new(
-                buffer_duration,
-                video_frames,
-                audio_samples,
-            )));
-            info!("Replay buffer created successfully");
-            buffer
-        } else {
-            info!("Replay buffer is disabled");
-            None
-        };
+        let replay_buffer = create_replay_buffer(config, &memory_usage);
 
         // Parse out path string from PathBuf
         info!("Getting output path string");
@@ -180,58 +277,119 @@ impl RecorderInner {
             .ok_or_else(|| RecorderError::FailedToStart("Invalid path string".to_string()))?;
         info!("Output path resolved to: {}", output_path);
 
+        // Hide the host application's own windows from the capture
+        let mut excluded_affinities = Vec::new();
+        for &excluded in excluded_windows {
+            match exclude_window_from_capture(excluded) {
+                Ok(previous) => excluded_affinities.push((excluded, previous)),
+                Err(e) => warn!("Failed to exclude window {:?} from capture: {:?}", excluded, e),
+            }
+        }
+        started.excluded_windows = excluded_affinities.clone();
+
         let recording = Arc::new(AtomicBool::new(true));
-        let mut collect_video_handle: Option<JoinHandle<Result<()>>> = None;
-        let mut process_handle: Option<JoinHandle<Result<()>>> = None;
-        let mut collect_audio_handle: Option<JoinHandle<Result<()>>> = None;
-        let mut collect_microphone_handle: Option<JoinHandle<Result<()>>> = None;
+        started.recording = Some(recording.clone());
+        let start_time = std::time::Instant::now();
+        let audio_levels = Arc::new(Mutex::new(AudioLevels::default()));
+        let mixer_stats = Arc::new(Mutex::new(MixerStats::default()));
+        let pool_sizes = config.buffer_pool_sizes();
+        let pool_stats = Arc::new(Mutex::new(PoolStats {
+            conversion_textures: pool_sizes.conversion_textures,
+            ..Default::default()
+        }));
+        let queue_stats = Arc::new(Mutex::new(FrameQueueStats::default()));
+        // Cleared by the microphone thread while it waits for a hot-plugged microphone
+        let microphone_attached = Arc::new(AtomicBool::new(true));
+        let segment_output: Arc<Mutex<Option<SegmentOutput>>> = Arc::new(Mutex::new(None));
+        let privacy_masks = Arc::new(Mutex::new(config.privacy_masks().to_vec()));
+        let window_gone = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
+        let av_sync_offset = Arc::new(AtomicI64::new(config.av_sync_offset_ms() * 10_000));
+        let extra_microphone_names: Vec<String> = extra_microphones
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mixer_controls = mixer_controls(
+            capture_audio,
+            capture_microphone,
+            config.system_volume(),
+            config.microphone_volume(),
+            &extra_microphone_names,
+        );
+        let events = EventEmitter::new(config.event_callback().cloned());
+        let errors = ErrorCollector::new(events.clone());
+        let counters = Arc::new(FrameCounters::default());
+        let started_at = unsafe { GetSystemTime() };
+        let thumbnail = config
+            .thumbnail()
+            .map(|spec| Arc::new(ThumbnailCapture::new(*spec)));
+        let markers = Arc::new(Mutex::new(Vec::new()));
+        let frame_tags = Arc::new(Mutex::new(Vec::new()));
+        let (tag_sender, tag_receiver) = channel::<PendingTag>();
+        let idle_throttle = match config.idle_detection().map(|policy| policy.action) {
+            Some(IdleAction::ReduceFrameRate { fps }) => Some(Arc::new(IdleThrottle::new(fps))),
+            _ => None,
+        };
+        let mut frame_injector: Option<Arc<FrameInjector>> = None;
+        let writer: Option<SendableWriter>;
+        let image_sequence: Option<ImageSequenceWriter>;
+        let high_fps_output: Option<SegmentOutput>;
+        let video_encoder_type: VideoEncoderType;
+        let profiler: Option<Arc<PipelineProfiler>>;
+        let encoder_name: String;
+        let pixel_pipeline: PixelPipeline;
+        let video_encoding: VideoEncoding;
+        let encoder_adapter: Option<u64>;
 
         unsafe {
             // Initialize Media Foundation
+            started.stage = StartStage::MediaFoundation;
             info!("Initializing Media Foundation");
             media::init_media_foundation()?;
+            started.media_foundation = true;
             info!("Media Foundation initialized successfully");
 
             // Get the video encoder
+            started.stage = StartStage::Encoder;
             info!("Getting video encoder");
-            let video_encoder = if let Some(encoder_name) = config.video_encoder_name() {
-                info!("Looking for encoder by name: '{}'", encoder_name);
-                match crate::device::get_video_encoder_by_name(encoder_name) {
+            encoder_adapter = target_encoder_adapter(config, hwnd);
+            let video_encoder =
+                match prepared.and_then(|session| session.video_encoder(encoder_adapter)) {
                     Some(encoder) => {
-                        info!(
-                            "Found encoder by name: '{}' ({:?})",
-                            encoder_name, encoder.encoder_type
-                        );
-                        encoder
-                    }
-                    None => {
-                        info!("Encoder '{}' not found, falling back to type", encoder_name);
-                        crate::device::get_video_encoder_by_type(*config.video_encoder())?
+                        info!("Using the prepared video encoder");
+                        encoder.clone()
                     }
-                }
-            } else {
-                info!("Looking for encoder by type: {:?}", config.video_encoder());
-                crate::device::get_video_encoder_by_type(*config.video_encoder())?
-            };
+                    None => resolve_video_encoder(config, encoder_adapter)?,
+                };
             info!(
-                "Video encoder obtained: {} ({:?})",
-                video_encoder.name, video_encoder.encoder_type
+                "Video encoder obtained: {} ({:?}, adapter: {:?})",
+                video_encoder.name, video_encoder.encoder_type, video_encoder.adapter_luid
             );
-
-            // Create and configure media sink
-            info!("Creating media sink writer for path: {}", output_path);
-            let media_sink = media::create_sink_writer(
-                output_path,
-                fps_num,
-                fps_den,
-                output_width,
-                output_height,
-                capture_audio,
-                capture_microphone,
-                video_bitrate,
-                &video_encoder.output_format_guid, // Use output_format_guid instead of id
-            )?;
-            info!("Media sink writer created successfully");
+            pixel_pipeline =
+                resolve_pixel_pipeline(config, (input_width, input_height), &video_encoder);
+            video_encoding = resolve_video_encoding(config, &video_encoder, pixel_pipeline)?;
+            let sink_settings =
+                sink_writer_settings(config, &video_encoder, pixel_pipeline, video_encoding);
+
+            // Create and configure media sink, or the image writer replacing it
+            started.stage = StartStage::SinkWriter;
+            image_sequence = create_image_sequence(config)?;
+            let media_sink = if image_sequence.is_some() {
+                info!("Writing an image sequence, no media sink needed");
+                None
+            } else {
+                if !std::path::Path::new(output_path).exists() {
+                    started.files.push(output_path.into());
+                }
+                info!("Creating media sink writer for path: {}", output_path);
+                let media_sink = media::create_sink_writer(
+                    output_path,
+                    &sink_settings,
+                    config.encoded_frame_callback().cloned(),
+                )?;
+                info!("Media sink writer created successfully");
+                Some(media_sink)
+            };
 
             // Window handle already found at the beginning
 
@@ -245,30 +403,79 @@ impl RecorderInner {
             info!("Process ID: {}", process_id);
 
             // Initialize recording
-            info!("Beginning writing to media sink");
-            media_sink.BeginWriting()?;
-            info!("BeginWriting successful");
-            let sendable_sink = SendableWriter(Arc::new(media_sink));
+            let sendable_sink = match media_sink {
+                Some(media_sink) => {
+                    info!("Beginning writing to media sink");
+                    media_sink.BeginWriting()?;
+                    info!("BeginWriting successful");
+                    Some(SendableWriter(Arc::new(media_sink)))
+                }
+                None => None,
+            };
+            started.writer = sendable_sink.clone();
             info!("SendableWriter created");
+            writer = sendable_sink.clone();
+            video_encoder_type = video_encoder.encoder_type;
+            encoder_name = video_encoder.name.clone();
+
+            high_fps_output = create_high_fps_output(config, &sink_settings, started)?;
+            let output_decimator = config
+                .high_fps_capture()
+                .map(|high_fps| OutputDecimator::new(fps_num, fps_den, high_fps.capture_fps));
+            let preview_tap = PreviewTap::new(config.preview(), preview.clone());
+            let shared_preview_writer = config
+                .shared_preview_texture()
+                .then(|| SharedPreviewWriter::new(shared_preview.clone()));
+            let video_filters = (!config.video_filters().is_empty())
+                .then(|| VideoFilterChain::new(config.video_filters().clone()));
 
             // Set up channels
             info!("Setting up communication channels");
-            let (sender_video, receiver_video) = channel::<SendableSample>();
-            info!("Video channel created");
+            let (sender_video, receiver_video) = frame_queue(
+                config.frame_queue_capacity(),
+                config.backpressure_policy(),
+                queue_stats.clone(),
+                counters.clone(),
+            );
+            info!(
+                "Video queue created ({} frames, {:?} when full)",
+                config.frame_queue_capacity(),
+                config.backpressure_policy()
+            );
             let (sender_audio, receiver_audio) = channel::<SendableSample>();
             info!("Audio channel created");
             let (sender_microphone, receiver_microphone) = channel::<SendableSample>();
             info!("Microphone channel created");
+            let (sender_process_audio, receiver_process_audio) = channel::<AudioPacket>();
+            let mut extra_microphone_senders = Vec::new();
+            let mut extra_microphone_receivers = Vec::new();
+            for (name, device_id) in extra_microphones {
+                let (sender, receiver) = channel::<SendableSample>();
+                extra_microphone_senders.push((name.clone(), device_id, sender));
+                extra_microphone_receivers.push((name, receiver));
+            }
 
             // Get initial window position and size before creating channels
             info!("Getting initial window position and size");
             let initial_window_position: Option<(i32, i32)>;
             let initial_window_size: Option<(u32, u32)>;
+            // Rounded corners the processing thread masks, kept up to date by the capture
+            let corner_radii = Arc::new(Mutex::new((0, 0)));
 
-            if let Some((x, y, width, height)) = get_window_rect(hwnd) {
+            if !matches!(config.video_source(), VideoSource::Duplication) {
+                // Application frames are recorded whole; the injector reports their size
+                initial_window_position = None;
+                initial_window_size = None;
+            } else if let Some(region) = get_capture_region(
+                hwnd,
+                config.capture_client_area_only(),
+                config.window_corners(),
+            ) {
+                *corner_radii.lock().unwrap() = region.corner_radii;
+                let (x, y, width, height) = region.rect;
                 info!(
-                    "Initial window rect - Position: [{}, {}], Size: {}x{}",
-                    x, y, width, height
+                    "Initial window rect ({:?}) - Position: [{}, {}], Size: {}x{}",
+                    region.display_mode, x, y, width, height
                 );
                 initial_window_position = Some((x, y));
                 initial_window_size = Some((width, height));
@@ -278,22 +485,41 @@ impl RecorderInner {
                 initial_window_size = None;
             }
 
-            let (sender_window_info, receiver_window_info) =
-                channel::<(Option<(i32, i32)>, Option<(u32, u32)>)>();
+            let (sender_window_info, receiver_window_info) = channel::<WindowGeometry>();
             info!(
                 "Window info channel created with initial position: {:?}, initial size: {:?}",
                 initial_window_position, initial_window_size
             );
 
-            // Create D3D11 device and context specifically for the window's adapter
+            // Create D3D11 device and context specifically for the window's adapter, unless the
+            // caller supplied one
+            started.stage = StartStage::Device;
             info!("Creating D3D11 device and context for the window's adapter");
-            let (device, context) = create_d3d11_device_for_window(hwnd)?;
+            let (device, context) = match prepared.and_then(|session| session.device_for(hwnd)) {
+                Some(prepared_device) => {
+                    info!("Using the prepared D3D11 device");
+                    prepared_device
+                }
+                None => recording_device(&config, hwnd)?,
+            };
             info!("D3D11 device and context created for window's adapter");
             let device = Arc::new(device);
             info!("D3D11 device wrapped in Arc");
+
+            // Window rects are desktop coordinates; the captured frame starts at the corner of
+            // the duplicated output
+            let frame_origin = capture_origin(&device, hwnd);
+            let initial_window_position = initial_window_position
+                .map(|(x, y)| (x - frame_origin.0, y - frame_origin.1));
             let context_mutex = Arc::new(std::sync::Mutex::new(context));
             info!("D3D11 context wrapped in mutex");
 
+            profiler = if config.profiling() {
+                Some(Arc::new(PipelineProfiler::new(&device)?))
+            } else {
+                None
+            };
+
             // Set up synchronization barrier
             // Always include video thread (1) plus audio if enabled
             // The microphone thread will wait on the barrier even if it fails,
@@ -301,7 +527,8 @@ impl RecorderInner {
             let barrier_count =
                 1 + // Video thread always included
                 (if capture_audio { 1 } else { 0 }) + 
-                (if capture_microphone { 1 } else { 0 });
+                (if capture_microphone { 1 } else { 0 }) +
+                extra_microphone_senders.len();
                 
             info!(
                 "Creating synchronization barrier with {} threads",
@@ -311,6 +538,7 @@ impl RecorderInner {
             info!("Synchronization barrier created");
 
             // Start video capture thread
+            started.stage = StartStage::Capture;
             info!("Starting video capture thread");
             let rec_clone = recording.clone();
             let dev_clone = device.clone();
@@ -318,30 +546,111 @@ impl RecorderInner {
             let process_name_clone = process_name.to_string();
             // Copy the capture_cursor value before using it in the thread
             let capture_cursor = config.capture_cursor();
-            collect_video_handle = Some(std::thread::spawn(move || {
-                info!("Video capture thread started");
-                let result = get_frames(
-                    sender_video,
-                    rec_clone,
-                    hwnd,
-                    &process_name_clone,
-                    fps_num,
-                    fps_den,
-                    input_width,
-                    input_height,
-                    barrier_clone,
-                    dev_clone,
-                    context_mutex,
-                    use_exact_match,
-                    capture_cursor,
-                    sender_window_info,
-                );
-                info!(
-                    "Video capture thread completed with result: {:?}",
-                    result.is_ok()
-                );
-                result
-            }));
+            // A caller-supplied device is kept even if the window moves to another adapter
+            let replaceable_device = config.external_device().is_none();
+            let client_area_only = config.capture_client_area_only();
+            let window_corners = config.window_corners();
+            let capture_corner_radii = corner_radii.clone();
+            let minimized_policy = config.minimized_policy();
+            let window_reacquire = config.window_reacquire();
+            let capture_window_gone = window_gone.clone();
+            let capture_events = events.clone();
+            let capture_timing = config.capture_timing();
+            let vsync_pacing = config.vsync_pacing();
+            let capture_profiler = profiler.clone();
+            let capture_counters = counters.clone();
+            let capture_pool_stats = pool_stats.clone();
+            let capture_memory_usage = memory_usage.clone();
+            match config.video_source() {
+                VideoSource::Duplication => {
+                    started.video_handle = Some(spawn_reporting(ThreadSource::Video, errors.clone(), move || {
+                        info!("Video capture thread started");
+                        let result = get_frames(
+                            sender_video,
+                            WindowCaptureContext {
+                                recording: rec_clone,
+                                hwnd,
+                                process_name: process_name_clone,
+                                fps_num: capture_fps_num,
+                                fps_den: capture_fps_den,
+                                input_width,
+                                input_height,
+                                started: barrier_clone,
+                                device: dev_clone,
+                                context_mutex,
+                                replaceable_device,
+                                relocate,
+                                capture_cursor,
+                                client_area_only,
+                                corners: window_corners,
+                                corner_radii: capture_corner_radii,
+                                minimized_policy,
+                                reacquire: window_reacquire,
+                                window_gone: capture_window_gone,
+                                window_info_sender: sender_window_info,
+                                events: capture_events,
+                                timing: capture_timing,
+                                vsync_pacing,
+                                profiler: capture_profiler,
+                                counters: capture_counters,
+                                pool_sizes,
+                                pool_stats: capture_pool_stats,
+                                memory_usage: capture_memory_usage,
+                            },
+                        );
+                        info!(
+                            "Video capture thread completed with result: {:?}",
+                            result.is_ok()
+                        );
+                        result
+                    }));
+                }
+                source => {
+                    let scene_device = dev_clone.clone();
+                    let scene_context = context_mutex.clone();
+                    let injector = Arc::new(FrameInjector::new(InjectorContext {
+                        device: dev_clone,
+                        context_mutex,
+                        input_width,
+                        input_height,
+                        fps_num: capture_fps_num,
+                        fps_den: capture_fps_den,
+                        send: sender_video,
+                        window_info_sender: sender_window_info,
+                        counters: capture_counters,
+                        pool_sizes,
+                        pool_stats: capture_pool_stats,
+                        memory_usage: capture_memory_usage,
+                    })?);
+                    let source = source.clone();
+                    let thread_injector = injector.clone();
+                    started.video_handle = Some(spawn_reporting(ThreadSource::Video, errors.clone(), move || {
+                        match source {
+                            VideoSource::Custom(source) => pull_frames(source, thread_injector, rec_clone, barrier_clone),
+                            VideoSource::Scene(scene) => compose_scene(
+                                scene,
+                                SceneCaptureContext {
+                                    device: scene_device,
+                                    context_mutex: scene_context,
+                                    injector: thread_injector,
+                                    fps_num: capture_fps_num,
+                                    fps_den: capture_fps_den,
+                                    pacing: capture_timing.pacing,
+                                    recording: rec_clone,
+                                    started: barrier_clone,
+                                },
+                            ),
+                            // Submitted frames arrive on the caller's threads; only join the
+                            // start barrier
+                            _ => {
+                                barrier_clone.wait();
+                                Ok(())
+                            }
+                        }
+                    }));
+                    frame_injector = Some(injector);
+                }
+            }
             info!("Video capture thread spawned");
 
             let mut start_qpc_i64: i64 = 0;
@@ -356,16 +665,32 @@ impl RecorderInner {
                 let rec_clone = recording.clone();
                 let barrier_clone = barrier.clone();
                 let audio_source_clone = config.audio_source().clone();
+                let loopback_mode = config.loopback_mode();
+                let audio_events = events.clone();
                 info!("Audio source: {:?}", audio_source_clone);
-                collect_audio_handle = Some(std::thread::spawn(move || {
+                started.audio_handle = Some(spawn_reporting(ThreadSource::Audio, errors.clone(), move || {
                     info!("Audio capture thread started");
+                    #[cfg(feature = "synthetic")]
+                    if let crate::AudioSource::Synthetic(tone) = audio_source_clone {
+                        return crate::capture::synthetic::generate_audio(
+                            sender_audio,
+                            rec_clone,
+                            barrier_clone,
+                            tone,
+                        );
+                    }
                     let result = collect_audio(
                         sender_audio,
-                        rec_clone,
+                        sender_process_audio,
+                        AudioCaptureContext {
+                            recording: rec_clone,
+                            started: barrier_clone,
+                            start_qpc: Some(shared_start_qpc),
+                            events: audio_events,
+                        },
                         process_id,
-                        barrier_clone,
-                        Some(shared_start_qpc),
                         &audio_source_clone,
+                        loopback_mode,
                     );
                     info!(
                         "Audio capture thread completed with result: {:?}",
@@ -384,83 +709,199 @@ impl RecorderInner {
                 let rec_clone = recording.clone();
                 let barrier_clone = barrier.clone();
                 let device_clone = microphone_device.clone();
+                let microphone_events = events.clone();
+                let microphone_hotplug = config.microphone_hotplug();
+                let compensate_latency = config.microphone_latency_compensation();
+                let attached_clone = microphone_attached.clone();
                 info!("Using microphone device: {:?}", device_clone);
-                collect_microphone_handle = Some(std::thread::spawn(move || {
-                    info!("Microphone capture thread started");
-                    let result = collect_microphone(
-                        sender_microphone,
-                        rec_clone,
-                        barrier_clone,
-                        Some(shared_start_qpc),
-                        device_clone.as_deref(),
-                    );
-                    
-                    // Check for the specific "Element not found" error (0x80070490)
-                    if let Err(e) = &result {
-                        // Convert HSTRING to String for comparison
-                        let error_message = e.code().message().to_string_lossy();
-                        if error_message.contains("Element not found") {
-                            info!("No microphone device found (Error {:X}). Recording will continue without microphone.", e.code().0);
-                            // Return Ok to allow recording to continue without microphone
-                            return Ok(());
-                        }
-                    }
-                    
-                    info!(
-                        "Microphone capture thread completed with result: {:?}",
-                        result.is_ok()
-                    );
-                    result
-                }));
+                started.microphone_handle = Some(spawn_reporting(
+                    ThreadSource::Microphone,
+                    errors.clone(),
+                    move || {
+                        info!("Microphone capture thread started");
+                        // Extra microphones are captured on threads of their own that end with this
+                        // one; losing one of them does not stop the recording
+                        std::thread::scope(|scope| {
+                            for (name, device_id, sender) in extra_microphone_senders {
+                                let capture = AudioCaptureContext {
+                                    recording: rec_clone.clone(),
+                                    started: barrier_clone.clone(),
+                                    start_qpc: Some(shared_start_qpc),
+                                    events: microphone_events.clone(),
+                                };
+                                scope.spawn(move || {
+                                    info!("Capturing microphone '{}'", name);
+                                    if let Err(e) = collect_microphone(
+                                        sender,
+                                        capture,
+                                        Some(&device_id),
+                                        false,
+                                        microphone_hotplug,
+                                        compensate_latency,
+                                        Arc::new(AtomicBool::new(true)),
+                                    ) {
+                                        warn!("Capture of microphone '{}' failed: {:?}", name, e);
+                                    }
+                                });
+                            }
+                            let result = collect_microphone(
+                                sender_microphone,
+                                AudioCaptureContext {
+                                    recording: rec_clone,
+                                    started: barrier_clone,
+                                    start_qpc: Some(shared_start_qpc),
+                                    events: microphone_events,
+                                },
+                                device_clone.as_deref(),
+                                true,
+                                microphone_hotplug,
+                                compensate_latency,
+                                attached_clone,
+                            );
+
+                            // Check for the specific "Element not found" error (0x80070490)
+                            if let Err(e) = &result {
+                                // Convert HSTRING to String for comparison
+                                let error_message = e.code().message().to_string_lossy();
+                                if error_message.contains("Element not found") {
+                                    info!("No microphone device found (Error {:X}). Recording will continue without microphone.", e.code().0);
+                                    // Return Ok to allow recording to continue without microphone
+                                    return Ok(());
+                                }
+                            }
+
+                            info!(
+                                "Microphone capture thread completed with result: {:?}",
+                                result.is_ok()
+                            );
+                            result
+                        })
+                    },
+                ));
                 info!("Microphone capture thread spawned");
             } else {
                 info!("Microphone capture disabled, skipping microphone thread");
             }
 
             // Start processing thread
+            started.stage = StartStage::Processing;
             info!("Starting sample processing thread");
-            let rec_clone = recording.clone();
-            let buffer_clone = replay_buffer.clone();
-            let initial_pos = initial_window_position;
-            let initial_size = initial_window_size;
+            let (replay_width, replay_height, _) = config.replay_output();
 
             // Create the texture pool for processing using the same dimensions as the capture
             info!("Creating texture pool for video processing");
-            let processing_texture_pool = crate::types::TexturePool::new(
+            let mut processing_texture_pool = crate::types::TexturePool::new(
                 device.clone(),
-                3,
+                0,
+                pool_sizes.conversion_textures,
                 input_width,
                 input_height,
                 windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM,
             )?;
-            let processing_texture_pool = Arc::new(processing_texture_pool);
+            if pixel_pipeline == PixelPipeline::Rgb {
+                // Frames are copied as they are rather than converted
+                processing_texture_pool.set_conversion_format(
+                    windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM,
+                );
+            }
+            processing_texture_pool.set_memory_sink(memory_usage.clone());
             info!("Created texture pool for video processing");
-            
-            let processing_texture_pool_clone = processing_texture_pool.clone();
-            
-            process_handle = Some(std::thread::spawn(move || {
-                info!("Processing thread started");
-                let result = process_samples(
-                    sendable_sink,
-                    receiver_video,
-                    receiver_audio,
-                    receiver_microphone,
-                    receiver_window_info,
-                    rec_clone,
-                    input_width,   // Capture dimensions
-                    input_height,  // Capture dimensions
-                    output_width,  // Target dimensions
-                    output_height, // Target dimensions
-                    device,
-                    capture_audio,
-                    capture_microphone,
-                    system_volume,
-                    microphone_volume,
-                    buffer_clone,
-                    initial_pos,  // Initial window position
-                    initial_size, // Initial window size
-                    processing_texture_pool_clone, // Texture pool for processing
+
+            // Clicks and keys for the input overlay come from low-level hooks on their own thread
+            let input_overlay = config.input_overlay().map(|overlay| {
+                let (sender_input, receiver_input) = channel::<InputEvent>();
+                spawn_input_hooks(
+                    sender_input,
+                    recording.clone(),
+                    overlay.show_clicks,
+                    overlay.show_keystrokes,
+                    frame_origin,
                 );
+                (overlay, receiver_input)
+            });
+
+            let capture_dump = open_capture_dump(
+                config,
+                DumpHeader {
+                    input_width,
+                    input_height,
+                    fps_num: capture_fps_num,
+                    fps_den: capture_fps_den,
+                },
+                initial_window_position,
+                initial_window_size,
+                started,
+            )?
+            .map(|dump| Arc::new(Mutex::new(dump)));
+
+            let context = ProcessingContext {
+                rec_video: receiver_video,
+                rec_audio: receiver_audio,
+                rec_microphone: receiver_microphone,
+                rec_process_audio: receiver_process_audio,
+                rec_extra_microphones: extra_microphone_receivers,
+                rec_window_info: receiver_window_info,
+                recording: recording.clone(),
+                input_width,
+                input_height,
+                output_width,
+                output_height,
+                device,
+                capture_audio,
+                per_process_audio,
+                capture_microphone,
+                microphone_attached: microphone_attached.clone(),
+                audio_start_qpc: Some(shared_start_qpc),
+                start_gate: config.start_gate(),
+                mixer_controls: mixer_controls.clone(),
+                audio_normalization: config.audio_normalization(),
+                replay_buffer: replay_buffer.clone(),
+                replay_size: Some((replay_width, replay_height)),
+                initial_window_position,
+                initial_window_size,
+                texture_pool: Arc::new(processing_texture_pool),
+                capture_dump,
+                audio_levels: audio_levels.clone(),
+                mixer_stats: mixer_stats.clone(),
+                microphone_filters: config.microphone_filters().clone(),
+                audio_filters: config.audio_filters().clone(),
+                segment_output: segment_output.clone(),
+                privacy_masks: privacy_masks.clone(),
+                corner_radii,
+                input_overlay,
+                scaling_quality: config.scaling_quality(),
+                aspect_mode: config.aspect_mode(),
+                video_transform: config.video_transform(),
+                color_space: config.color_space(),
+                pixel_pipeline,
+                debug_frame_stamp: config.debug_frame_stamp(),
+                low_latency: config.low_latency(),
+                scene_cut_threshold: config.scene_cut_keyframes(),
+                target_bitrate: config.video_bitrate(),
+                thumbnail: thumbnail.clone(),
+                image_sequence,
+                output_decimator,
+                high_fps_output,
+                preview: Some(preview_tap),
+                shared_preview: shared_preview_writer,
+                video_filters,
+                idle_throttle: idle_throttle.clone(),
+                paused: paused.clone(),
+                av_sync_offset: av_sync_offset.clone(),
+                stats_interval: config.stats_interval(),
+                frame_tagger: Some(FrameTagger::new(
+                    tag_receiver,
+                    frame_tags.clone(),
+                    markers.clone(),
+                )),
+                counters: counters.clone(),
+                profiler: profiler.clone(),
+                events: events.clone(),
+                errors: errors.clone(),
+            };
+            started.process_handle = Some(spawn_reporting(ThreadSource::Process, errors.clone(), move || {
+                info!("Processing thread started");
+                let result = process_samples(sendable_sink, context);
                 info!(
                     "Processing thread completed with result: {:?}",
                     result.is_ok()
@@ -470,19 +911,154 @@ impl RecorderInner {
             info!("Processing thread spawned");
         }
 
+        if let Some(policy) = config.idle_detection() {
+            info!("Idle detection enabled: {:?}", policy);
+            spawn_idle_monitor(
+                *policy,
+                IdleMonitorContext {
+                    recording: recording.clone(),
+                    start_time,
+                    counters: counters.clone(),
+                    audio_levels: audio_levels.clone(),
+                    throttle: idle_throttle,
+                    markers: markers.clone(),
+                    events: events.clone(),
+                },
+            );
+        }
+
         info!("All threads initialized and running");
         info!("Recorder initialized successfully");
         Ok(Self {
             recording,
-            collect_video_handle: RwLock::new(collect_video_handle),
-            process_handle: RwLock::new(process_handle),
-            collect_audio_handle: RwLock::new(collect_audio_handle),
-            collect_microphone_handle: RwLock::new(collect_microphone_handle),
+            collect_video_handle: RwLock::new(started.video_handle.take()),
+            process_handle: RwLock::new(started.process_handle.take()),
+            collect_audio_handle: RwLock::new(started.audio_handle.take()),
+            collect_microphone_handle: RwLock::new(started.microphone_handle.take()),
             replay_buffer: RwLock::new(replay_buffer),
+            writer,
+            video_encoder_type,
+            audio_levels,
+            mixer_stats,
+            pool_stats,
+            queue_stats,
+            memory_usage,
+            shared_preview,
+            excluded_windows: Mutex::new(excluded_affinities),
+            start_time,
+            markers,
+            tag_sender: Mutex::new(tag_sender),
+            frame_tags,
+            events,
+            errors,
+            profiler,
+            thumbnail,
+            counters,
+            session: SessionInfo {
+                process_name: get_process_exe_name(get_window_process_id(hwnd)).unwrap_or_default(),
+                window_title: get_window_title(hwnd),
+                input_size: (input_width, input_height),
+                output_size: (output_width, output_height),
+                fps: (fps_num, fps_den),
+                encoder: encoder_name,
+                started_at,
+            },
+            segment_output,
+            privacy_masks,
+            frame_injector,
+            window_gone,
+            paused,
+            av_sync_offset,
+            mixer_controls,
+            pixel_pipeline,
+            video_encoding,
+            encoder_adapter,
+            replay_saver: Mutex::new(None),
             config: config.clone(),
         })
     }
 
+    /// Get the codec private data (SPS/PPS/VPS) of the video stream being written
+    pub fn video_stream_header(&self) -> std::result::Result<Option<StreamHeader>, RecorderError> {
+        info!("Retrieving video stream header");
+        let Some(writer) = &self.writer else {
+            return Ok(None);
+        };
+        let header =
+            unsafe { encoder::get_video_stream_header(&writer.0, 0, self.video_encoder_type)? };
+        info!("Video stream header available: {}", header.is_some());
+        Ok(header)
+    }
+
+    /// Frame and byte totals of the video encoder
+    pub fn encoder_stats(&self) -> std::result::Result<EncoderStats, RecorderError> {
+        let writer = self.writer.as_ref().ok_or_else(|| {
+            RecorderError::Generic("No video is encoded in image sequence mode".to_string())
+        })?;
+        let stats = unsafe { encoder::get_encoder_stats(&writer.0, 0)? };
+        debug!("Encoder stats: {:?}", stats);
+        Ok(stats)
+    }
+
+    /// Per-stage pipeline timings since the recording started, None unless profiling is enabled
+    pub fn profile_report(&self) -> Option<ProfileReport> {
+        self.profiler.as_ref().map(|profiler| profiler.report())
+    }
+
+    /// Get the latest peak/RMS levels of the system audio and microphone sources
+    pub fn audio_levels(&self) -> std::result::Result<AudioLevels, RecorderError> {
+        let levels = self.audio_levels.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire audio levels lock".to_string())
+        })?;
+        Ok(*levels)
+    }
+
+    /// Totals of the audio mixer since the recording started
+    pub fn mixer_stats(&self) -> std::result::Result<MixerStats, RecorderError> {
+        let stats = self.mixer_stats.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire mixer stats lock".to_string())
+        })?;
+        Ok(*stats)
+    }
+
+    /// Usage of the capture texture pool since the recording started
+    pub fn pool_stats(&self) -> std::result::Result<PoolStats, RecorderError> {
+        let stats = self.pool_stats.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire pool stats lock".to_string())
+        })?;
+        Ok(*stats)
+    }
+
+    /// Totals of the queue between the capture and the encoder since the recording started
+    pub fn queue_stats(&self) -> std::result::Result<FrameQueueStats, RecorderError> {
+        let stats = self.queue_stats.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire frame queue stats lock".to_string())
+        })?;
+        Ok(*stats)
+    }
+
+    /// Memory held by the texture pools, the frame queue and the replay buffer right now
+    pub fn memory_usage(&self) -> std::result::Result<MemoryUsage, RecorderError> {
+        let queued_frames = self.queue_stats()?.queued;
+        let usage = self.memory_usage.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire memory usage lock".to_string())
+        })?;
+        Ok(MemoryUsage {
+            queued_frames,
+            ..*usage
+        })
+    }
+
+    /// The shared preview texture, once the first frame was copied into it
+    pub fn shared_preview_texture(
+        &self,
+    ) -> std::result::Result<Option<SharedPreviewTexture>, RecorderError> {
+        let texture = self.shared_preview.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire shared preview lock".to_string())
+        })?;
+        Ok(*texture)
+    }
+
     pub fn stop(&self) -> std::result::Result<(), RecorderError> {
         info!("Stop method called");
         if !self.recording.load(Ordering::Relaxed) {
@@ -546,211 +1122,573 @@ impl RecorderInner {
         }
 
         info!("Waiting for {} thread(s) to join", handles.len());
-        for (name, handle) in handles.into_iter() {
+        let total = handles.len();
+        self.events.emit(RecorderEvent::StopProgress {
+            stage: StopStage::StoppingCapture,
+            completed: 0,
+            total,
+        });
+        for (completed, (name, handle)) in handles.into_iter().enumerate() {
+            // The processing thread finalizes the file on exit
+            if name == "Process" {
+                self.events.emit(RecorderEvent::StopProgress {
+                    stage: StopStage::Finalizing,
+                    completed,
+                    total,
+                });
+            }
             info!("Joining {} thread", name);
-            if let Err(e) = handle
-                .join()
-                .map_err(|_| RecorderError::Generic(format!("{} Handle join failed", name)))?
-            {
-                error!("{} thread error: {:?}", name, e);
-                info!("{} thread joined with error", name);
-            } else {
-                info!("{} thread joined successfully", name);
+            // Failures were already reported by the thread itself; keep joining the rest so the
+            // file is finalized and excluded windows are restored
+            match handle.join() {
+                Ok(Ok(())) => info!("{} thread joined successfully", name),
+                Ok(Err(e)) => info!("{} thread joined with error: {:?}", name, e),
+                Err(_) => error!("{} Handle join failed", name),
+            }
+        }
+
+        self.restore_excluded_windows();
+
+        self.events.emit(RecorderEvent::StopProgress {
+            stage: StopStage::WritingMetadata,
+            completed: total,
+            total,
+        });
+        self.write_markers();
+        self.write_metadata();
+        if let Some(thumbnail) = &self.thumbnail {
+            if let Err(e) = thumbnail.write(self.config.output_path()) {
+                error!("Failed to write thumbnail: {:?}", e);
             }
         }
 
+        if let Some(e) = self.errors.first_error() {
+            error!("Recording stopped after a thread failure: {}", e);
+            return Err(e);
+        }
+
         info!("All threads joined, stop completed successfully");
         Ok(())
     }
 
-    /// Save the content of the replay buffer to a file
-    pub fn save_replay(&self, output_path: &str) -> std::result::Result<(), RecorderError> {
-        info!("Saving replay buffer to {}", output_path);
+    /// Spawn a supervisor thread that stops and finalizes the recording when one of `conditions`
+    /// is met; the thread exits early if the recording is stopped some other way
+    pub fn spawn_supervisor(self: &Arc<Self>, conditions: StopConditions) {
+        let inner = Arc::downgrade(self);
+        let recording = self.recording.clone();
+        let window_gone = self.window_gone.clone();
+        let deadline = conditions
+            .max_duration
+            .map(|max_duration| self.start_time + max_duration);
+        let output_path = self.config.output_path().clone();
+        let spawned = std::thread::Builder::new()
+            .name("windows-record-supervisor".to_string())
+            .spawn(move || {
+                let mut last_disk_check: Option<std::time::Instant> = None;
+                while recording.load(Ordering::Relaxed) {
+                    let now = std::time::Instant::now();
+                    let mut reason = None;
+                    if deadline.is_some_and(|deadline| now >= deadline) {
+                        let max_duration = conditions.max_duration.unwrap_or_default();
+                        info!("Maximum duration of {:?} reached, stopping", max_duration);
+                        reason = Some(RecorderEvent::MaxDurationReached { max_duration });
+                    }
+                    if let Some(timeout) = conditions.window_reacquire_timeout {
+                        if reason.is_none() && window_gone.load(Ordering::Relaxed) {
+                            info!(
+                                "Target window did not come back within {:?}, stopping",
+                                timeout
+                            );
+                            reason = Some(RecorderEvent::WindowReacquireTimedOut { timeout });
+                        }
+                    }
+                    if let Some(min_free_space) = conditions.min_free_space {
+                        if reason.is_none()
+                            && last_disk_check.is_none_or(|last| {
+                                now.duration_since(last) >= DISK_SPACE_CHECK_INTERVAL
+                            })
+                        {
+                            last_disk_check = Some(now);
+                            match free_disk_space(&output_path) {
+                                Some(free_bytes) if free_bytes < min_free_space => {
+                                    warn!(
+                                        "Only {} bytes free on the output volume, stopping",
+                                        free_bytes
+                                    );
+                                    reason = Some(RecorderEvent::LowDiskSpace {
+                                        free_bytes,
+                                        min_free_space,
+                                    });
+                                }
+                                Some(_) => {}
+                                None => debug!("Could not query free space for {:?}", output_path),
+                            }
+                        }
+                    }
 
-        info!("Acquiring read lock for replay buffer");
-        let replay_buffer = self.replay_buffer.read().map_err(|_| {
-            RecorderError::Generic("Failed to acquire replay buffer lock".to_string())
+                    let Some(reason) = reason else {
+                        let sleep = deadline
+                            .map_or(SUPERVISOR_POLL_INTERVAL, |deadline| {
+                                deadline.saturating_duration_since(now)
+                            })
+                            .min(SUPERVISOR_POLL_INTERVAL);
+                        std::thread::sleep(sleep);
+                        continue;
+                    };
+                    let Some(inner) = inner.upgrade() else {
+                        return;
+                    };
+                    inner.events().emit(reason);
+                    if let Err(e) = stop_and_notify(&inner) {
+                        warn!("Supervisor failed to stop the recording: {}", e);
+                    }
+                    return;
+                }
+                debug!("Recording stopped before any stop condition was met");
+            });
+        if let Err(e) = spawned {
+            error!("Failed to spawn the recording supervisor: {}", e);
+        }
+    }
+
+    /// Whether the capture threads are still running
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Stop or resume writing samples; capture keeps running while paused
+    pub fn set_paused(&self, paused: bool) -> std::result::Result<(), RecorderError> {
+        if !self.is_recording() {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
+        if self.paused.swap(paused, Ordering::Relaxed) == paused {
+            return Err(RecorderError::Generic(
+                if paused {
+                    "Recording is already paused"
+                } else {
+                    "Recording is not paused"
+                }
+                .to_string(),
+            ));
+        }
+        info!("{} recording", if paused { "Pausing" } else { "Resuming" });
+        Ok(())
+    }
+
+    /// Whether pausing has been requested and not resumed yet
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Shift the audio written from now on by `milliseconds`
+    pub fn set_av_sync_offset(&self, milliseconds: i64) -> std::result::Result<(), RecorderError> {
+        if !self.is_recording() {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
+        info!("Setting the A/V sync offset to {} ms", milliseconds);
+        self.av_sync_offset.store(milliseconds * 10_000, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Set the gain of the mixer source `source`, from the next packet on
+    pub fn set_audio_gain(&self, source: &str, gain: f32) -> std::result::Result<(), RecorderError> {
+        self.update_mixer_source(source, |control| control.gain = gain.clamp(0.0, 2.0))
+    }
+
+    /// Mute or unmute the mixer source `source`, from the next packet on
+    pub fn set_audio_muted(&self, source: &str, muted: bool) -> std::result::Result<(), RecorderError> {
+        self.update_mixer_source(source, |control| control.muted = muted)
+    }
+
+    /// Names of the audio sources being mixed, sorted
+    pub fn audio_sources(&self) -> Vec<String> {
+        let mut sources: Vec<String> = self
+            .mixer_controls
+            .lock()
+            .map(|controls| controls.keys().cloned().collect())
+            .unwrap_or_default();
+        sources.sort_unstable();
+        sources
+    }
+
+    fn update_mixer_source(
+        &self,
+        source: &str,
+        update: impl FnOnce(&mut SourceControl),
+    ) -> std::result::Result<(), RecorderError> {
+        if !self.is_recording() {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
+        let mut controls = self.mixer_controls.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire mixer controls lock".to_string())
         })?;
-        info!("Replay buffer lock acquired");
+        match controls.get_mut(source) {
+            Some(control) => {
+                update(control);
+                Ok(())
+            }
+            None => {
+                let mut sources: Vec<&str> = controls.keys().map(String::as_str).collect();
+                sources.sort_unstable();
+                Err(RecorderError::Generic(format!(
+                    "No audio source named '{}' is being recorded (sources: {})",
+                    source,
+                    sources.join(", ")
+                )))
+            }
+        }
+    }
 
-        let buffer = replay_buffer.as_ref().ok_or_else(|| {
-            info!("Replay buffer is not enabled");
-            RecorderError::Generic("Replay buffer is not enabled".to_string())
+    /// Path of the file being written
+    pub fn output_path(&self) -> &std::path::Path {
+        self.config.output_path()
+    }
+
+    pub(crate) fn events(&self) -> &EventEmitter {
+        &self.events
+    }
+
+    /// Replace the regions hidden from the recording, starting with the next frame
+    pub fn update_privacy_masks(&self, masks: Vec<MaskRect>) -> std::result::Result<(), RecorderError> {
+        if !self.recording.load(Ordering::Relaxed) {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
+        let mut privacy_masks = self.privacy_masks.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire privacy masks lock".to_string())
         })?;
+        debug!("Updating privacy masks: {:?}", masks);
+        *privacy_masks = masks;
+        Ok(())
+    }
+
+    /// Queue an application frame for encoding; see [`FrameInjector::submit`]
+    pub fn submit_frame(
+        &self,
+        texture: &ID3D11Texture2D,
+        timestamp: Duration,
+    ) -> std::result::Result<(), RecorderError> {
+        if !self.recording.load(Ordering::Relaxed) {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
+        let Some(injector) = &self.frame_injector else {
+            return Err(RecorderError::Generic(
+                "submit_frame requires video_source(VideoSource::Submitted)".to_string(),
+            ));
+        };
+        unsafe { injector.submit(texture, timestamp)? };
+        Ok(())
+    }
+
+    /// Bookmark the current position in the recording
+    pub fn add_marker(&self, label: &str) -> std::result::Result<Duration, RecorderError> {
+        if !self.recording.load(Ordering::Relaxed) {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
 
-        info!("Replay buffer reference obtained");
+        let time = self.start_time.elapsed();
+        let mut markers = self
+            .markers
+            .lock()
+            .map_err(|_| RecorderError::Generic("Failed to acquire markers lock".to_string()))?;
+        markers.push(Marker {
+            time,
+            label: label.to_string(),
+        });
+        info!("Added marker '{}' at {:?}", label, time);
+        Ok(time)
+    }
 
-        // Get the current time range from the buffer
-        info!("Getting current duration from replay buffer");
-        let duration = buffer.current_duration();
-        info!("Replay buffer current duration: {:?}", duration);
-        if duration.as_secs() == 0 {
-            info!("Replay buffer is empty, returning error");
-            return Err(RecorderError::Generic("Replay buffer is empty".to_string()));
+    /// Tag the frame being captured now; the processing thread places the tag on the video
+    /// timeline once that frame is written
+    pub fn tag_frame(&self, name: &str, payload: &str) -> std::result::Result<(), RecorderError> {
+        if !self.recording.load(Ordering::Relaxed) {
+            return Err(RecorderError::RecorderAlreadyStopped);
         }
 
+        let mut qpc = 0i64;
+        unsafe { QueryPerformanceCounter(&mut qpc) };
+        let sender = self
+            .tag_sender
+            .lock()
+            .map_err(|_| RecorderError::Generic("Failed to acquire tag sender lock".to_string()))?;
+        sender
+            .send(PendingTag {
+                name: name.to_string(),
+                payload: payload.to_string(),
+                qpc: qpc as u64,
+            })
+            .map_err(|_| RecorderError::RecorderAlreadyStopped)?;
+        debug!("Tag '{}' queued", name);
+        Ok(())
+    }
+
+    /// Tags placed on the video timeline so far, in the order they were added
+    pub fn frame_tags(&self) -> std::result::Result<Vec<FrameTag>, RecorderError> {
+        let tags = self
+            .frame_tags
+            .lock()
+            .map_err(|_| RecorderError::Generic("Failed to acquire frame tags lock".to_string()))?;
+        Ok(tags.clone())
+    }
+
+    // Write the chapter sidecar once the recording has been finalized
+    fn write_markers(&self) {
+        let markers = match self.markers.lock() {
+            Ok(markers) => markers,
+            Err(_) => return,
+        };
+        if markers.is_empty() {
+            return;
+        }
+
+        if let Err(e) = write_chapters_sidecar(self.config.output_path(), &markers) {
+            error!("Failed to write chapter markers: {:?}", e);
+        }
+    }
+
+    // Write the session metadata sidecar once the recording has been finalized
+    fn write_metadata(&self) {
+        if !self.config.metadata_sidecar() {
+            return;
+        }
+        let markers = self.markers.lock().map(|m| m.clone()).unwrap_or_default();
+        let tags = self
+            .frame_tags
+            .lock()
+            .map(|t| t.clone())
+            .unwrap_or_default();
+        let mixer_stats = self.mixer_stats.lock().map(|s| *s).unwrap_or_default();
+        let ended_at = unsafe { GetSystemTime() };
+        if let Err(e) = write_metadata_sidecar(
+            self.config.output_path(),
+            &self.session,
+            SessionOutcome {
+                counters: &self.counters,
+                mixer: &mixer_stats,
+                duration: self.start_time.elapsed(),
+                ended_at,
+                markers: &markers,
+                tags: &tags,
+            },
+        ) {
+            error!("Failed to write metadata sidecar: {:?}", e);
+        }
+    }
+
+    // Put back the display affinity of windows hidden from the capture
+    fn restore_excluded_windows(&self) {
+        if let Ok(mut excluded) = self.excluded_windows.lock() {
+            for (hwnd, affinity) in excluded.drain(..) {
+                restore_window_display_affinity(hwnd, affinity);
+            }
+        }
+    }
+
+    /// Start writing a second file that begins with the last `preroll` of the replay buffer
+    /// and continues with live samples until `stop_preroll_segment` or the recording stops
+    pub fn start_preroll_segment(
+        &self,
+        preroll: Duration,
+        output_path: &str,
+    ) -> std::result::Result<(), RecorderError> {
         info!(
-            "Replay buffer contains {} seconds of data",
-            duration.as_secs_f64()
+            "Starting pre-roll segment to {} with {:?} of pre-roll",
+            output_path, preroll
         );
+        if !self.recording.load(Ordering::Relaxed) {
+            return Err(RecorderError::RecorderAlreadyStopped);
+        }
 
-        unsafe {
-            // Get the oldest timestamp in the buffer
-            info!("Getting oldest timestamp from buffer");
-            let oldest_timestamp = *buffer.oldest_timestamp.lock().unwrap();
-            info!("Oldest timestamp: {}", oldest_timestamp);
-
-            // Get all video and audio samples from the buffer (within the time range)
-            info!("Retrieving samples from buffer");
-            let now = std::time::Instant::now();
-            info!("Retrieving video samples");
-            let video_samples = buffer.get_video_samples(oldest_timestamp, i64::MAX);
-            info!("Retrieved {} video samples", video_samples.len());
-            info!("Retrieving audio samples");
-            let audio_samples = buffer.get_audio_samples(oldest_timestamp, i64::MAX);
-            info!("Retrieved {} audio samples", audio_samples.len());
-            info!(
-                "Retrieved {} video frames and {} audio samples in {:?}",
-                video_samples.len(),
-                audio_samples.len(),
-                now.elapsed()
-            );
+        let (replay_width, replay_height, _) = self.config.replay_output();
+        if (replay_width, replay_height) != (self.config.output_width(), self.config.output_height()) {
+            return Err(RecorderError::Generic(
+                "Pre-roll segments need the replay buffer at the recording's resolution".to_string(),
+            ));
+        }
 
-            if video_samples.is_empty() {
-                info!("No video frames in replay buffer, returning error");
-                return Err(RecorderError::Generic(
-                    "No video frames in replay buffer".to_string(),
-                ));
-            }
+        let replay_buffer = self.replay_buffer.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire replay buffer lock".to_string())
+        })?;
+        let buffer = replay_buffer.as_ref().ok_or_else(|| {
+            RecorderError::Generic("Replay buffer is not enabled".to_string())
+        })?;
 
-            info!("Getting video encoder for replay file");
-            let video_encoder = if let Some(encoder_name) = self.config.video_encoder_name() {
-                info!("Looking for encoder by name: '{}'", encoder_name);
-                match crate::device::get_video_encoder_by_name(encoder_name) {
-                    Some(encoder) => {
-                        info!(
-                            "Found encoder by name: '{}' ({:?})",
-                            encoder_name, encoder.encoder_type
-                        );
-                        encoder
-                    }
-                    None => {
-                        info!("Encoder '{}' not found, falling back to type", encoder_name);
-                        crate::device::get_video_encoder_by_type(*self.config.video_encoder())?
-                    }
-                }
-            } else {
-                info!(
-                    "Looking for encoder by type: {:?}",
-                    self.config.video_encoder()
-                );
-                crate::device::get_video_encoder_by_type(*self.config.video_encoder())?
-            };
-            info!(
-                "Video encoder obtained: {} ({:?})",
-                video_encoder.name, video_encoder.encoder_type
-            );
+        // Hold the segment lock while writing the pre-roll so the processing thread
+        // picks up exactly where the buffered samples end
+        let mut segment_output = self.segment_output.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire segment output lock".to_string())
+        })?;
+        if segment_output.is_some() {
+            return Err(RecorderError::Generic(
+                "A pre-roll segment is already being recorded".to_string(),
+            ));
+        }
 
-            info!("Creating sink writer for replay file");
+        // Start the segment on the first buffered frame so it doesn't open with a gap
+        let clip = buffer.clip(Some(preroll)).ok_or_else(|| {
+            RecorderError::Generic("No video frames in replay buffer".to_string())
+        })?;
+        info!(
+            "Pre-roll contains {} video frames and {} audio samples",
+            clip.video.len(),
+            clip.audio.len()
+        );
+
+        let has_audio = self.config.capture_audio() || self.config.capture_microphone();
+        let mut segment = unsafe {
+            let video_encoder = resolve_video_encoder(&self.config, self.encoder_adapter)?;
             let media_sink = media::create_sink_writer(
                 output_path,
-                self.config.fps_num(),
-                self.config.fps_den(),
-                self.config.output_width(),
-                self.config.output_height(),
-                self.config.capture_audio(),
-                self.config.capture_microphone(),
-                self.config.video_bitrate(),
-                &video_encoder.output_format_guid, // Use output_format_guid instead of id
+                &sink_writer_settings(
+                    &self.config,
+                    &video_encoder,
+                    self.pixel_pipeline,
+                    self.video_encoding,
+                ),
+                self.config.encoded_frame_callback().cloned(),
             )?;
-            info!("Created sink writer for replay file");
-
-            // Begin writing
-            info!("Beginning writing to replay file");
             media_sink.BeginWriting()?;
-            info!("Begin writing successful");
-
-            // Define stream indices
-            let video_stream_index = 0;
-            info!("Video stream index: {}", video_stream_index);
-            let audio_stream_index = if !audio_samples.is_empty() { 1 } else { 0 };
-            info!("Audio stream index: {}", audio_stream_index);
-
-            // Find the earliest timestamp to use as a reference for normalization
-            let earliest_timestamp = if !video_samples.is_empty() {
-                video_samples[0].1
-            } else if !audio_samples.is_empty() {
-                audio_samples[0].1
-            } else {
-                oldest_timestamp
-            };
 
-            info!(
-                "Using earliest timestamp for normalization: {}",
-                earliest_timestamp
-            );
-
-            // Write video samples with normalized timestamps
-            info!(
-                "Writing {} video frames to replay file",
-                video_samples.len()
-            );
-            for (i, (sample, timestamp)) in video_samples.iter().enumerate() {
-                if i % 50 == 0 || i == video_samples.len() - 1 {
-                    info!("Writing video frame {}/{}", i + 1, video_samples.len());
-                }
-
-                // Calculate normalized timestamp (relative to the earliest timestamp)
-                let normalized_timestamp = timestamp - earliest_timestamp;
-
-                // Set the normalized timestamp directly on the sample
-                sample.SetSampleTime(normalized_timestamp)?;
+            SegmentOutput::new(
+                SendableWriter(Arc::new(media_sink)),
+                clip.start,
+                if has_audio { Some(1) } else { None },
+            )
+        };
 
-                // Write the sample with the normalized timestamp
-                info!(
-                    "Writing audio sample with timestamp: {}",
-                    normalized_timestamp
-                );
-                media_sink.WriteSample(audio_stream_index, &***sample)?;
+        unsafe {
+            for (sample, _) in &clip.video {
+                segment.write_video(sample)?;
+            }
+            for (sample, _) in &clip.audio {
+                segment.write_audio(sample)?;
             }
-            info!("Finished writing all video frames");
+        }
 
-            // Write audio samples with normalized timestamps
-            if !audio_samples.is_empty() {
-                info!(
-                    "Writing {} audio samples to replay file",
-                    audio_samples.len()
-                );
-                for (i, (sample, timestamp)) in audio_samples.iter().enumerate() {
-                    if i % 50 == 0 || i == audio_samples.len() - 1 {
-                        info!("Writing audio sample {}/{}", i + 1, audio_samples.len());
-                    }
+        *segment_output = Some(segment);
+        info!("Pre-roll segment started");
+        Ok(())
+    }
 
-                    // Calculate normalized timestamp (relative to the earliest timestamp)
-                    let normalized_timestamp = timestamp - earliest_timestamp;
+    /// Finalize the pre-roll segment started with `start_preroll_segment`
+    pub fn stop_preroll_segment(&self) -> std::result::Result<(), RecorderError> {
+        let segment = self
+            .segment_output
+            .lock()
+            .map_err(|_| {
+                RecorderError::Generic("Failed to acquire segment output lock".to_string())
+            })?
+            .take()
+            .ok_or_else(|| RecorderError::Generic("No pre-roll segment is active".to_string()))?;
+
+        unsafe { segment.finalize()? };
+        info!("Pre-roll segment finalized");
+        Ok(())
+    }
 
-                    // Set the normalized timestamp directly on the sample
-                    sample.SetSampleTime(normalized_timestamp)?;
+    /// Save the last `duration` of the replay buffer as an animated GIF at `fps` frames per
+    /// second, scaled down to at most `max_width` pixels wide
+    pub fn save_replay_gif(
+        &self,
+        output_path: &str,
+        duration: Duration,
+        fps: u32,
+        max_width: u32,
+    ) -> std::result::Result<(), RecorderError> {
+        info!(
+            "Saving last {:?} of the replay buffer as a {} fps GIF to {}",
+            duration, fps, output_path
+        );
+        if fps == 0 {
+            return Err(RecorderError::Generic("GIF frame rate must be greater than 0".to_string()));
+        }
 
-                    // Write the sample with the normalized timestamp
-                    media_sink.WriteSample(audio_stream_index, &***sample)?;
-                }
-                info!("Finished writing all audio samples");
+        let replay_buffer = self.replay_buffer.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire replay buffer lock".to_string())
+        })?;
+        let buffer = replay_buffer.as_ref().ok_or_else(|| {
+            RecorderError::Generic("Replay buffer is not enabled".to_string())
+        })?;
+
+        let all_video = buffer.video_samples();
+        let latest = all_video.last().map(|(_, timestamp)| *timestamp).ok_or_else(|| {
+            RecorderError::Generic("No video frames in replay buffer".to_string())
+        })?;
+        let start = latest - duration_to_hns(duration);
+        let frame_interval = 10_000_000 / fps as i64;
+
+        // Decimate to the GIF frame rate by keeping the first frame of each interval
+        let mut reader = Nv12Reader::default();
+        let mut frames = Vec::new();
+        let mut next_time = start;
+        for (sample, timestamp) in &all_video {
+            if *timestamp < next_time {
+                continue;
             }
+            frames.push(unsafe { reader.read(sample, max_width)? });
+            next_time = timestamp + frame_interval;
+        }
+        info!("Read back {} frames for the GIF", frames.len());
 
-            // Finalize the media sink
-            info!("Finalizing media sink");
-            media_sink.Finalize()?;
-            info!("Media sink finalized");
-            info!(
-                "Replay buffer saved to {} in {:?}",
+        unsafe {
+            image::write_gif(
                 output_path,
-                now.elapsed()
-            );
+                &frames,
+                Duration::from_nanos(1_000_000_000 / fps as u64),
+            )?
+        };
+        info!("GIF saved to {}", output_path);
+        Ok(())
+    }
+
+    /// Snapshot the replay buffer and queue it to be written to `output_path` by the replay
+    /// save worker, which is started with the first save
+    pub fn save_replay_in_background(
+        &self,
+        output_path: &str,
+    ) -> std::result::Result<ReplaySaveHandle, RecorderError> {
+        info!("Saving replay buffer to {}", output_path);
+
+        // Only copying the sample list happens under the buffer's locks
+        let clip = {
+            let replay_buffer = self.replay_buffer.read().map_err(|_| {
+                RecorderError::Generic("Failed to acquire replay buffer lock".to_string())
+            })?;
+            let buffer = replay_buffer.as_ref().ok_or_else(|| {
+                RecorderError::Generic("Replay buffer is not enabled".to_string())
+            })?;
+            buffer.clip(None).ok_or_else(|| {
+                RecorderError::Generic("No video frames in replay buffer".to_string())
+            })?
+        };
+        info!(
+            "Replay buffer contains {} video frames and {} audio samples ({:?})",
+            clip.video.len(),
+            clip.audio.len(),
+            clip.duration()
+        );
+
+        let mut replay_saver = self.replay_saver.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire replay saver lock".to_string())
+        })?;
+        if replay_saver.is_none() {
+            *replay_saver = Some(ReplaySaver::spawn(
+                self.config.clone(),
+                self.pixel_pipeline,
+                self.video_encoding,
+                self.encoder_adapter,
+                self.events.clone(),
+            )?);
         }
+        replay_saver
+            .as_ref()
+            .unwrap()
+            .submit(std::path::PathBuf::from(output_path), clip)
+    }
 
-        info!("save_replay completed successfully");
-        Ok(())
+    /// Save the content of the replay buffer to a file, waiting until it is finalized
+    pub fn save_replay(&self, output_path: &str) -> std::result::Result<(), RecorderError> {
+        self.save_replay_in_background(output_path)?.wait()
     }
 }
 
@@ -758,30 +1696,423 @@ impl Drop for RecorderInner {
     fn drop(&mut self) {
         unsafe {
             #[cfg(debug_assertions)]
-            log::info!("RecorderInner is being dropped, cleaning up resources");
+            crate::logging::info!("RecorderInner is being dropped, cleaning up resources");
 
             // Ensure recording flag is set to false to terminate threads
             if self.recording.load(std::sync::atomic::Ordering::Relaxed) {
                 #[cfg(debug_assertions)]
-                log::warn!("Recording flag was still true during drop; setting to false");
+                crate::logging::warn!("Recording flag was still true during drop; setting to false");
                 self.recording
                     .store(false, std::sync::atomic::Ordering::Relaxed);
             }
 
+            self.restore_excluded_windows();
+
             #[cfg(debug_assertions)]
-            log::info!("Shutting down Media Foundation");
+            crate::logging::info!("Shutting down Media Foundation");
 
             let _ = media::shutdown_media_foundation();
 
             #[cfg(debug_assertions)]
-            log::info!("RecorderInner cleanup complete");
+            crate::logging::info!("RecorderInner cleanup complete");
+        }
+    }
+}
+
+/// Adapter the video encoder is looked up on: the `encoder_adapter` option, or with
+/// `encoder_on_capture_adapter` the one capturing `hwnd` (the external device's if there is one)
+pub(super) unsafe fn target_encoder_adapter(config: &RecorderConfig, hwnd: HWND) -> Option<u64> {
+    if let Some(adapter) = config.encoder_adapter() {
+        return Some(adapter);
+    }
+    if !config.encoder_on_capture_adapter() {
+        return None;
+    }
+    let adapter = match config.external_device() {
+        Some(device) => adapter_luid(device),
+        None => window_adapter_luid(hwnd),
+    };
+    if adapter.is_none() {
+        warn!("Capture adapter not found, looking for the video encoder on every adapter");
+    }
+    adapter
+}
+
+/// Looks up the configured video encoder by name, falling back to the configured type
+///
+/// With an `adapter`, an encoder of that adapter is preferred: the named one if it runs there,
+/// otherwise its first hardware encoder of the configured type. The sink writer still creates
+/// the encoder MFT itself; the choice decides the output format, the pixel pipeline negotiated
+/// against the encoder and the encoder name that is reported.
+pub(super) unsafe fn resolve_video_encoder(
+    config: &RecorderConfig,
+    adapter: Option<u64>,
+) -> std::result::Result<crate::device::VideoEncoder, RecorderError> {
+    if let Some(adapter) = adapter {
+        let encoders = crate::device::enumerate_video_encoders_for_adapter(adapter)
+            .unwrap_or_else(|e| {
+                warn!("Failed to enumerate the encoders of adapter {:#x}: {}", adapter, e);
+                Vec::new()
+            });
+        let found = match config.video_encoder_name() {
+            Some(encoder_name) => encoders
+                .into_iter()
+                .find(|encoder| encoder.name == encoder_name),
+            None => encoders.into_iter().find(|encoder| {
+                encoder.adapter_luid == Some(adapter)
+                    && encoder.encoder_type == *config.video_encoder()
+            }),
+        };
+        if let Some(encoder) = found {
+            info!("Using encoder '{}' of adapter {:#x}", encoder.name, adapter);
+            return Ok(encoder);
+        }
+        warn!(
+            "No matching video encoder on adapter {:#x}, looking on every adapter",
+            adapter
+        );
+    }
+    if let Some(encoder_name) = config.video_encoder_name() {
+        if let Some(encoder) = crate::device::get_video_encoder_by_name(encoder_name) {
+            return Ok(encoder);
+        }
+        info!("Encoder '{}' not found, falling back to type", encoder_name);
+    }
+    crate::device::get_video_encoder_by_type(*config.video_encoder())
+}
+
+/// How frames of `input_size` reach `encoder` with `config`, see
+/// [`video::negotiate_pixel_pipeline`]
+pub(super) fn resolve_pixel_pipeline(
+    config: &RecorderConfig,
+    input_size: (u32, u32),
+    encoder: &crate::device::VideoEncoder,
+) -> PixelPipeline {
+    let replay_size = config.enable_replay_buffer().then(|| {
+        let (width, height, _) = config.replay_output();
+        (width, height)
+    });
+    video::negotiate_pixel_pipeline(
+        config.pixel_pipeline(),
+        input_size,
+        (config.output_width(), config.output_height()),
+        config.video_transform(),
+        config.color_space(),
+        replay_size,
+        || encoder_accepts_rgb_input(encoder),
+    )
+}
+
+/// Profile, level and rate control of the video for the `encoder_settings` and
+/// `quality_profile` of `config`, failing if `encoder` cannot encode them
+pub(super) fn resolve_video_encoding(
+    config: &RecorderConfig,
+    encoder: &crate::device::VideoEncoder,
+    pixel_pipeline: PixelPipeline,
+) -> std::result::Result<VideoEncoding, RecorderError> {
+    let invalid = |field: &'static str, message: String| {
+        RecorderError::InvalidConfig(vec![ConfigIssue { field, message }])
+    };
+    let settings = config.encoder_settings();
+    // The config is checked against `video_encoder`, a named encoder may be of another type
+    settings
+        .check(encoder.encoder_type)
+        .map_err(|message| invalid("encoder_settings", message))?;
+    let mut encoding = VideoEncoding {
+        profile: settings.profile,
+        level: settings.level,
+        entropy_coding: settings.entropy_coding,
+        constant_qp: None,
+    };
+    let lossless = config.quality_profile() == QualityProfile::Lossless;
+    if settings.profile.is_none() && !lossless {
+        return Ok(encoding);
+    }
+
+    let capabilities = query_encoder_capabilities(encoder)?;
+    if let Some(profile) = settings.profile {
+        if !capabilities.profiles.contains(&profile) {
+            return Err(invalid(
+                "encoder_settings",
+                format!(
+                    "video encoder '{}' does not support the {:?} profile",
+                    encoder.name, profile
+                ),
+            ));
+        }
+    }
+    if lossless {
+        if !capabilities
+            .rate_control_modes
+            .contains(&RateControlMode::Quality)
+        {
+            return Err(invalid(
+                "quality_profile",
+                format!(
+                    "video encoder '{}' has no constant quality rate control for lossless encoding",
+                    encoder.name
+                ),
+            ));
+        }
+        // NV12 frames have already lost the full chroma resolution; a chosen profile wins
+        let full_chroma = VideoProfile::full_chroma(encoder.encoder_type);
+        if encoding.profile.is_none()
+            && pixel_pipeline == PixelPipeline::Rgb
+            && capabilities.profiles.contains(&full_chroma)
+        {
+            encoding.profile = Some(full_chroma);
+        }
+        info!(
+            "Lossless encoding with '{}' at QP {} in profile {:?}",
+            encoder.name, LOSSLESS_QP, encoding.profile
+        );
+        encoding.constant_qp = Some(LOSSLESS_QP);
+    }
+    Ok(encoding)
+}
+
+/// Sink writer settings of the main output of `config`, encoded by `encoder`
+pub(super) fn sink_writer_settings(
+    config: &RecorderConfig,
+    encoder: &crate::device::VideoEncoder,
+    pixel_pipeline: PixelPipeline,
+    video_encoding: VideoEncoding,
+) -> SinkWriterSettings {
+    SinkWriterSettings {
+        fps_num: config.fps_num(),
+        fps_den: config.fps_den(),
+        output_width: config.output_width(),
+        output_height: config.output_height(),
+        audio: config.capture_audio() || config.capture_microphone(),
+        video_bitrate: config.video_bitrate(),
+        capped_bitrate: config.target_file_size().is_some(),
+        video_encoder: encoder.output_format_guid,
+        low_latency: config.low_latency(),
+        fragmented: config.fragmented_output(),
+        sink_options: config.sink_writer_options(),
+        color_space: config.color_space(),
+        pixel_pipeline,
+        video_encoding,
+        audio_codec: config.audio_codec(),
+    }
+}
+
+/// Device IDs of the microphone of `config`, None for the default one, and of its extra
+/// microphones by name; an extra microphone that is not plugged in is left out
+fn resolve_microphones(config: &RecorderConfig) -> (Option<String>, Vec<(String, String)>) {
+    info!("Checking for microphone device configuration");
+    let microphone_device = if let Some(device_name) = config.microphone_device() {
+        info!(
+            "Attempting to get device ID for microphone: '{}'",
+            device_name
+        );
+        match get_audio_input_device_by_name(Some(device_name)) {
+            Ok(device_id) => {
+                info!("Found device ID for '{}': {}", device_name, device_id);
+                Some(device_id)
+            }
+            Err(e) => {
+                info!(
+                    "Could not get device ID for '{}', using default: {:?}",
+                    device_name, e
+                );
+                None
+            }
         }
+    } else {
+        info!("No microphone device specified, using system default");
+        None
+    };
+
+    if !config.capture_microphone() {
+        return (microphone_device, Vec::new());
+    }
+    let extra_microphones = config
+        .extra_microphones()
+        .iter()
+        .filter_map(|name| match get_audio_input_device_by_name(Some(name)) {
+            Ok(device_id) => Some((name.clone(), device_id)),
+            Err(e) => {
+                warn!(
+                    "Microphone '{}' not found, recording without it: {:?}",
+                    name, e
+                );
+                None
+            }
+        })
+        .collect();
+    (microphone_device, extra_microphones)
+}
+
+/// The replay buffer of `config`, if it is enabled
+fn create_replay_buffer(
+    config: &RecorderConfig,
+    memory_usage: &Arc<Mutex<MemoryUsage>>,
+) -> Option<Arc<ReplayBuffer>> {
+    info!("Checking if replay buffer is enabled");
+    if !config.enable_replay_buffer() {
+        info!("Replay buffer is disabled");
+        return None;
+    }
+    info!(
+        "Replay buffer is enabled with {} seconds",
+        config.replay_buffer_seconds()
+    );
+    let buffer_duration = std::time::Duration::from_secs(config.replay_buffer_seconds() as u64);
+    let fps = config.fps_num() as f64 / config.fps_den() as f64;
+
+    // Estimate the number of frames and audio samples in the buffer
+    let video_frames = (fps * buffer_duration.as_secs_f64()) as usize;
+    let audio_samples = if config.capture_audio() || config.capture_microphone() {
+        // Audio at 44.1kHz, assume ~10 packets per second for buffer capacity
+        (10.0 * buffer_duration.as_secs_f64()) as usize
+    } else {
+        0
+    };
+
+    info!(
+        "Creating replay buffer for {} seconds ({} video frames, {} audio samples)",
+        config.replay_buffer_seconds(),
+        video_frames,
+        audio_samples
+    );
+
+    let buffer = Arc::new(ReplayBuffer::new(
+        buffer_duration,
+        video_frames,
+        audio_samples,
+        config.memory_limits(),
+        memory_usage.clone(),
+    ));
+    info!("Replay buffer created successfully");
+    Some(buffer)
+}
+
+/// The writer saving frames as images instead of encoding a video, if `config` asks for one
+fn create_image_sequence(config: &RecorderConfig) -> Result<Option<ImageSequenceWriter>> {
+    match config.output_mode() {
+        OutputMode::Video => Ok(None),
+        OutputMode::ImageSequence {
+            dir,
+            format,
+            every_nth,
+        } => Ok(Some(ImageSequenceWriter::new(dir, *format, *every_nth)?)),
+    }
+}
+
+/// The file every captured frame also goes to when `config` captures faster than the main
+/// output and names a secondary output, already writing
+unsafe fn create_high_fps_output(
+    config: &RecorderConfig,
+    sink_settings: &SinkWriterSettings,
+    started: &mut PartialStart,
+) -> Result<Option<SegmentOutput>> {
+    let Some((high_fps, path)) = config
+        .high_fps_capture()
+        .and_then(|high_fps| Some((high_fps, high_fps.secondary_output.as_ref()?)))
+    else {
+        return Ok(None);
+    };
+    if !path.exists() {
+        started.files.push(path.clone());
     }
+    let path = path.to_string_lossy();
+    info!(
+        "Writing {} fps video to {} at {} bps",
+        high_fps.capture_fps, path, high_fps.secondary_bitrate
+    );
+    let high_fps_sink = media::create_sink_writer(
+        &path,
+        &SinkWriterSettings {
+            fps_num: high_fps.capture_fps,
+            fps_den: 1,
+            audio: false,
+            video_bitrate: high_fps.secondary_bitrate,
+            capped_bitrate: false,
+            ..*sink_settings
+        },
+        None,
+    )?;
+    high_fps_sink.BeginWriting()?;
+    Ok(Some(SegmentOutput::new(
+        SendableWriter(Arc::new(high_fps_sink)),
+        0,
+        None,
+    )))
+}
+
+/// The capture dump of `config`, if requested, starting with the window's initial geometry
+fn open_capture_dump(
+    config: &RecorderConfig,
+    header: DumpHeader,
+    initial_position: Option<(i32, i32)>,
+    initial_size: Option<(u32, u32)>,
+    started: &mut PartialStart,
+) -> Result<Option<CaptureDumpWriter>> {
+    let Some(dump_path) = config.capture_dump_path() else {
+        return Ok(None);
+    };
+    info!("Capture dump enabled, writing to {}", dump_path.display());
+    let mut dump = CaptureDumpWriter::create(dump_path, header)?;
+    started.files.push(dump_path.to_path_buf());
+    if let (Some(position), Some(size)) = (initial_position, initial_size) {
+        dump.write_window_info(position, size)?;
+    }
+    Ok(Some(dump))
+}
+
+/// Free bytes available to the caller on the volume `path` is (or will be) written to
+pub(super) fn free_disk_space(path: &std::path::Path) -> Option<u64> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let mut free_bytes = 0u64;
+    unsafe {
+        GetDiskFreeSpaceExW(&HSTRING::from(directory.to_string_lossy().as_ref()), Some(&mut free_bytes), None, None)
+    }
+    .as_bool()
+    .then_some(free_bytes)
+}
+
+/// The caller's device from the `external_device` option, or a new one for `hwnd`'s adapter
+pub(super) unsafe fn recording_device(
+    config: &RecorderConfig,
+    hwnd: HWND,
+) -> Result<(ID3D11Device, ID3D11DeviceContext)> {
+    match config.external_device() {
+        Some(device) => prepare_external_device(device),
+        None => create_d3d11_device_for_window(hwnd),
+    }
+}
+
+/// Check that a caller-supplied device can be recorded on and enable multithread protection, as
+/// the capture, processing and encoder threads all use its immediate context
+unsafe fn prepare_external_device(
+    device: &ID3D11Device,
+) -> Result<(ID3D11Device, ID3D11DeviceContext)> {
+    info!("Using the external D3D11 device");
+    let flags = D3D11_CREATE_DEVICE_FLAG(device.GetCreationFlags());
+    if !flags.contains(D3D11_CREATE_DEVICE_BGRA_SUPPORT) {
+        return Err(windows::core::Error::new(
+            E_INVALIDARG,
+            "External D3D11 device was not created with D3D11_CREATE_DEVICE_BGRA_SUPPORT".into(),
+        ));
+    }
+
+    let multithread: ID3D11Multithread = device.cast()?;
+    if !multithread.GetMultithreadProtected().as_bool() {
+        warn!("Enabling multithread protection on the external D3D11 device");
+        multithread.SetMultithreadProtected(true);
+    }
+
+    let context = device.GetImmediateContext()?;
+    Ok((device.clone(), context))
 }
 
 /// Creates a D3D11 device for a specific window
 /// This ensures the device is created on the correct adapter for the window
-unsafe fn create_d3d11_device_for_window(
+pub(crate) unsafe fn create_d3d11_device_for_window(
     hwnd: HWND,
 ) -> Result<(ID3D11Device, ID3D11DeviceContext)> {
     info!("Attempting to create D3D11 device for window: {:?}", hwnd);
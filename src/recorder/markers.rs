@@ -0,0 +1,73 @@
+use crate::logging::info;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A bookmark placed during a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    /// Offset from the start of the recording
+    pub time: Duration,
+    pub label: String,
+}
+
+/// A tag attached to a moment of the recording with `Recorder::tag_frame`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameTag {
+    /// Timestamp of the video frame the tag landed on
+    pub time: Duration,
+    pub name: String,
+    /// Application data passed along with the tag, e.g. JSON
+    pub payload: String,
+}
+
+/// Path of the chapter sidecar written next to a recording (`clip.mp4` -> `clip.chapters.json`)
+pub fn chapters_sidecar_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("chapters.json")
+}
+
+/// Write markers as a JSON chapter list next to the recording
+pub fn write_chapters_sidecar(output_path: &Path, markers: &[Marker]) -> std::io::Result<PathBuf> {
+    let path = chapters_sidecar_path(output_path);
+    let mut file = std::fs::File::create(&path)?;
+
+    writeln!(file, "{{")?;
+    writeln!(
+        file,
+        "  \"recording\": \"{}\",",
+        escape_json(&output_path.display().to_string())
+    )?;
+    writeln!(file, "  \"chapters\": [")?;
+    for (i, marker) in markers.iter().enumerate() {
+        let separator = if i + 1 < markers.len() { "," } else { "" };
+        writeln!(
+            file,
+            "    {{ \"time_ms\": {}, \"label\": \"{}\" }}{}",
+            marker.time.as_millis(),
+            escape_json(&marker.label),
+            separator
+        )?;
+    }
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+
+    info!("Wrote {} chapter markers to {}", markers.len(), path.display());
+    Ok(path)
+}
+
+/// Escape a string for inclusion in a JSON string literal
+pub fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,169 @@
+use crate::logging::info;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use windows::Win32::Foundation::SYSTEMTIME;
+
+use super::markers::{escape_json, FrameTag, Marker};
+use crate::processing::audio::MixerStats;
+use crate::types::FrameCounters;
+
+/// Details of a recording session that are known when it starts
+#[derive(Debug, Clone)]
+pub(crate) struct SessionInfo {
+    /// Executable file name of the recorded process
+    pub process_name: String,
+    pub window_title: String,
+    pub input_size: (u32, u32),
+    pub output_size: (u32, u32),
+    pub fps: (u32, u32),
+    /// Friendly name of the video encoder
+    pub encoder: String,
+    pub started_at: SYSTEMTIME,
+}
+
+/// How a recording session went, known once it stops
+pub(crate) struct SessionOutcome<'a> {
+    pub counters: &'a FrameCounters,
+    pub mixer: &'a MixerStats,
+    pub duration: Duration,
+    pub ended_at: SYSTEMTIME,
+    pub markers: &'a [Marker],
+    pub tags: &'a [FrameTag],
+}
+
+/// Path of the metadata sidecar written next to a recording (`clip.mp4` -> `clip.meta.json`)
+pub fn metadata_sidecar_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("meta.json")
+}
+
+/// Write the session details, frame statistics, markers and frame tags as JSON next to the
+/// recording
+pub(crate) fn write_metadata_sidecar(
+    output_path: &Path,
+    session: &SessionInfo,
+    outcome: SessionOutcome<'_>,
+) -> std::io::Result<PathBuf> {
+    let SessionOutcome {
+        counters,
+        mixer,
+        duration,
+        ended_at,
+        markers,
+        tags,
+    } = outcome;
+    let path = metadata_sidecar_path(output_path);
+    let mut file = std::fs::File::create(&path)?;
+
+    let frames_written = counters.written.load(Ordering::Relaxed);
+    let average_fps = match duration.as_secs_f64() {
+        seconds if seconds > 0.0 => frames_written as f64 / seconds,
+        _ => 0.0,
+    };
+
+    writeln!(file, "{{")?;
+    writeln!(
+        file,
+        "  \"recording\": \"{}\",",
+        escape_json(&output_path.display().to_string())
+    )?;
+    writeln!(
+        file,
+        "  \"process_name\": \"{}\",",
+        escape_json(&session.process_name)
+    )?;
+    writeln!(
+        file,
+        "  \"window_title\": \"{}\",",
+        escape_json(&session.window_title)
+    )?;
+    writeln!(
+        file,
+        "  \"capture_resolution\": {{ \"width\": {}, \"height\": {} }},",
+        session.input_size.0, session.input_size.1
+    )?;
+    writeln!(
+        file,
+        "  \"output_resolution\": {{ \"width\": {}, \"height\": {} }},",
+        session.output_size.0, session.output_size.1
+    )?;
+    writeln!(file, "  \"encoder\": \"{}\",", escape_json(&session.encoder))?;
+    writeln!(
+        file,
+        "  \"fps\": {{ \"target\": {:.3}, \"average\": {:.3} }},",
+        session.fps.0 as f64 / session.fps.1 as f64,
+        average_fps
+    )?;
+    writeln!(
+        file,
+        "  \"frames\": {{ \"written\": {}, \"duplicated\": {}, \"dropped\": {} }},",
+        frames_written,
+        counters.duplicated.load(Ordering::Relaxed),
+        counters.dropped.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+        file,
+        concat!(
+            "  \"audio_mixer\": {{ \"mixed_packets\": {}, \"system_only_packets\": {}, ",
+            "\"microphone_only_packets\": {}, \"microphone_frames_missing\": {}, ",
+            "\"microphone_frames_discarded\": {}, \"microphone_drift_ms\": {:.3} }},"
+        ),
+        mixer.mixed_packets,
+        mixer.system_only_packets,
+        mixer.microphone_only_packets,
+        mixer.microphone_frames_missing,
+        mixer.microphone_frames_discarded,
+        mixer.microphone_drift_ms
+    )?;
+    writeln!(
+        file,
+        "  \"started_at\": \"{}\",",
+        format_utc(&session.started_at)
+    )?;
+    writeln!(file, "  \"ended_at\": \"{}\",", format_utc(&ended_at))?;
+    writeln!(file, "  \"duration_ms\": {},", duration.as_millis())?;
+    writeln!(file, "  \"markers\": [")?;
+    for (i, marker) in markers.iter().enumerate() {
+        let separator = if i + 1 < markers.len() { "," } else { "" };
+        writeln!(
+            file,
+            "    {{ \"time_ms\": {}, \"label\": \"{}\" }}{}",
+            marker.time.as_millis(),
+            escape_json(&marker.label),
+            separator
+        )?;
+    }
+    writeln!(file, "  ],")?;
+    writeln!(file, "  \"tags\": [")?;
+    for (i, tag) in tags.iter().enumerate() {
+        let separator = if i + 1 < tags.len() { "," } else { "" };
+        writeln!(
+            file,
+            "    {{ \"time_ms\": {}, \"name\": \"{}\", \"payload\": \"{}\" }}{}",
+            tag.time.as_millis(),
+            escape_json(&tag.name),
+            escape_json(&tag.payload),
+            separator
+        )?;
+    }
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+
+    info!("Wrote recording metadata to {}", path.display());
+    Ok(path)
+}
+
+/// ISO 8601 representation of a UTC system time, e.g. `2024-05-01T18:30:00.250Z`
+fn format_utc(time: &SYSTEMTIME) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time.wYear,
+        time.wMonth,
+        time.wDay,
+        time.wHour,
+        time.wMinute,
+        time.wSecond,
+        time.wMilliseconds
+    )
+}
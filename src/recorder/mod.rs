@@ -1,31 +1,127 @@
+mod asynchronous;
 mod config;
+mod dump_replay;
+pub(crate) mod errors;
+#[cfg(feature = "serde")]
+pub(crate) mod event_pipe;
+pub(crate) mod events;
 mod inner;
+pub(crate) mod markers;
+mod metadata;
+mod prepare;
+mod preset;
+mod repair;
+mod replay_save;
+mod rollback;
+mod schedule;
+mod stop;
+mod template;
+mod watcher;
+mod window_list;
 
+pub use self::asynchronous::{AsyncRecorder, EventStream, NextEvent, RecorderTask};
 // Re-export public types from config
-pub use self::config::{AudioSource, RecorderConfig, RecorderConfigBuilder};
+pub use self::config::{
+    AudioProcess, AudioSource, EncodingProfile, LoopbackMode, RecorderConfig, RecorderConfigBuilder,
+};
+pub use self::dump_replay::replay_capture_dump;
+#[cfg(feature = "serde")]
+pub use self::event_pipe::EventPipe;
+pub use self::events::{EventCallback, RecorderEvent, StopStage};
+pub use self::markers::{FrameTag, Marker};
+pub use self::preset::{bitrate_for_file_size, Preset};
+pub use self::repair::{repair_mp4, Mp4RepairReport};
+pub use self::replay_save::ReplaySaveHandle;
+pub use self::stop::StopHandle;
+pub use self::watcher::RecorderSupervisor;
+pub use self::window_list::{
+    list_windows, list_windows_with_thumbnail_size, WindowInfo, WindowThumbnail,
+};
 
-use self::inner::RecorderInner;
+pub(crate) use self::inner::create_d3d11_device_for_window;
+
+use self::events::EventEmitter;
+use self::inner::{free_disk_space, RecorderInner, StopConditions};
+use self::prepare::PreparedSession;
+use self::schedule::ScheduledStart;
+use self::template::render_output_template;
+use crate::capture::window::{
+    get_process_exe_name, get_window_process_id, get_window_title, CapturableWindow,
+    ReacquireTimeout, WindowPick, WindowTarget,
+};
 use crate::error::{RecorderError, Result};
-use log::{debug, info};
-use std::sync::RwLock;
-use windows::Win32::Foundation::{BOOL, LPARAM};
+use crate::processing::audio::{AudioLevels, MixerStats};
+use crate::types::frame_queue::FrameQueueStats;
+use crate::types::memory::MemoryUsage;
+use crate::types::texture_pool::PoolStats;
+use crate::processing::encoder::{EncoderStats, StreamHeader};
+use crate::processing::mask::MaskRect;
+use crate::processing::preview::{PreviewFrame, PreviewSubscribers, SharedPreviewTexture};
+use crate::processing::profiler::ProfileReport;
+use crate::logging::{debug, error, info, set_log_level, LogSubsystem};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::level_filters::LevelFilter;
+use windows::Win32::Foundation::{BOOL, HWND, LPARAM};
+use windows::Win32::Graphics::Direct3D11::ID3D11Texture2D;
 use windows::Win32::UI::WindowsAndMessaging::{GetWindowTextW, IsWindowVisible};
 
+/// How often `start_recording` looks for the target window while waiting for it
+const WINDOW_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 pub struct Recorder {
-    rec_inner: RwLock<Option<RecorderInner>>,
+    /// Shared with a scheduled start, which fills it in once the time has come
+    rec_inner: Arc<RwLock<Option<Arc<RecorderInner>>>>,
     config: RecorderConfig,
     process_name: RwLock<Option<String>>,
     use_exact_match: RwLock<bool>,
+    process_id: RwLock<Option<u32>>,
+    process_exe: RwLock<Option<String>>,
+    window_handle: RwLock<Option<HWND>>,
+    window_pick: RwLock<WindowPick>,
+    excluded_windows: RwLock<Vec<HWND>>,
+    wait_for_process: RwLock<Option<Duration>>,
+    max_duration: RwLock<Option<Duration>>,
+    scheduled_start: RwLock<Option<SystemTime>>,
+    /// Start waiting for `scheduled_start`
+    pending_start: Mutex<Option<ScheduledStart>>,
+    min_free_space: RwLock<Option<u64>>,
+    output_index: RwLock<u32>,
+    current_output_path: RwLock<Option<PathBuf>>,
+    /// Set up by `prepare`, used by the next start
+    prepared: Arc<RwLock<Option<PreparedSession>>>,
+    preview: PreviewSubscribers,
 }
 
 impl Recorder {
     // Create a new recorder instance with configuration
     pub fn new(config: RecorderConfig) -> Result<Self> {
+        #[cfg(feature = "serde")]
+        let config = match config.event_pipe() {
+            Some(pipe) => event_pipe::attach(&config, pipe)?,
+            None => config,
+        };
         Ok(Self {
-            rec_inner: RwLock::new(None),
+            rec_inner: Arc::new(RwLock::new(None)),
             config,
             process_name: RwLock::new(None),
             use_exact_match: RwLock::new(false),
+            process_id: RwLock::new(None),
+            process_exe: RwLock::new(None),
+            window_handle: RwLock::new(None),
+            window_pick: RwLock::new(WindowPick::default()),
+            excluded_windows: RwLock::new(Vec::new()),
+            wait_for_process: RwLock::new(None),
+            max_duration: RwLock::new(None),
+            scheduled_start: RwLock::new(None),
+            pending_start: Mutex::new(None),
+            min_free_space: RwLock::new(None),
+            output_index: RwLock::new(0),
+            current_output_path: RwLock::new(None),
+            prepared: Arc::new(RwLock::new(None)),
+            preview: PreviewSubscribers::default(),
         })
     }
 
@@ -52,8 +148,101 @@ impl Recorder {
         self
     }
 
-    // Begin recording
-    pub fn start_recording(&self) -> Result<()> {
+    /// Record the main window of the process with this ID instead of matching a window title
+    pub fn with_process_id(self, process_id: u32) -> Self {
+        if let Ok(mut target_process_id) = self.process_id.write() {
+            *target_process_id = Some(process_id);
+        }
+        self
+    }
+
+    /// Record the main window of a process started from this executable instead of matching a
+    /// window title, for localized titles or windows that change their title (e.g. browsers)
+    /// Takes a file name such as `game.exe` (case-insensitive, `.exe` optional) or a full path
+    pub fn with_process_exe(self, executable: &str) -> Self {
+        if let Ok(mut process_exe) = self.process_exe.write() {
+            *process_exe = Some(executable.to_string());
+        }
+        self
+    }
+
+    /// Record this window instead of matching a window title
+    /// Takes precedence over `with_process_id`, `with_process_exe` and `with_process_name`
+    pub fn with_window_handle(self, hwnd: HWND) -> Self {
+        if let Ok(mut window_handle) = self.window_handle.write() {
+            *window_handle = Some(hwnd);
+        }
+        self
+    }
+
+    /// Choose which window is recorded when several windows match the title, process or
+    /// executable (default: `WindowPick::First`, the topmost match)
+    pub fn with_window_pick(self, pick: WindowPick) -> Self {
+        if let Ok(mut window_pick) = self.window_pick.write() {
+            *window_pick = pick;
+        }
+        self
+    }
+
+    /// Only pass log messages of `subsystem` at `level` or more severe to the logger. The filter
+    /// is process-wide, see `set_log_level`
+    pub fn with_log_level(self, subsystem: LogSubsystem, level: LevelFilter) -> Self {
+        set_log_level(subsystem, level);
+        self
+    }
+
+    /// Exclude one of the host application's windows (e.g. an overlay or control panel)
+    /// from the capture while recording. Can be called multiple times.
+    /// The window must belong to the calling process; requires Windows 10 2004 or later
+    pub fn with_excluded_window(self, hwnd: HWND) -> Self {
+        if let Ok(mut excluded_windows) = self.excluded_windows.write() {
+            excluded_windows.push(hwnd);
+        }
+        self
+    }
+
+    /// Let `start_recording` wait up to `timeout` for the target window to appear instead of
+    /// failing right away, e.g. to start recording as soon as a game is launched
+    /// A `WindowFound` event is emitted when the window shows up
+    pub fn with_wait_for_process(self, timeout: Duration) -> Self {
+        if let Ok(mut wait_for_process) = self.wait_for_process.write() {
+            *wait_for_process = Some(timeout);
+        }
+        self
+    }
+
+    /// Stop and finalize each recording automatically once it has run for `max_duration`
+    /// A `MaxDurationReached` event is emitted before stopping
+    pub fn with_max_duration(self, max_duration: Duration) -> Self {
+        if let Ok(mut target_max_duration) = self.max_duration.write() {
+            *target_max_duration = Some(max_duration);
+        }
+        self
+    }
+
+    /// Delay the start of the recording until `start_at`
+    /// `start_recording` picks the window and returns right away, and the recording starts in
+    /// the background at `start_at`; `stop_recording` before then cancels it. A time in the past
+    /// starts right away
+    pub fn with_scheduled_start(self, start_at: SystemTime) -> Self {
+        if let Ok(mut scheduled_start) = self.scheduled_start.write() {
+            *scheduled_start = Some(start_at);
+        }
+        self
+    }
+
+    /// Refuse to start, or stop and finalize the recording, when the output volume has fewer
+    /// than `bytes` free. A `LowDiskSpace` event is emitted before stopping
+    pub fn with_min_free_space(self, bytes: u64) -> Self {
+        if let Ok(mut min_free_space) = self.min_free_space.write() {
+            *min_free_space = Some(bytes);
+        }
+        self
+    }
+
+    /// Begin recording
+    /// Returns the window being recorded, picked with `with_window_pick` if several match
+    pub fn start_recording(&self) -> Result<CapturableWindow> {
         if self.config.debug_mode() {
             info!(
                 "Starting recording to file: {}",
@@ -61,13 +250,20 @@ impl Recorder {
             );
         }
 
-        // Read the process_name and use_exact_match with read locks
-        let process_name_guard = self.process_name.read().map_err(|_| {
-            RecorderError::Generic("Failed to acquire read lock on process_name".to_string())
+        if self.start_pending()? {
+            return Err(RecorderError::RecordingInProgress);
+        }
+        let target = self.capture_target()?;
+
+        let wait_for_process = *self.wait_for_process.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on wait_for_process".to_string())
         })?;
+        if let Some(timeout) = wait_for_process {
+            self.wait_for_window(&target, timeout)?;
+        }
 
-        let use_exact_match = *self.use_exact_match.read().map_err(|_| {
-            RecorderError::Generic("Failed to acquire read lock on use_exact_match".to_string())
+        let excluded_windows = self.excluded_windows.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on excluded_windows".to_string())
         })?;
 
         // Get a write lock for rec_inner
@@ -75,21 +271,9 @@ impl Recorder {
             RecorderError::Generic("Failed to acquire write lock on rec_inner".to_string())
         })?;
 
-        let Some(ref proc_name) = *process_name_guard else {
-            return Err(RecorderError::NoProcessSpecified);
-        };
-
         // If debug mode is enabled, print all window titles for debugging
         if self.config.debug_mode() {
-            info!(
-                "Searching for windows with{} match: '{}'",
-                if use_exact_match {
-                    " exact"
-                } else {
-                    " substring"
-                },
-                proc_name
-            );
+            info!("Searching for window: {:?}", target);
 
             unsafe {
                 info!("Available windows:");
@@ -100,28 +284,268 @@ impl Recorder {
             }
         }
 
-        // We need to modify the init to take use_exact_match directly eventually
-        thread_local! {
-            static USE_EXACT_MATCH: std::cell::RefCell<bool> = std::cell::RefCell::new(false);
+        // Resolve the output path for this recording
+        let config = match self.config.output_template() {
+            Some(_) => {
+                let path = self.next_templated_path(&target_label(&target))?;
+                info!("Output template resolved to: {}", path.display());
+                self.config.with_output_path(path)
+            }
+            None => self.config.clone(),
+        };
+        let conditions = StopConditions {
+            max_duration: *self.max_duration.read().map_err(|_| {
+                RecorderError::Generic("Failed to acquire read lock on max_duration".to_string())
+            })?,
+            min_free_space: *self.min_free_space.read().map_err(|_| {
+                RecorderError::Generic("Failed to acquire read lock on min_free_space".to_string())
+            })?,
+            window_reacquire_timeout: Some(config.window_reacquire())
+                .filter(|policy| policy.on_timeout == ReacquireTimeout::StopRecording)
+                .map(|policy| policy.timeout),
+        };
+        if let Some(required) = conditions.min_free_space {
+            if let Some(free_bytes) = free_disk_space(config.output_path()) {
+                if free_bytes < required {
+                    return Err(RecorderError::InsufficientDiskSpace {
+                        free_bytes,
+                        required,
+                    });
+                }
+            }
         }
 
-        USE_EXACT_MATCH.with(|cell| {
-            *cell.borrow_mut() = use_exact_match;
-        });
+        if let Ok(mut current_output_path) = self.current_output_path.write() {
+            *current_output_path = Some(config.output_path().clone());
+        }
 
-        *rec_inner = Some(
-            RecorderInner::init_with_exact_match(&self.config, proc_name, use_exact_match)
-                .map_err(|e| RecorderError::FailedToStart(e.to_string()))?,
+        let window_pick = self.window_pick.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on window_pick".to_string())
+        })?;
+        let window = target
+            .pick(&window_pick)
+            .ok_or_else(|| RecorderError::FailedToStart("No window found".to_string()))?;
+        info!(
+            "Recording window {:?} '{}' of {} (pid {})",
+            window.hwnd, window.title, window.exe_name, window.process_id
         );
 
+        let scheduled_start = *self.scheduled_start.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on scheduled_start".to_string())
+        })?;
+        if let Some(start_at) = scheduled_start.filter(|start_at| *start_at > SystemTime::now()) {
+            drop(rec_inner);
+            self.schedule_start(
+                start_at,
+                config,
+                target,
+                window.hwnd,
+                &excluded_windows,
+                conditions,
+            )?;
+            return Ok(window);
+        }
+
+        // A prepared session serves one start; what it set up is released once the recording
+        // holds its own
+        let prepared = self
+            .prepared
+            .write()
+            .map_err(|_| {
+                RecorderError::Generic("Failed to acquire write lock on prepared".to_string())
+            })?
+            .take();
+        *rec_inner = Some(launch(
+            &config,
+            &target,
+            window.hwnd,
+            &excluded_windows,
+            prepared,
+            &self.preview,
+            conditions,
+        )?);
+
+        Ok(window)
+    }
+
+    // Start the recording of `hwnd` on a background thread at `start_at`, with everything but
+    // the recording itself already resolved
+    fn schedule_start(
+        &self,
+        start_at: SystemTime,
+        config: RecorderConfig,
+        target: WindowTarget,
+        hwnd: HWND,
+        excluded_windows: &[HWND],
+        conditions: StopConditions,
+    ) -> Result<()> {
+        if let Ok(delay) = start_at.duration_since(SystemTime::now()) {
+            info!("Recording scheduled to start in {:?}", delay);
+        }
+        let rec_inner = self.rec_inner.clone();
+        let prepared = self.prepared.clone();
+        let preview = self.preview.clone();
+        let excluded_windows = excluded_windows.to_vec();
+        let pending = ScheduledStart::spawn(start_at, move || {
+            let prepared = prepared
+                .write()
+                .ok()
+                .and_then(|mut prepared| prepared.take());
+            match launch(
+                &config,
+                &target,
+                hwnd,
+                &excluded_windows,
+                prepared,
+                &preview,
+                conditions,
+            ) {
+                Ok(inner) => match rec_inner.write() {
+                    Ok(mut rec_inner) => *rec_inner = Some(inner),
+                    Err(_) => error!("Failed to acquire write lock on rec_inner"),
+                },
+                Err(e) => {
+                    error!("Scheduled start failed: {}", e);
+                    EventEmitter::new(config.event_callback().cloned()).emit(
+                        RecorderEvent::ScheduledStartFailed {
+                            message: e.to_string(),
+                        },
+                    );
+                }
+            }
+        })?;
+        *self.pending_start.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire pending_start lock".to_string())
+        })? = Some(pending);
+        Ok(())
+    }
+
+    // Whether a scheduled start is still waiting, or starting right now
+    fn start_pending(&self) -> Result<bool> {
+        let pending_start = self.pending_start.lock().map_err(|_| {
+            RecorderError::Generic("Failed to acquire pending_start lock".to_string())
+        })?;
+        Ok(pending_start
+            .as_ref()
+            .is_some_and(ScheduledStart::is_pending))
+    }
+
+    // Cancel a scheduled start that has not happened yet, returning whether one was cancelled
+    fn cancel_pending_start(&self) -> Result<bool> {
+        let pending = self
+            .pending_start
+            .lock()
+            .map_err(|_| {
+                RecorderError::Generic("Failed to acquire pending_start lock".to_string())
+            })?
+            .take();
+        Ok(pending.is_some_and(ScheduledStart::cancel))
+    }
+
+    /// Do the slow part of starting ahead of time: load Media Foundation, look up the video
+    /// encoder and load its driver, and create the D3D11 device for the target window's adapter
+    ///
+    /// The next `start_recording` then only creates the sink writer and starts the capture,
+    /// e.g. for recording instantly on a hotkey. The sink writer still creates its own encoder,
+    /// but with the DLL and driver already loaded that no longer dominates the start. What no
+    /// longer fits by then, such as the device of a window that moved to another GPU, is set up
+    /// again. Without a matching window yet, only the encoder is prepared. Preparing again
+    /// replaces the previous preparation; preparing while recording fails with
+    /// `RecordingInProgress`.
+    pub fn prepare(&self) -> Result<()> {
+        if self.is_recording() {
+            return Err(RecorderError::RecordingInProgress);
+        }
+        let target = self.capture_target()?;
+        let window_pick = self.window_pick.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on window_pick".to_string())
+        })?;
+        let hwnd = target.pick(&window_pick).map(|window| window.hwnd);
+        if hwnd.is_none() {
+            info!(
+                "No window matches {:?} yet, preparing without a device",
+                target
+            );
+        }
+
+        let session = PreparedSession::prepare(&self.config, hwnd)?;
+        *self.prepared.write().map_err(|_| {
+            RecorderError::Generic("Failed to acquire write lock on prepared".to_string())
+        })? = Some(session);
         Ok(())
     }
 
-    /// Stop the current recording
+    /// Stop the current recording, or cancel one waiting for its scheduled start
     pub fn stop_recording(&self) -> Result<()> {
         if self.config.debug_mode() {
             info!("Stopping recording");
         }
+        if self.cancel_pending_start()? {
+            return Ok(());
+        }
+
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        stop::stop_and_notify(inner)
+    }
+
+    /// Pause the current recording; nothing is written until `resume_recording`
+    /// Capture keeps running, so resuming is immediate and the file continues without a gap
+    pub fn pause_recording(&self) -> Result<()> {
+        self.set_paused(true)
+    }
+
+    /// Resume a recording paused with `pause_recording`
+    pub fn resume_recording(&self) -> Result<()> {
+        self.set_paused(false)
+    }
+
+    /// Whether a recording is running
+    pub fn is_recording(&self) -> bool {
+        self.rec_inner
+            .read()
+            .ok()
+            .and_then(|rec_inner| rec_inner.as_ref().map(|inner| inner.is_recording()))
+            .unwrap_or(false)
+    }
+
+    /// Whether the current recording is paused
+    pub fn is_paused(&self) -> bool {
+        self.rec_inner
+            .read()
+            .ok()
+            .and_then(|rec_inner| rec_inner.as_ref().map(|inner| inner.is_paused()))
+            .unwrap_or(false)
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.set_paused(paused)
+    }
+
+    /// Stop the current recording without blocking
+    /// Joining the threads and finalizing the file happens on a background thread; progress
+    /// and completion are reported through the event callback and the returned handle
+    pub fn stop_recording_async(&self) -> Result<StopHandle> {
+        if self.config.debug_mode() {
+            info!("Stopping recording asynchronously");
+        }
+        if self.cancel_pending_start()? {
+            return Ok(StopHandle::finished());
+        }
 
         let rec_inner = self.rec_inner.read().map_err(|_| {
             RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
@@ -131,7 +555,75 @@ impl Recorder {
             return Err(RecorderError::NoRecorderBound);
         };
 
-        inner.stop()
+        stop::spawn_stop(inner.clone())
+    }
+
+    // Resolve the window targeting options; a window handle wins over a process ID, which wins
+    // over an executable, which wins over a title match
+    fn capture_target(&self) -> Result<WindowTarget> {
+        let window_handle = *self.window_handle.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on window_handle".to_string())
+        })?;
+        if let Some(hwnd) = window_handle {
+            return Ok(WindowTarget::Handle(hwnd));
+        }
+
+        let process_id = *self.process_id.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on process_id".to_string())
+        })?;
+        if let Some(process_id) = process_id {
+            return Ok(WindowTarget::ProcessId(process_id));
+        }
+
+        let process_exe = self.process_exe.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on process_exe".to_string())
+        })?;
+        if let Some(executable) = &*process_exe {
+            return Ok(WindowTarget::Executable(executable.clone()));
+        }
+
+        let process_name = self.process_name.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on process_name".to_string())
+        })?;
+        let use_exact_match = *self.use_exact_match.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on use_exact_match".to_string())
+        })?;
+        match &*process_name {
+            Some(title) => Ok(WindowTarget::Title {
+                title: title.clone(),
+                exact_match: use_exact_match,
+            }),
+            None => Err(RecorderError::NoProcessSpecified),
+        }
+    }
+
+    // Poll until the target resolves to a window, without holding any of the recorder's locks
+    fn wait_for_window(&self, target: &WindowTarget, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        if target.resolve().is_some() {
+            return Ok(());
+        }
+        info!("Waiting up to {:?} for window: {:?}", timeout, target);
+
+        loop {
+            std::thread::sleep(WINDOW_WAIT_POLL_INTERVAL);
+            if let Some(hwnd) = target.resolve() {
+                let waited = started.elapsed();
+                let title = get_window_title(hwnd);
+                info!("Window {:?} ({}) appeared after {:?}", hwnd, title, waited);
+                EventEmitter::new(self.config.event_callback().cloned()).emit(
+                    RecorderEvent::WindowFound {
+                        hwnd,
+                        title,
+                        waited,
+                    },
+                );
+                return Ok(());
+            }
+            if started.elapsed() >= timeout {
+                return Err(RecorderError::WindowWaitTimedOut(timeout));
+            }
+        }
     }
 
     /// Get the current configuration
@@ -139,7 +631,46 @@ impl Recorder {
         &self.config
     }
 
-    /// Save the content of the replay buffer to a file
+    /// Get the file the current (or last) recording is written to
+    /// This differs from `config().output_path()` when an output template is used
+    pub fn output_path(&self) -> Option<PathBuf> {
+        self.current_output_path
+            .read()
+            .ok()
+            .and_then(|path| path.clone())
+    }
+
+    // Render the output template with the next index, creating parent directories
+    fn next_templated_path(&self, proc_name: &str) -> Result<PathBuf> {
+        let Some(template) = self.config.output_template() else {
+            return Err(RecorderError::Generic(
+                "No output template configured".to_string(),
+            ));
+        };
+
+        let mut index = self.output_index.write().map_err(|_| {
+            RecorderError::Generic("Failed to acquire write lock on output_index".to_string())
+        })?;
+        *index += 1;
+
+        let path = render_output_template(template, proc_name, *index);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent).map_err(|e| {
+                    RecorderError::Generic(format!(
+                        "Failed to create output directory {}: {}",
+                        parent.display(),
+                        e
+                    ))
+                })?;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Save the content of the replay buffer to a file, returning once it is finalized
+    /// The file is written by the replay save worker, see `save_replay_in_background`
     pub fn save_replay(&self, output_path: &str) -> Result<()> {
         if !self.config.enable_replay_buffer() {
             return Err(RecorderError::Generic(
@@ -157,6 +688,425 @@ impl Recorder {
 
         inner.save_replay(output_path)
     }
+
+    /// Save the content of the replay buffer to a file without blocking
+    /// The buffer is copied right away and the file written on a background thread, after any
+    /// replays queued before it; progress and completion are reported through the event
+    /// callback and the returned handle
+    pub fn save_replay_in_background(&self, output_path: &str) -> Result<ReplaySaveHandle> {
+        if !self.config.enable_replay_buffer() {
+            return Err(RecorderError::Generic(
+                "Replay buffer is not enabled".to_string(),
+            ));
+        }
+
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.save_replay_in_background(output_path)
+    }
+
+    /// Save the last `duration` of the replay buffer as an endlessly looping animated GIF
+    /// `fps` should be low (10-15) and `max_width` small (e.g. 480) to keep the file shareable
+    pub fn save_replay_gif(
+        &self,
+        output_path: &str,
+        duration: Duration,
+        fps: u32,
+        max_width: u32,
+    ) -> Result<()> {
+        if !self.config.enable_replay_buffer() {
+            return Err(RecorderError::Generic(
+                "Replay buffer is not enabled".to_string(),
+            ));
+        }
+
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.save_replay_gif(output_path, duration, fps, max_width)
+    }
+
+    /// Receive downscaled RGBA frames of what is being recorded at the `preview` rate, e.g. to
+    /// show a live preview in a UI without capturing the window a second time
+    ///
+    /// The receiver gets the frames of this and every later recording until it is dropped.
+    /// Frames are only read back from the GPU while a receiver exists, and one that falls more
+    /// than two frames behind misses the newer ones.
+    pub fn preview_receiver(&self) -> Receiver<PreviewFrame> {
+        self.preview.subscribe()
+    }
+
+    /// Get the shared D3D11 texture holding the latest frame of the recording, with
+    /// `shared_preview_texture` enabled
+    /// Returns None until the first frame was copied into it. A new texture replaces it when the
+    /// capture moves to another device, announced by `RecorderEvent::SharedPreviewTextureChanged`
+    pub fn shared_preview_texture(&self) -> Result<Option<SharedPreviewTexture>> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.shared_preview_texture()
+    }
+
+    /// Get the codec private data (SPS/PPS, plus VPS for HEVC) of the video stream
+    /// Returns None if the encoder has not published a sequence header
+    pub fn video_stream_header(&self) -> Result<Option<StreamHeader>> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.video_stream_header()
+    }
+
+    /// Get the number of frames encoded and bytes written so far
+    /// Use `EncoderStats::effective_bitrate` for the bitrate the encoder actually produces
+    pub fn encoder_stats(&self) -> Result<EncoderStats> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.encoder_stats()
+    }
+
+    /// Get CPU and GPU timings of the capture copy, convert and encode submit stages since the
+    /// recording started, to find the stage causing frame drops
+    /// Returns None unless `profiling` is enabled in the config
+    pub fn profile_report(&self) -> Result<Option<ProfileReport>> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        Ok(inner.profile_report())
+    }
+
+    /// Get the latest peak/RMS levels of the system audio and microphone sources
+    /// Values are linear in 0.0..=1.0 of full scale and are updated per audio packet
+    pub fn audio_levels(&self) -> Result<AudioLevels> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.audio_levels()
+    }
+
+    /// Get the audio mixer totals: packets mixed or passed through on their own, frames that
+    /// could not be paired, queue depths and the microphone clock drift being corrected
+    pub fn mixer_stats(&self) -> Result<MixerStats> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.mixer_stats()
+    }
+
+    /// Get the capture texture pool usage: textures in use now and at peak, how often a frame
+    /// had to wait for a free texture and how many were dropped because none came free in time
+    pub fn pool_stats(&self) -> Result<PoolStats> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.pool_stats()
+    }
+
+    /// Get the totals of the queue between the capture and the encoder: frames queued now and
+    /// at peak, frames dropped by the backpressure policy and time the capture spent blocked
+    pub fn queue_stats(&self) -> Result<FrameQueueStats> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.queue_stats()
+    }
+
+    /// Get the memory held right now by the capture and conversion textures, the frames queued
+    /// for the encoder and the replay buffer, and how many replay samples the `memory_limits`
+    /// dropped
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.memory_usage()
+    }
+
+    /// Replace the regions blacked out or pixelated in the recording, e.g. to follow a chat
+    /// window as it moves; takes effect from the next frame
+    pub fn update_privacy_masks(&self, masks: Vec<MaskRect>) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.update_privacy_masks(masks)
+    }
+
+    /// Change the audio shift of the current recording to `milliseconds`, e.g. from a calibration
+    /// tool; takes effect from the next audio sample
+    ///
+    /// Lowering the offset drops the audio that would overlap what was already written, raising
+    /// it leaves a short silence.
+    pub fn set_av_sync_offset(&self, milliseconds: i64) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.set_av_sync_offset(milliseconds)
+    }
+
+    /// Names of the audio sources being mixed, sorted, for [`Recorder::set_audio_gain`] and
+    /// [`Recorder::set_audio_muted`]
+    ///
+    /// Captured processes are listed once they have delivered audio.
+    pub fn audio_sources(&self) -> Result<Vec<String>> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        Ok(inner.audio_sources())
+    }
+
+    /// Set the gain of one audio source while recording, clamped to 0.0..=2.0
+    ///
+    /// Sources are named `"system"` ([`crate::SYSTEM_AUDIO_SOURCE`]) and `"microphone"`
+    /// ([`crate::MICROPHONE_SOURCE`]), each process captured by `AudioSource::Processes` after
+    /// [`crate::AudioProcess::source_name`] and each extra microphone after its device; naming
+    /// one that is not recorded is an error.
+    pub fn set_audio_gain(&self, source: &str, gain: f32) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.set_audio_gain(source, gain)
+    }
+
+    /// Mute or unmute one audio source while recording; see [`Recorder::set_audio_gain`] for the
+    /// source names
+    ///
+    /// A muted source is mixed as silence, so the audio track keeps running.
+    pub fn set_audio_muted(&self, source: &str, muted: bool) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.set_audio_muted(source, muted)
+    }
+
+    /// Record an application-rendered frame at `timestamp` (time since the recording started)
+    ///
+    /// Requires `video_source(VideoSource::Submitted)`. The BGRA texture must live on the
+    /// recording device (see `external_device`); it is copied before this returns, so it can be
+    /// reused for the next frame. Frames not later than the previous one are dropped.
+    pub fn submit_frame(&self, texture: &ID3D11Texture2D, timestamp: Duration) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.submit_frame(texture, timestamp)
+    }
+
+    /// Bookmark the current moment of the recording (e.g. a kill or event to edit later)
+    /// Markers are written to a `.chapters.json` sidecar next to the output when recording stops
+    /// Returns the offset of the marker from the start of the recording
+    pub fn add_marker(&self, label: &str) -> Result<Duration> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.add_marker(label)
+    }
+
+    /// Attach `name` and an application `payload` (e.g. JSON) to the frame being captured now
+    /// The tag is timestamped with that frame's position in the video, added as a chapter marker
+    /// named `name` and written to the metadata sidecar; read the tags back with `frame_tags()`
+    pub fn tag_frame(&self, name: &str, payload: &str) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.tag_frame(name, payload)
+    }
+
+    /// Iterate over the tags added with `tag_frame()`, in the order they were added
+    /// Tags are placed once their frame is written, so the list is complete after the recording
+    /// stops
+    pub fn frame_tags(&self) -> Result<std::vec::IntoIter<FrameTag>> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        Ok(inner.frame_tags()?.into_iter())
+    }
+
+    /// Save the content of the replay buffer to a path derived from the output template
+    /// Returns the path the replay was written to
+    pub fn save_replay_from_template(&self) -> Result<PathBuf> {
+        let target = self.capture_target()?;
+        let path = self.next_templated_path(&target_label(&target))?;
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| RecorderError::Generic("Invalid path string".to_string()))?;
+        self.save_replay(path_str)?;
+
+        Ok(path)
+    }
+
+    /// Start recording a clip that begins with the last `preroll` of the replay buffer and
+    /// continues with live frames, with continuous timestamps
+    /// Requires a running recording with the replay buffer enabled; the clip is finalized by
+    /// `stop_preroll_recording()` or when the recording stops
+    pub fn start_recording_with_preroll(&self, preroll: Duration, output_path: &str) -> Result<()> {
+        if !self.config.enable_replay_buffer() {
+            return Err(RecorderError::Generic(
+                "Replay buffer is not enabled".to_string(),
+            ));
+        }
+
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.start_preroll_segment(preroll, output_path)
+    }
+
+    /// Finalize the clip started with `start_recording_with_preroll`
+    pub fn stop_preroll_recording(&self) -> Result<()> {
+        let rec_inner = self.rec_inner.read().map_err(|_| {
+            RecorderError::Generic("Failed to acquire read lock on rec_inner".to_string())
+        })?;
+
+        let Some(ref inner) = *rec_inner else {
+            return Err(RecorderError::NoRecorderBound);
+        };
+
+        inner.stop_preroll_segment()
+    }
+}
+
+/// Start recording `hwnd` and supervise the stop conditions, if there are any
+fn launch(
+    config: &RecorderConfig,
+    target: &WindowTarget,
+    hwnd: HWND,
+    excluded_windows: &[HWND],
+    prepared: Option<PreparedSession>,
+    preview: &PreviewSubscribers,
+    conditions: StopConditions,
+) -> Result<Arc<RecorderInner>> {
+    let inner = Arc::new(RecorderInner::init_with_window(
+        config,
+        target,
+        hwnd,
+        excluded_windows,
+        prepared.as_ref(),
+        preview,
+    )?);
+    if !conditions.is_empty() {
+        inner.spawn_supervisor(conditions);
+    }
+    Ok(inner)
+}
+
+/// Name used for `{process}` in output templates
+fn target_label(target: &WindowTarget) -> String {
+    match target {
+        WindowTarget::Title { title, .. } => title.clone(),
+        WindowTarget::ProcessId(process_id) => {
+            get_process_exe_name(*process_id).unwrap_or_else(|| format!("pid{}", process_id))
+        }
+        WindowTarget::Executable(executable) => Path::new(executable).file_name().map_or_else(
+            || executable.clone(),
+            |name| name.to_string_lossy().to_string(),
+        ),
+        WindowTarget::Handle(hwnd) => get_process_exe_name(get_window_process_id(*hwnd))
+            .unwrap_or_else(|| get_window_title(*hwnd)),
+    }
 }
 
 /// Debug callback to list all window titles
@@ -0,0 +1,92 @@
+use crate::logging::{info, warn};
+use std::time::Instant;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11DeviceContext};
+
+use super::config::RecorderConfig;
+use super::inner::{recording_device, resolve_video_encoder, target_encoder_adapter};
+use crate::capture::window_adapter_changed;
+use crate::device::capabilities::load_encoder;
+use crate::device::VideoEncoder;
+use crate::error::Result;
+use crate::processing::media;
+
+/// Start-up work done ahead of `start_recording` by `Recorder::prepare`
+///
+/// Holds Media Foundation, the video encoder, which has been created once so its driver is
+/// loaded, and the D3D11 device for the target window's adapter. Whatever no longer fits when
+/// the recording starts is set up again then.
+pub(crate) struct PreparedSession {
+    /// Window the device was created for
+    hwnd: Option<HWND>,
+    device: Option<ID3D11Device>,
+    /// Adapter the video encoder was looked up on
+    encoder_adapter: Option<u64>,
+    video_encoder: VideoEncoder,
+}
+
+impl PreparedSession {
+    /// Prepare a recording of `hwnd` with `config`; without a window only the encoder is
+    /// prepared
+    pub fn prepare(config: &RecorderConfig, hwnd: Option<HWND>) -> Result<Self> {
+        let started = Instant::now();
+        unsafe { media::init_media_foundation()? };
+        let session = unsafe { Self::prepare_with_media_foundation(config, hwnd) };
+        match &session {
+            Ok(_) => info!("Recording prepared in {:?}", started.elapsed()),
+            Err(_) => {
+                let _ = unsafe { media::shutdown_media_foundation() };
+            }
+        }
+        session
+    }
+
+    unsafe fn prepare_with_media_foundation(
+        config: &RecorderConfig,
+        hwnd: Option<HWND>,
+    ) -> Result<Self> {
+        let encoder_adapter = match hwnd {
+            Some(hwnd) => target_encoder_adapter(config, hwnd),
+            None => config.encoder_adapter(),
+        };
+        let video_encoder = resolve_video_encoder(config, encoder_adapter)?;
+        if let Err(e) = load_encoder(&video_encoder) {
+            warn!("Failed to load encoder '{}': {}", video_encoder.name, e);
+        }
+
+        let device = match hwnd {
+            Some(hwnd) => Some(recording_device(config, hwnd)?.0),
+            None => None,
+        };
+        Ok(Self {
+            hwnd,
+            device,
+            encoder_adapter,
+            video_encoder,
+        })
+    }
+
+    /// The prepared encoder, if it was looked up on `encoder_adapter` as well
+    pub fn video_encoder(&self, encoder_adapter: Option<u64>) -> Option<&VideoEncoder> {
+        (self.encoder_adapter == encoder_adapter).then_some(&self.video_encoder)
+    }
+
+    /// The prepared device and its immediate context, if it was created for `hwnd` and the
+    /// window is still on the device's adapter
+    pub unsafe fn device_for(&self, hwnd: HWND) -> Option<(ID3D11Device, ID3D11DeviceContext)> {
+        let device = self.device.as_ref().filter(|_| self.hwnd == Some(hwnd))?;
+        if window_adapter_changed(device, hwnd) {
+            info!("Window moved to another adapter since it was prepared");
+            return None;
+        }
+        let context = device.GetImmediateContext().ok()?;
+        Some((device.clone(), context))
+    }
+}
+
+impl Drop for PreparedSession {
+    fn drop(&mut self) {
+        // The recording holds Media Foundation on its own
+        let _ = unsafe { media::shutdown_media_foundation() };
+    }
+}
@@ -0,0 +1,249 @@
+use crate::logging::warn;
+use std::time::Duration;
+
+use super::config::RecorderConfigBuilder;
+use crate::device::VideoEncoderType;
+use crate::processing::media::AudioCodec;
+use crate::processing::scaler::ScalingQuality;
+
+/// Discord's upload limit without Nitro
+const DISCORD_UPLOAD_LIMIT: u64 = 25 * 1000 * 1000;
+/// Share of a file taken by the MP4 container rather than the streams
+const CONTAINER_OVERHEAD: f64 = 0.03;
+/// Lowest video bitrate a size budget is allowed to go down to; below it 720p is unwatchable
+const MIN_BUDGET_BITRATE: u32 = 300_000;
+
+/// Settings for common destinations, applied with `RecorderConfigBuilder::preset`
+///
+/// A preset sets the resolution, frame rate, codec, bitrate and audio codec. Options set on the
+/// builder after the preset override it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// 720p30 H.264 with `target_file_size` set so a recording of `duration`, and every saved
+    /// replay, stays under Discord's 25 MB upload limit
+    DiscordUnder25MB { duration: Duration },
+    /// 1080p60 H.264 at YouTube's recommended 12 Mbps
+    Youtube1080p60,
+    /// 1080p60 HEVC at a high bitrate with lossless FLAC audio, for keeping footage to edit
+    ArchivalHevc,
+    /// 1080p30 H.264 with `target_file_size(bytes, duration)`
+    TargetFileSize { bytes: u64, duration: Duration },
+}
+
+impl Preset {
+    pub(crate) fn apply(self, builder: RecorderConfigBuilder) -> RecorderConfigBuilder {
+        match self {
+            Preset::DiscordUnder25MB { duration } => builder
+                .output_dimensions(1280, 720)
+                .fps(30, 1)
+                .video_encoder(VideoEncoderType::H264)
+                .target_file_size(DISCORD_UPLOAD_LIMIT, duration)
+                .audio_codec(AudioCodec::Aac { bitrate: 128_000 }),
+            Preset::Youtube1080p60 => builder
+                .output_dimensions(1920, 1080)
+                .fps(60, 1)
+                .video_encoder(VideoEncoderType::H264)
+                .video_bitrate(12_000_000)
+                .audio_codec(AudioCodec::Aac { bitrate: 192_000 }),
+            Preset::ArchivalHevc => builder
+                .output_dimensions(1920, 1080)
+                .fps(60, 1)
+                .video_encoder(VideoEncoderType::HEVC)
+                .video_bitrate(40_000_000)
+                .scaling_quality(ScalingQuality::HighQuality)
+                .audio_codec(AudioCodec::Flac),
+            Preset::TargetFileSize { bytes, duration } => builder
+                .output_dimensions(1920, 1080)
+                .fps(30, 1)
+                .video_encoder(VideoEncoderType::H264)
+                .target_file_size(bytes, duration)
+                .audio_codec(AudioCodec::Aac { bitrate: 128_000 }),
+        }
+    }
+}
+
+/// Video bitrate in bits per second that keeps a recording of `duration` with `audio` under
+/// `bytes`, leaving room for the container
+///
+/// The audio track is always budgeted for, so the file also fits when audio is recorded. None for
+/// lossless audio, which has no fixed bitrate and which `target_file_size` rejects as well
+pub fn bitrate_for_file_size(bytes: u64, duration: Duration, audio: AudioCodec) -> Option<u32> {
+    Some(video_budget(bytes, duration, audio_budget(audio)?))
+}
+
+/// Audio bitrate a size budget reserves, None for lossless audio whose size is unknown
+pub(crate) fn audio_budget(audio: AudioCodec) -> Option<u32> {
+    match audio {
+        AudioCodec::Aac { bitrate } => Some(bitrate),
+        AudioCodec::Flac | AudioCodec::Pcm => None,
+    }
+}
+
+/// Video bitrate left for a file of `bytes` and `duration` next to `audio_bitrate` of audio
+pub(crate) fn video_budget(bytes: u64, duration: Duration, audio_bitrate: u32) -> u32 {
+    let seconds = duration.as_secs_f64().max(1.0);
+    let stream_bits = bytes as f64 * 8.0 * (1.0 - CONTAINER_OVERHEAD);
+    let video_bitrate = stream_bits / seconds - audio_bitrate as f64;
+    if video_bitrate < MIN_BUDGET_BITRATE as f64 {
+        warn!(
+            "{} bytes are not enough for {:?} of video, using {} bps; the file will be larger",
+            bytes, duration, MIN_BUDGET_BITRATE
+        );
+        return MIN_BUDGET_BITRATE;
+    }
+    video_bitrate.min(u32::MAX as f64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::config::RecorderConfig;
+
+    const MB: u64 = 1000 * 1000;
+
+    fn build(preset: Preset) -> RecorderConfig {
+        RecorderConfig::builder().preset(preset).build().unwrap()
+    }
+
+    #[test]
+    fn youtube_preset_values() {
+        let config = build(Preset::Youtube1080p60);
+        assert_eq!(
+            (config.output_width(), config.output_height()),
+            (1920, 1080)
+        );
+        assert_eq!((config.fps_num(), config.fps_den()), (60, 1));
+        assert_eq!(*config.video_encoder(), VideoEncoderType::H264);
+        assert_eq!(config.video_bitrate(), 12_000_000);
+        assert_eq!(config.audio_codec(), AudioCodec::Aac { bitrate: 192_000 });
+        assert_eq!(config.target_file_size(), None);
+    }
+
+    #[test]
+    fn archival_preset_values() {
+        let config = build(Preset::ArchivalHevc);
+        assert_eq!(
+            (config.output_width(), config.output_height()),
+            (1920, 1080)
+        );
+        assert_eq!((config.fps_num(), config.fps_den()), (60, 1));
+        assert_eq!(*config.video_encoder(), VideoEncoderType::HEVC);
+        assert_eq!(config.video_bitrate(), 40_000_000);
+        assert_eq!(config.scaling_quality(), ScalingQuality::HighQuality);
+        assert_eq!(config.audio_codec(), AudioCodec::Flac);
+    }
+
+    #[test]
+    fn discord_preset_fits_upload_limit() {
+        let duration = Duration::from_secs(120);
+        let config = build(Preset::DiscordUnder25MB { duration });
+        assert_eq!((config.output_width(), config.output_height()), (1280, 720));
+        assert_eq!((config.fps_num(), config.fps_den()), (30, 1));
+        assert_eq!(
+            config.target_file_size(),
+            Some((DISCORD_UPLOAD_LIMIT, duration))
+        );
+        // 25 MB over 120 s is 1.667 Mbps, 1.617 Mbps after the container, 1.489 Mbps after audio
+        assert_eq!(config.video_bitrate(), 1_488_666);
+    }
+
+    #[test]
+    fn target_size_preset_values() {
+        let duration = Duration::from_secs(60);
+        let config = build(Preset::TargetFileSize {
+            bytes: 50 * MB,
+            duration,
+        });
+        assert_eq!(
+            (config.output_width(), config.output_height()),
+            (1920, 1080)
+        );
+        assert_eq!((config.fps_num(), config.fps_den()), (30, 1));
+        assert_eq!(config.target_file_size(), Some((50 * MB, duration)));
+        assert_eq!(
+            config.video_bitrate(),
+            video_budget(50 * MB, duration, 128_000)
+        );
+    }
+
+    #[test]
+    fn budget_leaves_room_for_audio_and_container() {
+        // 10 MB over 100 s is 800 kbps, 776 kbps after the container, 648 kbps after the audio
+        let bitrate = video_budget(10 * MB, Duration::from_secs(100), 128_000);
+        assert_eq!(bitrate, 648_000);
+        assert_eq!(
+            bitrate_for_file_size(
+                10 * MB,
+                Duration::from_secs(100),
+                AudioCodec::Aac { bitrate: 128_000 }
+            ),
+            Some(648_000)
+        );
+    }
+
+    #[test]
+    fn budget_never_goes_below_minimum() {
+        assert_eq!(
+            video_budget(MB, Duration::from_secs(600), 128_000),
+            MIN_BUDGET_BITRATE
+        );
+        // More audio than the whole budget holds
+        assert_eq!(
+            video_budget(MB, Duration::from_secs(10), 10_000_000),
+            MIN_BUDGET_BITRATE
+        );
+    }
+
+    #[test]
+    fn budget_counts_short_durations_as_one_second() {
+        assert_eq!(
+            video_budget(MB, Duration::from_millis(100), 0),
+            video_budget(MB, Duration::from_secs(1), 0)
+        );
+    }
+
+    #[test]
+    fn lossless_audio_has_no_budget() {
+        for audio in [AudioCodec::Flac, AudioCodec::Pcm] {
+            assert_eq!(
+                bitrate_for_file_size(10 * MB, Duration::from_secs(100), audio),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn later_video_bitrate_overrides_size_preset() {
+        let config = RecorderConfig::builder()
+            .preset(Preset::DiscordUnder25MB {
+                duration: Duration::from_secs(60),
+            })
+            .video_bitrate(2_000_000)
+            .build()
+            .unwrap();
+        assert_eq!(config.target_file_size(), None);
+        assert_eq!(config.video_bitrate(), 2_000_000);
+    }
+
+    #[test]
+    fn later_target_file_size_overrides_bitrate_preset() {
+        let config = RecorderConfig::builder()
+            .preset(Preset::Youtube1080p60)
+            .target_file_size(100 * MB, Duration::from_secs(100))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.video_bitrate(),
+            video_budget(100 * MB, Duration::from_secs(100), 192_000)
+        );
+    }
+
+    #[test]
+    fn size_target_rejects_lossless_audio() {
+        let result = RecorderConfig::builder()
+            .preset(Preset::ArchivalHevc)
+            .target_file_size(100 * MB, Duration::from_secs(100))
+            .build();
+        assert!(result.is_err());
+    }
+}
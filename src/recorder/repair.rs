@@ -0,0 +1,135 @@
+use crate::logging::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::{RecorderError, Result};
+
+/// Outcome of [`repair_mp4`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Mp4RepairReport {
+    /// Complete fragments left in the file, 0 for a regular (non-fragmented) MP4
+    pub fragments: usize,
+    /// Bytes of incomplete data cut off the end of the file; 0 if the file was intact
+    pub truncated_bytes: u64,
+}
+
+/// Top-level box of an MP4 file
+struct Mp4Box {
+    kind: [u8; 4],
+    end: u64,
+}
+
+/// Salvage a recording that was interrupted before it could be finalized
+///
+/// Recordings written with `fragmented_output(true)` are made playable again by cutting the
+/// incomplete fragment off the end of the file, in place. A regular MP4 only gets its index
+/// (`moov` box) when it is finalized, so an interrupted one cannot be recovered and an error is
+/// returned.
+pub fn repair_mp4(path: impl AsRef<Path>) -> Result<Mp4RepairReport> {
+    let path = path.as_ref();
+    let io_error = |e: std::io::Error| {
+        RecorderError::Generic(format!("Failed to repair {}: {}", path.display(), e))
+    };
+
+    let mut file = File::open(path).map_err(io_error)?;
+    let file_len = file.metadata().map_err(io_error)?.len();
+
+    let mut has_moov = false;
+    let mut pending_moof = false;
+    let mut fragments = 0;
+    let mut valid_end = 0;
+    let mut offset = 0;
+    while offset < file_len {
+        let Some(mp4_box) = read_box_header(&mut file, offset, file_len).map_err(io_error)? else {
+            break;
+        };
+        if mp4_box.end > file_len {
+            break;
+        }
+        match &mp4_box.kind {
+            b"moov" => {
+                has_moov = true;
+                valid_end = mp4_box.end;
+            }
+            // A fragment is only usable once its media data is complete
+            b"moof" => pending_moof = true,
+            b"mdat" if pending_moof => {
+                pending_moof = false;
+                fragments += 1;
+                valid_end = mp4_box.end;
+            }
+            _ if !pending_moof => valid_end = mp4_box.end,
+            _ => {}
+        }
+        offset = mp4_box.end;
+    }
+
+    if !has_moov {
+        return Err(RecorderError::Generic(format!(
+            "{} has no moov box and cannot be recovered; record with fragmented_output(true) to \
+             make interrupted recordings recoverable",
+            path.display()
+        )));
+    }
+
+    let truncated_bytes = file_len - valid_end;
+    if truncated_bytes > 0 {
+        warn!(
+            "Cutting {} bytes of incomplete data off {}",
+            truncated_bytes,
+            path.display()
+        );
+        drop(file);
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid_end))
+            .map_err(io_error)?;
+    }
+
+    info!(
+        "Repaired {}: {} fragments kept, {} bytes removed",
+        path.display(),
+        fragments,
+        truncated_bytes
+    );
+    Ok(Mp4RepairReport {
+        fragments,
+        truncated_bytes,
+    })
+}
+
+// Read the box header at `offset`; None if it is truncated or malformed
+fn read_box_header(file: &mut File, offset: u64, file_len: u64) -> std::io::Result<Option<Mp4Box>> {
+    if file_len - offset < 8 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; 8];
+    file.read_exact(&mut header)?;
+    let kind = [header[4], header[5], header[6], header[7]];
+
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        // The box extends to the end of the file
+        0 => file_len - offset,
+        // 64-bit size follows the type
+        1 => {
+            if file_len - offset < 16 {
+                return Ok(None);
+            }
+            let mut large_size = [0u8; 8];
+            file.read_exact(&mut large_size)?;
+            u64::from_be_bytes(large_size)
+        }
+        size => size as u64,
+    };
+    if size < 8 {
+        return Ok(None);
+    }
+
+    Ok(Some(Mp4Box {
+        kind,
+        end: offset.saturating_add(size),
+    }))
+}
@@ -0,0 +1,279 @@
+use crate::logging::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use super::config::RecorderConfig;
+use super::events::{EventEmitter, RecorderEvent};
+use super::inner::{resolve_video_encoder, sink_writer_settings};
+use crate::error::{RecorderError, Result};
+use crate::processing::image::{self, Nv12Reader};
+use crate::processing::media::{self, SinkWriterSettings, VideoEncoding};
+use crate::processing::replay::ReplayClip;
+use crate::processing::video::PixelPipeline;
+use crate::types::duration_to_hns;
+
+/// Number of `ReplaySaveProgress` events per saved replay
+const PROGRESS_STEPS: usize = 10;
+
+/// Handle to a replay being saved by `Recorder::save_replay_in_background`
+///
+/// Dropping the handle does not cancel the save; the file is still written.
+pub struct ReplaySaveHandle {
+    output_path: PathBuf,
+    finished: Arc<AtomicBool>,
+    result: Receiver<Result<()>>,
+}
+
+impl ReplaySaveHandle {
+    /// File the replay is written to
+    pub fn output_path(&self) -> &Path {
+        &self.output_path
+    }
+
+    /// Whether the replay has been written and finalized (or failed)
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Block until the replay has been written and finalized
+    pub fn wait(self) -> Result<()> {
+        self.result.recv().unwrap_or_else(|_| {
+            Err(RecorderError::Generic(
+                "Replay save worker exited before saving".to_string(),
+            ))
+        })
+    }
+}
+
+/// A snapshot of the replay buffer waiting to be written
+struct ReplaySaveJob {
+    output_path: PathBuf,
+    clip: ReplayClip,
+    finished: Arc<AtomicBool>,
+    result: Sender<Result<()>>,
+}
+
+/// Worker thread that writes replay clips to their files one after another
+///
+/// Only the snapshot of the replay buffer is taken on the caller's thread. Encoding and writing
+/// happen here, so neither the caller nor the processing thread feeding the buffer waits for the
+/// file. Dropping the saver waits for the queued replays to be written.
+pub(crate) struct ReplaySaver {
+    jobs: Option<Sender<ReplaySaveJob>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplaySaver {
+    pub fn spawn(
+        config: RecorderConfig,
+        pixel_pipeline: PixelPipeline,
+        video_encoding: VideoEncoding,
+        encoder_adapter: Option<u64>,
+        events: EventEmitter,
+    ) -> Result<Self> {
+        let (jobs, queue) = channel::<ReplaySaveJob>();
+        let handle = std::thread::Builder::new()
+            .name("windows-record-replay-save".to_string())
+            .spawn(move || {
+                info!("Replay save worker started");
+                // Holds Media Foundation until the last queued replay is written, even if the
+                // recording has shut down its own hold by then
+                let media_foundation = unsafe { media::init_media_foundation() };
+                for job in queue {
+                    let _span = tracing::info_span!("writer", output = %job.output_path.display())
+                        .entered();
+                    let started = Instant::now();
+                    let result = match &media_foundation {
+                        Ok(()) => write_job(
+                            &config,
+                            pixel_pipeline,
+                            video_encoding,
+                            encoder_adapter,
+                            &events,
+                            &job,
+                        ),
+                        Err(e) => Err(e.clone().into()),
+                    };
+                    job.finish(&events, result, started);
+                }
+                if media_foundation.is_ok() {
+                    if let Err(e) = unsafe { media::shutdown_media_foundation() } {
+                        warn!("Failed to shut down Media Foundation: {:?}", e);
+                    }
+                }
+                info!("Replay save worker stopped");
+            })
+            .map_err(|e| {
+                RecorderError::Generic(format!("Failed to spawn replay save thread: {}", e))
+            })?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            handle: Some(handle),
+        })
+    }
+
+    /// Queue `clip` to be written to `output_path` after the replays queued before it
+    pub fn submit(&self, output_path: PathBuf, clip: ReplayClip) -> Result<ReplaySaveHandle> {
+        let finished = Arc::new(AtomicBool::new(false));
+        let (result, receiver) = channel();
+        let job = ReplaySaveJob {
+            output_path: output_path.clone(),
+            clip,
+            finished: finished.clone(),
+            result,
+        };
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send(job).ok())
+            .ok_or_else(|| RecorderError::Generic("Replay save worker has stopped".to_string()))?;
+
+        Ok(ReplaySaveHandle {
+            output_path,
+            finished,
+            result: receiver,
+        })
+    }
+}
+
+impl Drop for ReplaySaver {
+    fn drop(&mut self) {
+        // Closing the queue lets the worker exit once the queued replays are written
+        drop(self.jobs.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Replay save worker panicked");
+            }
+        }
+    }
+}
+
+impl ReplaySaveJob {
+    /// Report the outcome through the event callback and the handle
+    fn finish(self, events: &EventEmitter, result: Result<()>, started: Instant) {
+        match &result {
+            Ok(()) => {
+                info!(
+                    "Replay buffer saved to {} in {:?}",
+                    self.output_path.display(),
+                    started.elapsed()
+                );
+                events.emit(RecorderEvent::ReplaySaved {
+                    output_path: self.output_path.clone(),
+                    duration: self.clip.duration(),
+                    elapsed: started.elapsed(),
+                });
+            }
+            Err(e) => {
+                error!(
+                    "Failed to save replay to {}: {}",
+                    self.output_path.display(),
+                    e
+                );
+                events.emit(RecorderEvent::ReplaySaveFailed {
+                    output_path: self.output_path.clone(),
+                    message: e.to_string(),
+                });
+            }
+        }
+        // The handle may already be gone, the events above still report the outcome
+        let _ = self.result.send(result);
+        self.finished.store(true, Ordering::Release);
+    }
+}
+
+/// Encode the job's clip into a new file with the replay settings of `config`
+fn write_job(
+    config: &RecorderConfig,
+    pixel_pipeline: PixelPipeline,
+    video_encoding: VideoEncoding,
+    encoder_adapter: Option<u64>,
+    events: &EventEmitter,
+    job: &ReplaySaveJob,
+) -> Result<()> {
+    let output_path = job.output_path.to_string_lossy();
+    let clip = &job.clip;
+    info!(
+        "Writing {} video frames and {} audio samples ({:?}) to {}",
+        clip.video.len(),
+        clip.audio.len(),
+        clip.duration(),
+        output_path
+    );
+
+    unsafe {
+        let video_encoder = resolve_video_encoder(config, encoder_adapter)?;
+        info!(
+            "Video encoder obtained: {} ({:?})",
+            video_encoder.name, video_encoder.encoder_type
+        );
+
+        let (replay_width, replay_height, replay_bitrate) = config.replay_output();
+        // A target file size applies to each replay, budgeted for the clip's actual length
+        let budget = config.file_size_bitrate(clip.duration());
+        let replay_bitrate = budget.unwrap_or(replay_bitrate);
+        info!(
+            "Creating {}x{} sink writer for replay file at {} bps",
+            replay_width, replay_height, replay_bitrate
+        );
+        let settings = SinkWriterSettings {
+            output_width: replay_width,
+            output_height: replay_height,
+            video_bitrate: replay_bitrate,
+            capped_bitrate: budget.is_some(),
+            // Saved after the fact, so latency does not matter
+            low_latency: false,
+            // and the file is finalized right away
+            fragmented: false,
+            ..sink_writer_settings(config, &video_encoder, pixel_pipeline, video_encoding)
+        };
+        let media_sink = media::create_sink_writer(&output_path, &settings, None)?;
+        media_sink.BeginWriting()?;
+
+        // Audio goes to stream 1 whenever the file has an audio stream, even if no audio
+        // was buffered
+        let has_audio = config.capture_audio() || config.capture_microphone();
+        let mut reported = 0;
+        clip.write_to(
+            &media_sink,
+            if has_audio { Some(1) } else { None },
+            |written, total| {
+                let step = written * PROGRESS_STEPS / total.max(1);
+                if step > reported {
+                    reported = step;
+                    events.emit(RecorderEvent::ReplaySaveProgress {
+                        output_path: job.output_path.clone(),
+                        written,
+                        total,
+                    });
+                }
+            },
+        )?;
+        info!("Finished writing replay samples, finalizing");
+        media_sink.Finalize()?;
+
+        if let Some(spec) = config.thumbnail() {
+            // The frame `spec.at` into the clip, or its first frame for shorter clips
+            let at = clip.start + duration_to_hns(spec.at);
+            let (sample, _) = clip
+                .video
+                .iter()
+                .find(|(_, timestamp)| *timestamp >= at)
+                .unwrap_or(&clip.video[0]);
+            let path = image::thumbnail_path(&job.output_path);
+            let result = Nv12Reader::default()
+                .read(sample, spec.width)
+                .and_then(|frame| image::write_jpeg(&path.to_string_lossy(), &frame));
+            match result {
+                Ok(()) => info!("Replay thumbnail written to {}", path.display()),
+                Err(e) => error!("Failed to write replay thumbnail: {:?}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::logging::{debug, info, warn};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use windows::core::Result;
+use windows::Win32::Foundation::HWND;
+
+use crate::capture::window::restore_window_display_affinity;
+use crate::error::{RecorderError, StartStage};
+use crate::processing::media;
+use crate::types::SendableWriter;
+
+/// What a recording being started has set up so far
+///
+/// `RecorderInner` takes the threads once it is built. If a step fails first, `rollback` tears
+/// everything down in reverse order so no threads, sink writer or empty file are left behind.
+pub(super) struct PartialStart {
+    /// The step being performed, reported if it fails
+    pub stage: StartStage,
+    pub recording: Option<Arc<AtomicBool>>,
+    pub excluded_windows: Vec<(HWND, u32)>,
+    pub media_foundation: bool,
+    pub writer: Option<SendableWriter>,
+    /// Files created for this recording, deleted on rollback
+    pub files: Vec<PathBuf>,
+    pub video_handle: Option<JoinHandle<Result<()>>>,
+    pub audio_handle: Option<JoinHandle<Result<()>>>,
+    pub microphone_handle: Option<JoinHandle<Result<()>>>,
+    pub process_handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl PartialStart {
+    pub fn new() -> Self {
+        Self {
+            stage: StartStage::Setup,
+            recording: None,
+            excluded_windows: Vec::new(),
+            media_foundation: false,
+            writer: None,
+            files: Vec::new(),
+            video_handle: None,
+            audio_handle: None,
+            microphone_handle: None,
+            process_handle: None,
+        }
+    }
+
+    /// Undo everything set up so far and wrap `error` with the stage that failed
+    pub fn rollback(mut self, error: RecorderError) -> RecorderError {
+        warn!(
+            "Starting the recording failed during {:?}, rolling back: {}",
+            self.stage, error
+        );
+
+        // Every capture thread joins the start barrier before a later step can fail, so they
+        // all get past it and see the flag
+        if let Some(recording) = &self.recording {
+            recording.store(false, Ordering::SeqCst);
+        }
+        let threads = [
+            ("processing", self.process_handle.take()),
+            ("microphone", self.microphone_handle.take()),
+            ("audio", self.audio_handle.take()),
+            ("video", self.video_handle.take()),
+        ];
+        for (name, handle) in threads {
+            let Some(handle) = handle else {
+                continue;
+            };
+            match handle.join() {
+                Ok(Ok(())) => debug!("Stopped the {} thread", name),
+                Ok(Err(e)) => debug!("The {} thread stopped with: {}", name, e),
+                Err(_) => warn!("The {} thread panicked while rolling back", name),
+            }
+        }
+
+        // Released without finalizing; the file is deleted below
+        if let Some(writer) = self.writer.take() {
+            drop(writer);
+            debug!("Released the sink writer");
+        }
+
+        for (hwnd, affinity) in self.excluded_windows.drain(..) {
+            restore_window_display_affinity(hwnd, affinity);
+        }
+
+        if self.media_foundation {
+            if let Err(e) = unsafe { media::shutdown_media_foundation() } {
+                warn!("Failed to shut down Media Foundation: {:?}", e);
+            }
+        }
+
+        for path in &self.files {
+            if !path.exists() {
+                continue;
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => info!("Deleted {} left by the failed start", path.display()),
+                Err(e) => warn!("Failed to delete {}: {}", path.display(), e),
+            }
+        }
+
+        RecorderError::StartFailed {
+            stage: self.stage,
+            source: Box::new(error),
+        }
+    }
+}
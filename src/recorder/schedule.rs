@@ -0,0 +1,109 @@
+use crate::logging::info;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::error::{RecorderError, Result};
+
+/// Where a scheduled start stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Waiting,
+    Cancelled,
+    Started,
+}
+
+/// A recording waiting on a background thread for the time set with
+/// `Recorder::with_scheduled_start`
+///
+/// Dropping it cancels the start if it has not happened yet.
+pub(crate) struct ScheduledStart {
+    state: Arc<(Mutex<State>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ScheduledStart {
+    /// Run `start` at `start_at`, unless cancelled before then
+    pub fn spawn<F>(start_at: SystemTime, start: F) -> Result<Self>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let state = Arc::new((Mutex::new(State::Waiting), Condvar::new()));
+        let thread_state = state.clone();
+        let handle = std::thread::Builder::new()
+            .name("windows-record-scheduled-start".to_string())
+            .spawn(move || {
+                if wait_until(&thread_state, start_at) {
+                    start();
+                }
+            })
+            .map_err(|e| {
+                RecorderError::Generic(format!("Failed to spawn scheduled start thread: {}", e))
+            })?;
+        Ok(Self {
+            state,
+            handle: Some(handle),
+        })
+    }
+
+    /// Whether the start has not happened yet, or is happening right now
+    pub fn is_pending(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Cancel the start and wait for the thread; false if the recording was started already
+    pub fn cancel(mut self) -> bool {
+        self.cancel_and_join()
+    }
+
+    fn cancel_and_join(&mut self) -> bool {
+        let (lock, wake) = &*self.state;
+        let cancelled = match lock.lock() {
+            Ok(mut state) if *state == State::Waiting => {
+                *state = State::Cancelled;
+                true
+            }
+            _ => false,
+        };
+        wake.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        if cancelled {
+            info!("Scheduled start cancelled");
+        }
+        cancelled
+    }
+}
+
+impl Drop for ScheduledStart {
+    fn drop(&mut self) {
+        self.cancel_and_join();
+    }
+}
+
+// Sleep until `start_at` and claim the start, returning false when cancelled first
+fn wait_until(state: &(Mutex<State>, Condvar), start_at: SystemTime) -> bool {
+    let (lock, wake) = state;
+    let Ok(mut guard) = lock.lock() else {
+        return false;
+    };
+    loop {
+        if *guard == State::Cancelled {
+            return false;
+        }
+        let remaining = start_at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        if remaining.is_zero() {
+            *guard = State::Started;
+            return true;
+        }
+        guard = match wake.wait_timeout(guard, remaining) {
+            Ok((guard, _)) => guard,
+            Err(_) => return false,
+        };
+    }
+}
@@ -0,0 +1,79 @@
+use crate::logging::info;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use super::events::RecorderEvent;
+use super::inner::RecorderInner;
+use crate::error::{RecorderError, Result};
+
+/// Handle to a recording being stopped in the background by `Recorder::stop_recording_async`
+///
+/// Dropping the handle does not cancel the stop; the file is still finalized.
+pub struct StopHandle {
+    /// None when there was nothing to finalize, e.g. for a cancelled scheduled start
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl StopHandle {
+    /// Handle of a stop that had nothing to do
+    pub(crate) fn finished() -> Self {
+        Self { handle: None }
+    }
+
+    /// Whether stopping (including finalizing the file) has completed
+    pub fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+
+    /// Block until the recording has been stopped and finalized
+    pub fn wait(self) -> Result<()> {
+        let Some(handle) = self.handle else {
+            return Ok(());
+        };
+        handle
+            .join()
+            .map_err(|_| RecorderError::Generic("Stop thread panicked".to_string()))?
+    }
+}
+
+/// Stop the recorder and report the outcome through the event callback
+pub(crate) fn stop_and_notify(inner: &RecorderInner) -> Result<()> {
+    match inner.stop() {
+        Ok(()) => {
+            inner.events().emit(RecorderEvent::Stopped {
+                output_path: inner.output_path().to_path_buf(),
+            });
+            Ok(())
+        }
+        Err(RecorderError::RecorderAlreadyStopped) => Err(RecorderError::RecorderAlreadyStopped),
+        Err(e) => {
+            inner.events().emit(RecorderEvent::StopFailed {
+                message: e.to_string(),
+            });
+            Err(e)
+        }
+    }
+}
+
+/// Stop the recorder on a background thread
+pub(crate) fn spawn_stop(inner: Arc<RecorderInner>) -> Result<StopHandle> {
+    if !inner.is_recording() {
+        return Err(RecorderError::RecorderAlreadyStopped);
+    }
+
+    let handle = std::thread::Builder::new()
+        .name("windows-record-stop".to_string())
+        .spawn(move || {
+            info!("Background stop thread started");
+            let result = stop_and_notify(&inner);
+            info!("Background stop thread finished: {:?}", result.is_ok());
+            result
+        })
+        .map_err(|e| RecorderError::Generic(format!("Failed to spawn stop thread: {}", e)))?;
+
+    Ok(StopHandle {
+        handle: Some(handle),
+    })
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use windows::Win32::Foundation::SYSTEMTIME;
+use windows::Win32::System::SystemInformation::GetLocalTime;
+
+/// Expand an output filename template
+///
+/// Supported placeholders:
+/// - `{process}` - the target process/window name (sanitized for use in file names)
+/// - `{date}` - local date as `YYYY-MM-DD`
+/// - `{time}` - local time as `HH-MM-SS`
+/// - `{index}` - per-recorder counter, starting at 1
+pub fn render_output_template(template: &str, process_name: &str, index: u32) -> PathBuf {
+    expand(template, process_name, index, &unsafe { GetLocalTime() })
+}
+
+// Expand the placeholders with `now` as the local time
+fn expand(template: &str, process_name: &str, index: u32, now: &SYSTEMTIME) -> PathBuf {
+    let date = format!("{:04}-{:02}-{:02}", now.wYear, now.wMonth, now.wDay);
+    let time = format!("{:02}-{:02}-{:02}", now.wHour, now.wMinute, now.wSecond);
+
+    let rendered = template
+        .replace("{process}", &sanitize_file_name(process_name))
+        .replace("{date}", &date)
+        .replace("{time}", &time)
+        .replace("{index}", &index.to_string());
+
+    PathBuf::from(rendered)
+}
+
+/// Replace characters that are not allowed in Windows file names
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces from file names
+    let trimmed = sanitized
+        .trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+
+    if trimmed.is_empty() {
+        "recording".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> SYSTEMTIME {
+        SYSTEMTIME {
+            wYear: 2024,
+            wMonth: 3,
+            wDay: 7,
+            wHour: 9,
+            wMinute: 5,
+            wSecond: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expands_every_placeholder() {
+        let path = expand(
+            "{process}_{date}_{time}_{index}.mp4",
+            "game.exe",
+            3,
+            &time(),
+        );
+        assert_eq!(path, PathBuf::from("game.exe_2024-03-07_09-05-02_3.mp4"));
+    }
+
+    #[test]
+    fn expands_repeated_placeholders_and_keeps_unknown_ones() {
+        let path = expand("{index}/{index}-{other}.mp4", "game", 12, &time());
+        assert_eq!(path, PathBuf::from("12/12-{other}.mp4"));
+    }
+
+    #[test]
+    fn keeps_directories_in_the_template() {
+        let path = expand(r"C:\clips\{process}\{date}.mp4", "game", 1, &time());
+        assert_eq!(path, PathBuf::from(r"C:\clips\game\2024-03-07.mp4"));
+    }
+
+    #[test]
+    fn sanitizes_reserved_characters() {
+        assert_eq!(
+            sanitize_file_name(r#"a<b>c:d"e/f\g|h?i*j"#),
+            "a_b_c_d_e_f_g_h_i_j"
+        );
+        assert_eq!(sanitize_file_name("tab\there"), "tab_here");
+        let path = expand("{process}.mp4", "Game: Remastered?", 1, &time());
+        assert_eq!(path, PathBuf::from("Game_ Remastered_.mp4"));
+    }
+
+    #[test]
+    fn trims_names_windows_would_change() {
+        assert_eq!(sanitize_file_name("  title. . "), "title");
+        assert_eq!(sanitize_file_name("name..."), "name");
+        assert_eq!(sanitize_file_name(" spaced "), "spaced");
+    }
+
+    #[test]
+    fn falls_back_for_empty_names() {
+        assert_eq!(sanitize_file_name(""), "recording");
+        assert_eq!(sanitize_file_name("  "), "recording");
+        assert_eq!(sanitize_file_name("..."), "recording");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::logging::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::config::RecorderConfig;
+use super::events::{EventEmitter, RecorderEvent};
+use super::Recorder;
+use crate::capture::window::{enumerate_capturable_windows, is_process_running};
+use crate::error::{RecorderError, Result};
+
+/// How often the watcher looks for launched and exited processes
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Background recorder that starts a recording when one of the watched processes opens a window
+/// and stops and finalizes it when the process exits
+///
+/// Only one process is recorded at a time. Set an `output_template` in the config so each
+/// session gets its own file. Dropping the supervisor stops watching and finalizes any recording
+/// in progress.
+pub struct RecorderSupervisor {
+    watching: Arc<AtomicBool>,
+    recording: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// The process a watcher recording belongs to
+struct WatchedSession {
+    recorder: Recorder,
+    process_name: String,
+    process_id: u32,
+}
+
+impl RecorderSupervisor {
+    /// Start watching for any of `process_names` (executable names such as `game.exe`, matched
+    /// case-insensitively with or without the extension) and record them with `config`
+    pub fn watch(process_names: &[&str], config: RecorderConfig) -> Result<Self> {
+        if process_names.is_empty() {
+            return Err(RecorderError::NoProcessSpecified);
+        }
+        let names: Vec<String> = process_names.iter().map(|name| normalize(name)).collect();
+        info!("Watching for processes: {:?}", names);
+
+        let watching = Arc::new(AtomicBool::new(true));
+        let recording = Arc::new(AtomicBool::new(false));
+        let thread_watching = watching.clone();
+        let thread_recording = recording.clone();
+        let handle = std::thread::Builder::new()
+            .name("windows-record-watcher".to_string())
+            .spawn(move || watch_processes(names, config, thread_watching, thread_recording))
+            .map_err(|e| RecorderError::Generic(format!("Failed to spawn watcher thread: {}", e)))?;
+
+        Ok(Self {
+            watching,
+            recording,
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    /// Whether a watched process is being recorded right now
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Stop watching, finalizing the recording in progress if there is one
+    pub fn stop(&self) -> Result<()> {
+        self.watching.store(false, Ordering::Relaxed);
+        let handle = self
+            .handle
+            .lock()
+            .map_err(|_| RecorderError::Generic("Failed to acquire watcher handle lock".to_string()))?
+            .take();
+        if let Some(handle) = handle {
+            handle
+                .join()
+                .map_err(|_| RecorderError::Generic("Watcher thread panicked".to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RecorderSupervisor {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the process watcher: {}", e);
+        }
+    }
+}
+
+fn watch_processes(
+    names: Vec<String>,
+    config: RecorderConfig,
+    watching: Arc<AtomicBool>,
+    recording: Arc<AtomicBool>,
+) {
+    let events = EventEmitter::new(config.event_callback().cloned());
+    let mut session: Option<WatchedSession> = None;
+    // Processes whose recording could not be started are not retried
+    let mut failed: HashSet<u32> = HashSet::new();
+
+    while watching.load(Ordering::Relaxed) {
+        match &session {
+            Some(active) if !is_process_running(active.process_id) => {
+                if let Some(active) = session.take() {
+                    finish_session(active, &events);
+                }
+                recording.store(false, Ordering::Relaxed);
+            }
+            Some(_) => {}
+            None => {
+                let launched = enumerate_capturable_windows().into_iter().find(|window| {
+                    !failed.contains(&window.process_id)
+                        && names.contains(&normalize(&window.exe_name))
+                });
+                if let Some(window) = launched {
+                    info!(
+                        "Watched process {} ({}) launched, starting recording",
+                        window.exe_name, window.process_id
+                    );
+                    events.emit(RecorderEvent::ProcessLaunched {
+                        process_name: window.exe_name.clone(),
+                        process_id: window.process_id,
+                    });
+                    match start_session(&config, &window.exe_name, window.process_id) {
+                        Ok(started) => {
+                            session = Some(started);
+                            recording.store(true, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            error!("Failed to record {}: {}", window.exe_name, e);
+                            failed.insert(window.process_id);
+                            events.emit(RecorderEvent::AutoStartFailed {
+                                process_name: window.exe_name,
+                                process_id: window.process_id,
+                                message: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                failed.retain(|&process_id| is_process_running(process_id));
+            }
+        }
+        std::thread::sleep(WATCH_POLL_INTERVAL);
+    }
+
+    if let Some(active) = session.take() {
+        info!("Watcher stopped while recording {}", active.process_name);
+        finish_session(active, &events);
+    }
+    recording.store(false, Ordering::Relaxed);
+    debug!("Process watcher finished");
+}
+
+fn start_session(
+    config: &RecorderConfig,
+    process_name: &str,
+    process_id: u32,
+) -> Result<WatchedSession> {
+    let recorder = Recorder::new(config.clone())?.with_process_id(process_id);
+    recorder.start_recording()?;
+    Ok(WatchedSession {
+        recorder,
+        process_name: process_name.to_string(),
+        process_id,
+    })
+}
+
+fn finish_session(session: WatchedSession, events: &EventEmitter) {
+    info!(
+        "Finalizing recording of {} ({})",
+        session.process_name, session.process_id
+    );
+    let output_path: Option<PathBuf> = match session.recorder.stop_recording() {
+        // A stop condition such as the maximum duration may have ended it already
+        Ok(()) | Err(RecorderError::RecorderAlreadyStopped) => session.recorder.output_path(),
+        Err(e) => {
+            warn!("Failed to stop recording of {}: {}", session.process_name, e);
+            None
+        }
+    };
+    events.emit(RecorderEvent::ProcessExited {
+        process_name: session.process_name,
+        process_id: session.process_id,
+        output_path,
+    });
+}
+
+/// Lower-case executable name without the `.exe` extension
+fn normalize(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    match name.strip_suffix(".exe") {
+        Some(stem) => stem.to_string(),
+        None => name,
+    }
+}
@@ -0,0 +1,220 @@
+use crate::logging::{debug, info, warn};
+use std::collections::HashMap;
+use windows::core::{ComInterface, Result};
+use windows::Win32::Foundation::{HWND, RECT};
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::{IDXGIResource, DXGI_OUTDUPL_FRAME_INFO};
+use windows::Win32::Graphics::Gdi::{MonitorFromWindow, MONITOR_DEFAULTTONEAREST};
+use windows::Win32::UI::WindowsAndMessaging::{GetWindowRect, IsIconic};
+
+use super::inner::create_d3d11_device_for_window;
+use crate::capture::window::enumerate_capturable_windows;
+use crate::capture::{capture_output, duplicate_output, get_window_monitor_name};
+
+/// Default bounding box of window thumbnails
+const THUMBNAIL_MAX_WIDTH: u32 = 320;
+const THUMBNAIL_MAX_HEIGHT: u32 = 180;
+
+/// A small BGRA snapshot of a window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowThumbnail {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed BGRA rows (`width * 4` bytes per row)
+    pub data: Vec<u8>,
+}
+
+/// A window that can be recorded, with enough detail to build a source picker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub hwnd: HWND,
+    pub title: String,
+    pub process_id: u32,
+    /// Executable file name, e.g. `game.exe`
+    pub process_name: String,
+    /// Device name of the monitor the window is mostly on, e.g. `\\.\DISPLAY1`
+    pub monitor: String,
+    /// Window rectangle in desktop coordinates
+    pub rect: RECT,
+    /// None when the window is minimized or the desktop could not be captured
+    pub thumbnail: Option<WindowThumbnail>,
+}
+
+/// List the windows that can be recorded, with thumbnails of up to 320x180
+pub fn list_windows() -> Vec<WindowInfo> {
+    list_windows_with_thumbnail_size(THUMBNAIL_MAX_WIDTH, THUMBNAIL_MAX_HEIGHT)
+}
+
+/// List the windows that can be recorded, with thumbnails fitting in `max_width` x `max_height`
+/// Pass 0 for either dimension to skip thumbnails
+pub fn list_windows_with_thumbnail_size(max_width: u32, max_height: u32) -> Vec<WindowInfo> {
+    let mut windows: Vec<WindowInfo> = enumerate_capturable_windows()
+        .into_iter()
+        .map(|window| {
+            let mut rect = RECT::default();
+            unsafe {
+                GetWindowRect(window.hwnd, &mut rect);
+            }
+            WindowInfo {
+                hwnd: window.hwnd,
+                title: window.title,
+                process_id: window.process_id,
+                process_name: window.exe_name,
+                monitor: get_window_monitor_name(window.hwnd),
+                rect,
+                thumbnail: None,
+            }
+        })
+        .collect();
+    info!("Found {} capturable windows", windows.len());
+
+    if max_width == 0 || max_height == 0 {
+        return windows;
+    }
+
+    // Grab one desktop frame per monitor and crop every window on it
+    let mut by_monitor: HashMap<isize, Vec<usize>> = HashMap::new();
+    for (index, window) in windows.iter().enumerate() {
+        if unsafe { IsIconic(window.hwnd) }.as_bool() {
+            continue;
+        }
+        let monitor = unsafe { MonitorFromWindow(window.hwnd, MONITOR_DEFAULTTONEAREST) };
+        by_monitor.entry(monitor.0).or_default().push(index);
+    }
+
+    for indices in by_monitor.values() {
+        let hwnd = windows[indices[0]].hwnd;
+        let rects: Vec<RECT> = indices.iter().map(|&i| windows[i].rect).collect();
+        match unsafe { capture_thumbnails(hwnd, &rects, max_width, max_height) } {
+            Ok(thumbnails) => {
+                for (&index, thumbnail) in indices.iter().zip(thumbnails) {
+                    windows[index].thumbnail = thumbnail;
+                }
+            }
+            Err(e) => warn!("Failed to capture window thumbnails: {:?}", e),
+        }
+    }
+
+    windows
+}
+
+/// Capture the desktop of the output `hwnd` is on and scale each of `rects` into a thumbnail
+unsafe fn capture_thumbnails(
+    hwnd: HWND,
+    rects: &[RECT],
+    max_width: u32,
+    max_height: u32,
+) -> Result<Vec<Option<WindowThumbnail>>> {
+    let (device, context) = create_d3d11_device_for_window(hwnd)?;
+    let output = capture_output(&device, hwnd)?;
+    let duplication = duplicate_output(&device, &output)?;
+
+    // The duplicated output's position on the desktop, to map window rects into the frame
+    let mut output_desc = Default::default();
+    output.GetDesc(&mut output_desc)?;
+    let origin = output_desc.DesktopCoordinates;
+
+    let mut frame_info = DXGI_OUTDUPL_FRAME_INFO::default();
+    let mut resource: Option<IDXGIResource> = None;
+    duplication.AcquireNextFrame(500, &mut frame_info, &mut resource)?;
+    let Some(resource) = resource else {
+        let _ = duplication.ReleaseFrame();
+        return Ok(vec![None; rects.len()]);
+    };
+    let frame: ID3D11Texture2D = resource.cast()?;
+
+    let mut desc = D3D11_TEXTURE2D_DESC::default();
+    frame.GetDesc(&mut desc);
+    desc.Usage = D3D11_USAGE_STAGING;
+    desc.BindFlags = D3D11_BIND_FLAG(0);
+    desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ;
+    desc.MiscFlags = D3D11_RESOURCE_MISC_FLAG(0);
+    let mut staging = None;
+    device.CreateTexture2D(&desc, None, Some(&mut staging))?;
+    let staging = staging.unwrap();
+
+    context.CopyResource(&staging, &frame);
+    drop(frame);
+    duplication.ReleaseFrame()?;
+
+    let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+    context.Map(&staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped))?;
+    let pixels = std::slice::from_raw_parts(
+        mapped.pData as *const u8,
+        mapped.RowPitch as usize * desc.Height as usize,
+    );
+
+    let thumbnails = rects
+        .iter()
+        .map(|rect| {
+            // Window rect relative to the frame, clipped to it
+            let crop = RECT {
+                left: (rect.left - origin.left).max(0),
+                top: (rect.top - origin.top).max(0),
+                right: (rect.right - origin.left).min(desc.Width as i32),
+                bottom: (rect.bottom - origin.top).min(desc.Height as i32),
+            };
+            if crop.right <= crop.left || crop.bottom <= crop.top {
+                debug!("Window rect {:?} is outside the captured output", rect);
+                return None;
+            }
+            Some(downscale(
+                pixels,
+                mapped.RowPitch as usize,
+                crop,
+                max_width,
+                max_height,
+            ))
+        })
+        .collect();
+
+    context.Unmap(&staging, 0);
+    Ok(thumbnails)
+}
+
+/// Box-filter the `crop` region of a BGRA frame into a thumbnail that keeps its aspect ratio
+fn downscale(
+    pixels: &[u8],
+    row_pitch: usize,
+    crop: RECT,
+    max_width: u32,
+    max_height: u32,
+) -> WindowThumbnail {
+    let src_width = (crop.right - crop.left) as u64;
+    let src_height = (crop.bottom - crop.top) as u64;
+    let scale_num = (max_width as u64 * src_height).min(max_height as u64 * src_width);
+    let scale_den = src_width * src_height;
+    let width = ((src_width * scale_num / scale_den).clamp(1, src_width)) as u32;
+    let height = ((src_height * scale_num / scale_den).clamp(1, src_height)) as u32;
+
+    let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height as u64 {
+        let y0 = crop.top as u64 + y * src_height / height as u64;
+        let y1 = (crop.top as u64 + (y + 1) * src_height / height as u64).max(y0 + 1);
+        for x in 0..width as u64 {
+            let x0 = crop.left as u64 + x * src_width / width as u64;
+            let x1 = (crop.left as u64 + (x + 1) * src_width / width as u64).max(x0 + 1);
+
+            let mut sum = [0u64; 4];
+            for sy in y0..y1 {
+                let row = sy as usize * row_pitch;
+                for sx in x0..x1 {
+                    let offset = row + sx as usize * 4;
+                    for (channel, total) in sum.iter_mut().enumerate() {
+                        *total += pixels[offset + channel] as u64;
+                    }
+                }
+            }
+            let count = (y1 - y0) * (x1 - x0);
+            data.extend(sum[..3].iter().map(|total| (total / count) as u8));
+            // Desktop duplication leaves alpha undefined
+            data.push(255);
+        }
+    }
+
+    WindowThumbnail {
+        width,
+        height,
+        data,
+    }
+}
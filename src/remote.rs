@@ -0,0 +1,278 @@
+//! JSON control server for driving a recorder from other processes
+//!
+//! Stream Deck plugins, companion apps or scripts connect over TCP and send one JSON object per
+//! line; each gets one JSON object per line back. Commands are `start`, `stop`, `pause`,
+//! `resume`, `save_replay` (with an optional `path`, the output template otherwise) and `status`:
+//!
+//! ```text
+//! > {"id": 1, "command": "save_replay", "path": "clip.mp4"}
+//! < {"id":1,"ok":true,"path":"clip.mp4","status":{"recording":true,"paused":false,...}}
+//! > {"id": 2, "command": "resume"}
+//! < {"id":2,"ok":false,"error":"Generic error: Recording is not paused"}
+//! ```
+//!
+//! The `id` is echoed back as is. Anyone who can connect can control the recorder, so listen on
+//! a loopback address unless the server is given a token, which every request must then carry.
+
+use crate::logging::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::error::{RecorderError, Result};
+use crate::recorder::Recorder;
+
+/// How often blocked accepts and reads check whether the server is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest request line accepted; the connection is closed after a longer one
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    SaveReplay {
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    Status,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: serde_json::Value,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+/// State of the recorder sent back with every successful response
+#[derive(Debug, Serialize)]
+struct Status {
+    recording: bool,
+    paused: bool,
+    output_path: Option<PathBuf>,
+    replay_buffer: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    id: serde_json::Value,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// File a saved replay was written to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+}
+
+/// A running control server; dropping it stops accepting and closes the connections
+pub struct RemoteServer {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RemoteServer {
+    /// Listen on `addr` (e.g. `"127.0.0.1:7878"`, port 0 picks a free one) and serve commands
+    /// for `recorder`. With a `token`, requests without the same `token` field are refused.
+    pub fn bind(
+        recorder: Arc<Recorder>,
+        addr: impl ToSocketAddrs,
+        token: Option<String>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            RecorderError::Generic(format!("Failed to bind the remote control server: {}", e))
+        })?;
+        let local_addr = listener.local_addr().map_err(|e| {
+            RecorderError::Generic(format!("Failed to read the remote control address: {}", e))
+        })?;
+        if !local_addr.ip().is_loopback() && token.is_none() {
+            warn!(
+                "Remote control server on {} is reachable from the network without a token",
+                local_addr
+            );
+        }
+        // Accepting polls so the thread notices when the server is dropped
+        listener.set_nonblocking(true).map_err(|e| {
+            RecorderError::Generic(format!(
+                "Failed to configure the remote control socket: {}",
+                e
+            ))
+        })?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let accept_running = running.clone();
+        let token: Option<Arc<str>> = token.map(Into::into);
+        let handle = std::thread::Builder::new()
+            .name("windows-record-remote".to_string())
+            .spawn(move || {
+                info!("Remote control server listening on {}", local_addr);
+                while accept_running.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            debug!("Remote control connection from {}", peer);
+                            let recorder = recorder.clone();
+                            let running = accept_running.clone();
+                            let token = token.clone();
+                            let spawned = std::thread::Builder::new()
+                                .name("windows-record-remote-client".to_string())
+                                .spawn(move || {
+                                    if let Err(e) = serve_connection(
+                                        stream,
+                                        &recorder,
+                                        &running,
+                                        token.as_deref(),
+                                    ) {
+                                        debug!(
+                                            "Remote control connection from {} closed: {}",
+                                            peer, e
+                                        );
+                                    }
+                                });
+                            if let Err(e) = spawned {
+                                warn!("Failed to spawn a remote control connection thread: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(POLL_INTERVAL)
+                        }
+                        Err(e) => {
+                            warn!("Failed to accept a remote control connection: {}", e);
+                            std::thread::sleep(POLL_INTERVAL);
+                        }
+                    }
+                }
+                info!("Remote control server on {} stopped", local_addr);
+            })
+            .map_err(|e| {
+                RecorderError::Generic(format!("Failed to spawn remote control thread: {}", e))
+            })?;
+
+        Ok(Self {
+            local_addr,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address the server listens on, with the actual port if it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for RemoteServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Answer the requests of one client, one line each, until it disconnects or the server stops
+fn serve_connection(
+    stream: TcpStream,
+    recorder: &Recorder,
+    running: &AtomicBool,
+    token: Option<&str>,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    while running.load(Ordering::Relaxed) {
+        if line.len() >= MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+        // A timed out read keeps what it got so far in `line`
+        let remaining = (MAX_REQUEST_LEN - line.len()) as u64;
+        match (&mut reader).take(remaining).read_until(b'\n', &mut line) {
+            Ok(_) if line.ends_with(b"\n") => {}
+            // The client closed the connection, or the request hit the length limit
+            Ok(read) if read == 0 || line.len() < MAX_REQUEST_LEN => return Ok(()),
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+
+        let request = std::mem::take(&mut line);
+        if request.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let response = handle_request(&request, recorder, token);
+        let mut encoded = serde_json::to_vec(&response)?;
+        encoded.push(b'\n');
+        writer.write_all(&encoded)?;
+    }
+    Ok(())
+}
+
+fn handle_request(request: &[u8], recorder: &Recorder, token: Option<&str>) -> Response {
+    let request: Request = match serde_json::from_slice(request) {
+        Ok(request) => request,
+        Err(e) => return failure(serde_json::Value::Null, format!("Invalid request: {}", e)),
+    };
+    if token.is_some() && request.token.as_deref() != token {
+        return failure(request.id, "Invalid or missing token".to_string());
+    }
+
+    debug!("Remote control command: {:?}", request.command);
+    let result = match request.command {
+        Command::Start => recorder.start_recording().map(|_| None),
+        Command::Stop => recorder.stop_recording().map(|_| None),
+        Command::Pause => recorder.pause_recording().map(|_| None),
+        Command::Resume => recorder.resume_recording().map(|_| None),
+        Command::SaveReplay { path: Some(path) } => path
+            .to_str()
+            .ok_or_else(|| RecorderError::Generic("Invalid path string".to_string()))
+            .and_then(|path_str| recorder.save_replay(path_str))
+            .map(|_| Some(path)),
+        Command::SaveReplay { path: None } => recorder.save_replay_from_template().map(Some),
+        Command::Status => Ok(None),
+    };
+
+    match result {
+        Ok(path) => Response {
+            id: request.id,
+            ok: true,
+            error: None,
+            path,
+            status: Some(Status {
+                recording: recorder.is_recording(),
+                paused: recorder.is_paused(),
+                output_path: recorder.output_path(),
+                replay_buffer: recorder.config().enable_replay_buffer(),
+            }),
+        },
+        Err(e) => failure(request.id, e.to_string()),
+    }
+}
+
+fn failure(id: serde_json::Value, error: String) -> Response {
+    Response {
+        id,
+        ok: false,
+        error: Some(error),
+        path: None,
+        status: None,
+    }
+}
@@ -0,0 +1,180 @@
+use crate::logging::{debug, trace};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{SendError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{FrameCounters, SendableSample};
+
+/// What the capture does when the queue of frames waiting to be encoded is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BackpressurePolicy {
+    /// Drop the oldest queued frame to make room, keeping the recording as current as possible
+    #[default]
+    DropOldest,
+    /// Drop the new frame, keeping the frames already queued
+    DropNewest,
+    /// Make the capture wait until the encoder has taken a frame; no frame is dropped but the
+    /// capture falls behind and catches up by duplicating frames
+    BlockCapture,
+}
+
+/// Totals of the queue between the capture and the encoder since the recording started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameQueueStats {
+    /// Frames the queue holds before the backpressure policy applies
+    pub capacity: usize,
+    /// Frames waiting to be encoded
+    pub queued: usize,
+    /// Highest `queued` so far
+    pub peak_queued: usize,
+    /// Queued frames dropped to make room for newer ones (`DropOldest`)
+    pub dropped_oldest: u64,
+    /// New frames dropped because the queue was full (`DropNewest`)
+    pub dropped_newest: u64,
+    /// Times the capture waited for room in the queue (`BlockCapture`)
+    pub blocked: u64,
+    /// Total time the capture spent waiting for room in the queue
+    pub blocked_time: Duration,
+}
+
+struct QueueState {
+    frames: VecDeque<SendableSample>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    not_full: Condvar,
+    capacity: usize,
+    policy: BackpressurePolicy,
+    stats: Arc<Mutex<FrameQueueStats>>,
+    counters: Arc<FrameCounters>,
+}
+
+/// Sending half of a frame queue, used by the capture
+pub(crate) struct FrameSender(Arc<Shared>);
+
+/// Receiving half of a frame queue, used by the processing thread
+pub(crate) struct FrameReceiver(Arc<Shared>);
+
+/// Create a queue holding at most `capacity` frames that applies `policy` when it is full
+///
+/// Frames dropped by the policy are also counted in `counters.dropped`.
+pub(crate) fn frame_queue(
+    capacity: usize,
+    policy: BackpressurePolicy,
+    stats: Arc<Mutex<FrameQueueStats>>,
+    counters: Arc<FrameCounters>,
+) -> (FrameSender, FrameReceiver) {
+    let capacity = capacity.max(1);
+    stats.lock().unwrap().capacity = capacity;
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            frames: VecDeque::with_capacity(capacity),
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        not_full: Condvar::new(),
+        capacity,
+        policy,
+        stats,
+        counters,
+    });
+    (FrameSender(shared.clone()), FrameReceiver(shared))
+}
+
+impl FrameSender {
+    /// Queue a frame, applying the backpressure policy if the queue is full; fails only when
+    /// the receiver is gone
+    pub fn send(&self, frame: SendableSample) -> std::result::Result<(), SendError<SendableSample>> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        if !state.receiver_alive {
+            return Err(SendError(frame));
+        }
+
+        if state.frames.len() >= shared.capacity {
+            match shared.policy {
+                BackpressurePolicy::DropOldest => {
+                    // The dropped sample returns its texture and IMFSample to their pools
+                    state.frames.pop_front();
+                    shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    shared.stats.lock().unwrap().dropped_oldest += 1;
+                    trace!("Frame queue full, dropped the oldest frame");
+                }
+                BackpressurePolicy::DropNewest => {
+                    drop(state);
+                    shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    shared.stats.lock().unwrap().dropped_newest += 1;
+                    trace!("Frame queue full, dropped the new frame");
+                    return Ok(());
+                }
+                BackpressurePolicy::BlockCapture => {
+                    let waited_from = Instant::now();
+                    state = shared
+                        .not_full
+                        .wait_while(state, |state| {
+                            state.receiver_alive && state.frames.len() >= shared.capacity
+                        })
+                        .unwrap();
+                    let waited = waited_from.elapsed();
+                    {
+                        let mut stats = shared.stats.lock().unwrap();
+                        stats.blocked += 1;
+                        stats.blocked_time += waited;
+                    }
+                    debug!("Capture waited {:?} for room in the frame queue", waited);
+                    if !state.receiver_alive {
+                        return Err(SendError(frame));
+                    }
+                }
+            }
+        }
+
+        state.frames.push_back(frame);
+        let queued = state.frames.len();
+        let mut stats = shared.stats.lock().unwrap();
+        stats.queued = queued;
+        stats.peak_queued = stats.peak_queued.max(queued);
+        Ok(())
+    }
+}
+
+impl Drop for FrameSender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sender_alive = false;
+    }
+}
+
+impl FrameReceiver {
+    /// Take the oldest queued frame without waiting
+    pub fn try_recv(&self) -> std::result::Result<SendableSample, TryRecvError> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        match state.frames.pop_front() {
+            Some(frame) => {
+                shared.stats.lock().unwrap().queued = state.frames.len();
+                drop(state);
+                shared.not_full.notify_one();
+                Ok(frame)
+            }
+            None if !state.sender_alive => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl Drop for FrameReceiver {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.receiver_alive = false;
+        // Release the queued frames' textures now rather than when the last sender goes
+        state.frames.clear();
+        drop(state);
+        self.0.not_full.notify_all();
+    }
+}
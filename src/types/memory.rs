@@ -0,0 +1,63 @@
+use windows::Win32::Graphics::Dxgi::Common::{
+    DXGI_FORMAT, DXGI_FORMAT_NV12, DXGI_FORMAT_P010, DXGI_FORMAT_R16G16B16A16_FLOAT,
+};
+
+/// Hard caps on the memory a recording may hold; None leaves that kind unbounded
+///
+/// The texture pools are allocated up front, so growth only comes from the replay buffer: once a
+/// cap is reached, its oldest samples are dropped to make room and it holds less than
+/// `replay_buffer_seconds`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct MemoryLimits {
+    /// Most bytes of GPU textures held by the texture pools and the replay buffer together
+    pub gpu_bytes: Option<u64>,
+    /// Most bytes of video and audio samples held by the replay buffer
+    pub replay_buffer_bytes: Option<u64>,
+}
+
+/// Memory held by the texture pools, the frame queue and the replay buffer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// GPU textures captured frames are copied into, including the blank frame
+    pub capture_texture_bytes: u64,
+    /// GPU textures frames are converted into for the encoder
+    pub conversion_texture_bytes: u64,
+    /// Captured frames waiting in the queue to the encoder, each holding a capture texture
+    pub queued_frames: usize,
+    /// GPU textures owned by the replay buffer's frames; frames kept at the output size share
+    /// the conversion textures and are not counted again
+    pub replay_video_bytes: u64,
+    /// Audio samples held by the replay buffer
+    pub replay_audio_bytes: u64,
+    /// Replay buffer samples dropped early to stay within the `memory_limits`
+    pub replay_samples_dropped: u64,
+}
+
+impl MemoryUsage {
+    /// GPU texture memory held in total
+    pub fn gpu_bytes(&self) -> u64 {
+        self.capture_texture_bytes + self.conversion_texture_bytes + self.replay_video_bytes
+    }
+
+    /// Bytes of samples held by the replay buffer
+    pub fn replay_buffer_bytes(&self) -> u64 {
+        self.replay_video_bytes + self.replay_audio_bytes
+    }
+}
+
+/// Size of a `width` x `height` texture in `format`, ignoring the driver's padding
+pub(crate) fn texture_bytes(width: u32, height: u32, format: DXGI_FORMAT) -> u64 {
+    let pixels = width as u64 * height as u64;
+    match format {
+        DXGI_FORMAT_NV12 => pixels * 3 / 2,
+        DXGI_FORMAT_P010 => pixels * 3,
+        DXGI_FORMAT_R16G16B16A16_FLOAT => pixels * 8,
+        // BGRA and the other 32-bit formats
+        _ => pixels * 4,
+    }
+}
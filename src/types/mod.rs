@@ -1,7 +1,8 @@
-use log::{debug, error, info, trace};
-use std::collections::{HashMap, VecDeque};
+use crate::logging::{debug, error, info, trace};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use windows::core::{ComInterface, Result};
 use windows::Win32::Foundation::TRUE;
 use windows::Win32::Graphics::Direct3D11::ID3D11Texture2D;
@@ -9,16 +10,25 @@ use windows::Win32::Graphics::Dxgi::IDXGISurface;
 use windows::Win32::Media::MediaFoundation::{
     IMFSample, IMFSinkWriter, MFCreateDXGISurfaceBuffer, MFCreateSample,
 };
+pub mod frame_queue;
+pub mod memory;
 pub mod safe_wrapper;
 pub mod texture_pool;
 
+pub(crate) use frame_queue::{frame_queue, FrameReceiver, FrameSender};
+pub use memory::{MemoryLimits, MemoryUsage};
 // Re-export TexturePool
-pub use texture_pool::TexturePool;
+pub use texture_pool::{BufferPoolSizes, PoolStats, PooledTexture, TexturePool};
+
+/// Position and size of the captured window, sent to the processing thread as they change
+pub type WindowGeometry = (Option<(i32, i32)>, Option<(u32, u32)>);
 
 /// A wrapper for IMFSample that can be sent between threads
 pub struct SendableSample {
     pub sample: Arc<IMFSample>,
     pool: Option<Arc<SamplePool>>,
+    /// Pooled texture behind the sample, returned to its pool once the sample is dropped
+    texture: Option<Arc<PooledTexture>>,
 }
 
 impl SendableSample {
@@ -27,6 +37,7 @@ impl SendableSample {
         Self {
             sample: Arc::new(sample),
             pool: None,
+            texture: None,
         }
     }
 
@@ -38,8 +49,15 @@ impl SendableSample {
         Self {
             sample: Arc::new(sample),
             pool: Some(pool),
+            texture: None,
         }
     }
+
+    /// Keep `texture` out of its pool for as long as this sample lives
+    pub fn holding(mut self, texture: Option<Arc<PooledTexture>>) -> Self {
+        self.texture = texture;
+        self
+    }
 }
 
 impl std::ops::Deref for SendableSample {
@@ -98,6 +116,20 @@ impl Drop for SendableSample {
     }
 }
 
+/// Frame counters updated by the capture and processing threads
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    /// Frames with new desktop content copied by the capture thread
+    pub captured: AtomicU64,
+    /// Frames repeated by the capture thread to keep the frame rate
+    pub duplicated: AtomicU64,
+    /// Frames dropped before encoding: skipped in low latency mode, by the backpressure policy
+    /// or for lack of a free capture texture
+    pub dropped: AtomicU64,
+    /// Frames handed to the encoder
+    pub written: AtomicU64,
+}
+
 #[derive(Clone)]
 pub struct SendableWriter(pub Arc<IMFSinkWriter>);
 unsafe impl Send for SendableWriter {}
@@ -108,8 +140,9 @@ unsafe impl Sync for SendableWriter {}
 pub struct SamplePool {
     /// Mutex-protected vector of available IMFSample objects
     samples: Mutex<Vec<IMFSample>>,
-    /// FPS value used for sample duration
+    /// Frame rate the sample times and durations are derived from
     pub fps_num: u32,
+    pub fps_den: u32,
     // Tracking for debug purposes
     #[cfg(debug_assertions)]
     created_count: std::sync::atomic::AtomicU32,
@@ -121,12 +154,13 @@ pub struct SamplePool {
 
 impl SamplePool {
     /// Create a new sample pool
-    pub fn new(fps_num: u32, initial_capacity: usize) -> Self {
-        info!("Initializing SamplePool with FPS: {}", fps_num);
+    pub fn new(fps_num: u32, fps_den: u32, initial_capacity: usize) -> Self {
+        info!("Initializing SamplePool with FPS: {}/{}", fps_num, fps_den);
 
         Self {
             samples: Mutex::new(Vec::with_capacity(initial_capacity)),
             fps_num,
+            fps_den,
             #[cfg(debug_assertions)]
             created_count: std::sync::atomic::AtomicU32::new(0),
             #[cfg(debug_assertions)]
@@ -220,14 +254,81 @@ impl SamplePool {
         Ok(())
     }
 
-    /// Set the timestamp for a sample
-    pub unsafe fn set_sample_time(&self, sample: &IMFSample, frame_count: u64) -> Result<()> {
-        let frame_time = (frame_count as i64 * 10_000_000i64 / self.fps_num as i64) as i64;
+    /// Set the timestamp and duration of the sample for frame `frame_count`
+    ///
+    /// Both come from the exact frame times, so with fractional rates the durations alternate
+    /// by 100ns instead of the timestamps drifting away from the frame rate.
+    pub unsafe fn set_sample_timing(&self, sample: &IMFSample, frame_count: u64) -> Result<()> {
+        let frame_time = frame_time_hns(frame_count, self.fps_num, self.fps_den);
+        let next_frame_time = frame_time_hns(frame_count + 1, self.fps_num, self.fps_den);
         sample.SetSampleTime(frame_time)?;
+        sample.SetSampleDuration(next_frame_time - frame_time)?;
         Ok(())
     }
 }
 
+/// Start of frame `frame` at `fps_num / fps_den` frames per second, in 100ns units rounded to
+/// the nearest unit
+pub fn frame_time_hns(frame: u64, fps_num: u32, fps_den: u32) -> i64 {
+    let num = fps_num.max(1) as u128;
+    ((frame as u128 * fps_den as u128 * 10_000_000 + num / 2) / num) as i64
+}
+
+/// Start of frame `frame` at `fps_num / fps_den` frames per second
+pub fn frame_time(frame: u64, fps_num: u32, fps_den: u32) -> Duration {
+    let nanos = frame as u128 * fps_den as u128 * 1_000_000_000 / fps_num.max(1) as u128;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// When each frame is due at a rational frame rate
+///
+/// Frame times are computed from the start instead of adding up a rounded frame duration, so
+/// rates like 59.94 (60000/1001) don't drift over long recordings.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameClock {
+    start: Instant,
+    frame: u64,
+    fps_num: u32,
+    fps_den: u32,
+}
+
+impl FrameClock {
+    pub fn new(fps_num: u32, fps_den: u32) -> Self {
+        Self {
+            start: Instant::now(),
+            frame: 0,
+            fps_num,
+            fps_den,
+        }
+    }
+
+    /// When the current frame is due
+    pub fn due(&self) -> Instant {
+        self.start + frame_time(self.frame, self.fps_num, self.fps_den)
+    }
+
+    /// Length of the current frame
+    pub fn frame_duration(&self) -> Duration {
+        frame_time(self.frame + 1, self.fps_num, self.fps_den)
+            - frame_time(self.frame, self.fps_num, self.fps_den)
+    }
+
+    /// Move on to the next frame
+    pub fn advance(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Skip the frames that were due before `now`, so the next one is the first still ahead
+    pub fn skip_to(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.start).as_nanos();
+        let frames = elapsed * self.fps_num as u128 / (self.fps_den.max(1) as u128 * 1_000_000_000);
+        self.frame = self.frame.max(frames as u64 + 1);
+    }
+}
+
 pub fn duration_to_hns(duration: Duration) -> i64 {
     // Convert Duration to 100-nanosecond intervals (hns)
     duration.as_nanos() as i64 / 100
@@ -256,7 +357,7 @@ impl VideoConfig {
     }
 
     pub fn frame_duration(&self) -> Duration {
-        Duration::from_nanos(1_000_000_000 * self.fps_den as u64 / self.fps_num as u64)
+        frame_time(1, self.fps_num, self.fps_den)
     }
 }
 
@@ -284,181 +385,3 @@ impl AudioConfig {
         self.sample_rate * self.bytes_per_sample()
     }
 }
-
-/// A circular buffer to store recent video and audio samples for replay functionality
-pub struct ReplayBuffer {
-    /// Maximum duration to keep in the buffer
-    max_duration: Duration,
-    /// Video samples with their timestamps
-    video_samples: Mutex<VecDeque<(SendableSample, i64)>>, // (sample, timestamp)
-    /// Audio samples with their timestamps
-    audio_samples: Mutex<VecDeque<(SendableSample, i64)>>, // (sample, timestamp)
-    /// Current buffer size in memory (approximate)
-    size_bytes: Mutex<usize>,
-    /// Time of the oldest sample in the buffer
-    pub oldest_timestamp: Mutex<i64>,
-    /// Current size limits for video and audio
-    video_limit: usize,
-    audio_limit: usize,
-}
-
-impl ReplayBuffer {
-    /// Create a new replay buffer with a specified duration
-    pub fn new(
-        max_duration: Duration,
-        initial_video_limit: usize,
-        initial_audio_limit: usize,
-    ) -> Self {
-        info!(
-            "Creating replay buffer with max duration of {:?}",
-            max_duration
-        );
-        Self {
-            max_duration,
-            video_samples: Mutex::new(VecDeque::with_capacity(initial_video_limit)),
-            audio_samples: Mutex::new(VecDeque::with_capacity(initial_audio_limit)),
-            size_bytes: Mutex::new(0),
-            oldest_timestamp: Mutex::new(0),
-            video_limit: initial_video_limit,
-            audio_limit: initial_audio_limit,
-        }
-    }
-
-    /// Add a video sample to the buffer
-    pub fn add_video_sample(&self, sample: SendableSample, timestamp: i64) -> Result<()> {
-        let mut samples = self.video_samples.lock().unwrap();
-
-        // Add new sample
-        samples.push_back((sample, timestamp));
-
-        // Update oldest timestamp if this is the only sample
-        if samples.len() == 1 {
-            let mut oldest = self.oldest_timestamp.lock().unwrap();
-            *oldest = timestamp;
-        }
-
-        // Remove old samples if we exceed the limit
-        self.trim_buffer(&mut samples, timestamp)?;
-
-        Ok(())
-    }
-
-    /// Add an audio sample to the buffer
-    pub fn add_audio_sample(&self, sample: SendableSample, timestamp: i64) -> Result<()> {
-        let mut samples = self.audio_samples.lock().unwrap();
-
-        // Add new sample
-        samples.push_back((sample, timestamp));
-
-        // Update oldest timestamp if needed
-        if timestamp < *self.oldest_timestamp.lock().unwrap() {
-            let mut oldest = self.oldest_timestamp.lock().unwrap();
-            *oldest = timestamp;
-        }
-
-        // Remove old samples if we exceed the limit
-        self.trim_buffer(&mut samples, timestamp)?;
-
-        Ok(())
-    }
-
-    /// Remove samples that are too old
-    fn trim_buffer(
-        &self,
-        samples: &mut VecDeque<(SendableSample, i64)>,
-        latest_timestamp: i64,
-    ) -> Result<()> {
-        let cutoff_timestamp = latest_timestamp - duration_to_hns(self.max_duration);
-
-        // Remove samples older than the cutoff
-        while let Some((_, timestamp)) = samples.front() {
-            if *timestamp < cutoff_timestamp {
-                samples.pop_front();
-            } else {
-                break;
-            }
-        }
-
-        // Update oldest timestamp
-        if samples.is_empty() {
-            let mut oldest = self.oldest_timestamp.lock().unwrap();
-            *oldest = latest_timestamp;
-        } else if let Some((_, timestamp)) = samples.front() {
-            let mut oldest = self.oldest_timestamp.lock().unwrap();
-            *oldest = *timestamp;
-        }
-
-        Ok(())
-    }
-
-    /// Get a list of video samples within a time range
-    pub fn get_video_samples(&self, start_time: i64, end_time: i64) -> Vec<(SendableSample, i64)> {
-        let samples = self.video_samples.lock().unwrap();
-        samples
-            .iter()
-            .filter(|(_, timestamp)| *timestamp >= start_time && *timestamp <= end_time)
-            .map(|(sample, timestamp)| {
-                // Create a new SendableSample without pool tracking, since we don't want to return these to the pool
-                let sample_clone = unsafe { sample.sample.as_ref().clone() };
-                let new_sample = SendableSample::new(sample_clone);
-                (new_sample, *timestamp)
-            })
-            .collect()
-    }
-
-    /// Get a list of audio samples within a time range
-    pub fn get_audio_samples(&self, start_time: i64, end_time: i64) -> Vec<(SendableSample, i64)> {
-        let samples = self.audio_samples.lock().unwrap();
-        samples
-            .iter()
-            .filter(|(_, timestamp)| *timestamp >= start_time && *timestamp <= end_time)
-            .map(|(sample, timestamp)| {
-                // Create a new SendableSample without pool tracking, since we don't want to return these to the pool
-                let sample_clone = unsafe { sample.sample.as_ref().clone() };
-                let new_sample = SendableSample::new(sample_clone);
-                (new_sample, *timestamp)
-            })
-            .collect()
-    }
-
-    /// Get the buffer's current duration
-    pub fn current_duration(&self) -> Duration {
-        let video_samples = self.video_samples.lock().unwrap();
-        let audio_samples = self.audio_samples.lock().unwrap();
-
-        if video_samples.is_empty() && audio_samples.is_empty() {
-            return Duration::from_secs(0);
-        }
-
-        let mut latest_timestamp = 0;
-        let oldest_timestamp = *self.oldest_timestamp.lock().unwrap();
-
-        // Find the latest timestamp from video samples
-        if let Some((_, timestamp)) = video_samples.back() {
-            latest_timestamp = *timestamp;
-        }
-
-        // Check if the latest audio timestamp is newer
-        if let Some((_, timestamp)) = audio_samples.back() {
-            if *timestamp > latest_timestamp {
-                latest_timestamp = *timestamp;
-            }
-        }
-
-        // Convert the difference to duration
-        hns_to_duration(latest_timestamp - oldest_timestamp)
-    }
-
-    /// Clear the buffer
-    pub fn clear(&self) {
-        let mut video_samples = self.video_samples.lock().unwrap();
-        let mut audio_samples = self.audio_samples.lock().unwrap();
-        let mut size_bytes = self.size_bytes.lock().unwrap();
-
-        video_samples.clear();
-        audio_samples.clear();
-        *size_bytes = 0;
-
-        debug!("Replay buffer cleared");
-    }
-}
@@ -1,9 +1,14 @@
-use log::{debug, info, trace, warn};
-use std::sync::{Arc, Mutex};
-use windows::core::{ComInterface, Result};
-use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D};
+use crate::logging::{debug, info, trace, warn};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use windows::core::Result;
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11Device, ID3D11Texture2D, D3D11_RESOURCE_MISC_FLAG,
+};
 use windows::Win32::Graphics::Dxgi::Common::*;
 
+use super::memory::{texture_bytes, MemoryUsage};
+
 // Important notes about texture creation in Direct3D 11:
 //
 // 1. For textures that will be used with GDI (GetDC/ReleaseDC), these requirements must be met:
@@ -18,6 +23,77 @@ use windows::Win32::Graphics::Dxgi::Common::*;
 //
 // These rules are enforced by the D3D11 API and will result in errors if violated.
 
+/// Number of GPU textures allocated for frames in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct BufferPoolSizes {
+    /// Textures captured frames are copied into; each holds a frame until it has been converted
+    /// for the encoder, so this bounds how far processing can fall behind the capture
+    pub capture_textures: usize,
+    /// NV12 textures frames are converted into for the encoder, used in turn
+    pub conversion_textures: usize,
+    /// How long the capture waits for a texture when all are in use before dropping the frame
+    pub acquire_timeout: Duration,
+}
+
+impl Default for BufferPoolSizes {
+    fn default() -> Self {
+        Self {
+            capture_textures: 10,
+            conversion_textures: 1,
+            acquire_timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Usage of the texture pools since the recording started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Capture textures in the pool
+    pub capture_textures: usize,
+    /// Capture textures holding frames that have not been processed yet
+    pub capture_in_use: usize,
+    /// Highest `capture_in_use` so far
+    pub capture_peak_in_use: usize,
+    /// Frames that had to wait for a capture texture to be released
+    pub capture_waits: u64,
+    /// Frames dropped because no capture texture was released within the acquire timeout
+    pub capture_exhausted: u64,
+    /// NV12 textures frames are converted into
+    pub conversion_textures: usize,
+}
+
+impl PoolStats {
+    /// Fraction of the capture textures currently in use, 0.0..=1.0
+    pub fn utilization(&self) -> f32 {
+        self.capture_in_use as f32 / self.capture_textures.max(1) as f32
+    }
+}
+
+/// An acquisition texture on loan from a [`TexturePool`], returned to it when dropped
+pub struct PooledTexture {
+    texture: Option<ID3D11Texture2D>,
+    pool: Arc<TexturePool>,
+}
+
+impl PooledTexture {
+    pub fn texture(&self) -> &ID3D11Texture2D {
+        self.texture.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledTexture {
+    fn drop(&mut self) {
+        if let Some(texture) = self.texture.take() {
+            self.pool.release_acquisition_texture(texture);
+        }
+    }
+}
+
 /// A thread-safe pool of reusable D3D11 textures with specialized textures for different purposes
 pub struct TexturePool {
     /// Device used to create textures
@@ -28,20 +104,28 @@ pub struct TexturePool {
     height: u32,
     /// Format of textures in the pool
     format: DXGI_FORMAT,
-    /// Pool of acquisition textures (used for frame acquisition)
+    /// Free acquisition textures (used for frame acquisition)
     acquisition_textures: Mutex<Vec<ID3D11Texture2D>>,
+    /// Signalled when an acquisition texture is returned
+    acquisition_released: Condvar,
+    /// Number of acquisition textures, free or on loan
+    acquisition_capacity: usize,
+    /// How long to wait for an acquisition texture when all are on loan
+    acquire_timeout: Duration,
     /// Single blank texture (used for blank frames when window not in focus)
     blank_texture: Mutex<Option<ID3D11Texture2D>>,
-    /// Single conversion texture (used for format conversion e.g. BGRA to NV12)
-    conversion_texture: Mutex<Option<ID3D11Texture2D>>,
-    
-    // Tracking for debug purposes
-    #[cfg(debug_assertions)]
-    created_count: std::sync::atomic::AtomicU32,
-    #[cfg(debug_assertions)]
-    acquired_count: std::sync::atomic::AtomicU32,
-    #[cfg(debug_assertions)]
-    released_count: std::sync::atomic::AtomicU32,
+    /// Conversion textures (used for format conversion e.g. BGRA to NV12), created on first use
+    /// and handed out in turn, with the index of the next one
+    conversion_textures: Mutex<(Vec<ID3D11Texture2D>, usize)>,
+    /// Number of conversion textures to rotate through
+    conversion_capacity: usize,
+    /// Format of conversion textures, NV12 unless frames reach the encoder as BGRA
+    conversion_format: DXGI_FORMAT,
+    /// Usage shared with the recorder's `pool_stats`
+    stats: Option<Arc<Mutex<PoolStats>>>,
+    /// Texture memory shared with the recorder's `memory_usage`, given back when the pool is
+    /// dropped
+    memory: Option<Arc<Mutex<MemoryUsage>>>,
 }
 
 impl TexturePool {
@@ -50,12 +134,13 @@ impl TexturePool {
     /// This texture pool manages several types of textures:
     /// - Acquisition textures: Used for capturing frames (with GDI compatibility for cursor overlay)
     /// - Blank texture: Used for blank frames when window is not in focus (with GDI compatibility) 
-    /// - Conversion texture: Used for format conversion (BGRA to NV12)
+    /// - Conversion textures: Used for format conversion (BGRA to NV12)
     ///
     /// Note: For cursor drawing with GDI, textures need D3D11_RESOURCE_MISC_GDI_COMPATIBLE flag
     pub fn new(
         device: Arc<ID3D11Device>,
         acquisition_capacity: usize,
+        conversion_capacity: usize,
         width: u32,
         height: u32,
         format: DXGI_FORMAT,
@@ -73,10 +158,7 @@ impl TexturePool {
                 width,
                 height,
                 format,
-                D3D11_USAGE_DEFAULT.0 as u32,
-                (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32,
-                0, // CPU access flags
-                D3D11_RESOURCE_MISC_GDI_COMPATIBLE.0 as u32, // Add GDI compatibility
+                D3D11_RESOURCE_MISC_GDI_COMPATIBLE, // Add GDI compatibility
             )? };
             
             #[cfg(debug_assertions)]
@@ -92,32 +174,12 @@ impl TexturePool {
             width,
             height,
             format,
-            D3D11_USAGE_DEFAULT.0 as u32,
-            (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32, // Add RENDER_TARGET for GDI compatibility
-            0, // CPU access flags
-            D3D11_RESOURCE_MISC_GDI_COMPATIBLE.0 as u32, // Add GDI compatibility
+            D3D11_RESOURCE_MISC_GDI_COMPATIBLE, // Add GDI compatibility
         )? };
         
         #[cfg(debug_assertions)]
         debug!("TexturePool: Created blank texture at {:p}", &blank_texture as *const _);
         
-        // We no longer need a staging texture - using acquisition textures directly
-        
-        // Create a conversion texture (NV12 format) for video processing
-        let conversion_texture = unsafe { Self::create_texture(
-            &device,
-            width,
-            height,
-            DXGI_FORMAT_NV12,
-            D3D11_USAGE_DEFAULT.0 as u32,
-            (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32,
-            0, // CPU access flags
-            0, // Misc flags
-        )? };
-        
-        #[cfg(debug_assertions)]
-        debug!("TexturePool: Created conversion texture at {:p}", &conversion_texture as *const _);
-        
         info!("TexturePool initialized with {} acquisition textures of {}x{}", 
               acquisition_capacity, width, height);
         
@@ -127,85 +189,140 @@ impl TexturePool {
             height,
             format,
             acquisition_textures: Mutex::new(textures),
+            acquisition_released: Condvar::new(),
+            acquisition_capacity,
+            acquire_timeout: BufferPoolSizes::default().acquire_timeout,
             blank_texture: Mutex::new(Some(blank_texture)),
-            conversion_texture: Mutex::new(Some(conversion_texture)),
-            #[cfg(debug_assertions)]
-            created_count: std::sync::atomic::AtomicU32::new((acquisition_capacity + 2) as u32), // Reduced by 1 (no staging texture)
-            #[cfg(debug_assertions)]
-            acquired_count: std::sync::atomic::AtomicU32::new(0),
-            #[cfg(debug_assertions)]
-            released_count: std::sync::atomic::AtomicU32::new(0),
+            conversion_textures: Mutex::new((Vec::new(), 0)),
+            conversion_capacity: conversion_capacity.max(1),
+            conversion_format: DXGI_FORMAT_NV12,
+            stats: None,
+            memory: None,
         })
     }
-    
-    /// Acquire an acquisition texture from the pool or create a new one if the pool is empty
-    pub fn acquire_acquisition_texture(&self) -> Result<ID3D11Texture2D> {
-        let mut textures = self.acquisition_textures.lock().unwrap();
-        
-        let texture = if let Some(texture) = textures.pop() {
-            #[cfg(debug_assertions)]
-            trace!("TexturePool: Reusing acquisition texture from pool at {:p}", &texture as *const _);
-            texture
-        } else {
-            // If pool is empty, create a new texture
-            #[cfg(debug_assertions)]
-            debug!("TexturePool acquisition texture pool depleted, creating new one");
-            
-            use windows::Win32::Graphics::Direct3D11::*;
-            
-            let texture = unsafe { Self::create_texture(
-                &self.device, 
-                self.width, 
-                self.height, 
-                self.format,
-                D3D11_USAGE_DEFAULT.0 as u32,
-                (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32,
-                0, // CPU access flags
-                D3D11_RESOURCE_MISC_GDI_COMPATIBLE.0 as u32, // Add GDI compatibility
-            )? };
-            
-            #[cfg(debug_assertions)] {
-                self.created_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                debug!("TexturePool: Created new acquisition texture at {:p}", &texture as *const _);
+
+    /// A pool like this one with its textures on `device`, for when the recording moves to
+    /// another adapter
+    pub fn recreate_on(&self, device: Arc<ID3D11Device>) -> Result<Self> {
+        let mut pool = Self::new(
+            device,
+            self.acquisition_capacity,
+            self.conversion_capacity,
+            self.width,
+            self.height,
+            self.format,
+        )?;
+        pool.acquire_timeout = self.acquire_timeout;
+        pool.conversion_format = self.conversion_format;
+        pool.stats = self.stats.clone();
+        if let Some(memory) = &self.memory {
+            pool.set_memory_sink(memory.clone());
+        }
+        Ok(pool)
+    }
+
+    /// How long `acquire_acquisition_texture` waits when all textures are on loan
+    pub fn set_acquire_timeout(&mut self, timeout: Duration) {
+        self.acquire_timeout = timeout;
+    }
+
+    /// Create conversion textures in `format` instead of NV12
+    pub fn set_conversion_format(&mut self, format: DXGI_FORMAT) {
+        self.conversion_format = format;
+    }
+
+    /// Report acquisition texture usage into `stats`
+    pub fn set_stats_sink(&mut self, stats: Arc<Mutex<PoolStats>>) {
+        stats.lock().unwrap().capture_textures = self.acquisition_capacity;
+        self.stats = Some(stats);
+    }
+
+    /// Account the pool's textures in `memory`, now and as conversion textures are created
+    pub fn set_memory_sink(&mut self, memory: Arc<Mutex<MemoryUsage>>) {
+        self.memory = Some(memory);
+        let (capture, conversion) = (self.capture_bytes(), self.conversion_bytes());
+        self.update_memory(|memory| {
+            memory.capture_texture_bytes += capture;
+            memory.conversion_texture_bytes += conversion;
+        });
+    }
+
+    /// Bytes of the acquisition textures and the blank texture
+    fn capture_bytes(&self) -> u64 {
+        (self.acquisition_capacity as u64 + 1) * texture_bytes(self.width, self.height, self.format)
+    }
+
+    /// Bytes of the conversion textures created so far
+    fn conversion_bytes(&self) -> u64 {
+        let created = self.conversion_textures.lock().unwrap().0.len() as u64;
+        created * texture_bytes(self.width, self.height, self.conversion_format)
+    }
+
+    fn update_memory(&self, update: impl FnOnce(&mut MemoryUsage)) {
+        if let Some(memory) = &self.memory {
+            if let Ok(mut memory) = memory.lock() {
+                update(&mut memory);
             }
-            
-            texture
-        };
-        
-        #[cfg(debug_assertions)]
-        {
-            let acquired = self.acquired_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            let created = self.created_count.load(std::sync::atomic::Ordering::SeqCst);
-            let released = self.released_count.load(std::sync::atomic::Ordering::SeqCst);
-            let active = acquired - released + 1;
-            
-            if active % 30 == 0 { // Log every 30 frames to avoid excessive logging
-                info!("TexturePool stats - created: {}, acquired: {}, released: {}, active: {}", 
-                      created, acquired + 1, released, active);
+        }
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut PoolStats)) {
+        if let Some(stats) = &self.stats {
+            if let Ok(mut stats) = stats.lock() {
+                update(&mut stats);
             }
         }
-        
-        Ok(texture)
+    }
+    
+    /// Borrow an acquisition texture, waiting up to the acquire timeout for one to be returned
+    /// when all are on loan; None if the pool stayed exhausted
+    pub fn acquire_acquisition_texture(self: &Arc<Self>) -> Option<PooledTexture> {
+        let mut textures = self.acquisition_textures.lock().unwrap();
+
+        if textures.is_empty() {
+            trace!("TexturePool: All acquisition textures in use, waiting");
+            self.update_stats(|stats| stats.capture_waits += 1);
+            textures = self
+                .acquisition_released
+                .wait_timeout_while(textures, self.acquire_timeout, |textures| {
+                    textures.is_empty()
+                })
+                .unwrap()
+                .0;
+        }
+
+        let Some(texture) = textures.pop() else {
+            debug!(
+                "TexturePool: No acquisition texture returned within {:?}",
+                self.acquire_timeout
+            );
+            self.update_stats(|stats| stats.capture_exhausted += 1);
+            return None;
+        };
+
+        let in_use = self.acquisition_capacity - textures.len();
+        drop(textures);
+        self.update_stats(|stats| {
+            stats.capture_in_use = in_use;
+            stats.capture_peak_in_use = stats.capture_peak_in_use.max(in_use);
+        });
+
+        Some(PooledTexture {
+            texture: Some(texture),
+            pool: self.clone(),
+        })
     }
     
     /// Return an acquisition texture to the pool for reuse
-    pub fn release_acquisition_texture(&self, texture: ID3D11Texture2D) {
-        #[cfg(debug_assertions)]
-        trace!("TexturePool: Returning acquisition texture to pool at {:p}", &texture as *const _);
-        
+    fn release_acquisition_texture(&self, texture: ID3D11Texture2D) {
+        trace!("TexturePool: Returning acquisition texture to pool");
+
         let mut textures = self.acquisition_textures.lock().unwrap();
         textures.push(texture);
-        
-        #[cfg(debug_assertions)] {
-            self.released_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            let acquired = self.acquired_count.load(std::sync::atomic::Ordering::SeqCst);
-            let released = self.released_count.load(std::sync::atomic::Ordering::SeqCst);
-            let active = acquired - released;
-            
-            if active % 30 == 0 || active <= 0 { // Log every 30 frames or when pool is empty
-                debug!("TexturePool: After release - {} textures still active", active);
-            }
-        }
+        let in_use = self.acquisition_capacity.saturating_sub(textures.len());
+        drop(textures);
+        self.acquisition_released.notify_one();
+        self.update_stats(|stats| stats.capture_in_use = in_use);
     }
     
     /// Get the blank texture (create if not exists)
@@ -223,16 +340,11 @@ impl TexturePool {
                 self.width, 
                 self.height, 
                 self.format,
-                D3D11_USAGE_DEFAULT.0 as u32,
-                (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32, // Add RENDER_TARGET for GDI compatibility
-                0, // CPU access flags
-                D3D11_RESOURCE_MISC_GDI_COMPATIBLE.0 as u32, // Add GDI compatibility
+                D3D11_RESOURCE_MISC_GDI_COMPATIBLE, // Add GDI compatibility
             )? };
             
-            #[cfg(debug_assertions)] {
-                self.created_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                debug!("TexturePool: Created replacement blank texture at {:p}", &texture as *const _);
-            }
+            #[cfg(debug_assertions)]
+            debug!("TexturePool: Created replacement blank texture at {:p}", &texture as *const _);
             
             *blank_texture_lock = Some(texture);
         }
@@ -247,57 +359,44 @@ impl TexturePool {
         Ok(blank_texture)
     }
     
-    // Removed get_staging_texture since we no longer need it
-    
-    /// Get the conversion texture (create if not exists)
+    /// Get the next conversion texture, creating it on first use
     pub fn get_conversion_texture(&self) -> Result<ID3D11Texture2D> {
-        let mut conversion_texture_lock = self.conversion_texture.lock().unwrap();
-        
-        // If conversion texture is None (was taken without being returned), create a new one
-        if conversion_texture_lock.is_none() {
-            warn!("TexturePool: Conversion texture was not properly returned, creating a new one");
-            
-            use windows::Win32::Graphics::Direct3D11::*;
-            
+        use windows::Win32::Graphics::Direct3D11::*;
+
+        let mut conversion = self.conversion_textures.lock().unwrap();
+        let (textures, next) = &mut *conversion;
+        if textures.len() < self.conversion_capacity {
             let texture = unsafe { Self::create_texture(
-                &self.device, 
-                self.width, 
-                self.height, 
-                DXGI_FORMAT_NV12,
-                D3D11_USAGE_DEFAULT.0 as u32,
-                (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET).0 as u32,
-                0, // CPU access flags
-                0, // Misc flags
+                &self.device,
+                self.width,
+                self.height,
+                self.conversion_format,
+                D3D11_RESOURCE_MISC_FLAG(0),
             )? };
-            
-            #[cfg(debug_assertions)] {
-                self.created_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                debug!("TexturePool: Created replacement conversion texture at {:p}", &texture as *const _);
-            }
-            
-            *conversion_texture_lock = Some(texture);
+            debug!(
+                "TexturePool: Created conversion texture {} of {}",
+                textures.len() + 1,
+                self.conversion_capacity
+            );
+            textures.push(texture.clone());
+            let bytes = texture_bytes(self.width, self.height, self.conversion_format);
+            self.update_memory(|memory| memory.conversion_texture_bytes += bytes);
+            return Ok(texture);
         }
-        
-        // Clone the texture interface to return it (increases ref count)
-        // This way, the original stays in the pool
-        let conversion_texture = conversion_texture_lock.as_ref().unwrap().clone();
-        
-        #[cfg(debug_assertions)]
-        trace!("TexturePool: Providing conversion texture at {:p}", &conversion_texture as *const _);
-        
-        Ok(conversion_texture)
+
+        // Hand out the textures in turn so the encoder can still read the previous ones
+        let texture = textures[*next].clone();
+        *next = (*next + 1) % textures.len();
+        Ok(texture)
     }
     
-    /// Create a new texture with the specified parameters
+    /// Create a new GPU-only texture that can be rendered to and sampled from
     unsafe fn create_texture(
         device: &ID3D11Device,
         width: u32,
         height: u32,
         format: DXGI_FORMAT,
-        usage: u32,
-        bind_flags: u32,
-        cpu_access: u32,
-        misc_flags: u32,
+        misc_flags: D3D11_RESOURCE_MISC_FLAG,
     ) -> Result<ID3D11Texture2D> {
         use windows::Win32::Graphics::Direct3D11::*;
         
@@ -311,10 +410,10 @@ impl TexturePool {
                 Count: 1,
                 Quality: 0,
             },
-            Usage: D3D11_USAGE(usage as i32),
-            BindFlags: D3D11_BIND_FLAG(bind_flags as i32),
-            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(cpu_access as i32),
-            MiscFlags: D3D11_RESOURCE_MISC_FLAG(misc_flags as i32),
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: misc_flags,
         };
         
         let mut texture = None;
@@ -322,4 +421,15 @@ impl TexturePool {
         
         Ok(texture.unwrap())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for TexturePool {
+    fn drop(&mut self) {
+        let (capture, conversion) = (self.capture_bytes(), self.conversion_bytes());
+        self.update_memory(|memory| {
+            memory.capture_texture_bytes = memory.capture_texture_bytes.saturating_sub(capture);
+            memory.conversion_texture_bytes =
+                memory.conversion_texture_bytes.saturating_sub(conversion);
+        });
+    }
+}
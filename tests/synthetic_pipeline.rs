@@ -0,0 +1,214 @@
+//! End-to-end recordings of the synthetic sources, checked by reading the files back
+//!
+//! Run with `cargo test --features synthetic`. Only a D3D11 device is needed (WARP where there
+//! is no GPU), so these also run on CI machines without a display or an audio device.
+#![cfg(all(windows, feature = "synthetic"))]
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use windows::core::HSTRING;
+use windows::Win32::Foundation::HMODULE;
+use windows::Win32::Graphics::Direct3D::{
+    D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_WARP,
+};
+use windows::Win32::Graphics::Direct3D11::{
+    D3D11CreateDevice, ID3D11Device, D3D11_CREATE_DEVICE_BGRA_SUPPORT, D3D11_SDK_VERSION,
+};
+use windows::Win32::Graphics::Dxgi::IDXGIAdapter;
+use windows::Win32::Media::MediaFoundation::{
+    IMFSample, IMFSourceReader, MFCreateSourceReaderFromURL, MFShutdown, MFStartup, MFSTARTUP_FULL,
+    MF_SOURCE_READERF_ENDOFSTREAM, MF_SOURCE_READER_CONSTANTS, MF_SOURCE_READER_FIRST_AUDIO_STREAM,
+    MF_SOURCE_READER_FIRST_VIDEO_STREAM, MF_VERSION,
+};
+use windows::Win32::UI::WindowsAndMessaging::GetDesktopWindow;
+use windows_record::{
+    repair_mp4, AudioSource, Recorder, RecorderConfigBuilder, SineWave, SyntheticSource,
+    VideoSource,
+};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+const FPS: u32 = 30;
+const RECORD_FOR: Duration = Duration::from_secs(3);
+
+/// Longest the length of a stream may differ from the time recorded
+const DURATION_TOLERANCE: Duration = Duration::from_millis(300);
+/// Furthest the starts and ends of the video and audio may be apart
+const SYNC_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// Timestamps of the samples of one stream of a file in decode order, and the end of the
+/// last one presented, in 100ns units
+struct Track {
+    times: Vec<i64>,
+    end: i64,
+}
+
+impl Track {
+    fn start(&self) -> Duration {
+        hns(self.times.iter().copied().min().unwrap_or(0))
+    }
+
+    fn end(&self) -> Duration {
+        hns(self.end)
+    }
+}
+
+fn hns(time: i64) -> Duration {
+    Duration::from_nanos(time.max(0) as u64 * 100)
+}
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "windows-record-{}-{}.mp4",
+        name,
+        std::process::id()
+    ))
+}
+
+/// A device with BGRA support on the GPU, or on WARP where there is none
+fn create_device() -> ID3D11Device {
+    let create = |driver_type: D3D_DRIVER_TYPE| {
+        let mut device = None;
+        unsafe {
+            D3D11CreateDevice(
+                None::<&IDXGIAdapter>,
+                driver_type,
+                HMODULE::default(),
+                D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+                None,
+                D3D11_SDK_VERSION,
+                Some(&mut device),
+                None,
+                None,
+            )
+        }
+        .ok()
+        .and(device)
+    };
+    create(D3D_DRIVER_TYPE_HARDWARE)
+        .or_else(|| create(D3D_DRIVER_TYPE_WARP))
+        .expect("no D3D11 device")
+}
+
+/// Record the synthetic sources for `RECORD_FOR` with `configure` applied to the config
+fn record(
+    name: &str,
+    configure: impl FnOnce(RecorderConfigBuilder) -> RecorderConfigBuilder,
+) -> PathBuf {
+    let path = output_path(name);
+    let _ = std::fs::remove_file(&path);
+    let device = create_device();
+    let source = SyntheticSource::new(&device, WIDTH, HEIGHT, FPS, 1).unwrap();
+
+    let builder = Recorder::builder()
+        .fps(FPS, 1)
+        .input_dimensions(WIDTH, HEIGHT)
+        .output_dimensions(WIDTH, HEIGHT)
+        .capture_audio(true)
+        .capture_microphone(false)
+        .audio_source(AudioSource::Synthetic(SineWave::default()))
+        .video_source(VideoSource::custom(source))
+        .external_device(device)
+        .output_path(&path);
+    let config = configure(builder).build().unwrap();
+
+    // The window only decides the audio process and adapter, both of which are fixed here
+    let recorder = Recorder::new(config)
+        .unwrap()
+        .with_window_handle(unsafe { GetDesktopWindow() });
+    recorder.start_recording().unwrap();
+    std::thread::sleep(RECORD_FOR);
+    recorder.stop_recording().unwrap();
+    path
+}
+
+/// Read every sample of the first stream of the kind `stream`, failing on a damaged file
+unsafe fn read_track(reader: &IMFSourceReader, stream: MF_SOURCE_READER_CONSTANTS) -> Track {
+    let mut times = Vec::new();
+    let mut end = 0;
+    loop {
+        let mut flags = 0u32;
+        let mut time = 0i64;
+        let mut sample: Option<IMFSample> = None;
+        reader
+            .ReadSample(
+                stream.0 as u32,
+                0,
+                None,
+                Some(&mut flags),
+                Some(&mut time),
+                Some(&mut sample),
+            )
+            .unwrap();
+        if flags & MF_SOURCE_READERF_ENDOFSTREAM.0 as u32 != 0 {
+            break;
+        }
+        if let Some(sample) = sample {
+            end = end.max(time + sample.GetSampleDuration().unwrap_or(0));
+            times.push(time);
+        }
+    }
+    Track { times, end }
+}
+
+/// The video and audio tracks of `path`
+fn probe(path: &Path) -> (Track, Track) {
+    unsafe {
+        MFStartup(MF_VERSION, MFSTARTUP_FULL).unwrap();
+        let reader =
+            MFCreateSourceReaderFromURL(&HSTRING::from(path.to_str().unwrap()), None).unwrap();
+        let video = read_track(&reader, MF_SOURCE_READER_FIRST_VIDEO_STREAM);
+        let audio = read_track(&reader, MF_SOURCE_READER_FIRST_AUDIO_STREAM);
+        drop(reader);
+        MFShutdown().unwrap();
+        (video, audio)
+    }
+}
+
+fn assert_close(what: &str, actual: Duration, expected: Duration, tolerance: Duration) {
+    assert!(
+        actual.abs_diff(expected) <= tolerance,
+        "{} is {:?}, expected {:?} within {:?}",
+        what,
+        actual,
+        expected,
+        tolerance
+    );
+}
+
+#[test]
+fn records_frames_with_audio_in_sync() {
+    let path = record("sync", |builder| builder);
+    let (video, audio) = probe(&path);
+
+    assert_close("video length", video.end(), RECORD_FOR, DURATION_TOLERANCE);
+    assert_close("audio length", audio.end(), RECORD_FOR, DURATION_TOLERANCE);
+    let expected_frames = RECORD_FOR.as_secs_f64() * FPS as f64;
+    let missing = expected_frames - video.times.len() as f64;
+    assert!(
+        missing <= DURATION_TOLERANCE.as_secs_f64() * FPS as f64,
+        "{} frames recorded, expected about {}",
+        video.times.len(),
+        expected_frames
+    );
+    let mut times = video.times.clone();
+    times.sort_unstable();
+    times.dedup();
+    assert_eq!(times.len(), video.times.len(), "frames share a timestamp");
+
+    assert_close("audio start", audio.start(), video.start(), SYNC_TOLERANCE);
+    assert_close("audio end", audio.end(), video.end(), SYNC_TOLERANCE);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fragmented_recording_is_complete() {
+    let path = record("fragmented", |builder| builder.fragmented_output(true));
+
+    let report = repair_mp4(&path).unwrap();
+    assert!(report.fragments > 0, "no fragments written");
+    assert_eq!(report.truncated_bytes, 0, "the last fragment is incomplete");
+    let (video, _) = probe(&path);
+    assert_close("video length", video.end(), RECORD_FOR, DURATION_TOLERANCE);
+    std::fs::remove_file(&path).unwrap();
+}